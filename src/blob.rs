@@ -25,7 +25,9 @@ use std::path::PathBuf;
 use anyhow::Result;
 use sha2::{Digest, Sha256};
 use tempfile::NamedTempFile;
+use tracing::{debug, debug_span};
 
+use crate::retry::RetryPolicy;
 use crate::GlobalConfig;
 
 /// Buffer sizes for I/O operations, tuned for modern SSD performance
@@ -66,6 +68,7 @@ pub struct Blob {
     pub filename: Option<PathBuf>,
     media_type: Option<String>,
     output_dir: PathBuf,
+    retry: RetryPolicy,
 }
 
 impl Blob {
@@ -75,6 +78,7 @@ impl Blob {
             filename: None,
             media_type: media_type.map(|s| s.to_string()),
             output_dir: PathBuf::from(&global_conf.output),
+            retry: global_conf.io_retry,
         }
     }
 
@@ -82,6 +86,8 @@ impl Blob {
     where
         F: FnOnce(&mut NamedTempFile) -> Result<Option<String>>,
     {
+        let _span = debug_span!("blob", media_type = self.media_type.as_deref()).entered();
+
         // Create temp file in the target directory directly to allow atomic rename (persist)
         // We can't predict the filename yet, so we trust NamedTempFile to pick a safe one.
         // Note: NamedTempFile::new_in ensures the file is on the same filesystem.
@@ -108,7 +114,7 @@ impl Blob {
                 tmp.seek(SeekFrom::Start(0))?;
                 let mut reader = BufReader::with_capacity(IO_BUF_HUGE, tmp.reopen()?);
                 let mut hasher = Sha256::new();
-                let mut buf = [0u8; IO_BUF_HUGE];
+                let mut buf = vec![0u8; IO_BUF_HUGE];
                 loop {
                     let n = reader.read(&mut buf)?;
                     if n == 0 {
@@ -131,7 +137,8 @@ impl Blob {
             self.filename = Some(dest.clone());
             
             // Atomic rename to final digest name
-            tmp.persist(&dest).map_err(|e| anyhow::anyhow!("persist blob: {}", e))?;
+            self.retry.persist(tmp, &dest).map_err(|e| anyhow::anyhow!("persist blob: {}", e))?;
+            debug!(digest = %hexdigest, size, "wrote blob");
 
             Ok(())
         })();
@@ -167,7 +174,13 @@ impl Blob {
 
         let dest = blob_dir.join(hexdigest);
         self.filename = Some(dest.clone());
-        temp_file.persist(&dest).map_err(|e| anyhow::anyhow!("persist blob: {}", e))?;
+        self.retry.persist(temp_file, &dest).map_err(|e| anyhow::anyhow!("persist blob: {}", e))?;
+        debug!(
+            media_type = self.media_type.as_deref(),
+            digest = %hexdigest,
+            size,
+            "wrote blob"
+        );
 
         Ok(())
     }
@@ -0,0 +1,128 @@
+// Copyright (c) 2019, 2020 Codethink Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Background writing of new layer blobs. The compressed stream of a layer
+//! goes through a bounded queue to a thread that hashes it and writes it to
+//! the blob's temporary file, so compression carries on while earlier output
+//! is hashed and written instead of waiting for the disk.
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+use anyhow::Result;
+use crossbeam_channel::{bounded, Sender};
+use sha2::{Digest, Sha256};
+
+/// Size of the chunks handed to the writer thread
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// First error of a writer thread, for the writer feeding it to report
+type WriteError = Arc<Mutex<Option<io::Error>>>;
+
+/// Writer queuing a blob's bytes for its writer thread.
+pub struct QueuedWriter {
+    tx: Option<Sender<Vec<u8>>>,
+    buf: Vec<u8>,
+    error: WriteError,
+}
+
+/// Blob being written by a writer thread.
+pub struct PendingBlob {
+    writer: JoinHandle<(String, u64)>,
+    error: WriteError,
+}
+
+/// Start writing a blob to `file`, with at most `queue_mb` MiB of it queued.
+pub fn start(file: File, queue_mb: usize) -> Result<(QueuedWriter, PendingBlob)> {
+    let (tx, rx) = bounded::<Vec<u8>>(queue_mb.max(1));
+    let error = WriteError::default();
+    let thread_error = Arc::clone(&error);
+    let writer = std::thread::Builder::new().name("blob-writer".to_string()).spawn(move || {
+        let mut file = file;
+        let mut hasher = Sha256::new();
+        let mut size = 0;
+        // An error drops the receiver, which stops the sender too
+        let result = rx.iter().try_for_each(|chunk| {
+            hasher.update(&chunk);
+            size += chunk.len() as u64;
+            file.write_all(&chunk)
+        });
+        if let Err(e) = result.and_then(|_| file.flush()) {
+            *thread_error.lock().unwrap_or_else(|e| e.into_inner()) = Some(e);
+        }
+        (format!("{:x}", hasher.finalize()), size)
+    })?;
+    let queued = QueuedWriter { tx: Some(tx), buf: Vec::with_capacity(CHUNK_SIZE), error: Arc::clone(&error) };
+    Ok((queued, PendingBlob { writer, error }))
+}
+
+impl QueuedWriter {
+    fn send(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let chunk = mem::replace(&mut self.buf, Vec::with_capacity(CHUNK_SIZE));
+        match self.tx.as_ref().map(|tx| tx.send(chunk)) {
+            Some(Ok(())) => Ok(()),
+            _ => {
+                self.tx = None;
+                let error = self.error.lock().unwrap_or_else(|e| e.into_inner()).take();
+                Err(error.unwrap_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "blob writer stopped")))
+            }
+        }
+    }
+}
+
+impl Write for QueuedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(CHUNK_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&buf[..n]);
+        if self.buf.len() == CHUNK_SIZE {
+            self.send()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send()
+    }
+}
+
+impl Drop for QueuedWriter {
+    fn drop(&mut self) {
+        // The rest of the blob, when the last writer goes away
+        let _ = self.send();
+    }
+}
+
+impl PendingBlob {
+    /// Wait for the writer thread, once every [`QueuedWriter`] of the blob
+    /// is dropped, and return the blob's hex sha256 and size.
+    pub fn finish(self) -> Result<(String, u64)> {
+        let (digest, size) = self.writer.join().map_err(|_| anyhow::anyhow!("Blob writer panicked"))?;
+        if let Some(e) = self.error.lock().unwrap_or_else(|e| e.into_inner()).take() {
+            return Err(anyhow::Error::new(e).context("Writing layer blob"));
+        }
+        Ok((digest, size))
+    }
+}
@@ -0,0 +1,180 @@
+// Copyright (c) 2019, 2020 Codethink Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! `build-report: true`: `<output>/build-report.json`, a summary of every
+//! image of a document with its digests, layer sizes, deduplication counts
+//! and time spent per stage, for dashboards and size regression tracking.
+//! Layers and images record what they did as they are built; the report is
+//! put together from those records and the layout once all are done.
+
+use std::fs;
+use std::path::Path;
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use rustc_hash::FxHashMap;
+use serde_json::{json, Value};
+
+use crate::config::ImageSpec;
+use crate::image_builder::group_ref;
+use crate::layer_builder::DedupStats;
+use crate::layout::{descriptor_digest, descriptor_size, Layout};
+use crate::GlobalConfig;
+
+pub const FILE_NAME: &str = "build-report.json";
+
+/// What building one layer blob took and did.
+#[derive(Debug, Clone, Copy)]
+pub struct LayerRecord {
+    pub uncompressed_size: u64,
+    /// `None` for `layer-tar:` files, which are copied as they are
+    pub dedup: Option<DedupStats>,
+    pub duration: Duration,
+}
+
+/// Time spent in each stage of an image build, in order, and in all of it.
+#[derive(Debug, Default, Clone)]
+pub struct Timings {
+    stages: Vec<(&'static str, Duration)>,
+    pub total: Duration,
+}
+
+impl Timings {
+    pub fn add(&mut self, stage: &'static str, duration: Duration) {
+        match self.stages.iter_mut().find(|(name, _)| *name == stage) {
+            Some((_, total)) => *total += duration,
+            None => self.stages.push((stage, duration)),
+        }
+    }
+}
+
+/// Layers built by this process, keyed by blob digest
+static LAYERS: LazyLock<Mutex<FxHashMap<String, LayerRecord>>> = LazyLock::new(|| Mutex::new(FxHashMap::default()));
+/// Images built by this process, keyed by manifest digest
+static IMAGES: LazyLock<Mutex<FxHashMap<String, Timings>>> = LazyLock::new(|| Mutex::new(FxHashMap::default()));
+
+pub fn record_layer(digest: &str, record: LayerRecord) {
+    if let Ok(mut layers) = LAYERS.lock() {
+        layers.insert(digest.to_string(), record);
+    }
+}
+
+pub fn record_image(digest: &str, timings: Timings) {
+    if let Ok(mut images) = IMAGES.lock() {
+        images.insert(digest.to_string(), timings);
+    }
+}
+
+/// Write the report of `images` to the output layout, given the manifest
+/// descriptor of each (`None` for images that failed or were skipped) and
+/// the time the whole document took.
+pub fn write(
+    global_conf: &GlobalConfig,
+    images: &[ImageSpec],
+    descriptors: &[Option<Value>],
+    duration: Duration,
+) -> Result<()> {
+    let output = Path::new(&global_conf.output);
+    let layout = Layout::building(output);
+    let entries = images
+        .iter()
+        .zip(descriptors)
+        .enumerate()
+        .map(|(i, (image, desc))| {
+            let mut entry = json!({
+                "image": i,
+                "name": group_ref(image),
+                "platform": format!("{}/{}", image.os, image.architecture),
+                "built": desc.is_some(),
+            });
+            if let Some(desc) = desc {
+                image_report(&layout, desc, &mut entry)?;
+            }
+            Ok(entry)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let report = json!({
+        "output": global_conf.output,
+        "seconds": duration.as_secs_f64(),
+        "images": entries,
+    });
+    let path = output.join(FILE_NAME);
+    fs::write(&path, serde_json::to_string_pretty(&report)? + "\n")
+        .with_context(|| format!("Writing {}", path.display()))
+}
+
+/// Fill in the digests, sizes, layers and timings of the image with
+/// manifest descriptor `desc`.
+fn image_report(layout: &Layout, desc: &Value, entry: &mut Value) -> Result<()> {
+    let digest = descriptor_digest(desc)?;
+    let manifest = layout.read_json(digest)?;
+    let config_desc = &manifest["config"];
+    let config = layout.read_json(descriptor_digest(config_desc)?)?;
+    let diff_ids = config["rootfs"]["diff_ids"].as_array();
+    let layers = manifest["layers"].as_array().map(Vec::as_slice).unwrap_or_default();
+
+    let records = LAYERS.lock().map_err(|e| anyhow::anyhow!("Build report lock poisoned: {}", e))?;
+    let layer_entries: Vec<Value> = layers
+        .iter()
+        .enumerate()
+        .map(|(j, layer)| {
+            let mut layer_entry = json!({
+                "digest": layer["digest"],
+                "diffId": diff_ids.and_then(|d| d.get(j)),
+                "mediaType": layer["mediaType"],
+                "size": descriptor_size(layer),
+            });
+            // Layers taken from a parent or a previous build were not built now
+            let record = layer["digest"].as_str().and_then(|d| records.get(d));
+            layer_entry["built"] = record.is_some().into();
+            if let Some(record) = record {
+                layer_entry["uncompressedSize"] = record.uncompressed_size.into();
+                layer_entry["seconds"] = record.duration.as_secs_f64().into();
+                if let Some(dedup) = record.dedup {
+                    layer_entry["dedup"] = json!({
+                        "added": dedup.added,
+                        "skipped": dedup.skipped,
+                        "skippedBytes": dedup.skipped_bytes,
+                        "whiteouts": dedup.whiteouts,
+                    });
+                }
+            }
+            layer_entry
+        })
+        .collect();
+    drop(records);
+
+    entry["manifest"] = digest.into();
+    entry["config"] = config_desc["digest"].clone();
+    entry["size"] = (descriptor_size(desc) + descriptor_size(config_desc) + layers.iter().map(descriptor_size).sum::<u64>()).into();
+    entry["layers"] = layer_entries.into();
+    if let Some(timings) = IMAGES.lock().ok().and_then(|images| images.get(digest).cloned()) {
+        entry["seconds"] = timings.total.as_secs_f64().into();
+        entry["stages"] = timings
+            .stages
+            .iter()
+            .map(|(stage, duration)| (stage.to_string(), duration.as_secs_f64().into()))
+            .collect::<serde_json::Map<_, _>>()
+            .into();
+    }
+    Ok(())
+}
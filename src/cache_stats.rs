@@ -0,0 +1,117 @@
+// Copyright (c) 2019, 2020 Codethink Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Hit and miss counts of the build's caches, reported when a build ends so
+//! users can see whether caching is working for them.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tracing::info;
+
+/// Counters of one cache. Bytes saved are the blob or layer bytes that a hit
+/// spared from being copied, re-encoded, decompressed or parsed.
+pub struct Counter {
+    name: &'static str,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    bytes_saved: AtomicU64,
+}
+
+impl Counter {
+    const fn new(name: &'static str) -> Self {
+        Counter {
+            name,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            bytes_saved: AtomicU64::new(0),
+        }
+    }
+
+    pub fn hit(&self, bytes_saved: u64) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        self.bytes_saved.fetch_add(bytes_saved, Ordering::Relaxed);
+    }
+
+    pub fn miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn load(&self) -> (u64, u64, u64) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+            self.bytes_saved.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Parent images already copied into the output layout earlier in the run
+pub static EXTRACT: Counter = Counter::new("extract");
+/// Deduplication analyses of the same set of lower layers
+pub static ANALYSIS: Counter = Counter::new("analysis");
+/// Decompressed lower layers found in the `lower-cache:` directory
+pub static LOWER_CACHE: Counter = Counter::new("lowerCache");
+/// Lower layers whose stored file index replaced parsing their tar
+pub static LAYER_INDEX: Counter = Counter::new("layerIndex");
+/// Parent layer blobs already present in the output layout
+pub static BLOB_EXISTS: Counter = Counter::new("blobExists");
+/// Parent blobs verified earlier, with `verify-parents: always`
+pub static PARENT_VERIFY: Counter = Counter::new("parentVerify");
+/// New layers found in the `cache-dir:` directory instead of built
+pub static LAYER_CACHE: Counter = Counter::new("layerCache");
+/// Layers missing from `cache-dir:` and downloaded from `remote-cache:`
+pub static REMOTE_CACHE: Counter = Counter::new("remoteCache");
+
+const COUNTERS: [&Counter; 8] = [
+    &EXTRACT,
+    &ANALYSIS,
+    &LOWER_CACHE,
+    &LAYER_INDEX,
+    &BLOB_EXISTS,
+    &PARENT_VERIFY,
+    &LAYER_CACHE,
+    &REMOTE_CACHE,
+];
+
+/// Whether any cache was consulted in this run.
+pub fn any() -> bool {
+    COUNTERS.iter().any(|counter| matches!(counter.load(), (hits, misses, _) if hits + misses > 0))
+}
+
+/// All counters as a JSON object keyed by cache name.
+pub fn to_json() -> serde_json::Value {
+    COUNTERS
+        .iter()
+        .map(|counter| {
+            let (hits, misses, bytes_saved) = counter.load();
+            let stats = serde_json::json!({ "hits": hits, "misses": misses, "bytesSaved": bytes_saved });
+            (counter.name.to_string(), stats)
+        })
+        .collect::<serde_json::Map<_, _>>()
+        .into()
+}
+
+/// Log one `cache statistics` event per cache.
+pub fn log() {
+    for counter in COUNTERS {
+        let (hits, misses, bytes_saved) = counter.load();
+        info!(cache = counter.name, hits, misses, bytes_saved, "cache statistics");
+    }
+}
@@ -0,0 +1,446 @@
+// Copyright (c) 2019, 2020 Codethink Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Command line of build-oci. The same description parses the arguments
+//! and generates the shell completions and the man page.
+
+use std::ffi::OsString;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Result};
+use clap::builder::RangedU64ValueParser;
+use clap::error::ErrorKind;
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use clap_complete::Shell;
+
+fn flag(name: &'static str, help: &'static str) -> Arg {
+    Arg::new(name).long(name).action(ArgAction::SetTrue).help(help)
+}
+
+/// A non-negative integer value.
+fn number() -> RangedU64ValueParser<usize> {
+    RangedU64ValueParser::new()
+}
+
+/// A value of at least 1.
+fn positive() -> RangedU64ValueParser<usize> {
+    RangedU64ValueParser::new().range(1..)
+}
+
+fn layout_arg() -> Arg {
+    Arg::new("layout")
+        .value_name("LAYOUT")
+        .value_hint(clap::ValueHint::DirPath)
+        .help("OCI layout directory")
+}
+
+fn format_arg() -> Arg {
+    Arg::new("format")
+        .long("format")
+        .value_name("FORMAT")
+        .value_parser(["yaml", "json", "toml"])
+        .help("Manifest syntax (default: detected from the input)")
+}
+
+/// Flags of a build, which `watch` takes too.
+fn build_args() -> Vec<Arg> {
+    vec![
+        Arg::new("workers")
+            .short('j')
+            .long("workers")
+            .value_name("N")
+            .value_parser(number())
+            .help("Number of parallel worker threads (default: number of CPU cores)"),
+        format_arg(),
+        flag(
+            "allow-unknown-platform",
+            "Build images whose os/architecture/variant is not a known platform",
+        ),
+        Arg::new("iidfile")
+            .long("iidfile")
+            .value_name("PATH")
+            .value_hint(clap::ValueHint::FilePath)
+            .help("Write the index and manifest digests of every built layout to PATH"),
+        // The later of a flag and its --no- form wins
+        flag("skip-xattrs", "Do not record extended attributes (overrides skip-xattrs:)")
+            .overrides_with("no-skip-xattrs"),
+        flag("no-skip-xattrs", "Record extended attributes (overrides skip-xattrs:)")
+            .overrides_with("skip-xattrs"),
+        flag(
+            "write-checksum-xattrs",
+            "Store computed checksums on layer files (overrides write-checksum-xattrs:)",
+        )
+        .overrides_with("no-write-checksum-xattrs"),
+        flag(
+            "no-write-checksum-xattrs",
+            "Do not store computed checksums (overrides write-checksum-xattrs:)",
+        )
+        .overrides_with("write-checksum-xattrs"),
+        Arg::new("prefetch-limit-mb")
+            .long("prefetch-limit-mb")
+            .value_name("MB")
+            .value_parser(number())
+            .help("Memory limit of the file prefetch cache (overrides prefetch-limit-mb:)"),
+        Arg::new("max-memory-mb")
+            .long("max-memory-mb")
+            .value_name("MB")
+            .value_parser(number())
+            .help("Build-wide limit on mapped and cached file contents (overrides max-memory-mb:)"),
+        Arg::new("max-open-files")
+            .long("max-open-files")
+            .value_name("N")
+            .value_parser(number())
+            .help("Build-wide limit on mapped files and parent layer streams (overrides max-open-files:)"),
+        Arg::new("layer-threads")
+            .long("layer-threads")
+            .visible_alias("compression-threads")
+            .value_name("N")
+            .value_parser(positive())
+            .help("Compression threads per layer (default: workers divided by the image parallelism)"),
+        Arg::new("image-parallelism")
+            .long("image-parallelism")
+            .value_name("N")
+            .value_parser(positive())
+            .help("Images built at the same time (default: the number of workers)"),
+        Arg::new("nice")
+            .long("nice")
+            .value_name("N")
+            .value_parser(number())
+            .help("Nice level 0-19 for the build (overrides priority.nice:)"),
+        Arg::new("io-class")
+            .long("io-class")
+            .value_name("CLASS")
+            .value_parser(["best-effort", "idle"])
+            .help("I/O scheduling class (overrides priority.io-class:)"),
+        Arg::new("cpus")
+            .long("cpus")
+            .value_name("LIST")
+            .help("Pin layer compression to these CPUs, e.g. 0-3,8 (overrides priority.cpus:)"),
+        flag(
+            "dry-run",
+            "Print which files would be added, skipped or whited out without writing anything",
+        ),
+        flag(
+            "manifest-only",
+            "Write new configs, manifests and index over the layers of the previous build",
+        ),
+        flag("fail-fast", "Start no further images once one fails").conflicts_with("keep-going"),
+        flag(
+            "keep-going",
+            "Write the images that built even if others fail, then exit with an error",
+        ),
+    ]
+}
+
+pub fn command() -> Command {
+    Command::new("build-oci")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Build OCI images from a YAML, JSON or TOML manifest read on stdin")
+        .disable_help_subcommand(true)
+        .arg(
+            Arg::new("verbose")
+                .short('v')
+                .long("verbose")
+                .action(ArgAction::Count)
+                .global(true)
+                .help("More log output on stderr (repeatable)"),
+        )
+        .arg(
+            Arg::new("quiet")
+                .short('q')
+                .long("quiet")
+                .action(ArgAction::SetTrue)
+                .global(true)
+                .help("Only log errors"),
+        )
+        .arg(
+            Arg::new("log-format")
+                .long("log-format")
+                .value_name("FORMAT")
+                .value_parser(["text", "json"])
+                .global(true)
+                .help("Log output format"),
+        )
+        .arg(
+            Arg::new("no-progress")
+                .long("no-progress")
+                .action(ArgAction::SetTrue)
+                .global(true)
+                .help("Do not draw progress bars on a terminal"),
+        )
+        .arg(
+            Arg::new("error-json")
+                .long("error-json")
+                .value_name("PATH")
+                .value_hint(clap::ValueHint::FilePath)
+                .value_parser(clap::value_parser!(PathBuf))
+                .global(true)
+                .help("On failure, also write the error as JSON to PATH"),
+        )
+        .args(build_args())
+        .subcommand(
+            Command::new("ls")
+                .about("List images in a layout")
+                .arg(layout_arg().required(true))
+                .arg(flag("json", "Print JSON")),
+        )
+        .subcommand(
+            Command::new("du")
+                .about("Show uncompressed size per layer and top-level directory")
+                .arg(
+                    Arg::new("image")
+                        .value_name("LAYOUT[:REF]")
+                        .value_hint(clap::ValueHint::DirPath)
+                        .required(true)
+                        .help("Layout directory, optionally followed by a ref name, digest or index"),
+                )
+                .arg(flag("json", "Print JSON")),
+        )
+        .subcommand(
+            Command::new("inspect")
+                .about("Print the manifest, config and layers of an image")
+                .arg(
+                    Arg::new("image")
+                        .value_name("LAYOUT[:REF]")
+                        .value_hint(clap::ValueHint::DirPath)
+                        .required(true)
+                        .help("Layout directory, optionally followed by a ref name, digest or index"),
+                )
+                .arg(
+                    Arg::new("index")
+                        .long("index")
+                        .value_name("N")
+                        .value_parser(number())
+                        .conflicts_with("ref")
+                        .help("Select the image at position N of index.json"),
+                )
+                .arg(
+                    Arg::new("ref")
+                        .long("ref")
+                        .value_name("NAME")
+                        .help("Select the image with this ref.name annotation"),
+                )
+                .arg(flag("json", "Print the descriptor, manifest and config as JSON")),
+        )
+        .subcommand(
+            Command::new("unpack")
+                .about("Extract the root file system of an image by applying its layers")
+                .arg(
+                    Arg::new("image")
+                        .value_name("LAYOUT[:REF]")
+                        .value_hint(clap::ValueHint::DirPath)
+                        .required(true)
+                        .help("Layout directory, optionally followed by a ref name, digest or index"),
+                )
+                .arg(
+                    Arg::new("dest")
+                        .value_name("DEST")
+                        .value_hint(clap::ValueHint::DirPath)
+                        .required(true)
+                        .help("Missing or empty directory to extract into"),
+                ),
+        )
+        .subcommand(
+            Command::new("verify")
+                .about("Check the blobs, diff_ids and history of a layout, its GPG signature or its lazy-pull layers")
+                .arg(layout_arg().required(true))
+                .arg(flag("blobs", "Re-hash every blob and recompute layer diff_ids (default without other options)"))
+                .arg(flag("signatures", "Verify SHA256SUMS.asc and the files listed in SHA256SUMS"))
+                .arg(flag("lazy-pull", "Check the TOC annotations of eStargz and zstd:chunked layers"))
+                .arg(
+                    Arg::new("gpg-homedir")
+                        .long("gpg-homedir")
+                        .value_name("DIR")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .value_hint(clap::ValueHint::DirPath)
+                        .help("GnuPG home directory (default: $GNUPGHOME)"),
+                ),
+        )
+        .subcommand(
+            Command::new("gc")
+                .about("Delete the blobs of a layout that index.json no longer refers to")
+                .arg(layout_arg().required(true))
+                .arg(flag("dry-run", "List the unreachable blobs without deleting them"))
+                .arg(
+                    Arg::new("keep-last")
+                        .long("keep-last")
+                        .value_name("N")
+                        .value_parser(number())
+                        .help("Keep the last N index entries of each ref.name"),
+                )
+                .arg(
+                    Arg::new("keep-digests")
+                        .long("keep-digests")
+                        .value_name("FILE")
+                        .action(ArgAction::Append)
+                        .value_hint(clap::ValueHint::FilePath)
+                        .help("Keep the index entries whose digests FILE lists"),
+                )
+                .arg(
+                    Arg::new("min-age")
+                        .long("min-age")
+                        .value_name("AGE")
+                        .help("Keep index entries younger than AGE, e.g. 7d or 12h"),
+                ),
+        )
+        .subcommand(
+            Command::new("test-corpus")
+                .about("Write a root file system of tricky cases for testing image consumers")
+                .hide(true)
+                .arg(
+                    Arg::new("dir")
+                        .value_name("DIR")
+                        .value_hint(clap::ValueHint::DirPath)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            Command::new("push")
+                .about("Upload an image of a layout to a registry")
+                .arg(
+                    Arg::new("image")
+                        .value_name("LAYOUT[:REF]")
+                        .value_hint(clap::ValueHint::DirPath)
+                        .required(true)
+                        .help("Layout directory, optionally followed by a ref name, digest or index"),
+                )
+                .arg(
+                    Arg::new("destination")
+                        .value_name("REGISTRY/REPOSITORY[:TAG]")
+                        .required(true)
+                        .help("Repository and tag to push to (default tag: latest)"),
+                )
+                .arg(
+                    Arg::new("chunk-size-mb")
+                        .long("chunk-size-mb")
+                        .value_name("MB")
+                        .value_parser(RangedU64ValueParser::<u64>::new().range(1..))
+                        .help("Upload blobs larger than this in chunks of this size"),
+                )
+                .arg(
+                    Arg::new("limit-rate")
+                        .long("limit-rate")
+                        .value_name("RATE")
+                        .help("Send at most RATE bytes per second to the registry host, e.g. 500K or 2M"),
+                )
+                .arg(
+                    Arg::new("max-connections")
+                        .long("max-connections")
+                        .value_name("N")
+                        .value_parser(positive())
+                        .help("Keep at most N requests to the registry host in flight"),
+                )
+                .arg(flag("plain-http", "Talk to the registry over HTTP instead of HTTPS")),
+        )
+        .subcommand(
+            Command::new("watch")
+                .about("Build the manifest on stdin, then rebuild images when their layer directory changes")
+                .args(build_args().into_iter().filter(|arg| {
+                    !matches!(arg.get_id().as_str(), "dry-run" | "iidfile" | "manifest-only")
+                }))
+                .arg(
+                    Arg::new("debounce-ms")
+                        .long("debounce-ms")
+                        .value_name("MS")
+                        .value_parser(clap::value_parser!(u64))
+                        .help("Wait for this long without changes before rebuilding (default: 200)"),
+                ),
+        )
+        .subcommand(
+            Command::new("lint")
+                .about("Report common mistakes in a build manifest without building")
+                .arg(
+                    Arg::new("manifest")
+                        .value_name("MANIFEST")
+                        .value_hint(clap::ValueHint::FilePath)
+                        .help("Manifest file (default: stdin)"),
+                )
+                .arg(format_arg()),
+        )
+        .subcommand(
+            Command::new("completions")
+                .about("Print a shell completion script")
+                .arg(
+                    Arg::new("shell")
+                        .value_name("SHELL")
+                        .value_parser(clap::value_parser!(Shell))
+                        .required(true),
+                ),
+        )
+        .subcommand(Command::new("man").about("Print the build-oci(1) man page"))
+}
+
+/// Parse `args`, the program name first. `--help` and `--version` print
+/// on stdout and exit.
+pub fn parse(args: &[OsString]) -> Result<ArgMatches> {
+    command().try_get_matches_from(args).map_err(|err| match err.kind() {
+        ErrorKind::DisplayHelp | ErrorKind::DisplayVersion => err.exit(),
+        _ => {
+            let message = err.render().to_string();
+            anyhow!("{}", message.trim_end().trim_start_matches("error: "))
+        }
+    })
+}
+
+/// `--error-json <path>` of a command line that `parse` rejected, so that
+/// the report is written for that error too.
+pub fn find_error_json(args: &[OsString]) -> Option<PathBuf> {
+    let mut iter = args.iter().skip(1).take_while(|arg| *arg != "--");
+    while let Some(arg) = iter.next() {
+        if arg == "--error-json" {
+            return iter.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.to_str().and_then(|arg| arg.strip_prefix("--error-json=")) {
+            return Some(PathBuf::from(path));
+        }
+    }
+    None
+}
+
+/// Matches of the subcommand given, which also hold the global flags
+/// wherever they appear on the command line.
+pub fn innermost(matches: &ArgMatches) -> &ArgMatches {
+    match matches.subcommand() {
+        Some((_, subcommand)) => innermost(subcommand),
+        None => matches,
+    }
+}
+
+/// Value of an argument that `command()` makes required.
+pub fn required<'a>(matches: &'a ArgMatches, id: &str) -> &'a str {
+    matches.get_one::<String>(id).expect("clap checks required arguments")
+}
+
+/// `build-oci completions <shell>`: print a completion script on stdout.
+pub fn completions(matches: &ArgMatches) -> Result<()> {
+    let shell = *matches.get_one::<Shell>("shell").expect("clap checks required arguments");
+    // Generate into a buffer: clap_complete panics on write errors
+    let mut script = Vec::new();
+    clap_complete::generate(shell, &mut command(), "build-oci", &mut script);
+    io::stdout().write_all(&script)?;
+    Ok(())
+}
+
+/// `build-oci man`: print the roff man page on stdout.
+pub fn man() -> Result<()> {
+    clap_mangen::Man::new(command()).render(&mut io::stdout())?;
+    Ok(())
+}
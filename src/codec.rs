@@ -0,0 +1,380 @@
+// Copyright (c) 2019, 2020 Codethink Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Layer compression codecs. Everything that differs between codecs (media
+//! type suffix, magic bytes, default level, encoder and decoder) sits behind
+//! `Codec`; a new codec implements it and gets a `Compression` variant, and
+//! is listed in `CODECS` if its blobs are told apart by media type or magic.
+
+use std::io::{BufRead, Read, Write};
+
+use anyhow::{anyhow, bail, Result};
+use flate2::bufread::MultiGzDecoder;
+use gzp::deflate::Gzip as GzipFormat;
+use gzp::par::compress::ParCompress;
+use gzp::ZWriter;
+use zstd::stream::read::Decoder as ZstdDecoder;
+use zstd::stream::write::Encoder as ZstdEncoder;
+use zstd::zstd_safe::{CParameter, Strategy as ZstdStrategy};
+
+use crate::config::CompressionOptionsSpec;
+use crate::rsyncable;
+
+pub const LAYER_MEDIA_TYPE: &str = "application/vnd.oci.image.layer.v1.tar";
+/// Layers registries neither store nor serve; clients fetch them from the
+/// descriptor's `urls`
+pub const NONDISTRIBUTABLE_LAYER_MEDIA_TYPE: &str = "application/vnd.oci.image.layer.nondistributable.v1.tar";
+
+pub trait Codec: Sync {
+    /// Name recorded in the compression annotation
+    fn name(&self) -> &'static str;
+
+    /// Appended to the layer media type, e.g. "+gzip"
+    fn media_type_suffix(&self) -> &'static str;
+
+    /// Leading bytes of a stream in this format
+    fn magic(&self) -> &'static [u8];
+
+    /// Level used unless `compression-level:` is given
+    fn default_level(&self) -> Option<u32>;
+
+    /// Codec of parent layers re-encoded for this compression, which must
+    /// keep their tar stream and so their diff_id.
+    fn for_parents(&self) -> &'static dyn Codec;
+
+    /// Compress into `writer` with `threads` threads where the codec can,
+    /// applying the `options` it understands.
+    fn encoder(
+        &self,
+        writer: Box<dyn Write + Send>,
+        level: Option<u32>,
+        threads: usize,
+        options: &CompressionOptions,
+    ) -> Result<Box<dyn Encoder>>;
+
+    /// Decompress `reader`.
+    fn decoder<'a>(&self, reader: Box<dyn BufRead + Send + 'a>) -> Result<Box<dyn Read + Send + 'a>>;
+
+    /// Whether blobs differ from the tar they hold, so the blob digest is
+    /// not the diff_id.
+    fn compresses(&self) -> bool {
+        true
+    }
+
+    fn media_type(&self) -> String {
+        format!("{}{}", LAYER_MEDIA_TYPE, self.media_type_suffix())
+    }
+}
+
+/// Codec parameters beyond the level: `compression-options:` for zstd and
+/// `gzip-rsyncable:`. Codecs ignore those of others.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompressionOptions {
+    /// Long-distance matching, for repeats further apart than the window
+    /// of the level, as in large root file systems
+    pub long: bool,
+    /// Base-2 log of the match window (10-31)
+    pub window_log: Option<u32>,
+    pub strategy: Option<ZstdStrategy>,
+    /// Restart gzip compression at content-defined points
+    pub gzip_rsyncable: bool,
+}
+
+impl CompressionOptions {
+    pub fn from_spec(spec: &CompressionOptionsSpec) -> Result<Self> {
+        if let Some(window_log) = spec.window_log.filter(|w| !(10..=31).contains(w)) {
+            bail!("compression-options.window-log must be between 10 and 31, got: {}", window_log);
+        }
+        let strategy = match spec.strategy {
+            Some(ref name) => match ZSTD_STRATEGIES.iter().find(|(n, _)| n == name) {
+                Some((_, strategy)) => Some(*strategy),
+                None => {
+                    let names: Vec<&str> = ZSTD_STRATEGIES.iter().map(|(n, _)| *n).collect();
+                    bail!("compression-options.strategy must be one of {}, got: {}", names.join(", "), name);
+                }
+            },
+            None => None,
+        };
+        Ok(CompressionOptions {
+            long: spec.long.unwrap_or(false),
+            window_log: spec.window_log,
+            strategy,
+            gzip_rsyncable: false,
+        })
+    }
+}
+
+/// Largest window zstd decoders accept without being told to
+pub const DEFAULT_MAX_WINDOW_LOG: u32 = 27;
+
+/// zstd match finding strategies, fastest first, by their `zstd` names.
+pub const ZSTD_STRATEGIES: &[(&str, ZstdStrategy)] = &[
+    ("fast", ZstdStrategy::ZSTD_fast),
+    ("dfast", ZstdStrategy::ZSTD_dfast),
+    ("greedy", ZstdStrategy::ZSTD_greedy),
+    ("lazy", ZstdStrategy::ZSTD_lazy),
+    ("lazy2", ZstdStrategy::ZSTD_lazy2),
+    ("btlazy2", ZstdStrategy::ZSTD_btlazy2),
+    ("btopt", ZstdStrategy::ZSTD_btopt),
+    ("btultra", ZstdStrategy::ZSTD_btultra),
+    ("btultra2", ZstdStrategy::ZSTD_btultra2),
+];
+
+/// A compressing writer. `finish` ends the stream and flushes and drops the
+/// writer it was given.
+pub trait Encoder: Write + Send {
+    fn finish(self: Box<Self>) -> Result<Finished>;
+}
+
+/// What an encoder reports about the blob it wrote.
+#[derive(Default)]
+pub struct Finished {
+    /// Digest of the uncompressed blob, if the encoder added to the tar it was given
+    pub diff_digest: Option<String>,
+    /// Annotations for the layer descriptor
+    pub annotations: Vec<(&'static str, String)>,
+}
+
+pub static GZIP: Gzip = Gzip;
+pub static ZSTD: Zstd = Zstd;
+pub static UNCOMPRESSED: Uncompressed = Uncompressed;
+
+/// Every known codec, for recognising existing blobs and tar files.
+pub static CODECS: &[&dyn Codec] = &[&GZIP, &ZSTD, &UNCOMPRESSED];
+
+/// The codec of a layer with `media_type`; unknown suffixes are read as tar.
+pub fn for_media_type(media_type: &str) -> &'static dyn Codec {
+    CODECS
+        .iter()
+        .copied()
+        .find(|codec| !codec.media_type_suffix().is_empty() && media_type.ends_with(codec.media_type_suffix()))
+        .unwrap_or(&UNCOMPRESSED)
+}
+
+/// The codec of a stream starting with `bytes`.
+pub fn for_magic(bytes: &[u8]) -> &'static dyn Codec {
+    CODECS
+        .iter()
+        .copied()
+        .find(|codec| !codec.magic().is_empty() && bytes.starts_with(codec.magic()))
+        .unwrap_or(&UNCOMPRESSED)
+}
+
+pub struct Gzip;
+
+impl Codec for Gzip {
+    fn name(&self) -> &'static str {
+        "gzip"
+    }
+
+    fn media_type_suffix(&self) -> &'static str {
+        "+gzip"
+    }
+
+    fn magic(&self) -> &'static [u8] {
+        &[0x1f, 0x8b]
+    }
+
+    fn default_level(&self) -> Option<u32> {
+        Some(5)
+    }
+
+    fn for_parents(&self) -> &'static dyn Codec {
+        &GZIP
+    }
+
+    fn encoder(
+        &self,
+        writer: Box<dyn Write + Send>,
+        level: Option<u32>,
+        threads: usize,
+        options: &CompressionOptions,
+    ) -> Result<Box<dyn Encoder>> {
+        let level = level.or(self.default_level()).unwrap_or(5);
+        if options.gzip_rsyncable {
+            return Ok(Box::new(rsyncable::Writer::new(writer, level, threads)?));
+        }
+        let parz: ParCompress<GzipFormat> = ParCompress::<GzipFormat>::builder()
+            .num_threads(threads.max(1))
+            .map_err(|e| anyhow!("gzp thread config: {}", e))?
+            .compression_level(gzp::Compression::new(level))
+            .from_writer(writer);
+        Ok(Box::new(GzipEncoder(parz)))
+    }
+
+    fn decoder<'a>(&self, reader: Box<dyn BufRead + Send + 'a>) -> Result<Box<dyn Read + Send + 'a>> {
+        Ok(Box::new(MultiGzDecoder::new(reader)))
+    }
+}
+
+struct GzipEncoder(ParCompress<GzipFormat>);
+
+impl Write for GzipEncoder {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Encoder for GzipEncoder {
+    fn finish(mut self: Box<Self>) -> Result<Finished> {
+        // ParCompress consumes the writer and drops it when finished
+        self.0.finish().map_err(|e| anyhow!("parallel gzip: {}", e))?;
+        Ok(Finished::default())
+    }
+}
+
+pub struct Zstd;
+
+impl Codec for Zstd {
+    fn name(&self) -> &'static str {
+        "zstd"
+    }
+
+    fn media_type_suffix(&self) -> &'static str {
+        "+zstd"
+    }
+
+    fn magic(&self) -> &'static [u8] {
+        &[0x28, 0xb5, 0x2f, 0xfd]
+    }
+
+    fn default_level(&self) -> Option<u32> {
+        Some(1) // zstd level 1 for max speed
+    }
+
+    fn for_parents(&self) -> &'static dyn Codec {
+        &ZSTD
+    }
+
+    fn encoder(
+        &self,
+        writer: Box<dyn Write + Send>,
+        level: Option<u32>,
+        threads: usize,
+        options: &CompressionOptions,
+    ) -> Result<Box<dyn Encoder>> {
+        let level = level.or(self.default_level()).unwrap_or(1) as i32;
+        let mut encoder = ZstdEncoder::new(writer, level)?;
+        encoder.multithread(threads as u32)?;
+        if options.long {
+            encoder.long_distance_matching(true)?;
+        }
+        if let Some(window_log) = options.window_log {
+            encoder.window_log(window_log)?;
+        }
+        if let Some(strategy) = options.strategy {
+            encoder.set_parameter(CParameter::Strategy(strategy))?;
+        }
+        Ok(Box::new(ZstdWriter(encoder)))
+    }
+
+    fn decoder<'a>(&self, reader: Box<dyn BufRead + Send + 'a>) -> Result<Box<dyn Read + Send + 'a>> {
+        let mut decoder = ZstdDecoder::with_buffer(reader)?;
+        // Layers written with a window-log: above the default limit
+        decoder.window_log_max(31)?;
+        Ok(Box::new(decoder))
+    }
+}
+
+struct ZstdWriter(ZstdEncoder<'static, Box<dyn Write + Send>>);
+
+impl Write for ZstdWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Encoder for ZstdWriter {
+    fn finish(self: Box<Self>) -> Result<Finished> {
+        let mut writer = self.0.finish()?;
+        writer.flush()?;
+        Ok(Finished::default())
+    }
+}
+
+/// Plain tar layers.
+pub struct Uncompressed;
+
+impl Codec for Uncompressed {
+    fn name(&self) -> &'static str {
+        "none"
+    }
+
+    fn media_type_suffix(&self) -> &'static str {
+        ""
+    }
+
+    fn magic(&self) -> &'static [u8] {
+        &[]
+    }
+
+    fn default_level(&self) -> Option<u32> {
+        None
+    }
+
+    fn for_parents(&self) -> &'static dyn Codec {
+        &UNCOMPRESSED
+    }
+
+    fn encoder(
+        &self,
+        writer: Box<dyn Write + Send>,
+        _level: Option<u32>,
+        _threads: usize,
+        _options: &CompressionOptions,
+    ) -> Result<Box<dyn Encoder>> {
+        Ok(Box::new(PlainWriter(writer)))
+    }
+
+    fn decoder<'a>(&self, reader: Box<dyn BufRead + Send + 'a>) -> Result<Box<dyn Read + Send + 'a>> {
+        Ok(Box::new(reader))
+    }
+
+    fn compresses(&self) -> bool {
+        false
+    }
+}
+
+struct PlainWriter(Box<dyn Write + Send>);
+
+impl Write for PlainWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Encoder for PlainWriter {
+    fn finish(mut self: Box<Self>) -> Result<Finished> {
+        self.0.flush()?;
+        Ok(Finished::default())
+    }
+}
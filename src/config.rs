@@ -0,0 +1,1083 @@
+// Copyright (c) 2019, 2020 Codethink Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use serde::de::{self, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+
+use crate::id_map::IdMap;
+use crate::layer_builder::PathFilter;
+use crate::ownership::Ownership;
+use crate::platform::{self, Platform};
+use crate::{docker_archive, oci_archive, sbom};
+
+/// String-valued map used for annotations and labels
+pub type StringMap = BTreeMap<String, String>;
+
+/// Top-level build manifest read from stdin.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct BuildManifest {
+    /// Layout directory for this document, relative to the working directory
+    pub output: Option<PathBuf>,
+    /// Keep the entries of an existing index.json that this build does not replace
+    pub merge_index: Option<bool>,
+    pub compression: Option<String>,
+    pub compression_level: Option<u32>,
+    /// Advanced zstd parameters
+    pub compression_options: Option<CompressionOptionsSpec>,
+    /// Restart gzip compression at content-defined points, for delta transfers
+    pub gzip_rsyncable: Option<bool>,
+    /// Record codec, level and threads in layer descriptor annotations
+    pub compression_annotations: Option<bool>,
+    /// Record each image's build ID in its manifest annotations
+    pub build_id_annotation: Option<bool>,
+    /// Add the standard org.opencontainers.image.* annotations to every image
+    pub standard_annotations: Option<bool>,
+    pub skip_xattrs: Option<bool>,
+    /// Store computed checksums as `user.checksum.sha256` on source files
+    pub write_checksum_xattrs: Option<bool>,
+    /// Read layer sources with O_NOATIME where the kernel allows it
+    pub noatime: Option<bool>,
+    pub prefetch_limit_mb: Option<usize>,
+    /// Compressed layer data queued for its writer thread, per layer
+    pub persist_queue_mb: Option<usize>,
+    /// Retries of file system calls failing with ESTALE, EIO or ETIMEDOUT
+    pub io_retries: Option<u32>,
+    /// Wait before the first retry, doubled for each further one
+    pub io_retry_delay_ms: Option<u64>,
+    /// Bound on file contents mapped or cached at once across all layers
+    pub max_memory_mb: Option<usize>,
+    /// Bound on files mapped or parent layers streamed at once
+    pub max_open_files: Option<usize>,
+    /// Parent layer blobs in another compression: "recompress" (default) or "preserve"
+    pub parent_layers: Option<String>,
+    /// "always" hashes parent blobs before reusing them
+    pub verify_parents: Option<String>,
+    /// Layer tar format version to write
+    pub format_version: Option<u32>,
+    /// Keep decompressed lower layers in the user cache: "uncompressed" or "zstd"
+    pub lower_cache: Option<String>,
+    /// Directory of layer blobs kept across builds, reused when their inputs match
+    pub cache_dir: Option<PathBuf>,
+    /// HTTP store shared with other machines behind `cache-dir:`
+    pub remote_cache: Option<RemoteCacheSpec>,
+    /// Timestamp for file mtimes and `created`; overrides $SOURCE_DATE_EPOCH
+    pub source_date_epoch: Option<u64>,
+    /// Emit a per-layer file listing blob: "json" or "mtree"
+    pub layer_listing: Option<String>,
+    /// Emit a per-layer file index blob that speeds up builds using this layout as parent
+    pub layer_index: Option<bool>,
+    /// Write build-report.json with digests, sizes and timings of the images
+    pub build_report: Option<bool>,
+    /// Extra conventions to follow for other tools: "oci" (default) or "ggcr"
+    pub compatibility: Option<String>,
+    /// Scheduling priority of the build on shared machines
+    pub priority: Option<PrioritySpec>,
+    /// Ownership and permission checks run on every layer entry
+    #[serde(default)]
+    pub lint: Vec<LintRuleSpec>,
+    /// Sign the layout's digest manifest with GPG after building
+    pub gpg_sign: Option<GpgSignSpec>,
+    /// Layouts and registries the built layout is copied to afterwards
+    #[serde(default)]
+    pub publish: Vec<PublishSpec>,
+    /// Annotations on the OCI index
+    pub annotations: Option<StringMap>,
+    #[serde(default)]
+    pub images: Vec<ImageSpec>,
+}
+
+/// A single image to build.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ImageSpec {
+    pub architecture: String,
+    pub os: String,
+    #[serde(rename = "os.version")]
+    pub os_version: Option<String>,
+    #[serde(rename = "os.features")]
+    pub os_features: Option<Vec<String>>,
+    pub variant: Option<String>,
+    /// Platform `features`, reserved by the image spec; kept for manifests
+    /// written for Docker manifest lists
+    pub features: Option<Vec<String>>,
+    /// Further platform fields, passed through to the descriptor as they are
+    pub platform_fields: Option<BTreeMap<String, Value>>,
+    /// Groups images sharing a name and tag into a nested image index
+    pub name: Option<String>,
+    /// Tag of the nested image index (default "latest"); requires `name`
+    pub tag: Option<String>,
+    pub author: Option<String>,
+    pub comment: Option<String>,
+    /// Command or tool recorded as the image's history `created_by`
+    pub created_by: Option<String>,
+    /// Overrides the global source-date-epoch for this image
+    pub source_date_epoch: Option<u64>,
+    /// RFC 3339 `created` time of the config and history, instead of the
+    /// one derived from source-date-epoch; layer mtimes are unaffected
+    pub created: Option<String>,
+    /// Filesystem directory to pack as a layer
+    pub layer: Option<PathBuf>,
+    /// Directories packed as one layer each, bottom first
+    pub layers: Option<Vec<LayerEntry>>,
+    /// Compression of the image's own layers, instead of the document's
+    pub compression: Option<String>,
+    /// Tar file, optionally gzip or zstd compressed, added as the layer
+    pub layer_tar: Option<PathBuf>,
+    /// Dereference symlinks in `layer` or `layers` while walking them
+    #[serde(default)]
+    pub follow_symlinks: bool,
+    /// Directories followed symlinks may resolve into besides the layer's own
+    pub symlink_roots: Option<Vec<PathBuf>>,
+    /// Paths left out of `layer` or `layers`, in gitignore syntax
+    pub exclude: Option<Vec<String>>,
+    /// Paths kept from `layer` or `layers`, in gitignore syntax; the rest is left out
+    pub include_only: Option<Vec<String>>,
+    /// `from:to[:count]` rules rewriting the uids and gids of the image's own layers
+    pub id_map: Option<Vec<String>>,
+    /// Owner and mode overrides for paths of the image's own layers
+    pub ownership: Option<Vec<OwnershipSpec>>,
+    /// Trusted description of `layer`'s entries, used instead of walking it
+    pub layer_metadata: Option<PathBuf>,
+    /// overlayfs mount whose upper directory is packed as the layer
+    pub overlay: Option<OverlaySpec>,
+    pub parent: Option<ParentSpec>,
+    /// Lowers stacked above the parent's layers for deduplication, bottom first
+    pub dedup_lowers: Option<Vec<LowerSpec>>,
+    /// OCI image config, passed through as-is
+    pub config: Option<Value>,
+    /// Labels merged into `config.Labels`
+    pub labels: Option<StringMap>,
+    /// Annotations on the image manifest
+    pub annotations: Option<StringMap>,
+    /// Values of the standard annotations, with `standard-annotations`
+    pub source: Option<String>,
+    pub revision: Option<String>,
+    pub version: Option<String>,
+    pub licenses: Option<String>,
+    pub url: Option<String>,
+    pub annotations_file: Option<PathBuf>,
+    pub labels_file: Option<PathBuf>,
+    /// Annotations on the index entry for this manifest
+    pub index_annotations: Option<StringMap>,
+    /// File receiving the manifest digest once the image is built
+    pub digest_file: Option<PathBuf>,
+    /// Outputs of the image, joined with '+' (default "oci"); see [`OUTPUT_FORMATS`]
+    pub output_format: Option<String>,
+    /// Docker archive to write (default: `<output>/<os>-<architecture>[-<variant>].tar`)
+    pub archive_path: Option<PathBuf>,
+    /// OCI archive to write (default: `<output>/<os>-<architecture>[-<variant>].oci.tar`)
+    pub oci_archive_path: Option<PathBuf>,
+    /// Makes the image an artifact manifest of this type, holding `files:`
+    /// instead of a root filesystem
+    pub artifact_type: Option<String>,
+    /// Files of an artifact, one blob each
+    pub files: Option<Vec<ArtifactFile>>,
+    /// Position in `images:` of the image this one refers to, such as the
+    /// image an SBOM describes
+    pub subject: Option<usize>,
+    /// Software bill of materials of the image's layers, added as a layer;
+    /// with `subject:`, the entry is instead the SBOM artifact of its subject
+    pub sbom: Option<SbomSpec>,
+    pub config_patch: Option<json_patch::Patch>,
+    pub manifest_patch: Option<json_patch::Patch>,
+}
+
+/// A file of an artifact manifest.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ArtifactFile {
+    pub path: PathBuf,
+    /// Media type of the blob (default "application/octet-stream")
+    pub media_type: Option<String>,
+}
+
+/// Software bill of materials to generate.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SbomSpec {
+    /// "spdx" (default) or "cyclonedx"
+    pub format: Option<String>,
+    /// Also describe the parent's layers, not only the image's own
+    #[serde(default)]
+    pub parent: bool,
+    /// List every regular file with its sha256
+    #[serde(default)]
+    pub files: bool,
+}
+
+/// Outputs `output-format:` can combine: the output layout's index.json, a
+/// `docker load` tarball and a single-image OCI layout tarball
+pub const OUTPUT_FORMATS: &[&str] = &["oci", "docker-archive", "oci-archive"];
+
+/// A `layers:` entry: a directory, or a map that also gives its
+/// compression and marks it as a non-distributable layer fetched from `urls`.
+#[derive(Debug, Clone)]
+pub enum LayerEntry {
+    Dir(PathBuf),
+    Spec {
+        dir: PathBuf,
+        compression: Option<String>,
+        urls: Option<Vec<String>>,
+        nondistributable: bool,
+        /// Added to the image's `exclude` and `include-only` for this directory
+        exclude: Vec<String>,
+        include_only: Vec<String>,
+    },
+}
+
+/// The keys of a `layers:` entry given as a map.
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct LayerEntrySpec {
+    dir: PathBuf,
+    compression: Option<String>,
+    urls: Option<Vec<String>>,
+    #[serde(default)]
+    nondistributable: bool,
+    #[serde(default)]
+    exclude: Vec<String>,
+    #[serde(default)]
+    include_only: Vec<String>,
+}
+
+// By hand rather than untagged, so that errors within a map keep their key
+impl<'de> Deserialize<'de> for LayerEntry {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct EntryVisitor;
+
+        impl<'de> Visitor<'de> for EntryVisitor {
+            type Value = LayerEntry;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a directory or a map with its dir")
+            }
+
+            fn visit_str<E: de::Error>(self, dir: &str) -> Result<LayerEntry, E> {
+                Ok(LayerEntry::Dir(dir.into()))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<LayerEntry, A::Error> {
+                let spec = LayerEntrySpec::deserialize(de::value::MapAccessDeserializer::new(map))?;
+                Ok(LayerEntry::Spec {
+                    dir: spec.dir,
+                    compression: spec.compression,
+                    urls: spec.urls,
+                    nondistributable: spec.nondistributable,
+                    exclude: spec.exclude,
+                    include_only: spec.include_only,
+                })
+            }
+        }
+
+        deserializer.deserialize_any(EntryVisitor)
+    }
+}
+
+impl LayerEntry {
+    pub fn dir(&self) -> &Path {
+        match self {
+            LayerEntry::Dir(dir) | LayerEntry::Spec { dir, .. } => dir,
+        }
+    }
+
+    pub fn compression(&self) -> Option<&str> {
+        match self {
+            LayerEntry::Dir(_) => None,
+            LayerEntry::Spec { compression, .. } => compression.as_deref(),
+        }
+    }
+
+    /// URLs the layer's blob may be downloaded from instead of the registry.
+    pub fn urls(&self) -> &[String] {
+        match self {
+            LayerEntry::Spec { urls: Some(urls), .. } => urls,
+            _ => &[],
+        }
+    }
+
+    pub fn nondistributable(&self) -> bool {
+        matches!(self, LayerEntry::Spec { nondistributable: true, .. })
+    }
+
+    /// Paths of this directory left out or kept: the image's patterns, then its own.
+    pub fn filter(&self, image: &ImageSpec) -> PathFilter {
+        let mut filter = image.filter();
+        if let LayerEntry::Spec { exclude, include_only, .. } = self {
+            filter.exclude.extend(exclude.iter().cloned());
+            filter.include.extend(include_only.iter().cloned());
+        }
+        filter
+    }
+}
+
+impl ImageSpec {
+    /// The `exclude` and `include-only` patterns of the image's layer directories.
+    pub fn filter(&self) -> PathFilter {
+        PathFilter {
+            exclude: self.exclude.clone().unwrap_or_default(),
+            include: self.include_only.clone().unwrap_or_default(),
+        }
+    }
+
+    /// The parsed `id-map` rules, empty without any.
+    pub fn id_map(&self) -> Result<IdMap> {
+        IdMap::parse(self.id_map.as_deref().unwrap_or_default())
+    }
+
+    /// The parsed `ownership` rules, empty without any.
+    pub fn ownership(&self) -> Result<Ownership> {
+        Ownership::parse(self.ownership.as_deref().unwrap_or_default())
+    }
+
+    /// Number of layers the image adds on top of its parent's.
+    pub fn own_layers(&self) -> usize {
+        let layers = match self.layers {
+            Some(ref layers) => layers.len(),
+            None => usize::from(self.layer.is_some() || self.overlay.is_some() || self.layer_tar.is_some()),
+        };
+        layers + usize::from(self.sbom_layer())
+    }
+
+    /// Whether the image gets a layer holding its SBOM.
+    pub fn sbom_layer(&self) -> bool {
+        self.sbom.is_some() && self.subject.is_none()
+    }
+
+    /// Positions of the images that must be built before this one: its
+    /// `subject:` and the image its `parent.build` names.
+    pub fn dependencies(&self) -> impl Iterator<Item = usize> {
+        self.subject.into_iter().chain(self.parent.as_ref().and_then(|p| p.built))
+    }
+
+    /// Whether `output-format:` includes `format`.
+    pub fn writes(&self, format: &str) -> bool {
+        match self.output_format {
+            Some(ref formats) => formats.split('+').any(|part| part == format),
+            None => format == "oci",
+        }
+    }
+}
+
+/// Parent image to extend.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ParentSpec {
+    /// Path to the parent OCI layout directory; unset with `build`
+    #[serde(default)]
+    pub image: PathBuf,
+    /// `name` or `name:tag` of an earlier image of the manifest, for the
+    /// same platform, to build on instead of a layout
+    pub build: Option<String>,
+    /// Position in `images:` of the image `build` names, set by `parse`
+    #[serde(skip)]
+    pub built: Option<usize>,
+    /// Position of the image in the parent's index.json (default 0), counting
+    /// the images of nested indexes in place of the index
+    pub index: Option<usize>,
+    /// Digest of the image manifest, or of a nested index of the image
+    #[serde(rename = "ref")]
+    pub reference: Option<String>,
+    /// `org.opencontainers.image.ref.name` of the image or its nested index
+    pub tag: Option<String>,
+    /// `true` always copies (and re-encodes if needed) the parent's layers into
+    /// the output; `false` requires the parent to be the output layout, whose
+    /// blobs are then referenced in place. Unset, that happens automatically.
+    pub recompress: Option<bool>,
+}
+
+/// Extra lower layer used only for deduplication: a directory, or a tar
+/// file (optionally gzip or zstd compressed).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LowerSpec {
+    pub dir: Option<PathBuf>,
+    pub tar: Option<PathBuf>,
+}
+
+/// overlayfs mount to export the layer of: its mount point, or its upper
+/// directory and lowerdirs.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OverlaySpec {
+    /// Mount point, whose upperdir and lowerdir are read from the mount table
+    pub merged: Option<PathBuf>,
+    pub upper: Option<PathBuf>,
+    /// Lowerdirs, top first as in the `lowerdir` mount option
+    pub lowers: Option<Vec<PathBuf>>,
+}
+
+/// GPG key used to sign the layout's digest manifest.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GpgSignSpec {
+    /// Key reference: a keyring key ID, `file:`, `env:`, `pkcs11:` or a KMS URL
+    pub key: String,
+    /// GnuPG home directory for keyring keys (default: $GNUPGHOME or ~/.gnupg)
+    pub homedir: Option<PathBuf>,
+}
+
+/// Where `publish:` copies the built layout: another layout directory or a
+/// registry repository.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct PublishSpec {
+    /// Layout directory, relative to the working directory
+    pub layout: Option<PathBuf>,
+    /// `<registry>/<repository>[:<tag>]`, as for `build-oci push`
+    pub registry: Option<String>,
+    #[serde(default)]
+    pub plain_http: bool,
+    pub chunk_size_mb: Option<u64>,
+    /// Upload rate cap for the registry host, e.g. `2M`, as `--limit-rate`
+    pub limit_rate: Option<String>,
+    /// Requests in flight to the registry host at once
+    pub max_connections: Option<usize>,
+}
+
+/// HTTP server holding `cache-dir:` entries and blobs under the same paths.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct RemoteCacheSpec {
+    pub url: String,
+    /// Environment variable holding a bearer token
+    pub token_env: Option<String>,
+    /// Upload the layers this build makes (default: true)
+    pub push: Option<bool>,
+}
+
+/// Long-distance matching, window and strategy of zstd compression.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct CompressionOptionsSpec {
+    pub long: Option<bool>,
+    /// Base-2 log of the match window, 10-31
+    pub window_log: Option<u32>,
+    /// "fast" to "btultra2", as named by zstd
+    pub strategy: Option<String>,
+}
+
+/// Niceness, I/O class and CPU pinning of the build.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct PrioritySpec {
+    /// Nice level 0-19
+    pub nice: Option<u32>,
+    /// "best-effort" or "idle"
+    pub io_class: Option<String>,
+    /// Best-effort level 0-7 (default 4)
+    pub io_level: Option<u32>,
+    /// CPU list such as "0-3,8" for layer compression threads
+    pub cpus: Option<String>,
+}
+
+/// A lint rule: `max-uid`/`max-gid` (with `value`), `world-writable`,
+/// `setuid`, or `mode` (with `path` and octal `mode`).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LintRuleSpec {
+    pub rule: String,
+    /// "warn" or "fail" (default)
+    pub action: Option<String>,
+    pub value: Option<u64>,
+    pub path: Option<String>,
+    pub mode: Option<String>,
+}
+
+/// An `ownership:` rule: entries matching `path` (gitignore syntax) get
+/// the `uid`, `gid` and octal `mode` it sets.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OwnershipSpec {
+    pub path: String,
+    pub uid: Option<u64>,
+    pub gid: Option<u64>,
+    pub mode: Option<String>,
+}
+
+/// Syntax of the build manifest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ManifestFormat {
+    Yaml,
+    Json,
+    Toml,
+}
+
+impl ManifestFormat {
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "yaml" => Ok(Self::Yaml),
+            "json" => Ok(Self::Json),
+            "toml" => Ok(Self::Toml),
+            other => bail!("--format must be yaml, json or toml, got: {}", other),
+        }
+    }
+
+    /// Guess the format from the first line that is not blank or a comment:
+    /// `{` starts JSON, a `[table]` header or `key = value` starts TOML, and
+    /// anything else is YAML.
+    pub fn detect(input: &str) -> Self {
+        let Some(line) = input
+            .lines()
+            .map(str::trim)
+            .find(|l| !l.is_empty() && !l.starts_with('#'))
+        else {
+            return Self::Yaml;
+        };
+        if line.starts_with('{') {
+            return Self::Json;
+        }
+        let is_key = |key: &str| {
+            !key.is_empty()
+                && key
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '"' | ' '))
+        };
+        match line.split_once('=') {
+            _ if line.starts_with('[') => Self::Toml,
+            Some((key, _)) if is_key(key.trim()) && !line.contains(':') => Self::Toml,
+            _ => Self::Yaml,
+        }
+    }
+}
+
+/// Split the manifest input into documents: `---`-separated YAML documents,
+/// concatenated JSON values, or a single TOML document.
+pub fn read_documents(input: &str, format: ManifestFormat) -> Result<Vec<Value>> {
+    match format {
+        ManifestFormat::Yaml => serde_yaml::Deserializer::from_str(input)
+            .enumerate()
+            .map(|(i, document)| {
+                Value::deserialize(document).with_context(|| format!("Invalid YAML in document {}", i + 1))
+            })
+            .collect(),
+        ManifestFormat::Json => serde_json::Deserializer::from_str(input)
+            .into_iter::<Value>()
+            .enumerate()
+            .map(|(i, document)| document.with_context(|| format!("Invalid JSON in document {}", i + 1)))
+            .collect(),
+        ManifestFormat::Toml => {
+            let document: Value = toml::from_str(input).context("Invalid TOML")?;
+            Ok(vec![document])
+        }
+    }
+}
+
+/// Expand includes and environment variables, then validate and deserialize
+/// the build manifest. Relative `include:` paths are resolved against `base_dir`.
+pub fn parse(mut data: Value, base_dir: &Path) -> Result<BuildManifest> {
+    expand(&mut data, base_dir)?;
+    // An empty document is a valid (if useless) manifest
+    if data.is_null() {
+        return Ok(BuildManifest::default());
+    }
+    // Already merged into every image, but checked on its own so that its
+    // mistakes are reported where they were made
+    if let Some(defaults) = data.as_object_mut().and_then(|map| map.remove("defaults")) {
+        check_defaults(defaults).context("Invalid build manifest")?;
+    }
+    let mut manifest: BuildManifest = serde_path_to_error::deserialize(data).context("Invalid build manifest")?;
+    if manifest.persist_queue_mb == Some(0) {
+        bail!("persist-queue-mb: must be at least 1");
+    }
+    for (i, destination) in manifest.publish.iter().enumerate() {
+        if destination.layout.is_some() == destination.registry.is_some() {
+            bail!("publish[{}]: give either layout or registry", i);
+        }
+        if destination.chunk_size_mb == Some(0) {
+            bail!("publish[{}].chunk-size-mb: must be at least 1", i);
+        }
+        if let Some(ref rate) = destination.limit_rate {
+            crate::registry::parse_rate(rate).with_context(|| format!("publish[{}].limit-rate", i))?;
+        }
+        if destination.max_connections == Some(0) {
+            bail!("publish[{}].max-connections: must be at least 1", i);
+        }
+    }
+    // An SBOM with a subject is an artifact of the SBOM's media type
+    for (i, image) in manifest.images.iter_mut().enumerate() {
+        let Some(ref sbom) = image.sbom else { continue };
+        let format = sbom::Format::parse(sbom.format.as_deref()).with_context(|| format!("images[{}].sbom.format", i))?;
+        if image.subject.is_some() {
+            if image.files.is_some() {
+                bail!("images[{}].sbom: cannot be combined with files", i);
+            }
+            image.artifact_type.get_or_insert_with(|| format.media_type().to_string());
+        } else if image.artifact_type.is_some() {
+            bail!("images[{}].sbom: an artifact needs a subject to describe", i);
+        }
+    }
+    // Archives written so far and the image writing each
+    let mut archives: Vec<(PathBuf, usize)> = Vec::new();
+    for (i, image) in manifest.images.iter().enumerate() {
+        if image.tag.is_some() && image.name.is_none() {
+            bail!("images[{}].tag: requires a name", i);
+        }
+        platform::check_fields(i, image)?;
+        if image.layer_metadata.is_some() && image.layer.is_none() {
+            bail!("images[{}].layer-metadata: requires a layer", i);
+        }
+        if let Some(ref layers) = image.layers {
+            if image.layer.is_some() || image.overlay.is_some() {
+                bail!("images[{}].layers: cannot be combined with layer or overlay", i);
+            }
+            if layers.is_empty() {
+                bail!("images[{}].layers: must list at least one directory", i);
+            }
+            for (j, layer) in layers.iter().enumerate() {
+                check_compression(layer.compression(), &format!("images[{}].layers[{}].compression", i, j))?;
+                if let Some(url) = layer.urls().iter().find(|url| !url.starts_with("http://") && !url.starts_with("https://")) {
+                    bail!("images[{}].layers[{}].urls: must be http or https URLs, got: {}", i, j, url);
+                }
+                layer.filter(image).check().with_context(|| format!("images[{}].layers[{}]", i, j))?;
+            }
+        }
+        check_compression(image.compression.as_deref(), &format!("images[{}].compression", i))?;
+        if image.layer_tar.is_some() && (image.layer.is_some() || image.layers.is_some() || image.overlay.is_some()) {
+            bail!("images[{}].layer-tar: cannot be combined with layer, layers or overlay", i);
+        }
+        if image.follow_symlinks && (image.layer.is_none() && image.layers.is_none() || image.layer_metadata.is_some()) {
+            bail!("images[{}].follow-symlinks: requires layer or layers, without layer-metadata", i);
+        }
+        if image.symlink_roots.is_some() && !image.follow_symlinks {
+            bail!("images[{}].symlink-roots: requires follow-symlinks", i);
+        }
+        for (key, patterns) in [("exclude", &image.exclude), ("include-only", &image.include_only)] {
+            if patterns.is_some() && (image.layer.is_none() && image.layers.is_none() || image.layer_metadata.is_some()) {
+                bail!("images[{}].{}: requires layer or layers, without layer-metadata", i, key);
+            }
+        }
+        image.filter().check().with_context(|| format!("images[{}]", i))?;
+        if image.id_map.is_some() {
+            if image.layer.is_none() && image.layers.is_none() && image.overlay.is_none() {
+                bail!("images[{}].id-map: requires layer, layers or overlay", i);
+            }
+            image.id_map().with_context(|| format!("images[{}].id-map", i))?;
+        }
+        if image.ownership.is_some() {
+            if image.layer.is_none() && image.layers.is_none() && image.overlay.is_none() {
+                bail!("images[{}].ownership: requires layer, layers or overlay", i);
+            }
+            image.ownership().with_context(|| format!("images[{}]", i))?;
+        }
+        if let Some(ref format) = image.output_format {
+            if format.split('+').any(|part| !OUTPUT_FORMATS.contains(&part)) {
+                bail!(
+                    "images[{}].output-format: must be {} or several joined with '+', got: {}",
+                    i,
+                    OUTPUT_FORMATS.join(", "),
+                    format
+                );
+            }
+        }
+        let archive_paths = [
+            ("docker-archive", "archive-path", &image.archive_path, docker_archive::default_name(image)),
+            ("oci-archive", "oci-archive-path", &image.oci_archive_path, oci_archive::default_name(image)),
+        ];
+        for (format, key, path, default_name) in archive_paths {
+            if !image.writes(format) {
+                if path.is_some() {
+                    bail!("images[{}].{}: requires the {} output-format", i, key, format);
+                }
+                continue;
+            }
+            let archive = path.clone().unwrap_or_else(|| default_name.into());
+            if let Some((_, j)) = archives.iter().find(|(other, _)| *other == archive) {
+                bail!("images[{}]: writes the same archive as images[{}]; set {}", i, j, key);
+            }
+            archives.push((archive, i));
+        }
+        if let Some(ref overlay) = image.overlay {
+            if image.layer.is_some() {
+                bail!("images[{}].overlay: cannot be combined with layer", i);
+            }
+            if overlay.merged.is_some() == overlay.upper.is_some() {
+                bail!("images[{}].overlay: needs exactly one of merged and upper", i);
+            }
+            if overlay.merged.is_some() && overlay.lowers.is_some() {
+                bail!("images[{}].overlay.lowers: requires upper", i);
+            }
+        }
+        for (j, lower) in image.dedup_lowers.iter().flatten().enumerate() {
+            if lower.dir.is_some() == lower.tar.is_some() {
+                bail!("images[{}].dedup-lowers[{}]: needs exactly one of dir and tar", i, j);
+            }
+        }
+        if let Some(ref parent) = image.parent {
+            let selectors = [parent.index.is_some(), parent.reference.is_some(), parent.tag.is_some()];
+            if selectors.iter().filter(|&&set| set).count() > 1 {
+                bail!("images[{}].parent: index, ref and tag cannot be combined", i);
+            }
+            if parent.reference.as_ref().is_some_and(|r| !r.contains(':')) {
+                bail!("images[{}].parent.ref: expected a digest such as sha256:<hex>", i);
+            }
+            let has_image = !parent.image.as_os_str().is_empty();
+            if parent.build.is_some() == has_image {
+                bail!("images[{}].parent: needs exactly one of image and build", i);
+            }
+            if parent.build.is_some() && (selectors.contains(&true) || parent.recompress.is_some()) {
+                bail!(
+                    "images[{}].parent.build: cannot be combined with index, ref, tag or recompress; \
+                     the layers of a built parent are always used in place",
+                    i
+                );
+            }
+        }
+        if let Some(ref created) = image.created {
+            if let Err(e) = chrono::DateTime::parse_from_rfc3339(created) {
+                bail!("images[{}].created: expected an RFC 3339 time such as 2024-05-01T12:00:00Z, got '{}': {}", i, created, e);
+            }
+        }
+        if let Some(ref labels) = image.labels {
+            let inline = image.config.as_ref().map(|config| &config["Labels"]).filter(|l| !l.is_null());
+            match inline.map(Value::as_object) {
+                Some(None) => bail!("images[{}].labels: config.Labels must be a map to merge into", i),
+                Some(Some(inline)) => {
+                    for (key, value) in labels {
+                        if let Some(other) = inline.get(key).filter(|other| other.as_str() != Some(value)) {
+                            bail!(
+                                "images[{}].labels.{}: '{}' conflicts with config.Labels value {}",
+                                i,
+                                key,
+                                value,
+                                other
+                            );
+                        }
+                    }
+                }
+                None => {}
+            }
+        }
+        if image.artifact_type.is_some() {
+            let filesystem = [
+                image.layer.is_some(),
+                image.layers.is_some(),
+                image.layer_tar.is_some(),
+                image.overlay.is_some(),
+                image.parent.is_some(),
+                image.dedup_lowers.is_some(),
+                image.config.is_some(),
+                image.labels.is_some(),
+                image.labels_file.is_some(),
+                image.config_patch.is_some(),
+            ];
+            if filesystem.contains(&true) {
+                bail!(
+                    "images[{}].artifact-type: cannot be combined with layer, layers, layer-tar, overlay, \
+                     parent, dedup-lowers, config, labels, labels-file or config-patch",
+                    i
+                );
+            }
+            if image.writes("docker-archive") {
+                bail!("images[{}].artifact-type: artifacts cannot be written as a docker-archive", i);
+            }
+        } else if image.files.is_some() {
+            bail!("images[{}].files: requires artifact-type", i);
+        }
+        if let Some(subject) = image.subject {
+            let Some(target) = manifest.images.get(subject) else {
+                bail!("images[{}].subject: there is no images[{}]", i, subject);
+            };
+            if image.sbom.is_some() && target.artifact_type.is_some() {
+                bail!("images[{}].sbom: its subject images[{}] is an artifact, without layers", i, subject);
+            }
+            if !target.writes("oci") {
+                bail!("images[{}].subject: images[{}] is not written to the layout", i, subject);
+            }
+            // Subjects are built first, so they cannot loop back
+            let mut next = Some(subject);
+            for _ in 0..manifest.images.len() {
+                match next {
+                    Some(j) if j == i => bail!("images[{}].subject: images[{}] refers back to this image", i, subject),
+                    Some(j) => next = manifest.images[j].subject,
+                    None => break,
+                }
+            }
+        }
+    }
+    for i in 0..manifest.images.len() {
+        let image = &manifest.images[i];
+        let Some(name) = image.parent.as_ref().and_then(|p| p.build.as_deref()) else { continue };
+        let built = resolve_built_parent(&manifest.images[..i], image, name)
+            .with_context(|| format!("images[{}].parent.build", i))?;
+        if let Some(parent) = manifest.images[i].parent.as_mut() {
+            parent.built = Some(built);
+        }
+    }
+    // Subjects and built parents come first, so together they cannot loop back either
+    for i in 0..manifest.images.len() {
+        let mut pending: Vec<usize> = manifest.images[i].dependencies().collect();
+        let mut seen = vec![false; manifest.images.len()];
+        while let Some(j) = pending.pop() {
+            if j == i {
+                bail!("images[{}]: its subject and parent.build lead back to this image", i);
+            }
+            if !std::mem::replace(&mut seen[j], true) {
+                pending.extend(manifest.images[j].dependencies());
+            }
+        }
+    }
+    Ok(manifest)
+}
+
+/// Position among `earlier` of the image `parent.build: name` refers to: the
+/// one named `name` or `name:tag` whose platform `image` can build on.
+fn resolve_built_parent(earlier: &[ImageSpec], image: &ImageSpec, name: &str) -> Result<usize> {
+    let wanted = Platform::of(image);
+    let matches: Vec<usize> = earlier
+        .iter()
+        .enumerate()
+        .filter(|(_, other)| {
+            let Some(ref other_name) = other.name else { return false };
+            let tag = other.tag.as_deref().unwrap_or("latest");
+            (other_name == name || format!("{}:{}", other_name, tag) == name) && Platform::of(other).satisfies(&wanted)
+        })
+        .map(|(j, _)| j)
+        .collect();
+    match matches[..] {
+        [j] if earlier[j].artifact_type.is_some() => bail!("images[{}] is an artifact, without layers", j),
+        [j] => Ok(j),
+        [] => bail!(
+            "no earlier image is named '{}' for {}/{}",
+            name,
+            image.os,
+            image.architecture
+        ),
+        [first, second, ..] => bail!(
+            "images[{}] and images[{}] are both named '{}' for {}/{}; give the name:tag of one",
+            first,
+            second,
+            name,
+            image.os,
+            image.architecture
+        ),
+    }
+}
+
+/// Check a per-image or per-layer `compression:` value.
+fn check_compression(compression: Option<&str>, path: &str) -> Result<()> {
+    match compression {
+        None | Some("gzip" | "zstd" | "estargz" | "zstd-chunked" | "disabled") => Ok(()),
+        Some(other) => bail!("{}: must be gzip, zstd, estargz, zstd-chunked or disabled, got: {}", path, other),
+    }
+}
+
+/// Resolve `include:` keys, expand environment variables and merge
+/// `defaults:` into every image.
+pub fn expand(data: &mut Value, base_dir: &Path) -> Result<()> {
+    resolve_includes(data, base_dir, &mut Vec::new(), "")?;
+    interpolate(data, "")?;
+    apply_defaults(data);
+    Ok(())
+}
+
+/// Replace the `include:` key of the document, and of each of its
+/// `images:`, with the contents of the named files (a path or a list of
+/// paths), deep-merged under the map's own keys. Maps elsewhere are data, so
+/// a label or annotation named `include` is kept as it is. Later files
+/// override earlier ones and keys set next to `include:` override both. Paths
+/// are relative to the directory of the including file, and included files
+/// may include others; `stack` holds the files being included.
+fn resolve_includes(value: &mut Value, base_dir: &Path, stack: &mut Vec<PathBuf>, path: &str) -> Result<()> {
+    let Value::Object(map) = value else {
+        return Ok(());
+    };
+    if path.is_empty() {
+        if let Some(Value::Array(images)) = map.get_mut("images") {
+            for (i, image) in images.iter_mut().enumerate() {
+                resolve_includes(image, base_dir, stack, &format!("images[{}]", i))?;
+            }
+        }
+    }
+    let Some(include) = map.remove("include") else {
+        return Ok(());
+    };
+
+    let key = child_path(path, "include");
+    let files = match include {
+        Value::String(file) => vec![file],
+        Value::Array(list) => list
+            .into_iter()
+            .map(|file| match file {
+                Value::String(file) => Ok(file),
+                _ => bail!("{}: expected a list of strings", key),
+            })
+            .collect::<Result<_>>()?,
+        _ => bail!("{}: expected a string or a list of strings", key),
+    };
+    for file in files.iter().rev() {
+        let fragment = load_include(file, base_dir, stack).with_context(|| format!("{}: including {}", key, file))?;
+        merge_defaults(value, &fragment);
+    }
+    Ok(())
+}
+
+/// Read one included file, resolving its own includes.
+fn load_include(file: &str, base_dir: &Path, stack: &mut Vec<PathBuf>) -> Result<Value> {
+    let file = base_dir.join(expand_env(file)?);
+    let file = file.canonicalize().with_context(|| format!("Reading {}", file.display()))?;
+    if let Some(pos) = stack.iter().position(|p| *p == file) {
+        let chain: Vec<String> = stack[pos..].iter().chain([&file]).map(|p| p.display().to_string()).collect();
+        bail!("Include cycle: {}", chain.join(" -> "));
+    }
+
+    let input = std::fs::read_to_string(&file).with_context(|| format!("Reading {}", file.display()))?;
+    let mut documents = read_documents(&input, ManifestFormat::detect(&input))?;
+    if documents.len() != 1 || !documents[0].is_object() {
+        bail!("{} must contain a single map", file.display());
+    }
+    let mut fragment = documents.remove(0);
+
+    let dir = file.parent().unwrap_or(Path::new("/")).to_path_buf();
+    stack.push(file);
+    let result = resolve_includes(&mut fragment, &dir, stack, "");
+    stack.pop();
+    result.map(|()| fragment)
+}
+
+/// Check `defaults:` as an image, which may leave out the keys every image needs.
+fn check_defaults(mut defaults: Value) -> Result<(), serde_path_to_error::Error<serde_json::Error>> {
+    /// Keeps `defaults` in the paths of errors
+    #[derive(Deserialize)]
+    struct Defaults {
+        #[serde(rename = "defaults")]
+        _image: ImageSpec,
+    }
+
+    if let Some(map) = defaults.as_object_mut() {
+        for key in ["architecture", "os"] {
+            map.entry(key).or_insert_with(|| Value::String(String::new()));
+        }
+    }
+    serde_path_to_error::deserialize::<_, Defaults>(serde_json::json!({ "defaults": defaults })).map(drop)
+}
+
+/// `parent.name`, or `name` at the top of the document.
+fn child_path(parent: &str, name: &str) -> String {
+    if parent.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}.{}", parent, name)
+    }
+}
+
+/// Deep-merge the `defaults:` map into every entry of `images:`.
+fn apply_defaults(data: &mut Value) {
+    let Some(defaults) = data.get("defaults").filter(|d| d.is_object()).cloned() else {
+        return;
+    };
+    if let Some(images) = data.get_mut("images").and_then(Value::as_array_mut) {
+        for image in images {
+            merge_defaults(image, &defaults);
+        }
+    }
+}
+
+/// Fill in keys missing from `target` with those of `defaults`, recursing
+/// into maps present in both. Values set in `target` (including lists) win.
+fn merge_defaults(target: &mut Value, defaults: &Value) {
+    let (Some(target), Some(defaults)) = (target.as_object_mut(), defaults.as_object()) else {
+        return;
+    };
+    for (key, default) in defaults {
+        match target.get_mut(key) {
+            Some(value) => merge_defaults(value, default),
+            None => {
+                target.insert(key.clone(), default.clone());
+            }
+        }
+    }
+}
+
+/// Expand `${VAR}` and `${VAR:-default}` in every string value of the document.
+/// `$${` produces a literal `${`. Map keys are left untouched.
+fn interpolate(value: &mut Value, path: &str) -> Result<()> {
+    match value {
+        Value::String(s) if s.contains("${") => {
+            *s = expand_env(s)
+                .with_context(|| format!("{}: expanding environment variables", path))?;
+        }
+        Value::Array(list) => {
+            for (i, v) in list.iter_mut().enumerate() {
+                interpolate(v, &format!("{}[{}]", path, i))?;
+            }
+        }
+        Value::Object(map) => {
+            for (k, v) in map.iter_mut() {
+                interpolate(v, &child_path(path, k))?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn expand_env(input: &str) -> Result<String> {
+    let mut out = String::with_capacity(input.len());
+    let mut rest = input;
+
+    while let Some(pos) = rest.find("${") {
+        // `$${` escapes the expansion
+        if rest[..pos].ends_with('$') {
+            out.push_str(&rest[..pos - 1]);
+            out.push_str("${");
+            rest = &rest[pos + 2..];
+            continue;
+        }
+        out.push_str(&rest[..pos]);
+
+        let after = &rest[pos + 2..];
+        let end = after
+            .find('}')
+            .ok_or_else(|| anyhow::anyhow!("Unterminated '${{' in '{}'", input))?;
+        let expr = &after[..end];
+        let (name, default) = match expr.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (expr, None),
+        };
+        if name.is_empty() {
+            bail!("Empty variable name in '{}'", input);
+        }
+
+        // Like the shell, `:-` also applies the default when the variable is empty
+        let value = match std::env::var(name) {
+            Ok(v) if !v.is_empty() || default.is_none() => Some(v),
+            Ok(_) | Err(std::env::VarError::NotPresent) => None,
+            Err(std::env::VarError::NotUnicode(_)) => {
+                bail!("Environment variable '{}' is not valid UTF-8", name);
+            }
+        };
+        match (value, default) {
+            (Some(v), _) => out.push_str(&v),
+            (None, Some(d)) => out.push_str(d),
+            (None, None) => bail!("Environment variable '{}' is not set", name),
+        }
+        rest = &after[end + 1..];
+    }
+
+    out.push_str(rest);
+    Ok(out)
+}
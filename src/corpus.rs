@@ -0,0 +1,168 @@
+// Copyright (c) 2019, 2020 Codethink Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! `build-oci test-corpus`: write a root file system full of the cases layer
+//! builders and their consumers tend to get wrong, for the integration tests
+//! and for anyone checking a consumer of the images. Cases the file system
+//! does not allow (user xattrs on older tmpfs, large xattr values on ext4)
+//! are skipped and reported as such.
+
+use std::fs::{self, File};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use clap::ArgMatches;
+
+use crate::cli;
+
+/// A group of related entries, written under a directory of its name.
+struct Case {
+    name: &'static str,
+    write: fn(&Path) -> io::Result<()>,
+}
+
+const CASES: &[Case] = &[
+    Case { name: "long-paths", write: long_paths },
+    Case { name: "sparse", write: sparse },
+    Case { name: "hardlinks", write: hardlinks },
+    Case { name: "xattrs", write: xattrs },
+    Case { name: "symlinks", write: symlinks },
+    Case { name: "modes", write: modes },
+];
+
+/// `build-oci test-corpus <dir>`: write every case into `dir`, which must
+/// not exist yet or be empty, and print whether each was written or skipped.
+pub fn run(args: &ArgMatches) -> Result<()> {
+    let root = Path::new(cli::required(args, "dir"));
+    if root.exists() && fs::read_dir(root)?.next().is_some() {
+        bail!("{} is not empty", root.display());
+    }
+    fs::create_dir_all(root).with_context(|| format!("Creating {}", root.display()))?;
+
+    for case in CASES {
+        let dir = root.join(case.name);
+        fs::create_dir(&dir).with_context(|| format!("Creating {}", dir.display()))?;
+        match (case.write)(&dir) {
+            Ok(()) => println!("{}: written", case.name),
+            Err(err) if is_unsupported(&err) => println!("{}: skipped ({})", case.name, err),
+            Err(err) => return Err(err).with_context(|| format!("Writing {}", dir.display())),
+        }
+    }
+    Ok(())
+}
+
+/// Errors for what this file system or user cannot do, rather than failures.
+fn is_unsupported(err: &io::Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(libc::EPERM | libc::EACCES | libc::ENOTSUP | libc::ENOSPC | libc::E2BIG | libc::ERANGE)
+    )
+}
+
+/// Paths beyond the 100 bytes of a ustar name field, and names of the
+/// 255-byte maximum.
+fn long_paths(dir: &Path) -> io::Result<()> {
+    let mut deep = dir.to_path_buf();
+    for i in 0..6 {
+        deep.push(format!("{}{}", i, "d".repeat(59)));
+    }
+    fs::create_dir_all(&deep)?;
+    fs::write(deep.join(format!("{}.txt", "f".repeat(200))), "deep\n")?;
+    fs::write(dir.join("n".repeat(255)), "longest name\n")?;
+    symlink(deep.strip_prefix(dir).unwrap_or(&deep).join("x".repeat(120)), dir.join("long-target"))?;
+    Ok(())
+}
+
+/// Files that are mostly holes.
+fn sparse(dir: &Path) -> io::Result<()> {
+    let mut file = File::create(dir.join("holes.bin"))?;
+    file.write_all(b"start")?;
+    file.seek(SeekFrom::Start(64 << 20))?;
+    file.write_all(b"end")?;
+    File::create(dir.join("all-hole.bin"))?.set_len(16 << 20)?;
+    Ok(())
+}
+
+/// Hard links across directories, to empty files, and links of links.
+fn hardlinks(dir: &Path) -> io::Result<()> {
+    for sub in ["a", "b", "c/d"] {
+        fs::create_dir_all(dir.join(sub))?;
+    }
+    fs::write(dir.join("a/original"), "shared\n")?;
+    fs::hard_link(dir.join("a/original"), dir.join("a/link1"))?;
+    fs::hard_link(dir.join("a/link1"), dir.join("b/link2"))?;
+    fs::hard_link(dir.join("b/link2"), dir.join("c/d/link3"))?;
+    fs::write(dir.join("b/empty"), "")?;
+    fs::hard_link(dir.join("b/empty"), dir.join("c/empty"))?;
+    // Two webs that meet in one directory
+    fs::write(dir.join("c/second"), "second\n")?;
+    fs::hard_link(dir.join("c/second"), dir.join("a/second"))?;
+    Ok(())
+}
+
+/// A large xattr value, as large as the file system takes, and many small ones.
+fn xattrs(dir: &Path) -> io::Result<()> {
+    let big = dir.join("big");
+    fs::write(&big, "big xattr\n")?;
+    let mut sizes = [65536, 16384, 4000, 1024].into_iter().peekable();
+    while let Some(size) = sizes.next() {
+        match xattr::set(&big, "user.big", &vec![b'x'; size]) {
+            Ok(()) => break,
+            Err(err) if is_unsupported(&err) && sizes.peek().is_some() => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    let many = dir.join("many");
+    fs::write(&many, "many xattrs\n")?;
+    for i in 0..100 {
+        xattr::set(&many, format!("user.key{:03}", i), format!("value {}", i).as_bytes())?;
+    }
+    fs::create_dir(dir.join("dir"))?;
+    xattr::set(dir.join("dir"), "user.on-dir", b"yes")?;
+    Ok(())
+}
+
+/// Dangling, absolute, directory and looping symlinks.
+fn symlinks(dir: &Path) -> io::Result<()> {
+    fs::create_dir(dir.join("target-dir"))?;
+    symlink("does-not-exist", dir.join("dangling"))?;
+    symlink("/etc/passwd", dir.join("absolute"))?;
+    symlink("target-dir", dir.join("to-dir"))?;
+    symlink("../symlinks/./target-dir/..", dir.join("dotted"))?;
+    symlink("loop-b", dir.join("loop-a"))?;
+    symlink("loop-a", dir.join("loop-b"))?;
+    Ok(())
+}
+
+/// Special permission bits and an empty directory.
+fn modes(dir: &Path) -> io::Result<()> {
+    for (name, mode) in [("setuid", 0o4755), ("setgid", 0o2755), ("read-only", 0o444)] {
+        let path = dir.join(name);
+        fs::write(&path, format!("{}\n", name))?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(mode))?;
+    }
+    let sticky = dir.join("sticky");
+    fs::create_dir(&sticky)?;
+    fs::set_permissions(&sticky, fs::Permissions::from_mode(0o1777))?;
+    fs::create_dir(dir.join("empty"))?;
+    Ok(())
+}
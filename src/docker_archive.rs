@@ -0,0 +1,140 @@
+// Copyright (c) 2019, 2020 Codethink Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! `docker load` compatible tarballs of built images: `manifest.json`,
+//! `repositories`, the image config and one uncompressed tar per layer.
+
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, BufWriter, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+use tracing::info;
+
+use crate::codec;
+use crate::config::ImageSpec;
+use crate::layout::{descriptor_digest, descriptor_size, Layout};
+use crate::GlobalConfig;
+
+/// Archive file name used without `archive-path:`: `<os>-<architecture>[-<variant>].tar`.
+pub fn default_name(image: &ImageSpec) -> String {
+    match image.variant {
+        Some(ref variant) => format!("{}-{}-{}.tar", image.os, image.architecture, variant),
+        None => format!("{}-{}.tar", image.os, image.architecture),
+    }
+}
+
+/// Where the docker archive of `image` is written.
+pub fn path(image: &ImageSpec, output: &Path) -> PathBuf {
+    image.archive_path.clone().unwrap_or_else(|| output.join(default_name(image)))
+}
+
+/// Write the docker archive of the image whose manifest `manifest_desc` is
+/// in the output layout, tagged with the image's `name:tag` if it has a name.
+pub fn write(global_conf: &GlobalConfig, image: &ImageSpec, manifest_desc: &Value) -> Result<PathBuf> {
+    let output = Path::new(&global_conf.output);
+    let layout = Layout::building(output);
+    let manifest = layout.read_json(descriptor_digest(manifest_desc)?)?;
+    let config_digest = descriptor_digest(&manifest["config"])?;
+    let config_bytes = fs::read(layout.blob_path(config_digest)?)?;
+    let config: Value = serde_json::from_slice(&config_bytes).context("Parsing image config")?;
+    let layers = manifest["layers"].as_array().map(Vec::as_slice).unwrap_or_default();
+    let diff_ids: Vec<&str> = config["rootfs"]["diff_ids"].as_array().into_iter().flatten().filter_map(Value::as_str).collect();
+    if diff_ids.len() != layers.len() {
+        bail!("Image config lists {} diff_ids for {} layers", diff_ids.len(), layers.len());
+    }
+
+    let path = path(image, output);
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let archive_file = tempfile::NamedTempFile::new_in(dir).with_context(|| format!("Creating {}", path.display()))?;
+    let mut archive = tar::Builder::new(BufWriter::new(archive_file.as_file()));
+    let mtime = global_conf.source_date_epoch.unwrap_or(0);
+
+    let config_name = format!("{}.json", hex(config_digest));
+    append(&mut archive, &config_name, config_bytes.len() as u64, mtime, &config_bytes[..])?;
+
+    let mut layer_names = Vec::with_capacity(layers.len());
+    let mut written = HashSet::new();
+    let tmp_dir = output.join(".tmp");
+    fs::create_dir_all(&tmp_dir)?;
+    for (desc, diff_id) in layers.iter().zip(&diff_ids) {
+        let name = format!("{}/layer.tar", hex(diff_id));
+        layer_names.push(name.clone());
+        // A layer repeated in the image is stored once
+        if !written.insert(name.clone()) {
+            continue;
+        }
+        let media_type = desc["mediaType"].as_str().unwrap_or_default();
+        if codec::for_media_type(media_type).compresses() {
+            // Entries need their size up front, so compressed layers are
+            // unpacked to a temporary file first
+            let mut unpacked = tempfile::tempfile_in(&tmp_dir)?;
+            let size = io::copy(&mut layout.open_layer(desc)?, &mut unpacked)
+                .with_context(|| format!("Decompressing layer {}", descriptor_digest(desc).unwrap_or_default()))?;
+            unpacked.rewind()?;
+            append(&mut archive, &name, size, mtime, unpacked)?;
+        } else {
+            append(&mut archive, &name, descriptor_size(desc), mtime, layout.open_layer(desc)?)?;
+        }
+    }
+
+    let repo_tag = image
+        .name
+        .as_ref()
+        .map(|name| (name.as_str(), image.tag.as_deref().unwrap_or("latest")));
+    let docker_manifest = json!([{
+        "Config": config_name,
+        "RepoTags": repo_tag.map(|(name, tag)| format!("{}:{}", name, tag)).into_iter().collect::<Vec<_>>(),
+        "Layers": layer_names,
+    }]);
+    let bytes = serde_json::to_vec(&docker_manifest)?;
+    append(&mut archive, "manifest.json", bytes.len() as u64, mtime, &bytes[..])?;
+    // Read by older docker versions only: the top layer of each tag
+    if let (Some((name, tag)), Some(top)) = (repo_tag, diff_ids.last()) {
+        let bytes = serde_json::to_vec(&json!({ name: { tag: hex(top) } }))?;
+        append(&mut archive, "repositories", bytes.len() as u64, mtime, &bytes[..])?;
+    }
+
+    archive.into_inner()?.flush()?;
+    archive_file.persist(&path).with_context(|| format!("Writing {}", path.display()))?;
+    info!(path = %path.display(), "wrote docker archive");
+    Ok(path)
+}
+
+/// Hex part of a `sha256:<hex>` digest.
+fn hex(digest: &str) -> &str {
+    digest.split_once(':').map_or(digest, |(_, hex)| hex)
+}
+
+/// Append a regular file entry owned by root.
+pub fn append<W: Write, R: Read>(archive: &mut tar::Builder<W>, path: &str, size: u64, mtime: u64, data: R) -> Result<()> {
+    let mut header = tar::Header::new_ustar();
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_size(size);
+    header.set_mode(0o644);
+    header.set_mtime(mtime);
+    header.set_cksum();
+    archive.append_data(&mut header, path, data).with_context(|| format!("Writing {} to the archive", path))
+}
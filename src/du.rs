@@ -0,0 +1,157 @@
+// Copyright (c) 2019, 2020 Codethink Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use clap::ArgMatches;
+use rayon::prelude::*;
+use serde_json::Value;
+
+use crate::cli;
+use crate::layout::{descriptor_digest, descriptor_size, parse_image_ref, Layout};
+use crate::util::format_size;
+
+/// Uncompressed size and file count of a set of tar entries.
+#[derive(Debug, Default, Clone, Copy)]
+struct Usage {
+    size: u64,
+    files: u64,
+}
+
+impl Usage {
+    fn add(&mut self, other: Usage) {
+        self.size += other.size;
+        self.files += other.files;
+    }
+
+    fn to_json(self) -> Value {
+        serde_json::json!({ "size": self.size, "files": self.files })
+    }
+}
+
+struct LayerUsage {
+    digest: String,
+    compressed_size: u64,
+    total: Usage,
+    /// Usage keyed by top-level directory (or file name for entries at the root)
+    by_dir: BTreeMap<String, Usage>,
+}
+
+/// `build-oci du <layout>[:<ref>] [--json]`: uncompressed size breakdown
+/// per layer and per top-level directory, streamed without extraction.
+pub fn run(args: &ArgMatches) -> Result<()> {
+    let image_ref = cli::required(args, "image");
+    let json = args.get_flag("json");
+
+    let (path, reference) = parse_image_ref(image_ref);
+    let layout = Layout::open(&path)?;
+    let desc = layout.resolve(reference.as_deref())?;
+    let manifest = layout.read_json(descriptor_digest(&desc)?)?;
+    let layers = manifest["layers"].as_array().cloned().unwrap_or_default();
+
+    let usages = layers
+        .par_iter()
+        .map(|layer| layer_usage(&layout, layer))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut total = Usage::default();
+    let mut by_dir: BTreeMap<String, Usage> = BTreeMap::new();
+    for usage in &usages {
+        total.add(usage.total);
+        for (dir, u) in &usage.by_dir {
+            by_dir.entry(dir.clone()).or_default().add(*u);
+        }
+    }
+
+    if json {
+        let out = serde_json::json!({
+            "digest": descriptor_digest(&desc)?,
+            "total": total.to_json(),
+            "layers": usages.iter().map(|u| serde_json::json!({
+                "digest": u.digest,
+                "compressedSize": u.compressed_size,
+                "total": u.total.to_json(),
+                "directories": u.by_dir.iter()
+                    .map(|(d, usage)| (d.clone(), usage.to_json()))
+                    .collect::<serde_json::Map<_, _>>(),
+            })).collect::<Vec<_>>(),
+            "directories": by_dir.iter()
+                .map(|(d, usage)| (d.clone(), usage.to_json()))
+                .collect::<serde_json::Map<_, _>>(),
+        });
+        println!("{}", serde_json::to_string_pretty(&out)?);
+        return Ok(());
+    }
+
+    println!("{:<6}  {:>12}  {:>12}  {:>8}  DIGEST", "LAYER", "COMPRESSED", "UNCOMPRESSED", "FILES");
+    for (i, u) in usages.iter().enumerate() {
+        println!(
+            "{:<6}  {:>12}  {:>12}  {:>8}  {}",
+            i,
+            format_size(u.compressed_size),
+            format_size(u.total.size),
+            u.total.files,
+            u.digest
+        );
+    }
+    println!();
+
+    // Largest directories first
+    let mut dirs: Vec<(&String, &Usage)> = by_dir.iter().collect();
+    dirs.sort_by(|a, b| b.1.size.cmp(&a.1.size).then_with(|| a.0.cmp(b.0)));
+    println!("{:>12}  {:>8}  DIRECTORY", "SIZE", "FILES");
+    for (dir, u) in dirs {
+        println!("{:>12}  {:>8}  /{}", format_size(u.size), u.files, dir);
+    }
+    println!("{:>12}  {:>8}  total", format_size(total.size), total.files);
+
+    Ok(())
+}
+
+fn layer_usage(layout: &Layout, desc: &Value) -> Result<LayerUsage> {
+    let mut archive = tar::Archive::new(layout.open_layer(desc)?);
+    let mut total = Usage::default();
+    let mut by_dir: BTreeMap<String, Usage> = BTreeMap::new();
+
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let path = entry.path()?;
+        let path = path.to_string_lossy();
+        let rel = path.trim_start_matches("./").trim_start_matches('/');
+        let Some(top) = rel.split('/').next().filter(|s| !s.is_empty()) else {
+            continue;
+        };
+
+        let usage = Usage {
+            size: entry.header().size()?,
+            files: u64::from(entry.header().entry_type().is_file()),
+        };
+        total.add(usage);
+        by_dir.entry(top.to_string()).or_default().add(usage);
+    }
+
+    Ok(LayerUsage {
+        digest: descriptor_digest(desc)?.to_string(),
+        compressed_size: descriptor_size(desc),
+        total,
+        by_dir,
+    })
+}
@@ -0,0 +1,193 @@
+// Copyright (c) 2019, 2020 Codethink Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::fmt;
+use std::path::Path;
+
+use anyhow::Result;
+
+/// Failure category of a build, mapped to a distinct process exit code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCategory {
+    /// Anything not classified below (exit code 1)
+    Other,
+    /// Invalid build manifest or command line (exit code 2)
+    Config,
+    /// Parent image layout, or the requested image in it, does not exist (exit code 3)
+    MissingParent,
+    /// Filesystem or other I/O failure (exit code 4)
+    Io,
+    /// Blob content does not match its recorded digest (exit code 5)
+    DigestMismatch,
+}
+
+impl ErrorCategory {
+    pub fn exit_code(self) -> u8 {
+        match self {
+            ErrorCategory::Other => 1,
+            ErrorCategory::Config => 2,
+            ErrorCategory::MissingParent => 3,
+            ErrorCategory::Io => 4,
+            ErrorCategory::DigestMismatch => 5,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            ErrorCategory::Other => "other",
+            ErrorCategory::Config => "config",
+            ErrorCategory::MissingParent => "missing-parent",
+            ErrorCategory::Io => "io",
+            ErrorCategory::DigestMismatch => "digest-mismatch",
+        }
+    }
+
+    /// Category of an error: the outermost explicitly categorized error in
+    /// its chain, otherwise `Io` if any cause is an I/O error.
+    pub fn of(err: &anyhow::Error) -> ErrorCategory {
+        if let Some(category) = err.chain().find_map(|cause| {
+            cause
+                .downcast_ref::<Categorized>()
+                .map(|c| c.category)
+                .or_else(|| cause.downcast_ref::<ImageFailures>().map(ImageFailures::category))
+        }) {
+            return category;
+        }
+        if err.chain().any(|cause| cause.is::<std::io::Error>()) {
+            return ErrorCategory::Io;
+        }
+        ErrorCategory::Other
+    }
+}
+
+/// Transparent wrapper tagging an error with its category; it displays as
+/// the wrapped error, so messages are unchanged.
+struct Categorized {
+    category: ErrorCategory,
+    inner: anyhow::Error,
+}
+
+impl fmt::Debug for Categorized {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.inner, f)
+    }
+}
+
+impl fmt::Display for Categorized {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.inner, f)
+    }
+}
+
+impl std::error::Error for Categorized {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.inner.source()
+    }
+}
+
+pub trait ResultExt<T> {
+    /// Tag the error, if any, with a failure category.
+    fn category(self, category: ErrorCategory) -> Result<T>;
+}
+
+impl<T, E: Into<anyhow::Error>> ResultExt<T> for std::result::Result<T, E> {
+    fn category(self, category: ErrorCategory) -> Result<T> {
+        self.map_err(|e| {
+            anyhow::Error::new(Categorized {
+                category,
+                inner: e.into(),
+            })
+        })
+    }
+}
+
+/// An image that failed in a `--keep-going` build.
+#[derive(Debug)]
+pub struct ImageFailure {
+    /// Layout the image would have been written to
+    pub output: String,
+    /// Position of the image in the document's `images` list
+    pub image: usize,
+    /// `name:tag` of the image, if it has a name
+    pub name: Option<String>,
+    /// `os/architecture` of the image
+    pub platform: String,
+    pub error: anyhow::Error,
+}
+
+/// Every image that failed in a `--keep-going` run whose other images were
+/// written. Categorized like the first failure.
+#[derive(Debug)]
+pub struct ImageFailures(pub Vec<ImageFailure>);
+
+impl ImageFailures {
+    fn category(&self) -> ErrorCategory {
+        self.0.first().map_or(ErrorCategory::Other, |f| ErrorCategory::of(&f.error))
+    }
+}
+
+impl fmt::Display for ImageFailures {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} image(s) failed; the others were written", self.0.len())?;
+        for failure in &self.0 {
+            write!(f, "\n  {}: {:#}", failure.output, failure.error)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ImageFailures {}
+
+/// Write the `--error-json` report for a failed run.
+pub fn write_report(path: &Path, err: &anyhow::Error) -> Result<()> {
+    let category = ErrorCategory::of(err);
+    let mut report = serde_json::json!({
+        "category": category.name(),
+        "exitCode": category.exit_code(),
+        "message": err.to_string(),
+        "causes": err.chain().skip(1).map(|cause| cause.to_string()).collect::<Vec<_>>(),
+    });
+    if let Some(failures) = err.chain().find_map(|cause| cause.downcast_ref::<ImageFailures>()) {
+        report["images"] = failures
+            .0
+            .iter()
+            .map(|failure| {
+                let category = ErrorCategory::of(&failure.error);
+                serde_json::json!({
+                    "output": failure.output,
+                    "image": failure.image,
+                    "name": failure.name,
+                    "platform": failure.platform,
+                    "category": category.name(),
+                    "exitCode": category.exit_code(),
+                    "message": format!("{:#}", failure.error),
+                })
+            })
+            .collect();
+    }
+    if crate::cache_stats::any() {
+        report["cache"] = crate::cache_stats::to_json();
+    }
+    if crate::retry::any() {
+        report["ioRetries"] = crate::retry::to_json();
+    }
+    std::fs::write(path, serde_json::to_string_pretty(&report)? + "\n")?;
+    Ok(())
+}
@@ -0,0 +1,341 @@
+// Copyright (c) 2019, 2020 Codethink Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! eStargz layer output: gzip with a member per file chunk, the table of
+//! contents as the last tar entry and a footer giving its offset, so
+//! snapshotters can fetch single files of a layer lazily.
+
+use std::io::{self, BufRead, Read, Write};
+
+use anyhow::Result;
+use base64::Engine;
+use flate2::bufread::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+
+use crate::codec::{self, Codec, CompressionOptions, Encoder, Finished};
+use crate::tar_stream::{self, Sink, Splitter};
+use crate::lazy_pull::{ANNOTATION_ESTARGZ_TOC_DIGEST, ANNOTATION_ESTARGZ_UNCOMPRESSED_SIZE};
+
+/// Name of the tar entry holding the table of contents
+pub const TOC_NAME: &str = "stargz.index.json";
+/// Files larger than this are split into chunks fetched separately
+const CHUNK_SIZE: u64 = 4 << 20;
+const BLOCK: usize = 512;
+
+pub static ESTARGZ: Estargz = Estargz;
+
+pub struct Estargz;
+
+impl Codec for Estargz {
+    fn name(&self) -> &'static str {
+        "estargz"
+    }
+
+    fn media_type_suffix(&self) -> &'static str {
+        "+gzip"
+    }
+
+    fn magic(&self) -> &'static [u8] {
+        codec::GZIP.magic()
+    }
+
+    fn default_level(&self) -> Option<u32> {
+        codec::GZIP.default_level()
+    }
+
+    // Adding a table of contents would change a parent layer's diff_id
+    fn for_parents(&self) -> &'static dyn Codec {
+        &codec::GZIP
+    }
+
+    fn encoder(
+        &self,
+        writer: Box<dyn Write + Send>,
+        level: Option<u32>,
+        _threads: usize,
+        _options: &CompressionOptions,
+    ) -> Result<Box<dyn Encoder>> {
+        let level = level.or(self.default_level()).unwrap_or(5);
+        Ok(Box::new(Splitter::new(Writer::new(writer, level))))
+    }
+
+    fn decoder<'a>(&self, reader: Box<dyn BufRead + Send + 'a>) -> Result<Box<dyn Read + Send + 'a>> {
+        Ok(Box::new(MultiGzDecoder::new(reader)))
+    }
+}
+
+/// The regular file being written
+struct File {
+    entry: usize,
+    chunk_entry: usize,
+    size: u64,
+    written: u64,
+    chunk_remaining: u64,
+    digest: Sha256,
+    chunk_digest: Sha256,
+}
+
+/// Writes a gzip member per file chunk, recording each in the table of
+/// contents.
+struct Writer {
+    out: Box<dyn Write + Send>,
+    level: flate2::Compression,
+    /// Open gzip member
+    member: Option<GzEncoder<Vec<u8>>>,
+    /// Compressed bytes written to `out`
+    written: u64,
+    uncompressed: u64,
+    diff_digest: Sha256,
+    entries: Vec<Value>,
+    file: Option<File>,
+}
+
+impl Writer {
+    fn new(out: Box<dyn Write + Send>, level: u32) -> Self {
+        Writer {
+            out,
+            level: flate2::Compression::new(level),
+            member: None,
+            written: 0,
+            uncompressed: 0,
+            diff_digest: Sha256::new(),
+            entries: Vec::new(),
+            file: None,
+        }
+    }
+
+    /// Write uncompressed bytes to the open member, opening one if needed.
+    fn emit(&mut self, data: &[u8]) -> io::Result<()> {
+        let level = self.level;
+        let member = self.member.get_or_insert_with(|| GzEncoder::new(Vec::new(), level));
+        member.write_all(data)?;
+        let compressed = member.get_mut();
+        self.out.write_all(compressed)?;
+        self.written += compressed.len() as u64;
+        compressed.clear();
+        self.diff_digest.update(data);
+        self.uncompressed += data.len() as u64;
+        Ok(())
+    }
+
+    fn close_member(&mut self) -> io::Result<()> {
+        if let Some(member) = self.member.take() {
+            let compressed = member.finish()?;
+            self.out.write_all(&compressed)?;
+            self.written += compressed.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// Start a new member for the next chunk of the current file.
+    fn start_chunk(&mut self) -> io::Result<()> {
+        self.close_member()?;
+        let offset = self.written;
+        let Some(ref mut file) = self.file else {
+            return Ok(());
+        };
+        let remaining = file.size - file.written;
+        file.chunk_remaining = remaining.min(CHUNK_SIZE);
+        let entry = if file.written == 0 {
+            &mut self.entries[file.entry]
+        } else {
+            let name = self.entries[file.entry]["name"].clone();
+            self.entries.push(json!({"name": name, "type": "chunk"}));
+            file.chunk_entry = self.entries.len() - 1;
+            self.entries.last_mut().expect("chunk entry")
+        };
+        entry["offset"] = offset.into();
+        if file.written > 0 {
+            entry["chunkOffset"] = file.written.into();
+        }
+        if remaining >= CHUNK_SIZE {
+            entry["chunkSize"] = CHUNK_SIZE.into();
+        }
+        Ok(())
+    }
+
+    /// Record the digest of the chunk just written and start the next one.
+    fn end_chunk(&mut self) -> io::Result<()> {
+        let Some(ref mut file) = self.file else {
+            return Ok(());
+        };
+        let digest = std::mem::take(&mut file.chunk_digest).finalize();
+        self.entries[file.chunk_entry]["chunkDigest"] = format!("sha256:{:x}", digest).into();
+        if file.written < file.size {
+            self.start_chunk()?;
+        }
+        Ok(())
+    }
+}
+
+impl Sink for Writer {
+    fn metadata(&mut self, data: &[u8]) -> io::Result<()> {
+        self.emit(data)
+    }
+
+    fn entry(&mut self, entry: tar_stream::Entry) -> io::Result<()> {
+        let name = clean(&entry.name);
+        let Some(kind) = entry.kind.filter(|_| !name.is_empty()) else {
+            return Ok(());
+        };
+        let mut toc = Map::new();
+        toc.insert("name".into(), name.into());
+        toc.insert("type".into(), kind.into());
+        if kind == "reg" && entry.size > 0 {
+            toc.insert("size".into(), entry.size.into());
+        }
+        if let Some(time) = chrono::DateTime::from_timestamp(entry.mtime, 0) {
+            toc.insert("modtime".into(), time.to_rfc3339_opts(chrono::SecondsFormat::Secs, true).into());
+        }
+        if !entry.link_name.is_empty() {
+            let link = if kind == "hardlink" { clean(&entry.link_name) } else { entry.link_name };
+            toc.insert("linkName".into(), link.into());
+        }
+        let numbers = [("mode", entry.mode as u64), ("uid", entry.uid), ("gid", entry.gid)];
+        for (key, value) in numbers.into_iter().filter(|&(_, value)| value != 0) {
+            toc.insert(key.into(), value.into());
+        }
+        for (key, value) in [("uname", entry.uname), ("gname", entry.gname)] {
+            if !value.is_empty() {
+                toc.insert(key.into(), value.into());
+            }
+        }
+        if matches!(kind, "char" | "block") {
+            toc.insert("devMajor".into(), entry.dev_major.into());
+            toc.insert("devMinor".into(), entry.dev_minor.into());
+        }
+        if !entry.xattrs.is_empty() {
+            let xattrs: Map<String, Value> = entry
+                .xattrs
+                .iter()
+                .map(|(name, value)| (name.clone(), base64::engine::general_purpose::STANDARD.encode(value).into()))
+                .collect();
+            toc.insert("xattrs".into(), xattrs.into());
+        }
+        self.entries.push(toc.into());
+        if kind == "reg" && entry.size > 0 {
+            let index = self.entries.len() - 1;
+            self.file = Some(File {
+                entry: index,
+                chunk_entry: index,
+                size: entry.size,
+                written: 0,
+                chunk_remaining: 0,
+                digest: Sha256::new(),
+                chunk_digest: Sha256::new(),
+            });
+            self.start_chunk()?;
+        }
+        Ok(())
+    }
+
+    fn content(&mut self, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            let Some(ref mut file) = self.file else {
+                return self.emit(data);
+            };
+            let n = file.chunk_remaining.min(data.len() as u64) as usize;
+            file.digest.update(&data[..n]);
+            file.chunk_digest.update(&data[..n]);
+            file.written += n as u64;
+            file.chunk_remaining -= n as u64;
+            let chunk_done = file.chunk_remaining == 0;
+            self.emit(&data[..n])?;
+            data = &data[n..];
+            if chunk_done {
+                self.end_chunk()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn file_end(&mut self) -> io::Result<()> {
+        if let Some(file) = self.file.take() {
+            self.entries[file.entry]["digest"] = format!("sha256:{:x}", file.digest.finalize()).into();
+        }
+        Ok(())
+    }
+
+    // The end-of-archive blocks are written after the TOC entry
+    fn trailer(&mut self, _data: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<Finished> {
+        self.close_member()?;
+        let toc_offset = self.written;
+        let toc = serde_json::to_vec(&json!({"version": 1, "entries": self.entries}))?;
+        let mut header = tar::Header::new_ustar();
+        header.set_path(TOC_NAME)?;
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_size(toc.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(0);
+        header.set_cksum();
+        self.emit(header.as_bytes())?;
+        self.emit(&toc)?;
+        let padding = (BLOCK - toc.len() % BLOCK) % BLOCK;
+        self.emit(&[0; BLOCK][..padding])?;
+        self.emit(&[0; 2 * BLOCK])?;
+        self.close_member()?;
+        self.out.write_all(&footer(toc_offset))?;
+        self.out.flush()?;
+
+        Ok(Finished {
+            diff_digest: Some(format!("{:x}", self.diff_digest.finalize())),
+            annotations: vec![
+                (ANNOTATION_ESTARGZ_TOC_DIGEST, format!("sha256:{:x}", Sha256::digest(&toc))),
+                (ANNOTATION_ESTARGZ_UNCOMPRESSED_SIZE, self.uncompressed.to_string()),
+            ],
+        })
+    }
+}
+
+/// The 51-byte footer: an empty gzip member whose extra field holds the
+/// offset of the TOC member.
+fn footer(toc_offset: u64) -> Vec<u8> {
+    let subfield = format!("{:016x}STARGZ", toc_offset);
+    let mut footer = vec![0x1f, 0x8b, 8, 4, 0, 0, 0, 0, 0, 0xff];
+    footer.extend_from_slice(&(subfield.len() as u16 + 4).to_le_bytes());
+    footer.extend_from_slice(b"SG");
+    footer.extend_from_slice(&(subfield.len() as u16).to_le_bytes());
+    footer.extend_from_slice(subfield.as_bytes());
+    // An empty stored block, then CRC32 and size of nothing
+    footer.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    footer.extend_from_slice(&[0; 8]);
+    footer
+}
+
+/// Entry names as the TOC has them: relative, without `.` or trailing `/`
+fn clean(name: &str) -> String {
+    let mut parts: Vec<&str> = Vec::new();
+    for part in name.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
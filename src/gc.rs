@@ -0,0 +1,270 @@
+// Copyright (c) 2019, 2020 Codethink Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! `build-oci gc`: delete the blobs of a layout that nothing in index.json
+//! refers to any more, as left behind by repeated builds into it. Retention
+//! rules first drop old entries from index.json, for layouts used as caches.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, Context, Result};
+use clap::ArgMatches;
+use serde_json::Value;
+use tracing::warn;
+
+use crate::cli;
+use crate::layout::{descriptor_digest, Layout, ANNOTATION_REF_NAME};
+use crate::signing::DIGESTS_FILE;
+use crate::util::format_size;
+
+/// Which index.json entries to keep; with no rule given, all of them.
+#[derive(Debug, Default)]
+struct Retention {
+    /// Entries of each ref.name kept, counting back from the end of index.json
+    keep_last: Option<usize>,
+    /// Digests always kept
+    keep_digests: HashSet<String>,
+    /// Entries whose blob is younger than this are kept
+    min_age: Option<Duration>,
+}
+
+impl Retention {
+    fn is_set(&self) -> bool {
+        self.keep_last.is_some() || !self.keep_digests.is_empty() || self.min_age.is_some()
+    }
+
+    /// Split the index `entries` into those kept and those dropped. Entries
+    /// without a ref.name are always kept; the others when any rule keeps them.
+    fn apply(&self, layout: &Layout, entries: Vec<Value>) -> Result<(Vec<Value>, Vec<Value>)> {
+        let mut remaining: HashMap<String, usize> = HashMap::new();
+        for desc in &entries {
+            if let Some(name) = desc["annotations"][ANNOTATION_REF_NAME].as_str() {
+                *remaining.entry(name.to_string()).or_default() += 1;
+            }
+        }
+        let now = SystemTime::now();
+        let (mut kept, mut dropped) = (Vec::new(), Vec::new());
+        for desc in entries {
+            let Some(name) = desc["annotations"][ANNOTATION_REF_NAME].as_str() else {
+                kept.push(desc);
+                continue;
+            };
+            // Entries of this ref.name after this one
+            let later = remaining.get_mut(name).map_or(0, |n| {
+                *n -= 1;
+                *n
+            });
+            let digest = descriptor_digest(&desc)?;
+            let recent = match self.min_age {
+                Some(min_age) => {
+                    let path = layout.blob_path(digest)?;
+                    let modified = fs::metadata(&path).and_then(|m| m.modified()).ok();
+                    modified.is_some_and(|m| now.duration_since(m).unwrap_or_default() < min_age)
+                }
+                None => false,
+            };
+            let keep = self.keep_last.is_some_and(|n| later < n) || self.keep_digests.contains(digest) || recent;
+            if keep {
+                kept.push(desc);
+            } else {
+                dropped.push(desc);
+            }
+        }
+        Ok((kept, dropped))
+    }
+}
+
+/// `N`, `Ns`, `Nm`, `Nh` or `Nd`.
+fn parse_age(value: &str) -> Result<Duration> {
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => value.split_at(i),
+        None => (value, "s"),
+    };
+    let scale = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => bail!("--min-age must be a number of seconds, or end in s, m, h or d, got '{}'", value),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| anyhow::anyhow!("--min-age must be a number of seconds, or end in s, m, h or d, got '{}'", value))?;
+    Ok(Duration::from_secs(number.saturating_mul(scale)))
+}
+
+/// Digests listed one per line in `path`; blank lines and `#` comments are ignored.
+fn read_digests(path: &str) -> Result<HashSet<String>> {
+    let text = fs::read_to_string(path).with_context(|| format!("Reading {}", path))?;
+    Ok(text
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+/// `build-oci gc <layout> [--dry-run] [retention rules]`: drop the index
+/// entries the retention rules do not keep, then mark the blobs reachable
+/// from index.json and delete the others, or only list both with `--dry-run`.
+pub fn run(args: &ArgMatches) -> Result<()> {
+    let layout_path = Path::new(cli::required(args, "layout"));
+    let dry_run = args.get_flag("dry-run");
+    let mut retention = Retention {
+        keep_last: args.get_one::<usize>("keep-last").copied(),
+        ..Retention::default()
+    };
+    for file in args.get_many::<String>("keep-digests").into_iter().flatten() {
+        retention.keep_digests.extend(read_digests(file)?);
+    }
+    if let Some(age) = args.get_one::<String>("min-age") {
+        retention.min_age = Some(parse_age(age)?);
+    }
+    let layout = Layout::open(layout_path)?;
+
+    let mut index = layout.index()?;
+    if retention.is_set() {
+        let entries = match index["manifests"].take() {
+            Value::Array(entries) => entries,
+            _ => Vec::new(),
+        };
+        let (kept, dropped) = retention.apply(&layout, entries)?;
+        for desc in &dropped {
+            println!(
+                "index.json: {} {}",
+                descriptor_digest(desc)?,
+                desc["annotations"][ANNOTATION_REF_NAME].as_str().unwrap_or_default()
+            );
+        }
+        index["manifests"] = kept.into();
+        let verb = if dry_run { "would drop" } else { "dropped" };
+        println!("{}: {} {} index entry(s)", layout_path.display(), verb, dropped.len());
+        if !dry_run && !dropped.is_empty() {
+            // Replaced in one rename, so readers never see a partial index
+            let index_path = layout_path.join("index.json");
+            let mut index_file = tempfile::NamedTempFile::new_in(layout_path)?;
+            index_file.write_all(&serde_json::to_vec(&index)?)?;
+            index_file.persist(&index_path).with_context(|| format!("Writing {}", index_path.display()))?;
+        }
+    }
+    let reachable = reachable(&layout, &index)?;
+
+    let mut unreachable = Vec::new();
+    let blobs_dir = layout_path.join("blobs");
+    for algo in fs::read_dir(&blobs_dir).with_context(|| format!("Reading {}", blobs_dir.display()))? {
+        let algo = algo?;
+        if !algo.file_type()?.is_dir() {
+            continue;
+        }
+        for blob in fs::read_dir(algo.path())? {
+            let blob = blob?;
+            let (algo, hash) = (algo.file_name(), blob.file_name());
+            let (Some(algo), Some(hash)) = (algo.to_str(), hash.to_str()) else { continue };
+            // Temporary files of a build in progress start with a dot
+            if hash.starts_with('.') || !blob.file_type()?.is_file() {
+                continue;
+            }
+            if !reachable.contains(&format!("{}:{}", algo, hash)) {
+                unreachable.push((blob.path(), blob.metadata()?.len()));
+            }
+        }
+    }
+    unreachable.sort();
+
+    let mut freed = 0;
+    for (path, size) in &unreachable {
+        let rel = path.strip_prefix(layout_path).unwrap_or(path);
+        if dry_run {
+            println!("{}", rel.display());
+        } else {
+            fs::remove_file(path).with_context(|| format!("Removing {}", path.display()))?;
+        }
+        freed += size;
+    }
+    if dry_run {
+        println!(
+            "{}: would remove {} unreachable blob(s), {}",
+            layout_path.display(),
+            unreachable.len(),
+            format_size(freed)
+        );
+    } else {
+        println!("{}: removed {} unreachable blob(s), {}", layout_path.display(), unreachable.len(), format_size(freed));
+        if !unreachable.is_empty() && layout_path.join(DIGESTS_FILE).is_file() {
+            warn!("{} still lists the removed blobs; sign the layout again", DIGESTS_FILE);
+        }
+    }
+    Ok(())
+}
+
+/// Digests of every blob that `index` refers to, directly or not.
+pub fn reachable(layout: &Layout, index: &Value) -> Result<HashSet<String>> {
+    let mut reachable = HashSet::new();
+    mark_annotations(layout, index, &mut reachable)?;
+    for desc in index["manifests"].as_array().into_iter().flatten() {
+        mark(layout, desc, &mut reachable)?;
+    }
+    Ok(reachable)
+}
+
+/// Mark the blob of `desc` and, if it is a manifest or an index, everything
+/// it refers to.
+fn mark(layout: &Layout, desc: &Value, reachable: &mut HashSet<String>) -> Result<()> {
+    let digest = descriptor_digest(desc)?;
+    mark_annotations(layout, desc, reachable)?;
+    if !reachable.insert(digest.to_string()) {
+        return Ok(());
+    }
+    if !layout.blob_path(digest)?.is_file() {
+        warn!(digest, "blob referenced by the layout is missing");
+        return Ok(());
+    }
+    let document = layout.read_json(digest)?;
+    mark_annotations(layout, &document, reachable)?;
+    // Manifests of an index and the subject a referrer points to
+    for child in document["manifests"].as_array().into_iter().flatten().chain(document.get("subject")) {
+        mark(layout, child, reachable)?;
+    }
+    let layers = document["layers"].as_array().into_iter().flatten();
+    let blobs = document["blobs"].as_array().into_iter().flatten();
+    for leaf in document.get("config").into_iter().chain(layers).chain(blobs) {
+        reachable.insert(descriptor_digest(leaf)?.to_string());
+        mark_annotations(layout, leaf, reachable)?;
+    }
+    Ok(())
+}
+
+/// Mark the blobs named by annotation values of `object`, such as the file
+/// listings and indexes stored next to layers.
+fn mark_annotations(layout: &Layout, object: &Value, reachable: &mut HashSet<String>) -> Result<()> {
+    for value in object["annotations"].as_object().into_iter().flatten().filter_map(|(_, value)| value.as_str()) {
+        let is_digest = value
+            .split_once(':')
+            .is_some_and(|(algo, hash)| !algo.is_empty() && !hash.is_empty() && hash.bytes().all(|b| b.is_ascii_hexdigit()));
+        if is_digest && layout.blob_path(value)?.is_file() {
+            reachable.insert(value.to_string());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,83 @@
+// Copyright (c) 2019, 2020 Codethink Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//! `id-map:` rules rewriting the owners of layer entries, so a staging tree
+//! owned by the build user is emitted as a user namespace would see it.
+
+use anyhow::{bail, Context, Result};
+
+/// One `from:to[:count]` rule: ids `from..from+count` become `to..to+count`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct IdRange {
+    from: u64,
+    to: u64,
+    count: u64,
+}
+
+/// Parsed `id-map:` rules, applied to uids and gids alike. Ids no rule
+/// covers are kept.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IdMap {
+    ranges: Vec<IdRange>,
+}
+
+impl IdMap {
+    pub fn parse(rules: &[String]) -> Result<Self> {
+        let mut ranges = Vec::with_capacity(rules.len());
+        for rule in rules {
+            let range = parse_rule(rule).with_context(|| format!("Invalid id mapping '{}'", rule))?;
+            if let Some(other) = ranges.iter().find(|r: &&IdRange| r.from < range.from + range.count && range.from < r.from + r.count) {
+                bail!("Ids mapped by '{}' are already mapped from {}", rule, other.from);
+            }
+            ranges.push(range);
+        }
+        Ok(IdMap { ranges })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// The id `id` is written as.
+    pub fn map(&self, id: u64) -> u64 {
+        match self.ranges.iter().find(|r| id >= r.from && id - r.from < r.count) {
+            Some(r) => r.to + (id - r.from),
+            None => id,
+        }
+    }
+}
+
+fn parse_rule(rule: &str) -> Result<IdRange> {
+    let fields = rule.split(':').map(|f| f.trim().parse::<u64>()).collect::<Result<Vec<_>, _>>()
+        .context("expected from:to or from:to:count, with unsigned integers")?;
+    let (from, to, count) = match fields[..] {
+        [from, to] => (from, to, 1),
+        [from, to, count] => (from, to, count),
+        _ => bail!("expected from:to or from:to:count"),
+    };
+    if count == 0 {
+        bail!("count must be at least 1");
+    }
+    // Tar headers and config ids are unsigned 32-bit in practice
+    let limit = u32::MAX as u64 + 1;
+    if from.saturating_add(count) > limit || to.saturating_add(count) > limit {
+        bail!("ids must stay below 2^32");
+    }
+    Ok(IdRange { from, to, count })
+}
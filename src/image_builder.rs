@@ -18,34 +18,76 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
+use std::any::Any;
 use std::fs;
-use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, Write};
+use std::panic::{self, AssertUnwindSafe};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex, LazyLock};
+use std::time::{Duration, Instant};
 use rustc_hash::FxHashMap;
 use sha2::{Digest, Sha256};
 
 use anyhow::{Context, Result};
-use flate2::read::GzDecoder;
-use flate2::write::GzEncoder;
-use gzp::deflate::Gzip;
-use gzp::par::compress::ParCompress;
-use gzp::ZWriter;
 use rayon::prelude::*;
-use zstd::stream::read::Decoder as ZstdDecoder;
-use zstd::stream::write::Encoder as ZstdEncoder;
-
-use crate::util::{advise_sequential, get_source_date_epoch, HashingWriter, SharedHashWriter};
-
-use crate::blob::{Blob, IO_BUF_SMALL, IO_BUF_MEDIUM};
-use crate::layer_builder::{analyze_lowers, create_layer};
+use tracing::{debug, info, info_span, warn};
+
+use crate::util::{
+    advise_sequential, CountingWriter, HashingWriter,
+};
+
+use crate::blob::{Blob, BlobDescriptor, IO_BUF_SMALL, IO_BUF_MEDIUM};
+use crate::blob_queue;
+use crate::build_report::{self, LayerRecord, Timings};
+use crate::cache_stats;
+use crate::codec::{self, Codec, LAYER_MEDIA_TYPE, NONDISTRIBUTABLE_LAYER_MEDIA_TYPE};
+use crate::docker_archive;
+use crate::oci_archive;
+use crate::config::{ImageSpec, LayerEntry, LowerSpec, ParentSpec, SbomSpec, StringMap};
+use crate::error::{ErrorCategory, ImageFailure, ResultExt};
+use crate::layer_builder::{
+    self, analyze_lowers, create_layer, merge_lowers, ArchiveEntries, DedupStats, LayerPlan, LayerSource,
+    LowerAnalysis, PathFilter,
+};
+use crate::layer_cache::{self, CachedLayer, LayerCache};
+use crate::layer_index::{self, IndexTap, ANNOTATION_LAYER_INDEX};
+use crate::lazy_pull;
+use crate::tar_parser::parse_archive;
+use crate::layout::{self, descriptor_digest, descriptor_size, Layout, ANNOTATION_REF_NAME, MEDIA_TYPE_INDEX};
+use crate::listing;
+use crate::lower_cache::LowerCache;
+use crate::overlay;
+use crate::parent_verify;
+use crate::progress::Bar;
+use crate::platform::{self, Compatibility, Platform};
+use crate::sbom;
 use crate::{Compression, GlobalConfig};
 
+/// Stack of the threads building images side by side, as much as the main
+/// thread gets: a build nests deeply enough to overflow the 2 MiB default.
+const BUILD_THREAD_STACK: usize = 8 * 1024 * 1024;
+
+/// Config media type of artifact manifests: the empty JSON object `{}`
+pub const MEDIA_TYPE_EMPTY: &str = "application/vnd.oci.empty.v1+json";
+/// File name of an artifact's blob
+pub const ANNOTATION_TITLE: &str = "org.opencontainers.image.title";
+/// Layer descriptor annotations recording how the blob was compressed
+pub const ANNOTATION_COMPRESSION: &str = "org.freedesktopsdk.layer.compression";
+pub const ANNOTATION_COMPRESSION_LEVEL: &str = "org.freedesktopsdk.layer.compression.level";
+pub const ANNOTATION_COMPRESSION_THREADS: &str = "org.freedesktopsdk.layer.compression.threads";
+/// Layer descriptor annotation giving the tar format version of the layer
+pub const ANNOTATION_FORMAT_VERSION: &str = "org.freedesktopsdk.layer.format-version";
+/// Manifest annotation holding the image's build ID, when `build-id-annotation` is enabled
+pub const ANNOTATION_BUILD_ID: &str = "org.freedesktopsdk.build.id";
+
 /// Result type for extract_oci_image_info to reduce type complexity
 type OciImageInfo = (Vec<serde_json::Value>, Vec<PathBuf>, Vec<String>, Vec<serde_json::Value>);
 
-/// Cache key for extracted OCI images
-type ExtractCacheKey = (PathBuf, usize, Compression);
+/// Cache key for extracted OCI images; the output directory is part of the key
+/// because the cached layer files live in that layout's blob directory. The
+/// flag is whether parent blobs were preserved rather than re-encoded.
+type ExtractCacheKey = (PathBuf, usize, Compression, String, bool);
 
 /// Type alias to reduce clippy::type_complexity warning
 /// Uses Arc<OciImageInfo> to share cached data without full clones
@@ -56,26 +98,61 @@ static EXTRACT_CACHE: ExtractCache = LazyLock::new(|| Mutex::new(FxHashMap::defa
 
 static ANALYSIS_CACHE: AnalysisCache = LazyLock::new(|| Mutex::new(FxHashMap::default()));
 
-pub fn extract_oci_image_info(
-    path: &Path,
-    index: usize,
-    global_conf: &GlobalConfig,
-) -> Result<Arc<OciImageInfo>> {
-    let cache_key = (path.to_path_buf(), index, global_conf.compression);
-    {
-        let cache = EXTRACT_CACHE
-            .lock()
-            .map_err(|e| anyhow::anyhow!("Extract cache lock poisoned: {}", e))?;
-        if let Some(cached) = cache.get(&cache_key) {
-            return Ok(Arc::clone(cached)); // Cheap Arc clone instead of full data clone
+/// Layers, diff_ids and history of the images built by this process, keyed
+/// by manifest digest, for the images that name them in `parent.build`
+static BUILT_IMAGES: LazyLock<Mutex<FxHashMap<String, Arc<OciImageInfo>>>> =
+    LazyLock::new(|| Mutex::new(FxHashMap::default()));
+
+/// Layers of the image with manifest descriptor `desc`, built earlier by
+/// this process, as a parent.
+fn built_parent_layers(desc: &serde_json::Value) -> Result<Arc<OciImageInfo>> {
+    let digest = descriptor_digest(desc)?;
+    let built = BUILT_IMAGES.lock().map_err(|e| anyhow::anyhow!("Built images lock poisoned: {}", e))?;
+    let info = built.get(digest).with_context(|| format!("Built parent {} is not known to this build", digest))?;
+    info!(layers = info.0.len(), digest, "building on an image of this manifest");
+    Ok(Arc::clone(info))
+}
+
+/// Load and register the file index of a parent layer, if it has a valid
+/// one: a stored index lets derived layers skip parsing the layer tar.
+fn load_parent_index(
+    layout: &Path,
+    layer_desc: &serde_json::Value,
+    diff_id: &str,
+) -> Option<Arc<ArchiveEntries>> {
+    match layer_index::load(layout, layer_desc, diff_id) {
+        Ok(Some(entries)) => {
+            debug!(diff_id, "loaded layer file index");
+            let entries = Arc::new(entries);
+            layer_index::register(diff_id, Arc::clone(&entries));
+            Some(entries)
+        }
+        Ok(None) => None,
+        Err(e) => {
+            warn!(diff_id, "ignoring layer file index: {:#}", e);
+            None
         }
     }
+}
 
+/// Manifest and config of the image at `index` of the parent layout at
+/// `path`, each blob passed to `verify` before it is read.
+fn read_parent_image(
+    path: &Path,
+    index: usize,
+    verify: impl Fn(&Path, &str) -> Result<()>,
+) -> Result<(serde_json::Value, serde_json::Value)> {
     let index_path = path.join("index.json");
-    let index_data: serde_json::Value =
-        serde_json::from_reader(fs::File::open(&index_path).context("Opening index.json")?)?;
+    let index_file = fs::File::open(&index_path)
+        .with_context(|| format!("Opening parent {}", index_path.display()))
+        .category(ErrorCategory::MissingParent)?;
+    let index_data: serde_json::Value = serde_json::from_reader(index_file)?;
 
-    let image_desc = &index_data["manifests"][index];
+    let image_desc = layout::image_manifests(path, &index_data)?
+        .get(index)
+        .cloned()
+        .with_context(|| format!("Parent {} has no manifest at index {}", path.display(), index))
+        .category(ErrorCategory::MissingParent)?;
     let digest_str = image_desc["digest"]
         .as_str()
         .context("Missing 'digest' in manifest descriptor")?;
@@ -84,6 +161,7 @@ pub fn extract_oci_image_info(
         .context("Invalid digest format: expected 'algorithm:hash'")?;
 
     let manifest_path = path.join("blobs").join(algo).join(digest);
+    verify(&manifest_path, digest_str)?;
     let image_manifest: serde_json::Value =
         serde_json::from_reader(fs::File::open(&manifest_path)?)?;
 
@@ -94,7 +172,63 @@ pub fn extract_oci_image_info(
         .split_once(':')
         .context("Invalid config digest format: expected 'algorithm:hash'")?;
     let config_path = path.join("blobs").join(algo2).join(digest2);
+    verify(&config_path, config_digest_str)?;
     let image_config: serde_json::Value = serde_json::from_reader(fs::File::open(&config_path)?)?;
+    Ok((image_manifest, image_config))
+}
+
+/// Layers of a parent image as they are in its own layout: the same tars as
+/// the copies `extract_oci_image_info` makes, so layers can be built on them
+/// while it runs.
+fn parent_source_layers(path: &Path, index: usize) -> Result<OciImageInfo> {
+    // The extraction running alongside verifies these blobs
+    let (manifest, config) = read_parent_image(path, index, |_, _| Ok(()))?;
+    let layers = manifest["layers"].as_array().cloned().unwrap_or_default();
+    let source = Layout::building(path);
+    let files = layers
+        .iter()
+        .map(|layer| source.blob_path(descriptor_digest(layer)?))
+        .collect::<Result<Vec<_>>>()?;
+    let diff_ids = config["rootfs"]["diff_ids"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|d| d.as_str().map(str::to_string))
+        .collect();
+    Ok((layers, files, diff_ids, Vec::new()))
+}
+
+/// Copy the layers of a parent image into the output layout. With `preserve`
+/// every blob is kept as it is; otherwise blobs in another compression are
+/// re-encoded to the output compression.
+pub fn extract_oci_image_info(
+    path: &Path,
+    index: usize,
+    preserve: bool,
+    global_conf: &GlobalConfig,
+) -> Result<Arc<OciImageInfo>> {
+    let _span = info_span!("parent", path = %path.display(), index).entered();
+    let cache_key = (
+        path.to_path_buf(),
+        index,
+        global_conf.compression,
+        global_conf.output.clone(),
+        preserve,
+    );
+    {
+        let cache = EXTRACT_CACHE
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Extract cache lock poisoned: {}", e))?;
+        if let Some(cached) = cache.get(&cache_key) {
+            debug!("parent image already extracted");
+            cache_stats::EXTRACT.hit(cached.0.iter().map(descriptor_size).sum());
+            return Ok(Arc::clone(cached)); // Cheap Arc clone instead of full data clone
+        }
+    }
+    cache_stats::EXTRACT.miss();
+
+    let (image_manifest, image_config) =
+        read_parent_image(path, index, |blob, digest| parent_verify::check(blob, digest, global_conf))?;
 
     let diff_ids_array = image_config["rootfs"]["diff_ids"]
         .as_array()
@@ -142,107 +276,130 @@ pub fn extract_oci_image_info(
                 .split_once(':')
                 .ok_or_else(|| anyhow::anyhow!("Invalid layer digest format at index {}", i))?;
             let origfile = path.join("blobs").join(lalgo).join(ldigest);
+            parent_verify::check(&origfile, layer_digest_str, global_conf)?;
 
             let layer_media_type = layer["mediaType"]
                 .as_str()
                 .ok_or_else(|| anyhow::anyhow!("Missing 'mediaType' in layer {}", i))?;
-            let is_gzipped = layer_media_type.ends_with("+gzip");
-            let is_zstd = layer_media_type.ends_with("+zstd");
+            parent_verify::check_diff_id(&origfile, layer_media_type, &diff_ids[i], global_conf)?;
+            let source_codec = codec::for_media_type(layer_media_type);
 
             // diff_ids are read-only, safe to access (already bounds-checked above)
             let (_, _diff_id) = diff_ids[i]
                 .split_once(':')
                 .ok_or_else(|| anyhow::anyhow!("Invalid diff_id format at index {}", i))?;
 
-            let out_media_type = match global_conf.compression {
-                Compression::Gzip => "application/vnd.oci.image.layer.v1.tar+gzip",
-                Compression::Zstd => "application/vnd.oci.image.layer.v1.tar+zstd",
-                Compression::Disabled => "application/vnd.oci.image.layer.v1.tar",
-            };
+            let index = load_parent_index(path, layer, &diff_ids[i]);
 
-            let mut output_blob = Blob::new(global_conf, Some(out_media_type));
-
-            output_blob.create(|tmp_file| {
-                let inp = fs::File::open(&origfile)?;
-                advise_sequential(&inp); // Hint kernel for sequential layer reading
-                // Increase buffer size for I/O performance
-                let reader = BufReader::with_capacity(IO_BUF_SMALL, inp);
-
-                // First, get an uncompressed reader if needed
-                let mut decompressed: Box<dyn Read> = if is_gzipped {
-                    Box::new(GzDecoder::new(reader))
-                } else if is_zstd {
-                    Box::new(ZstdDecoder::new(reader)?)
-                } else {
-                    Box::new(reader)
-                };
+            if preserve {
+                let _slot = global_conf.limits.open_file();
+                let file = preserve_blob(&origfile, layer_digest_str, global_conf)?;
+                let mut desc = layer.clone();
+                // These name blobs of the parent layout
+                if let Some(annotations) = desc.get_mut("annotations").and_then(serde_json::Value::as_object_mut) {
+                    annotations.remove(ANNOTATION_LAYER_INDEX);
+                    annotations.remove(listing::ANNOTATION_LISTING);
+                }
+                if desc["annotations"].as_object().is_some_and(|a| a.is_empty()) {
+                    desc.as_object_mut().and_then(|d| d.remove("annotations"));
+                }
+                if let Some(entries) = index.filter(|_| global_conf.layer_index) {
+                    layer_index::attach(&mut desc, &diff_ids[i], &entries, global_conf)?;
+                }
+                return Ok((desc, file));
+            }
 
-                // Now compress to the target format AND compute digest on the fly
-                // This avoids reading the file back to hash it.
-                //
-                // We write the COMPRESSED stream to the temp file, but we need
-                // the digest of that compressed stream.
-                //
-                // Reader -> Decompress -> Compress -> HashingWriter -> TempFile
-
-                let mut hashing_writer = HashingWriter::new(tmp_file);
-
-                match global_conf.compression {
-                    Compression::Gzip => {
-                        if is_gzipped {
-                            // gzip -> gzip: reopen and copy directly (optimized path)
-                            let inp = fs::File::open(&origfile)?;
-                            advise_sequential(&inp);
-                            let mut reader = BufReader::with_capacity(IO_BUF_MEDIUM, inp);
-                            io::copy(&mut reader, &mut hashing_writer)?;
-                        } else {
-                            let level = flate2::Compression::new(
-                                global_conf.compression_level.unwrap_or(5),
-                            );
-                            let mut encoder =
-                                GzEncoder::new(&mut hashing_writer, level);
-                            io::copy(&mut decompressed, &mut encoder)?;
-                            encoder.finish()?;
-                        }
-                    }
-                    Compression::Zstd => {
-                        if is_zstd {
-                            // zstd -> zstd: reopen and copy directly
-                            let inp = fs::File::open(&origfile)?;
-                            advise_sequential(&inp);
-                            let mut reader = BufReader::with_capacity(IO_BUF_MEDIUM, inp);
-                            io::copy(&mut reader, &mut hashing_writer)?;
-                        } else {
-                            let level = global_conf.compression_level.unwrap_or(3) as i32;
-                            let mut encoder = ZstdEncoder::new(&mut hashing_writer, level)?;
-                            encoder.multithread(global_conf.compression_threads as u32)?;
-                            io::copy(&mut decompressed, &mut encoder)?;
-                            encoder.finish()?;
-                        }
-                    }
-                    Compression::Disabled => {
-                        if !is_gzipped && !is_zstd {
-                            let inp = fs::File::open(&origfile)?;
-                            advise_sequential(&inp);
-                            let mut reader = BufReader::with_capacity(IO_BUF_MEDIUM, inp);
-                            io::copy(&mut reader, &mut hashing_writer)?;
-                        } else {
-                            io::copy(&mut decompressed, &mut hashing_writer)?;
-                        }
+            let target_codec = global_conf.compression.codec().for_parents();
+            let out_media_type = target_codec.media_type();
+            // Verbatim copies keep whatever parameters the parent was built with
+            let reencoded = source_codec.name() != target_codec.name();
+
+            let mut output_blob = Blob::new(global_conf, Some(&out_media_type));
+            // A verbatim copy may already be in the layout from an earlier build
+            let existing = Path::new(&global_conf.output).join("blobs").join(lalgo).join(ldigest);
+            let existing_size = fs::metadata(&existing).ok().map(|m| m.len()).filter(|&size| {
+                !reencoded && layer["size"].as_u64() == Some(size)
+            });
+            if let Some(size) = existing_size {
+                cache_stats::BLOB_EXISTS.hit(size);
+                output_blob.descriptor = Some(BlobDescriptor {
+                    media_type: Some(out_media_type.clone()),
+                    size,
+                    digest: layer_digest_str.to_string(),
+                    platform: None,
+                    annotations: None,
+                });
+                output_blob.filename = Some(existing);
+            } else {
+                cache_stats::BLOB_EXISTS.miss();
+                // Each copy or re-encode holds its decompression stream for the whole layer
+                let _slot = global_conf.limits.open_file();
+                let bar = Bar::bytes(
+                    &format!("{}:{}", lalgo, &ldigest[..ldigest.len().min(12)]),
+                    if reencoded { "recompressing" } else { "copying" },
+                    fs::metadata(&origfile).map(|m| m.len()).unwrap_or(0),
+                );
+                let _pinned = if reencoded { global_conf.priority.pin_thread()? } else { None };
+
+                output_blob.create(|tmp_file| {
+                    let inp = fs::File::open(&origfile)?;
+                    advise_sequential(&inp); // Hint kernel for sequential layer reading
+                    if !reencoded {
+                        let mut reader = BufReader::with_capacity(IO_BUF_MEDIUM, bar.reader(inp));
+                        let mut hashing_writer = HashingWriter::new(tmp_file);
+                        io::copy(&mut reader, &mut hashing_writer)?;
+                        let (_, digest) = hashing_writer.finish()?;
+                        return Ok(Some(digest));
                     }
-                }
+                    // Reader -> decoder -> encoder -> queue -> hash(blob) -> file, so
+                    // the blob is not read back to hash it
+                    let reader = BufReader::with_capacity(IO_BUF_SMALL, bar.reader(inp));
+                    let mut decoded = source_codec.decoder(Box::new(reader))?;
+                    let (blob_writer, pending) = blob_queue::start(tmp_file.reopen()?, global_conf.persist_queue_mb)?;
+                    let mut encoder = target_codec.encoder(
+                        Box::new(blob_writer),
+                        global_conf.compression_level,
+                        global_conf.compression_threads,
+                        &global_conf.compression_options,
+                    )?;
+                    io::copy(&mut decoded, &mut encoder)?;
+                    encoder.finish()?;
+                    let (digest, _) = pending.finish()?;
+                    Ok(Some(digest))
+                })?;
+            }
 
-                // Return the computed digest so Blob can use it (avoid re-reading)
-                let (_, digest) = hashing_writer.finish()?;
-                Ok(Some(digest))
-            })?;
+            let mut desc = output_blob
+                .descriptor
+                .as_ref()
+                .ok_or_else(|| anyhow::anyhow!("Missing descriptor after layer extraction"))?
+                .to_json();
+            if reencoded {
+                annotate_compression(
+                    &mut desc,
+                    global_conf,
+                    target_codec,
+                    global_conf.compression_level,
+                    global_conf.compression_threads,
+                );
+                if lazy_pull::has_annotations(layer) {
+                    warn!(digest = layer_digest_str, "re-encoding drops the lazy-pull format of a parent layer");
+                }
+            } else {
+                // A verbatim copy is still a valid eStargz/zstd:chunked blob
+                lazy_pull::carry_annotations(layer, &mut desc);
+            }
+            // Re-encoding keeps the tar, so its format version still holds
+            if let Some(version) = layer["annotations"].get(ANNOTATION_FORMAT_VERSION) {
+                desc["annotations"][ANNOTATION_FORMAT_VERSION] = version.clone();
+            }
+            // Keep the parent's index so this layout is a fast parent too
+            if let Some(entries) = index.filter(|_| global_conf.layer_index) {
+                layer_index::attach(&mut desc, &diff_ids[i], &entries, global_conf)?;
+            }
 
             Ok((
-                output_blob
-                    .descriptor
-                    .as_ref()
-                    .ok_or_else(|| anyhow::anyhow!("Missing descriptor after layer extraction"))?
-                    .to_json(),
+                desc,
                 output_blob
                     .filename
                     .ok_or_else(|| anyhow::anyhow!("Missing filename after layer extraction"))?,
@@ -257,6 +414,7 @@ pub fn extract_oci_image_info(
         layer_files.push(file);
     }
 
+    info!(layers = layer_descs.len(), "extracted parent image");
     let out = Arc::new((layer_descs, layer_files, diff_ids, history));
     EXTRACT_CACHE
         .lock()
@@ -265,205 +423,719 @@ pub fn extract_oci_image_info(
     Ok(out)
 }
 
+/// Put a parent layer or `cache-dir:` blob into the output layout unchanged:
+/// as a hard link where possible, otherwise as a copy checked against its digest.
+fn preserve_blob(origfile: &Path, digest: &str, global_conf: &GlobalConfig) -> Result<PathBuf> {
+    let (algo, hash) = digest
+        .split_once(':')
+        .context("Invalid digest format: expected 'algorithm:hash'")?;
+    let dir = Path::new(&global_conf.output).join("blobs").join(algo);
+    fs::create_dir_all(&dir)?;
+    let dest = dir.join(hash);
+    if dest.exists() {
+        cache_stats::BLOB_EXISTS.hit(fs::metadata(&dest).map(|m| m.len()).unwrap_or(0));
+        return Ok(dest);
+    }
+    cache_stats::BLOB_EXISTS.miss();
+    match fs::hard_link(origfile, &dest) {
+        // Another image may have linked the same blob meanwhile
+        Ok(()) => return Ok(dest),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => return Ok(dest),
+        Err(e) => debug!(digest, "copying blob, cannot hard link it: {}", e),
+    }
+
+    let tmp = tempfile::NamedTempFile::new_in(&dir)?;
+    let inp = global_conf.io_retry.open(origfile).with_context(|| format!("Opening {}", origfile.display()))?;
+    advise_sequential(&inp);
+    let bar = Bar::bytes(&hash[..hash.len().min(12)], "copying", inp.metadata().map(|m| m.len()).unwrap_or(0));
+    let mut reader = BufReader::with_capacity(IO_BUF_MEDIUM, bar.reader(inp));
+    let mut writer = HashingWriter::new(BufWriter::new(tmp.reopen()?));
+    io::copy(&mut reader, &mut writer)?;
+    let (_, actual) = writer.finish()?;
+    if algo == "sha256" && actual != hash {
+        return Err(anyhow::anyhow!("Blob {} in {} has digest sha256:{}", digest, origfile.display(), actual))
+            .category(ErrorCategory::DigestMismatch);
+    }
+    global_conf.io_retry.persist(tmp, &dest).map_err(|e| anyhow::anyhow!("persist blob: {}", e))?;
+    Ok(dest)
+}
+
+/// Whether `parent` is the output layout, whose blobs can be used as they are.
+fn is_output_layout(parent: &Path, global_conf: &GlobalConfig) -> bool {
+    match (fs::canonicalize(parent), fs::canonicalize(&global_conf.output)) {
+        (Ok(parent_dir), Ok(output_dir)) => parent_dir == output_dir,
+        _ => false,
+    }
+}
+
+/// Position of `image`'s parent among the image manifests of its layout:
+/// `index:` as given, or the image that `ref:` or `tag:` selects. When they
+/// select a nested index of several images, the one for `image`'s platform.
+fn parent_position(parent: &ParentSpec, image: &ImageSpec) -> Result<usize> {
+    let (selector, value) = match (&parent.reference, &parent.tag) {
+        (Some(reference), _) => ("digest", reference),
+        (None, Some(tag)) => ("ref.name", tag),
+        (None, None) => return Ok(parent.index.unwrap_or(0)),
+    };
+    let layout = Layout::open(&parent.image).category(ErrorCategory::MissingParent)?;
+    let mut selected = layout.select_images(|desc| match parent.reference {
+        Some(_) => desc["digest"].as_str() == Some(value),
+        None => desc["annotations"][ANNOTATION_REF_NAME].as_str() == Some(value),
+    })?;
+    if selected.len() > 1 {
+        let wanted = Platform::of(image);
+        selected.retain(|(_, desc)| Platform::from_descriptor(desc).is_some_and(|p| p.satisfies(&wanted)));
+    }
+    match selected.as_slice() {
+        [(position, _)] => Ok(*position),
+        [] => Err(anyhow::anyhow!(
+            "Parent {} has no image with {} {} for {}/{}",
+            parent.image.display(),
+            selector,
+            value,
+            image.os,
+            image.architecture
+        ))
+        .category(ErrorCategory::MissingParent),
+        _ => Err(anyhow::anyhow!(
+            "Parent {} has {} images with {} {} for {}/{}; select one by digest with ref:",
+            parent.image.display(),
+            selected.len(),
+            selector,
+            value,
+            image.os,
+            image.architecture
+        ))
+        .category(ErrorCategory::Config),
+    }
+}
+
+/// Layers of a parent that lives in the output layout, referenced as they
+/// are: no layer blob is copied or re-encoded, whatever its compression.
+fn reuse_parent_layers(parent: &ParentSpec, index: usize, global_conf: &GlobalConfig) -> Result<Arc<OciImageInfo>> {
+    let _span = info_span!("parent", path = %parent.image.display(), index).entered();
+    let layout = Layout::open(&parent.image).category(ErrorCategory::MissingParent)?;
+    let manifests = layout.image_manifests()?;
+    let desc = manifests
+        .get(index)
+        .with_context(|| format!("Parent {} has no manifest at index {}", parent.image.display(), index))
+        .category(ErrorCategory::MissingParent)?;
+    let read_verified = |desc: &serde_json::Value| -> Result<serde_json::Value> {
+        let digest = descriptor_digest(desc)?;
+        parent_verify::check(&layout.blob_path(digest)?, digest, global_conf)?;
+        layout.read_json(digest)
+    };
+    let manifest = read_verified(desc)?;
+    let config = read_verified(&manifest["config"])?;
+
+    let layer_descs = manifest["layers"]
+        .as_array()
+        .context("Missing 'layers' array in image manifest")?
+        .clone();
+    let diff_ids: Vec<String> = serde_json::from_value(config["rootfs"]["diff_ids"].clone())
+        .context("Invalid 'rootfs.diff_ids' in image config")?;
+    if diff_ids.len() != layer_descs.len() {
+        anyhow::bail!(
+            "Malformed OCI image: diff_ids count ({}) does not match layers count ({})",
+            diff_ids.len(),
+            layer_descs.len()
+        );
+    }
+    let layer_files = layer_descs
+        .iter()
+        .zip(&diff_ids)
+        .map(|(desc, diff_id)| {
+            let path = layout.blob_path(descriptor_digest(desc)?)?;
+            if !path.is_file() {
+                anyhow::bail!("Missing layer blob {}", path.display());
+            }
+            parent_verify::check(&path, descriptor_digest(desc)?, global_conf)?;
+            let media_type = desc["mediaType"].as_str().unwrap_or_default();
+            parent_verify::check_diff_id(&path, media_type, diff_id, global_conf)?;
+            Ok(path)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let history = config["history"].as_array().cloned().unwrap_or_default();
+    for (desc, diff_id) in layer_descs.iter().zip(&diff_ids) {
+        load_parent_index(&parent.image, desc, diff_id);
+        cache_stats::BLOB_EXISTS.hit(descriptor_size(desc));
+    }
+
+    info!(layers = layer_descs.len(), "reusing parent layers in place");
+    Ok(Arc::new((layer_descs, layer_files, diff_ids, history)))
+}
+
+/// Layers of the image built for `image` last time, for `--manifest-only`:
+/// the manifest in the output layout under the same name and tag (or none)
+/// and platform. Its own history entries are left out, to be written again.
+fn previous_layers(image: &ImageSpec, global_conf: &GlobalConfig) -> Result<OciImageInfo> {
+    let output = Path::new(&global_conf.output);
+    let missing = || {
+        anyhow::anyhow!(
+            "--manifest-only found no previous build of {}/{} in {}; build it once without --manifest-only",
+            image.os,
+            image.architecture,
+            output.display()
+        )
+    };
+    if !output.join("index.json").is_file() {
+        return Err(missing()).category(ErrorCategory::MissingParent);
+    }
+    let layout = Layout::open(output).category(ErrorCategory::MissingParent)?;
+    let group_ref = group_ref(image);
+    let mut candidates = Vec::new();
+    for desc in layout.manifests()? {
+        let ref_name = desc["annotations"][ANNOTATION_REF_NAME].as_str();
+        let nested = desc["mediaType"].as_str() == Some(MEDIA_TYPE_INDEX);
+        match &group_ref {
+            Some(group_ref) if ref_name == Some(group_ref) && nested => {
+                candidates.extend(layout::image_manifests(output, &layout.read_json(descriptor_digest(&desc)?)?)?)
+            }
+            None if ref_name.is_none() && !nested => candidates.push(desc),
+            _ => {}
+        }
+    }
+    let platform = platform::descriptor(image);
+    candidates.retain(|desc| desc["platform"] == platform);
+    let desc = match candidates.as_slice() {
+        [desc] => desc,
+        [] => return Err(missing()).category(ErrorCategory::MissingParent),
+        _ => {
+            return Err(anyhow::anyhow!(
+                "--manifest-only found {} previous builds of {}/{} in {}",
+                candidates.len(),
+                image.os,
+                image.architecture,
+                output.display()
+            ))
+            .category(ErrorCategory::Config)
+        }
+    };
+
+    let manifest = layout.read_json(descriptor_digest(desc)?)?;
+    let config = layout.read_json(descriptor_digest(&manifest["config"])?)?;
+    let layer_descs = manifest["layers"]
+        .as_array()
+        .context("Missing 'layers' array in image manifest")?
+        .clone();
+    let diff_ids: Vec<String> = serde_json::from_value(config["rootfs"]["diff_ids"].clone())
+        .context("Invalid 'rootfs.diff_ids' in image config")?;
+    if diff_ids.len() != layer_descs.len() {
+        anyhow::bail!(
+            "Malformed OCI image: diff_ids count ({}) does not match layers count ({})",
+            diff_ids.len(),
+            layer_descs.len()
+        );
+    }
+    let layer_files = layer_descs
+        .iter()
+        .map(|desc| {
+            let path = layout.blob_path(descriptor_digest(desc)?)?;
+            if !path.is_file() {
+                return Err(anyhow::anyhow!("Missing layer blob {}", path.display()))
+                    .category(ErrorCategory::MissingParent);
+            }
+            Ok(path)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let mut history = config["history"].as_array().cloned().unwrap_or_default();
+    history.truncate(history.len().saturating_sub(image.own_layers().max(1)));
+    for desc in &layer_descs {
+        cache_stats::BLOB_EXISTS.hit(descriptor_size(desc));
+    }
+
+    info!(layers = layer_descs.len(), manifest = %descriptor_digest(desc)?, "reusing previously built layers");
+    Ok((layer_descs, layer_files, diff_ids, history))
+}
+
+/// Record the codec, level and thread count used for a layer blob in its
+/// descriptor annotations, when `compression-annotations` is enabled.
+fn annotate_compression(
+    desc: &mut serde_json::Value,
+    global_conf: &GlobalConfig,
+    codec: &dyn Codec,
+    level: Option<u32>,
+    threads: usize,
+) {
+    if !global_conf.compression_annotations {
+        return;
+    }
+    let annotations = &mut desc["annotations"];
+    annotations[ANNOTATION_COMPRESSION] = codec.name().into();
+    if let Some(level) = level.or(codec.default_level()).filter(|_| codec.compresses()) {
+        annotations[ANNOTATION_COMPRESSION_LEVEL] = level.to_string().into();
+        annotations[ANNOTATION_COMPRESSION_THREADS] = threads.to_string().into();
+    }
+}
+
+/// Directories packed as the image's layers, bottom first, where their
+/// entries come from, and their compression.
+fn layer_sources(image: &ImageSpec, global_conf: &GlobalConfig) -> Result<Vec<(PathBuf, LayerSource, Compression)>> {
+    let compression = |name: Option<&str>| match name.or(image.compression.as_deref()) {
+        Some(name) => Compression::parse(name).category(ErrorCategory::Config),
+        None => Ok(global_conf.compression),
+    };
+    if let Some(ref overlay) = image.overlay {
+        let (upper, lowers) = overlay::resolve(overlay).category(ErrorCategory::Config)?;
+        return Ok(vec![(upper, LayerSource::Overlay { lowers }, compression(None)?)]);
+    }
+    let directory = |filter: PathFilter| {
+        if image.follow_symlinks {
+            LayerSource::FollowSymlinks { roots: image.symlink_roots.clone().unwrap_or_default(), filter }
+        } else {
+            LayerSource::Directory { filter }
+        }
+    };
+    if let Some(ref layers) = image.layers {
+        return layers
+            .iter()
+            .map(|layer| Ok((layer.dir().to_path_buf(), directory(layer.filter(image)), compression(layer.compression())?)))
+            .collect();
+    }
+    if let Some(ref tar) = image.layer_tar {
+        return Ok(vec![(tar.clone(), LayerSource::Tar, compression(None)?)]);
+    }
+    image.layer.iter().map(|layer| {
+        let source = match image.layer_metadata {
+            Some(ref metadata) => LayerSource::Metadata(metadata.clone()),
+            None => directory(image.filter()),
+        };
+        Ok((layer.clone(), source, compression(None)?))
+    }).collect()
+}
+
+/// Level for layers in `compression`: `compression-level:` when that is
+/// the document's compression, else the codec's default.
+fn compression_level(global_conf: &GlobalConfig, compression: Compression) -> Option<u32> {
+    if compression == global_conf.compression {
+        global_conf.compression_level
+    } else {
+        compression.codec().default_level()
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn build_layer(
     upper: &Path,
+    source: &LayerSource,
+    compression: Compression,
+    dedup_lowers: &[LowerSpec],
     lowers: &[PathBuf],
+    lower_descs: &[serde_json::Value],
+    lower_diff_ids: &[String],
     global_conf: &GlobalConfig,
 ) -> Result<(Vec<serde_json::Value>, Vec<String>)> {
+    let _span = info_span!("layer", path = %upper.display()).entered();
+    let start = Instant::now();
+
+    // A layer built before from the same inputs is linked in as it was
+    let layer_cache = global_conf
+        .cache_dir
+        .as_deref()
+        .map(|dir| LayerCache::open(dir, global_conf.remote_cache.as_ref()))
+        .transpose()?;
+    let cache_key = match layer_cache {
+        Some(_) => layer_cache::key(
+            upper,
+            source,
+            compression,
+            compression_level(global_conf, compression),
+            !dedup_lowers.is_empty(),
+            lower_diff_ids,
+            global_conf,
+        )?,
+        None => None,
+    };
+    if let (Some(cache), Some(key)) = (&layer_cache, &cache_key) {
+        match cache.lookup(key) {
+            Ok(Some(layer)) => return reuse_cached_layer(layer, start, global_conf),
+            Ok(None) => {}
+            Err(e) => warn!(key, "ignoring layer cache entry: {:#}", e),
+        }
+        cache_stats::LAYER_CACHE.miss();
+    }
+
     // Use a temp dir inside the output dir to ensure same-filesystem moves
     let output_path = Path::new(&global_conf.output);
     let tmp_dir = output_path.join(".tmp");
     fs::create_dir_all(&tmp_dir).ok();
 
     let lower_cache_key = lowers.to_vec();
-    let lower_analysis = {
-        let cached = ANALYSIS_CACHE
-            .lock()
-            .map_err(|e| anyhow::anyhow!("Analysis cache lock poisoned: {}", e))?
-            .get(&lower_cache_key)
-            .cloned();
+    let lower_analysis = if let LayerSource::Tar = source {
+        // A tar file is copied as it is, with nothing to deduplicate
+        Arc::new(merge_lowers(Vec::new()))
+    } else {
+        // Directories may change between builds of the same process
+        let cached = match dedup_lowers {
+            [] => ANALYSIS_CACHE
+                .lock()
+                .map_err(|e| anyhow::anyhow!("Analysis cache lock poisoned: {}", e))?
+                .get(&lower_cache_key)
+                .cloned(),
+            _ => None,
+        };
         if let Some(cached) = cached {
+            debug!("reusing lower layer analysis");
+            cache_stats::ANALYSIS.hit(lowers.iter().filter_map(|path| fs::metadata(path).ok()).map(|m| m.len()).sum());
             cached
         } else {
-            // Open lower tars for deduplication analysis
-            let mut lower_archives: Vec<tar::Archive<Box<dyn Read + Send>>> = Vec::new();
-            for lower_path in lowers {
-                let f = fs::File::open(lower_path)?;
-                advise_sequential(&f); // Hint kernel for sequential tar reading
-                let reader: Box<dyn Read + Send> = match global_conf.compression {
-                    Compression::Gzip => Box::new(GzDecoder::new(BufReader::new(f))),
-                    Compression::Zstd => Box::new(ZstdDecoder::new(BufReader::new(f))?),
-                    Compression::Disabled => Box::new(BufReader::new(f)),
+            cache_stats::ANALYSIS.miss();
+            // Lowers with a loaded file index skip the tar entirely; the
+            // rest are opened for deduplication analysis
+            let mut indexed: Vec<Option<Arc<ArchiveEntries>>> =
+                lower_diff_ids.iter().map(|diff_id| layer_index::lookup(diff_id)).collect();
+            let lower_cache = global_conf.lower_cache.map(LowerCache::open).transpose()?;
+            let mut lower_readers: Vec<Box<dyn Read + Send>> = Vec::new();
+            let lowers_with_desc = lowers.iter().zip(lower_descs);
+            for (((lower_path, desc), diff_id), index) in lowers_with_desc.zip(lower_diff_ids).zip(&indexed) {
+                let lower_size = fs::metadata(lower_path).map(|m| m.len()).unwrap_or(0);
+                if index.is_some() {
+                    cache_stats::LAYER_INDEX.hit(lower_size);
+                    continue;
+                }
+                cache_stats::LAYER_INDEX.miss();
+                // Lowers reused in place keep the parent's compression
+                let media_type = desc["mediaType"].as_str().unwrap_or_default();
+                let decompress = || -> Result<Box<dyn Read + Send>> {
+                    let f = fs::File::open(lower_path)?;
+                    advise_sequential(&f); // Hint kernel for sequential tar reading
+                    codec::for_media_type(media_type).decoder(Box::new(BufReader::new(f)))
                 };
-                lower_archives.push(tar::Archive::new(reader));
+                let reader = match lower_cache {
+                    Some(ref cache) => {
+                        if cache.contains(diff_id) {
+                            cache_stats::LOWER_CACHE.hit(lower_size);
+                        } else {
+                            cache_stats::LOWER_CACHE.miss();
+                        }
+                        cache.reader(diff_id, decompress)?
+                    }
+                    None => decompress()?,
+                };
+                lower_readers.push(reader);
+            }
+            let num_parsed = lower_readers.len();
+            let parsed: Vec<ArchiveEntries> = lower_readers
+                .into_par_iter()
+                .map(parse_archive)
+                .collect::<Result<_>>()?;
+            let mut parsed = parsed.into_iter();
+            for slot in indexed.iter_mut().filter(|slot| slot.is_none()) {
+                *slot = parsed.next().map(Arc::new);
+            }
+            debug!(
+                indexed = lowers.len() - num_parsed,
+                parsed = num_parsed,
+                "read lower layers"
+            );
+            let mut layers: Vec<Arc<ArchiveEntries>> = indexed.into_iter().flatten().collect();
+            layers.extend(layer_builder::dedup_lowers(dedup_lowers, global_conf)?);
+            let analysis = Arc::new(merge_lowers(layers));
+            if dedup_lowers.is_empty() {
+                ANALYSIS_CACHE
+                    .lock()
+                    .map_err(|e| anyhow::anyhow!("Analysis cache lock poisoned: {}", e))?
+                    .insert(lower_cache_key, analysis.clone());
             }
-            let analysis = Arc::new(analyze_lowers(&mut lower_archives)?);
-            ANALYSIS_CACHE
-                .lock()
-                .map_err(|e| anyhow::anyhow!("Analysis cache lock poisoned: {}", e))?
-                .insert(lower_cache_key, analysis.clone());
             analysis
         }
     };
 
-    let mut new_layer_descs = Vec::new();
-
-    match global_conf.compression {
-        Compression::Gzip => {
-            let compressed_tmp = tempfile::NamedTempFile::new_in(&tmp_dir)?;
-            let level = global_conf.compression_level.unwrap_or(5);
-
-            // OPTIMIZATION: Use SharedHashWriter to compute blob digest on the fly.
-            // ParCompress consumes the writer, so we share the hasher via Arc<Mutex>.
-            let blob_hasher = Arc::new(Mutex::new(Sha256::new()));
-            let shared_writer = SharedHashWriter::new(BufWriter::new(compressed_tmp.reopen()?), blob_hasher.clone());
-
-            let parz: ParCompress<Gzip> = ParCompress::<Gzip>::builder()
-                    .num_threads(global_conf.compression_threads)
-                    .map_err(|e| anyhow::anyhow!("gzp thread config: {}", e))?
-                    .compression_level(gzp::Compression::new(level))
-                    .from_writer(shared_writer);
-
-            // Stack: tar -> BufWriter -> HashingWriter(diff_id) -> gzp -> SharedHashWriter(blob) -> file
-            let diff_hasher = HashingWriter::new(parz);
-            let mut tar_builder = tar::Builder::new(BufWriter::new(diff_hasher));
-            tar_builder.follow_symlinks(false);
-
-            create_layer(&mut tar_builder, upper, &lower_analysis, global_conf)?;
-
-            let buf_writer = tar_builder.into_inner()?;
-            let hashing_writer = buf_writer.into_inner().map_err(|e| anyhow::anyhow!("bufwriter: {}", e))?;
-            let (mut parz_writer, diff_digest) = hashing_writer.finish()?;
-            parz_writer.finish().map_err(|e| anyhow::anyhow!("parallel gzip: {}", e))?;
-
-            // Retrieve blob digest from shared hasher (no re-reading needed)
-            let blob_digest = format!(
-                "{:x}",
-                blob_hasher
-                    .lock()
-                    .map_err(|e| anyhow::anyhow!("Blob hasher lock poisoned: {}", e))?
-                    .clone()
-                    .finalize()
-            );
-
-            let mut blob = Blob::new(
-                global_conf,
-                Some("application/vnd.oci.image.layer.v1.tar+gzip"),
-            );
+    // Listings are made while walking a directory
+    let mut plan = global_conf
+        .layer_listing
+        .filter(|_| !matches!(source, LayerSource::Tar))
+        .map(|_| LayerPlan::default());
+
+    // Compression threads are started below and inherit the pinning
+    let _pinned = global_conf.priority.pin_thread()?;
+    let codec = compression.codec();
+    let compressed_tmp = tempfile::NamedTempFile::new_in(&tmp_dir)?;
+    // The blob digest is computed by the writer thread
+    let (blob_writer, pending) = blob_queue::start(compressed_tmp.reopen()?, global_conf.persist_queue_mb)?;
+    let (diff_digest, index, annotations, uncompressed_size, dedup) = if codec.compresses() {
+        let encoder = codec.encoder(
+            Box::new(blob_writer),
+            compression_level(global_conf, compression),
+            global_conf.compression_threads,
+            &global_conf.compression_options,
+        )?;
+        // Stack: tar -> BufWriter -> count -> HashingWriter(diff_id) -> encoder -> queue -> hash(blob) -> file
+        let diff_hasher = HashingWriter::new(encoder);
+        let tap = IndexTap::new(diff_hasher, global_conf.layer_index);
+        let (buf_writer, dedup) = write_layer(
+            BufWriter::new(CountingWriter::new(tap)),
+            upper,
+            source,
+            &lower_analysis,
+            global_conf,
+            plan.as_mut(),
+        )?;
+        let counter = buf_writer.into_inner().map_err(|e| anyhow::anyhow!("bufwriter: {}", e))?;
+        let (tap, uncompressed_size) = counter.finish();
+        let (hashing_writer, index) = tap.finish()?;
+        let (encoder, diff_digest) = hashing_writer.finish()?;
+        let finished = encoder.finish()?;
+        (Some(finished.diff_digest.unwrap_or(diff_digest)), index, finished.annotations, uncompressed_size, dedup)
+    } else {
+        // Uncompressed, the writer thread's hash is the diff_id too
+        let tap = IndexTap::new(blob_writer, global_conf.layer_index);
+        let (buf_writer, dedup) = write_layer(
+            BufWriter::new(CountingWriter::new(tap)),
+            upper,
+            source,
+            &lower_analysis,
+            global_conf,
+            plan.as_mut(),
+        )?;
+        let counter = buf_writer.into_inner().map_err(|e| anyhow::anyhow!("bufwriter: {}", e))?;
+        let (tap, uncompressed_size) = counter.finish();
+        let (mut blob_writer, index) = tap.finish()?;
+        blob_writer.flush()?;
+        drop(blob_writer);
+        (None, index, Vec::new(), uncompressed_size, dedup)
+    };
+    let (blob_digest, size) = pending.finish()?;
+    let diff_digest = diff_digest.unwrap_or_else(|| blob_digest.clone());
 
-            let size = compressed_tmp.as_file().metadata()?.len();
-            blob.create_from_temp_with_digest(compressed_tmp, size, &blob_digest)?;
+    let mut blob = Blob::new(global_conf, Some(&codec.media_type()));
+    // Use pre-computed digest - avoids re-reading the file
+    blob.create_from_temp_with_digest(compressed_tmp, size, &blob_digest)?;
+    let mut layer_desc = blob
+        .descriptor
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("Missing blob descriptor after {} layer creation", codec.name()))?
+        .to_json();
+    for (key, value) in annotations {
+        layer_desc["annotations"][key] = value.into();
+    }
+    // Pre-built tars follow their own conventions
+    if let Some(version) = global_conf.format_version.filter(|_| !matches!(source, LayerSource::Tar)) {
+        layer_desc["annotations"][ANNOTATION_FORMAT_VERSION] = version.to_string().into();
+    }
 
-            new_layer_descs.push(
-                blob.descriptor
-                    .as_ref()
-                    .ok_or_else(|| anyhow::anyhow!("Missing blob descriptor after gzip layer creation"))?
-                    .to_json(),
-            );
+    if let (Some(format), Some(plan)) = (global_conf.layer_listing, plan) {
+        let mut listing_blob = Blob::new(global_conf, Some(format.media_type()));
+        listing_blob.create(|f| {
+            let bytes = listing::render(format, &plan.entries);
+            f.write_all(&bytes)?;
+            Ok(Some(format!("{:x}", Sha256::digest(&bytes))))
+        })?;
+        let listing_desc = listing_blob
+            .descriptor
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Missing listing blob descriptor"))?;
+        layer_desc["annotations"][listing::ANNOTATION_LISTING] = listing_desc.digest.as_str().into();
+    }
+    let diff_id = format!("sha256:{}", diff_digest);
+    if let LayerSource::Tar = source {
+        check_tar_diff_id(upper, &diff_id)?;
+    }
+    if let Some(entries) = index {
+        layer_index::attach(&mut layer_desc, &diff_id, &entries, global_conf)?;
+        layer_index::register(&diff_id, Arc::new(entries));
+    }
+    annotate_compression(
+        &mut layer_desc,
+        global_conf,
+        codec,
+        compression_level(global_conf, compression),
+        global_conf.compression_threads,
+    );
 
-            let new_diff_ids = vec![format!("sha256:{}", diff_digest)];
-            Ok((new_layer_descs, new_diff_ids))
+    if let (Some(cache), Some(key)) = (&layer_cache, &cache_key) {
+        // The layer is built either way; a cache that cannot take it only costs the next build
+        if let Err(e) = cache.store(key, &layer_desc, &diff_id, uncompressed_size, dedup, &Layout::building(output_path)) {
+            warn!(key, "not caching layer: {:#}", e);
         }
-        Compression::Zstd => {
-            // STREAMING: tar -> hash(diff_id) -> zstd(multithread) -> hash(blob) -> file
-            // Unlike ParCompress, ZstdEncoder's finish() returns the inner writer,
-            // so we can properly chain HashingWriters.
-
-            let compressed_tmp = tempfile::NamedTempFile::new_in(&tmp_dir)?;
-            let level = global_conf.compression_level.unwrap_or(3) as i32;
-
-            // Outer hasher for BLOB digest (compressed)
-            let blob_hasher = HashingWriter::new(BufWriter::new(compressed_tmp.reopen()?));
-
-            let mut zstd_encoder = ZstdEncoder::new(blob_hasher, level)?;
-            zstd_encoder.multithread(global_conf.compression_threads as u32)?;
-
-            // Stack: tar -> BufWriter -> HashingWriter(diff_id) -> zstd -> HashingWriter(blob) -> file
-            let diff_hasher = HashingWriter::new(zstd_encoder);
-            let mut tar_builder = tar::Builder::new(BufWriter::new(diff_hasher));
-            tar_builder.follow_symlinks(false);
-
-            create_layer(&mut tar_builder, upper, &lower_analysis, global_conf)?;
-
-            let buf_writer_diff = tar_builder.into_inner()?;
-            let hashing_writer = buf_writer_diff.into_inner().map_err(|e| anyhow::anyhow!("bufwriter: {}", e))?;
-            let (zstd_writer, diff_digest) = hashing_writer.finish()?;
-            let blob_hasher = zstd_writer.finish()?;
+    }
 
-            let (mut buf_writer, blob_digest) = blob_hasher.finish()?;
-            buf_writer.flush()?;
+    if global_conf.build_report {
+        let record = LayerRecord { uncompressed_size, dedup, duration: start.elapsed() };
+        build_report::record_layer(descriptor_digest(&layer_desc)?, record);
+    }
 
-            let mut blob = Blob::new(
-                global_conf,
-                Some("application/vnd.oci.image.layer.v1.tar+zstd"),
-            );
+    info!(
+        digest = %layer_desc["digest"].as_str().unwrap_or_default(),
+        size = layer_desc["size"].as_u64().unwrap_or_default(),
+        "built layer"
+    );
+    Ok((vec![layer_desc], vec![diff_id]))
+}
 
-            let size = compressed_tmp.as_file().metadata()?.len();
-            blob.create_from_temp_with_digest(compressed_tmp, size, &blob_digest)?;
+/// Link a layer found in the `cache-dir:` into the output layout, with the
+/// listing and index blobs it names.
+fn reuse_cached_layer(
+    layer: CachedLayer,
+    start: Instant,
+    global_conf: &GlobalConfig,
+) -> Result<(Vec<serde_json::Value>, Vec<String>)> {
+    for (digest, file) in &layer.blobs {
+        preserve_blob(file, digest, global_conf)?;
+    }
+    cache_stats::LAYER_CACHE.hit(layer.uncompressed_size);
+    // Layers built on this one skip parsing it, as they would after building it
+    load_parent_index(Path::new(&global_conf.output), &layer.desc, &layer.diff_id);
 
-            new_layer_descs.push(
-                blob.descriptor
-                    .as_ref()
-                    .ok_or_else(|| anyhow::anyhow!("Missing blob descriptor after zstd layer creation"))?
-                    .to_json(),
-            );
+    if global_conf.build_report {
+        let record = LayerRecord { uncompressed_size: layer.uncompressed_size, dedup: layer.dedup, duration: start.elapsed() };
+        build_report::record_layer(descriptor_digest(&layer.desc)?, record);
+    }
+    info!(
+        digest = %layer.desc["digest"].as_str().unwrap_or_default(),
+        size = layer.desc["size"].as_u64().unwrap_or_default(),
+        "reused cached layer"
+    );
+    Ok((vec![layer.desc], vec![layer.diff_id]))
+}
 
-            let new_diff_ids = vec![format!("sha256:{}", diff_digest)];
-            Ok((new_layer_descs, new_diff_ids))
-        }
-        Compression::Disabled => {
-            // No compression: tar -> hash -> file
-            // Since uncompressed, diff_id == blob_id, compute once
+/// Write the uncompressed layer of `upper` to `writer`: a tar of the
+/// directory, with what deduplication left out, or the contents of a
+/// `layer-tar:` file as they are.
+fn write_layer<W: Write>(
+    mut writer: W,
+    upper: &Path,
+    source: &LayerSource,
+    lower_analysis: &LowerAnalysis,
+    global_conf: &GlobalConfig,
+    plan: Option<&mut LayerPlan>,
+) -> Result<(W, Option<DedupStats>)> {
+    if let LayerSource::Tar = source {
+        let mut reader = open_layer_tar(upper, global_conf)?;
+        io::copy(&mut reader, &mut writer).with_context(|| format!("Reading {}", upper.display()))?;
+        return Ok((writer, None));
+    }
+    let mut tar_builder = tar::Builder::new(writer);
+    tar_builder.follow_symlinks(false);
+    let dedup = create_layer(&mut tar_builder, upper, source, lower_analysis, global_conf, plan)?;
+    Ok((tar_builder.into_inner()?, Some(dedup)))
+}
 
-            let tar_tmp = tempfile::NamedTempFile::new_in(&tmp_dir)?;
+/// Uncompressed contents of a `layer-tar:` file, whose compression is
+/// recognised by its magic bytes.
+fn open_layer_tar(path: &Path, global_conf: &GlobalConfig) -> Result<Box<dyn Read + Send>> {
+    let file = global_conf
+        .io_retry
+        .open(path)
+        .with_context(|| format!("Opening layer tar {}", path.display()))
+        .category(ErrorCategory::Config)?;
+    advise_sequential(&file);
+    let mut reader = BufReader::with_capacity(IO_BUF_MEDIUM, file);
+    let codec = codec::for_magic(reader.fill_buf()?);
+    codec.decoder(Box::new(reader))
+}
 
-            let tar_hexdigest = {
-                // Hash while writing - this IS the blob digest too (no compression)
-                let hashing_writer = HashingWriter::new(BufWriter::new(tar_tmp.reopen()?));
-                let mut tar_builder = tar::Builder::new(BufWriter::new(hashing_writer));
-                tar_builder.follow_symlinks(false);
+/// Compare the diff_id of a `layer-tar:` file with the one recorded next to
+/// it in `<file>.diffid`, if there is one.
+fn check_tar_diff_id(path: &Path, diff_id: &str) -> Result<()> {
+    let mut sidecar = path.as_os_str().to_owned();
+    sidecar.push(".diffid");
+    let sidecar = PathBuf::from(sidecar);
+    let expected = match fs::read_to_string(&sidecar) {
+        Ok(expected) => expected,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err).with_context(|| format!("Reading {}", sidecar.display())),
+    };
+    let expected = expected.trim();
+    if expected != diff_id {
+        return Err(anyhow::anyhow!(
+            "{} has diff_id {}, but {} records {}",
+            path.display(),
+            diff_id,
+            sidecar.display(),
+            expected
+        ))
+        .category(ErrorCategory::DigestMismatch);
+    }
+    Ok(())
+}
 
-                create_layer(&mut tar_builder, upper, &lower_analysis, global_conf)?;
-                let buf_writer_tar = tar_builder.into_inner()?;
-                let hashing_writer = buf_writer_tar.into_inner().map_err(|e| anyhow::anyhow!("bufwriter: {}", e))?;
-                let (mut buf_writer_file, digest) = hashing_writer.finish()?;
-                buf_writer_file.flush()?;
-                digest
-            };
+/// Merge a string map read from a JSON/YAML file into `target`.
+/// Keys already present in `target` (set inline in the manifest) take precedence.
+fn merge_map_file(target: &mut serde_json::Value, path: &Path) -> Result<()> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Reading map file {}", path.display()))?;
+    let map: serde_json::Map<String, serde_json::Value> = serde_yaml::from_str(&contents)
+        .with_context(|| format!("Parsing map file {}", path.display()))?;
 
-            let size = tar_tmp.as_file().metadata()?.len();
+    if target.is_null() {
+        *target = serde_json::Value::Object(serde_json::Map::new());
+    }
+    let obj = target
+        .as_object_mut()
+        .ok_or_else(|| anyhow::anyhow!("Cannot merge {} into a non-map value", path.display()))?;
+
+    for (key, value) in map {
+        // OCI annotations and labels are string-valued; stringify scalars
+        // since YAML happily turns versions like 1.0 into numbers.
+        let value = match value {
+            serde_json::Value::String(s) => s,
+            serde_json::Value::Number(n) => n.to_string(),
+            serde_json::Value::Bool(b) => b.to_string(),
+            _ => anyhow::bail!("Value for '{}' in {} must be a string", key, path.display()),
+        };
+        obj.entry(key).or_insert(serde_json::Value::String(value));
+    }
+    Ok(())
+}
 
-            let mut blob = Blob::new(
-                global_conf,
-                Some("application/vnd.oci.image.layer.v1.tar"),
-            );
-            // Use pre-computed digest - avoids re-reading the file
-            blob.create_from_temp_with_digest(tar_tmp, size, &tar_hexdigest)?;
-            new_layer_descs.push(
-                blob.descriptor
-                    .as_ref()
-                    .ok_or_else(|| anyhow::anyhow!("Missing blob descriptor after uncompressed layer creation"))?
-                    .to_json(),
-            );
+/// The org.opencontainers.image.* annotations of an image, when
+/// `standard-annotations` is enabled: `created` from its config and the
+/// values of its `source:`, `revision:`, `version:`, `licenses:` and `url:`,
+/// falling back to those taken from CI variables.
+fn standard_annotations(image: &ImageSpec, config: &serde_json::Value, global_conf: &GlobalConfig) -> StringMap {
+    let Some(ref fallback) = global_conf.standard_annotations else {
+        return StringMap::new();
+    };
+    let values = [
+        ("created", config["created"].as_str()),
+        ("source", image.source.as_deref()),
+        ("revision", image.revision.as_deref()),
+        ("version", image.version.as_deref()),
+        ("licenses", image.licenses.as_deref()),
+        ("url", image.url.as_deref()),
+    ];
+    values
+        .into_iter()
+        .filter_map(|(key, value)| {
+            let value = value.or(fallback.get(key).map(String::as_str))?;
+            Some((format!("org.opencontainers.image.{}", key), value.to_string()))
+        })
+        .collect()
+}
 
-            let new_diff_ids = vec![format!("sha256:{}", tar_hexdigest)];
-            Ok((new_layer_descs, new_diff_ids))
+/// Add `annotations` to an annotations object, keeping the values it already has.
+fn add_annotations(target: &mut serde_json::Value, annotations: &StringMap) {
+    for (key, value) in annotations {
+        if target[key].is_null() {
+            target[key] = value.as_str().into();
         }
     }
 }
 
-pub fn build_image(
-    global_conf: &GlobalConfig,
-    image: &serde_json::Value,
-) -> Result<serde_json::Value> {
-    let mut layer_descs: Vec<serde_json::Value> = Vec::new();
-    let mut layer_files: Vec<PathBuf> = Vec::new();
-    let mut diff_ids: Vec<String> = Vec::new();
-    let mut history: Option<Vec<serde_json::Value>> = None;
+/// Copy of the global config with the per-image source-date-epoch, id-map
+/// and ownership overrides, if `image` has any.
+fn with_image_overrides(global_conf: &GlobalConfig, image: &ImageSpec) -> Result<Option<GlobalConfig>> {
+    if image.source_date_epoch.is_none() && image.id_map.is_none() && image.ownership.is_none() {
+        return Ok(None);
+    }
+    Ok(Some(GlobalConfig {
+        source_date_epoch: image.source_date_epoch.or(global_conf.source_date_epoch),
+        id_map: image.id_map().category(ErrorCategory::Config)?,
+        ownership: image.ownership().category(ErrorCategory::Config)?,
+        ..global_conf.clone()
+    }))
+}
 
-    // Create config
-    let epoch = get_source_date_epoch();
-    let created = if let Some(ep) = epoch {
+/// `created` time of `image`: its `created:`, SOURCE_DATE_EPOCH, or now.
+fn created_time(global_conf: &GlobalConfig, image: &ImageSpec) -> Result<String> {
+    if let Some(ref created) = image.created {
+        // Checked when the manifest was parsed; written in UTC like the others
+        let created = chrono::DateTime::parse_from_rfc3339(created)
+            .with_context(|| format!("Invalid created time: {}", created))?;
+        return Ok(created.with_timezone(&chrono::Utc).to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true));
+    }
+    let created = if let Some(ep) = global_conf.source_date_epoch {
         chrono::DateTime::from_timestamp(ep as i64, 0)
             .ok_or_else(|| anyhow::anyhow!("Invalid SOURCE_DATE_EPOCH timestamp: {}", ep))?
             .format("%Y-%m-%dT%H:%M:%SZ")
@@ -471,28 +1143,122 @@ pub fn build_image(
     } else {
         chrono::Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string()
     };
+    Ok(created)
+}
+
+/// Config of a (non-artifact) image and the descriptors of all its layers,
+/// its parent's included. The layers are built into the output layout.
+fn image_config(
+    global_conf: &GlobalConfig,
+    image: &ImageSpec,
+    built_parent: Option<&serde_json::Value>,
+    bar: &Bar,
+    timings: &mut Timings,
+) -> Result<(serde_json::Value, OciImageInfo)> {
+    let mut layer_descs: Vec<serde_json::Value> = Vec::new();
+    let mut layer_files: Vec<PathBuf> = Vec::new();
+    let mut diff_ids: Vec<String> = Vec::new();
+    let mut history: Option<Vec<serde_json::Value>> = None;
 
+    // Create config
+    let created = created_time(global_conf, image)?;
     let mut config = serde_json::json!({
         "created": created,
     });
 
-    if let Some(author) = image.get("author") {
-        config["author"] = author.clone();
+    if let Some(ref author) = image.author {
+        config["author"] = author.as_str().into();
+    }
+    config["architecture"] = image.architecture.as_str().into();
+    config["os"] = image.os.as_str().into();
+    if global_conf.compatibility == Compatibility::Ggcr {
+        // The rest of the platform, which ConfigFile.Platform() reads
+        for (key, value) in Platform::of(image).config_fields().to_json().as_object().into_iter().flatten() {
+            config[key] = value.clone();
+        }
     }
-    config["architecture"] = image["architecture"].clone();
-    config["os"] = image["os"].clone();
-    if let Some(img_config) = image.get("config") {
+    if let Some(ref img_config) = image.config {
         config["config"] = img_config.clone();
     }
+    // Checked against config.Labels when the manifest was parsed
+    for (key, value) in image.labels.iter().flatten() {
+        config["config"]["Labels"][key] = value.as_str().into();
+    }
+    if let Some(ref labels_file) = image.labels_file {
+        merge_map_file(&mut config["config"]["Labels"], labels_file)?;
+    }
 
     // Handle parent image
-    if let Some(parent) = image.get("parent") {
-        let parent_image = parent["image"]
-            .as_str()
-            .ok_or_else(|| anyhow::anyhow!("Missing 'image' in parent"))?;
-        let parent_index = parent.get("index").and_then(|v| v.as_u64()).unwrap_or(0) as usize;
-        let parent_info =
-            extract_oci_image_info(Path::new(parent_image), parent_index, global_conf)?;
+    let mut sources = if global_conf.manifest_only { Vec::new() } else { layer_sources(image, global_conf)? };
+    let mut new_layers = None;
+    let start = Instant::now();
+    if global_conf.manifest_only {
+        bar.set_message("reading previous build");
+        let (pld, plf, pdi, ph) = previous_layers(image, global_conf)?;
+        layer_descs = pld;
+        layer_files = plf;
+        diff_ids = pdi;
+        history = Some(ph);
+    } else if let Some(desc) = built_parent {
+        // Already in the output layout, with its file indexes registered
+        let (pld, plf, pdi, ph) = built_parent_layers(desc)?.as_ref().clone();
+        layer_descs = pld;
+        layer_files = plf;
+        diff_ids = pdi;
+        history = Some(ph);
+    } else if let Some(ref parent) = image.parent {
+        bar.set_message("extracting parent");
+        // A parent in the output layout already has its blobs in place;
+        // re-encoding them would only add copies under new digests
+        let in_place = is_output_layout(&parent.image, global_conf);
+        let index = parent_position(parent, image)?;
+        let preserve = match parent.recompress {
+            Some(false) if !in_place => {
+                return Err(anyhow::anyhow!(
+                    "parent.recompress: false requires the parent {} to be the output layout {}",
+                    parent.image.display(),
+                    global_conf.output
+                ))
+                .category(ErrorCategory::Config);
+            }
+            Some(true) => Some(false),
+            _ if in_place => None,
+            // Without layers of its own the image only needs a new config
+            // and manifest: the parent's blobs are linked in as they are
+            _ => Some(global_conf.preserve_parent_layers || image.own_layers() == 0),
+        };
+        let parent_info = match preserve {
+            Some(preserve) if !sources.is_empty() => {
+                // The new layers only read the parent's tars, so they are
+                // built on its own blobs while those are copied across. Not
+                // on the rayon pool: the directory walk needs it to be free.
+                let span = tracing::Span::current();
+                let ((parent_info, parent_time), (built, layers_time)) = std::thread::scope(|scope| {
+                    let extraction = scope.spawn(|| {
+                        let _entered = span.enter();
+                        let start = Instant::now();
+                        (extract_oci_image_info(&parent.image, index, preserve, global_conf), start.elapsed())
+                    });
+                    let start = Instant::now();
+                    let built = parent_source_layers(&parent.image, index).and_then(|(descs, files, diff_ids, _)| {
+                        build_layers(image, std::mem::take(&mut sources), (&descs, &files, &diff_ids), global_conf, bar)
+                    });
+                    let extracted = extraction.join().unwrap_or_else(|payload| {
+                        let message = format!("Extracting parent panicked: {}", panic_message(&*payload));
+                        (Err(anyhow::anyhow!(message)), Duration::ZERO)
+                    });
+                    (extracted, (built, start.elapsed()))
+                });
+                // A bad parent explains a failing layer, not the other way round
+                let parent_info = parent_info?;
+                new_layers = Some(built?);
+                timings.add("parent", parent_time);
+                timings.add("layers", layers_time);
+                parent_info
+            }
+            Some(preserve) => extract_oci_image_info(&parent.image, index, preserve, global_conf)?,
+            None => reuse_parent_layers(parent, index, global_conf)?,
+        };
         // Clone out of Arc - necessary since we modify these later
         let (pld, plf, pdi, ph) = parent_info.as_ref();
         layer_descs = pld.clone();
@@ -500,27 +1266,60 @@ pub fn build_image(
         diff_ids = pdi.clone();
         history = Some(ph.clone());
     }
+    if new_layers.is_none() {
+        timings.add("parent", start.elapsed());
+    }
 
-    // Build layer
-    if let Some(layer_path) = image.get("layer").and_then(|v| v.as_str()) {
-        let (new_descs, new_diffs) = build_layer(Path::new(layer_path), &layer_files, global_conf)?;
+    // Build layers; each one is deduplicated against all those below it
+    let start = Instant::now();
+    let built = match new_layers {
+        Some(built) => built,
+        None => {
+            let built = build_layers(image, sources, (&layer_descs, &layer_files, &diff_ids), global_conf, bar)?;
+            timings.add("layers", start.elapsed());
+            built
+        }
+    };
+    let (new_descs, new_files, new_diffs) = built;
+    let own_start = layer_descs.len();
+    layer_descs.extend(new_descs);
+    layer_files.extend(new_files);
+    diff_ids.extend(new_diffs);
+    // A --manifest-only build reuses the SBOM layer with the others
+    if let (Some(spec), false) = (image.sbom.as_ref().filter(|_| image.sbom_layer()), global_conf.manifest_only) {
+        bar.set_message("writing SBOM");
+        let start = Instant::now();
+        let output = Layout::building(Path::new(&global_conf.output));
+        let scanned = if spec.parent { &layer_descs[..] } else { &layer_descs[own_start..] };
+        let document = sbom_document(spec, &sbom_name(image), scanned, &created, global_conf)?;
+        let (new_descs, new_diffs) = build_sbom_layer(spec, image, &document, &layer_files, &layer_descs, &diff_ids, global_conf)?;
+        for desc in &new_descs {
+            layer_files.push(output.blob_path(descriptor_digest(desc)?)?);
+        }
         layer_descs.extend(new_descs);
         diff_ids.extend(new_diffs);
+        timings.add("sbom", start.elapsed());
     }
 
     // History
     let mut hist = history.unwrap_or_default();
     let mut hist_entry = serde_json::Map::new();
-    if image.get("layer").is_none() {
+    hist_entry.insert("created".to_string(), config["created"].clone());
+    if let Some(ref created_by) = image.created_by {
+        hist_entry.insert("created_by".to_string(), created_by.as_str().into());
+    }
+    if image.own_layers() == 0 {
         hist_entry.insert("empty_layer".to_string(), serde_json::Value::Bool(true));
     }
-    if let Some(author) = image.get("author") {
-        hist_entry.insert("author".to_string(), author.clone());
+    if let Some(ref author) = image.author {
+        hist_entry.insert("author".to_string(), author.as_str().into());
     }
-    if let Some(comment) = image.get("comment") {
-        hist_entry.insert("comment".to_string(), comment.clone());
+    if let Some(ref comment) = image.comment {
+        hist_entry.insert("comment".to_string(), comment.as_str().into());
     }
-    hist.push(serde_json::Value::Object(hist_entry));
+    // One entry for each layer of the image
+    let entry = serde_json::Value::Object(hist_entry);
+    hist.extend(std::iter::repeat_n(entry, image.own_layers().max(1)));
 
     config["rootfs"] = serde_json::json!({
         "type": "layers",
@@ -528,11 +1327,256 @@ pub fn build_image(
     });
     config["history"] = serde_json::Value::Array(hist);
 
-    // Write config blob
-    let mut config_blob = Blob::new(
+    if let Some(ref patch) = image.config_patch {
+        json_patch::patch(&mut config, patch).context("Applying config-patch")?;
+    }
+
+    let history = config["history"].as_array().cloned().unwrap_or_default();
+    Ok((config, (layer_descs, layer_files, diff_ids, history)))
+}
+
+/// Build the layers of `sources` on `lowers`, each deduplicated against all
+/// those below it, into the output layout. Returns the new descriptors, blob
+/// files and diff_ids.
+fn build_layers(
+    image: &ImageSpec,
+    sources: Vec<(PathBuf, LayerSource, Compression)>,
+    (lower_descs, lower_files, lower_diff_ids): (&[serde_json::Value], &[PathBuf], &[String]),
+    global_conf: &GlobalConfig,
+    bar: &Bar,
+) -> Result<(Vec<serde_json::Value>, Vec<PathBuf>, Vec<String>)> {
+    let mut layer_descs = lower_descs.to_vec();
+    let mut layer_files = lower_files.to_vec();
+    let mut diff_ids = lower_diff_ids.to_vec();
+    let own_start = layer_descs.len();
+    let output = Layout::building(Path::new(&global_conf.output));
+    for (i, (layer_path, source, compression)) in sources.into_iter().enumerate() {
+        bar.set_message("building layer");
+        let (mut new_descs, new_diffs) =
+            build_layer(
+                &layer_path,
+                &source,
+                compression,
+                image.dedup_lowers.as_deref().unwrap_or_default(),
+                &layer_files,
+                &layer_descs,
+                &diff_ids,
+                global_conf,
+            )?;
+        if let Some(entry) = image.layers.as_ref().and_then(|layers| layers.get(i)) {
+            for desc in &mut new_descs {
+                mark_foreign(desc, entry);
+            }
+        }
+        for desc in &new_descs {
+            layer_files.push(output.blob_path(descriptor_digest(desc)?)?);
+        }
+        layer_descs.extend(new_descs);
+        diff_ids.extend(new_diffs);
+    }
+    Ok((
+        layer_descs.split_off(own_start),
+        layer_files.split_off(own_start),
+        diff_ids.split_off(own_start),
+    ))
+}
+
+/// Give the descriptor of a `layers:` entry its `urls` and, for a
+/// non-distributable layer, the media type registries do not push.
+fn mark_foreign(desc: &mut serde_json::Value, entry: &LayerEntry) {
+    if entry.nondistributable() {
+        let media_type = desc["mediaType"].as_str().unwrap_or_default();
+        let suffix = media_type.strip_prefix(LAYER_MEDIA_TYPE).unwrap_or_default().to_string();
+        desc["mediaType"] = format!("{}{}", NONDISTRIBUTABLE_LAYER_MEDIA_TYPE, suffix).into();
+    }
+    if !entry.urls().is_empty() {
+        desc["urls"] = entry.urls().into();
+    }
+}
+
+/// Descriptors of the blobs of an artifact's `files:`, or of the SBOM of
+/// its subject, titled with their file names. An artifact without files
+/// holds the empty blob, as the OCI spec asks of manifests without layers.
+fn artifact_layers(
+    image: &ImageSpec,
+    subject: Option<Subject>,
+    global_conf: &GlobalConfig,
+) -> Result<Vec<serde_json::Value>> {
+    if let (Some(spec), Some(subject)) = (&image.sbom, subject) {
+        let output = Layout::building(Path::new(&global_conf.output));
+        let manifest = output.read_json(descriptor_digest(subject.descriptor)?)?;
+        let layers = manifest["layers"].as_array().map(Vec::as_slice).unwrap_or_default();
+        let own_start = if spec.parent { 0 } else { layers.len().saturating_sub(subject.image.own_layers()) };
+        let created = created_time(global_conf, image)?;
+        let document = sbom_document(spec, &sbom_name(subject.image), &layers[own_start..], &created, global_conf)?;
+        let format = sbom_format(spec)?;
+        let mut desc = bytes_blob(global_conf, format.media_type(), &document)?;
+        desc["annotations"] = serde_json::json!({ ANNOTATION_TITLE: format.file_name() });
+        return Ok(vec![desc]);
+    }
+    let Some(ref files) = image.files else {
+        return Ok(vec![bytes_blob(global_conf, MEDIA_TYPE_EMPTY, b"{}")?]);
+    };
+    let mut layers = Vec::with_capacity(files.len());
+    for file in files {
+        let mut source = fs::File::open(&file.path)
+            .with_context(|| format!("Opening artifact file {}", file.path.display()))?;
+        let media_type = file.media_type.as_deref().unwrap_or("application/octet-stream");
+        let mut blob = Blob::new(global_conf, Some(media_type));
+        blob.create(|f| {
+            let mut writer = HashingWriter::new(BufWriter::new(f));
+            io::copy(&mut source, &mut writer)?;
+            let (_, digest) = writer.finish()?;
+            Ok(Some(digest))
+        })?;
+        let mut desc = blob
+            .descriptor
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Missing artifact blob descriptor"))?
+            .to_json();
+        if let Some(name) = file.path.file_name() {
+            desc["annotations"] = serde_json::json!({ ANNOTATION_TITLE: name.to_string_lossy() });
+        }
+        layers.push(desc);
+    }
+    Ok(layers)
+}
+
+/// Descriptor of a blob holding `bytes`, written to the layout.
+fn bytes_blob(global_conf: &GlobalConfig, media_type: &str, bytes: &[u8]) -> Result<serde_json::Value> {
+    let mut blob = Blob::new(global_conf, Some(media_type));
+    blob.create(|f| {
+        f.write_all(bytes)?;
+        Ok(Some(format!("{:x}", Sha256::digest(bytes))))
+    })?;
+    Ok(blob
+        .descriptor
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("Missing {} blob descriptor", media_type))?
+        .to_json())
+}
+
+fn sbom_format(spec: &SbomSpec) -> Result<sbom::Format> {
+    sbom::Format::parse(spec.format.as_deref()).category(ErrorCategory::Config)
+}
+
+/// Name of `image` in its SBOM: its `name:tag`, or its platform.
+fn sbom_name(image: &ImageSpec) -> String {
+    group_ref(image).unwrap_or_else(|| format!("{}/{}", image.os, image.architecture))
+}
+
+/// SBOM of the files of `layers`, blobs of the output layout, bottom first.
+fn sbom_document(
+    spec: &SbomSpec,
+    name: &str,
+    layers: &[serde_json::Value],
+    created: &str,
+    global_conf: &GlobalConfig,
+) -> Result<Vec<u8>> {
+    let format = sbom_format(spec)?;
+    let output = Layout::building(Path::new(&global_conf.output));
+    let mut rootfs = sbom::Rootfs::new(spec.files);
+    for desc in layers {
+        rootfs.add_layer(output.open_layer(desc)?).context("Reading a layer for the SBOM")?;
+    }
+    sbom::generate(format, &rootfs, name, created)
+}
+
+/// Build the layer holding `document`, the image's SBOM, at
+/// `usr/share/sbom/`, annotated with the SBOM's media type.
+fn build_sbom_layer(
+    spec: &SbomSpec,
+    image: &ImageSpec,
+    document: &[u8],
+    lowers: &[PathBuf],
+    lower_descs: &[serde_json::Value],
+    lower_diff_ids: &[String],
+    global_conf: &GlobalConfig,
+) -> Result<(Vec<serde_json::Value>, Vec<String>)> {
+    let format = sbom_format(spec)?;
+    let tmp_dir = Path::new(&global_conf.output).join(".tmp");
+    fs::create_dir_all(&tmp_dir)?;
+    let mut tar_file = tempfile::NamedTempFile::new_in(&tmp_dir)?;
+    let mut builder = tar::Builder::new(BufWriter::new(tar_file.as_file_mut()));
+    let mut header = tar::Header::new_ustar();
+    header.set_path(format!("{}/{}", sbom::LAYER_DIR, format.file_name()))?;
+    header.set_size(document.len() as u64);
+    header.set_mode(0o644);
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_mtime(global_conf.source_date_epoch.unwrap_or_else(|| chrono::Utc::now().timestamp() as u64));
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_cksum();
+    builder.append(&header, document)?;
+    builder.into_inner()?.flush()?;
+
+    let compression = match image.compression {
+        Some(ref name) => Compression::parse(name).category(ErrorCategory::Config)?,
+        None => global_conf.compression,
+    };
+    let (mut descs, diff_ids) = build_layer(
+        tar_file.path(),
+        &LayerSource::Tar,
+        compression,
+        &[],
+        lowers,
+        lower_descs,
+        lower_diff_ids,
         global_conf,
-        Some("application/vnd.oci.image.config.v1+json"),
-    );
+    )?;
+    for desc in &mut descs {
+        desc["annotations"][sbom::ANNOTATION_SBOM] = format.media_type().into();
+    }
+    Ok((descs, diff_ids))
+}
+
+/// The image a `subject:` refers to.
+#[derive(Debug, Clone, Copy)]
+pub struct Subject<'a> {
+    pub image: &'a ImageSpec,
+    /// Descriptor of its built manifest
+    pub descriptor: &'a serde_json::Value,
+}
+
+/// Build `image` into the output layout, returning its manifest descriptor.
+/// `subject` is the image its `subject:` refers to and `built_parent` the
+/// manifest descriptor of the one its `parent.build` names, both already built.
+pub fn build_image(
+    global_conf: &GlobalConfig,
+    image: &ImageSpec,
+    subject: Option<Subject>,
+    built_parent: Option<&serde_json::Value>,
+) -> Result<serde_json::Value> {
+    let _span = info_span!(
+        "image",
+        architecture = %image.architecture,
+        os = %image.os
+    )
+    .entered();
+    let bar = Bar::image(&format!("{}/{}", image.os, image.architecture));
+    let build_start = Instant::now();
+    let mut timings = Timings::default();
+
+    let image_conf = with_image_overrides(global_conf, image)?;
+    let global_conf = image_conf.as_ref().unwrap_or(global_conf);
+
+    let (config, config_media_type, layer_descs, layers) = match image.artifact_type {
+        Some(_) => {
+            let start = Instant::now();
+            let layer_descs = artifact_layers(image, subject, global_conf)?;
+            timings.add("files", start.elapsed());
+            (serde_json::json!({}), MEDIA_TYPE_EMPTY, layer_descs, None)
+        }
+        None => {
+            let (config, layers) = image_config(global_conf, image, built_parent, &bar, &mut timings)?;
+            (config, "application/vnd.oci.image.config.v1+json", layers.0.clone(), Some(layers))
+        }
+    };
+
+    // Write config blob
+    bar.set_message("writing manifest");
+    let start = Instant::now();
+    let mut config_blob = Blob::new(global_conf, Some(config_media_type));
     config_blob.create(|f| {
         let json_bytes = serde_json::to_vec(&config)?;
         f.write_all(&json_bytes)?;
@@ -553,8 +1597,42 @@ pub fn build_image(
             .ok_or_else(|| anyhow::anyhow!("Missing config blob descriptor"))?
             .to_json(),
     });
-    if let Some(annotations) = image.get("annotations") {
-        manifest["annotations"] = annotations.clone();
+    if global_conf.compatibility == Compatibility::Ggcr && image.artifact_type.is_none() {
+        manifest["config"]["platform"] = platform::descriptor(image);
+    }
+    if let Some(ref artifact_type) = image.artifact_type {
+        manifest["artifactType"] = artifact_type.as_str().into();
+    }
+    if let Some(subject) = subject {
+        let desc = subject.descriptor;
+        manifest["subject"] = serde_json::json!({
+            "mediaType": desc["mediaType"],
+            "digest": desc["digest"],
+            "size": desc["size"],
+        });
+    }
+    if let Some(ref annotations) = image.annotations {
+        manifest["annotations"] = serde_json::to_value(annotations)?;
+    }
+    if let Some(ref annotations_file) = image.annotations_file {
+        merge_map_file(&mut manifest["annotations"], annotations_file)?;
+    }
+    if global_conf.build_id_annotation {
+        manifest["annotations"][ANNOTATION_BUILD_ID] = build_id(&config).into();
+    }
+    let mut standard = standard_annotations(image, &config, global_conf);
+    if !standard.is_empty() {
+        add_annotations(&mut manifest["annotations"], &standard);
+        // The index entry repeats the manifest's values, including overrides
+        for (key, value) in standard.iter_mut() {
+            if let Some(v) = manifest["annotations"][key.as_str()].as_str() {
+                *value = v.to_string();
+            }
+        }
+    }
+
+    if let Some(ref patch) = image.manifest_patch {
+        json_patch::patch(&mut manifest, patch).context("Applying manifest-patch")?;
     }
 
     let mut manifest_blob = Blob::new(
@@ -577,65 +1655,373 @@ pub fn build_image(
         .ok_or_else(|| anyhow::anyhow!("Missing manifest blob descriptor"))?
         .to_json();
 
-    // Platform
-    let mut platform = serde_json::json!({
-        "os": image["os"],
-        "architecture": image["architecture"],
-    });
-    if let Some(v) = image.get("os.version") {
-        platform["os.version"] = v.clone();
+    // Artifacts are listed by type: a platform would let them stand in for
+    // the image they describe
+    match image.artifact_type {
+        Some(ref artifact_type) => desc["artifactType"] = artifact_type.as_str().into(),
+        None => desc["platform"] = platform::descriptor(image),
     }
-    if let Some(v) = image.get("os.features") {
-        platform["os.features"] = v.clone();
+
+    if let Some(ref idx_ann) = image.index_annotations {
+        desc["annotations"] = serde_json::to_value(idx_ann)?;
     }
-    if let Some(v) = image.get("variant") {
-        platform["variant"] = v.clone();
+    if !standard.is_empty() {
+        add_annotations(&mut desc["annotations"], &standard);
     }
-    desc["platform"] = platform;
 
-    if let Some(idx_ann) = image.get("index-annotations") {
-        desc["annotations"] = idx_ann.clone();
+    let digest = descriptor_digest(&desc)?;
+    if let Some(ref digest_file) = image.digest_file {
+        fs::write(digest_file, format!("{}\n", digest))
+            .with_context(|| format!("Writing digest file {}", digest_file.display()))?;
+    }
+    timings.add("manifest", start.elapsed());
+    if let (Some(layers), Ok(mut built)) = (layers, BUILT_IMAGES.lock()) {
+        built.insert(digest.to_string(), Arc::new(layers));
+    }
+
+    let start = Instant::now();
+    if image.writes("docker-archive") {
+        bar.set_message("writing docker archive");
+        docker_archive::write(global_conf, image, &desc)?;
+    }
+    if image.writes("oci-archive") {
+        bar.set_message("writing OCI archive");
+        oci_archive::write(global_conf, image, &desc)?;
+    }
+    if image.writes("docker-archive") || image.writes("oci-archive") {
+        timings.add("archives", start.elapsed());
     }
 
+    if global_conf.build_report {
+        timings.total = build_start.elapsed();
+        build_report::record_image(digest, timings);
+    }
+    info!(digest, "built image");
     Ok(desc)
 }
 
+/// What a failing image means for the other images of its document.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailurePolicy {
+    /// Build every image, but write no index.json if any fails (default)
+    Isolate,
+    /// Start no further images after the first failure (`--fail-fast`)
+    FailFast,
+    /// Write index.json with the images that built (`--keep-going`)
+    KeepGoing,
+}
+
+/// Digests of a written layout, in index.json order, and the images left
+/// out of it by a `--keep-going` build.
+#[derive(Debug)]
+pub struct LayoutDigests {
+    pub index: String,
+    pub manifests: Vec<String>,
+    /// Manifest digest and build ID of every image built, in manifest order
+    pub images: Vec<serde_json::Value>,
+    pub failures: Vec<ImageFailure>,
+}
+
 pub fn build_images(
     global_conf: &GlobalConfig,
-    images: &[serde_json::Value],
-    annotations: Option<&serde_json::Value>,
-) -> Result<()> {
+    images: &[ImageSpec],
+    annotations: Option<&StringMap>,
+    policy: FailurePolicy,
+) -> Result<LayoutDigests> {
+    let start = Instant::now();
     // Ensure blob output directory exists before parallel work
     let blob_dir = Path::new(&global_conf.output).join("blobs").join("sha256");
     fs::create_dir_all(&blob_dir)?;
 
-    let manifests: Result<Vec<serde_json::Value>> = if images.len() > 1 && global_conf.workers > 1
-    {
-        // Build images in parallel
-        images
-            .par_iter()
-            .map(|image| build_image(global_conf, image))
-            .collect()
-    } else {
-        // Single image or single worker — sequential
-        images
+    // Each image is isolated: an error or a panic in one does not stop the
+    // others unless failing fast, and every failure is reported once all
+    // images are done
+    // Workers take the next image of the wave as each finishes; the work
+    // inside every image still runs on the shared rayon pool. `None` marks
+    // an image skipped by --fail-fast.
+    let failed = AtomicBool::new(false);
+    let mut results: Vec<Option<Result<serde_json::Value>>> = images.iter().map(|_| None).collect();
+    for wave in build_waves(images) {
+        let next = AtomicUsize::new(0);
+        let done = Mutex::new(Vec::with_capacity(wave.len()));
+        let built = &results;
+        let worker = || loop {
+            let n = next.fetch_add(1, Ordering::Relaxed);
+            let Some(&i) = wave.get(n) else { break };
+            let image = &images[i];
+            let result = if policy == FailurePolicy::FailFast && failed.load(Ordering::Relaxed) {
+                None
+            } else {
+                let descriptor = |j: usize| built[j].as_ref().and_then(|r| r.as_ref().ok());
+                let subject = image.subject.map(|s| (s, descriptor(s)));
+                let parent = image.parent.as_ref().and_then(|p| p.built).map(|p| (p, descriptor(p)));
+                Some(match (subject, parent) {
+                    (Some((s, None)), _) => Err(anyhow::anyhow!(
+                        "{}: its subject {} was not built",
+                        image_label(i, image),
+                        image_label(s, &images[s])
+                    )),
+                    (_, Some((p, None))) => Err(anyhow::anyhow!(
+                        "{}: its parent {} was not built",
+                        image_label(i, image),
+                        image_label(p, &images[p])
+                    )),
+                    (subject, parent) => build_isolated(
+                        global_conf,
+                        i,
+                        image,
+                        subject.and_then(|(s, descriptor)| Some(Subject { image: &images[s], descriptor: descriptor? })),
+                        parent.and_then(|(_, descriptor)| descriptor),
+                    ),
+                })
+            };
+            if matches!(result, Some(Err(_))) {
+                failed.store(true, Ordering::Relaxed);
+            }
+            done.lock().unwrap_or_else(|e| e.into_inner()).push((i, result));
+        };
+        let parallelism = global_conf.image_parallelism.min(wave.len());
+        if parallelism > 1 {
+            std::thread::scope(|scope| {
+                for _ in 0..parallelism {
+                    // A worker that cannot be spawned leaves its share to this thread
+                    let spawned = std::thread::Builder::new().stack_size(BUILD_THREAD_STACK).spawn_scoped(scope, worker);
+                    if spawned.is_err() {
+                        worker();
+                    }
+                }
+            });
+        } else {
+            worker();
+        }
+        for (i, result) in done.into_inner().unwrap_or_else(|e| e.into_inner()) {
+            results[i] = result;
+        }
+    }
+    if images.len() > 1 && results.iter().any(|r| !matches!(r, Some(Ok(_)))) {
+        log_image_summary(images, &results);
+    }
+
+    // Results are kept by position in `images:`, so index.json lists the
+    // images in manifest order whichever finished first.
+    // With --keep-going the layout holds the images that did build, as long
+    // as there is at least one
+    let mut failures = Vec::new();
+    let descriptors: Vec<Option<serde_json::Value>> =
+        if policy == FailurePolicy::KeepGoing && results.iter().any(|r| matches!(r, Some(Ok(_)))) {
+            let mut descriptors = Vec::with_capacity(results.len());
+            for (i, (image, result)) in images.iter().zip(results).enumerate() {
+                descriptors.push(match result {
+                    Some(Ok(manifest)) => Some(manifest),
+                    Some(Err(error)) => {
+                        failures.push(ImageFailure {
+                            output: global_conf.output.clone(),
+                            image: i,
+                            name: group_ref(image),
+                            platform: format!("{}/{}", image.os, image.architecture),
+                            error,
+                        });
+                        None
+                    }
+                    None => None,
+                });
+            }
+            descriptors
+        } else {
+            collect_image_results(results)?.into_iter().map(Some).collect()
+        };
+
+    if global_conf.build_report {
+        build_report::write(global_conf, images, &descriptors, start.elapsed())?;
+    }
+
+    let layout = Layout::building(Path::new(&global_conf.output));
+    let built_images = descriptors
+        .iter()
+        .flatten()
+        .map(|desc| {
+            let digest = descriptor_digest(desc)?;
+            let manifest = layout.read_json(digest)?;
+            let config = layout.read_json(descriptor_digest(&manifest["config"])?)?;
+            Ok(serde_json::json!({ "manifest": digest, "buildId": build_id(&config) }))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // Images written only as archives stay out of index.json
+    let descriptors: Vec<Option<serde_json::Value>> = descriptors
+        .into_iter()
+        .zip(images)
+        .map(|(desc, image)| desc.filter(|_| image.writes("oci")))
+        .collect();
+    let manifests = index_entries(global_conf, images, &descriptors)?;
+    let index_digest = write_index(global_conf, &manifests, annotations)?;
+
+    let built = descriptors.iter().flatten().count();
+    info!(images = built, failed = failures.len(), output = %global_conf.output, "wrote image layout");
+    Ok(LayoutDigests {
+        index: index_digest,
+        manifests: manifests
             .iter()
-            .map(|image| build_image(global_conf, image))
-            .collect()
-    };
-    let manifests = manifests?;
+            .map(|desc| descriptor_digest(desc).map(str::to_string))
+            .collect::<Result<_>>()?,
+        images: built_images,
+        failures,
+    })
+}
 
-    let mut index = serde_json::json!({
-        "schemaVersion": 2,
-        "manifests": manifests,
-    });
-    if let Some(ann) = annotations {
-        index["annotations"] = ann.clone();
+/// Build ID of the image with `config`: the sha256 of the config without its
+/// `created` timestamps. It covers the runtime settings and the diff_ids of
+/// every layer, so rebuilds from the same inputs share it whatever their
+/// clock, compression or output format.
+pub fn build_id(config: &serde_json::Value) -> String {
+    let mut config = config.clone();
+    if let Some(config) = config.as_object_mut() {
+        config.remove("created");
     }
+    for entry in config["history"].as_array_mut().into_iter().flatten() {
+        if let Some(entry) = entry.as_object_mut() {
+            entry.remove("created");
+        }
+    }
+    format!("{:x}", Sha256::digest(config.to_string().as_bytes()))
+}
 
-    let index_path = Path::new(&global_conf.output).join("index.json");
-    let index_file = BufWriter::new(fs::File::create(&index_path)?);
-    serde_json::to_writer(index_file, &index)?;
+/// Build one image, turning a panic in it (including in the rayon tasks it
+/// spawns) into an error naming the image.
+pub fn build_isolated(
+    global_conf: &GlobalConfig,
+    i: usize,
+    image: &ImageSpec,
+    subject: Option<Subject>,
+    built_parent: Option<&serde_json::Value>,
+) -> Result<serde_json::Value> {
+    panic::catch_unwind(AssertUnwindSafe(|| build_image(global_conf, image, subject, built_parent)))
+        .unwrap_or_else(|payload| Err(anyhow::anyhow!("panicked: {}", panic_message(&*payload))))
+        .with_context(|| image_label(i, image))
+}
+
+/// How errors and logs name the image at position `i` of `images:`:
+/// `images[i] (name:tag, os/architecture)`, without the name if it has none.
+pub fn image_label(i: usize, image: &ImageSpec) -> String {
+    match group_ref(image) {
+        Some(name) => format!("images[{}] ({}, {}/{})", i, name, image.os, image.architecture),
+        None => format!("images[{}] ({}/{})", i, image.os, image.architecture),
+    }
+}
+
+/// Positions of `images` in build order, as waves of images that can be built
+/// in parallel: every image comes one wave after its `subject:` and the
+/// image its `parent.build` names.
+pub fn build_waves(images: &[ImageSpec]) -> Vec<Vec<usize>> {
+    // The manifest was checked for loops, so every image's dependencies
+    // are placed before it is
+    fn depth(i: usize, images: &[ImageSpec], depths: &mut [Option<usize>]) -> usize {
+        if let Some(d) = depths[i] {
+            return d;
+        }
+        let d = images[i].dependencies().map(|j| depth(j, images, depths) + 1).max().unwrap_or(0);
+        depths[i] = Some(d);
+        d
+    }
+    let mut depths = vec![None; images.len()];
+    let mut waves: Vec<Vec<usize>> = Vec::new();
+    for i in 0..images.len() {
+        let depth = depth(i, images, &mut depths);
+        if waves.len() <= depth {
+            waves.resize(depth + 1, Vec::new());
+        }
+        waves[depth].push(i);
+    }
+    waves
+}
+
+/// `name:tag` under which an image is grouped, if it has a name.
+pub fn group_ref(image: &ImageSpec) -> Option<String> {
+    let name = image.name.as_deref()?;
+    Some(format!("{}:{}", name, image.tag.as_deref().unwrap_or("latest")))
+}
+
+/// Top-level index entries for the manifest `descriptors` of `images` (`None`
+/// for images that were not built). Images with a `name:` are collected, per
+/// name and tag, into a nested image index written as a blob and listed under
+/// its `ref.name`, at the position of its first image; the others are listed
+/// as they are.
+pub fn index_entries(
+    global_conf: &GlobalConfig,
+    images: &[ImageSpec],
+    descriptors: &[Option<serde_json::Value>],
+) -> Result<Vec<serde_json::Value>> {
+    let mut groups: Vec<(Option<String>, Vec<serde_json::Value>)> = Vec::new();
+    for (image, desc) in images.iter().zip(descriptors) {
+        let Some(desc) = desc else { continue };
+        let group_ref = group_ref(image);
+        match groups.iter_mut().find(|(r, _)| r.is_some() && *r == group_ref) {
+            Some((_, members)) => members.push(desc.clone()),
+            None => groups.push((group_ref, vec![desc.clone()])),
+        }
+    }
+
+    let mut entries = Vec::with_capacity(groups.len());
+    for (group_ref, members) in groups {
+        let Some(group_ref) = group_ref else {
+            entries.extend(members);
+            continue;
+        };
+        let nested = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": MEDIA_TYPE_INDEX,
+            "manifests": members,
+        });
+        let mut blob = Blob::new(global_conf, Some(MEDIA_TYPE_INDEX));
+        blob.create(|f| {
+            let json_bytes = serde_json::to_vec(&nested)?;
+            f.write_all(&json_bytes)?;
+            Ok(Some(format!("{:x}", Sha256::digest(&json_bytes))))
+        })?;
+        let mut desc = blob
+            .descriptor
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Missing index blob descriptor"))?
+            .to_json();
+        desc["annotations"] = serde_json::json!({ ANNOTATION_REF_NAME: group_ref });
+        entries.push(desc);
+    }
+    Ok(entries)
+}
+
+/// Write `index.json` listing `manifests` and the `oci-layout` marker,
+/// returning the digest of the index. With `merge-index`, the entries of the
+/// existing index.json are kept where `manifests` does not replace them.
+pub fn write_index(
+    global_conf: &GlobalConfig,
+    manifests: &[serde_json::Value],
+    annotations: Option<&StringMap>,
+) -> Result<String> {
+    let output = Path::new(&global_conf.output);
+    let index_path = output.join("index.json");
+    let mut index = if global_conf.merge_index && index_path.exists() {
+        let mut index = Layout::building(output).index()?;
+        let existing = match index["manifests"].take() {
+            serde_json::Value::Array(existing) => existing,
+            _ => Vec::new(),
+        };
+        index["manifests"] = merge_index_entries(existing, manifests).into();
+        index
+    } else {
+        serde_json::json!({
+            "schemaVersion": 2,
+            "manifests": manifests,
+        })
+    };
+    for (key, value) in annotations.into_iter().flatten() {
+        index["annotations"][key] = value.as_str().into();
+    }
+
+    // Replaced in one rename, so readers never see a partial index
+    let index_bytes = serde_json::to_vec(&index)?;
+    let mut index_file = tempfile::NamedTempFile::new_in(output)?;
+    index_file.write_all(&index_bytes)?;
+    index_file.persist(&index_path).with_context(|| format!("Writing {}", index_path.display()))?;
 
     let layout = serde_json::json!({
         "imageLayoutVersion": "1.0.0",
@@ -644,5 +2030,175 @@ pub fn build_images(
     let layout_file = BufWriter::new(fs::File::create(&layout_path)?);
     serde_json::to_writer(layout_file, &layout)?;
 
-    Ok(())
+    Ok(format!("sha256:{:x}", Sha256::digest(&index_bytes)))
+}
+
+/// `existing` index entries with those of `manifests` in place of the ones
+/// they replace: entries with the same ref name or, for entries without one,
+/// the same platform. The remaining new entries are appended.
+pub fn merge_index_entries(existing: Vec<serde_json::Value>, manifests: &[serde_json::Value]) -> Vec<serde_json::Value> {
+    let same_slot = |old: &serde_json::Value, new: &serde_json::Value| {
+        let old_ref = &old["annotations"][ANNOTATION_REF_NAME];
+        let new_ref = &new["annotations"][ANNOTATION_REF_NAME];
+        match new_ref {
+            serde_json::Value::Null => old_ref.is_null() && old["platform"] == new["platform"],
+            _ => old_ref == new_ref,
+        }
+    };
+    // Existing entries still in place, and the entries of this build
+    let mut merged: Vec<(Option<serde_json::Value>, bool)> = existing.into_iter().map(|old| (Some(old), false)).collect();
+    for new in manifests {
+        let mut slot = None;
+        for (i, (entry, replaced)) in merged.iter_mut().enumerate() {
+            if !*replaced && entry.as_ref().is_some_and(|old| same_slot(old, new)) {
+                // Further existing entries for the same slot are dropped
+                *entry = None;
+                slot.get_or_insert(i);
+            }
+        }
+        match slot {
+            Some(i) => merged[i] = (Some(new.clone()), true),
+            None => merged.push((Some(new.clone()), true)),
+        }
+    }
+    merged.into_iter().filter_map(|(entry, _)| entry).collect()
+}
+
+/// Log whether each image of a document was built, failed or skipped.
+fn log_image_summary(images: &[ImageSpec], results: &[Option<Result<serde_json::Value>>]) {
+    for (i, (image, result)) in images.iter().zip(results).enumerate() {
+        let status = match result {
+            Some(Ok(_)) => "built",
+            Some(Err(_)) => "failed",
+            None => "skipped",
+        };
+        warn!(
+            image = i,
+            name = group_ref(image).as_deref(),
+            platform = %format!("{}/{}", image.os, image.architecture),
+            status,
+            "image result"
+        );
+    }
+}
+
+/// Manifest descriptors of all images, or the first failure with a summary
+/// of all of them as context.
+fn collect_image_results(results: Vec<Option<Result<serde_json::Value>>>) -> Result<Vec<serde_json::Value>> {
+    let total = results.len();
+    let mut manifests = Vec::with_capacity(total);
+    let mut errors = Vec::new();
+    let mut skipped = 0;
+    for result in results {
+        match result {
+            Some(Ok(manifest)) => manifests.push(manifest),
+            Some(Err(e)) => errors.push(e),
+            None => skipped += 1,
+        }
+    }
+    match (errors.len(), skipped) {
+        (0, _) => Ok(manifests),
+        (1, 0) => Err(errors.remove(0)),
+        (failed, skipped) => {
+            let summary = errors.iter().map(|e| format!("{:#}", e)).collect::<Vec<_>>();
+            let skipped = if skipped > 0 { format!(", {} skipped", skipped) } else { String::new() };
+            Err(errors.remove(0).context(format!(
+                "{} of {} images failed{}:\n  {}",
+                failed,
+                total,
+                skipped,
+                summary.join("\n  ")
+            )))
+        }
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic")
+}
+
+/// Walk, analyse and deduplicate every image as a build would, without
+/// writing any blobs or touching index.json. Parent layers are analysed
+/// straight from the parent layout instead of being re-compressed.
+pub fn plan_images(global_conf: &GlobalConfig, images: &[ImageSpec]) -> Result<serde_json::Value> {
+    let plans = images
+        .iter()
+        .map(|image| plan_image(global_conf, image))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(serde_json::json!({ "output": global_conf.output, "images": plans }))
+}
+
+fn plan_image(global_conf: &GlobalConfig, image: &ImageSpec) -> Result<serde_json::Value> {
+    let image_conf = with_image_overrides(global_conf, image)?;
+    let global_conf = image_conf.as_ref().unwrap_or(global_conf);
+    let mut parent_layers = Vec::new();
+    let mut parent_layout = None;
+    // A parent built by the same manifest is not built by a plan either
+    if let Some(parent) = image.parent.as_ref().filter(|p| p.built.is_none()) {
+        let index = parent_position(parent, image)?;
+        let layout = Layout::open(&parent.image).category(ErrorCategory::MissingParent)?;
+        let manifests = layout.image_manifests()?;
+        let desc = manifests
+            .get(index)
+            .with_context(|| format!("Parent {} has no manifest at index {}", parent.image.display(), index))
+            .category(ErrorCategory::MissingParent)?;
+        let manifest = layout.read_json(descriptor_digest(desc)?)?;
+        parent_layers = manifest["layers"].as_array().cloned().unwrap_or_default();
+        parent_layout = Some(layout);
+    }
+
+    // Each layer of `layers:` is planned against the parent's layers and the
+    // planned tars of the entries below it, as a build would deduplicate it
+    let mut planned: Vec<fs::File> = Vec::new();
+    let mut plans = Vec::new();
+    for (upper, source, _) in layer_sources(image, global_conf)? {
+        let mut tar_file = tempfile::tempfile()?;
+        if let LayerSource::Tar = source {
+            let size = io::copy(&mut open_layer_tar(&upper, global_conf)?, &mut tar_file)?;
+            plans.push(serde_json::json!({ "path": upper, "uncompressedSize": size }));
+            planned.push(tar_file);
+            continue;
+        }
+        let mut lower_readers = match parent_layout {
+            Some(ref layout) => parent_layers.iter().map(|layer| layout.open_layer(layer)).collect::<Result<Vec<_>>>()?,
+            None => Vec::new(),
+        };
+        for file in &planned {
+            let mut reader = file.try_clone()?;
+            reader.rewind()?;
+            lower_readers.push(Box::new(BufReader::new(reader)));
+        }
+        let dedup = image.dedup_lowers.as_deref().unwrap_or_default();
+        let lower_analysis = analyze_lowers(lower_readers, dedup, global_conf)?;
+        let mut plan = LayerPlan::default();
+        let mut tar_builder = tar::Builder::new(BufWriter::new(tar_file));
+        tar_builder.follow_symlinks(false);
+        create_layer(&mut tar_builder, &upper, &source, &lower_analysis, global_conf, Some(&mut plan))?;
+        let tar_file = tar_builder.into_inner()?.into_inner().map_err(|e| e.into_error())?;
+        let size = tar_file.metadata()?.len();
+
+        plans.push(serde_json::json!({
+            "path": upper,
+            "added": plan.added,
+            "skipped": plan.skipped,
+            "whiteouts": plan.whiteouts,
+            "uncompressedSize": size,
+        }));
+        planned.push(tar_file);
+    }
+    let layer = match image.layers {
+        Some(_) => serde_json::Value::Array(plans),
+        None => plans.pop().unwrap_or_default(),
+    };
+
+    Ok(serde_json::json!({
+        "architecture": image.architecture,
+        "os": image.os,
+        "parentLayers": parent_layers.len(),
+        "layer": layer,
+    }))
 }
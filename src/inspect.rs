@@ -0,0 +1,174 @@
+// Copyright (c) 2019, 2020 Codethink Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use anyhow::{bail, Context, Result};
+use clap::ArgMatches;
+use serde_json::Value;
+
+use crate::cli;
+use crate::layout::{descriptor_digest, descriptor_size, parse_image_ref, Layout, ANNOTATION_REF_NAME};
+use crate::list::platform_string;
+use crate::util::format_size;
+
+/// `build-oci inspect <layout> [--index N | --ref name] [--json]`: print the
+/// manifest, config and layers of one image without digging through blobs.
+pub fn run(args: &ArgMatches) -> Result<()> {
+    let image_ref = cli::required(args, "image");
+    let index = args.get_one::<usize>("index").copied();
+    let ref_name = args.get_one::<String>("ref").map(String::as_str);
+    let json = args.get_flag("json");
+
+    let (path, reference) = parse_image_ref(image_ref);
+    if reference.is_some() && (index.is_some() || ref_name.is_some()) {
+        bail!("Select the image with only one of <layout>:<ref>, --index and --ref");
+    }
+    let layout = Layout::open(&path)?;
+    let manifests = layout.manifests()?;
+    // The index.json entry selected, whose ref.name a nested index keeps to itself
+    let entry = match (index, ref_name) {
+        (Some(i), _) => manifests.get(i).with_context(|| {
+            format!("{} has {} index.json entries; there is no --index {}", path.display(), manifests.len(), i)
+        })?,
+        (None, Some(name)) => manifests
+            .iter()
+            .find(|d| d["annotations"][ANNOTATION_REF_NAME].as_str() == Some(name))
+            .with_context(|| format!("No image with ref.name '{}' in {}", name, path.display()))?,
+        (None, None) => {
+            let desc = layout.resolve(reference.as_deref())?;
+            let entry = manifests.iter().find(|d| {
+                d["digest"] == desc["digest"]
+                    || manifests.len() == 1
+                    || reference.is_some()
+                        && d["annotations"][ANNOTATION_REF_NAME].as_str() == reference.as_deref()
+            });
+            return inspect(&layout, &desc, entry.unwrap_or(&desc), json);
+        }
+    };
+    // Resolve by digest so a nested index still selects its only image
+    let desc = layout.resolve(Some(descriptor_digest(entry)?))?;
+    inspect(&layout, &desc, entry, json)
+}
+
+/// Print the image of manifest descriptor `desc`, listed in index.json as `entry`.
+fn inspect(layout: &Layout, desc: &Value, entry: &Value, json: bool) -> Result<()> {
+    let ref_name = entry["annotations"][ANNOTATION_REF_NAME].as_str();
+    let manifest = layout.read_json(descriptor_digest(desc)?)?;
+    let config = layout.read_json(descriptor_digest(&manifest["config"])?)?;
+
+    if json {
+        let out = serde_json::json!({
+            "ref": ref_name,
+            "descriptor": desc,
+            "manifest": manifest,
+            "config": config,
+        });
+        println!("{}", serde_json::to_string_pretty(&out)?);
+        return Ok(());
+    }
+
+    print_image(desc, ref_name, &manifest, &config);
+    Ok(())
+}
+
+fn print_image(desc: &Value, ref_name: Option<&str>, manifest: &Value, config: &Value) {
+    let field = |name: &str, value: &str| println!("{:<15}{}", format!("{}:", name), value);
+
+    field("Digest", desc["digest"].as_str().unwrap_or("-"));
+    if let Some(name) = ref_name {
+        field("Ref", name);
+    }
+    field("Media type", manifest["mediaType"].as_str().or(desc["mediaType"].as_str()).unwrap_or("-"));
+    if let Some(artifact_type) = manifest["artifactType"].as_str() {
+        field("Artifact type", artifact_type);
+    } else {
+        field("Platform", &platform_string(config));
+    }
+    if let Some(created) = config["created"].as_str() {
+        field("Created", created);
+    }
+    if let Some(subject) = manifest["subject"]["digest"].as_str() {
+        field("Subject", subject);
+    }
+    field(
+        "Config",
+        &format!(
+            "{} ({})",
+            manifest["config"]["digest"].as_str().unwrap_or("-"),
+            format_size(descriptor_size(&manifest["config"]))
+        ),
+    );
+
+    let run_config = &config["config"];
+    for key in ["Entrypoint", "Cmd"] {
+        if let Some(value) = run_config.get(key).filter(|v| !v.is_null()) {
+            field(key, &value.to_string());
+        }
+    }
+    for key in ["WorkingDir", "User", "StopSignal"] {
+        if let Some(value) = run_config[key].as_str().filter(|v| !v.is_empty()) {
+            field(key, value);
+        }
+    }
+    if let Some(ports) = run_config["ExposedPorts"].as_object().filter(|p| !p.is_empty()) {
+        field("ExposedPorts", &ports.keys().cloned().collect::<Vec<_>>().join(" "));
+    }
+    if let Some(env) = run_config["Env"].as_array().filter(|e| !e.is_empty()) {
+        println!("Env:");
+        for var in env {
+            println!("  {}", var.as_str().unwrap_or_default());
+        }
+    }
+    print_map("Labels", &run_config["Labels"]);
+    print_map("Annotations", &manifest["annotations"]);
+
+    let layers = manifest["layers"].as_array().cloned().unwrap_or_default();
+    let total: u64 = layers.iter().map(descriptor_size).sum();
+    field("Layers", &format!("{} ({})", layers.len(), format_size(total)));
+    if layers.is_empty() {
+        return;
+    }
+    let diff_ids = config["rootfs"]["diff_ids"].as_array();
+    println!("  {:<5}  {:>10}  {:<48}  DIGEST", "LAYER", "SIZE", "MEDIA TYPE");
+    for (i, layer) in layers.iter().enumerate() {
+        println!(
+            "  {:<5}  {:>10}  {:<48}  {}",
+            i,
+            format_size(descriptor_size(layer)),
+            layer["mediaType"].as_str().unwrap_or("-"),
+            layer["digest"].as_str().unwrap_or("-")
+        );
+        if let Some(diff_id) = diff_ids.and_then(|d| d.get(i)).and_then(Value::as_str) {
+            println!("  {:<5}  {:>10}  {:<48}  diff_id {}", "", "", "", diff_id);
+        }
+    }
+}
+
+/// Print a string map as indented `key=value` lines, sorted by key.
+fn print_map(title: &str, map: &Value) {
+    let Some(map) = map.as_object().filter(|m| !m.is_empty()) else {
+        return;
+    };
+    println!("{}:", title);
+    let mut entries: Vec<_> = map.iter().collect();
+    entries.sort_by(|a, b| a.0.cmp(b.0));
+    for (key, value) in entries {
+        println!("  {}={}", key, value.as_str().map(str::to_string).unwrap_or_else(|| value.to_string()));
+    }
+}
@@ -0,0 +1,246 @@
+// Copyright (c) 2019, 2020 Codethink Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! `build-oci verify <layout>`: a self-check of a layout before publishing.
+//! Every blob index.json reaches is re-hashed and its size checked against
+//! the descriptors naming it; layers are decompressed on the way to
+//! recompute their diff_ids, which must match `rootfs.diff_ids`, and the
+//! non-empty history entries of each config must match its layers.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read};
+
+use anyhow::Result;
+use rayon::prelude::*;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::blob::IO_BUF_MEDIUM;
+use crate::codec;
+use crate::layout::{descriptor_digest, Layout, MEDIA_TYPE_INDEX};
+use crate::util::advise_sequential;
+
+const MEDIA_TYPE_DOCKER_LIST: &str = "application/vnd.docker.distribution.manifest.list.v2+json";
+
+/// A blob and what the descriptors naming it say about it.
+#[derive(Debug, Default)]
+struct Claims {
+    /// Size given by each descriptor, and where that descriptor is
+    sizes: Vec<(u64, String)>,
+    /// Whether some manifest lists the blob as a layer
+    layer: bool,
+}
+
+/// What reading a blob found.
+#[derive(Debug)]
+struct Checked {
+    /// Uncompressed digest of a layer
+    diff_id: Option<String>,
+    problems: Vec<String>,
+}
+
+/// Descriptors reachable from index.json, and the image manifests among them.
+#[derive(Debug, Default)]
+struct Walk {
+    blobs: BTreeMap<String, Claims>,
+    /// Digest and contents of every image manifest
+    images: Vec<(String, Value)>,
+    visited: HashSet<String>,
+}
+
+impl Walk {
+    fn claim(&mut self, desc: &Value, by: &str) -> Result<String> {
+        let digest = descriptor_digest(desc)?.to_string();
+        let size = desc["size"].as_u64().unwrap_or(0);
+        self.blobs.entry(digest.clone()).or_default().sizes.push((size, by.to_string()));
+        Ok(digest)
+    }
+
+    /// Record `desc` and, for a manifest or an index, everything it refers to.
+    fn visit(&mut self, layout: &Layout, desc: &Value, by: &str) -> Result<()> {
+        let digest = self.claim(desc, by)?;
+        if !self.visited.insert(digest.clone()) || !layout.blob_path(&digest)?.is_file() {
+            return Ok(());
+        }
+        let media_type = desc["mediaType"].as_str().unwrap_or_default();
+        let Ok(document) = layout.read_json(&digest) else {
+            // Not JSON: a blob that the digest check covers on its own
+            return Ok(());
+        };
+        if media_type == MEDIA_TYPE_INDEX || media_type == MEDIA_TYPE_DOCKER_LIST || document.get("manifests").is_some() {
+            for child in document["manifests"].as_array().into_iter().flatten() {
+                self.visit(layout, child, &format!("index {}", digest))?;
+            }
+            return Ok(());
+        }
+        let by = format!("manifest {}", digest);
+        if let Some(config) = document.get("config") {
+            self.claim(config, &by)?;
+        }
+        for layer in document["layers"].as_array().into_iter().flatten() {
+            let layer_digest = self.claim(layer, &by)?;
+            let media_type = layer["mediaType"].as_str().unwrap_or_default();
+            if is_layer(media_type) {
+                self.blobs.entry(layer_digest).or_default().layer = true;
+            }
+        }
+        if let Some(subject) = document.get("subject") {
+            self.visit(layout, subject, &format!("subject of {}", by))?;
+        }
+        self.images.push((digest, document));
+        Ok(())
+    }
+}
+
+/// Whether `media_type` is that of a filesystem layer tar, compressed or not.
+fn is_layer(media_type: &str) -> bool {
+    media_type.starts_with("application/vnd.oci.image.layer.") || media_type.starts_with("application/vnd.docker.image.rootfs.")
+}
+
+/// Reader that hashes and counts the bytes read through it.
+struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+    count: u64,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+/// Re-hash the blob `digest`, and for a layer also recompute its diff_id.
+fn check_blob(layout: &Layout, digest: &str, claims: &Claims) -> Result<Checked> {
+    let mut problems = Vec::new();
+    let Some(hash) = digest.strip_prefix("sha256:") else {
+        problems.push(format!("{}: only sha256 digests can be verified", digest));
+        return Ok(Checked { diff_id: None, problems });
+    };
+    let path = layout.blob_path(digest)?;
+    let file = match fs::File::open(&path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let by: Vec<&str> = claims.sizes.iter().map(|(_, by)| by.as_str()).collect();
+            problems.push(format!("{}: missing blob, referenced by {}", digest, by.join(", ")));
+            return Ok(Checked { diff_id: None, problems });
+        }
+        Err(e) => return Err(e.into()),
+    };
+    advise_sequential(&file);
+    let mut reader = HashingReader { inner: BufReader::with_capacity(IO_BUF_MEDIUM, file), hasher: Sha256::new(), count: 0 };
+
+    let mut diff_id = None;
+    if claims.layer {
+        let mut compressed = BufReader::with_capacity(IO_BUF_MEDIUM, &mut reader);
+        let codec = codec::for_magic(compressed.fill_buf()?);
+        let mut uncompressed = Sha256::new();
+        let decoded = codec
+            .decoder(Box::new(compressed))
+            .and_then(|mut decoder| Ok(io::copy(&mut decoder, &mut uncompressed)?));
+        match decoded {
+            Ok(_) => diff_id = Some(format!("sha256:{:x}", uncompressed.finalize())),
+            Err(e) => problems.push(format!("{}: cannot decompress the {} layer: {:#}", digest, codec.name(), e)),
+        }
+    }
+    // The rest of the blob, after a layer's end or all of any other blob
+    io::copy(&mut reader, &mut io::sink())?;
+
+    let actual = format!("{:x}", reader.hasher.finalize());
+    if actual != hash {
+        problems.push(format!("{}: content hashes to sha256:{}", digest, actual));
+    }
+    for (size, by) in &claims.sizes {
+        if *size != reader.count {
+            problems.push(format!("{}: {} bytes, but {} gives size {}", digest, reader.count, by, size));
+        }
+    }
+    Ok(Checked { diff_id, problems })
+}
+
+/// Compare the layers of `manifest` with the diff_ids and history of its config.
+fn check_image(
+    layout: &Layout,
+    digest: &str,
+    manifest: &Value,
+    checked: &BTreeMap<&str, Checked>,
+    problems: &mut Vec<String>,
+) -> Result<()> {
+    let layers = manifest["layers"].as_array().map(Vec::as_slice).unwrap_or_default();
+    if !layers.iter().any(|layer| is_layer(layer["mediaType"].as_str().unwrap_or_default())) {
+        // An artifact: its blobs are not filesystem layers
+        return Ok(());
+    }
+    let Ok(config) = layout.read_json(descriptor_digest(&manifest["config"])?) else {
+        return Ok(());
+    };
+    let diff_ids: Vec<&str> = config["rootfs"]["diff_ids"].as_array().into_iter().flatten().filter_map(Value::as_str).collect();
+    if diff_ids.len() != layers.len() {
+        problems.push(format!("manifest {}: {} layers, but its config lists {} diff_ids", digest, layers.len(), diff_ids.len()));
+    }
+    for (i, (layer, expected)) in layers.iter().zip(&diff_ids).enumerate() {
+        let layer_digest = descriptor_digest(layer)?;
+        if let Some(actual) = checked.get(layer_digest).and_then(|c| c.diff_id.as_deref()) {
+            if actual != *expected {
+                problems.push(format!(
+                    "manifest {}: layer {} ({}) has diff_id {}, but its config lists {}",
+                    digest, i, layer_digest, actual, expected
+                ));
+            }
+        }
+    }
+    if let Some(history) = config["history"].as_array() {
+        let non_empty = history.iter().filter(|entry| entry["empty_layer"].as_bool() != Some(true)).count();
+        if non_empty != diff_ids.len() {
+            problems.push(format!(
+                "manifest {}: config history has {} non-empty entries for {} diff_ids",
+                digest,
+                non_empty,
+                diff_ids.len()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Check every blob `root`'s index.json reaches, returning the number of
+/// blobs checked and the problems found.
+pub fn check_layout(root: &std::path::Path) -> Result<(usize, Vec<String>)> {
+    let layout = Layout::open(root)?;
+    let mut walk = Walk::default();
+    for desc in layout.index()?["manifests"].as_array().into_iter().flatten() {
+        walk.visit(&layout, desc, "index.json")?;
+    }
+
+    let checked: BTreeMap<&str, Checked> = walk
+        .blobs
+        .par_iter()
+        .map(|(digest, claims)| Ok((digest.as_str(), check_blob(&layout, digest, claims)?)))
+        .collect::<Result<_>>()?;
+    let mut problems: Vec<String> = checked.values().flat_map(|c| c.problems.iter().cloned()).collect();
+    for (digest, manifest) in &walk.images {
+        check_image(&layout, digest, manifest, &checked, &mut problems)?;
+    }
+    Ok((walk.blobs.len(), problems))
+}
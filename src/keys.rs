@@ -0,0 +1,193 @@
+// Copyright (c) 2019, 2020 Codethink Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Signing keys for `gpg-sign:`. Providers only sign: build-oci does not
+//! encrypt layers, so there is nothing to decrypt or wrap keys for.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use anyhow::{bail, Context, Result};
+
+/// A signing key, wherever it is stored: a GnuPG keyring, a file, an
+/// environment variable, or an HSM/KMS behind a helper program.
+pub trait KeyProvider: Send + Sync {
+    /// Key reference for logs and error messages; never contains key material
+    fn describe(&self) -> String;
+
+    /// Armored OpenPGP detached signature over `data`.
+    fn sign(&self, data: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// Resolve a key reference to its provider. `homedir` is the GnuPG home
+/// used by keyring keys. Supported references:
+/// - `gpg:<id>` or a bare key ID, fingerprint or user ID: the GnuPG keyring
+/// - `file:<path>`: an armored OpenPGP secret key file
+/// - `env:<VAR>`: an armored OpenPGP secret key in an environment variable
+/// - `pkcs11:<uri>` and KMS URLs such as `awskms://...`, `gcpkms://...` or
+///   `hashivault://...`: delegated to a `build-oci-key-<scheme>` helper on `PATH`
+pub fn provider(reference: &str, homedir: Option<&Path>) -> Result<Box<dyn KeyProvider>> {
+    if let Some(path) = reference.strip_prefix("file:") {
+        return Ok(Box::new(FileKey { path: PathBuf::from(path) }));
+    }
+    if let Some(var) = reference.strip_prefix("env:") {
+        return Ok(Box::new(EnvKey { var: var.to_string() }));
+    }
+    if let Some(id) = reference.strip_prefix("gpg:") {
+        return Ok(Box::new(KeyringKey::new(id, homedir)));
+    }
+    if reference.starts_with("pkcs11:") {
+        return Ok(Box::new(HelperKey { scheme: "pkcs11".to_string(), uri: reference.to_string() }));
+    }
+    if let Some((scheme, _)) = reference.split_once("://") {
+        if scheme.is_empty() || !scheme.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            bail!("Invalid key URL scheme in '{}'", reference);
+        }
+        return Ok(Box::new(HelperKey { scheme: scheme.to_string(), uri: reference.to_string() }));
+    }
+    Ok(Box::new(KeyringKey::new(reference, homedir)))
+}
+
+/// Key in a GnuPG keyring, selected with `--local-user`.
+struct KeyringKey {
+    id: String,
+    homedir: Option<PathBuf>,
+}
+
+impl KeyringKey {
+    fn new(id: &str, homedir: Option<&Path>) -> Self {
+        KeyringKey { id: id.to_string(), homedir: homedir.map(Path::to_path_buf) }
+    }
+}
+
+impl KeyProvider for KeyringKey {
+    fn describe(&self) -> String {
+        format!("gpg:{}", self.id)
+    }
+
+    fn sign(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut cmd = gpg(self.homedir.as_deref());
+        cmd.args(["--armor", "--detach-sign", "--local-user", &self.id]);
+        run(cmd, Some(data), "signing")
+    }
+}
+
+/// Armored secret key file, imported into a throwaway keyring for each signature.
+struct FileKey {
+    path: PathBuf,
+}
+
+impl KeyProvider for FileKey {
+    fn describe(&self) -> String {
+        format!("file:{}", self.path.display())
+    }
+
+    fn sign(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let key = std::fs::read(&self.path)
+            .with_context(|| format!("Reading key file {}", self.path.display()))?;
+        sign_with_imported_key(&key, data)
+    }
+}
+
+/// Armored secret key held in an environment variable (e.g. a CI secret).
+struct EnvKey {
+    var: String,
+}
+
+impl KeyProvider for EnvKey {
+    fn describe(&self) -> String {
+        format!("env:{}", self.var)
+    }
+
+    fn sign(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let key = std::env::var(&self.var)
+            .with_context(|| format!("Environment variable '{}' is not set", self.var))?;
+        sign_with_imported_key(key.as_bytes(), data)
+    }
+}
+
+/// PKCS#11 or KMS key, signed by an external `build-oci-key-<scheme>` helper
+/// invoked as `build-oci-key-<scheme> sign <uri>` with the data on stdin and
+/// the armored signature on stdout.
+struct HelperKey {
+    scheme: String,
+    uri: String,
+}
+
+impl KeyProvider for HelperKey {
+    fn describe(&self) -> String {
+        self.uri.clone()
+    }
+
+    fn sign(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let helper = format!("build-oci-key-{}", self.scheme);
+        let mut cmd = Command::new(&helper);
+        cmd.arg("sign").arg(&self.uri);
+        run(cmd, Some(data), "signing")
+            .with_context(|| format!("Key helper {} failed for {}", helper, self.uri))
+    }
+}
+
+fn sign_with_imported_key(key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    let homedir = tempfile::tempdir().context("Creating temporary GnuPG home")?;
+    let mut import = gpg(Some(homedir.path()));
+    import.arg("--import");
+    run(import, Some(key), "key import")?;
+
+    // The temporary keyring holds only the imported key, which gpg uses by default
+    let mut cmd = gpg(Some(homedir.path()));
+    cmd.args(["--armor", "--detach-sign"]);
+    run(cmd, Some(data), "signing")
+}
+
+/// `gpg --batch`, optionally with an explicit home directory.
+pub fn gpg(homedir: Option<&Path>) -> Command {
+    let mut cmd = Command::new("gpg");
+    cmd.arg("--batch");
+    if let Some(homedir) = homedir {
+        cmd.arg("--homedir").arg(homedir);
+    }
+    cmd
+}
+
+/// Run a command, feeding `input` on stdin, and return its stdout.
+pub fn run(mut cmd: Command, input: Option<&[u8]>, what: &str) -> Result<Vec<u8>> {
+    let program = cmd.get_program().to_string_lossy().into_owned();
+    cmd.stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    let mut child = cmd.spawn().with_context(|| format!("Failed to run {}", program))?;
+    if let Some(input) = input {
+        // Take stdin so it is closed once written
+        let mut stdin = child.stdin.take().context("Missing stdin pipe")?;
+        stdin.write_all(input)?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        bail!(
+            "{} {} failed:\n{}",
+            program,
+            what,
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        );
+    }
+    Ok(output.stdout)
+}
@@ -21,23 +21,37 @@
 use std::borrow::Cow;
 use std::collections::HashMap;
 use std::fs;
-use std::io::{BufReader, Read, Write};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::ffi::OsStringExt;
 use std::os::unix::fs::{MetadataExt, PermissionsExt};
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, LazyLock};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
 use jwalk::WalkDir;
 use lasso::ThreadedRodeo;
 use memmap2::Mmap;
 use rayon::prelude::*;
-use rustc_hash::FxHashMap;
+use rustc_hash::{FxHashMap, FxHashSet};
 use sha2::{Digest, Sha256};
 use smallvec::SmallVec;
+use tracing::{debug, info, trace};
 
 use crate::blob::IO_BUF_LARGE;
-use crate::util::advise_sequential;
+use crate::codec;
+use crate::config::LowerSpec;
+use crate::limits::Lease;
+use crate::layer_metadata;
+use crate::lint;
+use crate::listing::{EntryType, ListingEntry};
+use crate::overlay::OverlayUpper;
+use crate::progress::Bar;
+use crate::retry::Reader;
+use crate::tar_parser::parse_archive;
+pub use crate::tar_parser::{split_path, ArchiveEntries, LowerEntry};
+use crate::util::{advise_sequential, open_source};
 use crate::GlobalConfig;
 
 /// Global thread-safe string interner for path deduplication.
@@ -46,15 +60,28 @@ use crate::GlobalConfig;
 #[allow(dead_code)]
 static PATH_INTERNER: LazyLock<ThreadedRodeo> = LazyLock::new(ThreadedRodeo::default);
 
+/// Version of the tar conventions layers are written with: entry order,
+/// header fields, PAX records and whiteout style (see "Layer tar format" in
+/// the README). Anything that changes the tar written for the same files
+/// needs a new version, with the old one still written when
+/// `format-version:` asks for it.
+pub const FORMAT_VERSION: u32 = 1;
+
 pub const PAX_HEADER_SHA256: &str = "freedesktopsdk.checksum.sha256";
 pub const PAX_HEADER_XATTR: &str = "SCHILY.xattr.";
+/// xattr holding a file's sha256, trusted instead of hashing the file
+pub const XATTR_SHA256: &str = "user.checksum.sha256";
 
-fn file_sha256(path: &Path) -> Result<String> {
-    let file = fs::File::open(path)?;
+/// Ignore file read from the layer root, with gitignore semantics
+pub const IGNORE_FILE: &str = ".ociignore";
+
+pub(crate) fn file_sha256(path: &Path, config: &GlobalConfig) -> Result<String> {
+    let retry = config.io_retry;
+    let file = retry.run(path, || open_source(path, config.noatime))?;
     advise_sequential(&file); // Hint kernel for sequential read
-    let mut reader = BufReader::with_capacity(IO_BUF_LARGE, file);
+    let mut reader = BufReader::with_capacity(IO_BUF_LARGE, Reader::new(path, file, retry));
     let mut hasher = Sha256::new();
-    let mut buf = [0u8; IO_BUF_LARGE];
+    let mut buf = vec![0u8; IO_BUF_LARGE];
     loop {
         let n = reader.read(&mut buf)?;
         if n == 0 {
@@ -65,119 +92,111 @@ fn file_sha256(path: &Path) -> Result<String> {
     Ok(format!("{:x}", hasher.finalize()))
 }
 
-/// Lower layer entry metadata - field order optimized to reduce struct padding
-#[derive(Debug, Clone)]
-pub struct LowerEntry {
-    // 8-byte aligned fields first (pointer-based types)
-    pub pax_headers: HashMap<String, String>,
-    pub symlink_target: Option<String>,
-    // 8-byte aligned primitives
-    pub uid: u64,
-    pub gid: u64,
-    pub mtime: u64,
-    pub size: u64,
-    // 4-byte aligned, followed by 1-byte - packs efficiently
-    pub mode: u32,
-    pub entry_type: u8,
-}
-
 pub struct LowerAnalysis {
     pub files: FxHashMap<String, LowerEntry>,
     // Use SmallVec for directory contents as most dirs have few entries
     pub dir_contents: FxHashMap<String, SmallVec<[String; 4]>>,
 }
 
-/// Represents parsed entries from a single tar archive before merging
-struct ArchiveEntries {
-    /// Regular entries (non-whiteout)
-    entries: Vec<(String, LowerEntry)>,
-    /// Opaque whiteouts - directories whose contents should be deleted
-    opaque_whiteouts: Vec<String>,
-    /// File whiteouts - specific files to delete
-    file_whiteouts: Vec<String>,
-}
-
-/// Parse a single tar archive into entries (can run in parallel)
-fn parse_archive<R: Read>(archive: &mut tar::Archive<R>) -> Result<ArchiveEntries> {
-    let mut entries = Vec::with_capacity(1024);
-    let mut opaque_whiteouts = Vec::new();
-    let mut file_whiteouts = Vec::new();
-
-    for entry_result in archive.entries()? {
-        let mut entry = entry_result?;
-
-        let entry_type = entry.header().entry_type().as_byte();
-        let uid = entry.header().uid()?;
-        let gid = entry.header().gid()?;
-        let mode = entry.header().mode()?;
-        let mtime = entry.header().mtime()?;
-        let size = entry.header().size()?;
-        let path_str = entry.path()?.to_string_lossy().to_string();
-        let (dirname, basename) = split_path(&path_str);
-
-        if basename == ".wh..wh..opq" {
-            opaque_whiteouts.push(dirname.into_owned());
-        } else if let Some(real_name_str) = basename.strip_prefix(".wh.") {
-            let real_name = real_name_str;
-            let full_path = if dirname.is_empty() {
-                real_name.to_string()
-            } else {
-                format!("{}/{}", dirname, real_name)
-            };
-            file_whiteouts.push(full_path);
-        } else {
-            let mut pax_headers = HashMap::with_capacity(8);
-            if let Some(pax) = entry.pax_extensions()? {
-                for ext in pax.flatten() {
-                    let key = ext.key().unwrap_or_default().to_string();
-                    let val = ext.value().unwrap_or_default().to_string();
-                    pax_headers.insert(key, val);
-                }
-            }
+/// Analysis of the lower layer streams `lowers` with `dedup` stacked above.
+pub fn analyze_lowers<R: Read + Send>(
+    lowers: Vec<R>,
+    dedup: &[LowerSpec],
+    config: &GlobalConfig,
+) -> Result<LowerAnalysis> {
+    // Parse all archives in parallel
+    let parsed: Result<Vec<ArchiveEntries>> = lowers.into_par_iter().map(parse_archive).collect();
+    let mut layers: Vec<Arc<ArchiveEntries>> = parsed?.into_iter().map(Arc::new).collect();
+    layers.extend(dedup_lowers(dedup, config)?);
+    Ok(merge_lowers(layers))
+}
 
-            // Cache symlink target to avoid re-reading later
-            let symlink_target = if entry_type == tar::EntryType::Symlink.as_byte() {
-                entry
-                    .header()
-                    .link_name()?
-                    .map(|p| p.to_string_lossy().to_string())
-            } else {
-                None
+/// Entries of `dedup-lowers:`, bottom first. A directory gives the entries a
+/// layer of it would record; a tar is parsed as a lower layer would be.
+pub fn dedup_lowers(lowers: &[LowerSpec], config: &GlobalConfig) -> Result<Vec<Arc<ArchiveEntries>>> {
+    lowers
+        .iter()
+        .map(|lower| {
+            let entries = match (&lower.dir, &lower.tar) {
+                (Some(dir), _) => directory_entries(dir, config)
+                    .with_context(|| format!("Reading lower directory {}", dir.display()))?,
+                (None, Some(tar)) => open_tar(tar)
+                    .and_then(parse_archive)
+                    .with_context(|| format!("Reading lower tar {}", tar.display()))?,
+                (None, None) => ArchiveEntries::default(),
             };
+            Ok(Arc::new(entries))
+        })
+        .collect()
+}
 
-            let le = LowerEntry {
-                pax_headers,
-                symlink_target,
-                uid,
-                gid,
-                mtime,
-                size,
-                mode,
-                entry_type,
-            };
-            entries.push((path_str, le));
-        }
-    }
+/// Uncompressed stream of a tar file, its compression recognised by its magic.
+fn open_tar(path: &Path) -> Result<Box<dyn Read + Send>> {
+    let file = fs::File::open(path)?;
+    advise_sequential(&file);
+    let mut reader = BufReader::new(file);
+    let codec = codec::for_magic(reader.fill_buf()?);
+    codec.decoder(Box::new(reader))
+}
 
-    Ok(ArchiveEntries {
-        entries,
-        opaque_whiteouts,
-        file_whiteouts,
-    })
+/// The entries `create_layer` would write for directory `dir` without lower
+/// layers, as `parse_archive` would read them back.
+fn directory_entries(dir: &Path, config: &GlobalConfig) -> Result<ArchiveEntries> {
+    if !dir.is_dir() {
+        anyhow::bail!("not a directory");
+    }
+    // Only checksums are needed, so nothing is kept in memory, and
+    // reference directories are not written to
+    let config = GlobalConfig { prefetch_limit_mb: 0, write_checksum_xattrs: false, ..config.clone() };
+    let label = dir.display().to_string();
+    let layer_data = precalculate_layer_data(dir, None, &PathFilter::default(), &config, &Bar::files(&label, "scanning"))?;
+    let epoch = config.source_date_epoch;
+
+    let mut entries = Vec::with_capacity(layer_data.entries.len());
+    for (path, info) in &layer_data.entries {
+        let mut pax_headers = HashMap::new();
+        let mut symlink_target = None;
+        let (entry_type, size) = match &info.kind {
+            EntryKind::Regular { checksum, .. } => {
+                for (attr, value) in &info.xattrs {
+                    pax_headers.insert(format!("{}{}", PAX_HEADER_XATTR, attr), value.clone());
+                }
+                pax_headers.insert(PAX_HEADER_SHA256.to_string(), checksum.clone());
+                (tar::EntryType::Regular, info.metadata.size)
+            }
+            EntryKind::Directory => (tar::EntryType::Directory, 0),
+            EntryKind::Symlink { target } => {
+                symlink_target = Some(target.clone());
+                (tar::EntryType::Symlink, 0)
+            }
+            EntryKind::Hardlink { .. } => (tar::EntryType::Link, 0),
+            EntryKind::Other => (tar::EntryType::Regular, info.metadata.size),
+        };
+        let entry = LowerEntry {
+            pax_headers,
+            symlink_target,
+            uid: info.metadata.uid,
+            gid: info.metadata.gid,
+            mtime: epoch.unwrap_or(info.metadata.mtime as u64),
+            size,
+            mode: info.metadata.mode,
+            entry_type: entry_type.as_byte(),
+        };
+        entries.push((format!("./{}", pathdiff(path, dir)), entry));
+    }
+    Ok(ArchiveEntries { entries, ..Default::default() })
 }
 
-pub fn analyze_lowers<R: Read + Send>(lowers: &mut [tar::Archive<R>]) -> Result<LowerAnalysis> {
-    // Parse all archives in parallel
-    let parsed: Result<Vec<ArchiveEntries>> = lowers
-        .par_iter_mut()
-        .map(|archive| parse_archive(archive))
-        .collect();
-    let parsed = parsed?;
+/// Merge parsed lower layers, bottom first, applying whiteouts as overlayfs would.
+/// Layers shared with a cache are cloned, others are consumed.
+pub fn merge_lowers(layers: Vec<Arc<ArchiveEntries>>) -> LowerAnalysis {
+    let num_layers = layers.len();
 
     // Merge results sequentially to maintain overlay semantics
     let mut lower_files: FxHashMap<String, LowerEntry> = FxHashMap::default();
 
-    for archive_entries in parsed {
+    for archive_entries in layers {
+        let archive_entries = Arc::unwrap_or_clone(archive_entries);
         // Apply opaque whiteouts from this layer using O(n) retain
         // More efficient than collecting keys and removing one by one
         if !archive_entries.opaque_whiteouts.is_empty() {
@@ -218,26 +237,14 @@ pub fn analyze_lowers<R: Read + Send>(lowers: &mut [tar::Archive<R>]) -> Result<
     for child_list in dir_contents.values_mut() {
         child_list.sort();
     }
+    debug!(layers = num_layers, files = lower_files.len(), "analysed lower layers");
 
-    Ok(LowerAnalysis {
+    LowerAnalysis {
         files: lower_files,
         dir_contents,
-    })
+    }
 }
 
-#[inline]
-fn split_path(path: &str) -> (Cow<str>, Cow<str>) {
-    let p = Path::new(path);
-    let basename = p
-        .file_name()
-        .map(|f| f.to_string_lossy())
-        .unwrap_or_default();
-    let dirname = p
-        .parent()
-        .map(|d| d.to_string_lossy())
-        .unwrap_or_default();
-    (dirname, basename)
-}
 
 
 /// Threshold for using mmap vs reading into memory
@@ -276,7 +283,8 @@ pub enum EntryKind {
     },
     Directory,
     Symlink {
-        target: String,
+        /// Raw bytes of the target, which need not be UTF-8
+        target: Vec<u8>,
     },
     Hardlink {
         target_path: String,
@@ -296,38 +304,203 @@ pub struct LayerData {
     pub entries: FxHashMap<PathBuf, EntryInfo>,
     /// Map from relative directory path to list of child basenames.
     pub children: FxHashMap<PathBuf, Vec<String>>,
+    /// Build-wide memory and file slots held by the cached contents
+    _lease: Lease,
 }
 
-use dashmap::DashMap;
+use dashmap::{DashMap, DashSet};
+
+/// `exclude:` and `include-only:` patterns of a layer directory, in gitignore
+/// syntax, relative to its root.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PathFilter {
+    pub exclude: Vec<String>,
+    /// When not empty, only matching paths and the directories above them are kept
+    pub include: Vec<String>,
+}
+
+impl PathFilter {
+    /// Check that every pattern parses.
+    pub fn check(&self) -> Result<()> {
+        patterns_matcher(Path::new("/"), None, &self.exclude)?;
+        patterns_matcher(Path::new("/"), None, &self.include)?;
+        Ok(())
+    }
+}
+
+/// Matcher rooted at `root` of the rules in `file`, then `patterns`, or
+/// `None` without any.
+fn patterns_matcher(root: &Path, file: Option<&Path>, patterns: &[String]) -> Result<Option<Gitignore>> {
+    if file.is_none() && patterns.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = GitignoreBuilder::new(root);
+    if let Some(path) = file {
+        if let Some(err) = builder.add(path) {
+            return Err(err).with_context(|| format!("Reading {}", path.display()));
+        }
+    }
+    for pattern in patterns {
+        builder.add_line(None, pattern).with_context(|| format!("Invalid pattern '{}'", pattern))?;
+    }
+    let matcher = builder.build().context("Building path patterns")?;
+    Ok(Some(matcher))
+}
+
+/// Load `.ociignore` from the layer root, if present, with the `exclude:`
+/// patterns after its own.
+fn load_ignore_file(upper: &Path, exclude: &[String]) -> Result<Option<Gitignore>> {
+    let path = upper.join(IGNORE_FILE);
+    let file = path.is_file().then_some(path.as_path());
+    let matcher = patterns_matcher(upper, file, exclude)?;
+    if let Some(ref matcher) = matcher {
+        debug!(rules = matcher.num_ignores(), "using {} and exclude patterns", IGNORE_FILE);
+    }
+    Ok(matcher)
+}
+
+/// Store `checksum` on the source file so later builds, and other tools
+/// reading the same xattr, skip hashing it. Read-only files and
+/// filesystems without user xattrs are left alone.
+fn write_checksum_xattr(path: &Path, dev: u64, checksum: &str, no_xattrs: &DashSet<u64>) {
+    if let Err(err) = xattr::set(path, XATTR_SHA256, checksum.as_bytes()) {
+        if !xattrs_unsupported(&err, path, dev, no_xattrs) {
+            debug!(path = %path.display(), error = %err, "not storing checksum xattr");
+        }
+    }
+}
+
+/// Whether `err` says the filesystem of `dev` has no xattr support. The
+/// device is then added to `no_xattrs`, logging it once, so that no
+/// further xattr calls are made for its files.
+fn xattrs_unsupported(err: &io::Error, path: &Path, dev: u64, no_xattrs: &DashSet<u64>) -> bool {
+    if err.raw_os_error() != Some(libc::ENOTSUP) {
+        return false;
+    }
+    if no_xattrs.insert(dev) {
+        info!(path = %path.display(), "filesystem does not support extended attributes, skipping them");
+    }
+    true
+}
+
+/// Check that symlink `path` resolves inside `upper` or one of `roots`, and
+/// not to a directory containing it.
+fn check_symlink_target(path: &Path, upper: &Path, roots: &[PathBuf]) -> Result<()> {
+    let target = fs::canonicalize(path).with_context(|| format!("Following symlink {}", path.display()))?;
+    if let Some(parent) = path.parent() {
+        if fs::canonicalize(parent).is_ok_and(|parent| parent.starts_with(&target)) {
+            anyhow::bail!("Symlink {} points to {}, which contains it: a loop", path.display(), target.display());
+        }
+    }
+    if !target.starts_with(upper) && !roots.iter().any(|root| target.starts_with(root)) {
+        anyhow::bail!(
+            "Symlink {} resolves to {}, outside the layer directory and symlink-roots",
+            path.display(),
+            target.display()
+        );
+    }
+    Ok(())
+}
 
 /// Collect and pre-calculate all data for a directory tree in parallel.
-fn precalculate_layer_data(upper: &Path, config: &GlobalConfig) -> LayerData {
+/// With `follow` (the extra roots symlinks may resolve into), symlinks are
+/// replaced by what they point to.
+fn precalculate_layer_data(
+    upper: &Path,
+    follow: Option<&[PathBuf]>,
+    filter: &PathFilter,
+    config: &GlobalConfig,
+    bar: &Bar,
+) -> Result<LayerData> {
     // Use saturating_mul to prevent overflow on large prefetch limits
     let memory_limit = config.prefetch_limit_mb.saturating_mul(1024).saturating_mul(1024);
     let memory_used = Arc::new(AtomicUsize::new(0));
+    let lease = Lease::new(&config.limits);
     let skip_xattrs = config.skip_xattrs;
+    // Without reading xattrs, stored checksums would never be used
+    let write_checksums = config.write_checksum_xattrs && !skip_xattrs;
+    let retry = config.io_retry;
+    let noatime = config.noatime;
 
     // Map of (dev, ino) -> first seen relative path for hardlink detection
     // Use DashMap for wait-free concurrent access
     let inode_map: Arc<DashMap<(u64, u64), String>> = Arc::new(DashMap::default());
+    // Devices whose filesystem rejected an xattr call with ENOTSUP: later files
+    // on them skip xattr calls instead of failing one each
+    let no_xattrs: DashSet<u64> = DashSet::new();
+
+    // Entries matching .ociignore (and the ignore file itself) are left out
+    // before any hashing or reading happens
+    let ignore = load_ignore_file(upper, &filter.exclude)?;
+    let ignore_file = upper.join(IGNORE_FILE);
+    let is_ignored = |entry: &jwalk::DirEntry<((), ())>| match ignore {
+        Some(ref matcher) => {
+            let path = entry.path();
+            path == ignore_file
+                || matcher
+                    .matched_path_or_any_parents(&path, entry.file_type().is_dir())
+                    .is_ignore()
+        }
+        None => false,
+    };
 
     // Use jwalk to collect all entries (dirs, files, symlinks)
-    let all_entries: Vec<jwalk::DirEntry<((), ())>> = WalkDir::new(upper)
+    let walk = WalkDir::new(upper)
         .skip_hidden(false)
-        .follow_links(false)
-        .into_iter()
-        .filter_map(|entry| entry.ok())
-        .collect();
+        .follow_links(follow.is_some())
+        .into_iter();
+    let mut all_entries: Vec<jwalk::DirEntry<((), ())>> = match follow {
+        None => walk
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.depth() == 0 || !is_ignored(entry))
+            .collect(),
+        // Loops and dangling or escaping links fail instead of being skipped
+        Some(roots) => {
+            let canonical = |path: &Path| {
+                fs::canonicalize(path).with_context(|| format!("Resolving {}", path.display()))
+            };
+            let upper_real = canonical(upper)?;
+            let roots = roots.iter().map(|root| canonical(root)).collect::<Result<Vec<_>>>()?;
+            let mut entries = Vec::new();
+            for entry in walk {
+                let entry = entry.with_context(|| format!("Walking {}", upper.display()))?;
+                if entry.depth() > 0 && is_ignored(&entry) {
+                    continue;
+                }
+                if entry.path_is_symlink() {
+                    check_symlink_target(&entry.path(), &upper_real, &roots)?;
+                }
+                entries.push(entry);
+            }
+            entries
+        }
+    };
+    // Of what is left, only included paths and the directories leading to them
+    if let Some(include) = patterns_matcher(upper, None, &filter.include)? {
+        let mut kept: FxHashSet<PathBuf> = FxHashSet::default();
+        for entry in all_entries.iter().filter(|entry| entry.depth() > 0) {
+            let path = entry.path();
+            if include.matched_path_or_any_parents(&path, entry.file_type().is_dir()).is_ignore() {
+                kept.extend(path.ancestors().take_while(|dir| *dir != upper).map(Path::to_path_buf));
+            }
+        }
+        all_entries.retain(|entry| entry.depth() == 0 || kept.contains(&entry.path()));
+    }
+    bar.set_length(all_entries.len() as u64);
 
-    let results: FxHashMap<PathBuf, EntryInfo> = all_entries
+    let mut results: FxHashMap<PathBuf, EntryInfo> = all_entries
         .par_iter()
         .filter_map(|entry| {
+            bar.inc(1);
             let full_path = entry.path();
             if full_path == upper {
                 return None; // Skip root, handled specially or as part of traversal
             }
             
-            let meta = entry.metadata().ok()?;
+            let meta = match follow {
+                Some(_) => retry.run(&full_path, || fs::metadata(&full_path)).ok()?,
+                None => retry.run(&full_path, || fs::symlink_metadata(&full_path)).ok()?,
+            };
             let file_type = meta.file_type();
             
             let metadata = CachedMetadata {
@@ -344,13 +517,20 @@ fn precalculate_layer_data(upper: &Path, config: &GlobalConfig) -> LayerData {
             let mut xattrs = Vec::new();
             let mut xattr_checksum = None;
 
-            if !skip_xattrs {
-                if let Ok(attrs_list) = xattr::list(&full_path) {
+            if !skip_xattrs && !no_xattrs.contains(&meta.dev()) {
+                let attrs_list = match retry.run(&full_path, || xattr::list(&full_path)) {
+                    Ok(attrs_list) => Some(attrs_list),
+                    Err(err) => {
+                        xattrs_unsupported(&err, &full_path, meta.dev(), &no_xattrs);
+                        None
+                    }
+                };
+                if let Some(attrs_list) = attrs_list {
                     for attr_name in attrs_list {
                         let attr_str = attr_name.to_string_lossy().to_string();
                         // Only fetch value if we care about it
-                        if let Ok(Some(val)) = xattr::get(&full_path, &attr_name) {
-                            if attr_str == "user.checksum.sha256" {
+                        if let Ok(Some(val)) = retry.run(&full_path, || xattr::get(&full_path, &attr_name)) {
+                            if attr_str == XATTR_SHA256 {
                                 xattr_checksum = Some(String::from_utf8_lossy(&val).to_string());
                             } else {
                                 let val_str = String::from_utf8_lossy(&val).to_string();
@@ -366,8 +546,8 @@ fn precalculate_layer_data(upper: &Path, config: &GlobalConfig) -> LayerData {
             let kind = if file_type.is_dir() {
                 EntryKind::Directory
             } else if file_type.is_symlink() {
-                let target = fs::read_link(&full_path).ok()?
-                    .to_string_lossy().to_string();
+                let target = retry.run(&full_path, || fs::read_link(&full_path)).ok()?
+                    .into_os_string().into_vec();
                 EntryKind::Symlink { target }
             } else if file_type.is_file() {
                 // Hardlink detection using DashMap for atomic check-and-insert without manual locking
@@ -393,8 +573,17 @@ fn precalculate_layer_data(upper: &Path, config: &GlobalConfig) -> LayerData {
                         let new_usage = current_memory.saturating_add(file_size as usize);
                         let within_limit = new_usage <= memory_limit;
 
-                        let (contents, checksum) = if file_size >= MMAP_THRESHOLD && within_limit {
-                            let file = fs::File::open(&full_path).ok()?;
+                        let computed = xattr_checksum.is_none();
+                        // Both caches are also bounded build-wide by max-memory-mb,
+                        // and mappings by max-open-files
+                        let (contents, checksum) = if file_size >= MMAP_THRESHOLD
+                            && within_limit
+                            && lease.try_map(file_size as usize)
+                        {
+                            let Ok(file) = retry.run(&full_path, || open_source(&full_path, noatime)) else {
+                                lease.undo(file_size as usize, 1);
+                                return None;
+                            };
                             advise_sequential(&file); // Hint kernel for sequential access
                             // SAFETY: The source filesystem is expected to be stable during OCI builds.
                             if let Ok(mmap) = unsafe { Mmap::map(&file) } {
@@ -407,17 +596,21 @@ fn precalculate_layer_data(upper: &Path, config: &GlobalConfig) -> LayerData {
                                 (Some(FileContents::Mapped(Arc::new(mmap))), checksum)
                             } else {
                                 // mmap failed, fallback to read-hash-discard
+                                lease.undo(file_size as usize, 1);
                                 let checksum = xattr_checksum.unwrap_or_else(|| {
-                                    file_sha256(&full_path).unwrap_or_default()
+                                    file_sha256(&full_path, config).unwrap_or_default()
                                 });
                                 (None, checksum)
                             }
-                        } else if within_limit {
+                        } else if within_limit && lease.try_cache(file_size as usize) {
                             // For small cached files, use read with fadvise
-                            let file = fs::File::open(&full_path).ok()?;
+                            let Ok(file) = retry.run(&full_path, || open_source(&full_path, noatime)) else {
+                                lease.undo(file_size as usize, 0);
+                                return None;
+                            };
                             advise_sequential(&file);
                             let mut data = Vec::with_capacity(file_size as usize);
-                            let mut reader = BufReader::new(file);
+                            let mut reader = BufReader::new(Reader::new(&full_path, file, retry));
                             reader.read_to_end(&mut data).ok()?;
                             memory_used.fetch_add(data.len(), Ordering::Relaxed);
                             let checksum = xattr_checksum.unwrap_or_else(|| {
@@ -429,10 +622,13 @@ fn precalculate_layer_data(upper: &Path, config: &GlobalConfig) -> LayerData {
                         } else {
                             // Fallback: Read-Hash-Discard (for large files when limit exceeded)
                             let checksum = xattr_checksum.unwrap_or_else(|| {
-                                file_sha256(&full_path).unwrap_or_default()
+                                file_sha256(&full_path, config).unwrap_or_default()
                             });
                             (None, checksum)
                         };
+                        if write_checksums && computed && !checksum.is_empty() && !no_xattrs.contains(&dev_ino.0) {
+                            write_checksum_xattr(&full_path, dev_ino.0, &checksum, &no_xattrs);
+                        }
 
                         EntryKind::Regular { checksum, contents }
                     }
@@ -445,12 +641,75 @@ fn precalculate_layer_data(upper: &Path, config: &GlobalConfig) -> LayerData {
         })
         .collect();
 
+    order_hardlinks(&mut results, upper);
+    let children = children_of(&results)?;
+    debug!(
+        entries = results.len(),
+        cached_bytes = memory_used.load(Ordering::Relaxed),
+        "walked layer directory"
+    );
+
+    Ok(LayerData { entries: results, children, _lease: lease })
+}
+
+/// Make the first path of each hard link group in tar order the one holding
+/// the contents, so extractors meet it before the links to it. The parallel
+/// walk keeps whichever path it happened to see first.
+fn order_hardlinks(entries: &mut FxHashMap<PathBuf, EntryInfo>, upper: &Path) {
+    // A directory's files are written before its subdirectories, each sorted by name
+    let tar_order = |path: &Path| {
+        let mut components: Vec<(bool, String)> =
+            path.iter().map(|c| (true, c.to_string_lossy().into_owned())).collect();
+        if let Some(last) = components.last_mut() {
+            last.0 = false;
+        }
+        components
+    };
+    let mut groups: FxHashMap<String, Vec<PathBuf>> = FxHashMap::default();
+    for (path, info) in entries.iter() {
+        if let EntryKind::Hardlink { target_path } = &info.kind {
+            groups.entry(target_path.clone()).or_default().push(path.clone());
+        }
+    }
+    for (target, links) in groups {
+        let primary = upper.join(&target);
+        if !entries.contains_key(&primary) {
+            continue;
+        }
+        let first = links
+            .iter()
+            .min_by_key(|path| tar_order(path.strip_prefix(upper).unwrap_or(path)))
+            .cloned();
+        let Some(first) = first.filter(|first| {
+            tar_order(first.strip_prefix(upper).unwrap_or(first)) < tar_order(Path::new(&target))
+        }) else {
+            continue;
+        };
+        let new_target = pathdiff(&first, upper).into_owned();
+        let link = EntryKind::Hardlink { target_path: new_target.clone() };
+        let contents = entries.get_mut(&primary).map(|info| std::mem::replace(&mut info.kind, link));
+        if let (Some(contents), Some(info)) = (contents, entries.get_mut(&first)) {
+            info.kind = contents;
+        }
+        for path in links.iter().filter(|path| **path != first) {
+            if let Some(info) = entries.get_mut(path) {
+                info.kind = EntryKind::Hardlink { target_path: new_target.clone() };
+            }
+        }
+    }
+}
+
+/// Sorted child basenames of every directory holding an entry. Layer paths
+/// are written as UTF-8, so other names are rejected.
+fn children_of(entries: &FxHashMap<PathBuf, EntryInfo>) -> Result<FxHashMap<PathBuf, Vec<String>>> {
     let mut children: FxHashMap<PathBuf, Vec<String>> = FxHashMap::default();
-    for path in results.keys() {
+    for path in entries.keys() {
         if let Some(parent) = path.parent() {
             if let Some(file_name) = path.file_name() {
-                let name = file_name.to_string_lossy().to_string();
-                children.entry(parent.to_path_buf()).or_default().push(name);
+                let Some(name) = file_name.to_str() else {
+                    anyhow::bail!("{}: file name is not valid UTF-8", path.display());
+                };
+                children.entry(parent.to_path_buf()).or_default().push(name.to_string());
             }
         }
     }
@@ -459,23 +718,142 @@ fn precalculate_layer_data(upper: &Path, config: &GlobalConfig) -> LayerData {
     for child_list in children.values_mut() {
         child_list.sort();
     }
+    Ok(children)
+}
+
+/// Layer data described by a trusted metadata file instead of a walk.
+fn layer_data_from_metadata(upper: &Path, metadata: &Path, config: &GlobalConfig) -> Result<LayerData> {
+    let entries: FxHashMap<PathBuf, EntryInfo> = layer_metadata::load(upper, metadata)?.into_iter().collect();
+    let children = children_of(&entries)?;
+    debug!(entries = entries.len(), "loaded layer metadata");
+    Ok(LayerData { entries, children, _lease: Lease::new(&config.limits) })
+}
 
-    LayerData { entries: results, children }
+/// Reader of exactly `len` bytes of a file whose size was recorded earlier,
+/// so a file that changed size since cannot misalign the tar stream.
+struct ExactReader<R> {
+    inner: std::io::Take<R>,
 }
 
+impl<R: Read> ExactReader<R> {
+    fn new(inner: R, len: u64) -> Self {
+        ExactReader { inner: inner.take(len) }
+    }
+}
+
+impl<R: Read> Read for ExactReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n == 0 && !buf.is_empty() && self.inner.limit() > 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "file is shorter than its recorded size",
+            ));
+        }
+        Ok(n)
+    }
+}
+
+/// Per-entry record of what `create_layer` wrote, used for `--dry-run` and
+/// file listings. Paths are archive paths (`./usr/bin/foo`); directories are
+/// always emitted and only appear in `entries`.
+#[derive(Debug, Default)]
+pub struct LayerPlan {
+    pub added: Vec<String>,
+    /// Entries identical to the lower layers and left out of the layer
+    pub skipped: Vec<String>,
+    /// Lower entries deleted by a whiteout
+    pub whiteouts: Vec<String>,
+    /// Metadata of every tar entry written, in archive order
+    pub entries: Vec<ListingEntry>,
+}
+
+/// Counts of what `create_layer` wrote and what it left to the lower layers.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DedupStats {
+    /// Non-directory entries written
+    pub added: u64,
+    /// Entries identical to the lower layers and left out
+    pub skipped: u64,
+    /// File contents spared by the skipped entries
+    pub skipped_bytes: u64,
+    /// Whiteout entries written, opaque ones included
+    pub whiteouts: u64,
+}
+
+/// Where the entries of a layer directory come from.
+#[derive(Debug, Clone)]
+pub enum LayerSource {
+    /// Walk the directory, leaving out what `filter` excludes; lower entries
+    /// it lacks are whited out
+    Directory { filter: PathFilter },
+    /// Walk the directory, dereferencing symlinks that resolve inside it or
+    /// one of `roots`; any other target, or a loop, fails the build
+    FollowSymlinks { roots: Vec<PathBuf>, filter: PathFilter },
+    /// Trusted metadata file describing the directory
+    Metadata(PathBuf),
+    /// Walk the upper directory of an overlayfs mount, which holds its own
+    /// whiteouts; lower entries it lacks are left alone. Lowerdirs top first.
+    Overlay { lowers: Vec<PathBuf> },
+    /// Tar file, possibly gzip or zstd compressed, copied in as it is
+    Tar,
+}
+
+/// Write the layer of directory `upper`, with entries from `source`, to `output`.
 pub fn create_layer<W: std::io::Write>(
     output: &mut tar::Builder<W>,
     upper: &Path,
+    source: &LayerSource,
     lower_analysis: &LowerAnalysis,
     config: &GlobalConfig,
-) -> Result<()> {
-    let epoch = crate::util::get_source_date_epoch();
+    mut plan: Option<&mut LayerPlan>,
+) -> Result<DedupStats> {
+    let epoch = config.source_date_epoch;
 
     // Pre-calculate all data in parallel
-    let layer_data = precalculate_layer_data(upper, config);
+    let label = upper.display().to_string();
+    let mut layer_data = match source {
+        LayerSource::Tar => anyhow::bail!("{} is a tar file, not a directory", upper.display()),
+        LayerSource::Metadata(metadata) => layer_data_from_metadata(upper, metadata, config)?,
+        LayerSource::FollowSymlinks { roots, filter } => {
+            precalculate_layer_data(upper, Some(roots), filter, config, &Bar::files(&label, "scanning"))?
+        }
+        LayerSource::Directory { filter } => {
+            precalculate_layer_data(upper, None, filter, config, &Bar::files(&label, "scanning"))?
+        }
+        _ => precalculate_layer_data(upper, None, &PathFilter::default(), config, &Bar::files(&label, "scanning"))?,
+    };
+    let overlay = match source {
+        LayerSource::Overlay { lowers } => Some(OverlayUpper::scan(&mut layer_data, lowers)?),
+        _ => None,
+    };
+    // Owners and modes are rewritten before anything reads them, so headers
+    // and the comparison against lower entries both see the final ones
+    if !config.id_map.is_empty() {
+        for info in layer_data.entries.values_mut() {
+            info.metadata.uid = config.id_map.map(info.metadata.uid);
+            info.metadata.gid = config.id_map.map(info.metadata.gid);
+        }
+    }
+    if !config.ownership.is_empty() {
+        let ownership = config.ownership.matcher(upper)?;
+        for (path, info) in layer_data.entries.iter_mut() {
+            let (is_dir, is_symlink) = (matches!(info.kind, EntryKind::Directory), matches!(info.kind, EntryKind::Symlink { .. }));
+            ownership.apply(path, is_dir, is_symlink, &mut info.metadata);
+        }
+    }
+    let total_bytes = layer_data
+        .entries
+        .values()
+        .filter(|info| matches!(info.kind, EntryKind::Regular { .. }))
+        .map(|info| info.metadata.size)
+        .sum();
+    let bar = Bar::bytes(&label, "writing", total_bytes);
 
     let mut stack: Vec<PathBuf> = vec![upper.to_path_buf()];
     let mut path_scratch = String::with_capacity(256);
+    let mut violations = Vec::new();
+    let mut stats = DedupStats::default();
 
     while let Some(root) = stack.pop() {
         let root_rel = pathdiff(&root, upper);
@@ -494,8 +872,8 @@ pub fn create_layer<W: std::io::Write>(
             let meta = fs::symlink_metadata(&root)?;
             CachedMetadata {
                 mode: meta.permissions().mode(),
-                uid: meta.uid() as u64,
-                gid: meta.gid() as u64,
+                uid: config.id_map.map(meta.uid() as u64),
+                gid: config.id_map.map(meta.gid() as u64),
                 mtime: meta.mtime(),
                 size: 0,
             }
@@ -508,6 +886,9 @@ pub fn create_layer<W: std::io::Write>(
             }
         };
 
+        lint::check_entry(&config.lint, &mut violations, &rel_prefix, EntryType::Dir,
+            metadata.mode, metadata.uid, metadata.gid);
+
         dir_header.set_mode(metadata.mode);
         dir_header.set_uid(metadata.uid);
         dir_header.set_gid(metadata.gid);
@@ -515,6 +896,18 @@ pub fn create_layer<W: std::io::Write>(
         dir_header.set_size(0);
         dir_header.set_cksum();
         output.append_data(&mut dir_header, &*rel_prefix, &[] as &[u8])?;
+        if let Some(plan) = plan.as_deref_mut() {
+            plan.entries.push(ListingEntry {
+                path: rel_prefix.trim_end_matches('/').to_string(),
+                kind: EntryType::Dir,
+                size: 0,
+                mode: metadata.mode & 0o7777,
+                uid: metadata.uid,
+                gid: metadata.gid,
+                digest: None,
+                target: None,
+            });
+        }
 
         let empty_vec: Vec<String> = Vec::new();
         let child_names = layer_data.children.get(&root).unwrap_or(&empty_vec);
@@ -537,7 +930,34 @@ pub fn create_layer<W: std::io::Write>(
             Cow::Owned(format!("./{}", root_rel))
         };
 
-        if let Some(old_files) = lower_analysis.dir_contents.get(lookup_prefix.as_ref()) {
+        if let Some(ref overlay) = overlay {
+            if overlay.is_opaque(&root) {
+                if let Some(plan) = plan.as_deref_mut() {
+                    for old_file in lower_analysis.dir_contents.get(lookup_prefix.as_ref()).into_iter().flatten() {
+                        plan.whiteouts.push(format!("{}{}", rel_prefix, old_file));
+                    }
+                }
+                path_scratch.clear();
+                path_scratch.push_str(&rel_prefix);
+                path_scratch.push_str(".wh..wh..opq");
+                append_whiteout(output, plan.as_deref_mut(), &path_scratch, &metadata, epoch)?;
+                stats.whiteouts += 1;
+            }
+            for (name, whiteout) in overlay.whiteouts(&root) {
+                path_scratch.clear();
+                path_scratch.push_str(&rel_prefix);
+                path_scratch.push_str(name);
+                if let Some(plan) = plan.as_deref_mut() {
+                    plan.whiteouts.push(path_scratch.clone());
+                }
+                path_scratch.clear();
+                path_scratch.push_str(&rel_prefix);
+                path_scratch.push_str(".wh.");
+                path_scratch.push_str(name);
+                append_whiteout(output, plan.as_deref_mut(), &path_scratch, whiteout, epoch)?;
+                stats.whiteouts += 1;
+            }
+        } else if let Some(old_files) = lower_analysis.dir_contents.get(lookup_prefix.as_ref()) {
             // Build HashSet for O(1) lookups instead of O(log n) binary_search
             let child_set: std::collections::HashSet<&str> =
                 child_names.iter().map(|s| s.as_str()).collect();
@@ -550,6 +970,10 @@ pub fn create_layer<W: std::io::Write>(
                     path_scratch.push_str(old_file);
                     
                     if let Some(old_entry) = lower_analysis.files.get(&path_scratch) {
+                        if let Some(plan) = plan.as_deref_mut() {
+                            plan.whiteouts.push(path_scratch.clone());
+                        }
+
                         // Build whiteout name in scratch buffer
                         path_scratch.clear();
                         path_scratch.push_str(&rel_prefix);
@@ -565,6 +989,19 @@ pub fn create_layer<W: std::io::Write>(
                         wh_header.set_size(0);
                         wh_header.set_cksum();
                         output.append_data(&mut wh_header, &path_scratch, &[] as &[u8])?;
+                        stats.whiteouts += 1;
+                        if let Some(plan) = plan.as_deref_mut() {
+                            plan.entries.push(ListingEntry {
+                                path: path_scratch.clone(),
+                                kind: EntryType::Whiteout,
+                                size: 0,
+                                mode: old_entry.mode & 0o7777,
+                                uid: old_entry.uid,
+                                gid: old_entry.gid,
+                                digest: None,
+                                target: None,
+                            });
+                        }
                     }
                 }
             }
@@ -589,6 +1026,31 @@ pub fn create_layer<W: std::io::Write>(
             path_scratch.push_str(name);
             let rel = &path_scratch;
 
+            let kind = match info.kind {
+                EntryKind::Regular { .. } => EntryType::File,
+                EntryKind::Symlink { .. } => EntryType::Symlink,
+                EntryKind::Hardlink { .. } => EntryType::Hardlink,
+                _ => EntryType::Other,
+            };
+            lint::check_entry(&config.lint, &mut violations, rel, kind,
+                info.metadata.mode, info.metadata.uid, info.metadata.gid);
+
+            // Below an opaque overlay directory the lower layers are hidden
+            let lowers_visible = overlay.as_ref().is_none_or(|o| o.lowers_visible(&path));
+            if let Some(ref overlay) = overlay {
+                if overlay.unchanged(upper, &path, info, config) {
+                    trace!(path = %rel, "unchanged from lowerdirs");
+                    stats.skipped += 1;
+                    if matches!(info.kind, EntryKind::Regular { .. }) {
+                        stats.skipped_bytes += info.metadata.size;
+                    }
+                    if let Some(plan) = plan.as_deref_mut() {
+                        plan.skipped.push(rel.clone());
+                    }
+                    continue;
+                }
+            }
+
             let mut header = tar::Header::new_gnu();
             header.set_uid(info.metadata.uid);
             header.set_gid(info.metadata.gid);
@@ -596,6 +1058,7 @@ pub fn create_layer<W: std::io::Write>(
             header.set_mtime(if let Some(ep) = epoch { ep } else { info.metadata.mtime as u64 });
 
             let mut pax_headers: HashMap<String, String> = HashMap::with_capacity(8);
+            let mut pax_linkpath: Option<&[u8]> = None;
 
             match &info.kind {
                 EntryKind::Regular { checksum, .. } => {
@@ -607,7 +1070,7 @@ pub fn create_layer<W: std::io::Write>(
                     pax_headers.insert(PAX_HEADER_SHA256.to_string(), checksum.clone());
                     
                     // Deduplication check - short-circuit on checksum first (most discriminating, O(1))
-                    if let Some(lower_entry) = lower_analysis.files.get(rel.as_str()) {
+                    if let Some(lower_entry) = lower_analysis.files.get(rel.as_str()).filter(|_| lowers_visible) {
                         // Check checksum FIRST - most selective, avoids allocations if mismatch
                         let checksum_matches = lower_entry
                             .pax_headers
@@ -650,6 +1113,12 @@ pub fn create_layer<W: std::io::Write>(
                                 lower_xattrs.sort();
 
                                 if my_xattrs == lower_xattrs {
+                                    trace!(path = %rel, "unchanged from lower layers");
+                                    stats.skipped += 1;
+                                    stats.skipped_bytes += info.metadata.size;
+                                    if let Some(plan) = plan.as_deref_mut() {
+                                        plan.skipped.push(rel.clone());
+                                    }
                                     continue; // Skip! File is identical to lower layer
                                 }
                             }
@@ -659,10 +1128,16 @@ pub fn create_layer<W: std::io::Write>(
                 EntryKind::Symlink { target } => {
                     header.set_entry_type(tar::EntryType::Symlink);
                     header.set_size(0);
-                    header.set_link_name(target)?;
+                    // Targets over the 100 bytes of the header go in a PAX
+                    // linkpath record, as their raw bytes
+                    if target.len() <= 100 {
+                        header.set_link_name_literal(target)?;
+                    } else {
+                        pax_linkpath = Some(target.as_slice());
+                    }
 
                     // Deduplication check for symlinks
-                    if let Some(lower_entry) = lower_analysis.files.get(rel.as_str()) {
+                    if let Some(lower_entry) = lower_analysis.files.get(rel.as_str()).filter(|_| lowers_visible) {
                          if lower_entry.entry_type == tar::EntryType::Symlink.as_byte()
                             && lower_entry.mode == info.metadata.mode
                             && lower_entry.uid == info.metadata.uid
@@ -670,6 +1145,10 @@ pub fn create_layer<W: std::io::Write>(
                         {
                             if let Some(lower_target) = &lower_entry.symlink_target {
                                 if target == lower_target {
+                                    stats.skipped += 1;
+                                    if let Some(plan) = plan.as_deref_mut() {
+                                        plan.skipped.push(rel.clone());
+                                    }
                                     continue;
                                 }
                             }
@@ -701,13 +1180,14 @@ pub fn create_layer<W: std::io::Write>(
             }
 
             // Write PAX headers
-            if !pax_headers.is_empty() {
+            let mut records: Vec<(&str, &[u8])> =
+                pax_headers.iter().map(|(key, value)| (key.as_str(), value.as_bytes())).collect();
+            records.extend(pax_linkpath.map(|linkpath| ("linkpath", linkpath)));
+            if !records.is_empty() {
                 let mut pax_data = Vec::with_capacity(512);
-                let mut sorted_keys: Vec<_> = pax_headers.keys().collect();
-                sorted_keys.sort();
+                records.sort();
 
-                for key in sorted_keys {
-                    let value = &pax_headers[key];
+                for (key, value) in records {
                     let entry_str_len = key.len() + value.len() + 2;
                     let mut digits = 1; 
                     let mut total_len = digits + 1 + entry_str_len; 
@@ -716,7 +1196,9 @@ pub fn create_layer<W: std::io::Write>(
                         total_len = digits + 1 + entry_str_len;
                         if count_digits(total_len) != digits { total_len += 1; }
                     }
-                    writeln!(pax_data, "{} {}={}", total_len, key, value)?;
+                    write!(pax_data, "{} {}=", total_len, key)?;
+                    pax_data.extend_from_slice(value);
+                    pax_data.push(b'\n');
                 }
                 let mut pax_header = tar::Header::new_ustar();
                 pax_header.set_entry_type(tar::EntryType::XHeader);
@@ -728,18 +1210,96 @@ pub fn create_layer<W: std::io::Write>(
             header.set_cksum();
             if let EntryKind::Regular { contents: Some(ref c), .. } = info.kind {
                 output.append_data(&mut header, rel, c.as_slice())?;
+                bar.inc(info.metadata.size);
             } else if let EntryKind::Regular { .. } = info.kind {
-                let f = fs::File::open(&path)?;
-                output.append_data(&mut header, rel, f)?;
+                let f = config
+                    .io_retry
+                    .run(&path, || open_source(&path, config.noatime))
+                    .with_context(|| format!("Opening {}", path.display()))?;
+                let f = Reader::new(&path, f, config.io_retry);
+                output
+                    .append_data(&mut header, rel, ExactReader::new(bar.reader(f), info.metadata.size))
+                    .with_context(|| format!("Reading {}", path.display()))?;
             } else {
                 output.append_data(&mut header, rel, &[] as &[u8])?;
             }
+
+            stats.added += 1;
+            if let Some(plan) = plan.as_deref_mut() {
+                plan.added.push(rel.clone());
+                plan.entries.push(listing_entry(rel, info, &layer_data, upper));
+            }
         }
     }
 
+    lint::report(&violations, &upper.display().to_string())?;
+    Ok(stats)
+}
+
+/// Append an empty whiteout entry at archive path `path`, owned as `metadata`.
+fn append_whiteout<W: std::io::Write>(
+    output: &mut tar::Builder<W>,
+    plan: Option<&mut LayerPlan>,
+    path: &str,
+    metadata: &CachedMetadata,
+    epoch: Option<u64>,
+) -> Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_uid(metadata.uid);
+    header.set_gid(metadata.gid);
+    header.set_mode(metadata.mode & 0o7777);
+    header.set_mtime(epoch.unwrap_or(metadata.mtime as u64));
+    header.set_size(0);
+    header.set_cksum();
+    output.append_data(&mut header, path, &[] as &[u8])?;
+    if let Some(plan) = plan {
+        plan.entries.push(ListingEntry {
+            path: path.to_string(),
+            kind: EntryType::Whiteout,
+            size: 0,
+            mode: metadata.mode & 0o7777,
+            uid: metadata.uid,
+            gid: metadata.gid,
+            digest: None,
+            target: None,
+        });
+    }
     Ok(())
 }
 
+/// Build the file listing entry for a non-directory entry of the layer.
+fn listing_entry(rel: &str, info: &EntryInfo, layer_data: &LayerData, upper: &Path) -> ListingEntry {
+    let (kind, size, digest, target) = match &info.kind {
+        EntryKind::Regular { checksum, .. } => {
+            (EntryType::File, info.metadata.size, Some(checksum.clone()), None)
+        }
+        EntryKind::Symlink { target } => (EntryType::Symlink, 0, None, Some(String::from_utf8_lossy(target).into_owned())),
+        EntryKind::Hardlink { target_path } => {
+            // Hardlinks share their target's contents, so report its digest too
+            let digest = match layer_data.entries.get(&upper.join(target_path)) {
+                Some(EntryInfo { kind: EntryKind::Regular { checksum, .. }, .. }) => {
+                    Some(checksum.clone())
+                }
+                _ => None,
+            };
+            (EntryType::Hardlink, 0, digest, Some(format!("./{}", target_path.trim_start_matches("./"))))
+        }
+        EntryKind::Directory => (EntryType::Dir, 0, None, None),
+        EntryKind::Other => (EntryType::Other, info.metadata.size, None, None),
+    };
+    ListingEntry {
+        path: rel.to_string(),
+        kind,
+        size,
+        mode: info.metadata.mode & 0o7777,
+        uid: info.metadata.uid,
+        gid: info.metadata.gid,
+        digest,
+        target,
+    }
+}
+
 #[inline]
 fn count_digits(n: usize) -> usize {
     if n == 0 {
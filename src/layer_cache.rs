@@ -0,0 +1,464 @@
+// Copyright (c) 2019, 2020 Codethink Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! `cache-dir:`: layer blobs built earlier, possibly by other runs, keyed by
+//! what went into them: the contents of the layer directory (or `layer-tar:`
+//! file), the diff_ids of the layers below it and every setting that shapes
+//! the blob. A layer found there is linked into the output instead of built.
+
+use std::fs;
+use std::io::{self, BufReader, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use jwalk::WalkDir;
+use rayon::prelude::*;
+use rustc_hash::FxHashMap;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tracing::{debug, info, warn};
+
+use crate::blob::IO_BUF_MEDIUM;
+use crate::cache_stats;
+use crate::config::RemoteCacheSpec;
+use crate::layer_builder::{file_sha256, DedupStats, LayerSource, XATTR_SHA256};
+use crate::layer_index::ANNOTATION_LAYER_INDEX;
+use crate::layout::{descriptor_digest, Layout};
+use crate::listing::ANNOTATION_LISTING;
+use crate::util::HashingWriter;
+use crate::{Compression, GlobalConfig};
+
+/// A layer found in the cache, with the cached files of its blobs.
+pub struct CachedLayer {
+    pub desc: Value,
+    pub diff_id: String,
+    pub uncompressed_size: u64,
+    pub dedup: Option<DedupStats>,
+    /// Digest and cached file of the layer blob and the blobs it names
+    pub blobs: Vec<(String, PathBuf)>,
+}
+
+/// Layers in `<cache-dir>/layers/<key>.json`, their blobs in
+/// `<cache-dir>/blobs/sha256` where they are hard linked when possible.
+pub struct LayerCache {
+    dir: PathBuf,
+    remote: Option<RemoteCache>,
+}
+
+impl LayerCache {
+    pub fn open(dir: &Path, remote: Option<&RemoteCache>) -> Result<Self> {
+        for sub in ["layers", "blobs/sha256"] {
+            let path = dir.join(sub);
+            fs::create_dir_all(&path).with_context(|| format!("Creating {}", path.display()))?;
+        }
+        Ok(LayerCache { dir: dir.to_path_buf(), remote: remote.cloned() })
+    }
+
+    fn entry_path(&self, key: &str) -> PathBuf {
+        self.dir.join("layers").join(format!("{}.json", key))
+    }
+
+    /// The layer stored under `key`, if it and all its blobs are there,
+    /// fetched from the remote cache on a local miss.
+    pub fn lookup(&self, key: &str) -> Result<Option<CachedLayer>> {
+        if let Some(remote) = self.remote.as_ref().filter(|remote| remote.available() && !self.entry_path(key).is_file()) {
+            match self.fetch(remote, key) {
+                Ok(Some(size)) => cache_stats::REMOTE_CACHE.hit(size),
+                Ok(None) => cache_stats::REMOTE_CACHE.miss(),
+                Err(e) => remote.failed("fetching a layer", &e),
+            }
+        }
+        let entry: Value = match fs::read(self.entry_path(key)) {
+            Ok(bytes) => serde_json::from_slice(&bytes).context("Reading layer cache entry")?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).context("Reading layer cache entry"),
+        };
+        let desc = entry["descriptor"].clone();
+        let blobs = self.blob_files(&desc)?;
+        if blobs.iter().any(|(_, path)| !path.is_file()) {
+            debug!(key, "layer cache entry lacks a blob, rebuilding it");
+            return Ok(None);
+        }
+        let dedup = entry["dedup"].as_object().map(|d| {
+            let count = |name: &str| d.get(name).and_then(Value::as_u64).unwrap_or(0);
+            DedupStats {
+                added: count("added"),
+                skipped: count("skipped"),
+                skipped_bytes: count("skippedBytes"),
+                whiteouts: count("whiteouts"),
+            }
+        });
+        Ok(Some(CachedLayer {
+            diff_id: entry["diffId"].as_str().context("Layer cache entry without a diffId")?.to_string(),
+            uncompressed_size: entry["uncompressedSize"].as_u64().unwrap_or(0),
+            dedup,
+            blobs,
+            desc,
+        }))
+    }
+
+    /// Store a layer built into the `output` layout under `key`. The entry is
+    /// written last, so an interrupted store leaves no entry behind.
+    pub fn store(
+        &self,
+        key: &str,
+        desc: &Value,
+        diff_id: &str,
+        uncompressed_size: u64,
+        dedup: Option<DedupStats>,
+        output: &Layout,
+    ) -> Result<()> {
+        for (digest, path) in self.blob_files(desc)? {
+            if !path.is_file() {
+                link_or_copy(&output.blob_path(&digest)?, &path)?;
+            }
+        }
+        let entry = serde_json::json!({
+            "descriptor": desc,
+            "diffId": diff_id,
+            "uncompressedSize": uncompressed_size,
+            "dedup": dedup.map(|d| serde_json::json!({
+                "added": d.added,
+                "skipped": d.skipped,
+                "skippedBytes": d.skipped_bytes,
+                "whiteouts": d.whiteouts,
+            })),
+        });
+        let mut tmp = tempfile::NamedTempFile::new_in(self.dir.join("layers"))?;
+        tmp.write_all(&serde_json::to_vec(&entry)?)?;
+        // Concurrent builds may race to store the same layer; the content is identical
+        tmp.persist(self.entry_path(key)).map_err(|e| e.error)?;
+        debug!(key, diff_id, "stored layer in the cache");
+
+        if let Some(remote) = self.remote.as_ref().filter(|remote| remote.push && remote.available()) {
+            if let Err(e) = self.upload(remote, key) {
+                remote.failed("uploading a layer", &e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Download the entry `key` and its blobs from `remote` into the local
+    /// cache, returning the layer's uncompressed size, or `None` if the
+    /// remote does not have all of it.
+    fn fetch(&self, remote: &RemoteCache, key: &str) -> Result<Option<u64>> {
+        let entry_path = format!("layers/{}.json", key);
+        let Some(response) = remote.get(&entry_path)? else {
+            return Ok(None);
+        };
+        let mut bytes = Vec::new();
+        response.into_reader().read_to_end(&mut bytes)?;
+        let entry: Value = serde_json::from_slice(&bytes).context("Reading remote layer cache entry")?;
+        for (digest, path) in self.blob_files(&entry["descriptor"])? {
+            if path.is_file() {
+                continue;
+            }
+            let hash = digest.strip_prefix("sha256:").with_context(|| format!("Unsupported digest {}", digest))?;
+            let Some(response) = remote.get(&format!("blobs/sha256/{}", hash))? else {
+                debug!(key, digest, "remote layer cache entry lacks a blob");
+                return Ok(None);
+            };
+            let tmp = tempfile::NamedTempFile::new_in(self.dir.join("blobs/sha256"))?;
+            let mut writer = HashingWriter::new(io::BufWriter::new(tmp.reopen()?));
+            io::copy(&mut response.into_reader(), &mut writer).with_context(|| format!("Downloading {}", digest))?;
+            let (mut file, actual) = writer.finish()?;
+            file.flush()?;
+            if actual != hash {
+                bail!("Remote blob {} has digest sha256:{}", digest, actual);
+            }
+            tmp.persist(&path).map_err(|e| e.error)?;
+        }
+        // Like a local store, the entry goes in after its blobs
+        let mut tmp = tempfile::NamedTempFile::new_in(self.dir.join("layers"))?;
+        tmp.write_all(&bytes)?;
+        tmp.persist(self.entry_path(key)).map_err(|e| e.error)?;
+        debug!(key, "fetched layer from the remote cache");
+        Ok(Some(entry["uncompressedSize"].as_u64().unwrap_or(0)))
+    }
+
+    /// Upload the blobs the remote lacks, then the entry `key`.
+    fn upload(&self, remote: &RemoteCache, key: &str) -> Result<()> {
+        let entry_path = self.entry_path(key);
+        let entry: Value = serde_json::from_slice(&fs::read(&entry_path)?)?;
+        for (digest, path) in self.blob_files(&entry["descriptor"])? {
+            let hash = digest.strip_prefix("sha256:").with_context(|| format!("Unsupported digest {}", digest))?;
+            let remote_path = format!("blobs/sha256/{}", hash);
+            if !remote.exists(&remote_path)? {
+                remote.put(&remote_path, &path)?;
+            }
+        }
+        remote.put(&format!("layers/{}.json", key), &entry_path)?;
+        debug!(key, "uploaded layer to the remote cache");
+        Ok(())
+    }
+
+    /// The layer blob of `desc` and the listing and index blobs it names.
+    fn blob_files(&self, desc: &Value) -> Result<Vec<(String, PathBuf)>> {
+        let blobs = Layout::building(&self.dir);
+        let named = [ANNOTATION_LISTING, ANNOTATION_LAYER_INDEX]
+            .into_iter()
+            .filter_map(|key| desc["annotations"][key].as_str());
+        std::iter::once(descriptor_digest(desc)?)
+            .chain(named)
+            .map(|digest| Ok((digest.to_string(), blobs.blob_path(digest)?)))
+            .collect()
+    }
+}
+
+/// Set when the remote cache fails to answer, so that the rest of the run
+/// goes on with the local cache alone instead of waiting for it on each layer
+static REMOTE_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// `remote-cache:`: a plain HTTP server where entries are read with GET
+/// and written with PUT at the paths they have in `cache-dir:`.
+#[derive(Debug, Clone)]
+pub struct RemoteCache {
+    url: String,
+    /// `Authorization` header value, with the bearer token
+    authorization: Option<String>,
+    push: bool,
+    agent: ureq::Agent,
+}
+
+impl RemoteCache {
+    pub fn from_spec(spec: &RemoteCacheSpec) -> Result<Self> {
+        if !spec.url.starts_with("http://") && !spec.url.starts_with("https://") {
+            bail!("remote-cache.url must be an http:// or https:// URL, got: {}", spec.url);
+        }
+        let authorization = match spec.token_env {
+            Some(ref name) => match std::env::var(name) {
+                Ok(token) if !token.is_empty() => Some(format!("Bearer {}", token)),
+                // Forks and local runs usually lack the secret; reading may still be allowed
+                _ => {
+                    warn!(variable = %name, "remote-cache token is not set, sending no token");
+                    None
+                }
+            },
+            None => None,
+        };
+        Ok(RemoteCache {
+            url: spec.url.trim_end_matches('/').to_string(),
+            authorization,
+            push: spec.push.unwrap_or(true),
+            agent: ureq::AgentBuilder::new()
+                .user_agent(concat!("build-oci/", env!("CARGO_PKG_VERSION")))
+                .timeout_connect(Duration::from_secs(10))
+                .timeout_read(Duration::from_secs(60))
+                .build(),
+        })
+    }
+
+    fn available(&self) -> bool {
+        !REMOTE_DOWN.load(Ordering::Relaxed)
+    }
+
+    /// Log a failed exchange. A server that cannot be reached is not tried
+    /// again; one that answers with an error still is.
+    fn failed(&self, what: &str, err: &anyhow::Error) {
+        if err.downcast_ref::<ureq::Transport>().is_some() {
+            if !REMOTE_DOWN.swap(true, Ordering::Relaxed) {
+                warn!(url = %self.url, "remote cache unreachable, using the local cache only: {:#}", err);
+            }
+        } else {
+            warn!(url = %self.url, "remote cache: {}: {:#}", what, err);
+        }
+    }
+
+    fn request(&self, method: &str, path: &str) -> ureq::Request {
+        let request = self.agent.request(method, &format!("{}/{}", self.url, path));
+        match self.authorization {
+            Some(ref authorization) => request.set("Authorization", authorization),
+            None => request,
+        }
+    }
+
+    /// Any HTTP status is a response; only transport failures are errors.
+    fn send(&self, result: std::result::Result<ureq::Response, ureq::Error>) -> Result<ureq::Response> {
+        match result {
+            Ok(response) | Err(ureq::Error::Status(_, response)) => Ok(response),
+            Err(ureq::Error::Transport(transport)) => Err(anyhow!(transport)),
+        }
+    }
+
+    /// The body at `path`, or `None` if the server does not have it.
+    fn get(&self, path: &str) -> Result<Option<ureq::Response>> {
+        let response = self.send(self.request("GET", path).call())?;
+        match response.status() {
+            200 => Ok(Some(response)),
+            404 => Ok(None),
+            status => bail!("GET {}: server answered {}", path, status),
+        }
+    }
+
+    fn exists(&self, path: &str) -> Result<bool> {
+        let response = self.send(self.request("HEAD", path).call())?;
+        match response.status() {
+            200 => Ok(true),
+            404 => Ok(false),
+            status => bail!("HEAD {}: server answered {}", path, status),
+        }
+    }
+
+    fn put(&self, path: &str, file: &Path) -> Result<()> {
+        let input = fs::File::open(file).with_context(|| format!("Opening {}", file.display()))?;
+        let length = input.metadata()?.len();
+        let request = self.request("PUT", path).set("Content-Length", &length.to_string());
+        let response = self.send(request.send(BufReader::with_capacity(IO_BUF_MEDIUM, input)))?;
+        match response.status() {
+            200..=204 => {
+                info!(path, size = length, "uploaded to the remote cache");
+                Ok(())
+            }
+            status => bail!("PUT {}: server answered {}", path, status),
+        }
+    }
+}
+
+fn link_or_copy(from: &Path, to: &Path) -> Result<()> {
+    match fs::hard_link(from, to) {
+        Ok(()) => return Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => return Ok(()),
+        Err(e) => debug!(path = %from.display(), "copying into the layer cache, cannot hard link: {}", e),
+    }
+    let dir = to.parent().context("Layer cache blob without a directory")?;
+    let tmp = tempfile::NamedTempFile::new_in(dir)?;
+    fs::copy(from, tmp.path()).with_context(|| format!("Copying {}", from.display()))?;
+    tmp.persist(to).map_err(|e| e.error)?;
+    Ok(())
+}
+
+/// Cache key of the layer built from `upper` on the layers with
+/// `lower_diff_ids`, or `None` for sources the cache does not cover:
+/// metadata files, overlays, followed symlinks and `dedup-lowers:`.
+pub fn key(
+    upper: &Path,
+    source: &LayerSource,
+    compression: Compression,
+    level: Option<u32>,
+    dedup_lowers: bool,
+    lower_diff_ids: &[String],
+    global_conf: &GlobalConfig,
+) -> Result<Option<String>> {
+    let contents = match source {
+        _ if dedup_lowers => return Ok(None),
+        LayerSource::Directory { filter } => format!("{} {:?}", tree_hash(upper, global_conf)?, filter),
+        LayerSource::Tar => file_sha256(upper, global_conf)?,
+        _ => return Ok(None),
+    };
+    let mut hasher = Sha256::new();
+    // Everything in GlobalConfig that changes the bytes of a layer or its descriptor
+    let settings = format!(
+        "{} {:?} {:?} {} {:?} {} {:?} {:?} {} {} {:?} {:?} {:?} {:?} {:?}",
+        env!("CARGO_PKG_VERSION"),
+        compression,
+        level,
+        global_conf.compression_threads,
+        global_conf.compression_options,
+        global_conf.compression_annotations,
+        global_conf.source_date_epoch,
+        global_conf.layer_listing,
+        global_conf.layer_index,
+        global_conf.skip_xattrs,
+        global_conf.format_version,
+        global_conf.lint,
+        global_conf.compatibility,
+        global_conf.id_map,
+        global_conf.ownership,
+    );
+    hasher.update(settings.as_bytes());
+    hasher.update(b"\0");
+    hasher.update(contents.as_bytes());
+    for diff_id in lower_diff_ids {
+        hasher.update(b"\0");
+        hasher.update(diff_id.as_bytes());
+    }
+    Ok(Some(format!("{:x}", hasher.finalize())))
+}
+
+/// Hash of everything in `upper` a layer is made of: paths, types,
+/// ownership, modes, mtimes (unless source-date-epoch replaces them),
+/// xattrs, link targets and file contents. Files
+/// are hashed whole, never trusting stored checksum xattrs.
+fn tree_hash(upper: &Path, global_conf: &GlobalConfig) -> Result<String> {
+    let paths = WalkDir::new(upper)
+        .skip_hidden(false)
+        .sort(true)
+        .into_iter()
+        .map(|entry| Ok(entry.with_context(|| format!("Walking {}", upper.display()))?.path()))
+        .collect::<Result<Vec<_>>>()?;
+    let records = paths
+        .par_iter()
+        .map(|path| entry_record(upper, path, global_conf))
+        .collect::<Result<Vec<_>>>()?;
+
+    // Hard links are recorded by the first path of their inode, which
+    // unlike inode numbers holds across checkouts
+    let mut inodes: FxHashMap<Inode, usize> = FxHashMap::default();
+    let mut hasher = Sha256::new();
+    for (i, (record, inode)) in records.iter().enumerate() {
+        hasher.update(record);
+        if let Some(inode) = inode {
+            let first = *inodes.entry(*inode).or_insert(i);
+            hasher.update(first.to_le_bytes());
+        }
+        hasher.update(b"\n");
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Device and inode number of a file
+type Inode = (u64, u64);
+
+/// What the entry at `path` contributes to [`tree_hash`], and its inode if it
+/// is a regular file.
+fn entry_record(upper: &Path, path: &Path, global_conf: &GlobalConfig) -> Result<(Vec<u8>, Option<Inode>)> {
+    let meta = fs::symlink_metadata(path).with_context(|| format!("Reading {}", path.display()))?;
+    let rel = path.strip_prefix(upper).unwrap_or(path);
+    let mut record = rel.as_os_str().as_bytes().to_vec();
+    // With source-date-epoch every header takes the epoch, so mtimes, which
+    // a fresh checkout always changes, do not change the layer
+    let mtime = if global_conf.source_date_epoch.is_some() { 0 } else { meta.mtime() };
+    record.extend(format!("\0{:o} {} {} {} {}\0", meta.mode(), meta.uid(), meta.gid(), mtime, meta.len()).bytes());
+    if !global_conf.skip_xattrs {
+        let mut names: Vec<_> = xattr::list(path).map(|names| names.collect()).unwrap_or_default();
+        names.sort();
+        // The checksum xattr only restates the contents, and may be written by the build itself
+        for name in names.into_iter().filter(|name| name.as_bytes() != XATTR_SHA256.as_bytes()) {
+            if let Ok(Some(value)) = xattr::get(path, &name) {
+                record.extend(name.as_bytes());
+                record.push(b'=');
+                record.extend(value);
+                record.push(0);
+            }
+        }
+    }
+    let file_type = meta.file_type();
+    if file_type.is_symlink() {
+        record.extend(fs::read_link(path)?.as_os_str().as_bytes());
+    } else if file_type.is_file() {
+        record.extend(file_sha256(path, global_conf)?.bytes());
+        return Ok((record, Some((meta.dev(), meta.ino()))));
+    }
+    Ok((record, None))
+}
@@ -0,0 +1,282 @@
+// Copyright (c) 2019, 2020 Codethink Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::thread::JoinHandle;
+
+use anyhow::{bail, Context, Result};
+use crossbeam_channel::{bounded, Receiver, Sender};
+use rustc_hash::FxHashMap;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::blob::Blob;
+use crate::layer_builder::{ArchiveEntries, LowerEntry};
+use crate::layout::descriptor_digest;
+use crate::tar_parser::parse_archive;
+use crate::GlobalConfig;
+
+/// Media type of a per-layer file index blob
+pub const MEDIA_TYPE_LAYER_INDEX: &str = "application/vnd.freedesktopsdk.layer.index.v1+json";
+/// Layer descriptor annotation holding the digest of the layer's file index blob
+pub const ANNOTATION_LAYER_INDEX: &str = "org.freedesktopsdk.layer.index";
+
+const INDEX_VERSION: u32 = 1;
+
+/// On-disk form of a layer file index: every tar entry of the layer with the
+/// metadata deduplication compares, plus the whiteouts it applies.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct IndexFile {
+    version: u32,
+    /// diff_id of the layer the index describes
+    diff_id: String,
+    entries: Vec<IndexEntry>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    opaque_whiteouts: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    whiteouts: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct IndexEntry {
+    /// Archive path, e.g. `./usr/bin/foo`
+    path: String,
+    /// Tar entry type byte
+    #[serde(rename = "type")]
+    entry_type: u8,
+    mode: u32,
+    uid: u64,
+    gid: u64,
+    mtime: u64,
+    size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    linkname: Option<String>,
+    /// Symlink target that is not UTF-8, as bytes, in place of `linkname`
+    #[serde(default, rename = "linknameBytes", skip_serializing_if = "Option::is_none")]
+    linkname_bytes: Option<Vec<u8>>,
+    /// PAX headers: content checksum and xattrs
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pax: BTreeMap<String, String>,
+}
+
+/// Parsed indexes of layers known to this process, keyed by diff_id
+static INDEXES: LazyLock<Mutex<FxHashMap<String, Arc<ArchiveEntries>>>> =
+    LazyLock::new(|| Mutex::new(FxHashMap::default()));
+
+pub fn register(diff_id: &str, entries: Arc<ArchiveEntries>) {
+    if let Ok(mut indexes) = INDEXES.lock() {
+        indexes.insert(diff_id.to_string(), entries);
+    }
+}
+
+/// Parsed entries of the layer with `diff_id`, if its index has been loaded.
+pub fn lookup(diff_id: &str) -> Option<Arc<ArchiveEntries>> {
+    INDEXES.lock().ok()?.get(diff_id).cloned()
+}
+
+/// Serialize the parsed entries of the layer with `diff_id`.
+pub fn encode(diff_id: &str, entries: &ArchiveEntries) -> Result<Vec<u8>> {
+    let file = IndexFile {
+        version: INDEX_VERSION,
+        diff_id: diff_id.to_string(),
+        entries: entries
+            .entries
+            .iter()
+            .map(|(path, e)| IndexEntry {
+                path: path.clone(),
+                entry_type: e.entry_type,
+                mode: e.mode,
+                uid: e.uid,
+                gid: e.gid,
+                mtime: e.mtime,
+                size: e.size,
+                linkname: e.symlink_target.as_ref().and_then(|t| String::from_utf8(t.clone()).ok()),
+                linkname_bytes: e.symlink_target.clone().filter(|t| std::str::from_utf8(t).is_err()),
+                pax: e.pax_headers.iter().map(|(k, v)| (k.clone(), v.clone())).collect(),
+            })
+            .collect(),
+        opaque_whiteouts: entries.opaque_whiteouts.clone(),
+        whiteouts: entries.file_whiteouts.clone(),
+    };
+    Ok(serde_json::to_vec(&file)?)
+}
+
+fn decode(bytes: &[u8], diff_id: &str) -> Result<ArchiveEntries> {
+    let file: IndexFile = serde_json::from_slice(bytes)?;
+    if file.version != INDEX_VERSION {
+        bail!("unsupported layer index version {}", file.version);
+    }
+    if file.diff_id != diff_id {
+        bail!("index describes {}, not {}", file.diff_id, diff_id);
+    }
+    Ok(ArchiveEntries {
+        entries: file
+            .entries
+            .into_iter()
+            .map(|e| {
+                let entry = LowerEntry {
+                    pax_headers: e.pax.into_iter().collect(),
+                    symlink_target: e.linkname.map(String::into_bytes).or(e.linkname_bytes),
+                    uid: e.uid,
+                    gid: e.gid,
+                    mtime: e.mtime,
+                    size: e.size,
+                    mode: e.mode,
+                    entry_type: e.entry_type,
+                };
+                (e.path, entry)
+            })
+            .collect(),
+        opaque_whiteouts: file.opaque_whiteouts,
+        file_whiteouts: file.whiteouts,
+    })
+}
+
+/// Load the file index referenced by a parent layer descriptor, if it has one.
+/// The blob must match its digest and describe the layer's diff_id.
+pub fn load(layout: &Path, layer_desc: &serde_json::Value, diff_id: &str) -> Result<Option<ArchiveEntries>> {
+    let Some(index_digest) = layer_desc["annotations"][ANNOTATION_LAYER_INDEX].as_str() else {
+        return Ok(None);
+    };
+    let hash = index_digest
+        .strip_prefix("sha256:")
+        .with_context(|| format!("Unsupported layer index digest '{}'", index_digest))?;
+    let path = layout.join("blobs").join("sha256").join(hash);
+    let bytes = std::fs::read(&path).with_context(|| format!("Reading layer index {}", index_digest))?;
+    if format!("{:x}", Sha256::digest(&bytes)) != hash {
+        bail!("Layer index {} does not match its digest", index_digest);
+    }
+    let entries = decode(&bytes, diff_id).with_context(|| {
+        format!(
+            "Invalid layer index {} for layer {}",
+            index_digest,
+            descriptor_digest(layer_desc).unwrap_or("<unknown>")
+        )
+    })?;
+    Ok(Some(entries))
+}
+
+/// Write the file index of the layer with `diff_id` as a blob of the output
+/// layout and link it from the layer descriptor.
+pub fn attach(
+    layer_desc: &mut serde_json::Value,
+    diff_id: &str,
+    entries: &ArchiveEntries,
+    global_conf: &GlobalConfig,
+) -> Result<()> {
+    let bytes = encode(diff_id, entries)?;
+    let mut blob = Blob::new(global_conf, Some(MEDIA_TYPE_LAYER_INDEX));
+    blob.create(|f| {
+        f.write_all(&bytes)?;
+        Ok(Some(format!("{:x}", Sha256::digest(&bytes))))
+    })?;
+    let desc = blob
+        .descriptor
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("Missing layer index blob descriptor"))?;
+    layer_desc["annotations"][ANNOTATION_LAYER_INDEX] = desc.digest.as_str().into();
+    Ok(())
+}
+
+/// Writer adapter that forwards a layer tar stream to `inner` and, when
+/// enabled, hands a copy to a thread running [`parse_archive`] on it, so the
+/// index of a new layer comes from the same parser used for parent tars.
+pub struct IndexTap<W: Write> {
+    inner: W,
+    tx: Option<Sender<Vec<u8>>>,
+    parser: Option<JoinHandle<Result<ArchiveEntries>>>,
+}
+
+impl<W: Write> IndexTap<W> {
+    pub fn new(inner: W, enabled: bool) -> Self {
+        if !enabled {
+            return Self { inner, tx: None, parser: None };
+        }
+        let (tx, rx) = bounded(TAP_QUEUE_CHUNKS);
+        let parser = std::thread::spawn(move || {
+            let reader = ChannelReader { rx, chunk: Vec::new(), pos: 0 };
+            parse_archive(reader)
+        });
+        Self { inner, tx: Some(tx), parser: Some(parser) }
+    }
+
+    /// Return the inner writer and, if enabled, the parsed entries.
+    pub fn finish(mut self) -> Result<(W, Option<ArchiveEntries>)> {
+        drop(self.tx.take());
+        let entries = match self.parser.take() {
+            Some(parser) => Some(
+                parser
+                    .join()
+                    .map_err(|_| anyhow::anyhow!("Layer index parser panicked"))??,
+            ),
+            None => None,
+        };
+        Ok((self.inner, entries))
+    }
+}
+
+impl<W: Write> Write for IndexTap<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if let Some(tx) = &self.tx {
+            // The parser stops at the end-of-archive marker and drops the
+            // receiver; the padding written after that is not needed
+            if tx.send(buf[..n].to_vec()).is_err() {
+                self.tx = None;
+            }
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Chunks of tar stream buffered between the layer writer and the index parser
+const TAP_QUEUE_CHUNKS: usize = 64;
+
+struct ChannelReader {
+    rx: Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match self.rx.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
@@ -0,0 +1,158 @@
+// Copyright (c) 2019, 2020 Codethink Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Trusted layer metadata: a description of a layer directory produced by
+//! whatever built it, used instead of walking, stat'ing and hashing the
+//! directory. Only file contents are read from disk.
+//!
+//! The format is that of JSON layer listings (`{"entries": [...]}`), with
+//! optional `mtime` and `xattrs` per entry.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+use crate::layer_builder::{CachedMetadata, EntryInfo, EntryKind};
+use crate::listing::EntryType;
+
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Metadata {
+    entries: Vec<MetadataEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MetadataEntry {
+    path: String,
+    #[serde(rename = "type")]
+    kind: EntryType,
+    #[serde(default)]
+    size: u64,
+    /// Permission bits, without the file type
+    mode: u32,
+    #[serde(default)]
+    uid: u64,
+    #[serde(default)]
+    gid: u64,
+    #[serde(default)]
+    mtime: i64,
+    sha256: Option<String>,
+    target: Option<String>,
+    #[serde(default)]
+    xattrs: BTreeMap<String, String>,
+}
+
+/// Path relative to the layer root, without `./`; `None` for the root.
+fn relative(path: &str) -> Result<Option<PathBuf>> {
+    let mut rel = PathBuf::new();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(name) => rel.push(name),
+            Component::CurDir | Component::RootDir => {}
+            Component::ParentDir | Component::Prefix(_) => bail!("{}: path leaves the layer", path),
+        }
+    }
+    Ok((!rel.as_os_str().is_empty()).then_some(rel))
+}
+
+/// Entries of the layer directory `upper` as described by the metadata file
+/// at `path`, keyed like those of a directory walk. Regular files carry no
+/// cached contents and are streamed from `upper` when the layer is written.
+pub fn load(upper: &Path, path: &Path) -> Result<Vec<(PathBuf, EntryInfo)>> {
+    let file = fs::File::open(path).with_context(|| format!("Opening layer metadata {}", path.display()))?;
+    let metadata: Metadata = serde_json::from_reader(std::io::BufReader::new(file))
+        .with_context(|| format!("Parsing layer metadata {}", path.display()))?;
+
+    let mut dirs = HashSet::new();
+    let mut files = HashSet::new();
+    let mut entries = Vec::with_capacity(metadata.entries.len());
+    for entry in metadata.entries {
+        let context = || format!("Layer metadata {}: {}", path.display(), entry.path);
+        let Some(rel) = relative(&entry.path).with_context(context)? else {
+            continue; // The root's metadata comes from the directory itself
+        };
+        let (file_type, kind) = match entry.kind {
+            EntryType::Dir => {
+                dirs.insert(rel.clone());
+                (S_IFDIR, EntryKind::Directory)
+            }
+            EntryType::File => {
+                let checksum = entry.sha256.clone().with_context(|| format!("{}: file without sha256", context()))?;
+                files.insert(rel.clone());
+                (S_IFREG, EntryKind::Regular { checksum, contents: None })
+            }
+            EntryType::Symlink => {
+                let target = entry.target.clone().with_context(|| format!("{}: symlink without target", context()))?;
+                (S_IFLNK, EntryKind::Symlink { target: target.into_bytes() })
+            }
+            EntryType::Hardlink => {
+                let target = entry.target.as_deref().with_context(|| format!("{}: hardlink without target", context()))?;
+                let target_path = relative(target)
+                    .with_context(context)?
+                    .with_context(|| format!("{}: hardlink to the layer root", context()))?;
+                (S_IFREG, EntryKind::Hardlink { target_path: target_path.to_string_lossy().into_owned() })
+            }
+            EntryType::Whiteout | EntryType::Other => {
+                bail!("{}: entries of type {:?} cannot be built from metadata", context(), entry.kind)
+            }
+        };
+        let info = EntryInfo {
+            metadata: CachedMetadata {
+                mode: file_type | (entry.mode & 0o7777),
+                uid: entry.uid,
+                gid: entry.gid,
+                mtime: entry.mtime,
+                size: if file_type == S_IFREG { entry.size } else { 0 },
+            },
+            kind,
+            xattrs: entry.xattrs.into_iter().collect(),
+        };
+        entries.push((rel, info));
+    }
+
+    // The layer is written by descending from the root, so every entry
+    // needs its directory listed, and hardlinks a file to point at
+    for (rel, info) in &entries {
+        if let Some(parent) = rel.parent().filter(|p| !p.as_os_str().is_empty()) {
+            if !dirs.contains(parent) {
+                bail!("Layer metadata {}: ./{} is listed without its directory", path.display(), rel.display());
+            }
+        }
+        if let EntryKind::Hardlink { ref target_path } = info.kind {
+            if !files.contains(Path::new(target_path)) {
+                bail!(
+                    "Layer metadata {}: ./{} links to ./{}, which is not a listed file",
+                    path.display(),
+                    rel.display(),
+                    target_path
+                );
+            }
+        }
+    }
+    Ok(entries.into_iter().map(|(rel, info)| (upper.join(rel), info)).collect())
+}
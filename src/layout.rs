@@ -0,0 +1,222 @@
+// Copyright (c) 2019, 2020 Codethink Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::fs;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+
+use crate::blob::IO_BUF_MEDIUM;
+use crate::codec;
+use crate::util::advise_sequential;
+
+pub const MEDIA_TYPE_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+pub const ANNOTATION_REF_NAME: &str = "org.opencontainers.image.ref.name";
+
+/// Read-only view of an OCI image layout directory.
+pub struct Layout {
+    root: PathBuf,
+}
+
+impl Layout {
+    pub fn open(root: &Path) -> Result<Self> {
+        if !root.join("oci-layout").is_file() {
+            bail!("{} is not an OCI image layout (missing oci-layout)", root.display());
+        }
+        Ok(Layout {
+            root: root.to_path_buf(),
+        })
+    }
+
+    /// The output layout of a build in progress, before index.json and
+    /// oci-layout are written; only its blobs can be read.
+    pub fn building(root: &Path) -> Self {
+        Layout {
+            root: root.to_path_buf(),
+        }
+    }
+
+    pub fn index(&self) -> Result<Value> {
+        let path = self.root.join("index.json");
+        let file = fs::File::open(&path).with_context(|| format!("Opening {}", path.display()))?;
+        Ok(serde_json::from_reader(file)?)
+    }
+
+    /// Path of the blob with the given `algorithm:hash` digest.
+    pub fn blob_path(&self, digest: &str) -> Result<PathBuf> {
+        blob_path(&self.root, digest)
+    }
+
+    pub fn read_json(&self, digest: &str) -> Result<Value> {
+        let path = self.blob_path(digest)?;
+        let file = fs::File::open(&path).with_context(|| format!("Opening blob {}", digest))?;
+        Ok(serde_json::from_reader(file)?)
+    }
+
+    /// Manifest descriptors listed in index.json.
+    pub fn manifests(&self) -> Result<Vec<Value>> {
+        Ok(self.index()?["manifests"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    /// Image manifest descriptors of the layout, with those of nested image
+    /// indexes listed in place of the index.
+    pub fn image_manifests(&self) -> Result<Vec<Value>> {
+        image_manifests(&self.root, &self.index()?)
+    }
+
+    /// Image manifest descriptors selected by `matches`, with their positions
+    /// in [`Layout::image_manifests`]. A matching nested index selects all
+    /// of its images.
+    pub fn select_images(&self, matches: impl Fn(&Value) -> bool) -> Result<Vec<(usize, Value)>> {
+        let mut selected = Vec::new();
+        let mut position = 0;
+        for desc in self.manifests()? {
+            let images = if desc["mediaType"].as_str() == Some(MEDIA_TYPE_INDEX) {
+                image_manifests(&self.root, &self.read_json(descriptor_digest(&desc)?)?)?
+            } else {
+                vec![desc.clone()]
+            };
+            let whole = matches(&desc);
+            let count = images.len();
+            for (i, image) in images.into_iter().enumerate() {
+                if whole || matches(&image) {
+                    selected.push((position + i, image));
+                }
+            }
+            position += count;
+        }
+        Ok(selected)
+    }
+
+    /// Find the manifest descriptor matching `reference`, which may be a
+    /// `ref.name` annotation, a full digest, or a position in index.json.
+    /// Without a reference the layout must contain exactly one image. A
+    /// nested image index is resolved to its image if it holds only one.
+    pub fn resolve(&self, reference: Option<&str>) -> Result<Value> {
+        let manifests = self.manifests()?;
+        let desc = match reference {
+            None if manifests.len() == 1 => manifests[0].clone(),
+            None => bail!(
+                "{} contains {} images; specify one as <layout>:<ref>",
+                self.root.display(),
+                manifests.len()
+            ),
+            Some(reference) => match manifests.iter().find(|desc| {
+                desc["annotations"][ANNOTATION_REF_NAME].as_str() == Some(reference)
+                    || desc["digest"].as_str() == Some(reference)
+            }) {
+                Some(desc) => desc.clone(),
+                None => match reference.parse::<usize>().ok().and_then(|i| manifests.get(i)) {
+                    Some(desc) => desc.clone(),
+                    // Images inside nested indexes by digest
+                    None => self
+                        .image_manifests()?
+                        .into_iter()
+                        .find(|desc| desc["digest"].as_str() == Some(reference))
+                        .with_context(|| format!("No image matching '{}' in {}", reference, self.root.display()))?,
+                },
+            },
+        };
+        if desc["mediaType"].as_str() != Some(MEDIA_TYPE_INDEX) {
+            return Ok(desc);
+        }
+        let mut images = image_manifests(&self.root, &self.read_json(descriptor_digest(&desc)?)?)?;
+        match images.len() {
+            1 => Ok(images.remove(0)),
+            n => bail!(
+                "{} is an index of {} images; specify one by digest",
+                descriptor_digest(&desc)?,
+                n
+            ),
+        }
+    }
+
+    /// Open a layer blob as an uncompressed tar stream.
+    pub fn open_layer(&self, desc: &Value) -> Result<Box<dyn Read + Send>> {
+        let path = self.blob_path(descriptor_digest(desc)?)?;
+        let file = fs::File::open(&path).with_context(|| format!("Opening {}", path.display()))?;
+        advise_sequential(&file);
+        let reader = BufReader::with_capacity(IO_BUF_MEDIUM, file);
+
+        let media_type = desc["mediaType"].as_str().unwrap_or_default();
+        codec::for_media_type(media_type).decoder(Box::new(reader))
+    }
+}
+
+/// Image manifest descriptors listed by `index` in the layout at `root`,
+/// descending into nested image indexes.
+pub fn image_manifests(root: &Path, index: &Value) -> Result<Vec<Value>> {
+    let mut manifests = Vec::new();
+    for desc in index["manifests"].as_array().into_iter().flatten() {
+        if desc["mediaType"].as_str() == Some(MEDIA_TYPE_INDEX) {
+            let path = blob_path(root, descriptor_digest(desc)?)?;
+            let file = fs::File::open(&path).with_context(|| format!("Opening {}", path.display()))?;
+            let nested: Value = serde_json::from_reader(file)?;
+            manifests.extend(image_manifests(root, &nested)?);
+        } else {
+            manifests.push(desc.clone());
+        }
+    }
+    Ok(manifests)
+}
+
+/// Path of the blob `digest` in the layout at `root`. The digest must have
+/// the syntax of the image spec, so it cannot name a path outside `blobs/`.
+fn blob_path(root: &Path, digest: &str) -> Result<PathBuf> {
+    let valid = digest.split_once(':').filter(|(algo, hash)| {
+        algo.split(['+', '.', '_', '-'])
+            .all(|part| !part.is_empty() && part.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit()))
+            && !hash.is_empty()
+            && hash.bytes().all(|b| b.is_ascii_alphanumeric() || b"=_-".contains(&b))
+    });
+    let Some((algo, hash)) = valid else {
+        bail!("Invalid digest '{}': expected 'algorithm:hash'", digest);
+    };
+    Ok(root.join("blobs").join(algo).join(hash))
+}
+
+/// Split a `<layout>[:<ref>]` argument. A path that exists as-is is never split,
+/// so layouts whose directory name contains a colon keep working.
+pub fn parse_image_ref(arg: &str) -> (PathBuf, Option<String>) {
+    if Path::new(arg).is_dir() {
+        return (PathBuf::from(arg), None);
+    }
+    match arg.split_once(':') {
+        Some((path, reference)) => (PathBuf::from(path), Some(reference.to_string())),
+        None => (PathBuf::from(arg), None),
+    }
+}
+
+/// Digest string of a descriptor.
+pub fn descriptor_digest(desc: &Value) -> Result<&str> {
+    desc["digest"]
+        .as_str()
+        .context("Missing 'digest' in descriptor")
+}
+
+/// Size of a descriptor, 0 if absent.
+pub fn descriptor_size(desc: &Value) -> u64 {
+    desc["size"].as_u64().unwrap_or(0)
+}
@@ -0,0 +1,192 @@
+// Copyright (c) 2019, 2020 Codethink Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Lazy-pull layer formats (eStargz, zstd:chunked) and the descriptor
+//! annotations snapshotters need to use them. Without the annotations such a
+//! layer is silently pulled in full.
+
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use anyhow::Result;
+use rayon::prelude::*;
+
+use crate::layout::{descriptor_digest, Layout};
+
+/// Digest of the eStargz table of contents
+pub const ANNOTATION_ESTARGZ_TOC_DIGEST: &str = "containerd.io/snapshot/stargz/toc.digest";
+/// Size of the eStargz layer once decompressed
+pub const ANNOTATION_ESTARGZ_UNCOMPRESSED_SIZE: &str = "io.containers.estargz.uncompressed-size";
+/// Digest of the zstd:chunked manifest
+pub const ANNOTATION_ZSTD_CHUNKED_CHECKSUM: &str = "io.github.containers.zstd-chunked.manifest-checksum";
+/// Offset, lengths and type of the zstd:chunked manifest
+pub const ANNOTATION_ZSTD_CHUNKED_POSITION: &str = "io.github.containers.zstd-chunked.manifest-position";
+
+/// Prefixes of every annotation describing the layout of a lazy-pull blob
+const ANNOTATION_PREFIXES: &[&str] = &[
+    "containerd.io/snapshot/stargz/",
+    "io.containers.estargz.",
+    "io.github.containers.zstd-chunked.",
+];
+
+/// eStargz footer: a gzip member whose extra field ends in `STARGZ`
+/// (51 bytes; 47 for legacy stargz)
+const ESTARGZ_FOOTER_LEN: u64 = 51;
+const ESTARGZ_MAGIC: &[u8] = b"STARGZ";
+/// zstd:chunked footer: a skippable frame ending in this magic
+const ZSTD_CHUNKED_FOOTER_LEN: u64 = 64;
+const ZSTD_CHUNKED_MAGIC: &[u8] = b"GNUlInUx";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Estargz,
+    ZstdChunked,
+}
+
+impl Format {
+    fn name(self) -> &'static str {
+        match self {
+            Format::Estargz => "eStargz",
+            Format::ZstdChunked => "zstd:chunked",
+        }
+    }
+}
+
+fn is_lazy_pull_annotation(key: &str) -> bool {
+    ANNOTATION_PREFIXES.iter().any(|prefix| key.starts_with(prefix))
+}
+
+/// Copy the lazy-pull annotations of a parent layer descriptor to the
+/// descriptor of its verbatim copy.
+pub fn carry_annotations(from: &serde_json::Value, to: &mut serde_json::Value) {
+    let Some(annotations) = from["annotations"].as_object() else {
+        return;
+    };
+    for (key, value) in annotations.iter().filter(|(key, _)| is_lazy_pull_annotation(key)) {
+        to["annotations"][key] = value.clone();
+    }
+}
+
+/// Whether a parent layer descriptor carries lazy-pull annotations, which a
+/// re-encoded copy loses.
+pub fn has_annotations(desc: &serde_json::Value) -> bool {
+    desc["annotations"]
+        .as_object()
+        .is_some_and(|annotations| annotations.keys().any(|key| is_lazy_pull_annotation(key)))
+}
+
+/// Lazy-pull format of a layer blob, recognised by its footer.
+pub fn detect(path: &Path) -> Result<Option<Format>> {
+    let mut file = fs::File::open(path)?;
+    let len = file.metadata()?.len();
+    let tail_len = ESTARGZ_FOOTER_LEN.max(ZSTD_CHUNKED_FOOTER_LEN).min(len);
+    file.seek(SeekFrom::End(-(tail_len as i64)))?;
+    let mut tail = Vec::with_capacity(tail_len as usize);
+    file.read_to_end(&mut tail)?;
+
+    let estargz_footer = &tail[tail.len().saturating_sub(ESTARGZ_FOOTER_LEN as usize)..];
+    if estargz_footer.windows(ESTARGZ_MAGIC.len()).any(|w| w == ESTARGZ_MAGIC) {
+        return Ok(Some(Format::Estargz));
+    }
+    if tail.ends_with(ZSTD_CHUNKED_MAGIC) {
+        return Ok(Some(Format::ZstdChunked));
+    }
+    Ok(None)
+}
+
+/// Annotations a snapshotter needs for a layer of the given format
+fn required_annotations(format: Format) -> &'static [&'static str] {
+    match format {
+        Format::Estargz => &[ANNOTATION_ESTARGZ_TOC_DIGEST, ANNOTATION_ESTARGZ_UNCOMPRESSED_SIZE],
+        Format::ZstdChunked => &[ANNOTATION_ZSTD_CHUNKED_CHECKSUM, ANNOTATION_ZSTD_CHUNKED_POSITION],
+    }
+}
+
+/// Check every layer of every image in a layout: lazy-pull blobs must carry
+/// complete, well-formed annotations, and annotated layers must really be in
+/// that format. Returns the number of lazy-pull layers and the problems found.
+pub fn check_layout(root: &Path) -> Result<(usize, Vec<String>)> {
+    let layout = Layout::open(root)?;
+    let mut layers = std::collections::BTreeMap::new();
+    for desc in layout.image_manifests()? {
+        let manifest = layout.read_json(descriptor_digest(&desc)?)?;
+        for layer in manifest["layers"].as_array().into_iter().flatten() {
+            layers.insert(descriptor_digest(layer)?.to_string(), layer.clone());
+        }
+    }
+
+    let checked: Vec<(bool, Vec<String>)> = layers
+        .par_iter()
+        .map(|(digest, desc)| check_layer(&layout, digest, desc))
+        .collect::<Result<_>>()?;
+    let lazy = checked.iter().filter(|(lazy, _)| *lazy).count();
+    Ok((lazy, checked.into_iter().flat_map(|(_, problems)| problems).collect()))
+}
+
+fn check_layer(layout: &Layout, digest: &str, desc: &serde_json::Value) -> Result<(bool, Vec<String>)> {
+    let format = detect(&layout.blob_path(digest)?)?;
+    let annotation = |key: &str| desc["annotations"][key].as_str();
+    let mut problems = Vec::new();
+
+    for claimed in [Format::Estargz, Format::ZstdChunked] {
+        let keys = required_annotations(claimed);
+        if format != Some(claimed) && keys.iter().any(|key| annotation(key).is_some()) {
+            problems.push(format!("{}: annotated as {} but the blob is not", digest, claimed.name()));
+        }
+    }
+    let Some(format) = format else {
+        return Ok((false, problems));
+    };
+    for key in required_annotations(format) {
+        if annotation(key).is_none() {
+            problems.push(format!(
+                "{}: {} layer without {}; it will be pulled in full",
+                digest,
+                format.name(),
+                key
+            ));
+        }
+    }
+
+    if let Some(toc) = annotation(ANNOTATION_ESTARGZ_TOC_DIGEST).or(annotation(ANNOTATION_ZSTD_CHUNKED_CHECKSUM)) {
+        let well_formed = toc
+            .strip_prefix("sha256:")
+            .is_some_and(|hex| hex.len() == 64 && hex.bytes().all(|b| b.is_ascii_hexdigit()));
+        if !well_formed {
+            problems.push(format!("{}: malformed TOC digest '{}'", digest, toc));
+        }
+    }
+    if let (Format::Estargz, Some(size)) = (format, annotation(ANNOTATION_ESTARGZ_UNCOMPRESSED_SIZE)) {
+        match size.parse::<u64>() {
+            Ok(size) => {
+                let actual = io::copy(&mut layout.open_layer(desc)?, &mut io::sink())?;
+                if actual != size {
+                    problems.push(format!(
+                        "{}: {} is {} but the layer decompresses to {} bytes",
+                        digest, ANNOTATION_ESTARGZ_UNCOMPRESSED_SIZE, size, actual
+                    ));
+                }
+            }
+            Err(_) => problems.push(format!("{}: malformed uncompressed size '{}'", digest, size)),
+        }
+    }
+    Ok((true, problems))
+}
@@ -0,0 +1,155 @@
+// Copyright (c) 2019, 2020 Codethink Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+
+/// Build-wide resource bounds from `max-memory-mb` and `max-open-files`,
+/// shared by every layer and image of a document. Unset limits are unbounded.
+#[derive(Debug, Default)]
+pub struct Limits {
+    /// Bytes of file contents that may be mapped or cached at once
+    max_memory: Option<usize>,
+    memory_used: AtomicUsize,
+    /// Files that may be mapped or streamed at once
+    max_files: Option<usize>,
+    files_used: Mutex<usize>,
+    files_released: Condvar,
+}
+
+impl Limits {
+    pub fn new(max_memory_mb: Option<usize>, max_open_files: Option<usize>) -> Arc<Limits> {
+        Arc::new(Limits {
+            max_memory: max_memory_mb.map(|mb| mb.saturating_mul(1024 * 1024)),
+            max_files: max_open_files,
+            ..Limits::default()
+        })
+    }
+
+    fn try_reserve_memory(&self, bytes: usize) -> bool {
+        let Some(max) = self.max_memory else {
+            return true;
+        };
+        self.memory_used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                used.checked_add(bytes).filter(|&total| total <= max)
+            })
+            .is_ok()
+    }
+
+    fn try_reserve_file(&self) -> bool {
+        let Some(max) = self.max_files else {
+            return true;
+        };
+        let mut used = self.files_used.lock().unwrap_or_else(|e| e.into_inner());
+        if *used < max {
+            *used += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn release(&self, bytes: usize, files: usize) {
+        if self.max_memory.is_some() {
+            self.memory_used.fetch_sub(bytes, Ordering::AcqRel);
+        }
+        if self.max_files.is_some() && files > 0 {
+            let mut used = self.files_used.lock().unwrap_or_else(|e| e.into_inner());
+            *used -= files;
+            self.files_released.notify_all();
+        }
+    }
+
+    /// Wait for a file slot, held until the returned guard is dropped.
+    pub fn open_file(self: &Arc<Self>) -> FileSlot {
+        if let Some(max) = self.max_files {
+            let mut used = self.files_used.lock().unwrap_or_else(|e| e.into_inner());
+            // A single slot is always granted so a limit of 0 cannot hang the build
+            while *used >= max.max(1) {
+                used = self.files_released.wait(used).unwrap_or_else(|e| e.into_inner());
+            }
+            *used += 1;
+        }
+        FileSlot(Arc::clone(self))
+    }
+}
+
+/// A file slot taken with [`Limits::open_file`].
+pub struct FileSlot(Arc<Limits>);
+
+impl Drop for FileSlot {
+    fn drop(&mut self) {
+        self.0.release(0, 1);
+    }
+}
+
+/// Memory and file slots held by the cached contents of one layer, released
+/// together when the layer has been written.
+#[derive(Debug)]
+pub struct Lease {
+    limits: Arc<Limits>,
+    bytes: AtomicUsize,
+    files: AtomicUsize,
+}
+
+impl Lease {
+    pub fn new(limits: &Arc<Limits>) -> Lease {
+        Lease { limits: Arc::clone(limits), bytes: AtomicUsize::new(0), files: AtomicUsize::new(0) }
+    }
+
+    /// Reserve `bytes` of memory, failing without waiting if that would
+    /// exceed `max-memory-mb`.
+    pub fn try_cache(&self, bytes: usize) -> bool {
+        if !self.limits.try_reserve_memory(bytes) {
+            return false;
+        }
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        true
+    }
+
+    /// Reserve `bytes` of memory and a file slot for a mapping, failing
+    /// without waiting if either limit would be exceeded.
+    pub fn try_map(&self, bytes: usize) -> bool {
+        if !self.limits.try_reserve_file() {
+            return false;
+        }
+        if !self.limits.try_reserve_memory(bytes) {
+            self.limits.release(0, 1);
+            return false;
+        }
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.files.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// Give back a reservation whose contents were not kept after all.
+    pub fn undo(&self, bytes: usize, files: usize) {
+        self.bytes.fetch_sub(bytes, Ordering::Relaxed);
+        self.files.fetch_sub(files, Ordering::Relaxed);
+        self.limits.release(bytes, files);
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.limits.release(*self.bytes.get_mut(), *self.files.get_mut());
+    }
+}
@@ -0,0 +1,177 @@
+// Copyright (c) 2019, 2020 Codethink Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::fmt;
+
+use anyhow::{bail, Context, Result};
+use tracing::warn;
+
+use crate::config::LintRuleSpec;
+use crate::listing::EntryType;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LintAction {
+    Warn,
+    Fail,
+}
+
+#[derive(Debug, Clone)]
+pub enum LintCheck {
+    /// Entries owned by a uid above the limit
+    MaxUid(u64),
+    /// Entries owned by a gid above the limit
+    MaxGid(u64),
+    /// World-writable files, and world-writable directories without the sticky bit
+    WorldWritable,
+    /// setuid or setgid files
+    Setuid,
+    /// The entry at this path (e.g. `/etc/shadow`) must have exactly this mode
+    Mode { path: String, mode: u32 },
+}
+
+#[derive(Debug, Clone)]
+pub struct LintRule {
+    pub check: LintCheck,
+    pub action: LintAction,
+}
+
+/// A rule matched by a layer entry.
+#[derive(Debug, Clone)]
+pub struct Violation {
+    pub rule: &'static str,
+    pub action: LintAction,
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}: {}", self.rule, self.path, self.message)
+    }
+}
+
+impl LintRule {
+    pub fn from_spec(spec: &LintRuleSpec) -> Result<LintRule> {
+        let action = match spec.action.as_deref().unwrap_or("fail") {
+            "warn" => LintAction::Warn,
+            "fail" => LintAction::Fail,
+            other => bail!("lint action must be warn or fail, got: {}", other),
+        };
+        let value = || {
+            spec.value
+                .ok_or_else(|| anyhow::anyhow!("lint rule {} requires a value", spec.rule))
+        };
+        let check = match spec.rule.as_str() {
+            "max-uid" => LintCheck::MaxUid(value()?),
+            "max-gid" => LintCheck::MaxGid(value()?),
+            "world-writable" => LintCheck::WorldWritable,
+            "setuid" => LintCheck::Setuid,
+            "mode" => {
+                let (Some(path), Some(mode)) = (&spec.path, &spec.mode) else {
+                    bail!("lint rule mode requires a path and a mode");
+                };
+                let mode = u32::from_str_radix(mode, 8)
+                    .with_context(|| format!("lint rule mode: invalid octal mode '{}'", mode))?;
+                LintCheck::Mode { path: format!("/{}", path.trim_start_matches('/')), mode }
+            }
+            other => bail!(
+                "lint rule must be max-uid, max-gid, world-writable, setuid or mode, got: {}",
+                other
+            ),
+        };
+        Ok(LintRule { check, action })
+    }
+
+    fn name(&self) -> &'static str {
+        match self.check {
+            LintCheck::MaxUid(_) => "max-uid",
+            LintCheck::MaxGid(_) => "max-gid",
+            LintCheck::WorldWritable => "world-writable",
+            LintCheck::Setuid => "setuid",
+            LintCheck::Mode { .. } => "mode",
+        }
+    }
+
+    /// Check one entry; `path` is absolute within the image, `mode` includes
+    /// the setuid/setgid/sticky bits.
+    fn check(&self, path: &str, kind: EntryType, mode: u32, uid: u64, gid: u64) -> Option<String> {
+        // Symlink permissions are meaningless
+        let has_mode = !matches!(kind, EntryType::Symlink | EntryType::Whiteout);
+        match &self.check {
+            LintCheck::MaxUid(max) if uid > *max => {
+                Some(format!("owned by uid {} (maximum {})", uid, max))
+            }
+            LintCheck::MaxGid(max) if gid > *max => {
+                Some(format!("owned by gid {} (maximum {})", gid, max))
+            }
+            LintCheck::WorldWritable if has_mode && mode & 0o002 != 0 => match kind {
+                EntryType::Dir if mode & 0o1000 != 0 => None,
+                EntryType::Dir => Some("world-writable directory without sticky bit".to_string()),
+                _ => Some("world-writable".to_string()),
+            },
+            LintCheck::Setuid if has_mode && mode & 0o6000 != 0 => {
+                Some(format!("setuid/setgid mode {:04o}", mode & 0o7777))
+            }
+            LintCheck::Mode { path: want, mode: want_mode }
+                if has_mode && path == want && mode & 0o7777 != *want_mode =>
+            {
+                Some(format!("mode {:04o}, expected {:04o}", mode & 0o7777, want_mode))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Evaluate every rule against one layer entry. `rel` is the archive path
+/// (`./etc/shadow`, or `./` for the layer root).
+pub fn check_entry(
+    rules: &[LintRule],
+    violations: &mut Vec<Violation>,
+    rel: &str,
+    kind: EntryType,
+    mode: u32,
+    uid: u64,
+    gid: u64,
+) {
+    if rules.is_empty() {
+        return;
+    }
+    let path = format!("/{}", rel.trim_start_matches('.').trim_matches('/'));
+    for rule in rules {
+        if let Some(message) = rule.check(&path, kind, mode, uid, gid) {
+            violations.push(Violation { rule: rule.name(), action: rule.action, path: path.clone(), message });
+        }
+    }
+}
+
+/// Log warnings and fail if any `fail` rule was violated.
+pub fn report(violations: &[Violation], layer: &str) -> Result<()> {
+    let mut failures = Vec::new();
+    for v in violations {
+        match v.action {
+            LintAction::Warn => warn!(rule = v.rule, path = %v.path, layer, "{}", v.message),
+            LintAction::Fail => failures.push(v.to_string()),
+        }
+    }
+    if !failures.is_empty() {
+        bail!("Lint failed for layer {}:\n  {}", layer, failures.join("\n  "));
+    }
+    Ok(())
+}
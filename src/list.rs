@@ -0,0 +1,158 @@
+// Copyright (c) 2019, 2020 Codethink Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::path::Path;
+
+use anyhow::Result;
+use clap::ArgMatches;
+use serde_json::Value;
+
+use crate::cli;
+use crate::layout::{
+    descriptor_digest, descriptor_size, Layout, ANNOTATION_REF_NAME, MEDIA_TYPE_INDEX,
+};
+use crate::util::format_size;
+
+/// One row of `build-oci ls` output.
+struct ImageEntry {
+    ref_name: Option<String>,
+    digest: String,
+    media_type: Option<String>,
+    platform: Option<String>,
+    size: u64,
+    created: Option<String>,
+}
+
+impl ImageEntry {
+    fn to_json(&self) -> Value {
+        serde_json::json!({
+            "ref": self.ref_name,
+            "digest": self.digest,
+            "mediaType": self.media_type,
+            "platform": self.platform,
+            "size": self.size,
+            "created": self.created,
+        })
+    }
+}
+
+/// `build-oci ls <layout> [--json]`: list the images in an OCI layout.
+pub fn run(args: &ArgMatches) -> Result<()> {
+    let layout_path = cli::required(args, "layout");
+    let json = args.get_flag("json");
+
+    let layout = Layout::open(Path::new(layout_path))?;
+    let entries = layout
+        .manifests()?
+        .iter()
+        .map(|desc| describe(&layout, desc))
+        .collect::<Result<Vec<_>>>()?;
+
+    if json {
+        let list: Vec<Value> = entries.iter().map(ImageEntry::to_json).collect();
+        println!("{}", serde_json::to_string_pretty(&list)?);
+    } else {
+        print_table(&entries);
+    }
+    Ok(())
+}
+
+fn describe(layout: &Layout, desc: &Value) -> Result<ImageEntry> {
+    let digest = descriptor_digest(desc)?.to_string();
+    let media_type = desc["mediaType"].as_str().map(|s| s.to_string());
+    let ref_name = desc["annotations"][ANNOTATION_REF_NAME]
+        .as_str()
+        .map(|s| s.to_string());
+    let mut platform = desc.get("platform").map(platform_string);
+    let mut size = descriptor_size(desc);
+    let mut created = None;
+
+    // Nested indexes are listed as a single entry without chasing children
+    if media_type.as_deref() != Some(MEDIA_TYPE_INDEX) {
+        let manifest = layout.read_json(&digest)?;
+        size += descriptor_size(&manifest["config"]);
+        if let Some(layers) = manifest["layers"].as_array() {
+            size += layers.iter().map(descriptor_size).sum::<u64>();
+        }
+        if let Ok(config_digest) = descriptor_digest(&manifest["config"]) {
+            let config = layout.read_json(config_digest)?;
+            created = config["created"].as_str().map(|s| s.to_string());
+            // Artifacts have the empty config, without a platform
+            if platform.is_none() && manifest.get("artifactType").is_none() {
+                platform = Some(platform_string(&config));
+            }
+        }
+    }
+
+    Ok(ImageEntry {
+        ref_name,
+        digest,
+        media_type,
+        platform,
+        size,
+        created,
+    })
+}
+
+/// Render a platform object as `os/architecture[/variant]`.
+pub fn platform_string(platform: &Value) -> String {
+    let os = platform["os"].as_str().unwrap_or("unknown");
+    let arch = platform["architecture"].as_str().unwrap_or("unknown");
+    match platform["variant"].as_str() {
+        Some(variant) => format!("{}/{}/{}", os, arch, variant),
+        None => format!("{}/{}", os, arch),
+    }
+}
+
+fn print_table(entries: &[ImageEntry]) {
+    let header = ["REF", "PLATFORM", "SIZE", "CREATED", "DIGEST"];
+    let rows: Vec<[String; 5]> = entries
+        .iter()
+        .map(|e| {
+            [
+                e.ref_name.clone().unwrap_or_else(|| "<none>".to_string()),
+                e.platform.clone().unwrap_or_else(|| "-".to_string()),
+                format_size(e.size),
+                e.created.clone().unwrap_or_else(|| "-".to_string()),
+                e.digest.clone(),
+            ]
+        })
+        .collect();
+
+    let mut widths = header.map(str::len);
+    for row in &rows {
+        for (w, cell) in widths.iter_mut().zip(row) {
+            *w = (*w).max(cell.len());
+        }
+    }
+
+    let print_row = |cells: &[&str]| {
+        let line: Vec<String> = cells
+            .iter()
+            .zip(widths)
+            .map(|(cell, w)| format!("{:<width$}", cell, width = w))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    };
+    print_row(&header);
+    for row in &rows {
+        print_row(&row.each_ref().map(String::as_str));
+    }
+}
@@ -0,0 +1,128 @@
+// Copyright (c) 2019, 2020 Codethink Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::fmt::Write as _;
+
+use serde::{Deserialize, Serialize};
+
+/// Layer descriptor annotation holding the digest of the layer's file listing blob
+pub const ANNOTATION_LISTING: &str = "org.freedesktopsdk.layer.listing";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ListingFormat {
+    Json,
+    Mtree,
+}
+
+impl ListingFormat {
+    pub fn media_type(self) -> &'static str {
+        match self {
+            ListingFormat::Json => "application/vnd.freedesktopsdk.layer.listing.v1+json",
+            ListingFormat::Mtree => "application/vnd.freedesktopsdk.layer.listing.v1+mtree",
+        }
+    }
+}
+
+/// One entry of a layer tar, as recorded by `create_layer`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ListingEntry {
+    /// Archive path, e.g. `./usr/bin/foo` (`.` for the layer root)
+    pub path: String,
+    #[serde(rename = "type")]
+    pub kind: EntryType,
+    pub size: u64,
+    /// Permission bits, without the file type
+    pub mode: u32,
+    pub uid: u64,
+    pub gid: u64,
+    /// Hex SHA256 of regular file (and hardlink) contents
+    #[serde(rename = "sha256", skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+    /// Symlink or hardlink target
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryType {
+    Dir,
+    File,
+    Symlink,
+    Hardlink,
+    Whiteout,
+    Other,
+}
+
+pub fn render(format: ListingFormat, entries: &[ListingEntry]) -> Vec<u8> {
+    match format {
+        ListingFormat::Json => {
+            // Serializing plain structs into a Vec cannot fail
+            serde_json::to_vec(&serde_json::json!({ "entries": entries })).unwrap_or_default()
+        }
+        ListingFormat::Mtree => render_mtree(entries).into_bytes(),
+    }
+}
+
+fn render_mtree(entries: &[ListingEntry]) -> String {
+    let mut out = String::with_capacity(64 + entries.len() * 96);
+    out.push_str("#mtree\n");
+    for e in entries {
+        let kind = match e.kind {
+            EntryType::Dir => "dir",
+            EntryType::Symlink => "link",
+            // mtree has no hardlink or whiteout type; both are regular files in the tar
+            EntryType::File | EntryType::Hardlink | EntryType::Whiteout | EntryType::Other => "file",
+        };
+        let _ = write!(
+            out,
+            "{} type={} mode={:04o} uid={} gid={}",
+            mtree_escape(&e.path),
+            kind,
+            e.mode,
+            e.uid,
+            e.gid
+        );
+        if kind == "file" {
+            let _ = write!(out, " size={}", e.size);
+        }
+        if let Some(ref digest) = e.digest {
+            let _ = write!(out, " sha256digest={}", digest);
+        }
+        if let (EntryType::Symlink, Some(target)) = (e.kind, &e.target) {
+            let _ = write!(out, " link={}", mtree_escape(target));
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// Escape whitespace, backslashes and non-printable bytes as `\ooo`, as mtree(5) expects.
+fn mtree_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for &b in s.as_bytes() {
+        if b.is_ascii_graphic() && b != b'\\' {
+            out.push(b as char);
+        } else {
+            let _ = write!(out, "\\{:03o}", b);
+        }
+    }
+    out
+}
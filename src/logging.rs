@@ -0,0 +1,71 @@
+// Copyright (c) 2019, 2020 Codethink Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::io::IsTerminal;
+
+use clap::ArgMatches;
+use tracing_subscriber::EnvFilter;
+
+use crate::progress;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+/// Install the global subscriber as the logging flags (`-v`/`--verbose`,
+/// `-q`/`--quiet`, `--log-format text|json`, `--no-progress`) ask.
+///
+/// Logs go to stderr. The default level is `warn`; each `-v` raises it one
+/// step (info, debug, trace) and `-q` lowers it to `error`. `RUST_LOG`, when
+/// set, overrides the level entirely. Progress bars are drawn alongside text
+/// logs when stderr is a terminal, unless `-q` or `--no-progress` is given.
+pub fn init(args: &ArgMatches) {
+    let verbosity = i32::from(args.get_count("verbose")) - i32::from(args.get_flag("quiet"));
+    let format = match args.get_one::<String>("log-format").map(String::as_str) {
+        Some("json") => LogFormat::Json,
+        _ => LogFormat::Text,
+    };
+    let no_progress = args.get_flag("no-progress");
+
+    let level = match verbosity {
+        i32::MIN..=-1 => "error",
+        0 => "warn",
+        1 => "info",
+        2 => "debug",
+        _ => "trace",
+    };
+    progress::init(
+        format == LogFormat::Text && verbosity >= 0 && !no_progress && std::io::stderr().is_terminal(),
+    );
+
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(level));
+
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(|| progress::LogWriter)
+        .with_target(false);
+    // Ignore error if a subscriber is already installed
+    let _ = match format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().with_current_span(true).with_span_list(true).try_init(),
+    };
+}
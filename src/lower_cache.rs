@@ -0,0 +1,139 @@
+// Copyright (c) 2019, 2020 Codethink Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use tracing::{debug, warn};
+use zstd::stream::read::Decoder as ZstdDecoder;
+use zstd::stream::write::Encoder as ZstdEncoder;
+
+use crate::blob::IO_BUF_MEDIUM;
+use crate::util::{advise_sequential, HashingWriter};
+
+/// How lower layer tars are stored in the cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LowerCacheFormat {
+    /// Plain tar: analysis is limited by disk reads only
+    Uncompressed,
+    /// zstd level 1: roughly a third of the space, still far cheaper to read than gzip
+    Zstd,
+}
+
+/// Decompressed copies of lower layers, shared across invocations and keyed
+/// by diff_id, so repeated analysis of the same parent skips decompression.
+/// Lives in `$XDG_CACHE_HOME/build-oci/lowers` (or `~/.cache/build-oci/lowers`).
+pub struct LowerCache {
+    dir: PathBuf,
+    format: LowerCacheFormat,
+}
+
+impl LowerCache {
+    pub fn open(format: LowerCacheFormat) -> Result<Self> {
+        let dir = crate::util::cache_dir()
+            .context("Cannot locate a cache directory: neither XDG_CACHE_HOME nor HOME is set")?
+            .join("lowers")
+            .join("sha256");
+        fs::create_dir_all(&dir).with_context(|| format!("Creating {}", dir.display()))?;
+        Ok(LowerCache { dir, format })
+    }
+
+    fn path(&self, hash: &str) -> PathBuf {
+        match self.format {
+            LowerCacheFormat::Uncompressed => self.dir.join(format!("{}.tar", hash)),
+            LowerCacheFormat::Zstd => self.dir.join(format!("{}.tar.zst", hash)),
+        }
+    }
+
+    /// Whether the lower layer with `diff_id` is cached.
+    pub fn contains(&self, diff_id: &str) -> bool {
+        self.path(diff_id.strip_prefix("sha256:").unwrap_or(diff_id)).is_file()
+    }
+
+    /// Uncompressed tar stream of the lower layer with `diff_id`, from the
+    /// cache if present; otherwise `decompress` is run once to fill the cache.
+    /// A copy whose content does not hash to `diff_id` is never stored.
+    pub fn reader(
+        &self,
+        diff_id: &str,
+        decompress: impl Fn() -> Result<Box<dyn Read + Send>>,
+    ) -> Result<Box<dyn Read + Send>> {
+        let hash = diff_id.strip_prefix("sha256:").unwrap_or(diff_id);
+        let path = self.path(hash);
+        if !path.is_file() {
+            if !self.fill(diff_id, hash, &path, decompress()?)? {
+                // Not cacheable, fall back to streaming the blob itself
+                return decompress();
+            }
+        } else {
+            debug!(diff_id, "lower layer cache hit");
+        }
+        open_cached(&path, self.format)
+    }
+
+    /// Write `reader` to the cache, returning whether it matched `hash`.
+    fn fill(
+        &self,
+        diff_id: &str,
+        hash: &str,
+        path: &Path,
+        mut reader: Box<dyn Read + Send>,
+    ) -> Result<bool> {
+        let tmp = tempfile::NamedTempFile::new_in(&self.dir)?;
+        let file = BufWriter::with_capacity(IO_BUF_MEDIUM, tmp.reopen()?);
+        let digest = match self.format {
+            LowerCacheFormat::Uncompressed => {
+                let mut writer = HashingWriter::new(file);
+                io::copy(&mut reader, &mut writer)?;
+                let (mut file, digest) = writer.finish()?;
+                file.flush()?;
+                digest
+            }
+            LowerCacheFormat::Zstd => {
+                let mut writer = HashingWriter::new(ZstdEncoder::new(file, 1)?);
+                io::copy(&mut reader, &mut writer)?;
+                let (encoder, digest) = writer.finish()?;
+                encoder.finish()?.flush()?;
+                digest
+            }
+        };
+
+        if digest != hash {
+            warn!(diff_id, actual = %digest, "lower layer does not match its diff_id, not caching it");
+            return Ok(false);
+        }
+        // Concurrent builds may race to fill the same entry; the content is identical
+        tmp.persist(path).map_err(|e| e.error)?;
+        debug!(diff_id, "cached lower layer");
+        Ok(true)
+    }
+}
+
+fn open_cached(path: &Path, format: LowerCacheFormat) -> Result<Box<dyn Read + Send>> {
+    let file = fs::File::open(path).with_context(|| format!("Opening {}", path.display()))?;
+    advise_sequential(&file);
+    let reader = BufReader::with_capacity(IO_BUF_MEDIUM, file);
+    Ok(match format {
+        LowerCacheFormat::Uncompressed => Box::new(reader),
+        LowerCacheFormat::Zstd => Box::new(ZstdDecoder::new(reader)?),
+    })
+}
@@ -26,51 +26,215 @@ use tikv_jemallocator::Jemalloc;
 static GLOBAL: Jemalloc = Jemalloc;
 
 mod blob;
+mod blob_queue;
+mod build_report;
+mod cache_stats;
+mod cli;
+mod codec;
+mod config;
+mod corpus;
+mod docker_archive;
+mod du;
+mod error;
+mod estargz;
+mod gc;
+mod id_map;
 mod image_builder;
+mod inspect;
+mod integrity;
+mod keys;
 mod layer_builder;
+mod layer_cache;
+mod layer_index;
+mod layer_metadata;
+mod lazy_pull;
+mod layout;
+mod limits;
+mod lint;
+mod list;
+mod listing;
+mod logging;
+mod lower_cache;
+mod manifest_lint;
+mod oci_archive;
+mod overlay;
+mod ownership;
+mod parent_verify;
+mod platform;
+mod priority;
+mod progress;
+mod publish;
+mod registry;
+mod retry;
+mod rsyncable;
+mod sbom;
+mod signing;
+mod tar_stream;
+mod unpack;
 pub mod util;
+mod verify;
+#[cfg(target_os = "linux")]
+mod watch;
+mod zstd_chunked;
 
+use std::collections::HashSet;
+use std::ffi::OsString;
 use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::{bail, Result};
+use anyhow::{anyhow, Context, Result};
+use build_oci::tar_parser;
+use clap::ArgMatches;
+
+use crate::codec::{Codec, CompressionOptions};
+use crate::config::{ManifestFormat, StringMap};
+use crate::error::{ErrorCategory, ImageFailures, ResultExt};
+use crate::id_map::IdMap;
+use crate::image_builder::{FailurePolicy, LayoutDigests};
+use crate::layer_cache::RemoteCache;
+use crate::limits::Limits;
+use crate::lint::LintRule;
+use crate::listing::ListingFormat;
+use crate::lower_cache::LowerCacheFormat;
+use crate::ownership::Ownership;
+use crate::platform::Compatibility;
+use crate::priority::Priority;
+use crate::retry::RetryPolicy;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Compression {
     Gzip,
     Zstd,
+    Estargz,
+    ZstdChunked,
     Disabled,
 }
 
+impl Compression {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            "estargz" => Ok(Compression::Estargz),
+            "zstd-chunked" => Ok(Compression::ZstdChunked),
+            "disabled" => Ok(Compression::Disabled),
+            other => Err(anyhow!("Compression must be gzip, zstd, estargz, zstd-chunked, or disabled, got: {}", other)),
+        }
+    }
+
+    pub fn codec(self) -> &'static dyn Codec {
+        match self {
+            Compression::Gzip => &codec::GZIP,
+            Compression::Zstd => &codec::ZSTD,
+            Compression::Estargz => &estargz::ESTARGZ,
+            Compression::ZstdChunked => &zstd_chunked::ZSTD_CHUNKED,
+            Compression::Disabled => &codec::UNCOMPRESSED,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct GlobalConfig {
     pub compression: Compression,
     pub compression_level: Option<u32>,
+    pub compression_options: CompressionOptions,
+    pub compression_annotations: bool,
+    pub build_id_annotation: bool,
+    /// Fallback values of the standard annotations from CI variables, when enabled
+    pub standard_annotations: Option<StringMap>,
     pub output: String,
+    /// Merge into the output's existing index.json instead of replacing it
+    pub merge_index: bool,
+    /// Reuse the layers of the previous build in the output instead of building them
+    pub manifest_only: bool,
     pub workers: usize,
     pub compression_threads: usize,
+    /// Images of a document built at the same time
+    pub image_parallelism: usize,
     pub skip_xattrs: bool,
+    /// Store checksums computed for layer files back on the files
+    pub write_checksum_xattrs: bool,
+    /// Open layer source files with O_NOATIME when permitted
+    pub noatime: bool,
     pub prefetch_limit_mb: usize,
+    /// MiB of each new layer blob queued between compression and its writer thread
+    pub persist_queue_mb: usize,
+    /// Retries of file system calls that fail transiently on network storage
+    pub io_retry: RetryPolicy,
+    pub limits: Arc<Limits>,
+    pub source_date_epoch: Option<u64>,
+    /// Owners of new layer entries rewritten by an image's `id-map:`
+    pub id_map: IdMap,
+    /// Owners and modes of new layer entries set by an image's `ownership:`
+    pub ownership: Ownership,
+    pub layer_listing: Option<ListingFormat>,
+    pub layer_index: bool,
+    pub lower_cache: Option<LowerCacheFormat>,
+    /// Layer blobs kept across builds, keyed by their inputs
+    pub cache_dir: Option<PathBuf>,
+    pub remote_cache: Option<RemoteCache>,
+    pub lint: Vec<LintRule>,
+    pub compatibility: Compatibility,
+    pub priority: Priority,
+    /// Copy parent layer blobs unchanged instead of re-encoding them
+    pub preserve_parent_layers: bool,
+    /// Hash parent blobs against their digests, and layers against their
+    /// diff_ids, before using them
+    pub verify_parents: parent_verify::Verification,
+    /// Layer tar format version asked for, recorded on each new layer
+    pub format_version: Option<u32>,
+    /// Write build-report.json into the output once the images are built
+    pub build_report: bool,
 }
 
-fn parse_workers_arg() -> Option<usize> {
-    let args: Vec<String> = std::env::args().collect();
-    let mut i = 1;
-    while i < args.len() {
-        if args[i] == "-j" || args[i] == "--workers" {
-            if i + 1 < args.len() {
-                return args[i + 1].parse::<usize>().ok();
+fn main() -> ExitCode {
+    let args: Vec<OsString> = std::env::args_os().collect();
+
+    let matches = cli::parse(&args).category(ErrorCategory::Config);
+    let error_json = match &matches {
+        Ok(matches) => cli::innermost(matches).get_one::<PathBuf>("error-json").cloned(),
+        Err(_) => cli::find_error_json(&args),
+    };
+
+    match matches.and_then(|matches| run(&matches)) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {:?}", err);
+            if let Some(path) = error_json {
+                if let Err(e) = error::write_report(&path, &err) {
+                    eprintln!("Error: writing {}: {:#}", path.display(), e);
+                }
             }
-        } else if args[i].starts_with("-j") {
-            // Handle -j4 (no space)
-            return args[i][2..].parse::<usize>().ok();
+            ExitCode::from(ErrorCategory::of(&err).exit_code())
         }
-        i += 1;
     }
-    None
 }
 
-fn main() -> Result<()> {
-    let workers = parse_workers_arg().unwrap_or_else(num_cpus);
+fn run(matches: &ArgMatches) -> Result<()> {
+    logging::init(cli::innermost(matches));
+
+    // Subcommands; building from stdin is the default
+    let (args, watch) = match matches.subcommand() {
+        Some(("ls", args)) => return list::run(args),
+        Some(("du", args)) => return du::run(args),
+        Some(("inspect", args)) => return inspect::run(args),
+        Some(("unpack", args)) => return unpack::run(args),
+        Some(("verify", args)) => return verify::run(args),
+        Some(("gc", args)) => return gc::run(args),
+        Some(("test-corpus", args)) => return corpus::run(args),
+        Some(("push", args)) => return registry::run(args),
+        Some(("lint", args)) => return manifest_lint::run(args).category(ErrorCategory::Config),
+        Some(("completions", args)) => return cli::completions(args),
+        Some(("man", _)) => return cli::man(),
+        // `watch` takes the same flags and manifest as a build
+        Some(("watch", args)) => (args, true),
+        _ => (matches, false),
+    };
+
+    let workers = args.get_one::<usize>("workers").copied().unwrap_or_else(num_cpus);
 
     // Configure rayon thread pool
     rayon::ThreadPoolBuilder::new()
@@ -81,77 +245,412 @@ fn main() -> Result<()> {
     let mut input = String::new();
     std::io::stdin().read_to_string(&mut input)?;
 
-    let data: serde_json::Value = serde_yaml::from_str(&input)?;
+    // Each document (`---`-separated in YAML) is an independent build with its own layout
+    let format = match parse_format_arg(args).category(ErrorCategory::Config)? {
+        Some(format) => format,
+        None => ManifestFormat::detect(&input),
+    };
+    let mut documents = Vec::new();
+    let cwd = std::env::current_dir()?;
+    let data = config::read_documents(&input, format).category(ErrorCategory::Config)?;
+    for (i, data) in data.into_iter().enumerate() {
+        let manifest = config::parse(data, &cwd)
+            .with_context(|| format!("In document {}", i + 1))
+            .category(ErrorCategory::Config)?;
+        documents.push(manifest);
+    }
+    if documents.is_empty() {
+        documents.push(config::BuildManifest::default());
+    }
+
+    let mut outputs = HashSet::new();
+    for (i, manifest) in documents.iter().enumerate() {
+        let output = output_dir(manifest, &cwd);
+        if !outputs.insert(output.clone()) {
+            return Err(anyhow!(
+                "Document {} writes to {}, which is already used by an earlier document",
+                i + 1,
+                output.display()
+            ))
+            .category(ErrorCategory::Config);
+        }
+    }
+
+    if !args.get_flag("allow-unknown-platform") {
+        for (i, manifest) in documents.iter().enumerate() {
+            platform::validate(&manifest.images)
+                .with_context(|| format!("In document {}", i + 1))
+                .category(ErrorCategory::Config)?;
+        }
+    }
+    for (i, manifest) in documents.iter().enumerate() {
+        for (j, image) in manifest.images.iter().enumerate() {
+            for warning in platform::warnings(j, image) {
+                tracing::warn!("document {}: {}", i + 1, warning);
+            }
+        }
+    }
+
+    let overrides = parse_tuning_args(args);
+    let policy = parse_failure_policy(args);
+    if watch {
+        return run_watch(&documents, &cwd, workers, &overrides, args);
+    }
+    let iidfile = args.get_one::<String>("iidfile");
+    let dry_run = args.get_flag("dry-run");
+    let mut built = Vec::new();
+    let mut failures = Vec::new();
+    for (i, manifest) in documents.iter().enumerate() {
+        let digests = build_document(manifest, &cwd, workers, &overrides, dry_run, policy)
+            .with_context(|| format!("In document {}", i + 1))?;
+        if let Some(digests) = digests {
+            built.push(serde_json::json!({
+                "output": output_dir(manifest, &cwd),
+                "index": digests.index,
+                "manifests": digests.manifests,
+                "images": digests.images,
+            }));
+            failures.extend(digests.failures);
+        }
+    }
 
-    let compression_str = data
-        .get("compression")
-        .and_then(|v| v.as_str())
-        .unwrap_or("zstd");
+    if !dry_run {
+        cache_stats::log();
+        retry::log();
+    }
 
-    let compression = match compression_str {
-        "gzip" => Compression::Gzip,
-        "zstd" => Compression::Zstd,
-        "disabled" => Compression::Disabled,
-        other => bail!("Compression must be gzip, zstd, or disabled, got: {}", other),
+    if let (Some(path), false) = (iidfile, dry_run) {
+        std::fs::write(path, serde_json::to_string_pretty(&built)? + "\n")
+            .with_context(|| format!("Writing {}", path))?;
+    }
+
+    if !failures.is_empty() {
+        return Err(ImageFailures(failures).into());
+    }
+    Ok(())
+}
+
+/// `build-oci watch`: build every document, then rebuild images as their layers change.
+#[cfg(target_os = "linux")]
+fn run_watch(
+    documents: &[config::BuildManifest],
+    cwd: &Path,
+    workers: usize,
+    overrides: &TuningOverrides,
+    args: &ArgMatches,
+) -> Result<()> {
+    let configs = documents
+        .iter()
+        .enumerate()
+        .map(|(i, manifest)| {
+            document_config(manifest, cwd, workers, overrides, false).with_context(|| format!("In document {}", i + 1))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    watch::run(documents, configs, args)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn run_watch(
+    _documents: &[config::BuildManifest],
+    _cwd: &Path,
+    _workers: usize,
+    _overrides: &TuningOverrides,
+    _args: &ArgMatches,
+) -> Result<()> {
+    Err(anyhow!("watch needs inotify, which is only available on Linux")).category(ErrorCategory::Config)
+}
+
+/// `--format yaml|json|toml`: syntax of the manifest on stdin (default: detected).
+pub fn parse_format_arg(args: &ArgMatches) -> Result<Option<ManifestFormat>> {
+    args.get_one::<String>("format").map(|name| ManifestFormat::from_name(name)).transpose()
+}
+
+/// `--fail-fast` or `--keep-going`, which clap keeps from being combined:
+/// what a failing image means for the others.
+fn parse_failure_policy(args: &ArgMatches) -> FailurePolicy {
+    if args.get_flag("fail-fast") {
+        FailurePolicy::FailFast
+    } else if args.get_flag("keep-going") {
+        FailurePolicy::KeepGoing
+    } else {
+        FailurePolicy::Isolate
+    }
+}
+
+/// Tuning flags that take precedence over the manifest values.
+#[derive(Debug, Default)]
+struct TuningOverrides {
+    skip_xattrs: Option<bool>,
+    write_checksum_xattrs: Option<bool>,
+    prefetch_limit_mb: Option<usize>,
+    max_memory_mb: Option<usize>,
+    max_open_files: Option<usize>,
+    compression_threads: Option<usize>,
+    image_parallelism: Option<usize>,
+    nice: Option<usize>,
+    io_class: Option<String>,
+    cpus: Option<String>,
+    manifest_only: bool,
+}
+
+/// `--skip-xattrs`/`--no-skip-xattrs`,
+/// `--write-checksum-xattrs`/`--no-write-checksum-xattrs`, `--prefetch-limit-mb <MB>`, `--max-memory-mb <MB>`,
+/// `--max-open-files <N>`, `--layer-threads <N>`, `--image-parallelism <N>`,
+/// `--nice <N>`, `--io-class <CLASS>`, `--cpus <LIST>` and `--manifest-only`.
+fn parse_tuning_args(args: &ArgMatches) -> TuningOverrides {
+    let number = |id: &str| args.get_one::<usize>(id).copied();
+    let string = |id: &str| args.get_one::<String>(id).cloned();
+    // clap keeps only the later of a flag and its --no- form
+    let toggle = |id: &str, negated: &str| {
+        if args.get_flag(id) {
+            Some(true)
+        } else if args.get_flag(negated) {
+            Some(false)
+        } else {
+            None
+        }
+    };
+    TuningOverrides {
+        skip_xattrs: toggle("skip-xattrs", "no-skip-xattrs"),
+        write_checksum_xattrs: toggle("write-checksum-xattrs", "no-write-checksum-xattrs"),
+        prefetch_limit_mb: number("prefetch-limit-mb"),
+        max_memory_mb: number("max-memory-mb"),
+        max_open_files: number("max-open-files"),
+        // Also given as --compression-threads, its older name
+        compression_threads: number("layer-threads"),
+        image_parallelism: number("image-parallelism"),
+        nice: number("nice"),
+        io_class: string("io-class"),
+        cpus: string("cpus"),
+        // watch has no --manifest-only
+        manifest_only: matches!(args.try_get_one::<bool>("manifest-only"), Ok(Some(true))),
+    }
+}
+
+/// Layout directory of a document; relative `output:` paths are resolved against the working directory.
+fn output_dir(manifest: &config::BuildManifest, cwd: &Path) -> PathBuf {
+    match &manifest.output {
+        Some(dir) => cwd.join(dir),
+        None => cwd.to_path_buf(),
+    }
+}
+
+/// Build (or plan, with `--dry-run`) all images of one manifest document into its output layout.
+fn build_document(
+    manifest: &config::BuildManifest,
+    cwd: &Path,
+    workers: usize,
+    overrides: &TuningOverrides,
+    dry_run: bool,
+    policy: FailurePolicy,
+) -> Result<Option<LayoutDigests>> {
+    let global_conf = document_config(manifest, cwd, workers, overrides, dry_run)?;
+    let images = &manifest.images;
+
+    if dry_run {
+        let plan = image_builder::plan_images(&global_conf, images)?;
+        println!("{}", serde_json::to_string_pretty(&plan)?);
+        return Ok(None);
+    }
+
+    global_conf.priority.apply().category(ErrorCategory::Config)?;
+
+    let digests = image_builder::build_images(&global_conf, images, manifest.annotations.as_ref(), policy)?;
+
+    if let Some(ref gpg_sign) = manifest.gpg_sign {
+        signing::sign_layout(Path::new(&global_conf.output), gpg_sign)?;
+    }
+
+    if !manifest.publish.is_empty() {
+        if digests.failures.is_empty() {
+            publish::run(Path::new(&global_conf.output), &manifest.publish, cwd)?;
+        } else {
+            tracing::warn!("not publishing {}: some of its images failed", global_conf.output);
+        }
+    }
+
+    Ok(Some(digests))
+}
+
+/// Resolve the settings of one manifest document and the tuning flags, and
+/// create its output layout directory unless planning a `--dry-run`.
+fn document_config(
+    manifest: &config::BuildManifest,
+    cwd: &Path,
+    workers: usize,
+    overrides: &TuningOverrides,
+    dry_run: bool,
+) -> Result<GlobalConfig> {
+    let compression = Compression::parse(manifest.compression.as_deref().unwrap_or("zstd")).category(ErrorCategory::Config)?;
+    let compression_level = manifest.compression_level.or(compression.codec().default_level());
+    let compression_options = CompressionOptions {
+        gzip_rsyncable: manifest.gzip_rsyncable.unwrap_or(false),
+        ..CompressionOptions::from_spec(&manifest.compression_options.clone().unwrap_or_default())
+            .category(ErrorCategory::Config)?
+    };
+    if compression_options.window_log.is_some_and(|w| w > codec::DEFAULT_MAX_WINDOW_LOG) {
+        tracing::warn!(
+            "compression-options.window-log above {} needs zstd readers that raise their window limit \
+             (zstd -d --long=N); container runtimes may refuse such layers",
+            codec::DEFAULT_MAX_WINDOW_LOG
+        );
+    }
+
+    let output_path = output_dir(manifest, cwd);
+    if !dry_run {
+        std::fs::create_dir_all(&output_path)
+            .with_context(|| format!("Failed to create output directory {}", output_path.display()))?;
+    }
+    let output = output_path.to_string_lossy().to_string();
+
+    let skip_xattrs = overrides.skip_xattrs.or(manifest.skip_xattrs).unwrap_or(false);
+    // A dry run leaves the layer directories as it found them
+    let write_checksum_xattrs = !dry_run
+        && overrides
+            .write_checksum_xattrs
+            .or(manifest.write_checksum_xattrs)
+            .unwrap_or(false);
+
+    // Default 512MB limit for prefetch cache
+    let prefetch_limit_mb = overrides.prefetch_limit_mb.or(manifest.prefetch_limit_mb).unwrap_or(512);
+    let persist_queue_mb = manifest.persist_queue_mb.unwrap_or(16);
+    let default_retry = RetryPolicy::default();
+    let io_retry = RetryPolicy {
+        retries: manifest.io_retries.unwrap_or(default_retry.retries),
+        delay: manifest.io_retry_delay_ms.map_or(default_retry.delay, Duration::from_millis),
     };
 
-    let compression_level = data
-        .get("compression-level")
-        .and_then(|v| v.as_u64())
-        .map(|v| v as u32)
-        .or(match compression {
-            Compression::Gzip => Some(5),
-            Compression::Zstd => Some(1), // zstd level 1 for max speed
-            Compression::Disabled => None,
-        });
-
-    let output = std::env::current_dir()?
-        .to_string_lossy()
-        .to_string();
-
-    let skip_xattrs = data
-        .get("skip-xattrs")
-        .and_then(|v| v.as_bool())
-        .unwrap_or(false);
-
-    let prefetch_limit_mb = data
-        .get("prefetch-limit-mb")
-        .and_then(|v| v.as_u64())
-        .map(|v| v as usize)
-        .unwrap_or(512); // Default 512MB limit for prefetch cache
-
-    let images = data
-        .get("images")
-        .and_then(|v| v.as_array())
-        .cloned()
-        .unwrap_or_default();
-
-    let num_images = if !images.is_empty() { images.len() } else { 1 };
+    // Build-wide bounds on cached file contents and open files; unlimited by default
+    let limits = Limits::new(
+        overrides.max_memory_mb.or(manifest.max_memory_mb),
+        overrides.max_open_files.or(manifest.max_open_files),
+    );
+
+    let source_date_epoch = manifest.source_date_epoch.or_else(util::get_source_date_epoch);
+
+    let layer_listing = match manifest.layer_listing.as_deref() {
+        None => Ok(None),
+        Some("json") => Ok(Some(ListingFormat::Json)),
+        Some("mtree") => Ok(Some(ListingFormat::Mtree)),
+        Some(other) => Err(anyhow!("layer-listing must be json or mtree, got: {}", other)),
+    }
+    .category(ErrorCategory::Config)?;
+
+    let preserve_parent_layers = match manifest.parent_layers.as_deref() {
+        None | Some("recompress") => Ok(false),
+        Some("preserve") => Ok(true),
+        Some(other) => Err(anyhow!("parent-layers must be preserve or recompress, got: {}", other)),
+    }
+    .category(ErrorCategory::Config)?;
+
+    let verify_parents =
+        parent_verify::Verification::from_name(manifest.verify_parents.as_deref()).category(ErrorCategory::Config)?;
+
+    let format_version = match manifest.format_version {
+        None => Ok(None),
+        Some(version) if version == layer_builder::FORMAT_VERSION => Ok(Some(version)),
+        Some(other) => Err(anyhow!(
+            "format-version: this build-oci writes layer format {}, got: {}",
+            layer_builder::FORMAT_VERSION,
+            other
+        )),
+    }
+    .category(ErrorCategory::Config)?;
+
+    let lower_cache = match manifest.lower_cache.as_deref() {
+        None => Ok(None),
+        Some("uncompressed") => Ok(Some(LowerCacheFormat::Uncompressed)),
+        Some("zstd") => Ok(Some(LowerCacheFormat::Zstd)),
+        Some(other) => Err(anyhow!("lower-cache must be uncompressed or zstd, got: {}", other)),
+    }
+    .category(ErrorCategory::Config)?;
+
+    let remote_cache = match manifest.remote_cache {
+        Some(_) if manifest.cache_dir.is_none() => {
+            Err(anyhow!("remote-cache requires cache-dir, where its layers are kept"))
+        }
+        Some(ref spec) => RemoteCache::from_spec(spec).map(Some),
+        None => Ok(None),
+    }
+    .category(ErrorCategory::Config)?;
+
+    let compatibility = Compatibility::from_name(manifest.compatibility.as_deref().unwrap_or("oci"))
+        .category(ErrorCategory::Config)?;
+
+    let spec = manifest.priority.clone().unwrap_or_default();
+    let priority = Priority::new(
+        overrides.nice.map(|n| n.min(u32::MAX as usize) as u32).or(spec.nice),
+        overrides.io_class.as_deref().or(spec.io_class.as_deref()),
+        spec.io_level,
+        overrides.cpus.as_deref().or(spec.cpus.as_deref()),
+    )
+    .category(ErrorCategory::Config)?;
+
+    let lint = manifest
+        .lint
+        .iter()
+        .map(LintRule::from_spec)
+        .collect::<Result<Vec<_>>>()
+        .category(ErrorCategory::Config)?;
+
+    let num_images = manifest.images.len().max(1);
     
     // Avoid thread oversubscription:
     // If we build M images in parallel, and each uses N compression threads, we have M*N threads.
     // We want M*N <= workers approximately.
-    let compression_threads = if num_images > 1 {
-        std::cmp::max(1, workers / num_images)
-    } else {
-        workers
-    };
+    let image_parallelism = overrides.image_parallelism.unwrap_or(workers).min(num_images).max(1);
+    let compression_threads = overrides
+        .compression_threads
+        .unwrap_or_else(|| std::cmp::max(1, workers / image_parallelism));
 
-    let global_conf = GlobalConfig {
+    Ok(GlobalConfig {
         compression,
         compression_level,
+        compression_options,
+        compression_annotations: manifest.compression_annotations.unwrap_or(false),
+        build_id_annotation: manifest.build_id_annotation.unwrap_or(false),
+        standard_annotations: manifest.standard_annotations.unwrap_or(false).then(ci_annotations),
         output,
+        merge_index: manifest.merge_index.unwrap_or(false),
+        manifest_only: overrides.manifest_only,
         workers,
         compression_threads,
+        image_parallelism,
         skip_xattrs,
+        write_checksum_xattrs,
+        noatime: manifest.noatime.unwrap_or(false),
         prefetch_limit_mb,
-    };
-
-    let annotations = data.get("annotations");
-
-    image_builder::build_images(&global_conf, &images, annotations)?;
+        persist_queue_mb,
+        io_retry,
+        limits,
+        source_date_epoch,
+        id_map: IdMap::default(),
+        ownership: Ownership::default(),
+        layer_listing,
+        layer_index: manifest.layer_index.unwrap_or(false),
+        lower_cache,
+        cache_dir: manifest.cache_dir.clone(),
+        remote_cache,
+        lint,
+        compatibility,
+        priority,
+        preserve_parent_layers,
+        verify_parents,
+        format_version,
+        build_report: manifest.build_report.unwrap_or(false),
+    })
+}
 
-    Ok(())
+/// Standard annotation values taken from GitLab CI variables when the image
+/// does not set them.
+fn ci_annotations() -> StringMap {
+    [("revision", "CI_COMMIT_SHA"), ("source", "CI_PROJECT_URL"), ("version", "CI_COMMIT_TAG")]
+        .into_iter()
+        .filter_map(|(key, var)| {
+            let value = std::env::var(var).ok().filter(|v| !v.is_empty())?;
+            Some((key.to_string(), value))
+        })
+        .collect()
 }
 
 fn num_cpus() -> usize {
@@ -0,0 +1,188 @@
+// Copyright (c) 2019, 2020 Codethink Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use clap::ArgMatches;
+use serde_json::Value;
+
+use crate::config::{self, ManifestFormat};
+use crate::platform;
+
+/// `build-oci lint [<manifest>]`: report common mistakes in a build manifest
+/// without building anything. Fails if any problem is found.
+pub fn run(args: &ArgMatches) -> Result<()> {
+    let manifest_path = args.get_one::<String>("manifest").map(String::as_str);
+    let format = args.get_one::<String>("format").map(|name| ManifestFormat::from_name(name)).transpose()?;
+
+    let input = match manifest_path {
+        Some(path) => std::fs::read_to_string(path).with_context(|| format!("Reading {}", path))?,
+        None => {
+            let mut input = String::new();
+            std::io::stdin().read_to_string(&mut input)?;
+            input
+        }
+    };
+
+    // Includes are relative to the manifest, or to the working directory for stdin
+    let base_dir = match manifest_path.map(Path::new).and_then(Path::parent) {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => std::env::current_dir()?,
+    };
+    let format = format.unwrap_or_else(|| ManifestFormat::detect(&input));
+    let documents = match config::read_documents(&input, format) {
+        Ok(documents) => documents,
+        Err(e) => {
+            println!("{:#}", e);
+            bail!("1 problem found");
+        }
+    };
+    let mut problems = Vec::new();
+    for (i, mut data) in documents.into_iter().enumerate() {
+        let prefix = format!("document {}", i + 1);
+        if let Err(e) = config::expand(&mut data, &base_dir) {
+            problems.push(format!("{}: {:#}", prefix, e));
+            continue;
+        }
+        problems.extend(check(&data).into_iter().map(|p| format!("{}: {}", prefix, p)));
+        // Anything else the build would reject (unknown keys, wrong types)
+        match config::parse(data, &base_dir) {
+            Ok(manifest) => {
+                for (j, image) in manifest.images.iter().enumerate() {
+                    problems.extend(platform::warnings(j, image).into_iter().map(|w| format!("{}: {}", prefix, w)));
+                }
+            }
+            Err(e) => problems.push(format!("{}: {:#}", prefix, e)),
+        }
+    }
+
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    match problems.len() {
+        0 => {
+            println!("No problems found");
+            Ok(())
+        }
+        n => bail!("{} problem{} found", n, if n == 1 { "" } else { "s" }),
+    }
+}
+
+/// Checks on one expanded manifest document, each returning a message that
+/// says what to change.
+fn check(data: &Value) -> Vec<String> {
+    let mut problems = Vec::new();
+    check_annotations(&data["annotations"], "annotations", &mut problems);
+
+    let images = data["images"].as_array().map(Vec::as_slice).unwrap_or_default();
+    // (name and tag, os, architecture, variant) -> first image
+    type Platform = (Option<(String, String)>, String, String, String);
+    let mut platforms: HashMap<Platform, usize> = HashMap::new();
+    for (i, image) in images.iter().enumerate() {
+        let path = format!("images[{}]", i);
+
+        if image.get("architecture").is_none() {
+            problems.push(format!(
+                "{}: missing architecture; set e.g. `architecture: amd64` (or add it to defaults:)",
+                path
+            ));
+        }
+
+        if let Some(parent) = image["parent"]["image"].as_str() {
+            if !Path::new(parent).join("index.json").is_file() {
+                problems.push(format!(
+                    "{}.parent.image: {} is not an OCI layout (no index.json); build the parent first or fix the path",
+                    path, parent
+                ));
+            }
+        }
+
+        check_annotations(&image["annotations"], &format!("{}.annotations", path), &mut problems);
+        check_annotations(&image["index-annotations"], &format!("{}.index-annotations", path), &mut problems);
+
+        for key in ["Entrypoint", "Cmd"] {
+            if let Some(command) = image["config"][key].as_str() {
+                problems.push(format!(
+                    "{}.config.{}: is a string, which runtimes do not split; use a list, e.g. {}",
+                    path,
+                    key,
+                    serde_json::json!(command.split_whitespace().collect::<Vec<_>>())
+                ));
+            }
+        }
+
+        // Artifacts are listed without a platform
+        if image.get("artifact-type").is_some() {
+            continue;
+        }
+        // Images grouped under different names may share a platform
+        let field = |name: &str| image[name].as_str().unwrap_or_default().to_string();
+        let group = image["name"]
+            .as_str()
+            .map(|name| (name.to_string(), image["tag"].as_str().unwrap_or("latest").to_string()));
+        let platform = (group, field("os"), field("architecture"), field("variant"));
+        if let Some(first) = platforms.get(&platform) {
+            let (_, os, arch, variant) = &platform;
+            let variant = if variant.is_empty() { String::new() } else { format!("/{}", variant) };
+            problems.push(format!(
+                "{}: same platform {}/{}{} as images[{}]; runtimes pick only one, set a distinct variant or drop one",
+                path, os, arch, variant, first
+            ));
+        } else {
+            platforms.insert(platform, i);
+        }
+    }
+    problems
+}
+
+/// Annotation keys should be namespaced with a reverse domain name
+/// (`org.opencontainers.image.title`), as the OCI spec recommends.
+fn check_annotations(annotations: &Value, path: &str, problems: &mut Vec<String>) {
+    let Some(map) = annotations.as_object() else {
+        return;
+    };
+    for key in map.keys() {
+        if !is_reverse_dns(key) {
+            problems.push(format!(
+                "{}.{}: key is not reverse-DNS namespaced; use e.g. org.example.{}",
+                path,
+                key,
+                key.to_ascii_lowercase().replace(|c: char| !c.is_ascii_alphanumeric(), "-")
+            ));
+        }
+    }
+}
+
+fn is_reverse_dns(key: &str) -> bool {
+    let mut labels = key.split('.');
+    let is_label = |label: &str| {
+        !label.is_empty()
+            && label
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+    };
+    let (Some(tld), Some(domain)) = (labels.next(), labels.next()) else {
+        return false;
+    };
+    is_label(tld) && is_label(domain) && labels.all(|l| !l.is_empty() && !l.contains(char::is_whitespace))
+}
@@ -0,0 +1,132 @@
+// Copyright (c) 2019, 2020 Codethink Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! `output-format: oci-archive`: the image as a single-image OCI layout in
+//! one tarball (oci-layout, index.json and the blobs it needs). Entries are
+//! sorted and timestamped with SOURCE_DATE_EPOCH, so rebuilding the same
+//! image gives the same archive byte for byte.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use tracing::info;
+
+use crate::config::ImageSpec;
+use crate::docker_archive::append;
+use crate::layer_index::ANNOTATION_LAYER_INDEX;
+use crate::layout::{descriptor_digest, Layout, ANNOTATION_REF_NAME};
+use crate::listing::ANNOTATION_LISTING;
+use crate::GlobalConfig;
+
+/// Archive file name used without `oci-archive-path:`: `<os>-<architecture>[-<variant>].oci.tar`.
+pub fn default_name(image: &ImageSpec) -> String {
+    match image.variant {
+        Some(ref variant) => format!("{}-{}-{}.oci.tar", image.os, image.architecture, variant),
+        None => format!("{}-{}.oci.tar", image.os, image.architecture),
+    }
+}
+
+/// Where the OCI archive of `image` is written.
+pub fn path(image: &ImageSpec, output: &Path) -> PathBuf {
+    image.oci_archive_path.clone().unwrap_or_else(|| output.join(default_name(image)))
+}
+
+/// Write the OCI archive of the image whose manifest `manifest_desc` is in
+/// the output layout. Its index.json lists that manifest alone, with the
+/// ref name `<name>:<tag>` if the image has a name.
+pub fn write(global_conf: &GlobalConfig, image: &ImageSpec, manifest_desc: &Value) -> Result<PathBuf> {
+    let output = Path::new(&global_conf.output);
+    let layout = Layout::building(output);
+    let manifest_digest = descriptor_digest(manifest_desc)?;
+    let manifest = layout.read_json(manifest_digest)?;
+
+    // Sorted by digest, which orders blobs/sha256/* the same on every build
+    let mut blobs = BTreeSet::from([manifest_digest, descriptor_digest(&manifest["config"])?]);
+    for layer in manifest["layers"].as_array().into_iter().flatten() {
+        blobs.insert(descriptor_digest(layer)?);
+        // Listings and layer indexes stored next to the layer, when the
+        // layout has them
+        for key in [ANNOTATION_LISTING, ANNOTATION_LAYER_INDEX] {
+            if let Some(digest) = layer["annotations"][key].as_str() {
+                if layout.blob_path(digest)?.exists() {
+                    blobs.insert(digest);
+                }
+            }
+        }
+    }
+
+    let mut desc = manifest_desc.clone();
+    if let Some(ref name) = image.name {
+        let tag = image.tag.as_deref().unwrap_or("latest");
+        desc["annotations"][ANNOTATION_REF_NAME] = format!("{}:{}", name, tag).into();
+    }
+    let index = serde_json::to_vec(&json!({
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.oci.image.index.v1+json",
+        "manifests": [desc],
+    }))?;
+    let oci_layout = serde_json::to_vec(&json!({ "imageLayoutVersion": "1.0.0" }))?;
+
+    let path = path(image, output);
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let archive_file = tempfile::NamedTempFile::new_in(dir).with_context(|| format!("Creating {}", path.display()))?;
+    let mut archive = tar::Builder::new(BufWriter::new(archive_file.as_file()));
+    let mtime = global_conf.source_date_epoch.unwrap_or(0);
+
+    let mut algorithms = BTreeSet::new();
+    for digest in &blobs {
+        algorithms.insert(digest.split_once(':').map_or("sha256", |(algo, _)| algo));
+    }
+    append_dir(&mut archive, "blobs/", mtime)?;
+    for algo in algorithms {
+        append_dir(&mut archive, &format!("blobs/{}/", algo), mtime)?;
+        for digest in blobs.iter().filter(|digest| digest.starts_with(&format!("{}:", algo))) {
+            let blob_path = layout.blob_path(digest)?;
+            let file = fs::File::open(&blob_path).with_context(|| format!("Opening blob {}", digest))?;
+            let size = file.metadata()?.len();
+            append(&mut archive, &format!("blobs/{}", digest.replacen(':', "/", 1)), size, mtime, file)?;
+        }
+    }
+    append(&mut archive, "index.json", index.len() as u64, mtime, &index[..])?;
+    append(&mut archive, "oci-layout", oci_layout.len() as u64, mtime, &oci_layout[..])?;
+
+    archive.into_inner()?.flush()?;
+    archive_file.persist(&path).with_context(|| format!("Writing {}", path.display()))?;
+    info!(path = %path.display(), "wrote OCI archive");
+    Ok(path)
+}
+
+/// Append a directory entry owned by root.
+fn append_dir<W: Write>(archive: &mut tar::Builder<W>, path: &str, mtime: u64) -> Result<()> {
+    let mut header = tar::Header::new_ustar();
+    header.set_entry_type(tar::EntryType::Directory);
+    header.set_size(0);
+    header.set_mode(0o755);
+    header.set_mtime(mtime);
+    header.set_cksum();
+    archive.append_data(&mut header, path, std::io::empty()).with_context(|| format!("Writing {} to the archive", path))
+}
@@ -0,0 +1,345 @@
+// Copyright (c) 2019, 2020 Codethink Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Layers exported from an overlayfs upper directory. The upper directory
+//! already holds exactly the delta: deleted entries are character devices
+//! 0/0 and directories replacing a lower one carry the `overlay.opaque`
+//! xattr. These become OCI whiteouts, and only files identical to the entry
+//! they shadow in the lowerdirs are left out, so the lower trees are never
+//! walked.
+
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use rayon::prelude::*;
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::config::OverlaySpec;
+use crate::layer_builder::{file_sha256, CachedMetadata, EntryInfo, EntryKind, LayerData, XATTR_SHA256};
+use crate::GlobalConfig;
+
+/// Namespaces of the xattrs overlayfs keeps its own state in: `trusted.` for
+/// mounts by root, `user.` for mounts with `userxattr`
+const XATTR_PREFIXES: [&str; 2] = ["trusted.overlay.", "user.overlay."];
+
+fn is_overlay_xattr(name: &str) -> bool {
+    XATTR_PREFIXES.iter().any(|prefix| name.starts_with(prefix))
+}
+
+/// Value of overlayfs xattr `name` (e.g. "opaque") in either namespace.
+fn overlay_xattr(path: &Path, name: &str) -> Option<Vec<u8>> {
+    XATTR_PREFIXES
+        .iter()
+        .find_map(|prefix| xattr::get(path, format!("{}{}", prefix, name)).ok().flatten())
+}
+
+fn is_whiteout(meta: &fs::Metadata) -> bool {
+    meta.file_type().is_char_device() && meta.rdev() == 0
+}
+
+fn is_opaque(dir: &Path) -> bool {
+    overlay_xattr(dir, "opaque").as_deref() == Some(b"y")
+}
+
+/// Upper directory and lowerdirs (top first) of an `overlay:` spec, read
+/// from the mount table for a `merged:` mount point.
+pub fn resolve(spec: &OverlaySpec) -> Result<(PathBuf, Vec<PathBuf>)> {
+    let (upper, lowers) = match (&spec.merged, &spec.upper) {
+        (Some(merged), _) => mount_dirs(merged)?,
+        (None, Some(upper)) => (upper.clone(), spec.lowers.clone().unwrap_or_default()),
+        (None, None) => bail!("overlay: needs merged or upper"),
+    };
+    for dir in std::iter::once(&upper).chain(&lowers) {
+        if !dir.is_dir() {
+            bail!("overlay: {} is not a directory", dir.display());
+        }
+    }
+    Ok((upper, lowers))
+}
+
+/// `upperdir` and `lowerdir` options of the overlayfs mounted on `merged`.
+fn mount_dirs(merged: &Path) -> Result<(PathBuf, Vec<PathBuf>)> {
+    let merged = fs::canonicalize(merged).with_context(|| format!("overlay: {}", merged.display()))?;
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo").context("Reading /proc/self/mountinfo")?;
+    // Fields: id parent major:minor root mount-point options [optional...] - fstype source super-options;
+    // the last mount on a path is the visible one
+    let mount = mountinfo
+        .lines()
+        .filter_map(|line| {
+            let (before, after) = line.split_once(" - ")?;
+            let mount_point = unescape(before.split(' ').nth(4)?);
+            let mut after = after.split(' ');
+            let fstype = after.next()?;
+            let options = after.nth(1).unwrap_or_default();
+            (Path::new(&mount_point) == merged).then_some((fstype, options))
+        })
+        .next_back();
+    let Some((fstype, options)) = mount else {
+        bail!("overlay: {} is not a mount point", merged.display());
+    };
+    if fstype != "overlay" {
+        bail!("overlay: {} is a {} mount, not overlay", merged.display(), fstype);
+    }
+
+    let mut upper = None;
+    let mut lowers = Vec::new();
+    for option in options.split(',') {
+        match option.split_once('=') {
+            Some(("upperdir", dir)) => upper = Some(PathBuf::from(unescape(dir))),
+            Some(("lowerdir", dirs)) => lowers.extend(split_lowerdir(&unescape(dirs))),
+            Some(("lowerdir+", dir)) => lowers.push(PathBuf::from(unescape(dir))),
+            _ => {}
+        }
+    }
+    let upper = upper.with_context(|| format!("overlay: {} is mounted without an upperdir", merged.display()))?;
+    Ok((upper, lowers))
+}
+
+/// Undo the octal escapes (`\040`) of /proc/self/mountinfo.
+fn unescape(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes.get(i + 1..i + 4).filter(|digits| digits.iter().all(|d| (b'0'..=b'7').contains(d)));
+        match octal {
+            Some(digits) if bytes[i] == b'\\' => {
+                out.push(digits.iter().fold(0u8, |n, d| n.wrapping_mul(8) + (d - b'0')));
+                i += 4;
+            }
+            _ => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Split a `lowerdir` option on unescaped colons. Data-only lowers, after
+/// `::`, are never looked up by name and are left out.
+fn split_lowerdir(dirs: &str) -> Vec<PathBuf> {
+    let mut lowers = Vec::new();
+    let mut current = String::new();
+    let mut chars = dirs.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => current.extend(chars.next()),
+            ':' if current.is_empty() => return lowers,
+            ':' => lowers.push(PathBuf::from(std::mem::take(&mut current))),
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        lowers.push(PathBuf::from(current));
+    }
+    lowers
+}
+
+/// The whiteouts and opaque directories of a walked upper directory, and
+/// its lowerdirs for deduplication.
+pub struct OverlayUpper {
+    lowers: Vec<PathBuf>,
+    /// Name and metadata of each whiteout, by directory
+    whiteouts: FxHashMap<PathBuf, Vec<(String, CachedMetadata)>>,
+    opaque: FxHashSet<PathBuf>,
+}
+
+impl OverlayUpper {
+    /// Take the whiteout devices out of `layer_data` and the overlayfs
+    /// xattrs off its entries. Renamed directories and metadata-only copy
+    /// ups point into the lowerdirs and cannot be exported.
+    pub fn scan(layer_data: &mut LayerData, lowers: &[PathBuf]) -> Result<Self> {
+        enum Mark {
+            Whiteout,
+            Opaque,
+        }
+        let marks: Vec<(PathBuf, Mark)> = layer_data
+            .entries
+            .par_iter()
+            .map(|(path, info)| -> Result<Option<(PathBuf, Mark)>> {
+                Ok(match info.kind {
+                    EntryKind::Directory => {
+                        if overlay_xattr(path, "redirect").is_some() {
+                            bail!("{}: renamed directory; mount with redirect_dir=off to export layers", path.display());
+                        }
+                        is_opaque(path).then(|| (path.clone(), Mark::Opaque))
+                    }
+                    EntryKind::Regular { .. } if overlay_xattr(path, "metacopy").is_some() => {
+                        bail!("{}: metadata-only copy up; mount with metacopy=off to export layers", path.display());
+                    }
+                    EntryKind::Other => {
+                        let meta = fs::symlink_metadata(path)?;
+                        is_whiteout(&meta).then(|| (path.clone(), Mark::Whiteout))
+                    }
+                    _ => None,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .flatten()
+            .collect();
+
+        let mut upper = OverlayUpper {
+            lowers: lowers.to_vec(),
+            whiteouts: FxHashMap::default(),
+            opaque: FxHashSet::default(),
+        };
+        for (path, mark) in marks {
+            match mark {
+                Mark::Opaque => {
+                    upper.opaque.insert(path);
+                }
+                Mark::Whiteout => {
+                    let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else { continue };
+                    let name = name.to_string_lossy().into_owned();
+                    if let Some(children) = layer_data.children.get_mut(dir) {
+                        children.retain(|child| *child != name);
+                    }
+                    if let Some(info) = layer_data.entries.remove(&path) {
+                        upper.whiteouts.entry(dir.to_path_buf()).or_default().push((name, info.metadata));
+                    }
+                }
+            }
+        }
+        for whiteouts in upper.whiteouts.values_mut() {
+            whiteouts.sort_by(|a, b| a.0.cmp(&b.0));
+        }
+        for info in layer_data.entries.values_mut() {
+            info.xattrs.retain(|(name, _)| !is_overlay_xattr(name));
+        }
+        Ok(upper)
+    }
+
+    /// Whiteouts in directory `dir` of the upper directory, sorted by name.
+    pub fn whiteouts(&self, dir: &Path) -> &[(String, CachedMetadata)] {
+        self.whiteouts.get(dir).map_or(&[], Vec::as_slice)
+    }
+
+    pub fn is_opaque(&self, dir: &Path) -> bool {
+        self.opaque.contains(dir)
+    }
+
+    /// Whether lower layers show through at `path`, i.e. no directory
+    /// above it is opaque.
+    pub fn lowers_visible(&self, path: &Path) -> bool {
+        path.ancestors().skip(1).all(|dir| !self.opaque.contains(dir))
+    }
+
+    /// Whether the upper entry `info` at `path` is identical to the entry
+    /// it shadows in the lowerdirs: a copy up whose contents and metadata
+    /// were left as they were.
+    pub fn unchanged(&self, upper: &Path, path: &Path, info: &EntryInfo, config: &GlobalConfig) -> bool {
+        if !self.lowers_visible(path) {
+            return false;
+        }
+        let Some(lower) = path.strip_prefix(upper).ok().and_then(|rel| self.lookup(rel)) else {
+            return false;
+        };
+        let Ok(meta) = fs::symlink_metadata(&lower) else {
+            return false;
+        };
+        if meta.mode() != info.metadata.mode
+            || meta.uid() as u64 != info.metadata.uid
+            || meta.gid() as u64 != info.metadata.gid
+            || (config.source_date_epoch.is_none() && meta.mtime() != info.metadata.mtime)
+        {
+            return false;
+        }
+        match &info.kind {
+            EntryKind::Regular { checksum, .. } => {
+                let mut xattrs = info.xattrs.clone();
+                xattrs.sort();
+                meta.len() == info.metadata.size
+                    && lower_xattrs(&lower, config) == xattrs
+                    && lower_checksum(&lower, config).as_ref() == Some(checksum)
+            }
+            EntryKind::Symlink { target } => {
+                fs::read_link(&lower).is_ok_and(|lower_target| lower_target.as_os_str().as_bytes() == target.as_slice())
+            }
+            _ => false,
+        }
+    }
+
+    /// The lowerdir entry that `rel` resolves to as overlayfs looks it up:
+    /// the topmost lowerdir holding it, unless a whiteout, a non-directory
+    /// or an opaque directory above it hides the lowerdirs below.
+    fn lookup(&self, rel: &Path) -> Option<PathBuf> {
+        'lowers: for lower in &self.lowers {
+            let mut path = lower.clone();
+            let mut hides_below = false;
+            let mut components = rel.components().peekable();
+            while let Some(component) = components.next() {
+                path.push(component);
+                let Ok(meta) = fs::symlink_metadata(&path) else {
+                    if hides_below {
+                        return None;
+                    }
+                    continue 'lowers;
+                };
+                if is_whiteout(&meta) {
+                    return None;
+                }
+                if components.peek().is_none() {
+                    return Some(path);
+                }
+                if !meta.is_dir() {
+                    return None;
+                }
+                hides_below |= is_opaque(&path);
+            }
+        }
+        None
+    }
+}
+
+/// Sorted xattrs of a lowerdir file, as the walk of a layer reads them.
+fn lower_xattrs(path: &Path, config: &GlobalConfig) -> Vec<(String, String)> {
+    if config.skip_xattrs {
+        return Vec::new();
+    }
+    let mut xattrs: Vec<(String, String)> = xattr::list(path)
+        .into_iter()
+        .flatten()
+        .filter_map(|name| {
+            let name_str = name.to_string_lossy().into_owned();
+            if name_str == XATTR_SHA256 || is_overlay_xattr(&name_str) {
+                return None;
+            }
+            let value = xattr::get(path, &name).ok().flatten()?;
+            Some((name_str, String::from_utf8_lossy(&value).into_owned()))
+        })
+        .collect();
+    xattrs.sort();
+    xattrs
+}
+
+fn lower_checksum(path: &Path, config: &GlobalConfig) -> Option<String> {
+    let stored = (!config.skip_xattrs)
+        .then(|| xattr::get(path, XATTR_SHA256).ok().flatten())
+        .flatten();
+    match stored {
+        Some(value) => Some(String::from_utf8_lossy(&value).into_owned()),
+        None => file_sha256(path, config).ok(),
+    }
+}
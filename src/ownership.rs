@@ -0,0 +1,107 @@
+// Copyright (c) 2019, 2020 Codethink Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//! `ownership:` rules setting the owner and mode of layer entries by path,
+//! without touching the source tree.
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+
+use crate::config::OwnershipSpec;
+use crate::layer_builder::CachedMetadata;
+
+/// One rule: entries matching `path` (gitignore syntax, relative to the
+/// layer root) get whichever of `uid`, `gid` and `mode` it sets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct OwnershipRule {
+    path: String,
+    uid: Option<u64>,
+    gid: Option<u64>,
+    /// Permission bits, setuid/setgid/sticky included
+    mode: Option<u32>,
+}
+
+/// An image's `ownership:` rules, in order; later rules win.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Ownership {
+    rules: Vec<OwnershipRule>,
+}
+
+/// The rules compiled against one layer directory.
+pub struct OwnershipMatcher<'a> {
+    rules: Vec<(Gitignore, &'a OwnershipRule)>,
+}
+
+impl Ownership {
+    pub fn parse(specs: &[OwnershipSpec]) -> Result<Self> {
+        let rules = specs
+            .iter()
+            .enumerate()
+            .map(|(i, spec)| {
+                let mode = spec
+                    .mode
+                    .as_deref()
+                    .map(|mode| match u32::from_str_radix(mode, 8) {
+                        Ok(bits) if bits <= 0o7777 => Ok(bits),
+                        _ => anyhow::bail!("ownership[{}].mode: invalid octal mode '{}'", i, mode),
+                    })
+                    .transpose()?;
+                let rule = OwnershipRule { path: spec.path.clone(), uid: spec.uid, gid: spec.gid, mode };
+                compile(Path::new("/"), &rule.path).with_context(|| format!("ownership[{}].path", i))?;
+                Ok(rule)
+            })
+            .collect::<Result<_>>()?;
+        Ok(Ownership { rules })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Compile the rules for entries under `upper`.
+    pub fn matcher(&self, upper: &Path) -> Result<OwnershipMatcher<'_>> {
+        let rules = self.rules.iter().map(|rule| Ok((compile(upper, &rule.path)?, rule))).collect::<Result<_>>()?;
+        Ok(OwnershipMatcher { rules })
+    }
+}
+
+impl OwnershipMatcher<'_> {
+    /// Apply the rules matching `path` to its `metadata`. Symlinks keep
+    /// their mode, which nothing reads.
+    pub fn apply(&self, path: &Path, is_dir: bool, is_symlink: bool, metadata: &mut CachedMetadata) {
+        for (matcher, rule) in &self.rules {
+            if !matcher.matched(path, is_dir).is_ignore() {
+                continue;
+            }
+            metadata.uid = rule.uid.unwrap_or(metadata.uid);
+            metadata.gid = rule.gid.unwrap_or(metadata.gid);
+            if let Some(bits) = rule.mode.filter(|_| !is_symlink) {
+                metadata.mode = metadata.mode & !0o7777 | bits;
+            }
+        }
+    }
+}
+
+fn compile(root: &Path, pattern: &str) -> Result<Gitignore> {
+    let mut builder = GitignoreBuilder::new(root);
+    builder.add_line(None, pattern).with_context(|| format!("Invalid pattern '{}'", pattern))?;
+    builder.build().context("Building path patterns")
+}
@@ -0,0 +1,209 @@
+// Copyright (c) 2019, 2020 Codethink Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! `verify-parents: always`: parent blobs are hashed against their digests
+//! before they are linked, copied or read. `verify-parents: diff-ids` also
+//! decompresses every parent layer and hashes it against its diff_id, so a
+//! layer whose config lies about its contents fails the build instead of
+//! ending up in derived images. Files already verified are remembered by
+//! digest, device, inode, size and mtime, in memory and in
+//! `$XDG_CACHE_HOME/build-oci/verified`, so unchanged blobs are hashed once.
+
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufReader, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex};
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use tracing::debug;
+
+use crate::blob::IO_BUF_MEDIUM;
+use crate::cache_stats;
+use crate::codec;
+use crate::error::{ErrorCategory, ResultExt};
+use crate::progress::Bar;
+use crate::util::advise_sequential;
+use crate::GlobalConfig;
+
+/// How much of a parent is checked before it is used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verification {
+    /// Digests and diff_ids are taken on trust (`never`, the default)
+    Trusted,
+    /// Blobs are hashed against their digests (`always`)
+    Digests,
+    /// And layers are decompressed and hashed against their diff_ids (`diff-ids`)
+    DiffIds,
+}
+
+impl Verification {
+    pub fn from_name(name: Option<&str>) -> Result<Self> {
+        match name {
+            None | Some("never") => Ok(Self::Trusted),
+            Some("always") => Ok(Self::Digests),
+            Some("diff-ids") => Ok(Self::DiffIds),
+            Some(other) => Err(anyhow::anyhow!("verify-parents must be always, diff-ids or never, got: {}", other)),
+        }
+    }
+}
+
+/// Keys of the files verified so far, loaded from the cache file on first use.
+static VERIFIED: LazyLock<Mutex<HashSet<String>>> = LazyLock::new(|| {
+    let keys = cache_file()
+        .and_then(|path| fs::read_to_string(path).ok())
+        .map(|contents| contents.lines().map(str::to_string).collect())
+        .unwrap_or_default();
+    Mutex::new(keys)
+});
+
+fn cache_file() -> Option<PathBuf> {
+    crate::util::cache_dir().map(|dir| dir.join("verified"))
+}
+
+/// Identifies `path` with contents `digest`; any change of the file gives another key.
+fn key(digest: &str, meta: &fs::Metadata) -> String {
+    format!(
+        "{} {} {} {} {}.{:09}",
+        digest,
+        meta.dev(),
+        meta.ino(),
+        meta.len(),
+        meta.mtime(),
+        meta.mtime_nsec()
+    )
+}
+
+/// Check that the parent blob at `path` hashes to `digest`, when
+/// `verify-parents: always` or `diff-ids` is set.
+pub fn check(path: &Path, digest: &str, global_conf: &GlobalConfig) -> Result<()> {
+    if global_conf.verify_parents < Verification::Digests {
+        return Ok(());
+    }
+    let Some(hash) = digest.strip_prefix("sha256:") else {
+        return Err(anyhow::anyhow!("Cannot verify parent blob {}: only sha256 is supported", digest))
+            .category(ErrorCategory::DigestMismatch);
+    };
+    let file = global_conf
+        .io_retry
+        .open(path)
+        .with_context(|| format!("Opening parent blob {}", path.display()))
+        .category(ErrorCategory::MissingParent)?;
+    let meta = file.metadata()?;
+    let key = key(digest, &meta);
+    if lock()?.contains(&key) {
+        cache_stats::PARENT_VERIFY.hit(meta.len());
+        return Ok(());
+    }
+    cache_stats::PARENT_VERIFY.miss();
+
+    advise_sequential(&file);
+    let bar = Bar::bytes(&hash[..hash.len().min(12)], "verifying", meta.len());
+    let mut reader = BufReader::with_capacity(IO_BUF_MEDIUM, bar.reader(file));
+    let mut hasher = Sha256::new();
+    io::copy(&mut reader, &mut hasher).with_context(|| format!("Reading parent blob {}", path.display()))?;
+    let actual = format!("{:x}", hasher.finalize());
+    if actual != hash {
+        return Err(anyhow::anyhow!(
+            "Parent blob {} does not match its digest {}: it has sha256:{}",
+            path.display(),
+            digest,
+            actual
+        ))
+        .category(ErrorCategory::DigestMismatch);
+    }
+    debug!(digest, "verified parent blob");
+    if let Err(err) = remember(&key) {
+        debug!(error = %err, "not recording verified parent blob");
+    }
+    lock()?.insert(key);
+    Ok(())
+}
+
+/// Check that the parent layer at `path`, of `media_type`, decompresses to
+/// `diff_id`, when `verify-parents: diff-ids` is set.
+pub fn check_diff_id(path: &Path, media_type: &str, diff_id: &str, global_conf: &GlobalConfig) -> Result<()> {
+    if global_conf.verify_parents < Verification::DiffIds {
+        return Ok(());
+    }
+    let Some(hash) = diff_id.strip_prefix("sha256:") else {
+        return Err(anyhow::anyhow!("Cannot verify parent layer diff_id {}: only sha256 is supported", diff_id))
+            .category(ErrorCategory::DigestMismatch);
+    };
+    let file = global_conf
+        .io_retry
+        .open(path)
+        .with_context(|| format!("Opening parent blob {}", path.display()))
+        .category(ErrorCategory::MissingParent)?;
+    let meta = file.metadata()?;
+    // An uncompressed layer has the same key for its digest and its diff_id
+    let key = key(diff_id, &meta);
+    if lock()?.contains(&key) {
+        cache_stats::PARENT_VERIFY.hit(meta.len());
+        return Ok(());
+    }
+    cache_stats::PARENT_VERIFY.miss();
+
+    advise_sequential(&file);
+    let bar = Bar::bytes(&hash[..hash.len().min(12)], "verifying diff_id", meta.len());
+    let reader = BufReader::with_capacity(IO_BUF_MEDIUM, bar.reader(file));
+    let mut decoded = codec::for_media_type(media_type).decoder(Box::new(reader))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut decoded, &mut hasher)
+        .with_context(|| format!("Decompressing parent layer {}", path.display()))
+        .category(ErrorCategory::DigestMismatch)?;
+    let actual = format!("{:x}", hasher.finalize());
+    if actual != hash {
+        return Err(anyhow::anyhow!(
+            "Parent layer {} does not match its diff_id {}: it decompresses to sha256:{}",
+            path.display(),
+            diff_id,
+            actual
+        ))
+        .category(ErrorCategory::DigestMismatch);
+    }
+    debug!(diff_id, "verified parent layer diff_id");
+    if let Err(err) = remember(&key) {
+        debug!(error = %err, "not recording verified parent layer");
+    }
+    lock()?.insert(key);
+    Ok(())
+}
+
+fn lock() -> Result<std::sync::MutexGuard<'static, HashSet<String>>> {
+    VERIFIED
+        .lock()
+        .map_err(|e| anyhow::anyhow!("Verified blob cache lock poisoned: {}", e))
+}
+
+/// Append `key` to the cache file, so later runs skip hashing the file.
+fn remember(key: &str) -> io::Result<()> {
+    let Some(path) = cache_file() else {
+        return Ok(());
+    };
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // One short write per line, so concurrent builds do not interleave lines
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(format!("{}\n", key).as_bytes())
+}
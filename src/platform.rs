@@ -0,0 +1,256 @@
+// Copyright (c) 2019, 2020 Codethink Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::collections::BTreeMap;
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::config::ImageSpec;
+
+/// Which consumers' conventions to follow beyond the OCI image spec.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compatibility {
+    /// Platform only on index entries, as the image spec describes
+    Oci,
+    /// Also record the full platform where go-containerregistry based tools
+    /// (crane, ko) read it: in the image config and on the manifest's config
+    /// descriptor
+    Ggcr,
+}
+
+impl Compatibility {
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "oci" => Ok(Self::Oci),
+            "ggcr" => Ok(Self::Ggcr),
+            other => bail!("compatibility must be oci or ggcr, got: {}", other),
+        }
+    }
+}
+
+/// A descriptor's platform object. Fields this version does not know are
+/// kept in `other`, so platforms read from parents and existing indexes
+/// are written back whole.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Platform {
+    pub architecture: String,
+    pub os: String,
+    #[serde(rename = "os.version", skip_serializing_if = "Option::is_none")]
+    pub os_version: Option<String>,
+    #[serde(rename = "os.features", skip_serializing_if = "Option::is_none")]
+    pub os_features: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+    /// Reserved by the image spec; only Docker manifest lists used it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub features: Option<Vec<String>>,
+    #[serde(flatten)]
+    pub other: BTreeMap<String, Value>,
+}
+
+/// Fields `Platform` has a member for, which `platform-fields:` may not set.
+const KNOWN_FIELDS: &[&str] = &["architecture", "os", "os.version", "os.features", "variant", "features"];
+
+impl Platform {
+    /// The platform `image` declares.
+    pub fn of(image: &ImageSpec) -> Self {
+        Platform {
+            architecture: image.architecture.clone(),
+            os: image.os.clone(),
+            os_version: image.os_version.clone(),
+            os_features: image.os_features.clone(),
+            variant: image.variant.clone(),
+            features: image.features.clone(),
+            other: image.platform_fields.clone().unwrap_or_default(),
+        }
+    }
+
+    /// The platform of a descriptor, if it has a valid one.
+    pub fn from_descriptor(desc: &Value) -> Option<Self> {
+        serde_json::from_value(desc["platform"].clone()).ok()
+    }
+
+    /// Whether this platform can stand in for `wanted`: the same os and
+    /// architecture, and the variant and os.version `wanted` sets, if any.
+    pub fn satisfies(&self, wanted: &Platform) -> bool {
+        self.os == wanted.os
+            && self.architecture == wanted.architecture
+            && (wanted.variant.is_none() || self.variant == wanted.variant)
+            && (wanted.os_version.is_none() || self.os_version == wanted.os_version)
+    }
+
+    /// Only the fields the image spec defines for image configs.
+    pub fn config_fields(&self) -> Self {
+        Platform {
+            features: None,
+            other: BTreeMap::new(),
+            ..self.clone()
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+}
+
+/// The image's platform object, as used in descriptors.
+pub fn descriptor(image: &ImageSpec) -> Value {
+    Platform::of(image).to_json()
+}
+
+/// Check the platform fields of `images[i]` beyond os and architecture:
+/// Windows versions and features, and `platform-fields:` keys.
+pub fn check_fields(i: usize, image: &ImageSpec) -> Result<()> {
+    if let Some(ref version) = image.os_version {
+        if version.is_empty() {
+            bail!("images[{}].os.version: must not be empty", i);
+        }
+        // major.minor.build[.revision], as `ver` and Windows base images report it
+        let parts: Vec<&str> = version.split('.').collect();
+        let numeric = parts.iter().all(|p| !p.is_empty() && p.bytes().all(|b| b.is_ascii_digit()));
+        if image.os == "windows" && (!(3..=4).contains(&parts.len()) || !numeric) {
+            bail!(
+                "images[{}].os.version: expected a Windows version such as 10.0.20348.2113, got '{}'",
+                i,
+                version
+            );
+        }
+    }
+    for (key, values) in [("os.features", &image.os_features), ("features", &image.features)] {
+        let values = values.as_deref().unwrap_or_default();
+        if let Some(value) = values.iter().find(|v| v.is_empty()) {
+            bail!("images[{}].{}: entries must not be empty, got '{}'", i, key, value);
+        }
+        if let Some((j, value)) = values.iter().enumerate().find(|(j, v)| values[..*j].contains(v)) {
+            bail!("images[{}].{}[{}]: '{}' is listed twice", i, key, j, value);
+        }
+    }
+    for key in image.platform_fields.iter().flat_map(BTreeMap::keys) {
+        if KNOWN_FIELDS.contains(&key.as_str()) {
+            bail!("images[{}].platform-fields.{}: set images[{}].{} instead", i, key, i, key);
+        }
+    }
+    Ok(())
+}
+
+/// Deprecated platform fields of `images[i]`, as messages saying what to
+/// use instead.
+pub fn warnings(i: usize, image: &ImageSpec) -> Vec<String> {
+    let mut warnings = Vec::new();
+    if image.features.is_some() {
+        warnings.push(format!(
+            "images[{}].features: reserved by the OCI image spec and ignored by runtimes; use os.features or variant",
+            i
+        ));
+    }
+    warnings
+}
+
+/// Known os/architecture combinations, as listed by `go tool dist list`.
+const PLATFORMS: &[(&str, &[&str])] = &[
+    ("aix", &["ppc64"]),
+    ("android", &["386", "amd64", "arm", "arm64"]),
+    ("darwin", &["amd64", "arm64"]),
+    ("dragonfly", &["amd64"]),
+    ("freebsd", &["386", "amd64", "arm", "arm64", "riscv64"]),
+    ("illumos", &["amd64"]),
+    ("ios", &["amd64", "arm64"]),
+    ("js", &["wasm"]),
+    (
+        "linux",
+        &[
+            "386", "amd64", "arm", "arm64", "loong64", "mips", "mips64", "mips64le", "mipsle",
+            "ppc64", "ppc64le", "riscv64", "s390x",
+        ],
+    ),
+    ("netbsd", &["386", "amd64", "arm", "arm64"]),
+    ("openbsd", &["386", "amd64", "arm", "arm64", "ppc64", "riscv64"]),
+    ("plan9", &["386", "amd64", "arm"]),
+    ("solaris", &["amd64"]),
+    ("wasip1", &["wasm"]),
+    ("windows", &["386", "amd64", "arm", "arm64"]),
+];
+
+/// Known variants per architecture; architectures not listed take no variant.
+const VARIANTS: &[(&str, &[&str])] = &[
+    ("amd64", &["v1", "v2", "v3", "v4"]),
+    ("arm", &["v5", "v6", "v7", "v8"]),
+    (
+        "arm64",
+        &[
+            "v8", "v8.1", "v8.2", "v8.3", "v8.4", "v8.5", "v8.6", "v8.7", "v8.8", "v8.9", "v9",
+            "v9.1", "v9.2", "v9.3", "v9.4", "v9.5",
+        ],
+    ),
+];
+
+/// Check every image's os, architecture and variant against the known
+/// platform table, reporting all problems together.
+pub fn validate(images: &[ImageSpec]) -> Result<()> {
+    let mut errors = Vec::new();
+    for (i, image) in images.iter().enumerate() {
+        if let Err(e) = check(&image.os, &image.architecture, image.variant.as_deref()) {
+            errors.push(format!("images[{}]: {}", i, e));
+        }
+    }
+    if !errors.is_empty() {
+        bail!(
+            "Unknown platform (use --allow-unknown-platform to build anyway):\n  {}",
+            errors.join("\n  ")
+        );
+    }
+    Ok(())
+}
+
+fn check(os: &str, arch: &str, variant: Option<&str>) -> Result<()> {
+    let Some((_, arches)) = PLATFORMS.iter().find(|(name, _)| *name == os) else {
+        let known: Vec<&str> = PLATFORMS.iter().map(|(name, _)| *name).collect();
+        bail!("unknown os '{}' (expected one of: {})", os, known.join(", "));
+    };
+    if !arches.contains(&arch) {
+        bail!(
+            "unknown architecture '{}' for os '{}' (expected one of: {})",
+            arch,
+            os,
+            arches.join(", ")
+        );
+    }
+    if let Some(variant) = variant {
+        let variants = VARIANTS
+            .iter()
+            .find(|(name, _)| *name == arch)
+            .map(|(_, variants)| *variants)
+            .unwrap_or_default();
+        if !variants.contains(&variant) {
+            if variants.is_empty() {
+                bail!("architecture '{}' has no variants, got '{}'", arch, variant);
+            }
+            bail!(
+                "unknown variant '{}' for architecture '{}' (expected one of: {})",
+                variant,
+                arch,
+                variants.join(", ")
+            );
+        }
+    }
+    Ok(())
+}
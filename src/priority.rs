@@ -0,0 +1,235 @@
+// Copyright (c) 2019, 2020 Codethink Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Scheduling priority for builds on shared machines: niceness and I/O
+//! priority for the whole process, and CPU pinning of layer compression.
+
+use anyhow::{bail, Context, Result};
+
+/// I/O scheduling class, as set by `ionice`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoClass {
+    /// Best-effort with a level from 0 (highest) to 7 (lowest)
+    BestEffort(u8),
+    /// Only gets disk time when no other process needs it
+    Idle,
+}
+
+/// Resolved `priority:` settings. Everything unset leaves the process as it was started.
+#[derive(Debug, Clone, Default)]
+pub struct Priority {
+    /// Nice level 0-19 for every build thread
+    pub nice: Option<u32>,
+    pub io_class: Option<IoClass>,
+    /// CPUs that layer compression threads are pinned to
+    pub cpus: Option<Vec<usize>>,
+}
+
+impl Priority {
+    pub fn new(nice: Option<u32>, io_class: Option<&str>, io_level: Option<u32>, cpus: Option<&str>) -> Result<Self> {
+        if let Some(nice) = nice.filter(|&n| n > 19) {
+            bail!("priority.nice must be between 0 and 19, got: {}", nice);
+        }
+        let level = match io_level {
+            Some(level) if level > 7 => bail!("priority.io-level must be between 0 and 7, got: {}", level),
+            Some(level) => level as u8,
+            None => 4,
+        };
+        let io_class = match io_class {
+            None if io_level.is_some() => Some(IoClass::BestEffort(level)),
+            None => None,
+            Some("best-effort") => Some(IoClass::BestEffort(level)),
+            Some("idle") if io_level.is_some() => bail!("priority.io-level only applies to the best-effort class"),
+            Some("idle") => Some(IoClass::Idle),
+            Some(other) => bail!("priority.io-class must be best-effort or idle, got: {}", other),
+        };
+        let cpus = cpus.map(parse_cpu_list).transpose()?;
+        let priority = Priority { nice, io_class, cpus };
+        if !cfg!(target_os = "linux") && (priority.nice.is_some() || priority.io_class.is_some() || priority.cpus.is_some()) {
+            bail!("priority: is only supported on Linux");
+        }
+        Ok(priority)
+    }
+
+    /// Apply the nice level and I/O class to every thread of the process.
+    /// Threads started afterwards inherit them from the thread starting them.
+    /// The CPU list is checked here so a bad one fails before any build work.
+    pub fn apply(&self) -> Result<()> {
+        drop(self.pin_thread()?);
+        if self.nice.is_none() && self.io_class.is_none() {
+            return Ok(());
+        }
+        for tid in thread_ids()? {
+            if let Some(nice) = self.nice {
+                sys::set_nice(tid, nice).context("Setting priority.nice")?;
+            }
+            if let Some(class) = self.io_class {
+                sys::set_io_class(tid, class).context("Setting priority.io-class")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Pin the calling thread to `cpus` until the guard is dropped, so the
+    /// compression threads it starts (which inherit the affinity) stay on them.
+    pub fn pin_thread(&self) -> Result<Option<Pinned>> {
+        match self.cpus {
+            Some(ref cpus) => sys::pin(cpus).context("Setting priority.cpus").map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Parse a CPU list like `0-3,8,10-11`.
+fn parse_cpu_list(list: &str) -> Result<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for part in list.split(',').map(str::trim) {
+        let parse = |n: &str| {
+            n.trim()
+                .parse::<usize>()
+                .with_context(|| format!("priority.cpus: invalid CPU list '{}'", list))
+        };
+        match part.split_once('-') {
+            Some((first, last)) => {
+                let (first, last) = (parse(first)?, parse(last)?);
+                if first > last {
+                    bail!("priority.cpus: invalid range '{}'", part);
+                }
+                cpus.extend(first..=last);
+            }
+            None => cpus.push(parse(part)?),
+        }
+    }
+    cpus.sort_unstable();
+    cpus.dedup();
+    Ok(cpus)
+}
+
+/// Ids of all threads of this process.
+fn thread_ids() -> Result<Vec<i32>> {
+    let mut ids = Vec::new();
+    for entry in std::fs::read_dir("/proc/self/task").context("Listing threads")? {
+        if let Some(tid) = entry?.file_name().to_str().and_then(|name| name.parse().ok()) {
+            ids.push(tid);
+        }
+    }
+    Ok(ids)
+}
+
+/// Restores the thread's previous CPU affinity when dropped.
+pub struct Pinned {
+    #[cfg(target_os = "linux")]
+    previous: libc::cpu_set_t,
+}
+
+impl Drop for Pinned {
+    fn drop(&mut self) {
+        #[cfg(target_os = "linux")]
+        // SAFETY: `previous` is a fully initialised CPU set
+        unsafe {
+            libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &self.previous);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::io;
+
+    use anyhow::{bail, Result};
+
+    use super::{IoClass, Pinned};
+
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+    const IOPRIO_CLASS_BE: libc::c_int = 2;
+    const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+
+    /// Linux nice levels and I/O priorities are per thread, so `tid` names one thread.
+    pub fn set_nice(tid: i32, nice: u32) -> Result<()> {
+        // SAFETY: plain syscall on integer arguments
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS, tid as libc::id_t, nice as libc::c_int) } != 0 {
+            return thread_error();
+        }
+        Ok(())
+    }
+
+    pub fn set_io_class(tid: i32, class: IoClass) -> Result<()> {
+        let prio = match class {
+            IoClass::BestEffort(level) => (IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT) | level as libc::c_int,
+            IoClass::Idle => IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+        };
+        // SAFETY: plain syscall on integer arguments
+        if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, tid, prio) } != 0 {
+            return thread_error();
+        }
+        Ok(())
+    }
+
+    /// The error of a failed per-thread call; a thread that exited meanwhile is not one.
+    fn thread_error() -> Result<()> {
+        match io::Error::last_os_error() {
+            e if e.raw_os_error() == Some(libc::ESRCH) => Ok(()),
+            e => Err(e.into()),
+        }
+    }
+
+    pub fn pin(cpus: &[usize]) -> Result<Pinned> {
+        let size = std::mem::size_of::<libc::cpu_set_t>();
+        // SAFETY: cpu_set_t is plain data, and every pointer passed is to a
+        // live set of `size` bytes
+        unsafe {
+            let mut previous: libc::cpu_set_t = std::mem::zeroed();
+            if libc::sched_getaffinity(0, size, &mut previous) != 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+            let mut set: libc::cpu_set_t = std::mem::zeroed();
+            for &cpu in cpus {
+                if cpu >= libc::CPU_SETSIZE as usize {
+                    bail!("CPU {} is out of range", cpu);
+                }
+                libc::CPU_SET(cpu, &mut set);
+            }
+            if libc::sched_setaffinity(0, size, &set) != 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+            Ok(Pinned { previous })
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use anyhow::{bail, Result};
+
+    use super::{IoClass, Pinned};
+
+    pub fn set_nice(_tid: i32, _nice: u32) -> Result<()> {
+        bail!("not supported on this platform")
+    }
+
+    pub fn set_io_class(_tid: i32, _class: IoClass) -> Result<()> {
+        bail!("not supported on this platform")
+    }
+
+    pub fn pin(_cpus: &[usize]) -> Result<Pinned> {
+        bail!("not supported on this platform")
+    }
+}
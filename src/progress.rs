@@ -0,0 +1,156 @@
+// Copyright (c) 2019, 2020 Codethink Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Interactive progress bars on stderr.
+//!
+//! Bars are only drawn when stderr is a terminal and text logging is in use;
+//! otherwise every [`Bar`] is a no-op and plain logs are the only output.
+
+use std::io::{self, Read, Write};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+
+static MULTI: OnceLock<MultiProgress> = OnceLock::new();
+
+/// Enable progress bars for the rest of the process.
+pub fn init(enabled: bool) {
+    if enabled {
+        let _ = MULTI.set(MultiProgress::new());
+    }
+}
+
+/// A progress bar, or nothing when progress output is disabled. The bar is
+/// cleared when dropped, so bars of failed steps do not linger.
+pub struct Bar(Option<ProgressBar>);
+
+impl Bar {
+    fn add(template: &str, len: Option<u64>, prefix: &str) -> Bar {
+        let Some(multi) = MULTI.get() else {
+            return Bar(None);
+        };
+        let style = ProgressStyle::with_template(template)
+            .unwrap_or_else(|_| ProgressStyle::default_bar())
+            .progress_chars("=> ");
+        let bar = match len {
+            Some(len) => ProgressBar::new(len),
+            None => ProgressBar::new_spinner(),
+        };
+        let bar = multi.add(bar.with_style(style).with_prefix(prefix.to_string()));
+        if len.is_none() {
+            bar.enable_steady_tick(Duration::from_millis(120));
+        }
+        Bar(Some(bar))
+    }
+
+    /// Spinner showing the current step of an image build.
+    pub fn image(prefix: &str) -> Bar {
+        Bar::add("{spinner:.green} {prefix:.bold} {wide_msg}", None, prefix)
+    }
+
+    /// Bar counting files, with the total set later by [`Bar::set_length`].
+    pub fn files(prefix: &str, msg: &'static str) -> Bar {
+        let bar = Bar::add("  {prefix} {msg} [{bar:30}] {pos}/{len} files", Some(0), prefix);
+        bar.set_message(msg);
+        bar
+    }
+
+    /// Bar counting `total` bytes.
+    pub fn bytes(prefix: &str, msg: &'static str, total: u64) -> Bar {
+        let bar = Bar::add(
+            "  {prefix} {msg} [{bar:30}] {bytes}/{total_bytes} ({bytes_per_sec})",
+            Some(total),
+            prefix,
+        );
+        bar.set_message(msg);
+        bar
+    }
+
+    pub fn set_length(&self, len: u64) {
+        if let Some(bar) = &self.0 {
+            bar.set_length(len);
+        }
+    }
+
+    pub fn inc(&self, delta: u64) {
+        if let Some(bar) = &self.0 {
+            bar.inc(delta);
+        }
+    }
+
+    pub fn set_message(&self, msg: &'static str) {
+        if let Some(bar) = &self.0 {
+            bar.set_message(msg);
+        }
+    }
+
+    /// Wrap `inner` so bytes read through it advance this bar.
+    pub fn reader<R: Read>(&self, inner: R) -> BarReader<R> {
+        BarReader { inner, bar: self.0.clone() }
+    }
+}
+
+impl Drop for Bar {
+    fn drop(&mut self) {
+        if let Some(bar) = &self.0 {
+            bar.finish_and_clear();
+        }
+    }
+}
+
+pub struct BarReader<R> {
+    inner: R,
+    bar: Option<ProgressBar>,
+}
+
+impl<R: Read> Read for BarReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(bar) = &self.bar {
+            bar.inc(n as u64);
+        }
+        Ok(n)
+    }
+}
+
+/// Stderr writer for the log subscriber that hides the bars while a log line
+/// is written, so the two do not interleave.
+pub struct LogWriter;
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match MULTI.get() {
+            Some(multi) => multi.suspend(|| io::stderr().write(buf)),
+            None => io::stderr().write(buf),
+        }
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        match MULTI.get() {
+            Some(multi) => multi.suspend(|| io::stderr().write_all(buf)),
+            None => io::stderr().write_all(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
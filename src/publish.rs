@@ -0,0 +1,142 @@
+// Copyright (c) 2019, 2020 Codethink Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! `publish:`: once a document is built, copy its layout to other layout
+//! directories and push it to registries, all destinations at once. Each
+//! one reports its own result; a failed destination does not stop the others.
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use rayon::prelude::*;
+use tracing::{info, warn};
+
+use crate::config::PublishSpec;
+use crate::gc;
+use crate::image_builder::merge_index_entries;
+use crate::layout::Layout;
+use crate::registry;
+
+/// Publish layout `output` to every destination, relative layouts resolved
+/// against `cwd`. Fails after all destinations were tried if any failed.
+pub fn run(output: &Path, destinations: &[PublishSpec], cwd: &Path) -> Result<()> {
+    let results: Vec<(String, Result<String>)> = destinations
+        .par_iter()
+        .map(|destination| match (&destination.layout, &destination.registry) {
+            (Some(layout), _) => {
+                let dest = cwd.join(layout);
+                (dest.display().to_string(), copy_layout(output, &dest))
+            }
+            (None, Some(reference)) => {
+                let result = push_options(destination)
+                    .and_then(|options| registry::push(output, None, reference, &options));
+                (reference.clone(), result)
+            }
+            (None, None) => (String::new(), Err(anyhow::anyhow!("publish: no layout or registry"))),
+        })
+        .collect();
+
+    let mut failed = Vec::new();
+    for (destination, result) in results {
+        match result {
+            Ok(summary) => {
+                info!(destination, "published");
+                println!("{}: published {}", destination, summary);
+            }
+            Err(err) => {
+                warn!(destination, "publishing failed: {:#}", err);
+                println!("{}: failed", destination);
+                failed.push(destination);
+            }
+        }
+    }
+    if !failed.is_empty() {
+        bail!(
+            "Publishing failed for {} of {} destination(s): {}",
+            failed.len(),
+            destinations.len(),
+            failed.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// What a `registry:` destination asks of its push.
+fn push_options(destination: &PublishSpec) -> Result<registry::PushOptions> {
+    Ok(registry::PushOptions {
+        plain_http: destination.plain_http,
+        chunk_size: destination.chunk_size_mb.map(|mb| mb * 1024 * 1024),
+        limit_rate: destination.limit_rate.as_deref().map(registry::parse_rate).transpose()?,
+        max_connections: destination.max_connections,
+    })
+}
+
+/// Copy the blobs of layout `source` that `dest` lacks, hard linked where
+/// possible, then merge its index.json entries into those of `dest` as
+/// `merge-index: true` would.
+fn copy_layout(source: &Path, dest: &Path) -> Result<String> {
+    fs::create_dir_all(dest).with_context(|| format!("Creating {}", dest.display()))?;
+    let layout = Layout::open(source)?;
+    let mut index = layout.index()?;
+    let digests: Vec<String> = gc::reachable(&layout, &index)?.into_iter().collect();
+    let copied = digests
+        .par_iter()
+        .map(|digest| copy_blob(&layout.blob_path(digest)?, &Layout::building(dest).blob_path(digest)?))
+        .collect::<Result<Vec<bool>>>()?
+        .into_iter()
+        .filter(|&copied| copied)
+        .count();
+
+    let dest_index = dest.join("index.json");
+    if dest_index.is_file() {
+        let existing = match Layout::building(dest).index()?["manifests"].take() {
+            serde_json::Value::Array(existing) => existing,
+            _ => Vec::new(),
+        };
+        let manifests = index["manifests"].as_array().cloned().unwrap_or_default();
+        index["manifests"] = merge_index_entries(existing, &manifests).into();
+    }
+    fs::write(dest.join("oci-layout"), br#"{"imageLayoutVersion":"1.0.0"}"#)?;
+    // Replaced in one rename, so readers never see a partial index
+    let mut index_file = tempfile::NamedTempFile::new_in(dest)?;
+    index_file.write_all(&serde_json::to_vec(&index)?)?;
+    index_file.persist(&dest_index).with_context(|| format!("Writing {}", dest_index.display()))?;
+    Ok(format!("({} blob(s) copied, {} already present)", copied, digests.len() - copied))
+}
+
+/// Put blob `from` at `to` unless it is there already; whether it was copied.
+fn copy_blob(from: &Path, to: &Path) -> Result<bool> {
+    if to.is_file() {
+        return Ok(false);
+    }
+    let dir = to.parent().context("Blob path without a directory")?;
+    fs::create_dir_all(dir).with_context(|| format!("Creating {}", dir.display()))?;
+    match fs::hard_link(from, to) {
+        Ok(()) => return Ok(true),
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => return Ok(false),
+        Err(_) => {}
+    }
+    let mut tmp = tempfile::NamedTempFile::new_in(dir)?;
+    io::copy(&mut fs::File::open(from).with_context(|| format!("Opening {}", from.display()))?, &mut tmp)?;
+    tmp.persist(to).with_context(|| format!("Writing {}", to.display()))?;
+    Ok(true)
+}
@@ -0,0 +1,636 @@
+// Copyright (c) 2019, 2020 Codethink Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! `build-oci push`: upload an image of a layout to a registry as the OCI
+//! distribution spec describes: blobs the registry lacks (checked with HEAD),
+//! then manifests bottom up, the top one under its tag. Every push to a
+//! registry host shares its `--limit-rate` and `--max-connections` limits.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use clap::ArgMatches;
+use rayon::prelude::*;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tracing::{debug, info};
+
+use crate::cli;
+use crate::codec::NONDISTRIBUTABLE_LAYER_MEDIA_TYPE;
+use crate::error::{ErrorCategory, ResultExt};
+use crate::keys;
+use crate::layout::{self, descriptor_digest, Layout, ANNOTATION_REF_NAME, MEDIA_TYPE_INDEX};
+use crate::progress::Bar;
+
+const MEDIA_TYPE_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+
+/// Registry that `docker.io/...` references and bare names push to
+const DOCKER_HUB: &str = "registry-1.docker.io";
+/// Key of Docker Hub credentials in docker config files
+const DOCKER_HUB_AUTH_KEY: &str = "https://index.docker.io/v1/";
+
+/// How a push talks to the registry, besides what it pushes where.
+#[derive(Debug, Clone, Default)]
+pub struct PushOptions {
+    pub plain_http: bool,
+    /// Blobs larger than this are uploaded in chunks of this size
+    pub chunk_size: Option<u64>,
+    /// Bytes per second sent to the registry host
+    pub limit_rate: Option<u64>,
+    /// Requests in flight to the registry host at once
+    pub max_connections: Option<usize>,
+}
+
+/// `build-oci push <layout>[:<ref>] <destination>`: `<ref>` is a ref.name
+/// annotation, digest or position in index.json; without one, a layout with
+/// several entries is pushed as an image index of all of them.
+pub fn run(args: &ArgMatches) -> Result<()> {
+    let source = cli::required(args, "image");
+    let destination = cli::required(args, "destination");
+    let options = PushOptions {
+        plain_http: args.get_flag("plain-http"),
+        chunk_size: args.get_one::<u64>("chunk-size-mb").map(|mb| mb * 1024 * 1024),
+        limit_rate: match args.get_one::<String>("limit-rate") {
+            Some(rate) => Some(parse_rate(rate).context("--limit-rate").category(ErrorCategory::Config)?),
+            None => None,
+        },
+        max_connections: args.get_one::<usize>("max-connections").copied(),
+    };
+    let (layout_path, reference) = layout::parse_image_ref(source);
+    println!("{}", push(&layout_path, reference.as_deref(), destination, &options)?);
+    Ok(())
+}
+
+/// Bytes per second from `value`: a number with an optional K, M or G
+/// suffix (powers of 1024), as curl's `--limit-rate` takes.
+pub fn parse_rate(value: &str) -> Result<u64> {
+    let (number, unit) = match value.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&value[..i], c.to_ascii_uppercase()),
+        _ => (value, 'B'),
+    };
+    let shift = match unit {
+        'B' => 0,
+        'K' => 10,
+        'M' => 20,
+        'G' => 30,
+        _ => bail!("Invalid rate '{}': the suffix must be K, M or G", value),
+    };
+    match number.parse::<u64>() {
+        Ok(n) if n > 0 => n.checked_shl(shift).filter(|r| r >> shift == n).with_context(|| format!("Rate '{}' is too large", value)),
+        _ => bail!("Invalid rate '{}': expected a positive number of bytes per second, e.g. 500K or 2M", value),
+    }
+}
+
+/// Push the image of `layout_path` that `reference` selects (all of them
+/// without one) to `destination`, returning a one-line summary.
+pub fn push(layout_path: &Path, reference: Option<&str>, destination: &str, options: &PushOptions) -> Result<String> {
+    let destination = Destination::parse(destination).category(ErrorCategory::Config)?;
+    let layout = Layout::open(layout_path).category(ErrorCategory::MissingParent)?;
+    let (media_type, bytes) = select(&layout, reference).category(ErrorCategory::MissingParent)?;
+
+    let mut client = Client::new(&destination, options)?;
+    client.authenticate()?;
+    let (uploaded, present) = client.push_image(&layout, &media_type, &bytes, &destination.tag)?;
+    Ok(format!(
+        "{}/{}:{}@sha256:{:x} ({} blob(s) uploaded, {} already present)",
+        destination.registry,
+        destination.repository,
+        destination.tag,
+        Sha256::digest(&bytes),
+        uploaded,
+        present
+    ))
+}
+
+/// Media type and contents of the manifest or index to push.
+fn select(layout: &Layout, reference: Option<&str>) -> Result<(String, Vec<u8>)> {
+    let manifests = layout.manifests()?;
+    let desc = match reference {
+        Some(reference) => manifests
+            .iter()
+            .find(|desc| {
+                desc["annotations"][ANNOTATION_REF_NAME].as_str() == Some(reference)
+                    || desc["digest"].as_str() == Some(reference)
+            })
+            .or_else(|| reference.parse::<usize>().ok().and_then(|i| manifests.get(i)))
+            .with_context(|| format!("No image matching '{}' in the layout", reference))?,
+        None if manifests.len() == 1 => &manifests[0],
+        None => {
+            // index.json itself is an image index of every entry
+            let mut index = layout.index()?;
+            index["mediaType"] = MEDIA_TYPE_INDEX.into();
+            return Ok((MEDIA_TYPE_INDEX.to_string(), serde_json::to_vec(&index)?));
+        }
+    };
+    let bytes = fs::read(layout.blob_path(descriptor_digest(desc)?)?)?;
+    Ok((manifest_media_type(desc, &bytes), bytes))
+}
+
+/// Media type of a manifest: its descriptor's, else its own field.
+fn manifest_media_type(desc: &Value, bytes: &[u8]) -> String {
+    desc["mediaType"]
+        .as_str()
+        .map(str::to_string)
+        .or_else(|| {
+            let manifest: Value = serde_json::from_slice(bytes).ok()?;
+            manifest["mediaType"].as_str().map(str::to_string)
+        })
+        .unwrap_or_else(|| MEDIA_TYPE_MANIFEST.to_string())
+}
+
+/// `<registry>/<repository>[:<tag>]`, with Docker Hub for references whose
+/// first component is not a host name.
+#[derive(Debug)]
+struct Destination {
+    registry: String,
+    repository: String,
+    tag: String,
+}
+
+impl Destination {
+    fn parse(reference: &str) -> Result<Self> {
+        let (first, rest) = reference.split_once('/').unwrap_or(("", reference));
+        let is_host = first.contains('.') || first.contains(':') || first == "localhost";
+        let (registry, path) = match (first, is_host) {
+            ("docker.io" | "index.docker.io", _) => (DOCKER_HUB.to_string(), rest.to_string()),
+            (_, true) => (first.to_string(), rest.to_string()),
+            _ => (DOCKER_HUB.to_string(), reference.to_string()),
+        };
+        // A tag follows the last colon after the last slash
+        let name_start = path.rfind('/').map_or(0, |i| i + 1);
+        let (repository, tag) = match path[name_start..].rfind(':') {
+            Some(i) => (&path[..name_start + i], &path[name_start + i + 1..]),
+            None => (&path[..], "latest"),
+        };
+        if repository.is_empty() || tag.is_empty() || repository.contains('@') {
+            bail!("Invalid destination '{}': expected <registry>/<repository>[:<tag>]", reference);
+        }
+        let repository = if registry == DOCKER_HUB && !repository.contains('/') {
+            format!("library/{}", repository)
+        } else {
+            repository.to_string()
+        };
+        Ok(Destination { registry, repository, tag: tag.to_string() })
+    }
+}
+
+/// Distribution API client for one repository.
+struct Client {
+    agent: ureq::Agent,
+    /// `http(s)://<registry>`
+    origin: String,
+    /// `<origin>/v2/<repository>`
+    repository_url: String,
+    repository: String,
+    registry: String,
+    /// Blobs larger than this are uploaded in chunks of this size
+    chunk_size: Option<u64>,
+    /// `Authorization` header value once authenticated
+    authorization: Option<String>,
+    limits: Arc<HostLimits>,
+}
+
+/// Limits shared by every client of one registry host. When pushes to a
+/// host ask for different ones, the strictest apply to all of them.
+#[derive(Debug)]
+struct HostLimits {
+    /// Requests in flight, and the most allowed at once
+    connections: Mutex<(usize, usize)>,
+    freed: Condvar,
+    /// Bytes per second, and when the bytes sent so far are paced out
+    rate: Mutex<(Option<u64>, Instant)>,
+}
+
+impl HostLimits {
+    /// The limits of `registry`, tightened to `options`.
+    fn of(registry: &str, options: &PushOptions) -> Arc<HostLimits> {
+        static HOSTS: OnceLock<Mutex<HashMap<String, Arc<HostLimits>>>> = OnceLock::new();
+        let mut hosts = HOSTS.get_or_init(Default::default).lock().unwrap_or_else(|e| e.into_inner());
+        let limits = hosts
+            .entry(registry.to_string())
+            .or_insert_with(|| {
+                Arc::new(HostLimits {
+                    connections: Mutex::new((0, usize::MAX)),
+                    freed: Condvar::new(),
+                    rate: Mutex::new((None, Instant::now())),
+                })
+            })
+            .clone();
+        if let Some(max) = options.max_connections {
+            let mut connections = limits.connections.lock().unwrap_or_else(|e| e.into_inner());
+            connections.1 = connections.1.min(max);
+        }
+        if let Some(limit) = options.limit_rate {
+            let mut rate = limits.rate.lock().unwrap_or_else(|e| e.into_inner());
+            rate.0 = Some(rate.0.map_or(limit, |r| r.min(limit)));
+        }
+        limits
+    }
+
+    /// Wait for a free connection, held until the slot is dropped.
+    fn acquire(&self) -> Slot<'_> {
+        let mut connections = self.connections.lock().unwrap_or_else(|e| e.into_inner());
+        while connections.0 >= connections.1 {
+            connections = self.freed.wait(connections).unwrap_or_else(|e| e.into_inner());
+        }
+        connections.0 += 1;
+        Slot(self)
+    }
+
+    fn limits_rate(&self) -> bool {
+        self.rate.lock().unwrap_or_else(|e| e.into_inner()).0.is_some()
+    }
+
+    /// Sleep until sending `bytes` more keeps the host under its rate.
+    fn pace(&self, bytes: usize) {
+        let until = {
+            let mut rate = self.rate.lock().unwrap_or_else(|e| e.into_inner());
+            let Some(limit) = rate.0 else { return };
+            let start = rate.1.max(Instant::now());
+            rate.1 = start + Duration::from_secs_f64(bytes as f64 / limit as f64);
+            rate.1
+        };
+        std::thread::sleep(until.saturating_duration_since(Instant::now()));
+    }
+}
+
+/// A connection to a registry host counted against its `max-connections`.
+struct Slot<'a>(&'a HostLimits);
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        self.0.connections.lock().unwrap_or_else(|e| e.into_inner()).0 -= 1;
+        self.0.freed.notify_one();
+    }
+}
+
+/// Request body sent no faster than the host's rate allows.
+struct Paced<'a> {
+    inner: Box<dyn Read + 'a>,
+    limits: Arc<HostLimits>,
+}
+
+impl Read for Paced<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.limits.pace(n);
+        Ok(n)
+    }
+}
+
+enum Body<'a> {
+    Empty,
+    Bytes(&'a [u8]),
+    Reader(Box<dyn Read + 'a>),
+}
+
+impl Client {
+    fn new(destination: &Destination, options: &PushOptions) -> Result<Self> {
+        let host = destination.registry.rsplit_once(':').map_or(&destination.registry[..], |(host, _)| host);
+        let local = matches!(host, "localhost" | "[::1]") || host.starts_with("127.");
+        let scheme = if options.plain_http || local { "http" } else { "https" };
+        let origin = format!("{}://{}", scheme, destination.registry);
+        Ok(Client {
+            agent: ureq::AgentBuilder::new()
+                .user_agent(concat!("build-oci/", env!("CARGO_PKG_VERSION")))
+                .build(),
+            repository_url: format!("{}/v2/{}", origin, destination.repository),
+            origin,
+            repository: destination.repository.clone(),
+            registry: destination.registry.clone(),
+            chunk_size: options.chunk_size,
+            authorization: None,
+            limits: HostLimits::of(&destination.registry, options),
+        })
+    }
+
+    /// Send a request; any HTTP status is a response, only transport
+    /// failures are errors. The registry's credentials only go to its own
+    /// origin, not to storage an upload `Location` points elsewhere.
+    fn send(&self, method: &str, url: &str, headers: &[(&str, &str)], body: Body) -> Result<ureq::Response> {
+        let mut request = self.agent.request(method, url);
+        if let Some(authorization) = self.authorization.as_ref().filter(|_| origin_of(url) == self.origin) {
+            request = request.set("Authorization", authorization);
+        }
+        for (name, value) in headers {
+            request = request.set(name, value);
+        }
+        let _slot = self.limits.acquire();
+        let result = match body {
+            Body::Empty => request.call(),
+            Body::Bytes(bytes) => {
+                self.limits.pace(bytes.len());
+                request.send_bytes(bytes)
+            }
+            Body::Reader(inner) if self.limits.limits_rate() => {
+                request.send(Paced { inner, limits: self.limits.clone() })
+            }
+            Body::Reader(reader) => request.send(reader),
+        };
+        match result {
+            Ok(response) | Err(ureq::Error::Status(_, response)) => Ok(response),
+            Err(err) => Err(anyhow!(err)).with_context(|| format!("{} {}", method, url)),
+        }
+    }
+
+    /// Check the API version endpoint and answer its challenge, if any:
+    /// Basic with the stored credentials, or Bearer with a token for
+    /// pushing to the repository.
+    fn authenticate(&mut self) -> Result<()> {
+        let url = format!("{}/v2/", self.origin);
+        let response = self.send("GET", &url, &[], Body::Empty)?;
+        if response.status() != 401 {
+            expect(response, &[200], "Checking the registry API")?;
+            return Ok(());
+        }
+        let (scheme, params) = parse_challenge(response.header("WWW-Authenticate").unwrap_or_default());
+        let credentials = credentials(&self.registry)?;
+        let basic = credentials
+            .as_ref()
+            .map(|(user, password)| format!("Basic {}", BASE64.encode(format!("{}:{}", user, password))));
+        if scheme.eq_ignore_ascii_case("basic") {
+            self.authorization = Some(basic.with_context(|| format!("{} requires credentials", self.registry))?);
+            return Ok(());
+        }
+        if !scheme.eq_ignore_ascii_case("bearer") {
+            bail!("{}: unsupported authentication scheme '{}'", self.registry, scheme);
+        }
+        let realm = params.get("realm").context("Bearer challenge without a realm")?;
+        let mut request = self
+            .agent
+            .get(realm)
+            .query("scope", &format!("repository:{}:pull,push", self.repository));
+        if let Some(service) = params.get("service") {
+            request = request.query("service", service);
+        }
+        if let Some(ref basic) = basic {
+            request = request.set("Authorization", basic);
+        }
+        let response = match request.call() {
+            Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+            Err(err) => return Err(anyhow!(err)).with_context(|| format!("Requesting a token from {}", realm)),
+        };
+        let response = expect(response, &[200], "Requesting a registry token")?;
+        let token: Value = serde_json::from_reader(response.into_reader()).context("Parsing token response")?;
+        let token = token["token"]
+            .as_str()
+            .or_else(|| token["access_token"].as_str())
+            .context("Token response without a token")?;
+        self.authorization = Some(format!("Bearer {}", token));
+        Ok(())
+    }
+
+    /// Push the manifest or index `bytes` under `reference` after
+    /// everything it refers to. Returns the blobs uploaded and those the
+    /// registry already had.
+    fn push_image(&self, layout: &Layout, media_type: &str, bytes: &[u8], reference: &str) -> Result<(usize, usize)> {
+        let manifest: Value = serde_json::from_slice(bytes).context("Parsing manifest")?;
+        let (mut uploaded, mut present) = (0, 0);
+        if media_type == MEDIA_TYPE_INDEX {
+            for desc in manifest["manifests"].as_array().into_iter().flatten() {
+                let digest = descriptor_digest(desc)?;
+                let child = fs::read(layout.blob_path(digest)?)?;
+                let (u, p) = self.push_image(layout, &manifest_media_type(desc, &child), &child, digest)?;
+                uploaded += u;
+                present += p;
+            }
+        } else {
+            // Registries do not take non-distributable layers; clients
+            // download them from their urls
+            let blobs: Vec<&Value> = std::iter::once(&manifest["config"])
+                .chain(manifest["layers"].as_array().into_iter().flatten())
+                .filter(|desc| {
+                    let foreign = desc["mediaType"].as_str().is_some_and(|m| m.starts_with(NONDISTRIBUTABLE_LAYER_MEDIA_TYPE));
+                    if foreign {
+                        debug!(digest = desc["digest"].as_str(), "not pushing non-distributable layer");
+                    }
+                    !foreign
+                })
+                .collect();
+            let results = blobs
+                .par_iter()
+                .map(|desc| self.push_blob(layout, desc))
+                .collect::<Result<Vec<bool>>>()?;
+            uploaded += results.iter().filter(|&&u| u).count();
+            present += results.iter().filter(|&&u| !u).count();
+        }
+
+        let url = format!("{}/manifests/{}", self.repository_url, reference);
+        let response = self.send("PUT", &url, &[("Content-Type", media_type)], Body::Bytes(bytes))?;
+        expect(response, &[200, 201], &format!("Pushing manifest {}", reference))?;
+        info!(reference, "pushed manifest");
+        Ok((uploaded, present))
+    }
+
+    /// Upload one blob unless the registry has it. Returns whether it was uploaded.
+    fn push_blob(&self, layout: &Layout, desc: &Value) -> Result<bool> {
+        let digest = descriptor_digest(desc)?;
+        let url = format!("{}/blobs/{}", self.repository_url, digest);
+        if self.send("HEAD", &url, &[], Body::Empty)?.status() == 200 {
+            debug!(digest, "blob already in the registry");
+            return Ok(false);
+        }
+        let path = layout.blob_path(digest)?;
+        let size = fs::metadata(&path).with_context(|| format!("Reading blob {}", digest))?.len();
+        let bar = Bar::bytes(&digest[..digest.len().min(19)], "uploading", size);
+        let what = format!("Uploading blob {}", digest);
+
+        let url = format!("{}/blobs/uploads/", self.repository_url);
+        let response = expect(self.send("POST", &url, &[], Body::Bytes(&[]))?, &[202], &what)?;
+        let mut location = self.location(&response)?;
+        let file = fs::File::open(&path)?;
+        let response = match self.chunk_size.filter(|&chunk| size > chunk) {
+            Some(chunk) => {
+                let mut offset = 0;
+                while offset < size {
+                    let len = chunk.min(size - offset);
+                    let headers = [
+                        ("Content-Type", "application/octet-stream"),
+                        ("Content-Length", &len.to_string()),
+                        ("Content-Range", &format!("{}-{}", offset, offset + len - 1)),
+                    ];
+                    let body = Body::Reader(Box::new(bar.reader((&file).take(len))));
+                    let response = expect(self.send("PATCH", &location, &headers, body)?, &[202], &what)?;
+                    location = self.location(&response)?;
+                    offset += len;
+                }
+                self.send("PUT", &with_digest(&location, digest), &[], Body::Bytes(&[]))?
+            }
+            None => {
+                let headers = [
+                    ("Content-Type", "application/octet-stream"),
+                    ("Content-Length", &size.to_string()),
+                ];
+                let body = Body::Reader(Box::new(bar.reader(&file)));
+                self.send("PUT", &with_digest(&location, digest), &headers, body)?
+            }
+        };
+        expect(response, &[201], &what)?;
+        info!(digest, size, "uploaded blob");
+        Ok(true)
+    }
+
+    /// Absolute upload URL from a response's `Location` header.
+    fn location(&self, response: &ureq::Response) -> Result<String> {
+        let location = response.header("Location").context("Upload response without a Location")?;
+        Ok(if location.starts_with("http://") || location.starts_with("https://") {
+            location.to_string()
+        } else {
+            format!("{}{}", self.origin, location)
+        })
+    }
+}
+
+/// `scheme://host[:port]` of the absolute URL `url`.
+fn origin_of(url: &str) -> &str {
+    let start = url.find("://").map_or(0, |i| i + 3);
+    match url[start..].find(['/', '?', '#']) {
+        Some(end) => &url[..start + end],
+        None => url,
+    }
+}
+
+/// Upload URL with the `digest` query parameter that completes the upload.
+fn with_digest(location: &str, digest: &str) -> String {
+    let separator = if location.contains('?') { '&' } else { '?' };
+    format!("{}{}digest={}", location, separator, digest.replace(':', "%3A"))
+}
+
+/// `response` if its status is one of `expected`, otherwise an error with
+/// the registry's answer.
+fn expect(response: ureq::Response, expected: &[u16], what: &str) -> Result<ureq::Response> {
+    if expected.contains(&response.status()) {
+        return Ok(response);
+    }
+    let status = response.status();
+    let body = response.into_string().unwrap_or_default();
+    bail!("{}: registry answered {} {}", what, status, body.trim())
+}
+
+/// Scheme and parameters of a `WWW-Authenticate` header such as
+/// `Bearer realm="https://auth.example/token",service="registry.example"`.
+fn parse_challenge(header: &str) -> (String, HashMap<String, String>) {
+    let (scheme, rest) = header.trim().split_once(' ').unwrap_or((header.trim(), ""));
+    let mut params = HashMap::new();
+    let mut chars = rest.chars().peekable();
+    loop {
+        while chars.next_if(|c| *c == ',' || c.is_whitespace()).is_some() {}
+        let key: String = std::iter::from_fn(|| chars.next_if(|c| *c != '=')).collect();
+        if key.is_empty() || chars.next().is_none() {
+            break;
+        }
+        let value: String = if chars.next_if_eq(&'"').is_some() {
+            let mut value = String::new();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => value.extend(chars.next()),
+                    c => value.push(c),
+                }
+            }
+            value
+        } else {
+            std::iter::from_fn(|| chars.next_if(|c| *c != ',')).collect()
+        };
+        params.insert(key.trim().to_ascii_lowercase(), value);
+    }
+    (scheme.to_string(), params)
+}
+
+/// Username and password for `registry` from `$REGISTRY_AUTH_FILE`, then
+/// `$DOCKER_CONFIG/config.json` (default `~/.docker/config.json`): an `auths`
+/// entry, or the `credHelpers` or `credsStore` credential helper.
+fn credentials(registry: &str) -> Result<Option<(String, String)>> {
+    let mut files: Vec<PathBuf> = std::env::var_os("REGISTRY_AUTH_FILE").map(PathBuf::from).into_iter().collect();
+    match std::env::var_os("DOCKER_CONFIG") {
+        Some(dir) => files.push(Path::new(&dir).join("config.json")),
+        None => files.extend(std::env::var_os("HOME").map(|home| Path::new(&home).join(".docker/config.json"))),
+    }
+    for file in files.iter().filter(|file| file.is_file()) {
+        let config: Value = serde_json::from_slice(&fs::read(file)?)
+            .with_context(|| format!("Parsing {}", file.display()))?;
+        if let Some(credentials) = config_credentials(&config, registry)
+            .with_context(|| format!("Reading credentials from {}", file.display()))?
+        {
+            debug!(file = %file.display(), registry, "using registry credentials");
+            return Ok(Some(credentials));
+        }
+    }
+    Ok(None)
+}
+
+/// Host of an `auths` key, which may be a URL such as `https://index.docker.io/v1/`.
+fn auth_key_host(key: &str) -> &str {
+    let key = key.split_once("://").map_or(key, |(_, rest)| rest);
+    let host = key.split('/').next().unwrap_or(key);
+    match host {
+        "docker.io" | "index.docker.io" => DOCKER_HUB,
+        host => host,
+    }
+}
+
+fn config_credentials(config: &Value, registry: &str) -> Result<Option<(String, String)>> {
+    for (key, entry) in config["auths"].as_object().into_iter().flatten() {
+        if auth_key_host(key) != registry {
+            continue;
+        }
+        if let Some(auth) = entry["auth"].as_str().filter(|auth| !auth.is_empty()) {
+            let decoded = String::from_utf8(BASE64.decode(auth).context("Invalid base64 in auth")?)?;
+            let (user, password) = decoded.split_once(':').context("auth is not user:password")?;
+            return Ok(Some((user.to_string(), password.to_string())));
+        }
+        if let (Some(user), Some(password)) = (entry["username"].as_str(), entry["password"].as_str()) {
+            return Ok(Some((user.to_string(), password.to_string())));
+        }
+    }
+    let server = if registry == DOCKER_HUB { DOCKER_HUB_AUTH_KEY } else { registry };
+    let helper = config["credHelpers"]
+        .as_object()
+        .and_then(|helpers| helpers.iter().find(|(key, _)| auth_key_host(key) == registry))
+        .and_then(|(_, helper)| helper.as_str())
+        .or_else(|| config["credsStore"].as_str());
+    let Some(helper) = helper else {
+        return Ok(None);
+    };
+    let mut cmd = Command::new(format!("docker-credential-{}", helper));
+    cmd.arg("get");
+    match keys::run(cmd, Some(server.as_bytes()), "get") {
+        Ok(output) => {
+            let answer: Value = serde_json::from_slice(&output).context("Parsing credential helper output")?;
+            match (answer["Username"].as_str(), answer["Secret"].as_str()) {
+                (Some(user), Some(secret)) => Ok(Some((user.to_string(), secret.to_string()))),
+                _ => Ok(None),
+            }
+        }
+        // Helpers fail for servers they hold nothing for
+        Err(err) => {
+            debug!(helper, error = %format!("{:#}", err), "no credentials from helper");
+            Ok(None)
+        }
+    }
+}
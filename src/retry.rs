@@ -0,0 +1,176 @@
+// Copyright (c) 2019, 2020 Codethink Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Bounded retries of file system calls that fail transiently on network
+//! storage: NFS and CIFS mounts return ESTALE, EIO or ETIMEDOUT for
+//! operations that succeed a moment later. Retries are counted for the
+//! end-of-build log and the `--error-json` report.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use tempfile::NamedTempFile;
+use tracing::warn;
+
+/// How often and how patiently transient errors are retried.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt
+    pub retries: u32,
+    /// Wait before the first retry, doubled before each further one
+    pub delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy { retries: 3, delay: Duration::from_millis(100) }
+    }
+}
+
+/// Retries made
+static RETRIES: AtomicU64 = AtomicU64::new(0);
+/// Calls that succeeded after retrying
+static RECOVERED: AtomicU64 = AtomicU64::new(0);
+/// Path and error of calls still failing once the retries ran out
+static FAILURES: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+
+/// Whether `error` is worth retrying on network storage.
+fn is_transient(error: &io::Error) -> bool {
+    matches!(error.raw_os_error(), Some(libc::ESTALE | libc::EIO | libc::ETIMEDOUT))
+}
+
+impl RetryPolicy {
+    /// Run `op` on `path`, retrying transient errors.
+    pub fn run<T>(&self, path: &Path, mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+        let mut attempt = 0;
+        let mut delay = self.delay;
+        loop {
+            match op() {
+                Ok(value) => {
+                    if attempt > 0 {
+                        RECOVERED.fetch_add(1, Ordering::Relaxed);
+                    }
+                    return Ok(value);
+                }
+                Err(e) if is_transient(&e) && attempt < self.retries => {
+                    attempt += 1;
+                    RETRIES.fetch_add(1, Ordering::Relaxed);
+                    warn!(path = %path.display(), attempt, "retrying after {}", e);
+                    thread::sleep(delay);
+                    delay = delay.saturating_mul(2);
+                }
+                Err(e) => {
+                    if is_transient(&e) {
+                        let mut failures = FAILURES.lock().unwrap_or_else(|e| e.into_inner());
+                        failures.push((path.display().to_string(), e.to_string()));
+                    }
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// Open the file at `path` for reading.
+    pub fn open(&self, path: &Path) -> io::Result<File> {
+        self.run(path, || File::open(path))
+    }
+
+    /// Rename `tmp` to `dest`.
+    pub fn persist(&self, tmp: NamedTempFile, dest: &Path) -> io::Result<()> {
+        let mut tmp = Some(tmp);
+        self.run(dest, || {
+            let file = tmp.take().expect("temporary file is kept until persisted");
+            file.persist(dest).map(drop).map_err(|e| {
+                tmp = Some(e.file);
+                e.error
+            })
+        })
+    }
+}
+
+/// Reader of a file that reopens it at the same offset when a read fails
+/// transiently, as a stale NFS handle needs.
+pub struct Reader {
+    path: PathBuf,
+    file: File,
+    offset: u64,
+    policy: RetryPolicy,
+}
+
+impl Reader {
+    pub fn new(path: &Path, file: File, policy: RetryPolicy) -> Self {
+        Reader { path: path.to_path_buf(), file, offset: 0, policy }
+    }
+}
+
+impl Read for Reader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Reader { path, file, offset, policy } = self;
+        let n = policy.run(path, || match file.read(buf) {
+            Err(e) if is_transient(&e) => {
+                if let Ok(mut reopened) = File::open(&*path) {
+                    if reopened.seek(SeekFrom::Start(*offset)).is_ok() {
+                        *file = reopened;
+                    }
+                }
+                Err(e)
+            }
+            result => result,
+        })?;
+        *offset += n as u64;
+        Ok(n)
+    }
+}
+
+/// Whether any call was retried or ran out of retries in this run.
+pub fn any() -> bool {
+    RETRIES.load(Ordering::Relaxed) > 0 || !FAILURES.lock().unwrap_or_else(|e| e.into_inner()).is_empty()
+}
+
+/// Retry counts and the calls that still failed, as JSON.
+pub fn to_json() -> serde_json::Value {
+    let failures = FAILURES.lock().unwrap_or_else(|e| e.into_inner());
+    serde_json::json!({
+        "retries": RETRIES.load(Ordering::Relaxed),
+        "recovered": RECOVERED.load(Ordering::Relaxed),
+        "failed": failures
+            .iter()
+            .map(|(path, error)| serde_json::json!({ "path": path, "error": error }))
+            .collect::<Vec<_>>(),
+    })
+}
+
+/// Log an `io retries` event when calls were retried.
+pub fn log() {
+    if any() {
+        let failed = FAILURES.lock().unwrap_or_else(|e| e.into_inner()).len();
+        warn!(
+            retries = RETRIES.load(Ordering::Relaxed),
+            recovered = RECOVERED.load(Ordering::Relaxed),
+            failed,
+            "io retries"
+        );
+    }
+}
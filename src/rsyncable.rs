@@ -0,0 +1,162 @@
+// Copyright (c) 2019, 2020 Codethink Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! `gzip-rsyncable: true`: gzip layers that restart compression at points
+//! chosen by their content, like `gzip --rsyncable`, so a change to a file
+//! only changes the compressed bytes near it and delta transfers between
+//! versions of a layer find the rest unchanged. The stream is cut where the
+//! sum of the last 4 KiB of input is a multiple of 4096; every chunk is
+//! deflated on its own and ends with a full flush, so chunks can be
+//! compressed in parallel and concatenated into one gzip member.
+
+use std::io::{self, Write};
+
+use anyhow::Result;
+use flate2::{Compress, Crc, FlushCompress};
+use rayon::prelude::*;
+
+use crate::codec::{Encoder, Finished};
+
+/// Bytes covered by the rolling sum, and the smallest chunk
+const WINDOW: usize = 4096;
+/// Chunks are cut here when the content gives no boundary before
+const MAX_CHUNK: usize = 1 << 20;
+/// Input gathered before its chunks are compressed
+const BATCH: usize = 8 << 20;
+
+/// Gzip header: deflate, no flags, no mtime, unknown OS.
+const HEADER: [u8; 10] = [0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255];
+
+pub struct Writer {
+    out: Box<dyn Write + Send>,
+    level: flate2::Compression,
+    threads: usize,
+    /// Last `WINDOW` bytes of input and the position of the oldest
+    window: Vec<u8>,
+    pos: usize,
+    sum: u32,
+    /// Input not compressed yet, and where its complete chunks end
+    batch: Vec<u8>,
+    boundaries: Vec<usize>,
+    chunk_len: usize,
+    crc: Crc,
+}
+
+impl Writer {
+    pub fn new(out: Box<dyn Write + Send>, level: u32, threads: usize) -> io::Result<Self> {
+        let mut writer = Writer {
+            out,
+            level: flate2::Compression::new(level),
+            threads: threads.max(1),
+            window: vec![0; WINDOW],
+            pos: 0,
+            sum: 0,
+            batch: Vec::with_capacity(BATCH),
+            boundaries: Vec::new(),
+            chunk_len: 0,
+            crc: Crc::new(),
+        };
+        writer.out.write_all(&HEADER)?;
+        Ok(writer)
+    }
+
+    /// Compress the complete chunks of the batch, keeping the rest for the
+    /// next one, or all of it at the end of the stream.
+    fn compress_batch(&mut self, last: bool) -> io::Result<()> {
+        let end = match last {
+            true => self.batch.len(),
+            false => self.boundaries.last().copied().unwrap_or(0),
+        };
+        let mut starts = vec![0];
+        starts.extend(self.boundaries.iter().copied().filter(|&b| b < end));
+        let chunks: Vec<&[u8]> = starts
+            .iter()
+            .zip(starts.iter().skip(1).copied().chain([end]))
+            .map(|(&start, end)| &self.batch[start..end])
+            .filter(|chunk| !chunk.is_empty())
+            .collect();
+        let level = self.level;
+        let compressed = if self.threads > 1 {
+            chunks.par_iter().map(|chunk| deflate_chunk(chunk, level)).collect::<io::Result<Vec<_>>>()?
+        } else {
+            chunks.iter().map(|chunk| deflate_chunk(chunk, level)).collect::<io::Result<Vec<_>>>()?
+        };
+        for data in compressed {
+            self.out.write_all(&data)?;
+        }
+        self.crc.update(&self.batch[..end]);
+        self.batch.drain(..end);
+        self.boundaries.clear();
+        Ok(())
+    }
+}
+
+/// Deflate `chunk` with a fresh dictionary, ending on a full flush.
+fn deflate_chunk(chunk: &[u8], level: flate2::Compression) -> io::Result<Vec<u8>> {
+    let mut compress = Compress::new(level, false);
+    let mut out = Vec::with_capacity(chunk.len() / 2 + 64);
+    loop {
+        let consumed = compress.total_in() as usize;
+        compress.compress_vec(&chunk[consumed..], &mut out, FlushCompress::Full)?;
+        // The flush is complete once it leaves room in the output
+        if compress.total_in() as usize == chunk.len() && out.len() < out.capacity() {
+            return Ok(out);
+        }
+        out.reserve(out.capacity().max(64));
+    }
+}
+
+impl Write for Writer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            self.sum = self.sum.wrapping_add(u32::from(byte)).wrapping_sub(u32::from(self.window[self.pos]));
+            self.window[self.pos] = byte;
+            self.pos = (self.pos + 1) % WINDOW;
+            self.batch.push(byte);
+            self.chunk_len += 1;
+            if (self.chunk_len >= WINDOW && self.sum.is_multiple_of(WINDOW as u32)) || self.chunk_len >= MAX_CHUNK {
+                self.boundaries.push(self.batch.len());
+                self.chunk_len = 0;
+            }
+        }
+        if self.batch.len() >= BATCH {
+            self.compress_batch(false)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+impl Encoder for Writer {
+    fn finish(mut self: Box<Self>) -> Result<Finished> {
+        self.compress_batch(true)?;
+        // An empty final block ends the deflate stream
+        let mut end = Vec::with_capacity(16);
+        Compress::new(self.level, false).compress_vec(&[], &mut end, FlushCompress::Finish)?;
+        self.out.write_all(&end)?;
+        self.out.write_all(&self.crc.sum().to_le_bytes())?;
+        self.out.write_all(&self.crc.amount().to_le_bytes())?;
+        self.out.flush()?;
+        Ok(Finished::default())
+    }
+}
@@ -0,0 +1,477 @@
+// Copyright (c) 2019, 2020 Codethink Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Software bill of materials of an image's layers: the distribution named
+//! by os-release, the packages recorded by dpkg and rpm and, optionally,
+//! every regular file with its sha256, written as SPDX 2.3 or CycloneDX 1.5
+//! JSON.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read};
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use crate::util::HashingWriter;
+
+/// Layer descriptor annotation marking a layer that holds only the image's
+/// SBOM, with the SBOM's media type as its value
+pub const ANNOTATION_SBOM: &str = "org.freedesktopsdk.layer.sbom";
+
+/// Directory of the SBOM file in an SBOM layer
+pub const LAYER_DIR: &str = "usr/share/sbom";
+
+const OS_RELEASE: &[&str] = &["etc/os-release", "usr/lib/os-release"];
+const DPKG_STATUS: &str = "var/lib/dpkg/status";
+/// Directories of the rpm database, legacy location first
+const RPMDB_DIRS: &[&str] = &["var/lib/rpm", "usr/lib/sysimage/rpm"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Spdx,
+    CycloneDx,
+}
+
+impl Format {
+    /// Parse `sbom.format:` (default "spdx").
+    pub fn parse(name: Option<&str>) -> Result<Self> {
+        match name {
+            None | Some("spdx") => Ok(Format::Spdx),
+            Some("cyclonedx") => Ok(Format::CycloneDx),
+            Some(other) => bail!("must be spdx or cyclonedx, got: {}", other),
+        }
+    }
+
+    pub fn media_type(self) -> &'static str {
+        match self {
+            Format::Spdx => "application/spdx+json",
+            Format::CycloneDx => "application/vnd.cyclonedx+json",
+        }
+    }
+
+    /// File name of the SBOM in an SBOM layer or artifact.
+    pub fn file_name(self) -> &'static str {
+        match self {
+            Format::Spdx => "sbom.spdx.json",
+            Format::CycloneDx => "sbom.cdx.json",
+        }
+    }
+}
+
+/// The files an SBOM is made from, collected from layers bottom first.
+#[derive(Debug, Default)]
+pub struct Rootfs {
+    /// Contents of os-release and the package databases, by path
+    metadata: BTreeMap<String, Vec<u8>>,
+    /// sha256 of every regular file, by path, when hashing files
+    files: BTreeMap<String, String>,
+    hash_files: bool,
+}
+
+impl Rootfs {
+    pub fn new(hash_files: bool) -> Self {
+        Rootfs { hash_files, ..Rootfs::default() }
+    }
+
+    /// Apply an uncompressed layer tar on top of the layers read so far.
+    pub fn add_layer(&mut self, reader: impl Read) -> Result<()> {
+        let mut archive = tar::Archive::new(reader);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.to_string_lossy().trim_start_matches("./").trim_matches('/').to_string();
+            let (dir, name) = path.rsplit_once('/').unwrap_or(("", &path));
+            if name == ".wh..wh..opq" {
+                self.remove_below(dir);
+                continue;
+            }
+            if let Some(hidden) = name.strip_prefix(".wh.") {
+                let target = if dir.is_empty() { hidden.to_string() } else { format!("{}/{}", dir, hidden) };
+                self.remove(&target);
+                self.remove_below(&target);
+                continue;
+            }
+            // Whatever the entry is, it replaces the file at its path
+            self.remove(&path);
+            let kind = entry.header().entry_type();
+            if kind.is_hard_link() {
+                let target = entry.link_name()?.unwrap_or_default().to_string_lossy().trim_start_matches("./").to_string();
+                if let Some(contents) = self.metadata.get(&target).cloned() {
+                    self.metadata.insert(path.clone(), contents);
+                }
+                if let Some(digest) = self.files.get(&target).cloned() {
+                    self.files.insert(path, digest);
+                }
+                continue;
+            }
+            if !kind.is_file() {
+                continue;
+            }
+            if is_metadata(&path) {
+                let mut contents = Vec::new();
+                entry.read_to_end(&mut contents)?;
+                if self.hash_files {
+                    self.files.insert(path.clone(), format!("{:x}", Sha256::digest(&contents)));
+                }
+                self.metadata.insert(path, contents);
+            } else if self.hash_files {
+                let mut writer = HashingWriter::new(io::sink());
+                io::copy(&mut entry, &mut writer)?;
+                self.files.insert(path, writer.finish()?.1);
+            }
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, path: &str) {
+        self.metadata.remove(path);
+        self.files.remove(path);
+    }
+
+    /// Remove everything inside directory `dir` ("" for the root).
+    fn remove_below(&mut self, dir: &str) {
+        let inside = |key: &String| dir.is_empty() || key.strip_prefix(dir).is_some_and(|rest| rest.starts_with('/'));
+        self.metadata.retain(|key, _| !inside(key));
+        self.files.retain(|key, _| !inside(key));
+    }
+}
+
+fn is_metadata(path: &str) -> bool {
+    OS_RELEASE.contains(&path)
+        || path == DPKG_STATUS
+        || path
+            .rsplit_once('/')
+            .is_some_and(|(dir, _)| RPMDB_DIRS.contains(&dir))
+}
+
+/// The distribution from os-release.
+#[derive(Debug, Default)]
+struct OsRelease {
+    id: Option<String>,
+    version_id: Option<String>,
+    name: Option<String>,
+}
+
+/// An installed package.
+#[derive(Debug)]
+struct Package {
+    /// purl type: "deb" or "rpm"
+    kind: &'static str,
+    name: String,
+    version: String,
+    arch: Option<String>,
+    license: Option<String>,
+}
+
+impl Package {
+    fn purl(&self, os: &OsRelease) -> String {
+        let namespace = os.id.as_deref().unwrap_or(if self.kind == "deb" { "debian" } else { "redhat" });
+        let mut purl = format!("pkg:{}/{}/{}@{}", self.kind, namespace, encode(&self.name), encode(&self.version));
+        let mut qualifiers = Vec::new();
+        if let Some(ref arch) = self.arch {
+            qualifiers.push(format!("arch={}", encode(arch)));
+        }
+        if let (Some(id), Some(version)) = (&os.id, &os.version_id) {
+            qualifiers.push(format!("distro={}", encode(&format!("{}-{}", id, version))));
+        }
+        if !qualifiers.is_empty() {
+            purl.push('?');
+            purl.push_str(&qualifiers.join("&"));
+        }
+        purl
+    }
+}
+
+/// Percent-encode a purl component.
+fn encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b".-_~".contains(&b) {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+fn os_release(rootfs: &Rootfs) -> OsRelease {
+    // /etc/os-release takes precedence, as for systemd
+    let Some(contents) = OS_RELEASE.iter().find_map(|path| rootfs.metadata.get(*path)) else {
+        return OsRelease::default();
+    };
+    let mut os = OsRelease::default();
+    for line in String::from_utf8_lossy(contents).lines() {
+        let Some((key, value)) = line.split_once('=') else { continue };
+        let value = Some(value.trim().trim_matches(|c| c == '"' || c == '\'').to_string());
+        match key.trim() {
+            "ID" => os.id = value,
+            "VERSION_ID" => os.version_id = value,
+            "NAME" => os.name = value,
+            _ => {}
+        }
+    }
+    os
+}
+
+/// Installed packages of the dpkg status file.
+fn dpkg_packages(status: &[u8]) -> Vec<Package> {
+    let mut packages = Vec::new();
+    for paragraph in String::from_utf8_lossy(status).split("\n\n") {
+        let mut fields = BTreeMap::new();
+        for line in paragraph.lines() {
+            // Continuation lines belong to multi-line fields we do not read
+            if line.starts_with([' ', '\t']) {
+                continue;
+            }
+            if let Some((key, value)) = line.split_once(':') {
+                fields.insert(key, value.trim());
+            }
+        }
+        let installed = fields.get("Status").is_some_and(|status| status.ends_with(" installed"));
+        if let (true, Some(name), Some(version)) = (installed, fields.get("Package"), fields.get("Version")) {
+            packages.push(Package {
+                kind: "deb",
+                name: name.to_string(),
+                version: version.to_string(),
+                arch: fields.get("Architecture").map(|arch| arch.to_string()),
+                license: None,
+            });
+        }
+    }
+    packages
+}
+
+/// Installed packages of the rpm database, queried with the host's rpm.
+fn rpm_packages(rootfs: &Rootfs) -> Result<Vec<Package>> {
+    // The newer location wins if both hold a database
+    let Some(dir) = RPMDB_DIRS
+        .iter()
+        .rev()
+        .find(|dir| rootfs.metadata.keys().any(|path| path.rsplit_once('/').is_some_and(|(d, _)| d == **dir)))
+    else {
+        return Ok(Vec::new());
+    };
+    let dbpath = tempfile::tempdir()?;
+    for (path, contents) in &rootfs.metadata {
+        if let Some(name) = path.strip_prefix(dir).and_then(|rest| rest.strip_prefix('/')) {
+            fs::write(dbpath.path().join(name), contents)?;
+        }
+    }
+    let output = match Command::new("rpm")
+        .arg("--dbpath")
+        .arg(dbpath.path())
+        .args(["-qa", "--qf", "%{NAME}\\t%|EPOCH?{%{EPOCH}:}:{}|%{VERSION}-%{RELEASE}\\t%{ARCH}\\t%{LICENSE}\\n"])
+        .output()
+    {
+        Ok(output) => output,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            warn!("the layers hold an rpm database, but rpm is not installed to read it; its packages are left out");
+            return Ok(Vec::new());
+        }
+        Err(e) => return Err(e).context("Running rpm"),
+    };
+    if !output.status.success() {
+        bail!("rpm failed to read the database in /{}: {}", dir, String::from_utf8_lossy(&output.stderr).trim());
+    }
+    let mut packages: Vec<Package> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split('\t');
+            let (name, version, arch, license) = (fields.next()?, fields.next()?, fields.next()?, fields.next()?);
+            Some(Package {
+                kind: "rpm",
+                name: name.to_string(),
+                version: version.to_string(),
+                arch: Some(arch.to_string()).filter(|arch| arch != "(none)"),
+                license: Some(license.to_string()).filter(|license| license != "(none)"),
+            })
+        })
+        .collect();
+    packages.sort_by(|a, b| (&a.name, &a.version).cmp(&(&b.name, &b.version)));
+    Ok(packages)
+}
+
+/// Write the SBOM of `rootfs` for the image called `name`, created at the
+/// RFC 3339 time `created`.
+pub fn generate(format: Format, rootfs: &Rootfs, name: &str, created: &str) -> Result<Vec<u8>> {
+    let os = os_release(rootfs);
+    let mut packages = rootfs.metadata.get(DPKG_STATUS).map(|status| dpkg_packages(status)).unwrap_or_default();
+    packages.extend(rpm_packages(rootfs)?);
+    // Identifies the document by its contents, so rebuilds reproduce it
+    let mut hasher = Sha256::new();
+    for package in &packages {
+        hasher.update(format!("{} {} {}\n", package.kind, package.name, package.version));
+    }
+    for (path, digest) in &rootfs.files {
+        hasher.update(format!("{} {}\n", path, digest));
+    }
+    let id = format!("{:x}", hasher.finalize());
+    let document = match format {
+        Format::Spdx => spdx(&os, &packages, &rootfs.files, name, created, &id),
+        Format::CycloneDx => cyclonedx(&os, &packages, &rootfs.files, name, created, &id),
+    };
+    let mut bytes = serde_json::to_vec_pretty(&document)?;
+    bytes.push(b'\n');
+    Ok(bytes)
+}
+
+/// `object` without the keys whose value is unknown.
+fn without_nulls(mut object: Value) -> Value {
+    if let Some(map) = object.as_object_mut() {
+        map.retain(|_, value| !value.is_null());
+    }
+    object
+}
+
+fn tool() -> String {
+    format!("build-oci-{}", env!("CARGO_PKG_VERSION"))
+}
+
+fn spdx(
+    os: &OsRelease,
+    packages: &[Package],
+    files: &BTreeMap<String, String>,
+    name: &str,
+    created: &str,
+    id: &str,
+) -> Value {
+    let mut spdx_packages = Vec::new();
+    let mut relationships = Vec::new();
+    if let Some(ref os_id) = os.id {
+        spdx_packages.push(without_nulls(json!({
+            "name": os_id,
+            "SPDXID": "SPDXRef-OperatingSystem",
+            "versionInfo": os.version_id,
+            "description": os.name,
+            "primaryPackagePurpose": "OPERATING-SYSTEM",
+            "downloadLocation": "NOASSERTION",
+            "filesAnalyzed": false,
+        })));
+    }
+    for (i, package) in packages.iter().enumerate() {
+        let spdx_id = format!("SPDXRef-Package-{}-{}", package.kind, i);
+        spdx_packages.push(json!({
+            "name": package.name,
+            "SPDXID": spdx_id,
+            "versionInfo": package.version,
+            "downloadLocation": "NOASSERTION",
+            "licenseDeclared": package.license.as_deref().unwrap_or("NOASSERTION"),
+            "filesAnalyzed": false,
+            "externalRefs": [{
+                "referenceCategory": "PACKAGE-MANAGER",
+                "referenceType": "purl",
+                "referenceLocator": package.purl(os),
+            }],
+        }));
+    }
+    for package in &spdx_packages {
+        relationships.push(json!({
+            "spdxElementId": "SPDXRef-DOCUMENT",
+            "relationshipType": "DESCRIBES",
+            "relatedSpdxElement": package["SPDXID"],
+        }));
+    }
+    let spdx_files: Vec<Value> = files
+        .iter()
+        .enumerate()
+        .map(|(i, (path, digest))| {
+            json!({
+                "fileName": format!("./{}", path),
+                "SPDXID": format!("SPDXRef-File-{}", i),
+                "checksums": [{ "algorithm": "SHA256", "checksumValue": digest }],
+            })
+        })
+        .collect();
+    json!({
+        "spdxVersion": "SPDX-2.3",
+        "dataLicense": "CC0-1.0",
+        "SPDXID": "SPDXRef-DOCUMENT",
+        "name": name,
+        "documentNamespace": format!("https://spdx.org/spdxdocs/build-oci/{}", id),
+        "creationInfo": { "created": created, "creators": [format!("Tool: {}", tool())] },
+        "packages": spdx_packages,
+        "files": spdx_files,
+        "relationships": relationships,
+    })
+}
+
+fn cyclonedx(
+    os: &OsRelease,
+    packages: &[Package],
+    files: &BTreeMap<String, String>,
+    name: &str,
+    created: &str,
+    id: &str,
+) -> Value {
+    let mut components = Vec::new();
+    if let Some(ref os_id) = os.id {
+        components.push(without_nulls(json!({
+            "type": "operating-system",
+            "name": os_id,
+            "version": os.version_id,
+            "description": os.name,
+        })));
+    }
+    for package in packages {
+        let mut component = json!({
+            "type": "library",
+            "name": package.name,
+            "version": package.version,
+            "purl": package.purl(os),
+        });
+        if let Some(ref license) = package.license {
+            component["licenses"] = json!([{ "expression": license }]);
+        }
+        components.push(component);
+    }
+    for (path, digest) in files {
+        components.push(json!({
+            "type": "file",
+            "name": format!("/{}", path),
+            "hashes": [{ "alg": "SHA-256", "content": digest }],
+        }));
+    }
+    // A name-based UUID (version 5 layout) derived from the contents
+    let uuid = format!(
+        "{}-{}-5{}-{:x}{}-{}",
+        &id[0..8],
+        &id[8..12],
+        &id[13..16],
+        8 | (u8::from_str_radix(&id[16..17], 16).unwrap_or(0) & 3),
+        &id[17..20],
+        &id[20..32]
+    );
+    json!({
+        "bomFormat": "CycloneDX",
+        "specVersion": "1.5",
+        "serialNumber": format!("urn:uuid:{}", uuid),
+        "version": 1,
+        "metadata": {
+            "timestamp": created,
+            "tools": { "components": [{ "type": "application", "name": "build-oci", "version": env!("CARGO_PKG_VERSION") }] },
+            "component": { "type": "container", "name": name },
+        },
+        "components": components,
+    })
+}
@@ -0,0 +1,115 @@
+// Copyright (c) 2019, 2020 Codethink Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Result};
+use rayon::prelude::*;
+use sha2::{Digest, Sha256};
+use tracing::info;
+
+use crate::config::GpgSignSpec;
+use crate::error::{ErrorCategory, ResultExt};
+use crate::keys;
+
+/// Digest manifest of a layout, in `sha256sum` format, written next to index.json
+pub const DIGESTS_FILE: &str = "SHA256SUMS";
+/// Armored detached GPG signature of `DIGESTS_FILE`
+pub const SIGNATURE_FILE: &str = "SHA256SUMS.asc";
+
+/// Write the digest manifest of `index.json` and every blob in the layout,
+/// then sign it with the configured key provider.
+pub fn sign_layout(root: &Path, spec: &GpgSignSpec) -> Result<()> {
+    let mut lines = vec![format!("{}  index.json", file_sha256(&root.join("index.json"))?)];
+
+    // Blob file names are their digests, so they are listed without re-hashing
+    let blob_dir = root.join("blobs").join("sha256");
+    let mut blobs = fs::read_dir(&blob_dir)
+        .with_context(|| format!("Reading {}", blob_dir.display()))?
+        .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+        .collect::<Result<Vec<_>>>()?;
+    blobs.sort();
+    lines.extend(blobs.iter().map(|hash| format!("{}  blobs/sha256/{}", hash, hash)));
+
+    let digests = lines.join("\n") + "\n";
+    fs::write(root.join(DIGESTS_FILE), &digests)?;
+
+    let key = keys::provider(&spec.key, spec.homedir.as_deref())?;
+    let signature = key
+        .sign(digests.as_bytes())
+        .with_context(|| format!("Signing {} with {}", DIGESTS_FILE, key.describe()))?;
+    fs::write(root.join(SIGNATURE_FILE), signature)?;
+
+    info!(key = %key.describe(), blobs = blobs.len(), "signed layout digests");
+    Ok(())
+}
+
+/// Check the GPG signature of the digest manifest, then re-hash every file it lists.
+pub fn verify_layout(root: &Path, homedir: Option<&Path>) -> Result<usize> {
+    let digests_path = root.join(DIGESTS_FILE);
+    let signature_path = root.join(SIGNATURE_FILE);
+    if !signature_path.is_file() {
+        bail!("{} is not signed (missing {})", root.display(), SIGNATURE_FILE);
+    }
+
+    let mut cmd = keys::gpg(homedir);
+    cmd.arg("--verify").arg(&signature_path).arg(&digests_path);
+    keys::run(cmd, None, "signature verification")?;
+
+    let digests = fs::read_to_string(&digests_path)
+        .with_context(|| format!("Reading {}", digests_path.display()))?;
+    let entries = digests
+        .lines()
+        .map(|line| {
+            line.split_once("  ")
+                .with_context(|| format!("Malformed line in {}: '{}'", DIGESTS_FILE, line))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // Only paths inside the layout are accepted, whatever the signed file says
+    let mismatches: Vec<String> = entries
+        .par_iter()
+        .filter_map(|(expected, rel)| {
+            if rel.starts_with('/') || rel.split('/').any(|c| c == "..") {
+                return Some(format!("{}: path outside the layout", rel));
+            }
+            match file_sha256(&root.join(rel)) {
+                Ok(actual) if actual == *expected => None,
+                Ok(actual) => Some(format!("{}: expected sha256:{}, got sha256:{}", rel, expected, actual)),
+                Err(e) => Some(format!("{}: {:#}", rel, e)),
+            }
+        })
+        .collect();
+
+    if !mismatches.is_empty() {
+        return Err(anyhow!("Digest verification failed:\n  {}", mismatches.join("\n  ")))
+            .category(ErrorCategory::DigestMismatch);
+    }
+    Ok(entries.len())
+}
+
+fn file_sha256(path: &Path) -> Result<String> {
+    let mut file = fs::File::open(path).with_context(|| format!("Opening {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
@@ -0,0 +1,325 @@
+// Copyright (c) 2019, 2020 Codethink Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Streaming, metadata-only tar parser for lower layers.
+//!
+//! Parent layers may come from anywhere, so this walks the archive block by
+//! block instead of going through `tar::Archive`, which buffers PAX headers
+//! and GNU long names of any size. Every size read from the archive is
+//! checked and bounded, and errors name the entry and offset they occur at.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use tar::{EntryType, Header};
+
+const BLOCK_SIZE: u64 = 512;
+/// Largest PAX extended header accepted; real ones are well under a kilobyte
+/// plus any xattr values
+pub const MAX_PAX_HEADER: u64 = 1 << 20;
+/// Largest GNU long name or link target accepted
+pub const MAX_LONG_NAME: u64 = 64 * 1024;
+
+/// Lower layer entry metadata - field order optimized to reduce struct padding
+#[derive(Debug, Clone)]
+pub struct LowerEntry {
+    // 8-byte aligned fields first (pointer-based types)
+    pub pax_headers: HashMap<String, String>,
+    /// Raw bytes of a symlink's target
+    pub symlink_target: Option<Vec<u8>>,
+    // 8-byte aligned primitives
+    pub uid: u64,
+    pub gid: u64,
+    pub mtime: u64,
+    pub size: u64,
+    // 4-byte aligned, followed by 1-byte - packs efficiently
+    pub mode: u32,
+    pub entry_type: u8,
+}
+
+/// Represents parsed entries from a single tar archive before merging.
+/// Also loaded from stored per-layer file indexes (see `layer_index`).
+#[derive(Debug, Clone, Default)]
+pub struct ArchiveEntries {
+    /// Regular entries (non-whiteout)
+    pub entries: Vec<(String, LowerEntry)>,
+    /// Opaque whiteouts - directories whose contents should be deleted
+    pub opaque_whiteouts: Vec<String>,
+    /// File whiteouts - specific files to delete
+    pub file_whiteouts: Vec<String>,
+}
+
+#[inline]
+pub fn split_path(path: &str) -> (Cow<'_, str>, Cow<'_, str>) {
+    let p = Path::new(path);
+    let basename = p
+        .file_name()
+        .map(|f| f.to_string_lossy())
+        .unwrap_or_default();
+    let dirname = p
+        .parent()
+        .map(|d| d.to_string_lossy())
+        .unwrap_or_default();
+    (dirname, basename)
+}
+
+/// Parse the tar stream of a layer into its entries and whiteouts, skipping
+/// file contents.
+pub fn parse_archive(reader: impl Read) -> Result<ArchiveEntries> {
+    let mut parser = Parser { reader, offset: 0 };
+    let mut parsed = ArchiveEntries {
+        entries: Vec::with_capacity(1024),
+        ..Default::default()
+    };
+    // Extension headers apply to the next regular header
+    let mut pax: Option<HashMap<String, String>> = None;
+    // Link targets are kept as raw bytes, which need not be UTF-8
+    let mut pax_linkpath: Option<Vec<u8>> = None;
+    let mut long_name: Option<Vec<u8>> = None;
+    let mut long_link: Option<Vec<u8>> = None;
+
+    for index in 0.. {
+        let offset = parser.offset;
+        let Some(block) = parser.read_block()? else {
+            break; // Truncated archives without an end marker are accepted, as tar does
+        };
+        if block.iter().all(|&b| b == 0) {
+            break; // End-of-archive marker
+        }
+        let header = Header::from_byte_slice(&block);
+        let label = match &long_name {
+            Some(name) => String::from_utf8_lossy(trim_nul(name)).into_owned(),
+            None => String::from_utf8_lossy(&header.path_bytes()).into_owned(),
+        };
+        let context = || format!("tar entry {} ({}) at offset {}", index, label, offset);
+
+        check_checksum(header).with_context(context)?;
+        let pax_size = pax
+            .as_ref()
+            .and_then(|p| p.get("size"))
+            .map(|s| s.parse::<u64>().with_context(|| format!("invalid PAX size '{}'", s)))
+            .transpose()
+            .with_context(context)?;
+        let data_size = match pax_size {
+            Some(size) => size,
+            None => header.entry_size().context("invalid size field").with_context(context)?,
+        };
+
+        let entry_type = header.entry_type();
+        match entry_type {
+            EntryType::XHeader => {
+                let data = parser.read_data(data_size, MAX_PAX_HEADER).with_context(context)?;
+                pax_linkpath = pax_value(&data, "linkpath");
+                pax = Some(parse_pax(&data));
+                continue;
+            }
+            EntryType::GNULongName => {
+                long_name = Some(parser.read_data(data_size, MAX_LONG_NAME).with_context(context)?);
+                continue;
+            }
+            EntryType::GNULongLink => {
+                long_link = Some(parser.read_data(data_size, MAX_LONG_NAME).with_context(context)?);
+                continue;
+            }
+            EntryType::XGlobalHeader => {
+                parser.skip_data(data_size).with_context(context)?;
+                continue;
+            }
+            _ => {}
+        }
+
+        // GNU sparse files continue their sparse map in extension blocks
+        if header.as_gnu().is_some_and(|gnu| gnu.is_extended()) {
+            loop {
+                let ext = parser
+                    .read_block()?
+                    .context("unexpected end of archive in sparse header")
+                    .with_context(context)?;
+                // `isextended` flag of a GNU sparse extension block
+                if ext[504] == 0 {
+                    break;
+                }
+            }
+        }
+        parser.skip_data(data_size).with_context(context)?;
+
+        let pax_headers = pax.take().unwrap_or_default();
+        let raw_path = match (pax_headers.get("path"), long_name.take()) {
+            (Some(path), _) => path.clone(),
+            (None, Some(name)) => String::from_utf8_lossy(trim_nul(&name)).into_owned(),
+            (None, None) => String::from_utf8_lossy(&header.path_bytes()).into_owned(),
+        };
+        let link_name = match (pax_linkpath.take(), long_link.take()) {
+            (Some(link), _) => Some(link),
+            (None, Some(link)) => Some(trim_nul(&link).to_vec()),
+            (None, None) => header.link_name_bytes().map(|link| link.into_owned()),
+        };
+
+        // Normalise to the "./usr/bin/foo" form create_layer looks up; the tar
+        // crate strips the leading "./" from the names we write.
+        let trimmed = raw_path
+            .trim_start_matches("./")
+            .trim_start_matches('/')
+            .trim_end_matches('/');
+        if trimmed.is_empty() || trimmed == "." {
+            continue; // Layer root
+        }
+        let path_str = format!("./{}", trimmed);
+        let (dirname, basename) = split_path(&path_str);
+
+        if basename == ".wh..wh..opq" {
+            parsed.opaque_whiteouts.push(dirname.into_owned());
+        } else if let Some(real_name) = basename.strip_prefix(".wh.") {
+            let full_path = if dirname.is_empty() {
+                real_name.to_string()
+            } else {
+                format!("{}/{}", dirname, real_name)
+            };
+            parsed.file_whiteouts.push(full_path);
+        } else {
+            let entry = (|| -> Result<LowerEntry> {
+                Ok(LowerEntry {
+                    symlink_target: link_name.filter(|_| entry_type == EntryType::Symlink),
+                    uid: header.uid().context("invalid uid field")?,
+                    gid: header.gid().context("invalid gid field")?,
+                    mtime: header.mtime().context("invalid mtime field")?,
+                    size: match pax_size {
+                        Some(size) => size,
+                        None => header.size().context("invalid size field")?,
+                    },
+                    mode: header.mode().context("invalid mode field")?,
+                    entry_type: entry_type.as_byte(),
+                    pax_headers,
+                })
+            })()
+            .with_context(context)?;
+            parsed.entries.push((path_str, entry));
+        }
+    }
+
+    Ok(parsed)
+}
+
+struct Parser<R> {
+    reader: R,
+    offset: u64,
+}
+
+impl<R: Read> Parser<R> {
+    /// Next 512-byte block, or None at a clean end of stream.
+    fn read_block(&mut self) -> Result<Option<[u8; BLOCK_SIZE as usize]>> {
+        let mut block = [0u8; BLOCK_SIZE as usize];
+        let mut filled = 0;
+        while filled < block.len() {
+            match self.reader.read(&mut block[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e).with_context(|| format!("reading tar header at offset {}", self.offset)),
+            }
+        }
+        match filled {
+            0 => Ok(None),
+            n if n < block.len() => bail!("truncated tar header at offset {}", self.offset),
+            _ => {
+                self.offset += BLOCK_SIZE;
+                Ok(Some(block))
+            }
+        }
+    }
+
+    /// Read the `size` bytes of entry data that follow a header, refusing
+    /// anything larger than `limit` before allocating.
+    fn read_data(&mut self, size: u64, limit: u64) -> Result<Vec<u8>> {
+        if size > limit {
+            bail!("extension header of {} bytes exceeds the {} byte limit", size, limit);
+        }
+        let mut data = vec![0u8; size as usize];
+        self.reader
+            .read_exact(&mut data)
+            .context("unexpected end of archive in entry data")?;
+        self.offset += size;
+        self.skip(padding(size))?;
+        Ok(data)
+    }
+
+    fn skip_data(&mut self, size: u64) -> Result<()> {
+        let padded = size
+            .checked_add(padding(size))
+            .with_context(|| format!("entry size {} out of range", size))?;
+        self.skip(padded)
+    }
+
+    fn skip(&mut self, len: u64) -> Result<()> {
+        let skipped = io::copy(&mut (&mut self.reader).take(len), &mut io::sink())?;
+        self.offset += skipped;
+        if skipped < len {
+            bail!("unexpected end of archive: {} of {} data bytes present", skipped, len);
+        }
+        Ok(())
+    }
+}
+
+/// Bytes of zero padding after `size` bytes of data, up to the next block.
+fn padding(size: u64) -> u64 {
+    (BLOCK_SIZE - size % BLOCK_SIZE) % BLOCK_SIZE
+}
+
+fn check_checksum(header: &Header) -> Result<()> {
+    let bytes = header.as_bytes();
+    // The checksum field itself counts as eight spaces
+    let sum = bytes[..148]
+        .iter()
+        .chain(&bytes[156..])
+        .fold(8 * u32::from(b' '), |sum, &b| sum + u32::from(b));
+    let stored = header.cksum().context("invalid checksum field")?;
+    if sum != stored {
+        bail!("header checksum mismatch");
+    }
+    Ok(())
+}
+
+/// PAX records of an extended header; malformed records are skipped.
+fn parse_pax(data: &[u8]) -> HashMap<String, String> {
+    tar::PaxExtensions::new(data)
+        .flatten()
+        .map(|ext| {
+            let key = ext.key().unwrap_or_default().to_string();
+            let value = ext.value().unwrap_or_default().to_string();
+            (key, value)
+        })
+        .collect()
+}
+
+/// Raw value of PAX record `key`.
+fn pax_value(data: &[u8], key: &str) -> Option<Vec<u8>> {
+    tar::PaxExtensions::new(data)
+        .flatten()
+        .find(|ext| ext.key_bytes() == key.as_bytes())
+        .map(|ext| ext.value_bytes().to_vec())
+}
+
+fn trim_nul(bytes: &[u8]) -> &[u8] {
+    let end = bytes.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+    &bytes[..end]
+}
@@ -0,0 +1,294 @@
+// Copyright (c) 2019, 2020 Codethink Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Walk a tar stream as it is written, for encoders that frame or index each
+//! file of a layer (eStargz, zstd:chunked).
+
+use std::io::{self, Write};
+
+use anyhow::{bail, Result};
+
+use crate::codec::{Encoder, Finished};
+
+const BLOCK: usize = 512;
+
+/// An entry of the stream, with its pax records and GNU long names applied
+pub struct Entry {
+    /// TOC type: "reg", "dir", "symlink", "hardlink", "char", "block" or
+    /// "fifo"; None for other tar types
+    pub kind: Option<&'static str>,
+    /// Path as written in the tar
+    pub name: String,
+    pub link_name: String,
+    pub size: u64,
+    pub mode: u32,
+    pub uid: u64,
+    pub gid: u64,
+    pub uname: String,
+    pub gname: String,
+    pub mtime: i64,
+    pub dev_major: u32,
+    pub dev_minor: u32,
+    /// `SCHILY.xattr.` records
+    pub xattrs: Vec<(String, Vec<u8>)>,
+}
+
+/// What a `Splitter` reports. Every byte written to it reaches exactly one of
+/// `metadata`, `content` or `trailer`, in order.
+pub trait Sink: Send {
+    /// Headers, extension entries and padding
+    fn metadata(&mut self, data: &[u8]) -> io::Result<()>;
+    /// An entry whose header was just passed to `metadata`
+    fn entry(&mut self, entry: Entry) -> io::Result<()>;
+    /// Contents of the last regular file passed to `entry`
+    fn content(&mut self, data: &[u8]) -> io::Result<()>;
+    /// The contents of a regular file with a size are complete
+    fn file_end(&mut self) -> io::Result<()>;
+    /// The end-of-archive blocks and anything after them
+    fn trailer(&mut self, data: &[u8]) -> io::Result<()>;
+    fn finish(self) -> Result<Finished>;
+}
+
+enum State {
+    Header,
+    /// Contents of a pax or GNU long name entry, applying to the next entry
+    Extension { kind: u8, remaining: u64, padding: u64 },
+    /// Contents of a regular file
+    Content { remaining: u64, padding: u64 },
+    /// Padding, and contents of entries other than regular files
+    Skip { remaining: u64 },
+    /// After the end-of-archive blocks
+    End,
+}
+
+/// Parses the tar written to it and reports it to a `Sink`.
+pub struct Splitter<S> {
+    sink: S,
+    state: State,
+    block: Vec<u8>,
+    extension: Vec<u8>,
+    pax: Vec<(String, Vec<u8>)>,
+    long_name: Option<Vec<u8>>,
+    long_link: Option<Vec<u8>>,
+}
+
+impl<S: Sink> Splitter<S> {
+    pub fn new(sink: S) -> Self {
+        Splitter {
+            sink,
+            state: State::Header,
+            block: Vec::with_capacity(BLOCK),
+            extension: Vec::new(),
+            pax: Vec::new(),
+            long_name: None,
+            long_link: None,
+        }
+    }
+
+    fn header(&mut self) -> io::Result<()> {
+        let block = std::mem::take(&mut self.block);
+        if block.iter().all(|&b| b == 0) {
+            self.state = State::End;
+            return self.sink.trailer(&block);
+        }
+        self.sink.metadata(&block)?;
+        let header = tar::Header::from_byte_slice(&block);
+        let padding = |size: u64| (BLOCK as u64 - size % BLOCK as u64) % BLOCK as u64;
+        let kind = header.entry_type().as_byte();
+        if matches!(kind, b'x' | b'g' | b'L' | b'K') {
+            let size = header.entry_size()?;
+            self.extension.clear();
+            self.state = State::Extension { kind, remaining: size, padding: padding(size) };
+            return Ok(());
+        }
+        let entry = self.entry(header)?;
+        self.pax.clear();
+        self.long_name = None;
+        self.long_link = None;
+        let (size, padding) = (entry.size, padding(entry.size));
+        let regular = entry.kind == Some("reg");
+        self.sink.entry(entry)?;
+        self.state = if regular && size > 0 {
+            State::Content { remaining: size, padding }
+        } else {
+            State::Skip { remaining: size + padding }
+        };
+        Ok(())
+    }
+
+    fn pax_value(&self, key: &str) -> Option<&[u8]> {
+        self.pax.iter().rev().find(|(k, _)| k == key).map(|(_, v)| v.as_slice())
+    }
+
+    fn pax_number<T: std::str::FromStr>(&self, key: &str) -> io::Result<Option<T>> {
+        self.pax_value(key)
+            .map(|value| String::from_utf8_lossy(value).parse().map_err(|_| invalid(key)))
+            .transpose()
+    }
+
+    fn extension_done(&mut self, kind: u8) -> io::Result<()> {
+        let data = std::mem::take(&mut self.extension);
+        match kind {
+            b'x' => {
+                for record in tar::PaxExtensions::new(&data) {
+                    let record = record?;
+                    let key = record.key().map_err(|_| invalid("pax key"))?;
+                    self.pax.push((key.to_string(), record.value_bytes().to_vec()));
+                }
+            }
+            b'L' => self.long_name = Some(trim_nul(&data).to_vec()),
+            b'K' => self.long_link = Some(trim_nul(&data).to_vec()),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn entry(&self, header: &tar::Header) -> io::Result<Entry> {
+        let kind = match header.entry_type() {
+            tar::EntryType::Regular | tar::EntryType::Continuous => Some("reg"),
+            tar::EntryType::Directory => Some("dir"),
+            tar::EntryType::Symlink => Some("symlink"),
+            tar::EntryType::Link => Some("hardlink"),
+            tar::EntryType::Char => Some("char"),
+            tar::EntryType::Block => Some("block"),
+            tar::EntryType::Fifo => Some("fifo"),
+            _ => None,
+        };
+        let name = self
+            .pax_value("path")
+            .map(<[u8]>::to_vec)
+            .or_else(|| self.long_name.clone())
+            .unwrap_or_else(|| header.path_bytes().into_owned());
+        let link_name = self
+            .pax_value("linkpath")
+            .map(<[u8]>::to_vec)
+            .or_else(|| self.long_link.clone())
+            .or_else(|| header.link_name_bytes().map(|l| l.into_owned()))
+            .unwrap_or_default();
+        let text = |bytes: Option<&[u8]>| bytes.map(|b| String::from_utf8_lossy(b).into_owned()).unwrap_or_default();
+        let mtime = match self.pax_number::<f64>("mtime")? {
+            Some(mtime) => mtime as i64,
+            None => header.mtime()? as i64,
+        };
+        let device = matches!(kind, Some("char" | "block"));
+        Ok(Entry {
+            kind,
+            name: String::from_utf8_lossy(&name).into_owned(),
+            link_name: String::from_utf8_lossy(&link_name).into_owned(),
+            size: match self.pax_number("size")? {
+                Some(size) => size,
+                None => header.entry_size()?,
+            },
+            mode: header.mode()?,
+            uid: self.pax_number("uid")?.map_or_else(|| header.uid(), Ok)?,
+            gid: self.pax_number("gid")?.map_or_else(|| header.gid(), Ok)?,
+            uname: text(self.pax_value("uname").or(header.username_bytes())),
+            gname: text(self.pax_value("gname").or(header.groupname_bytes())),
+            mtime,
+            dev_major: if device { header.device_major()?.unwrap_or(0) } else { 0 },
+            dev_minor: if device { header.device_minor()?.unwrap_or(0) } else { 0 },
+            xattrs: self
+                .pax
+                .iter()
+                .filter_map(|(key, value)| Some((key.strip_prefix("SCHILY.xattr.")?.to_string(), value.clone())))
+                .collect(),
+        })
+    }
+}
+
+impl<S: Sink> Write for Splitter<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut rest = buf;
+        while !rest.is_empty() {
+            match self.state {
+                State::End => {
+                    self.sink.trailer(rest)?;
+                    break;
+                }
+                State::Header => {
+                    let n = (BLOCK - self.block.len()).min(rest.len());
+                    self.block.extend_from_slice(&rest[..n]);
+                    rest = &rest[n..];
+                    if self.block.len() == BLOCK {
+                        self.header()?;
+                    }
+                }
+                State::Extension { kind, remaining, padding } => {
+                    let n = (remaining + padding).min(rest.len() as u64) as usize;
+                    self.sink.metadata(&rest[..n])?;
+                    let data = remaining.min(n as u64) as usize;
+                    self.extension.extend_from_slice(&rest[..data]);
+                    rest = &rest[n..];
+                    let (remaining, padding) = (remaining - data as u64, padding - (n - data) as u64);
+                    if remaining + padding == 0 {
+                        self.state = State::Header;
+                        self.extension_done(kind)?;
+                    } else {
+                        self.state = State::Extension { kind, remaining, padding };
+                    }
+                }
+                State::Content { remaining, padding } => {
+                    let n = remaining.min(rest.len() as u64) as usize;
+                    self.sink.content(&rest[..n])?;
+                    rest = &rest[n..];
+                    self.state = match remaining - n as u64 {
+                        0 => {
+                            self.sink.file_end()?;
+                            State::Skip { remaining: padding }
+                        }
+                        remaining => State::Content { remaining, padding },
+                    };
+                }
+                State::Skip { remaining } => {
+                    let n = remaining.min(rest.len() as u64) as usize;
+                    self.sink.metadata(&rest[..n])?;
+                    rest = &rest[n..];
+                    self.state = match remaining - n as u64 {
+                        0 => State::Header,
+                        remaining => State::Skip { remaining },
+                    };
+                }
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<S: Sink> Encoder for Splitter<S> {
+    fn finish(self: Box<Self>) -> Result<Finished> {
+        if !matches!(self.state, State::Header | State::End) || !self.block.is_empty() {
+            bail!("layer tar ended inside an entry");
+        }
+        self.sink.finish()
+    }
+}
+
+fn trim_nul(data: &[u8]) -> &[u8] {
+    let end = data.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+    &data[..end]
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid {} in layer tar", what))
+}
@@ -0,0 +1,306 @@
+// Copyright (c) 2019, 2020 Codethink Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! `build-oci unpack`: extract the root file system of an image by applying
+//! its layers in order, as a container runtime would. Whiteouts and opaque
+//! whiteouts remove what lower layers added; modes, mtimes, xattrs and hard
+//! links are restored, and ownership when running as root.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io::{self, BufReader};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, Context, Result};
+use clap::ArgMatches;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+use crate::blob::IO_BUF_MEDIUM;
+use crate::cli;
+use crate::layer_builder::PAX_HEADER_XATTR;
+use crate::layout::{descriptor_digest, parse_image_ref, Layout};
+
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+const WHITEOUT_PREFIX: &str = ".wh.";
+
+/// `build-oci unpack <layout>[:<ref>] <dest>`: apply every layer of an image
+/// into `dest`, which must be missing or empty.
+pub fn run(args: &ArgMatches) -> Result<()> {
+    let image_ref = cli::required(args, "image");
+    let dest = Path::new(cli::required(args, "dest"));
+
+    let (path, reference) = parse_image_ref(image_ref);
+    let layout = Layout::open(&path)?;
+    let desc = layout.resolve(reference.as_deref())?;
+    let manifest = layout.read_json(descriptor_digest(&desc)?)?;
+    if manifest.get("artifactType").is_some() {
+        bail!("{} is an artifact, not an image with a root file system", descriptor_digest(&desc)?);
+    }
+    let layers = manifest["layers"].as_array().cloned().unwrap_or_default();
+
+    if dest.exists() && fs::read_dir(dest)?.next().is_some() {
+        bail!("{} is not empty", dest.display());
+    }
+    for layer in &layers {
+        verify_layer(&layout, layer)?;
+    }
+    fs::create_dir_all(dest).with_context(|| format!("Creating {}", dest.display()))?;
+    let dest = dest.canonicalize()?;
+
+    let mut unpacker = Unpacker::new(dest.clone());
+    for (i, layer) in layers.iter().enumerate() {
+        unpacker
+            .apply(&layout, layer)
+            .with_context(|| format!("Unpacking layer {} ({})", i, layer["digest"].as_str().unwrap_or("?")))?;
+    }
+    unpacker.finish()?;
+
+    info!(layers = layers.len(), digest = descriptor_digest(&desc)?, "unpacked image");
+    println!("{}: {} layers unpacked", dest.display(), layers.len());
+    Ok(())
+}
+
+struct Unpacker {
+    dest: PathBuf,
+    /// Ownership and non-user xattrs can only be restored by root
+    privileged: bool,
+    /// Mode and mtime of every directory, applied once nothing more is
+    /// written into them
+    dirs: BTreeMap<PathBuf, (u32, u64)>,
+    /// xattrs skipped for lack of privileges, reported once at the end
+    skipped_xattrs: usize,
+}
+
+impl Unpacker {
+    fn new(dest: PathBuf) -> Self {
+        Unpacker {
+            dest,
+            privileged: is_root(),
+            dirs: BTreeMap::new(),
+            skipped_xattrs: 0,
+        }
+    }
+
+    fn apply(&mut self, layout: &Layout, layer: &Value) -> Result<()> {
+        let mut archive = tar::Archive::new(layout.open_layer(layer)?);
+        archive.set_preserve_permissions(true);
+        archive.set_preserve_mtime(true);
+        archive.set_preserve_ownerships(self.privileged);
+        archive.set_unpack_xattrs(false);
+        archive.set_overwrite(true);
+
+        // Paths this layer wrote, which its own opaque whiteouts must keep
+        let mut written: HashSet<PathBuf> = HashSet::new();
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let rel = relative_path(&entry.path()?)?;
+            let name = rel.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+
+            if name == OPAQUE_WHITEOUT {
+                let dir = rel.parent().unwrap_or(Path::new(""));
+                let target = self.target(dir)?;
+                if target.symlink_metadata().is_ok_and(|m| m.is_dir()) {
+                    for child in fs::read_dir(&target)? {
+                        let child_rel = dir.join(child?.file_name());
+                        if !written.contains(&child_rel) {
+                            self.remove(&child_rel)?;
+                        }
+                    }
+                }
+                continue;
+            }
+            if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX) {
+                self.remove(&rel.with_file_name(hidden))?;
+                continue;
+            }
+
+            let target = self.target(&rel)?;
+            let entry_type = entry.header().entry_type();
+            // Only directories are updated in place; anything else replaces
+            // what is there, which hard links would otherwise fail on
+            if let Ok(existing) = target.symlink_metadata() {
+                if !(entry_type.is_dir() && existing.is_dir()) {
+                    self.remove(&rel)?;
+                }
+            }
+
+            let xattrs: Vec<(String, Vec<u8>)> = match entry.pax_extensions()? {
+                Some(exts) => exts
+                    .filter_map(|ext| ext.ok())
+                    .filter_map(|ext| {
+                        let key = ext.key().ok()?.strip_prefix(PAX_HEADER_XATTR)?.to_string();
+                        Some((key, ext.value_bytes().to_vec()))
+                    })
+                    .collect(),
+                None => Vec::new(),
+            };
+            let mode = entry.header().mode()?;
+            let mtime = entry.header().mtime()?;
+
+            if !entry.unpack_in(&self.dest)? {
+                bail!("{} points outside the destination", rel.display());
+            }
+            if entry_type.is_dir() {
+                self.dirs.insert(rel.clone(), (mode, mtime));
+                // Keep read-only directories writable until the end
+                make_writable(&target)?;
+            }
+            if !entry_type.is_symlink() {
+                for (key, value) in xattrs {
+                    if !self.privileged && !key.starts_with("user.") {
+                        self.skipped_xattrs += 1;
+                        continue;
+                    }
+                    xattr::set(&target, &key, &value)
+                        .with_context(|| format!("Setting xattr {} on {}", key, rel.display()))?;
+                }
+            }
+            written.insert(rel);
+        }
+        Ok(())
+    }
+
+    /// Path of `rel` under the destination, refused when a directory on
+    /// the way is a symlink: whatever follows it may be outside.
+    fn target(&self, rel: &Path) -> Result<PathBuf> {
+        let mut target = self.dest.clone();
+        let mut parts = rel.components().peekable();
+        while let Some(part) = parts.next() {
+            target.push(part);
+            if parts.peek().is_none() {
+                break;
+            }
+            match target.symlink_metadata() {
+                Ok(metadata) if metadata.file_type().is_symlink() => {
+                    bail!("Refusing layer entry {}: {} is a symlink", rel.display(), target.display())
+                }
+                Ok(_) => {}
+                // Nothing is beneath a missing directory yet
+                Err(e) if e.kind() == io::ErrorKind::NotFound => break,
+                Err(e) => return Err(e).with_context(|| format!("Reading {}", target.display())),
+            }
+        }
+        Ok(self.dest.join(rel))
+    }
+
+    /// Remove `rel` and everything under it, if present.
+    fn remove(&mut self, rel: &Path) -> Result<()> {
+        let target = self.target(rel)?;
+        let Ok(metadata) = target.symlink_metadata() else {
+            return Ok(());
+        };
+        if metadata.is_dir() {
+            fs::remove_dir_all(&target)?;
+            self.dirs.retain(|dir, _| !dir.starts_with(rel));
+        } else {
+            fs::remove_file(&target)?;
+        }
+        Ok(())
+    }
+
+    /// Restore directory modes and mtimes, children before their parents.
+    fn finish(self) -> Result<()> {
+        for (rel, (mode, mtime)) in self.dirs.iter().rev() {
+            let target = self.target(rel)?;
+            fs::File::open(&target)?.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(*mtime))?;
+            set_mode(&target, *mode)?;
+        }
+        if self.skipped_xattrs > 0 {
+            warn!(count = self.skipped_xattrs, "only user.* xattrs can be restored without root");
+        }
+        Ok(())
+    }
+}
+
+/// Check that the layer blob hashes to its digest, before anything is
+/// written: a layer whose entries were swapped could point anywhere.
+fn verify_layer(layout: &Layout, layer: &Value) -> Result<()> {
+    let digest = descriptor_digest(layer)?;
+    let Some(hash) = digest.strip_prefix("sha256:") else {
+        bail!("Cannot verify layer {}: only sha256 is supported", digest);
+    };
+    let path = layout.blob_path(digest)?;
+    let file = fs::File::open(&path).with_context(|| format!("Opening {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    let size = io::copy(&mut BufReader::with_capacity(IO_BUF_MEDIUM, file), &mut hasher)?;
+    let actual = format!("{:x}", hasher.finalize());
+    if actual != hash {
+        bail!("Layer {} does not match its digest: it has sha256:{}", digest, actual);
+    }
+    if let Some(expected) = layer["size"].as_u64().filter(|&expected| expected != size) {
+        bail!("Layer {} is {} bytes, but its descriptor gives size {}", digest, size, expected);
+    }
+    Ok(())
+}
+
+/// Normalise a layer entry path relative to the destination, refusing any
+/// that could escape it.
+fn relative_path(path: &Path) -> Result<PathBuf> {
+    let mut rel = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => rel.push(part),
+            Component::CurDir | Component::RootDir => {}
+            _ => bail!("Refusing layer entry {}", path.display()),
+        }
+    }
+    Ok(rel)
+}
+
+#[cfg(unix)]
+fn is_root() -> bool {
+    // SAFETY: geteuid has no preconditions and cannot fail
+    unsafe { libc::geteuid() == 0 }
+}
+
+#[cfg(not(unix))]
+fn is_root() -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o7777))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn make_writable(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = path.metadata()?.permissions().mode();
+    if mode & 0o700 != 0o700 {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode | 0o700))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn make_writable(_path: &Path) -> Result<()> {
+    Ok(())
+}
@@ -20,7 +20,7 @@
 
 use std::fs::File;
 use std::io::{self, Write};
-use std::sync::{Arc, Mutex};
+use std::path::{Path, PathBuf};
 use sha2::{Digest, Sha256};
 
 /// Hint to the kernel for sequential file access (Linux optimization).
@@ -39,6 +39,26 @@ pub fn advise_sequential(_file: &File) {
     // No-op on non-Linux platforms
 }
 
+/// Open a layer source file for reading, with O_NOATIME when `noatime` is
+/// set. The kernel only allows the flag to the file's owner (or with
+/// CAP_FOWNER); other files are opened normally.
+#[cfg(target_os = "linux")]
+pub fn open_source(path: &Path, noatime: bool) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    if noatime {
+        match std::fs::OpenOptions::new().read(true).custom_flags(libc::O_NOATIME).open(path) {
+            Err(e) if e.raw_os_error() == Some(libc::EPERM) => {}
+            result => return result,
+        }
+    }
+    File::open(path)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn open_source(path: &Path, _noatime: bool) -> io::Result<File> {
+    File::open(path)
+}
+
 /// A writer wrapper that computes SHA256 hash while writing.
 /// This eliminates a separate hashing pass over the data.
 ///
@@ -78,28 +98,59 @@ impl<W: Write> Write for HashingWriter<W> {
     }
 }
 
-/// A writer that updates a shared SHA256 hasher.
-/// Used when the writer ownership is consumed by a third-party library (like gzp)
-/// but we still need the hash of the data written to it.
-pub struct SharedHashWriter<W: Write> {
+pub fn get_source_date_epoch() -> Option<u64> {
+    std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+}
+
+/// Per-user cache directory for build-oci: `$XDG_CACHE_HOME/build-oci`,
+/// falling back to `~/.cache/build-oci`.
+pub fn cache_dir() -> Option<PathBuf> {
+    std::env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .map(|dir| dir.join("build-oci"))
+}
+
+/// Format a byte count for humans (e.g. "12.3 MB").
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1000.0 && unit < UNITS.len() - 1 {
+        size /= 1000.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// A writer wrapper that counts the bytes passed on to `inner`.
+pub struct CountingWriter<W: Write> {
     inner: W,
-    hasher: Arc<Mutex<Sha256>>,
+    count: u64,
 }
 
-impl<W: Write> SharedHashWriter<W> {
-    pub fn new(inner: W, hasher: Arc<Mutex<Sha256>>) -> Self {
-        Self { inner, hasher }
+impl<W: Write> CountingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        CountingWriter { inner, count: 0 }
+    }
+
+    /// Return the inner writer and the number of bytes written to it.
+    pub fn finish(self) -> (W, u64) {
+        (self.inner, self.count)
     }
 }
 
-impl<W: Write> Write for SharedHashWriter<W> {
+impl<W: Write> Write for CountingWriter<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let n = self.inner.write(buf)?;
-        // Handle poisoned mutex gracefully - in I/O context, convert to io::Error
-        self.hasher
-            .lock()
-            .map_err(|_| io::Error::new(io::ErrorKind::Other, "hasher mutex poisoned"))?
-            .update(&buf[..n]);
+        self.count += n as u64;
         Ok(n)
     }
 
@@ -107,9 +158,3 @@ impl<W: Write> Write for SharedHashWriter<W> {
         self.inner.flush()
     }
 }
-
-pub fn get_source_date_epoch() -> Option<u64> {
-    std::env::var("SOURCE_DATE_EPOCH")
-        .ok()
-        .and_then(|v| v.parse::<u64>().ok())
-}
@@ -0,0 +1,65 @@
+// Copyright (c) 2019, 2020 Codethink Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+use clap::ArgMatches;
+
+use crate::cli;
+use crate::error::{ErrorCategory, ResultExt};
+use crate::integrity;
+use crate::lazy_pull;
+use crate::signing;
+
+/// `build-oci verify <layout> --blobs` (the default without options):
+/// re-hash every blob and check the diff_ids and history of every image.
+/// `--signatures`: check the GPG-signed digest manifest of a layout and
+/// every file it lists. `--lazy-pull`: check the annotations of eStargz and
+/// zstd:chunked layers.
+pub fn run(args: &ArgMatches) -> Result<()> {
+    let layout_path = cli::required(args, "layout");
+    let signatures = args.get_flag("signatures");
+    let lazy_pull = args.get_flag("lazy-pull");
+    let blobs = args.get_flag("blobs") || (!signatures && !lazy_pull);
+    let homedir = args.get_one::<PathBuf>("gpg-homedir").map(PathBuf::as_path);
+
+    if blobs {
+        let (count, problems) = integrity::check_layout(Path::new(layout_path))?;
+        if !problems.is_empty() {
+            return Err(anyhow::anyhow!("{} problem(s):\n  {}", problems.len(), problems.join("\n  ")))
+                .category(ErrorCategory::DigestMismatch);
+        }
+        println!("{}: {} blobs OK", layout_path, count);
+    }
+
+    if signatures {
+        let count = signing::verify_layout(Path::new(layout_path), homedir)?;
+        println!("{}: signature OK, {} files verified", layout_path, count);
+    }
+    if lazy_pull {
+        let (count, problems) = lazy_pull::check_layout(Path::new(layout_path))?;
+        if !problems.is_empty() {
+            bail!("{} lazy-pull problem(s):\n  {}", problems.len(), problems.join("\n  "));
+        }
+        println!("{}: {} lazy-pull layers OK", layout_path, count);
+    }
+    Ok(())
+}
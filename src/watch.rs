@@ -0,0 +1,309 @@
+// Copyright (c) 2019, 2020 Codethink Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! `build-oci watch`: build the manifest, then rebuild the images whose
+//! `layer:` directory changes. The process stays up between rebuilds, so
+//! extracted parents and the analysis of their layers are reused.
+
+use std::collections::{BTreeSet, HashMap};
+use std::ffi::{CString, OsStr};
+use std::fs;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use clap::ArgMatches;
+use tracing::{debug, error, warn};
+
+use crate::config::BuildManifest;
+use crate::error::{ErrorCategory, ResultExt};
+use crate::image_builder;
+use crate::signing;
+use crate::GlobalConfig;
+
+/// Quiet period after the last change before rebuilding.
+const DEFAULT_DEBOUNCE_MS: u64 = 200;
+
+const WATCH_MASK: u32 = libc::IN_CREATE
+    | libc::IN_DELETE
+    | libc::IN_MODIFY
+    | libc::IN_ATTRIB
+    | libc::IN_MOVED_FROM
+    | libc::IN_MOVED_TO;
+
+/// A watched document and the last manifest descriptor built for each image.
+struct Document<'a> {
+    manifest: &'a BuildManifest,
+    global_conf: GlobalConfig,
+    descriptors: Vec<Option<serde_json::Value>>,
+}
+
+impl Document<'_> {
+    /// Rebuild `images`, and the images whose `subject:` or `parent.build`
+    /// is rebuilt, then rewrite the layout's index. An image that fails
+    /// keeps its previous build in the index.
+    fn rebuild(&mut self, images: &[usize]) -> Result<()> {
+        let start = Instant::now();
+        let mut built = 0;
+        let mut order = Vec::new();
+        for i in image_builder::build_waves(&self.manifest.images).into_iter().flatten() {
+            let mut dependencies = self.manifest.images[i].dependencies();
+            if images.contains(&i) || dependencies.any(|j| order.contains(&j)) {
+                order.push(i);
+            }
+        }
+        for &i in &order {
+            let image = &self.manifest.images[i];
+            let subject = image.subject.and_then(|s| {
+                let descriptor = self.descriptors[s].as_ref()?;
+                Some(image_builder::Subject { image: &self.manifest.images[s], descriptor })
+            });
+            if image.subject.is_some() && subject.is_none() {
+                error!("{}: its subject was not built", image_builder::image_label(i, image));
+                continue;
+            }
+            let built_parent = image.parent.as_ref().and_then(|p| p.built);
+            let parent = built_parent.and_then(|p| self.descriptors[p].as_ref());
+            if built_parent.is_some() && parent.is_none() {
+                error!("{}: its parent was not built", image_builder::image_label(i, image));
+                continue;
+            }
+            match image_builder::build_isolated(&self.global_conf, i, image, subject, parent) {
+                Ok(desc) => {
+                    self.descriptors[i] = Some(desc);
+                    built += 1;
+                }
+                Err(e) => error!("{:#}", e),
+            }
+        }
+        let entries = image_builder::index_entries(&self.global_conf, &self.manifest.images, &self.descriptors)?;
+        image_builder::write_index(&self.global_conf, &entries, self.manifest.annotations.as_ref())?;
+        if let Some(ref gpg_sign) = self.manifest.gpg_sign {
+            signing::sign_layout(Path::new(&self.global_conf.output), gpg_sign)?;
+        }
+        println!(
+            "{}: built {} of {} image(s) in {:.2}s",
+            self.global_conf.output,
+            built,
+            order.len(),
+            start.elapsed().as_secs_f64()
+        );
+        Ok(())
+    }
+}
+
+/// `build-oci watch [--debounce-ms <ms>]`: `configs` are the resolved
+/// settings of `documents`, in order. Runs until interrupted.
+pub fn run(documents: &[BuildManifest], configs: Vec<GlobalConfig>, args: &ArgMatches) -> Result<()> {
+    let debounce_ms = args.get_one::<u64>("debounce-ms").copied();
+    let debounce = Duration::from_millis(debounce_ms.unwrap_or(DEFAULT_DEBOUNCE_MS));
+    let mut documents: Vec<Document> = documents
+        .iter()
+        .zip(configs)
+        .map(|(manifest, mut global_conf)| {
+            // A file edited in place keeps its xattrs, so a stored checksum
+            // would outlive the contents it was computed from
+            if global_conf.write_checksum_xattrs {
+                warn!(output = %global_conf.output, "write-checksum-xattrs is ignored by watch");
+                global_conf.write_checksum_xattrs = false;
+            }
+            Document {
+                manifest,
+                global_conf,
+                descriptors: vec![None; manifest.images.len()],
+            }
+        })
+        .collect();
+
+    // Layouts may live inside a layer directory; their writes are not changes
+    let outputs: Vec<PathBuf> = documents
+        .iter()
+        .map(|doc| {
+            let output = Path::new(&doc.global_conf.output);
+            output.canonicalize().unwrap_or_else(|_| output.to_path_buf())
+        })
+        .collect();
+    let mut watcher = Watcher::new(outputs)?;
+    // (document, image, canonical layer directory)
+    let mut layers = Vec::new();
+    for (d, doc) in documents.iter().enumerate() {
+        for (i, image) in doc.manifest.images.iter().enumerate() {
+            let entries = image.layers.iter().flatten().map(|entry| entry.dir());
+            for layer in image.layer.as_deref().into_iter().chain(entries) {
+                let dir = layer
+                    .canonicalize()
+                    .with_context(|| format!("Watching {}", layer.display()))
+                    .category(ErrorCategory::Config)?;
+                watcher.add_tree(&dir)?;
+                layers.push((d, i, dir));
+            }
+        }
+    }
+    if layers.is_empty() {
+        return Err(anyhow!("No image has a layer: or layers: directory to watch")).category(ErrorCategory::Config);
+    }
+
+    // Watches are in place first, so changes made during these builds are not missed
+    for doc in &mut documents {
+        doc.global_conf.priority.apply().category(ErrorCategory::Config)?;
+        let images: Vec<usize> = (0..doc.manifest.images.len()).collect();
+        doc.rebuild(&images)?;
+    }
+    println!("Watching {} layer director{} for changes", layers.len(), if layers.len() == 1 { "y" } else { "ies" });
+
+    loop {
+        let changed = watcher.wait(debounce)?;
+        let affected: BTreeSet<(usize, usize)> = layers
+            .iter()
+            .filter(|(_, _, dir)| changed.iter().any(|path| path.starts_with(dir)))
+            .map(|(d, i, _)| (*d, *i))
+            .collect();
+        for (d, doc) in documents.iter_mut().enumerate() {
+            let images: Vec<usize> = affected.iter().filter(|(ad, _)| *ad == d).map(|(_, i)| *i).collect();
+            if !images.is_empty() {
+                doc.rebuild(&images)?;
+            }
+        }
+    }
+}
+
+/// Recursive inotify watch over directory trees. inotify watches single
+/// directories, so directories created later are added as they appear.
+struct Watcher {
+    fd: OwnedFd,
+    dirs: HashMap<i32, PathBuf>,
+    /// Trees that are neither watched nor reported
+    skip: Vec<PathBuf>,
+}
+
+impl Watcher {
+    fn new(skip: Vec<PathBuf>) -> Result<Self> {
+        // SAFETY: plain syscall; the descriptor is owned from here on
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error()).context("Starting inotify");
+        }
+        Ok(Watcher {
+            // SAFETY: `fd` is a new descriptor nothing else owns
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+            dirs: HashMap::new(),
+            skip,
+        })
+    }
+
+    /// Watch `dir` and every directory below it.
+    fn add_tree(&mut self, dir: &Path) -> Result<()> {
+        if self.skip.iter().any(|skip| dir.starts_with(skip)) {
+            return Ok(());
+        }
+        let path = CString::new(dir.as_os_str().as_bytes())?;
+        // SAFETY: `path` is a valid NUL-terminated string
+        let wd = unsafe { libc::inotify_add_watch(self.fd.as_raw_fd(), path.as_ptr(), WATCH_MASK) };
+        if wd < 0 {
+            return Err(io::Error::last_os_error()).with_context(|| format!("Watching {}", dir.display()));
+        }
+        self.dirs.insert(wd, dir.to_path_buf());
+        for entry in fs::read_dir(dir).with_context(|| format!("Reading {}", dir.display()))? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                self.add_tree(&entry.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Block until something changes, then until nothing has changed for
+    /// `debounce`, and return the paths that changed.
+    fn wait(&mut self, debounce: Duration) -> Result<Vec<PathBuf>> {
+        let mut changed = Vec::new();
+        while changed.is_empty() || self.poll(debounce)? {
+            self.read(&mut changed)?;
+        }
+        Ok(changed)
+    }
+
+    /// Whether events arrive within `timeout`.
+    fn poll(&self, timeout: Duration) -> Result<bool> {
+        let mut pollfd = libc::pollfd {
+            fd: self.fd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout = timeout.as_millis().min(i32::MAX as u128) as i32;
+        // SAFETY: `pollfd` is a single valid entry
+        match unsafe { libc::poll(&mut pollfd, 1, timeout) } {
+            n if n >= 0 => Ok(n > 0),
+            _ => match io::Error::last_os_error() {
+                e if e.kind() == io::ErrorKind::Interrupted => Ok(true),
+                e => Err(e).context("Waiting for inotify events"),
+            },
+        }
+    }
+
+    /// Read one batch of events (blocking until there is one).
+    fn read(&mut self, changed: &mut Vec<PathBuf>) -> Result<()> {
+        const HEADER: usize = std::mem::size_of::<libc::inotify_event>();
+        let mut buf = vec![0u8; 64 * 1024];
+        // SAFETY: `buf` is writable for its whole length
+        let n = unsafe { libc::read(self.fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+        if n < 0 {
+            return match io::Error::last_os_error() {
+                e if e.kind() == io::ErrorKind::Interrupted => Ok(()),
+                e => Err(e).context("Reading inotify events"),
+            };
+        }
+        let buf = &buf[..n as usize];
+
+        let mut pos = 0;
+        while pos + HEADER <= buf.len() {
+            let field = |offset: usize| u32::from_ne_bytes(buf[pos + offset..pos + offset + 4].try_into().unwrap());
+            let (wd, mask, len) = (field(0) as i32, field(4), field(12) as usize);
+            let name = &buf[pos + HEADER..(pos + HEADER + len).min(buf.len())];
+            let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+            pos += HEADER + len;
+
+            if mask & libc::IN_Q_OVERFLOW != 0 {
+                // Events were lost: treat every tree as changed
+                changed.extend(self.dirs.values().cloned());
+                continue;
+            }
+            if mask & libc::IN_IGNORED != 0 {
+                self.dirs.remove(&wd);
+                continue;
+            }
+            let Some(dir) = self.dirs.get(&wd) else { continue };
+            let path = dir.join(OsStr::from_bytes(name));
+            if self.skip.iter().any(|skip| path.starts_with(skip)) {
+                continue;
+            }
+            if mask & libc::IN_ISDIR != 0 && mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0 {
+                // The directory may already be gone again
+                if let Err(e) = self.add_tree(&path) {
+                    debug!(path = %path.display(), "not watching new directory: {:#}", e);
+                }
+            }
+            changed.push(path);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,339 @@
+// Copyright (c) 2019, 2020 Codethink Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! zstd:chunked layer output, as read by containers/storage: the contents of
+//! each file in a zstd frame of their own, then the table of contents and
+//! the tar-split data in skippable frames and a footer locating them, so
+//! podman can fetch only the files it lacks. Decompressors skip the
+//! skippable frames, so the layer's diff_id is that of the tar as written.
+
+use std::io::{self, BufRead, Read, Write};
+
+use anyhow::Result;
+use base64::Engine;
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use zstd::stream::write::Encoder as ZstdEncoder;
+
+use crate::codec::{self, Codec, CompressionOptions, Encoder, Finished};
+use crate::lazy_pull::{ANNOTATION_ZSTD_CHUNKED_CHECKSUM, ANNOTATION_ZSTD_CHUNKED_POSITION};
+use crate::tar_stream::{self, Sink, Splitter};
+
+/// Offset, lengths of the compressed tar-split data
+pub const ANNOTATION_TARSPLIT_POSITION: &str = "io.github.containers.zstd-chunked.tarsplit-position";
+
+const SKIPPABLE_FRAME_MAGIC: u32 = 0x184d_2a50;
+const FOOTER_MAGIC: &[u8] = b"GNUlInUx";
+/// The manifest type containers/storage reads
+const MANIFEST_TYPE_CRFS: u64 = 1;
+/// tar-split entry types
+const TAR_SPLIT_FILE: u8 = 1;
+const TAR_SPLIT_SEGMENT: u8 = 2;
+
+pub static ZSTD_CHUNKED: ZstdChunked = ZstdChunked;
+
+pub struct ZstdChunked;
+
+impl Codec for ZstdChunked {
+    fn name(&self) -> &'static str {
+        "zstd-chunked"
+    }
+
+    fn media_type_suffix(&self) -> &'static str {
+        "+zstd"
+    }
+
+    fn magic(&self) -> &'static [u8] {
+        codec::ZSTD.magic()
+    }
+
+    fn default_level(&self) -> Option<u32> {
+        codec::ZSTD.default_level()
+    }
+
+    // Parents already in zstd are copied as they are rather than chunked
+    fn for_parents(&self) -> &'static dyn Codec {
+        &codec::ZSTD
+    }
+
+    fn encoder(
+        &self,
+        writer: Box<dyn Write + Send>,
+        level: Option<u32>,
+        _threads: usize,
+        _options: &CompressionOptions,
+    ) -> Result<Box<dyn Encoder>> {
+        let level = level.or(self.default_level()).unwrap_or(1) as i32;
+        Ok(Box::new(Splitter::new(Writer::new(writer, level))))
+    }
+
+    fn decoder<'a>(&self, reader: Box<dyn BufRead + Send + 'a>) -> Result<Box<dyn Read + Send + 'a>> {
+        codec::ZSTD.decoder(reader)
+    }
+}
+
+/// The regular file being written
+struct File {
+    entry: usize,
+    name: String,
+    size: u64,
+    digest: Sha256,
+    crc: u64,
+}
+
+/// Writes a zstd frame per file and collects the table of contents and the
+/// tar-split data.
+struct Writer {
+    out: Box<dyn Write + Send>,
+    level: i32,
+    /// Open zstd frame
+    frame: Option<ZstdEncoder<'static, Vec<u8>>>,
+    /// Compressed bytes written to `out`
+    written: u64,
+    entries: Vec<Value>,
+    file: Option<File>,
+    /// tar-split JSON lines, and the bytes of the tar since its last entry
+    tar_split: Vec<u8>,
+    segment: Vec<u8>,
+    position: u64,
+}
+
+impl Writer {
+    fn new(out: Box<dyn Write + Send>, level: i32) -> Self {
+        Writer {
+            out,
+            level,
+            frame: None,
+            written: 0,
+            entries: Vec::new(),
+            file: None,
+            tar_split: Vec::new(),
+            segment: Vec::new(),
+            position: 0,
+        }
+    }
+
+    /// Write uncompressed bytes to the open frame, opening one if needed.
+    fn emit(&mut self, data: &[u8]) -> io::Result<()> {
+        let frame = match self.frame {
+            Some(ref mut frame) => frame,
+            None => self.frame.insert(ZstdEncoder::new(Vec::new(), self.level)?),
+        };
+        frame.write_all(data)?;
+        let compressed = frame.get_mut();
+        self.out.write_all(compressed)?;
+        self.written += compressed.len() as u64;
+        compressed.clear();
+        Ok(())
+    }
+
+    fn close_frame(&mut self) -> io::Result<()> {
+        if let Some(frame) = self.frame.take() {
+            let compressed = frame.finish()?;
+            self.out.write_all(&compressed)?;
+            self.written += compressed.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// Write `data` in a skippable frame, returning the offset of `data`.
+    fn skippable_frame(&mut self, data: &[u8]) -> io::Result<u64> {
+        self.out.write_all(&SKIPPABLE_FRAME_MAGIC.to_le_bytes())?;
+        self.out.write_all(&(data.len() as u32).to_le_bytes())?;
+        self.out.write_all(data)?;
+        self.written += 8 + data.len() as u64;
+        Ok(self.written - data.len() as u64)
+    }
+
+    /// Add a tar-split entry as a JSON line.
+    fn tar_split_entry(&mut self, mut entry: Value) -> io::Result<()> {
+        entry["position"] = self.position.into();
+        self.position += 1;
+        serde_json::to_writer(&mut self.tar_split, &entry)?;
+        self.tar_split.push(b'\n');
+        Ok(())
+    }
+
+    /// Record the tar bytes since the last entry as a tar-split segment.
+    fn flush_segment(&mut self) -> io::Result<()> {
+        if self.segment.is_empty() {
+            return Ok(());
+        }
+        let payload = base64::engine::general_purpose::STANDARD.encode(std::mem::take(&mut self.segment));
+        self.tar_split_entry(json!({"type": TAR_SPLIT_SEGMENT, "payload": payload}))
+    }
+}
+
+impl Sink for Writer {
+    fn metadata(&mut self, data: &[u8]) -> io::Result<()> {
+        self.segment.extend_from_slice(data);
+        self.emit(data)
+    }
+
+    fn entry(&mut self, entry: tar_stream::Entry) -> io::Result<()> {
+        self.flush_segment()?;
+        let regular = entry.kind == Some("reg") && entry.size > 0;
+        if !regular {
+            let mut file = json!({"type": TAR_SPLIT_FILE, "name": entry.name, "payload": null});
+            if entry.size > 0 {
+                file["size"] = entry.size.into();
+            }
+            self.tar_split_entry(file)?;
+        }
+        let Some(kind) = entry.kind else {
+            return Ok(());
+        };
+
+        let mut toc = Map::new();
+        toc.insert("type".into(), kind.into());
+        toc.insert("name".into(), entry.name.clone().into());
+        if !entry.link_name.is_empty() {
+            toc.insert("linkName".into(), entry.link_name.into());
+        }
+        let numbers = [("mode", entry.mode as u64), ("size", entry.size), ("uid", entry.uid), ("gid", entry.gid)];
+        for (key, value) in numbers.into_iter().filter(|&(_, value)| value != 0) {
+            toc.insert(key.into(), value.into());
+        }
+        if let Some(time) = chrono::DateTime::from_timestamp(entry.mtime, 0) {
+            toc.insert("modtime".into(), time.to_rfc3339_opts(chrono::SecondsFormat::Secs, true).into());
+        }
+        for (key, value) in [("devMajor", entry.dev_major), ("devMinor", entry.dev_minor)] {
+            if value != 0 {
+                toc.insert(key.into(), value.into());
+            }
+        }
+        if !entry.xattrs.is_empty() {
+            let xattrs: Map<String, Value> = entry
+                .xattrs
+                .iter()
+                .map(|(name, value)| (name.clone(), base64::engine::general_purpose::STANDARD.encode(value).into()))
+                .collect();
+            toc.insert("xattrs".into(), xattrs.into());
+        }
+        if regular {
+            // Contents start a frame of their own
+            self.close_frame()?;
+            toc.insert("offset".into(), self.written.into());
+            self.file = Some(File {
+                entry: self.entries.len(),
+                name: entry.name,
+                size: entry.size,
+                digest: Sha256::new(),
+                crc: 0,
+            });
+        }
+        self.entries.push(toc.into());
+        Ok(())
+    }
+
+    fn content(&mut self, data: &[u8]) -> io::Result<()> {
+        if let Some(ref mut file) = self.file {
+            file.digest.update(data);
+            file.crc = crc64_iso(file.crc, data);
+        }
+        self.emit(data)
+    }
+
+    fn file_end(&mut self) -> io::Result<()> {
+        self.close_frame()?;
+        let Some(file) = self.file.take() else {
+            return Ok(());
+        };
+        let entry = &mut self.entries[file.entry];
+        entry["endOffset"] = self.written.into();
+        entry["digest"] = format!("sha256:{:x}", file.digest.finalize()).into();
+        let crc = base64::engine::general_purpose::STANDARD.encode(file.crc.to_be_bytes());
+        self.tar_split_entry(json!({"type": TAR_SPLIT_FILE, "name": file.name, "size": file.size, "payload": crc}))
+    }
+
+    fn trailer(&mut self, data: &[u8]) -> io::Result<()> {
+        self.segment.extend_from_slice(data);
+        self.emit(data)
+    }
+
+    fn finish(mut self) -> Result<Finished> {
+        self.close_frame()?;
+        self.flush_segment()?;
+        let tar_split = zstd::encode_all(&self.tar_split[..], self.level)?;
+        let toc = serde_json::to_vec(&json!({
+            "version": 1,
+            "entries": self.entries,
+            "tarsplit-digest": format!("sha256:{:x}", Sha256::digest(&tar_split)),
+        }))?;
+        let manifest = zstd::encode_all(&toc[..], self.level)?;
+
+        let manifest_offset = self.skippable_frame(&manifest)?;
+        let tar_split_offset = self.skippable_frame(&tar_split)?;
+        let mut footer = Vec::with_capacity(64);
+        for value in [
+            manifest_offset,
+            manifest.len() as u64,
+            toc.len() as u64,
+            MANIFEST_TYPE_CRFS,
+            tar_split_offset,
+            tar_split.len() as u64,
+            self.tar_split.len() as u64,
+        ] {
+            footer.extend_from_slice(&value.to_le_bytes());
+        }
+        footer.extend_from_slice(FOOTER_MAGIC);
+        self.skippable_frame(&footer)?;
+        self.out.flush()?;
+
+        Ok(Finished {
+            diff_digest: None,
+            annotations: vec![
+                (ANNOTATION_ZSTD_CHUNKED_CHECKSUM, format!("sha256:{:x}", Sha256::digest(&manifest))),
+                (
+                    ANNOTATION_ZSTD_CHUNKED_POSITION,
+                    format!("{}:{}:{}:{}", manifest_offset, manifest.len(), toc.len(), MANIFEST_TYPE_CRFS),
+                ),
+                (
+                    ANNOTATION_TARSPLIT_POSITION,
+                    format!("{}:{}:{}", tar_split_offset, tar_split.len(), self.tar_split.len()),
+                ),
+            ],
+        })
+    }
+}
+
+/// CRC-64 with the ISO polynomial, as tar-split records file contents
+fn crc64_iso(crc: u64, data: &[u8]) -> u64 {
+    const TABLE: [u64; 256] = {
+        let mut table = [0u64; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut crc = i as u64;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = if crc & 1 == 1 { (crc >> 1) ^ 0xd800_0000_0000_0000 } else { crc >> 1 };
+                bit += 1;
+            }
+            table[i] = crc;
+            i += 1;
+        }
+        table
+    };
+    let mut crc = !crc;
+    for &byte in data {
+        crc = TABLE[((crc as u8) ^ byte) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
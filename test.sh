@@ -87,6 +87,39 @@ else
     pass "rejects invalid compression type"
 fi
 
+# Test error handling - misspelled key
+if echo 'compresion: gzip' | build-oci 2>/dev/null; then
+    fail "error handling" "should reject unknown keys"
+else
+    pass "rejects unknown manifest keys"
+fi
+
+# Test error handling - schema errors name the offending value's path
+ERR_FILE=$(mktemp)
+ERRORS=""
+for case in "images: [{architecture: amd64}]|images[0]: missing field \`os\`" \
+    "images: [{architecture: amd64, os: linux, layers: [a, {dir: b, bogus: 1}]}]|images[0].layers[1].bogus: unknown field \`bogus\`" \
+    "images: [{architecture: amd64, os: linux, config-patch: [{op: add, path: /x}]}]|images[0].config-patch[0]: missing field \`value\`" \
+    "images: [{architecture: amd64, os: linux, labels: {a: [1]}}]|images[0].labels.a: invalid type" \
+    "defaults: {parent: {bogus: 1}}|defaults.parent.bogus: unknown field \`bogus\`"; do
+    echo "${case%%|*}" | build-oci --dry-run 2> "$ERR_FILE" >/dev/null || true
+    grep -qF "${case#*|}" "$ERR_FILE" || ERRORS="$ERRORS ${case#*|};"
+done
+rm -f "$ERR_FILE"
+if [ -z "$ERRORS" ]; then
+    pass "manifest errors name the path of the offending value"
+else
+    fail "schema validation" "paths missing from errors:$ERRORS"
+fi
+ERR_FILE=$(mktemp)
+printf 'compresion: gzip\nimages: [{architecture: amd64, os: linux, bogus: 1}]\n' | build-oci --dry-run 2> "$ERR_FILE" >/dev/null || true
+if grep -q "compresion: unknown field" "$ERR_FILE" && ! grep -q "bogus" "$ERR_FILE"; then
+    pass "validation stops at the first manifest error"
+else
+    fail "schema validation" "expected only the first error: $(cat "$ERR_FILE")"
+fi
+rm -f "$ERR_FILE"
+
 # --------------------------------------------------
 # Test 2: Minimal image (no layers)
 # --------------------------------------------------
@@ -908,6 +941,4900 @@ fi
 
 rm -rf "$WORKDIR"
 
+# --------------------------------------------------
+# Test 12: annotations-file / labels-file
+# --------------------------------------------------
+echo ""
+echo "Test 12: Annotations and labels from files"
+
+WORKDIR=$(mktemp -d)
+cd "$WORKDIR"
+
+cat > annotations.json <<'JSON'
+{"org.opencontainers.image.version": "1.0", "org.opencontainers.image.title": "from-file"}
+JSON
+cat > labels.yaml <<'LABELS'
+release: 24.08
+maintainer: release-team
+LABELS
+
+cat <<'YAML' | build-oci
+compression: gzip
+images:
+  - architecture: amd64
+    os: linux
+    annotations:
+      org.opencontainers.image.title: "inline"
+    annotations-file: annotations.json
+    labels-file: labels.yaml
+YAML
+
+MANIFEST_BLOB=$(get_manifest_blob "$WORKDIR")
+VERSION_ANN=$(jq -r '.annotations["org.opencontainers.image.version"]' "$MANIFEST_BLOB" 2>/dev/null)
+TITLE_ANN=$(jq -r '.annotations["org.opencontainers.image.title"]' "$MANIFEST_BLOB" 2>/dev/null)
+if [ "$VERSION_ANN" = "1.0" ] && [ "$TITLE_ANN" = "inline" ]; then
+    pass "annotations-file merged, inline annotations take precedence"
+else
+    fail "annotations-file" "got version=$VERSION_ANN title=$TITLE_ANN"
+fi
+
+CONFIG_BLOB=$(get_config_blob "$WORKDIR")
+LABEL=$(jq -r '.config.Labels.maintainer' "$CONFIG_BLOB" 2>/dev/null)
+if [ "$LABEL" = "release-team" ]; then
+    pass "labels-file merged into config Labels"
+else
+    fail "labels-file" "expected release-team, got $LABEL"
+fi
+
+rm -rf "$WORKDIR"
+
+# --------------------------------------------------
+# Test 13: config-patch / manifest-patch
+# --------------------------------------------------
+echo ""
+echo "Test 13: JSON Patch of generated config and manifest"
+
+WORKDIR=$(mktemp -d)
+cd "$WORKDIR"
+
+cat <<'YAML' | build-oci
+compression: gzip
+images:
+  - architecture: amd64
+    os: linux
+    config:
+      Env:
+        - PATH=/usr/bin:/bin
+    config-patch:
+      - op: add
+        path: /config/StopSignal
+        value: SIGTERM
+      - op: remove
+        path: /config/Env
+    manifest-patch:
+      - op: add
+        path: /annotations
+        value:
+          org.example.patched: "yes"
+YAML
+
+CONFIG_BLOB=$(get_config_blob "$WORKDIR")
+STOP=$(jq -r '.config.StopSignal' "$CONFIG_BLOB" 2>/dev/null)
+HAS_ENV=$(jq -r '.config | has("Env")' "$CONFIG_BLOB" 2>/dev/null)
+if [ "$STOP" = "SIGTERM" ] && [ "$HAS_ENV" = "false" ]; then
+    pass "config-patch applied to image config"
+else
+    fail "config-patch" "got StopSignal=$STOP has Env=$HAS_ENV"
+fi
+
+PATCHED=$(jq -r '.annotations["org.example.patched"]' "$(get_manifest_blob "$WORKDIR")" 2>/dev/null)
+if [ "$PATCHED" = "yes" ]; then
+    pass "manifest-patch applied to image manifest"
+else
+    fail "manifest-patch" "expected yes, got $PATCHED"
+fi
+
+# Config digest must match the patched content
+CFG_DIGEST=$(jq -r '.config.digest' "$(get_manifest_blob "$WORKDIR")" | cut -d: -f2)
+if [ "$(sha256sum "$CONFIG_BLOB" | cut -d' ' -f1)" = "$CFG_DIGEST" ]; then
+    pass "patched config is hashed after patching"
+else
+    fail "config-patch digest" "config digest does not match content"
+fi
+
+rm -rf "$WORKDIR"
+
+# --------------------------------------------------
+# Test 14: environment variable interpolation
+# --------------------------------------------------
+echo ""
+echo "Test 14: Environment variable interpolation"
+
+WORKDIR=$(mktemp -d)
+cd "$WORKDIR"
+mkdir -p rootfs-env
+echo "hello" > rootfs-env/hello.txt
+
+cat <<'YAML' | LAYER_DIR=rootfs-env build-oci
+compression: gzip
+images:
+  - architecture: ${TEST_ARCH:-arm64}
+    os: linux
+    layer: ${LAYER_DIR}
+    comment: "literal $${NOT_EXPANDED}"
+YAML
+
+ARCH=$(jq -r '.manifests[0].platform.architecture' "$WORKDIR/index.json" 2>/dev/null)
+NLAYERS=$(jq '.layers | length' "$(get_manifest_blob "$WORKDIR")" 2>/dev/null)
+if [ "$ARCH" = "arm64" ] && [ "$NLAYERS" = "1" ]; then
+    pass "\${VAR} and \${VAR:-default} expanded"
+else
+    fail "interpolation" "got architecture=$ARCH layers=$NLAYERS"
+fi
+
+COMMENT=$(jq -r '.history[-1].comment' "$(get_config_blob "$WORKDIR")" 2>/dev/null)
+if [ "$COMMENT" = "literal \${NOT_EXPANDED}" ]; then
+    pass "\$\${ escapes interpolation"
+else
+    fail "interpolation escape" "got $COMMENT"
+fi
+
+if echo 'images: [{architecture: "${UNSET_TEST_VARIABLE}", os: linux}]' | build-oci 2>/dev/null; then
+    fail "interpolation" "should reject unset variables without default"
+else
+    pass "rejects unset variables without default"
+fi
+
+rm -rf "$WORKDIR"
+
+# --------------------------------------------------
+# Test 15: ls subcommand
+# --------------------------------------------------
+echo ""
+echo "Test 15: ls subcommand"
+
+WORKDIR=$(mktemp -d)
+cd "$WORKDIR"
+
+cat <<'YAML' | build-oci
+compression: gzip
+images:
+  - architecture: amd64
+    os: linux
+    index-annotations:
+      org.opencontainers.image.ref.name: "latest"
+  - architecture: arm64
+    os: linux
+    variant: v8
+YAML
+
+if build-oci ls "$WORKDIR" | grep -q "latest.*linux/amd64"; then
+    pass "ls table lists ref name and platform"
+else
+    fail "ls" "table output missing ref/platform"
+fi
+
+LS_PLATFORM=$(build-oci ls "$WORKDIR" --json | jq -r '.[1].platform' 2>/dev/null)
+LS_DIGEST=$(build-oci ls "$WORKDIR" --json | jq -r '.[0].digest' 2>/dev/null)
+IDX_DIGEST=$(jq -r '.manifests[0].digest' "$WORKDIR/index.json")
+if [ "$LS_PLATFORM" = "linux/arm64/v8" ] && [ "$LS_DIGEST" = "$IDX_DIGEST" ]; then
+    pass "ls --json reports platforms and digests"
+else
+    fail "ls --json" "got platform=$LS_PLATFORM digest=$LS_DIGEST"
+fi
+
+if build-oci ls "$WORKDIR/does-not-exist" 2>/dev/null; then
+    fail "ls" "should reject a missing layout"
+else
+    pass "ls rejects a missing layout"
+fi
+
+rm -rf "$WORKDIR"
+
+# --------------------------------------------------
+# Test 16: du subcommand
+# --------------------------------------------------
+echo ""
+echo "Test 16: du subcommand"
+
+WORKDIR=$(mktemp -d)
+cd "$WORKDIR"
+mkdir -p rootfs-du/usr/lib rootfs-du/etc
+head -c 100000 /dev/zero > rootfs-du/usr/lib/big.bin
+echo "config" > rootfs-du/etc/app.conf
+
+cat <<'YAML' | build-oci
+compression: zstd
+images:
+  - architecture: amd64
+    os: linux
+    layer: rootfs-du
+    index-annotations:
+      org.opencontainers.image.ref.name: "latest"
+YAML
+
+USR_SIZE=$(build-oci du "$WORKDIR:latest" --json | jq -r '.directories.usr.size' 2>/dev/null)
+ETC_FILES=$(build-oci du "$WORKDIR:latest" --json | jq -r '.directories.etc.files' 2>/dev/null)
+if [ "$USR_SIZE" = "100000" ] && [ "$ETC_FILES" = "1" ]; then
+    pass "du reports per-directory uncompressed sizes"
+else
+    fail "du" "got usr size=$USR_SIZE etc files=$ETC_FILES"
+fi
+
+if build-oci du "$WORKDIR" | grep -q "/usr"; then
+    pass "du resolves single-image layouts without a ref"
+else
+    fail "du" "table output missing /usr"
+fi
+
+rm -rf "$WORKDIR"
+
+# --------------------------------------------------
+# Test 17: --dry-run and parent deduplication
+# --------------------------------------------------
+echo ""
+echo "Test 17: Dry run and parent deduplication"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/parent/rootfs" "$WORKDIR/child/rootfs"
+echo "kept" > "$WORKDIR/parent/rootfs/keep.txt"
+echo "removed" > "$WORKDIR/parent/rootfs/gone.txt"
+cp -a "$WORKDIR/parent/rootfs/keep.txt" "$WORKDIR/child/rootfs/keep.txt"
+echo "added" > "$WORKDIR/child/rootfs/new.txt"
+
+cd "$WORKDIR/parent"
+cat <<'YAML' | SOURCE_DATE_EPOCH=1700000000 build-oci
+compression: gzip
+images:
+  - architecture: amd64
+    os: linux
+    layer: rootfs
+YAML
+
+cd "$WORKDIR/child"
+DRY=$(cat <<'YAML' | SOURCE_DATE_EPOCH=1700000000 build-oci --dry-run
+compression: gzip
+images:
+  - architecture: amd64
+    os: linux
+    layer: rootfs
+    parent:
+      image: ../parent
+YAML
+)
+
+if [ ! -e "$WORKDIR/child/index.json" ] && [ ! -e "$WORKDIR/child/blobs" ]; then
+    pass "--dry-run writes no blobs or index.json"
+else
+    fail "--dry-run" "output was written"
+fi
+
+ADDED=$(echo "$DRY" | jq -r '.images[0].layer.added | join(",")' 2>/dev/null)
+SKIPPED=$(echo "$DRY" | jq -r '.images[0].layer.skipped | join(",")' 2>/dev/null)
+WHITEOUTS=$(echo "$DRY" | jq -r '.images[0].layer.whiteouts | join(",")' 2>/dev/null)
+if [ "$ADDED" = "./new.txt" ] && [ "$SKIPPED" = "./keep.txt" ] && [ "$WHITEOUTS" = "./gone.txt" ]; then
+    pass "--dry-run reports added, skipped and whiteout entries"
+else
+    fail "--dry-run" "added=$ADDED skipped=$SKIPPED whiteouts=$WHITEOUTS"
+fi
+
+cat <<'YAML' | SOURCE_DATE_EPOCH=1700000000 build-oci
+compression: gzip
+images:
+  - architecture: amd64
+    os: linux
+    layer: rootfs
+    parent:
+      image: ../parent
+YAML
+
+LAST_LAYER=$(jq -r '.layers[-1].digest' "$(get_manifest_blob "$WORKDIR/child")" | cut -d: -f2)
+LISTING=$(tar tzf "$WORKDIR/child/blobs/sha256/$LAST_LAYER" 2>/dev/null | tr '\n' ' ')
+if echo "$LISTING" | grep -q ".wh.gone.txt" && ! echo "$LISTING" | grep -q "keep.txt"; then
+    pass "child layer whites out removed files and skips unchanged ones"
+else
+    fail "parent deduplication" "layer contents: $LISTING"
+fi
+
+rm -rf "$WORKDIR"
+
+# --------------------------------------------------
+# Test 18: per-layer file listing
+# --------------------------------------------------
+echo ""
+echo "Test 18: Per-layer file listing blobs"
+
+WORKDIR=$(mktemp -d)
+cd "$WORKDIR"
+mkdir -p rootfs-list/bin
+echo "#!/bin/sh" > rootfs-list/bin/run
+chmod 755 rootfs-list/bin/run
+
+for FORMAT in json mtree; do
+    rm -rf "$WORKDIR/blobs" "$WORKDIR/index.json" "$WORKDIR/oci-layout"
+    cat <<YAML | build-oci
+compression: gzip
+layer-listing: $FORMAT
+images:
+  - architecture: amd64
+    os: linux
+    layer: rootfs-list
+YAML
+    LISTING=$(jq -r '.layers[0].annotations["org.freedesktopsdk.layer.listing"]' "$(get_manifest_blob "$WORKDIR")" | cut -d: -f2)
+    LISTING_BLOB="$WORKDIR/blobs/sha256/$LISTING"
+    FILE_SHA=$(sha256sum rootfs-list/bin/run | cut -d' ' -f1)
+    if [ "$FORMAT" = "json" ]; then
+        GOT=$(jq -r '.entries[] | select(.path == "./bin/run") | "\(.mode) \(.sha256)"' "$LISTING_BLOB" 2>/dev/null)
+        EXPECTED="493 $FILE_SHA"
+    else
+        GOT=$(grep '^./bin/run ' "$LISTING_BLOB" 2>/dev/null | grep -o 'mode=[0-7]* .*sha256digest=[0-9a-f]*' | sed 's/ uid.*sha256digest=/ /')
+        EXPECTED="mode=0755 $FILE_SHA"
+    fi
+    if [ -f "$LISTING_BLOB" ] && [ "$GOT" = "$EXPECTED" ]; then
+        pass "$FORMAT layer listing records mode and digest"
+    else
+        fail "$FORMAT layer listing" "expected '$EXPECTED', got '$GOT'"
+    fi
+done
+
+rm -rf "$WORKDIR"
+
+# --------------------------------------------------
+# Test 19: .ociignore
+# --------------------------------------------------
+echo ""
+echo "Test 19: .ociignore in the layer root"
+
+WORKDIR=$(mktemp -d)
+cd "$WORKDIR"
+mkdir -p rootfs-ign/src rootfs-ign/cache/deep rootfs-ign/keep
+echo "code" > rootfs-ign/src/main.c
+echo "obj" > rootfs-ign/src/main.o
+echo "tmp" > rootfs-ign/cache/deep/blob
+echo "data" > rootfs-ign/keep/data.txt
+printf '*.o\n/cache/\n' > rootfs-ign/.ociignore
+
+cat <<'YAML' | build-oci
+compression: gzip
+images:
+  - architecture: amd64
+    os: linux
+    layer: rootfs-ign
+YAML
+
+LAYER=$(jq -r '.layers[0].digest' "$(get_manifest_blob "$WORKDIR")" | cut -d: -f2)
+LISTING=$(tar tzf "$WORKDIR/blobs/sha256/$LAYER" 2>/dev/null | tr '\n' ' ')
+if echo "$LISTING" | grep -q "src/main.c" && echo "$LISTING" | grep -q "keep/data.txt"; then
+    pass ".ociignore keeps non-matching files"
+else
+    fail ".ociignore" "expected files missing: $LISTING"
+fi
+if echo "$LISTING" | grep -qE "main\.o|cache|\.ociignore"; then
+    fail ".ociignore" "ignored files present: $LISTING"
+else
+    pass ".ociignore excludes matching files, directories and itself"
+fi
+
+rm -rf "$WORKDIR"
+
+# --------------------------------------------------
+# Test 20: logging
+# --------------------------------------------------
+echo ""
+echo "Test 20: Structured logging"
+
+WORKDIR=$(mktemp -d)
+cd "$WORKDIR"
+
+QUIET_LOG=$(echo 'images: [{architecture: amd64, os: linux}]' | build-oci 2>&1 >/dev/null)
+if [ -z "$QUIET_LOG" ]; then
+    pass "no log output by default"
+else
+    fail "default logging" "unexpected output: $QUIET_LOG"
+fi
+
+JSON_LOG=$(echo 'images: [{architecture: amd64, os: linux}]' | build-oci -v --log-format json 2>&1 >/dev/null)
+if echo "$JSON_LOG" | jq -s -e 'any(.[]; .fields.message == "built image" and .span.architecture == "amd64")' >/dev/null 2>&1; then
+    pass "--log-format json emits JSON events with image spans"
+else
+    fail "--log-format json" "got: $JSON_LOG"
+fi
+
+rm -rf "$WORKDIR"
+
+# --------------------------------------------------
+# Test 21: multi-document input
+# --------------------------------------------------
+echo ""
+echo "Test 21: Multi-document YAML input"
+
+WORKDIR=$(mktemp -d)
+cd "$WORKDIR"
+
+build-oci <<'YAML'
+output: first
+images:
+  - architecture: amd64
+    os: linux
+---
+output: second/nested
+images:
+  - architecture: arm64
+    os: linux
+YAML
+
+if [ -f first/index.json ] && [ -f second/nested/index.json ]; then
+    pass "each document writes its own layout"
+else
+    fail "multi-document" "missing index.json in first/ or second/nested/"
+fi
+
+if [ "$(build-oci ls second/nested --json | jq -r '.[0].platform')" = "linux/arm64" ]; then
+    pass "documents are built independently"
+else
+    fail "multi-document" "second layout does not hold the arm64 image"
+fi
+
+mkdir -p rootfs
+echo "parent file" > rootfs/file.txt
+printf 'output: parent\nimages: [{architecture: amd64, os: linux, layer: rootfs}]\n' | build-oci
+build-oci <<'YAML'
+output: child-a
+images: [{architecture: amd64, os: linux, parent: {image: parent}}]
+---
+output: child-b
+images: [{architecture: amd64, os: linux, parent: {image: parent}}]
+YAML
+CHILD_B_LAYER=$(build-oci du child-b --json 2>/dev/null | jq -r '.layers | length')
+if [ "$CHILD_B_LAYER" = "1" ]; then
+    pass "documents sharing a parent each get the parent's blobs"
+else
+    fail "multi-document" "child-b cannot read its parent layer"
+fi
+
+if printf 'images: []\n---\nimages: []\n' | build-oci 2>/dev/null; then
+    fail "multi-document" "two documents writing to the same output were accepted"
+else
+    pass "documents sharing an output directory are rejected"
+fi
+
+rm -rf "$WORKDIR"
+
+# --------------------------------------------------
+# Test 22: lint rules
+# --------------------------------------------------
+echo ""
+echo "Test 22: Ownership and permission lint rules"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/layer/etc" "$WORKDIR/layer/shared" "$WORKDIR/out"
+echo "root:*::" > "$WORKDIR/layer/etc/shadow"
+chmod 0644 "$WORKDIR/layer/etc/shadow"
+chmod 0777 "$WORKDIR/layer/shared"
+cd "$WORKDIR/out"
+
+if build-oci > "$WORKDIR/lint.log" 2>&1 <<YAML
+lint:
+  - rule: mode
+    path: /etc/shadow
+    mode: "0600"
+images:
+  - architecture: amd64
+    os: linux
+    layer: $WORKDIR/layer
+YAML
+then
+    fail "lint fail action" "build succeeded despite a failing rule"
+elif grep -q "/etc/shadow: mode 0644, expected 0600" "$WORKDIR/lint.log"; then
+    pass "failing lint rule aborts the build"
+else
+    fail "lint fail action" "got: $(cat "$WORKDIR/lint.log")"
+fi
+
+LINT_WARN=$(build-oci 2>&1 >/dev/null <<YAML
+lint:
+  - rule: world-writable
+    action: warn
+images:
+  - architecture: amd64
+    os: linux
+    layer: $WORKDIR/layer
+YAML
+)
+if [ -f index.json ] && echo "$LINT_WARN" | grep -q "without sticky bit"; then
+    pass "warn lint rule logs and continues"
+else
+    fail "lint warn action" "got: $LINT_WARN"
+fi
+
+rm -rf "$WORKDIR"
+
+# --------------------------------------------------
+# Test 23: source-date-epoch in the manifest
+# --------------------------------------------------
+echo ""
+echo "Test 23: source-date-epoch manifest key"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/layer" "$WORKDIR/out"
+echo "hello" > "$WORKDIR/layer/file.txt"
+cd "$WORKDIR/out"
+
+cat <<YAML | SOURCE_DATE_EPOCH=1600000000 build-oci
+compression: disabled
+source-date-epoch: 1700000000
+images:
+  - architecture: amd64
+    os: linux
+    layer: $WORKDIR/layer
+  - architecture: arm64
+    os: linux
+    source-date-epoch: 1710000000
+YAML
+
+CREATED=$(for m in $(jq -r '.manifests[].digest' index.json | sed 's/sha256://'); do
+    CFG=$(jq -r '.config.digest' "blobs/sha256/$m" | sed 's/sha256://')
+    jq -r '.created' "blobs/sha256/$CFG"
+done | tr '\n' ' ')
+if [ "$CREATED" = "2023-11-14T22:13:20Z 2024-03-09T16:00:00Z " ]; then
+    pass "global and per-image source-date-epoch override the environment"
+else
+    fail "source-date-epoch" "got created timestamps: $CREATED"
+fi
+
+M0=$(jq -r '.manifests[0].digest' index.json | sed 's/sha256://')
+L0=$(jq -r '.layers[0].digest' "blobs/sha256/$M0" | sed 's/sha256://')
+MTIME=$(TZ=UTC tar -tvf "blobs/sha256/$L0" --full-time 2>/dev/null | grep 'file.txt$' | awk '{print $4" "$5}')
+if [ "$MTIME" = "2023-11-14 22:13:20" ]; then
+    pass "source-date-epoch clamps layer file mtimes"
+else
+    fail "source-date-epoch" "file mtime: $MTIME"
+fi
+
+rm -rf "$WORKDIR"
+
+# --------------------------------------------------
+# Test 24: GPG signatures
+# --------------------------------------------------
+echo ""
+echo "Test 24: GPG-signed digest manifest"
+
+if ! command -v gpg >/dev/null 2>&1; then
+    warn "gpg signatures" "gpg not installed, skipping"
+else
+    WORKDIR=$(mktemp -d)
+    mkdir -p "$WORKDIR/gnupg" "$WORKDIR/out"
+    chmod 700 "$WORKDIR/gnupg"
+    gpg --homedir "$WORKDIR/gnupg" --batch --passphrase '' \
+        --quick-gen-key "build-oci test <test@example.com>" ed25519 sign never >/dev/null 2>&1
+    cd "$WORKDIR/out"
+
+    build-oci <<YAML
+gpg-sign:
+  key: test@example.com
+  homedir: $WORKDIR/gnupg
+images:
+  - architecture: amd64
+    os: linux
+YAML
+
+    if [ -f SHA256SUMS.asc ] && grep -q "  index.json$" SHA256SUMS \
+        && build-oci verify . --signatures --gpg-homedir "$WORKDIR/gnupg" >/dev/null; then
+        pass "signed layout verifies"
+    else
+        fail "gpg signatures" "signing or verification of a fresh layout failed"
+    fi
+
+    BLOB=$(ls blobs/sha256 | head -1)
+    echo "tampered" >> "blobs/sha256/$BLOB"
+    if build-oci verify . --signatures --gpg-homedir "$WORKDIR/gnupg" >/dev/null 2>&1; then
+        fail "gpg signatures" "tampered blob was not detected"
+    else
+        pass "verify --signatures detects a modified blob"
+    fi
+
+    # Key providers: an exported key file and an external helper for a KMS URL
+    gpg --homedir "$WORKDIR/gnupg" --batch --armor --export-secret-keys test@example.com > "$WORKDIR/key.asc"
+    mkdir -p "$WORKDIR/bin" "$WORKDIR/file-key" "$WORKDIR/kms-key"
+    cat > "$WORKDIR/bin/build-oci-key-testkms" <<HELPER
+#!/bin/sh
+[ "\$1" = sign ] && [ "\$2" = "testkms://signing-key" ] || exit 2
+exec gpg --homedir "$WORKDIR/gnupg" --batch --armor --detach-sign --local-user test@example.com
+HELPER
+    chmod +x "$WORKDIR/bin/build-oci-key-testkms"
+
+    for provider in "file:$WORKDIR/key.asc:file-key" "testkms://signing-key:kms-key"; do
+        KEY_REF=${provider%:*}
+        cd "$WORKDIR/${provider##*:}"
+        printf 'gpg-sign: {key: "%s"}\nimages: [{architecture: amd64, os: linux}]\n' "$KEY_REF" \
+            | PATH="$WORKDIR/bin:$PATH" build-oci
+        if build-oci verify . --signatures --gpg-homedir "$WORKDIR/gnupg" >/dev/null 2>&1; then
+            pass "signing with key provider ${KEY_REF%%:*}"
+        else
+            fail "key providers" "layout signed with $KEY_REF does not verify"
+        fi
+    done
+
+    rm -rf "$WORKDIR"
+fi
+
+# --------------------------------------------------
+# Test 25: platform validation
+# --------------------------------------------------
+echo ""
+echo "Test 25: Platform validation"
+
+WORKDIR=$(mktemp -d)
+cd "$WORKDIR"
+
+if echo 'images: [{architecture: amd_64, os: linux}]' | build-oci 2>/dev/null; then
+    fail "platform validation" "unknown architecture amd_64 was accepted"
+else
+    pass "rejects unknown architecture"
+fi
+
+if echo 'images: [{architecture: amd64, os: linux, variant: v8}]' | build-oci 2>/dev/null; then
+    fail "platform validation" "arm variant on amd64 was accepted"
+else
+    pass "rejects variant not valid for the architecture"
+fi
+
+if echo 'images: [{architecture: amd_64, os: linux}]' | build-oci --allow-unknown-platform 2>/dev/null \
+    && [ "$(jq -r '.manifests[0].platform.architecture' index.json)" = "amd_64" ]; then
+    pass "--allow-unknown-platform builds unknown platforms"
+else
+    fail "platform validation" "--allow-unknown-platform did not build"
+fi
+
+rm -rf "$WORKDIR"
+
+# --------------------------------------------------
+# Test 26: digest output files
+# --------------------------------------------------
+echo ""
+echo "Test 26: --iidfile and digest-file"
+
+WORKDIR=$(mktemp -d)
+cd "$WORKDIR"
+
+build-oci --iidfile "$WORKDIR/iid.json" <<YAML
+images:
+  - architecture: amd64
+    os: linux
+    digest-file: $WORKDIR/amd64.digest
+  - architecture: arm64
+    os: linux
+YAML
+
+INDEX_DIGEST="sha256:$(sha256sum index.json | cut -d' ' -f1)"
+if [ "$(jq -r '.[0].index' iid.json)" = "$INDEX_DIGEST" ] \
+    && [ "$(jq -c '.[0].manifests' iid.json)" = "$(jq -c '[.manifests[].digest]' index.json)" ]; then
+    pass "--iidfile records index and manifest digests"
+else
+    fail "--iidfile" "got: $(cat iid.json)"
+fi
+
+if [ "$(cat amd64.digest)" = "$(jq -r '.manifests[0].digest' index.json)" ]; then
+    pass "digest-file receives the image's manifest digest"
+else
+    fail "digest-file" "got: $(cat amd64.digest 2>/dev/null)"
+fi
+
+rm -rf "$WORKDIR"
+
+# --------------------------------------------------
+# Test 27: compression annotations
+# --------------------------------------------------
+echo ""
+echo "Test 27: Compression parameter annotations"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/layer" "$WORKDIR/out"
+echo "data" > "$WORKDIR/layer/file.txt"
+cd "$WORKDIR/out"
+
+build-oci -j 2 <<YAML
+compression: zstd
+compression-level: 7
+compression-annotations: true
+images:
+  - architecture: amd64
+    os: linux
+    layer: $WORKDIR/layer
+YAML
+
+M=$(jq -r '.manifests[0].digest' index.json | sed 's/sha256://')
+ANN=$(jq -c '.layers[0].annotations' "blobs/sha256/$M")
+if [ "$ANN" = '{"org.freedesktopsdk.layer.compression":"zstd","org.freedesktopsdk.layer.compression.level":"7","org.freedesktopsdk.layer.compression.threads":"2"}' ]; then
+    pass "layer descriptor records codec, level and threads"
+else
+    fail "compression annotations" "got: $ANN"
+fi
+
+rm -rf "$WORKDIR"
+
+# --------------------------------------------------
+# Test 28: exit codes and error report
+# --------------------------------------------------
+echo ""
+echo "Test 28: Exit codes and --error-json"
+
+WORKDIR=$(mktemp -d)
+cd "$WORKDIR"
+
+RC=0
+echo 'compresion: gzip' | build-oci --error-json "$WORKDIR/err.json" 2>/dev/null || RC=$?
+if [ "$RC" = "2" ] && [ "$(jq -r '.category' err.json)" = "config" ] \
+    && jq -r '.causes[]' err.json | grep -q "compresion: unknown field"; then
+    pass "manifest errors exit 2 with a config error report"
+else
+    fail "exit codes" "config error: rc=$RC report=$(cat err.json 2>/dev/null)"
+fi
+RC=0
+echo "images: [{architecture: amd64, os: linux, parent: {image: $WORKDIR/missing}}]" | build-oci 2>/dev/null || RC=$?
+if [ "$RC" = "3" ]; then
+    pass "missing parent exits 3"
+else
+    fail "exit codes" "missing parent: rc=$RC"
+fi
+
+rm -rf "$WORKDIR"
+
+# --------------------------------------------------
+# Test 29: lower layer cache
+# --------------------------------------------------
+echo ""
+echo "Test 29: Decompressed lower layer cache"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/base" "$WORKDIR/app"
+echo "base" > "$WORKDIR/base/base.txt"
+cp "$WORKDIR/base/base.txt" "$WORKDIR/app/"
+echo "app" > "$WORKDIR/app/app.txt"
+cd "$WORKDIR"
+
+printf 'output: parent\ncompression: gzip\nimages: [{architecture: amd64, os: linux, layer: base}]\n' | build-oci
+for run in 1 2; do
+    XDG_CACHE_HOME="$WORKDIR/cache" build-oci <<'YAML'
+output: child
+compression: gzip
+lower-cache: uncompressed
+images:
+  - architecture: amd64
+    os: linux
+    parent: {image: parent}
+    layer: app
+YAML
+done
+
+M=$(jq -r '.manifests[0].digest' parent/index.json | sed 's/sha256://')
+C=$(jq -r '.config.digest' "parent/blobs/sha256/$M" | sed 's/sha256://')
+PARENT_DIFF=$(jq -r '.rootfs.diff_ids[0]' "parent/blobs/sha256/$C" | sed 's/sha256://')
+CACHED=$(ls "$WORKDIR/cache/build-oci/lowers/sha256/" 2>/dev/null)
+CHILD_FILES=$(build-oci du child --json | jq -r '.layers[1].total.files')
+if [ "$CACHED" = "$PARENT_DIFF.tar" ] && [ "$CHILD_FILES" = "1" ]; then
+    pass "parent layer cached by diff_id and reused for deduplication"
+else
+    fail "lower cache" "cache: $CACHED, child layer files: $CHILD_FILES"
+fi
+
+rm -rf "$WORKDIR"
+
+# Test 30: shell completions and man page
+# --------------------------------------------------
+echo ""
+echo "Test 30: Shell completions and man page"
+
+if build-oci completions bash | grep -q -- "--iidfile" \
+    && build-oci completions zsh | grep -q "#compdef build-oci" \
+    && build-oci man | grep -q '^\.TH build-oci 1'; then
+    pass "completions and man page generated"
+else
+    fail "completions/man" "missing output"
+fi
+if build-oci completions tcsh >/dev/null 2>&1; then
+    fail "completions" "unsupported shell accepted"
+else
+    pass "unsupported shell rejected"
+fi
+
+# Test 31: per-layer file indexes
+# --------------------------------------------------
+echo ""
+echo "Test 31: Per-layer file indexes"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/base" "$WORKDIR/app"
+echo "base" > "$WORKDIR/base/base.txt"
+cp -p "$WORKDIR/base/base.txt" "$WORKDIR/app/"
+echo "app" > "$WORKDIR/app/app.txt"
+cd "$WORKDIR"
+
+printf 'output: parent\nlayer-index: true\nimages: [{architecture: amd64, os: linux, layer: base}]\n' | build-oci
+printf 'output: plain\nimages: [{architecture: amd64, os: linux, layer: base}]\n' | build-oci
+for p in parent plain; do
+    printf 'output: child-%s\nlayer-index: true\nimages: [{architecture: amd64, os: linux, parent: {image: %s}, layer: app}]\n' "$p" "$p" | build-oci
+done
+
+M=$(jq -r '.manifests[0].digest' parent/index.json | cut -d: -f2)
+IDX=$(jq -r '.layers[0].annotations["org.freedesktopsdk.layer.index"]' "parent/blobs/sha256/$M" | cut -d: -f2)
+if [ -f "parent/blobs/sha256/$IDX" ] && jq -e '.version == 1 and (.entries | map(.path) | index("./base.txt"))' "parent/blobs/sha256/$IDX" >/dev/null; then
+    pass "layer index blob written and linked from the layer descriptor"
+else
+    fail "layer index" "missing or invalid index blob '$IDX'"
+fi
+
+CM=$(jq -r '.manifests[0].digest' child-parent/index.json | cut -d: -f2)
+if [ "$(jq '[.layers[].annotations["org.freedesktopsdk.layer.index"] // empty] | length' "child-parent/blobs/sha256/$CM")" = "2" ] \
+    && [ "$(build-oci du child-parent --json | jq -c '[.layers[].total.files]')" = "[1,1]" ] \
+    && [ "$(build-oci du child-plain --json | jq -c '[.layers[].total.files]')" = "[1,1]" ]; then
+    pass "indexed and tar-parsed parents deduplicate the same"
+else
+    fail "layer index" "child layers differ between indexed and plain parents"
+fi
+
+rm -rf "$WORKDIR"
+
+# Test 32: image defaults
+# --------------------------------------------------
+echo ""
+echo "Test 32: defaults: block merged into images"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/rootfs"
+echo "hello" > "$WORKDIR/rootfs/file.txt"
+cd "$WORKDIR"
+
+build-oci <<'YAML'
+defaults:
+  os: linux
+  author: Defaults
+  annotations: {org.example.shared: "yes", org.example.kind: default}
+  config:
+    Env: [PATH=/usr/bin]
+    WorkingDir: /
+  layer: rootfs
+images:
+  - architecture: amd64
+  - architecture: arm64
+    annotations: {org.example.kind: arm}
+    config: {WorkingDir: /srv}
+YAML
+
+ok=true
+for i in 0 1; do
+    M=$(jq -r ".manifests[$i].digest" index.json | cut -d: -f2)
+    C=$(jq -r '.config.digest' "blobs/sha256/$M" | cut -d: -f2)
+    [ "$(jq -r '.author + " " + .os + " " + .config.Env[0]' "blobs/sha256/$C")" = "Defaults linux PATH=/usr/bin" ] || ok=false
+    [ "$(jq -r '.annotations["org.example.shared"]' "blobs/sha256/$M")" = "yes" ] || ok=false
+done
+M1=$(jq -r '.manifests[1].digest' index.json | cut -d: -f2)
+C1=$(jq -r '.config.digest' "blobs/sha256/$M1" | cut -d: -f2)
+[ "$(jq -r '.annotations["org.example.kind"]' "blobs/sha256/$M1")" = "arm" ] || ok=false
+[ "$(jq -r '.config.WorkingDir' "blobs/sha256/$C1")" = "/srv" ] || ok=false
+if $ok; then
+    pass "defaults deep-merged, image values take precedence"
+else
+    fail "defaults" "merged config or annotations wrong"
+fi
+
+if printf 'defaults: {bogus: 1}\nimages: [{architecture: amd64, os: linux}]\n' | build-oci --dry-run >/dev/null 2>&1; then
+    fail "defaults" "unknown key in defaults accepted"
+else
+    pass "unknown key in defaults rejected"
+fi
+
+rm -rf "$WORKDIR"
+
+# Test 33: tuning flags override the manifest
+# --------------------------------------------------
+echo ""
+echo "Test 33: --compression-threads and other tuning flags"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/rootfs"
+echo "hello" > "$WORKDIR/rootfs/file.txt"
+cd "$WORKDIR"
+
+printf 'compression-annotations: true\nskip-xattrs: false\nprefetch-limit-mb: 64\nimages: [{architecture: amd64, os: linux, layer: rootfs}]\n' \
+    | build-oci --compression-threads 3 --skip-xattrs --prefetch-limit-mb 16
+M=$(jq -r '.manifests[0].digest' index.json | cut -d: -f2)
+THREADS=$(jq -r '.layers[0].annotations["org.freedesktopsdk.layer.compression.threads"]' "blobs/sha256/$M")
+if [ "$THREADS" = "3" ]; then
+    pass "--compression-threads used for new layers"
+else
+    fail "--compression-threads" "threads annotation: $THREADS"
+fi
+if printf 'images: []\n' | build-oci --dry-run --compression-threads 0 >/dev/null 2>&1 \
+    || printf 'images: []\n' | build-oci --dry-run --prefetch-limit-mb lots >/dev/null 2>&1; then
+    fail "tuning flags" "invalid value accepted"
+else
+    pass "invalid tuning flag values rejected"
+fi
+
+rm -rf "$WORKDIR"
+
+# ======================================================================
+echo ""
+echo "============================================================"
+echo "OPTIONAL: Extraction conformance (podman / containerd)"
+echo "--------------------------------------------------------------"
+# ======================================================================
+# Set BUILD_OCI_CONFORMANCE=1 to extract a parent + child image with each
+# available container engine and compare the result to the child's input
+# rootfs: paths, types, modes, contents, symlink targets, hardlink groups
+# and user xattrs. Guards whiteout handling across releases.
+
+# One line per entry of a rootfs, with hardlinks reported as groups
+rootfs_manifest() {
+    local dir="$1"
+    (
+        cd "$dir"
+        find . -mindepth 1 \( -path ./dev -o -path ./proc -o -path ./sys -o -path ./run \) -prune -o -printf '%P\n' \
+            | LC_ALL=C sort | while IFS= read -r p; do
+            case "$p" in
+                etc/hosts|etc/hostname|etc/resolv.conf|etc/mtab) [ -e "$EXPECTED_ROOT/$p" ] || continue ;;
+            esac
+            if [ -L "$p" ]; then
+                echo "$p symlink $(readlink "$p")"
+            elif [ -d "$p" ]; then
+                echo "$p dir $(stat -c %a "$p")"
+            else
+                echo "$p file $(stat -c %a "$p") $(sha256sum < "$p" | cut -d' ' -f1)"
+            fi
+            if [ -n "$CHECK_XATTRS" ] && [ ! -L "$p" ]; then
+                getfattr --absolute-names -d -m '^user\.' "$p" 2>/dev/null | grep -v '^#' | sed "/^$/d;s|^|$p xattr |"
+            fi
+        done
+        find . -type f -links +1 -printf '%i %P\n' | LC_ALL=C sort \
+            | awk '{g[$1] = g[$1] " " $2} END {for (i in g) print "hardlinks" g[i]}' | LC_ALL=C sort
+    )
+}
+
+# Compare an extracted rootfs with the expected one
+check_extracted() {
+    local engine="$1" extracted="$2"
+    if diff <(rootfs_manifest "$EXPECTED_ROOT") <(rootfs_manifest "$extracted") > "$CONF_DIR/$engine.diff"; then
+        pass "$engine extraction matches the input rootfs"
+    else
+        fail "$engine conformance" "extracted rootfs differs from input"
+        head -20 "$CONF_DIR/$engine.diff"
+    fi
+}
+
+if [ "${BUILD_OCI_CONFORMANCE:-}" = "1" ]; then
+    CONF_DIR=$(mktemp -d)
+    mkdir -p "$CONF_DIR/base/etc" "$CONF_DIR/base/usr/bin" "$CONF_DIR/base/var/cache/app/db"
+    echo "keep" > "$CONF_DIR/base/etc/keep.conf"
+    echo "old" > "$CONF_DIR/base/etc/old.conf"
+    echo "cached" > "$CONF_DIR/base/var/cache/app/db/data"
+    printf '#!/bin/sh\necho tool\n' > "$CONF_DIR/base/usr/bin/tool"
+    chmod 755 "$CONF_DIR/base/usr/bin/tool"
+    ln "$CONF_DIR/base/usr/bin/tool" "$CONF_DIR/base/usr/bin/tool-alias"
+
+    # Child: delete a file and a directory tree, replace a hardlink, add new entries
+    cp -a "$CONF_DIR/base" "$CONF_DIR/app"
+    rm "$CONF_DIR/app/etc/old.conf"
+    rm -r "$CONF_DIR/app/var/cache/app"
+    rm "$CONF_DIR/app/usr/bin/tool-alias"
+    mkdir -p "$CONF_DIR/app/usr/lib" "$CONF_DIR/app/srv/private"
+    echo "library" > "$CONF_DIR/app/usr/lib/libnew.so.1"
+    ln -s libnew.so.1 "$CONF_DIR/app/usr/lib/libnew.so"
+    ln "$CONF_DIR/app/usr/lib/libnew.so.1" "$CONF_DIR/app/usr/lib/libnew-copy.so.1"
+    ln -s tool "$CONF_DIR/app/usr/bin/sh"
+    echo "secret" > "$CONF_DIR/app/srv/private/key"
+    chmod 600 "$CONF_DIR/app/srv/private/key"
+    chmod 750 "$CONF_DIR/app/srv/private"
+    CHECK_XATTRS=""
+    if command -v setfattr >/dev/null 2>&1 && command -v getfattr >/dev/null 2>&1 \
+        && setfattr -n user.conformance -v yes "$CONF_DIR/app/etc/keep.conf" 2>/dev/null; then
+        CHECK_XATTRS=1
+    fi
+    EXPECTED_ROOT="$CONF_DIR/app"
+
+    cd "$CONF_DIR"
+    printf 'output: parent\ncompression: gzip\nimages: [{architecture: amd64, os: linux, layer: base}]\n' | build-oci
+    printf 'output: child\ncompression: gzip\nimages: [{architecture: amd64, os: linux, parent: {image: parent}, layer: app, index-annotations: {org.opencontainers.image.ref.name: conformance}}]\n' | build-oci
+
+    if command -v podman >/dev/null 2>&1; then
+        IMG=""
+        if IMG=$(podman pull -q "oci:$CONF_DIR/child:conformance" 2>/dev/null) \
+            && CTR=$(podman create "$IMG" /bin/true 2>/dev/null); then
+            mkdir "$CONF_DIR/podman"
+            podman export "$CTR" | tar --xattrs --xattrs-include='user.*' -xf - -C "$CONF_DIR/podman"
+            podman rm "$CTR" >/dev/null
+            check_extracted podman "$CONF_DIR/podman"
+        else
+            warn "podman conformance" "could not load the image"
+        fi
+        [ -n "$IMG" ] && podman rmi -f "$IMG" >/dev/null 2>&1 || true
+    else
+        info "podman not available, skipping"
+    fi
+
+    if command -v ctr >/dev/null 2>&1 && ctr version >/dev/null 2>&1; then
+        tar -C "$CONF_DIR/child" -cf "$CONF_DIR/child.tar" .
+        if ctr -n build-oci-conformance images import --index-name build-oci/conformance "$CONF_DIR/child.tar" >/dev/null 2>&1 \
+            && mkdir "$CONF_DIR/containerd" \
+            && ctr -n build-oci-conformance images mount build-oci/conformance "$CONF_DIR/containerd" >/dev/null 2>&1; then
+            check_extracted containerd "$CONF_DIR/containerd"
+            ctr -n build-oci-conformance images unmount --rm "$CONF_DIR/containerd" >/dev/null 2>&1 || true
+        else
+            warn "containerd conformance" "could not import or mount the image"
+        fi
+        ctr -n build-oci-conformance images rm build-oci/conformance >/dev/null 2>&1 || true
+    else
+        info "containerd (ctr) not available, skipping"
+    fi
+
+    cd /
+    rm -rf "$CONF_DIR"
+else
+    info "Set BUILD_OCI_CONFORMANCE=1 to run extraction conformance checks"
+fi
+
+# Test 34: manifest linter
+# --------------------------------------------------
+echo ""
+echo "Test 34: build-oci lint"
+
+WORKDIR=$(mktemp -d)
+cd "$WORKDIR"
+cat > bad.yaml <<'YAML'
+annotations: {Title: x}
+images:
+  - os: linux
+    parent: {image: ./missing-parent}
+    config: {Entrypoint: "/bin/sh -c run"}
+  - {architecture: amd64, os: linux}
+  - {architecture: amd64, os: linux}
+YAML
+
+set +e
+build-oci lint bad.yaml > lint.out 2>&1
+RC=$?
+set -e
+FOUND=0
+for pattern in "annotations.Title" "images\[0\]: missing architecture" "parent.image" "config.Entrypoint" "same platform linux/amd64 as images\[1\]"; do
+    grep -q "$pattern" lint.out && FOUND=$((FOUND + 1))
+done
+if [ "$RC" = "2" ] && [ "$FOUND" = "5" ]; then
+    pass "lint reports all common mistakes"
+else
+    fail "lint" "exit $RC, $FOUND of 5 problems reported"
+fi
+if echo 'images: [{architecture: amd64, os: linux, annotations: {org.example.title: ok}}]' | build-oci lint >/dev/null; then
+    pass "clean manifest passes lint"
+else
+    fail "lint" "clean manifest rejected"
+fi
+
+cd /
+rm -rf "$WORKDIR"
+
+# Test 35: hostile parent layers
+# --------------------------------------------------
+echo ""
+echo "Test 35: Hostile parent layer tars are rejected cleanly"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/app"
+echo "app" > "$WORKDIR/app/app.txt"
+cd "$WORKDIR"
+
+# Write a parent layout whose only (uncompressed) layer is the given bytes
+make_parent() {
+    python3 - "$1" "$2" <<'PYEOF'
+import hashlib, json, os, sys
+out, kind = sys.argv[1], sys.argv[2]
+
+def header(name, typeflag, size):
+    h = bytearray(512)
+    h[0:len(name)] = name.encode()
+    h[100:108] = b"0000644\0"
+    h[108:116] = b"0000000\0"
+    h[116:124] = b"0000000\0"
+    h[124:136] = b"%011o\0" % size
+    h[136:148] = b"00000000000\0"
+    h[156:157] = typeflag
+    h[257:265] = b"ustar  \0"
+    h[148:156] = b"        "
+    h[148:156] = b"%06o\0 " % sum(h)
+    return bytes(h)
+
+if kind == "huge-pax":
+    # PAX header claiming 8 GiB, as a decompression bomb would deliver
+    layer = header("PaxHeaders/x", b"x", 0o77777777777) + b"\0" * 4096
+elif kind == "bad-checksum":
+    h = bytearray(header("file", b"0", 0))
+    h[0] = ord("g")
+    layer = bytes(h) + b"\0" * 1024
+else:
+    layer = header("file", b"0", 1 << 20) + b"short"
+
+os.makedirs(f"{out}/blobs/sha256")
+def blob(data):
+    digest = hashlib.sha256(data).hexdigest()
+    open(f"{out}/blobs/sha256/{digest}", "wb").write(data)
+    return {"digest": "sha256:" + digest, "size": len(data)}
+diff = "sha256:" + hashlib.sha256(layer).hexdigest()
+config = blob(json.dumps({"architecture": "amd64", "os": "linux",
+                          "rootfs": {"type": "layers", "diff_ids": [diff]}}).encode())
+layer_desc = dict(blob(layer), mediaType="application/vnd.oci.image.layer.v1.tar")
+manifest = blob(json.dumps({"schemaVersion": 2,
+                            "config": dict(config, mediaType="application/vnd.oci.image.config.v1+json"),
+                            "layers": [layer_desc]}).encode())
+json.dump({"schemaVersion": 2, "manifests": [dict(manifest, mediaType="application/vnd.oci.image.manifest.v1+json")]},
+          open(f"{out}/index.json", "w"))
+open(f"{out}/oci-layout", "w").write('{"imageLayoutVersion":"1.0.0"}')
+PYEOF
+}
+
+for kind in huge-pax bad-checksum truncated; do
+    make_parent "parent-$kind" "$kind"
+    set +e
+    printf 'output: child-%s\ncompression: disabled\nimages: [{architecture: amd64, os: linux, parent: {image: parent-%s}, layer: app}]\n' "$kind" "$kind" \
+        | build-oci > "$kind.out" 2>&1
+    RC=$?
+    set -e
+    case "$kind" in
+        huge-pax) EXPECTED="exceeds the 1048576 byte limit" ;;
+        bad-checksum) EXPECTED="checksum mismatch" ;;
+        truncated) EXPECTED="unexpected end of archive" ;;
+    esac
+    if [ "$RC" -ne 0 ] && [ "$RC" -lt 128 ] && grep -q "$EXPECTED" "$kind.out" && grep -q "offset 0" "$kind.out"; then
+        pass "$kind parent layer rejected with a located error"
+    else
+        fail "$kind parent layer" "exit $RC: $(tail -3 "$kind.out")"
+    fi
+done
+
+cd /
+rm -rf "$WORKDIR"
+
+# Test 36: failures isolated per image
+# --------------------------------------------------
+echo ""
+echo "Test 36: Failing images do not stop the others"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/ok"
+echo "ok" > "$WORKDIR/ok/file.txt"
+cd "$WORKDIR"
+
+set +e
+build-oci > out.txt 2>&1 <<'YAML'
+compression: disabled
+images:
+  - {architecture: amd64, os: linux, parent: {image: missing-a}}
+  - {architecture: arm64, os: linux, layer: ok}
+  - {architecture: riscv64, os: linux, parent: {image: missing-b}}
+YAML
+RC=$?
+set -e
+# The arm64 image still produced its layer, config and manifest
+if [ "$RC" -ne 0 ] && grep -q "2 of 3 images failed" out.txt \
+    && grep -q "images\[0\] (linux/amd64)" out.txt && grep -q "images\[2\] (linux/riscv64)" out.txt \
+    && [ "$(ls blobs/sha256 | wc -l)" = "3" ] && [ ! -f index.json ]; then
+    pass "all image failures reported, remaining image built"
+else
+    fail "per-image isolation" "exit $RC: $(head -5 out.txt)"
+fi
+
+cd /
+rm -rf "$WORKDIR"
+
+# Test 37: JSON and TOML manifests
+# --------------------------------------------------
+echo ""
+echo "Test 37: JSON and TOML manifests"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/rootfs"
+echo "hello" > "$WORKDIR/rootfs/file.txt"
+cd "$WORKDIR"
+export SOURCE_DATE_EPOCH=1700000000
+
+printf 'output: yaml\nimages:\n  - architecture: amd64\n    os: linux\n    layer: rootfs\n    config: {Env: [A=b]}\n' | build-oci
+echo '{"output": "json", "images": [{"architecture": "amd64", "os": "linux", "layer": "rootfs", "config": {"Env": ["A=b"]}}]}' | build-oci
+cat > manifest.toml <<'TOML'
+# Same image as TOML
+output = "toml"
+
+[[images]]
+architecture = "amd64"
+os = "linux"
+layer = "rootfs"
+config = { Env = ["A=b"] }
+TOML
+build-oci < manifest.toml
+cp manifest.toml manifest.txt
+sed -i 's/^output = "toml"/output = "toml-explicit"/' manifest.txt
+build-oci --format toml < manifest.txt
+
+Y=$(jq -r '.manifests[0].digest' yaml/index.json)
+if [ "$(jq -r '.manifests[0].digest' json/index.json)" = "$Y" ] \
+    && [ "$(jq -r '.manifests[0].digest' toml/index.json)" = "$Y" ] \
+    && [ "$(jq -r '.manifests[0].digest' toml-explicit/index.json)" = "$Y" ]; then
+    pass "YAML, JSON and TOML manifests build identical images"
+else
+    fail "manifest formats" "manifest digests differ"
+fi
+if echo 'images: []' | build-oci --format json >/dev/null 2>&1; then
+    fail "--format" "YAML accepted as JSON"
+else
+    pass "--format json rejects YAML input"
+fi
+
+unset SOURCE_DATE_EPOCH
+cd /
+rm -rf "$WORKDIR"
+
+# Test 38: Progress bars
+# --------------------------------------------------
+echo ""
+echo "Test 38: Progress bars"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/rootfs"
+for i in 1 2 3; do echo "file $i" > "$WORKDIR/rootfs/f$i"; done
+cd "$WORKDIR"
+export SOURCE_DATE_EPOCH=1700000000
+
+printf 'output: plain\nimages:\n  - architecture: amd64\n    os: linux\n    layer: rootfs\n' | build-oci 2> plain.err
+if grep -q $'\e' plain.err; then
+    fail "progress" "escape codes written to redirected stderr"
+else
+    pass "No progress bars when stderr is not a terminal"
+fi
+
+if command -v script >/dev/null 2>&1; then
+    BIN=$(command -v build-oci)
+    printf 'output: tty\nimages:\n  - architecture: amd64\n    os: linux\n    layer: rootfs\n' > tty.yaml
+    sed 's/output: tty/output: tty-quiet/' tty.yaml > tty-quiet.yaml
+    script -qc "$BIN < tty.yaml" /dev/null > tty.out
+    script -qc "$BIN --no-progress < tty-quiet.yaml" /dev/null > tty-quiet.out
+    if grep -q 'linux/amd64' tty.out && ! grep -q 'linux/amd64' tty-quiet.out; then
+        pass "Progress bars drawn on a terminal and disabled by --no-progress"
+    else
+        fail "progress" "unexpected terminal output"
+    fi
+    if [ "$(jq -r '.manifests[0].digest' tty/index.json)" = "$(jq -r '.manifests[0].digest' plain/index.json)" ]; then
+        pass "Progress output does not change the image"
+    else
+        fail "progress" "digests differ with progress bars"
+    fi
+else
+    warn "script not available, skipping terminal progress checks"
+fi
+
+unset SOURCE_DATE_EPOCH
+cd /
+rm -rf "$WORKDIR"
+
+# Test 39: --keep-going
+# --------------------------------------------------
+echo ""
+echo "Test 39: --keep-going writes the images that built"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/ok"
+echo "ok" > "$WORKDIR/ok/file.txt"
+cd "$WORKDIR"
+
+set +e
+build-oci --keep-going --iidfile iid.json --error-json err.json > out.txt 2>&1 <<'YAML'
+compression: disabled
+images:
+  - {architecture: amd64, os: linux, parent: {image: missing-a}}
+  - {architecture: arm64, os: linux, layer: ok}
+YAML
+RC=$?
+set -e
+if [ "$RC" -eq 3 ] && [ "$(jq '.manifests | length' index.json)" = "1" ] \
+    && [ "$(jq -r '.manifests[0].platform.architecture' index.json)" = "arm64" ] \
+    && [ "$(jq '.[0].manifests | length' iid.json)" = "1" ]; then
+    pass "index.json and --iidfile hold the successful image, exit code of the failure"
+else
+    fail "--keep-going" "exit $RC: $(head -5 out.txt)"
+fi
+if [ "$(jq -r '.images | length' err.json)" = "1" ] && [ "$(jq -r '.images[0].image' err.json)" = "0" ] \
+    && [ "$(jq -r '.images[0].platform' err.json)" = "linux/amd64" ] \
+    && [ "$(jq -r '.images[0].category' err.json)" = "missing-parent" ]; then
+    pass "--error-json lists the failed image"
+else
+    fail "--keep-going" "unexpected report: $(cat err.json)"
+fi
+
+rm -f index.json
+set +e
+echo '{architecture: amd64, os: linux, parent: {image: missing}}' | sed 's/^/images: [/; s/$/]/' \
+    | build-oci --keep-going > out.txt 2>&1
+RC=$?
+set -e
+if [ "$RC" -eq 3 ] && [ ! -f index.json ]; then
+    pass "nothing written when every image fails"
+else
+    fail "--keep-going" "exit $RC with all images failing"
+fi
+
+cd /
+rm -rf "$WORKDIR"
+
+# Test 40: max-memory-mb and max-open-files
+# --------------------------------------------------
+echo ""
+echo "Test 40: Build-wide memory and open file limits"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/base" "$WORKDIR/app"
+head -c 3000000 /dev/urandom > "$WORKDIR/base/big.bin"
+for i in 1 2 3 4 5; do echo "small $i" > "$WORKDIR/base/s$i"; done
+head -c 2000000 /dev/urandom > "$WORKDIR/app/big2.bin"
+cd "$WORKDIR"
+export SOURCE_DATE_EPOCH=1700000000
+
+printf 'output: parent\ncompression: gzip\nimages:\n  - {architecture: amd64, os: linux, layer: base}\n' | build-oci
+for variant in unlimited limited; do
+    LIMITS=""
+    [ "$variant" = limited ] && LIMITS=$'max-memory-mb: 0\nmax-open-files: 1\n'
+    printf 'output: %s\n%simages:\n  - {architecture: amd64, os: linux, layer: base}\n  - {architecture: arm64, os: linux, layer: app, parent: {image: parent}}\n' \
+        "$variant" "$LIMITS" | RUST_LOG=debug build-oci --log-format json 2> "$variant.log"
+done
+if [ "$(jq -c '[.manifests[].digest]' limited/index.json)" = "$(jq -c '[.manifests[].digest]' unlimited/index.json)" ]; then
+    pass "Limits do not change the images"
+else
+    fail "resource limits" "digests differ under limits"
+fi
+if grep "walked layer directory" limited.log | grep -q '"cached_bytes":0' \
+    && ! grep "walked layer directory" unlimited.log | grep -q '"cached_bytes":0'; then
+    pass "max-memory-mb: 0 disables file caching"
+else
+    fail "resource limits" "file contents cached despite max-memory-mb: 0"
+fi
+if printf 'images:\n  - {architecture: amd64, os: linux, layer: base}\n' \
+    | build-oci --max-open-files x >/dev/null 2>&1; then
+    fail "resource limits" "invalid --max-open-files accepted"
+else
+    pass "--max-open-files requires an integer"
+fi
+
+unset SOURCE_DATE_EPOCH
+cd /
+rm -rf "$WORKDIR"
+
+# Test 41: parent in the output layout
+# --------------------------------------------------
+echo ""
+echo "Test 41: Parents in the output layout are referenced in place"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/rootfs" "$WORKDIR/app"
+echo "hello" > "$WORKDIR/rootfs/file.txt"
+cp "$WORKDIR/rootfs/file.txt" "$WORKDIR/app/"
+echo "app" > "$WORKDIR/app/app.txt"
+cd "$WORKDIR"
+
+manifest_of() { echo "out/blobs/sha256/$(jq -r ".manifests[$1].digest" out/index.json | cut -d: -f2)"; }
+printf 'output: out\ncompression: gzip\nimages:\n  - {architecture: amd64, os: linux, layer: rootfs}\n' | build-oci
+BASE_LAYERS=$(jq -c '.layers' "$(manifest_of 0)")
+BLOBS_BEFORE=$(ls out/blobs/sha256 | wc -l)
+build-oci <<'YAML'
+output: out
+images:
+  - architecture: amd64
+    os: linux
+    parent: {image: out, recompress: false}
+    config: {Env: [MODE=derived]}
+YAML
+MANIFEST=$(manifest_of 0)
+CONFIG="out/blobs/sha256/$(jq -r '.config.digest' "$MANIFEST" | cut -d: -f2)"
+# zstd output, yet the gzip parent layer is referenced unchanged
+if [ "$(jq -c '.layers' "$MANIFEST")" = "$BASE_LAYERS" ] \
+    && [ "$(jq -r '.config.Env[0]' "$CONFIG")" = "MODE=derived" ] \
+    && [ "$(ls out/blobs/sha256 | wc -l)" -eq $((BLOBS_BEFORE + 2)) ]; then
+    pass "Parent layers referenced in place, only config and manifest written"
+else
+    fail "in-place parent" "unexpected layers or blobs"
+fi
+
+# A new zstd layer on top of the gzip parent, deduplicated against it
+printf 'output: out\nimages:\n  - {architecture: amd64, os: linux, layer: app, parent: {image: out}}\n' | build-oci
+MANIFEST=$(manifest_of 0)
+if [ "$(jq -c '.layers[0]' "$MANIFEST")" = "$(echo "$BASE_LAYERS" | jq -c '.[0]')" ] \
+    && [ "$(jq -r '.layers[1].mediaType' "$MANIFEST")" = "application/vnd.oci.image.layer.v1.tar+zstd" ] \
+    && [ "$(build-oci du out --json | jq -c '.layers[1].directories | keys')" = '["app.txt"]' ]; then
+    pass "New layer deduplicated against an in-place gzip parent"
+else
+    fail "in-place parent" "unexpected layers: $(jq -c '.layers' "$MANIFEST")"
+fi
+
+set +e
+printf 'output: other\nimages:\n  - {architecture: amd64, os: linux, parent: {image: out, recompress: false}}\n' \
+    | build-oci > other.txt 2>&1
+RC_OTHER=$?
+set -e
+if [ "$RC_OTHER" -eq 2 ] && grep -q "to be the output layout" other.txt; then
+    pass "recompress: false rejected for a parent outside the output"
+else
+    fail "recompress: false" "exit $RC_OTHER"
+fi
+printf 'output: out\nimages:\n  - {architecture: amd64, os: linux, parent: {image: out, recompress: true}}\n' | build-oci
+if [ "$(jq -r '.layers[0].mediaType' "$(manifest_of 0)")" = "application/vnd.oci.image.layer.v1.tar+zstd" ]; then
+    pass "recompress: true re-encodes a parent in the output layout"
+else
+    fail "recompress: true" "parent layers not re-encoded"
+fi
+
+cd /
+rm -rf "$WORKDIR"
+
+# Test 42: --image-parallelism and --layer-threads
+# --------------------------------------------------
+echo ""
+echo "Test 42: --image-parallelism and --layer-threads"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/rootfs"
+echo "hello" > "$WORKDIR/rootfs/file.txt"
+cd "$WORKDIR"
+
+layer_threads() {
+    for d in $(jq -r '.manifests[].digest' "$1/index.json" | cut -d: -f2); do
+        jq -r '.layers[0].annotations["org.freedesktopsdk.layer.compression.threads"]' "$1/blobs/sha256/$d"
+    done | sort -u | tr '\n' ' '
+}
+MANIFEST='compression-annotations: true
+images:
+  - {architecture: amd64, os: linux, layer: rootfs}
+  - {architecture: arm64, os: linux, layer: rootfs}
+  - {architecture: riscv64, os: linux, layer: rootfs}'
+echo "output: default
+$MANIFEST" | build-oci -j 4
+echo "output: serial
+$MANIFEST" | build-oci -j 4 --image-parallelism 1
+echo "output: explicit
+$MANIFEST" | build-oci -j 4 --image-parallelism 3 --layer-threads 2
+if [ "$(layer_threads default)" = "1 " ] && [ "$(layer_threads serial)" = "4 " ] \
+    && [ "$(layer_threads explicit)" = "2 " ]; then
+    pass "Layer threads follow the image parallelism unless set"
+else
+    fail "--image-parallelism" "threads: $(layer_threads default)/$(layer_threads serial)/$(layer_threads explicit)"
+fi
+if [ "$(jq -r '[.manifests[].platform.architecture] | join(",")' explicit/index.json)" = "amd64,arm64,riscv64" ]; then
+    pass "Images keep manifest order when built in parallel"
+else
+    fail "--image-parallelism" "index order changed"
+fi
+if printf 'images: []\n' | build-oci --dry-run --image-parallelism 0 >/dev/null 2>&1; then
+    fail "--image-parallelism" "0 accepted"
+else
+    pass "--image-parallelism 0 rejected"
+fi
+
+cd /
+rm -rf "$WORKDIR"
+
+# Test 43: --fail-fast and the per-image summary
+# --------------------------------------------------
+echo ""
+echo "Test 43: --fail-fast and per-image results"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/ok"
+echo "ok" > "$WORKDIR/ok/file.txt"
+cd "$WORKDIR"
+MANIFEST='compression: disabled
+images:
+  - {architecture: amd64, os: linux, parent: {image: missing}}
+  - {architecture: arm64, os: linux, layer: ok}
+  - {architecture: riscv64, os: linux, layer: ok}'
+
+set +e
+echo "output: isolate
+$MANIFEST" | build-oci --image-parallelism 1 --log-format json > isolate.txt 2>&1
+echo "output: fast
+$MANIFEST" | build-oci --image-parallelism 1 --fail-fast --log-format json > fast.txt 2>&1
+RC=$?
+set -e
+STATUSES=$(grep '"image result"' isolate.txt | jq -r '.fields.status' | tr '\n' ' ')
+if [ "$STATUSES" = "failed built built " ]; then
+    pass "Per-image results logged when an image fails"
+else
+    fail "image summary" "statuses: $STATUSES"
+fi
+STATUSES=$(grep '"image result"' fast.txt | jq -r '.fields.status' | tr '\n' ' ')
+if [ "$RC" -eq 3 ] && [ "$STATUSES" = "failed skipped skipped " ] \
+    && grep -q "1 of 3 images failed, 2 skipped" fast.txt && [ ! -d fast/blobs/sha256 -o -z "$(ls fast/blobs/sha256)" ]; then
+    pass "--fail-fast skips the remaining images"
+else
+    fail "--fail-fast" "exit $RC, statuses: $STATUSES"
+fi
+if echo 'images: []' | build-oci --fail-fast --keep-going >/dev/null 2>&1; then
+    fail "--fail-fast" "combined with --keep-going"
+else
+    pass "--fail-fast and --keep-going are exclusive"
+fi
+
+cd /
+rm -rf "$WORKDIR"
+
+# Test 44: lazy-pull parent layers
+# --------------------------------------------------
+echo ""
+echo "Test 44: eStargz parent layers keep their annotations"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/app"
+echo "a" > "$WORKDIR/app/a.txt"
+echo "b" > "$WORKDIR/app/b.txt"
+echo "new" > "$WORKDIR/app/new.txt"
+cd "$WORKDIR"
+export SOURCE_DATE_EPOCH=1700000000
+
+# An eStargz-style parent: every 512-byte block of a layer tar in its own
+# gzip member plus the STARGZ footer member, so only a multi-member gzip
+# reader sees every file
+mkdir base
+cp app/a.txt app/b.txt base/
+printf 'output: plain\ncompression: disabled\nimages: [{architecture: amd64, os: linux, layer: base}]\n' | build-oci
+python3 - plain parent <<'PYEOF'
+import hashlib, json, os, sys, zlib
+src, out = sys.argv[1], sys.argv[2]
+def read_blob(digest):
+    return open(f"{src}/blobs/sha256/{digest.split(':')[1]}", "rb").read()
+src_manifest = json.loads(read_blob(json.load(open(f"{src}/index.json"))["manifests"][0]["digest"]))
+layer = read_blob(src_manifest["layers"][0]["digest"])
+def member(data):
+    c = zlib.compressobj(9, zlib.DEFLATED, 31)
+    return c.compress(data) + c.flush()
+extra = b"SG" + (22).to_bytes(2, "little") + b"%016xSTARGZ" % 0
+footer = (b"\x1f\x8b\x08\x04\0\0\0\0\0\xff" + len(extra).to_bytes(2, "little") + extra
+          + b"\x03\x00" + b"\0" * 8)
+blob_data = b"".join(member(layer[i:i + 512]) for i in range(0, len(layer), 512)) + footer
+
+os.makedirs(f"{out}/blobs/sha256")
+def blob(data):
+    digest = hashlib.sha256(data).hexdigest()
+    open(f"{out}/blobs/sha256/{digest}", "wb").write(data)
+    return {"digest": "sha256:" + digest, "size": len(data)}
+diff = "sha256:" + hashlib.sha256(layer).hexdigest()
+config = blob(json.dumps({"architecture": "amd64", "os": "linux",
+                          "rootfs": {"type": "layers", "diff_ids": [diff]}}).encode())
+layer_desc = dict(blob(blob_data), mediaType="application/vnd.oci.image.layer.v1.tar+gzip")
+layer_desc["annotations"] = {
+    "containerd.io/snapshot/stargz/toc.digest": "sha256:" + "ab" * 32,
+    "io.containers.estargz.uncompressed-size": str(len(layer)),
+}
+manifest = blob(json.dumps({"schemaVersion": 2,
+                            "config": dict(config, mediaType="application/vnd.oci.image.config.v1+json"),
+                            "layers": [layer_desc]}).encode())
+json.dump({"schemaVersion": 2, "manifests": [dict(manifest, mediaType="application/vnd.oci.image.manifest.v1+json")]},
+          open(f"{out}/index.json", "w"))
+open(f"{out}/oci-layout", "w").write('{"imageLayoutVersion":"1.0.0"}')
+PYEOF
+
+if build-oci verify parent --lazy-pull | grep -q "1 lazy-pull layers OK"; then
+    pass "verify --lazy-pull accepts an annotated eStargz layer"
+else
+    fail "verify --lazy-pull" "annotated parent rejected"
+fi
+
+printf 'output: gzip\ncompression: gzip\nimages: [{architecture: amd64, os: linux, parent: {image: parent}, layer: app}]\n' | build-oci
+printf 'output: zstd\ncompression: zstd\nimages: [{architecture: amd64, os: linux, parent: {image: parent}, layer: app}]\n' \
+    | build-oci 2> zstd.err
+M=$(jq -r '.manifests[0].digest' gzip/index.json | cut -d: -f2)
+if [ "$(jq -r '.layers[0].annotations["containerd.io/snapshot/stargz/toc.digest"]' "gzip/blobs/sha256/$M")" = "sha256:$(printf 'ab%.0s' $(seq 32))" ] \
+    && build-oci verify gzip --lazy-pull | grep -q "1 lazy-pull layers OK"; then
+    pass "Verbatim parent copies keep the lazy-pull annotations"
+else
+    fail "lazy-pull annotations" "annotations lost in verbatim copy"
+fi
+# a.txt and b.txt live in later gzip members of the parent and are deduplicated
+if [ "$(build-oci du gzip --json | jq -c '.layers[1].directories | keys')" = '["new.txt"]' ] \
+    && [ "$(build-oci du zstd --json | jq -r '.layers[0].total.files')" = "2" ] \
+    && grep -q "re-encoding drops the lazy-pull format" zstd.err; then
+    pass "Multi-member gzip parents are read in full"
+else
+    fail "lazy-pull parents" "parent layer not read in full"
+fi
+
+jq '.layers[0].annotations = {}' "gzip/blobs/sha256/$M" > stripped.json
+STRIPPED=$(sha256sum stripped.json | cut -d' ' -f1)
+cp stripped.json "gzip/blobs/sha256/$STRIPPED"
+jq --arg d "sha256:$STRIPPED" --argjson s "$(stat -c %s stripped.json)" \
+    '.manifests[0].digest = $d | .manifests[0].size = $s' gzip/index.json > index.tmp && mv index.tmp gzip/index.json
+set +e
+build-oci verify gzip --lazy-pull > stripped.out 2>&1
+RC=$?
+set -e
+if [ "$RC" -ne 0 ] && grep -q "eStargz layer without containerd.io/snapshot/stargz/toc.digest" stripped.out; then
+    pass "verify --lazy-pull reports missing annotations"
+else
+    fail "verify --lazy-pull" "missing annotations not reported (exit $RC)"
+fi
+
+unset SOURCE_DATE_EPOCH
+cd /
+rm -rf "$WORKDIR"
+
+# Test 45: manifest includes
+# --------------------------------------------------
+echo ""
+echo "Test 45: include: pulls in shared manifest fragments"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/rootfs" "$WORKDIR/shared" "$WORKDIR/elsewhere"
+echo "data" > "$WORKDIR/rootfs/file.txt"
+cat > "$WORKDIR/shared/config.yaml" <<'YAML'
+include: env.yaml
+config:
+  User: "1000"
+annotations:
+  org.example.team: platform
+YAML
+cat > "$WORKDIR/shared/env.yaml" <<'YAML'
+config:
+  Env: [PATH=/usr/bin]
+  WorkingDir: /srv
+YAML
+cat > "$WORKDIR/manifest.yaml" <<YAML
+compression: disabled
+output: $WORKDIR/out
+images:
+  - architecture: amd64
+    os: linux
+    layer: $WORKDIR/rootfs
+    include: shared/config.yaml
+    config:
+      WorkingDir: /app
+    labels:
+      include: x
+YAML
+cd "$WORKDIR/elsewhere"
+build-oci lint ../manifest.yaml >/dev/null
+cd "$WORKDIR"
+build-oci < manifest.yaml
+MANIFEST_DIGEST=$(jq -r '.manifests[0].digest' out/index.json | cut -d: -f2)
+CONFIG_DIGEST=$(jq -r '.config.digest' "out/blobs/sha256/$MANIFEST_DIGEST" | cut -d: -f2)
+CONFIG=$(jq -c '.config | {Env, User, WorkingDir}' "out/blobs/sha256/$CONFIG_DIGEST")
+if [ "$CONFIG" = '{"Env":["PATH=/usr/bin"],"User":"1000","WorkingDir":"/app"}' ] \
+    && jq -e '.annotations["org.example.team"] == "platform"' "out/blobs/sha256/$MANIFEST_DIGEST" >/dev/null; then
+    pass "Nested includes resolve relative to the including file"
+else
+    fail "include" "config: $CONFIG"
+fi
+if [ "$(jq -c '.config.Labels' "out/blobs/sha256/$CONFIG_DIGEST")" = '{"include":"x"}' ]; then
+    pass "Only the document and images: entries take include:, other maps keep the key"
+else
+    fail "include" "labels: $(jq -c '.config.Labels' "out/blobs/sha256/$CONFIG_DIGEST")"
+fi
+
+echo "include: b.yaml" > shared/a.yaml
+echo "include: a.yaml" > shared/b.yaml
+set +e
+printf 'include: shared/a.yaml\nimages: []\n' | build-oci --dry-run > cycle.txt 2>&1
+RC=$?
+set -e
+if [ "$RC" -eq 2 ] && grep -q "Include cycle: .*shared/a.yaml -> .*shared/b.yaml -> .*shared/a.yaml" cycle.txt; then
+    pass "Include cycles are rejected"
+else
+    fail "include cycle" "exit $RC: $(cat cycle.txt)"
+fi
+set +e
+printf 'images: [{include: missing.yaml}]\n' | build-oci --dry-run > missing.txt 2>&1
+RC=$?
+set -e
+if [ "$RC" -eq 2 ] && grep -q "images\[0\].include: including missing.yaml" missing.txt; then
+    pass "Missing include files are reported"
+else
+    fail "include" "missing file: exit $RC: $(cat missing.txt)"
+fi
+
+cd /
+rm -rf "$WORKDIR"
+
+# Test 46: ggcr compatibility profile
+# --------------------------------------------------
+echo ""
+echo "Test 46: compatibility: ggcr records the platform for crane/ko"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/rootfs"
+echo "data" > "$WORKDIR/rootfs/file.txt"
+cd "$WORKDIR"
+MANIFEST='compression: disabled
+source-date-epoch: 0
+images:
+  - {architecture: arm64, os: linux, variant: v8, layer: rootfs}'
+echo "output: oci
+$MANIFEST" | build-oci
+echo "output: ggcr
+compatibility: ggcr
+$MANIFEST" | build-oci
+manifest_blob() {
+    echo "$1/blobs/sha256/$(jq -r '.manifests[0].digest' "$1/index.json" | cut -d: -f2)"
+}
+config_blob() {
+    echo "$1/blobs/sha256/$(jq -r '.config.digest' "$(manifest_blob "$1")" | cut -d: -f2)"
+}
+if jq -e '.config.platform == null' "$(manifest_blob oci)" >/dev/null \
+    && jq -e '.variant == null' "$(config_blob oci)" >/dev/null; then
+    pass "Default profile leaves manifest and config unchanged"
+else
+    fail "compatibility" "oci profile added platform fields"
+fi
+if jq -e '.config.platform == {"os": "linux", "architecture": "arm64", "variant": "v8"}' "$(manifest_blob ggcr)" >/dev/null \
+    && jq -e '.variant == "v8" and .architecture == "arm64"' "$(config_blob ggcr)" >/dev/null \
+    && jq -e '.manifests[0].platform.variant == "v8"' ggcr/index.json >/dev/null; then
+    pass "ggcr profile records the platform in config and config descriptor"
+else
+    fail "compatibility" "ggcr profile: $(jq -c .config "$(manifest_blob ggcr)")"
+fi
+set +e
+printf 'compatibility: docker\nimages: []\n' | build-oci --dry-run >/dev/null 2>&1
+RC=$?
+set -e
+if [ "$RC" -eq 2 ]; then
+    pass "Unknown compatibility profile rejected"
+else
+    fail "compatibility" "unknown profile: exit $RC"
+fi
+
+cd /
+rm -rf "$WORKDIR"
+
+# Test 47: build priority
+# --------------------------------------------------
+echo ""
+echo "Test 47: priority: nice level, I/O class and CPU pinning"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/rootfs"
+seq 1 20000 > "$WORKDIR/rootfs/numbers.txt"
+cd "$WORKDIR"
+FIRST_CPU=$(cut -d, -f1 /sys/fs/cgroup/cpuset.cpus.effective 2>/dev/null | cut -d- -f1)
+FIRST_CPU=${FIRST_CPU:-0}
+if printf 'output: niced\npriority: {nice: 10, io-class: idle, cpus: "%s"}\nimages:\n  - {architecture: amd64, os: linux, layer: rootfs}\n' "$FIRST_CPU" | build-oci \
+    && printf 'output: flags\nimages:\n  - {architecture: amd64, os: linux, layer: rootfs}\n' \
+        | build-oci --nice 12 --io-class best-effort --cpus "$FIRST_CPU" \
+    && [ "$(jq -r '.manifests[0].digest' niced/index.json)" = "$(jq -r '.manifests[0].digest' flags/index.json)" ]; then
+    pass "Builds with a priority produce the same image"
+else
+    fail "priority" "build with nice/io-class/cpus failed"
+fi
+REJECTED=0
+for PRIORITY in '{nice: 20}' '{io-class: realtime}' '{io-class: idle, io-level: 3}' '{cpus: "3-1"}' '{cpus: "100000"}'; do
+    set +e
+    printf 'output: bad\npriority: %s\nimages:\n  - {architecture: amd64, os: linux, layer: rootfs}\n' "$PRIORITY" | build-oci >/dev/null 2>&1
+    RC=$?
+    set -e
+    if [ "$RC" -eq 2 ]; then
+        REJECTED=$((REJECTED + 1))
+    else
+        echo "  priority $PRIORITY: exit $RC"
+    fi
+done
+if [ "$REJECTED" -eq 5 ]; then
+    pass "Invalid priority settings rejected"
+else
+    fail "priority" "$REJECTED of 5 invalid settings rejected"
+fi
+
+cd /
+rm -rf "$WORKDIR"
+
+# Test 48: watch mode
+# --------------------------------------------------
+echo ""
+echo "Test 48: build-oci watch rebuilds images whose layer changed"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/a" "$WORKDIR/b"
+echo "1" > "$WORKDIR/a/file.txt"
+echo "1" > "$WORKDIR/b/file.txt"
+cd "$WORKDIR"
+printf 'output: out\ncompression: disabled\nsource-date-epoch: 0\nimages:\n  - {architecture: amd64, os: linux, layer: a}\n  - {architecture: arm64, os: linux, layer: b}\n' > manifest.yaml
+build-oci watch --debounce-ms 100 < manifest.yaml > watch.txt 2>&1 &
+WATCH_PID=$!
+# Wait for up to 20s until watch.txt has $1 lines saying images were built
+wait_builds() {
+    for _ in $(seq 1 200); do
+        [ "$(grep -c "image(s) in" watch.txt)" -ge "$1" ] && return 0
+        sleep 0.1
+    done
+    return 1
+}
+digests() {
+    jq -r '[.manifests[].digest] | join(" ")' out/index.json
+}
+wait_builds 1 || true
+BEFORE=$(digests)
+mkdir a/sub
+echo "2" > a/sub/new.txt
+wait_builds 2 || true
+AFTER=$(digests)
+echo "3" > a/sub/new.txt
+wait_builds 3 || true
+kill "$WATCH_PID" 2>/dev/null || true
+wait "$WATCH_PID" 2>/dev/null || true
+if [ "${BEFORE%% *}" != "${AFTER%% *}" ] && [ "${BEFORE##* }" = "${AFTER##* }" ] \
+    && grep -q "built 1 of 1 image(s)" watch.txt; then
+    pass "Only the image whose layer changed is rebuilt"
+else
+    fail "watch" "digests $BEFORE -> $AFTER: $(cat watch.txt)"
+fi
+if [ "$(grep -c "image(s) in" watch.txt)" -ge 3 ] && [ "$(digests)" != "$AFTER" ]; then
+    pass "Directories created while watching are watched too"
+else
+    fail "watch" "no rebuild for a change in a new directory: $(cat watch.txt)"
+fi
+set +e
+build-oci watch --dry-run < manifest.yaml >/dev/null 2>&1
+RC=$?
+set -e
+if [ "$RC" -eq 2 ]; then
+    pass "watch rejects --dry-run"
+else
+    fail "watch" "--dry-run: exit $RC"
+fi
+
+cd /
+rm -rf "$WORKDIR"
+
+# Test 49: nested per-name image indexes
+# --------------------------------------------------
+echo ""
+echo "Test 49: name:/tag: group platforms into nested image indexes"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/rootfs"
+echo "data" > "$WORKDIR/rootfs/file.txt"
+cd "$WORKDIR"
+cat > manifest.yaml <<'YAML'
+output: out
+compression: disabled
+source-date-epoch: 0
+images:
+  - {architecture: amd64, os: linux, layer: rootfs, name: example/app, tag: "1.0"}
+  - {architecture: amd64, os: linux, layer: rootfs}
+  - {architecture: arm64, os: linux, layer: rootfs, name: example/app, tag: "1.0"}
+  - {architecture: amd64, os: linux, layer: rootfs, name: example/tools}
+YAML
+build-oci lint manifest.yaml >/dev/null
+build-oci --iidfile iid.json < manifest.yaml
+TOP=$(jq -c '[.manifests[] | [.mediaType, .annotations["org.opencontainers.image.ref.name"]]]' out/index.json)
+EXPECTED='[["application/vnd.oci.image.index.v1+json","example/app:1.0"],["application/vnd.oci.image.manifest.v1+json",null],["application/vnd.oci.image.index.v1+json","example/tools:latest"]]'
+NESTED=$(jq -r '.manifests[0].digest' out/index.json | cut -d: -f2)
+if [ "$TOP" = "$EXPECTED" ] \
+    && [ "$(jq -c '[.manifests[].platform.architecture]' "out/blobs/sha256/$NESTED")" = '["amd64","arm64"]' ] \
+    && [ "$(jq -r '.[0].manifests[0]' iid.json)" = "$(jq -r '.manifests[0].digest' out/index.json)" ]; then
+    pass "Images sharing a name and tag form one nested index"
+else
+    fail "name/tag" "index.json entries: $TOP"
+fi
+# index.json has 3 entries, but parent.index counts the 4 images
+printf 'output: child\ncompression: disabled\nimages:\n  - {architecture: amd64, os: linux, parent: {image: out, index: 3}}\n' | build-oci
+CHILD_MANIFEST=$(jq -r '.manifests[0].digest' child/index.json | cut -d: -f2)
+if build-oci du out:example/tools:latest >/dev/null \
+    && [ "$(jq -r '.layers | length' "child/blobs/sha256/$CHILD_MANIFEST")" = "1" ]; then
+    pass "Nested indexes resolve for du and parent.index"
+else
+    fail "name/tag" "nested index not usable as a parent or by du"
+fi
+set +e
+printf 'images:\n  - {architecture: amd64, os: linux, tag: "1.0"}\n' | build-oci --dry-run >/dev/null 2>&1
+RC=$?
+set -e
+if [ "$RC" -eq 2 ]; then
+    pass "tag: without name: rejected"
+else
+    fail "name/tag" "tag without name: exit $RC"
+fi
+
+cd /
+rm -rf "$WORKDIR"
+
+# Test 50: parent-layers: preserve
+# --------------------------------------------------
+echo ""
+echo "Test 50: parent-layers: preserve keeps parent blobs as they are"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/base" "$WORKDIR/app"
+echo "base" > "$WORKDIR/base/base.txt"
+echo "app" > "$WORKDIR/app/app.txt"
+cd "$WORKDIR"
+printf 'output: parent\ncompression: gzip\nimages:\n  - {architecture: amd64, os: linux, layer: base}\n' | build-oci
+CHILD='compression: zstd
+images:
+  - {architecture: amd64, os: linux, layer: app, parent: {image: parent}}'
+echo "output: preserved
+parent-layers: preserve
+$CHILD" | build-oci
+echo "output: recompressed
+parent-layers: preserve
+compression: zstd
+images:
+  - {architecture: amd64, os: linux, layer: app, parent: {image: parent, recompress: true}}" | build-oci
+first_layer() {
+    jq -c '.layers[0] | {mediaType, digest}' "$1/blobs/sha256/$(jq -r '.manifests[0].digest' "$1/index.json" | cut -d: -f2)"
+}
+PARENT_LAYER=$(first_layer parent)
+DIGEST=$(echo "$PARENT_LAYER" | jq -r .digest | cut -d: -f2)
+if [ "$(first_layer preserved)" = "$PARENT_LAYER" ] \
+    && [ "$(stat -c %i "parent/blobs/sha256/$DIGEST")" = "$(stat -c %i "preserved/blobs/sha256/$DIGEST")" ]; then
+    pass "Parent layer kept with its digest and hard linked"
+else
+    fail "parent-layers" "preserved layer: $(first_layer preserved), parent: $PARENT_LAYER"
+fi
+if [ "$(first_layer recompressed | jq -r .mediaType)" = "application/vnd.oci.image.layer.v1.tar+zstd" ]; then
+    pass "parent.recompress: true still re-encodes"
+else
+    fail "parent-layers" "recompress: true kept $(first_layer recompressed)"
+fi
+set +e
+printf 'parent-layers: keep\nimages: []\n' | build-oci --dry-run >/dev/null 2>&1
+RC=$?
+set -e
+if [ "$RC" -eq 2 ]; then
+    pass "Unknown parent-layers mode rejected"
+else
+    fail "parent-layers" "unknown mode: exit $RC"
+fi
+
+cd /
+rm -rf "$WORKDIR"
+
+# --------------------------------------------------
+# Test 51: cache statistics
+# --------------------------------------------------
+echo ""
+echo "Test 51: cache statistics are logged and reported"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/base" "$WORKDIR/app"
+echo "base" > "$WORKDIR/base/base.txt"
+echo "app" > "$WORKDIR/app/app.txt"
+cd "$WORKDIR"
+printf 'output: parent\nimages:\n  - {architecture: amd64, os: linux, layer: base}\n' | build-oci
+printf 'output: child\nimages:
+  - {architecture: amd64, os: linux, layer: app, parent: {image: parent}}
+  - {architecture: amd64, os: linux, layer: app, parent: {image: parent}, annotations: {x.y: z}}\n' \
+    | build-oci -v --image-parallelism 1 --log-format json 2> stats.log
+cache_field() {
+    jq -s -r --arg cache "$1" --arg field "$2" \
+        '.[] | select(.fields.message == "cache statistics" and .fields.cache == $cache) | .fields[$field]' stats.log
+}
+if [ "$(cache_field extract hits)" = "1" ] && [ "$(cache_field extract misses)" = "1" ] \
+    && [ "$(cache_field extract bytes_saved)" -gt 0 ]; then
+    pass "Second image of the same parent counted as an extract cache hit"
+else
+    fail "cache statistics" "extract: $(grep '"extract"' stats.log)"
+fi
+printf 'output: child\nimages:\n  - {architecture: amd64, os: linux, layer: app, parent: {image: parent}}\n' \
+    | build-oci -v --log-format json 2> rebuild.log
+if [ "$(cache_field analysis hits)" = "1" ] \
+    && jq -s -e 'any(.[]; .fields.cache == "blobExists" and .fields.hits == 1)' rebuild.log >/dev/null; then
+    pass "Analysis and blob-exists hits counted"
+else
+    fail "cache statistics" "$(grep 'cache statistics' stats.log)"
+fi
+set +e
+printf 'output: failed\nimages:
+  - {architecture: amd64, os: linux, parent: {image: parent}}
+  - {architecture: amd64, os: linux, layer: missing}\n' \
+    | build-oci --image-parallelism 1 --error-json report.json >/dev/null 2>&1
+set -e
+if jq -e '.cache.extract.misses == 1 and (.cache.analysis | has("bytesSaved"))' report.json >/dev/null 2>&1; then
+    pass "--error-json report includes cache counts"
+else
+    fail "cache statistics" "report: $(cat report.json 2>/dev/null)"
+fi
+
+cd /
+rm -rf "$WORKDIR"
+
+# --------------------------------------------------
+# Test 52: write-checksum-xattrs
+# --------------------------------------------------
+echo ""
+echo "Test 52: write-checksum-xattrs stores computed checksums on layer files"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/rootfs"
+echo "hello" > "$WORKDIR/rootfs/hello.txt"
+echo "stored" > "$WORKDIR/rootfs/stored.txt"
+cd "$WORKDIR"
+getxattr() {
+    python3 -c 'import os, sys; print(os.getxattr(sys.argv[1], "user.checksum.sha256").decode())' "$1" 2>/dev/null
+}
+if python3 -c 'import os, sys; os.setxattr(sys.argv[1], "user.checksum.sha256", b"0" * 64)' rootfs/stored.txt 2>/dev/null; then
+    printf 'images: [{architecture: amd64, os: linux, layer: rootfs}]\n' | build-oci
+    if [ -z "$(getxattr rootfs/hello.txt)" ]; then
+        pass "No checksum xattrs written by default"
+    else
+        fail "write-checksum-xattrs" "xattr written without the option"
+    fi
+    printf 'write-checksum-xattrs: true\nimages: [{architecture: amd64, os: linux, layer: rootfs}]\n' | build-oci
+    if [ "$(getxattr rootfs/hello.txt)" = "$(sha256sum rootfs/hello.txt | cut -d' ' -f1)" ] \
+        && [ "$(getxattr rootfs/stored.txt)" = "$(printf '0%.0s' $(seq 64))" ]; then
+        pass "Computed checksum stored, existing one left alone"
+    else
+        fail "write-checksum-xattrs" "hello.txt: $(getxattr rootfs/hello.txt), stored.txt: $(getxattr rootfs/stored.txt)"
+    fi
+    echo "new" > rootfs/new.txt
+    printf 'write-checksum-xattrs: true\nimages: [{architecture: amd64, os: linux, layer: rootfs}]\n' \
+        | build-oci --no-write-checksum-xattrs
+    if [ -z "$(getxattr rootfs/new.txt)" ]; then
+        pass "--no-write-checksum-xattrs overrides the manifest"
+    else
+        fail "write-checksum-xattrs" "xattr written despite --no-write-checksum-xattrs"
+    fi
+else
+    warn "write-checksum-xattrs" "filesystem lacks user xattrs, test skipped"
+fi
+
+cd /
+rm -rf "$WORKDIR"
+
+# --------------------------------------------------
+# Test 53: parent selected by ref or tag
+# --------------------------------------------------
+echo ""
+echo "Test 53: parent selected by digest (ref:) or ref.name (tag:)"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/one" "$WORKDIR/two" "$WORKDIR/app"
+echo "one" > "$WORKDIR/one/one.txt"
+echo "two" > "$WORKDIR/two/two.txt"
+echo "app" > "$WORKDIR/app/app.txt"
+cd "$WORKDIR"
+printf 'output: parent\nimages:
+  - {architecture: amd64, os: linux, layer: one, index-annotations: {org.opencontainers.image.ref.name: one}}
+  - {architecture: amd64, os: linux, layer: two, name: two}
+  - {architecture: arm64, os: linux, layer: two, name: two}\n' | build-oci
+layer_digests() {
+    jq -r '.layers[].digest' "$1/blobs/sha256/$(jq -r '.manifests[0].digest' "$1/index.json" | cut -d: -f2)" | tr '\n' ' '
+}
+ONE_DIGEST=$(jq -r '.manifests[0].digest' parent/index.json)
+printf 'output: by-ref\nimages: [{architecture: amd64, os: linux, layer: app, parent: {image: parent, ref: "%s"}}]\n' \
+    "$ONE_DIGEST" | build-oci
+printf 'output: by-tag\nimages: [{architecture: arm64, os: linux, layer: app, parent: {image: parent, tag: "two:latest"}}]\n' \
+    | build-oci
+ONE_LAYER=$(jq -r '.layers[0].digest' "parent/blobs/sha256/${ONE_DIGEST#sha256:}")
+TWO_INDEX=$(jq -r '.manifests[1].digest' parent/index.json | cut -d: -f2)
+TWO_ARM=$(jq -r '.manifests[] | select(.platform.architecture == "arm64") | .digest' "parent/blobs/sha256/$TWO_INDEX")
+TWO_LAYER=$(jq -r '.layers[0].digest' "parent/blobs/sha256/${TWO_ARM#sha256:}")
+if [ "$(layer_digests by-ref | cut -d' ' -f1)" = "$ONE_LAYER" ]; then
+    pass "ref: selects the parent image by manifest digest"
+else
+    fail "parent ref" "layers: $(layer_digests by-ref), expected $ONE_LAYER first"
+fi
+if [ "$(layer_digests by-tag | cut -d' ' -f1)" = "$TWO_LAYER" ]; then
+    pass "tag: selects the image of a nested index by platform"
+else
+    fail "parent tag" "layers: $(layer_digests by-tag), expected $TWO_LAYER first"
+fi
+set +e
+printf 'images: [{architecture: amd64, os: linux, parent: {image: parent, tag: missing}}]\n' | build-oci --dry-run >/dev/null 2>&1
+MISSING_RC=$?
+printf 'images: [{architecture: amd64, os: linux, parent: {image: parent, tag: one, index: 0}}]\n' | build-oci --dry-run >/dev/null 2>&1
+COMBINED_RC=$?
+set -e
+if [ "$MISSING_RC" -eq 3 ] && [ "$COMBINED_RC" -eq 2 ]; then
+    pass "Unknown tag is a missing parent; index with tag is a config error"
+else
+    fail "parent tag" "missing tag: exit $MISSING_RC, index with tag: exit $COMBINED_RC"
+fi
+
+cd /
+rm -rf "$WORKDIR"
+
+# --------------------------------------------------
+# Test 54: layer-metadata
+# --------------------------------------------------
+echo ""
+echo "Test 54: layer-metadata builds the layer from trusted metadata"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/rootfs/etc"
+echo "hello" > "$WORKDIR/rootfs/etc/hello.conf"
+echo "unlisted" > "$WORKDIR/rootfs/unlisted.txt"
+cd "$WORKDIR"
+cat > metadata.json <<JSON
+{"entries": [
+  {"path": ".", "type": "dir", "size": 0, "mode": 493, "uid": 0, "gid": 0},
+  {"path": "./etc", "type": "dir", "size": 0, "mode": 448, "uid": 7, "gid": 7},
+  {"path": "./etc/hello.conf", "type": "file", "size": 6, "mode": 384, "uid": 7, "gid": 7,
+   "sha256": "$(printf 'a%.0s' $(seq 64))", "xattrs": {"user.origin": "buildsystem"}},
+  {"path": "./etc/link", "type": "symlink", "size": 0, "mode": 511, "uid": 0, "gid": 0, "target": "hello.conf"}
+]}
+JSON
+printf 'compression: disabled\nimages: [{architecture: amd64, os: linux, layer: rootfs, layer-metadata: metadata.json}]\n' \
+    | build-oci
+MANIFEST=$(jq -r '.manifests[0].digest' index.json | cut -d: -f2)
+LAYER=$(jq -r '.layers[0].digest' "blobs/sha256/$MANIFEST" | cut -d: -f2)
+MEMBERS=$(python3 - "blobs/sha256/$LAYER" <<'PY'
+import sys, tarfile
+for m in tarfile.open(sys.argv[1]):
+    print(m.name, m.uid, oct(m.mode), m.pax_headers.get("freedesktopsdk.checksum.sha256", "")[:4],
+          m.pax_headers.get("SCHILY.xattr.user.origin", ""), m.linkname)
+PY
+)
+if echo "$MEMBERS" | grep -q '^etc/hello.conf 7 0o100600 aaaa buildsystem' \
+    && echo "$MEMBERS" | grep -q '^etc/link 0 .* hello.conf$' \
+    && ! echo "$MEMBERS" | grep -q unlisted; then
+    pass "Layer entries, checksums and xattrs come from the metadata"
+else
+    fail "layer-metadata" "members: $MEMBERS"
+fi
+echo "longer than recorded" > rootfs/etc/hello.conf
+truncate -s 2 rootfs/etc/hello.conf
+set +e
+printf 'images: [{architecture: amd64, os: linux, layer: rootfs, layer-metadata: metadata.json}]\n' \
+    | build-oci >/dev/null 2>err.txt
+SHORT_RC=$?
+printf 'images: [{architecture: amd64, os: linux, layer-metadata: metadata.json}]\n' | build-oci --dry-run >/dev/null 2>&1
+NO_LAYER_RC=$?
+set -e
+if [ "$SHORT_RC" -ne 0 ] && grep -q "shorter than its recorded size" err.txt && [ "$NO_LAYER_RC" -eq 2 ]; then
+    pass "Short files and layer-metadata without layer are rejected"
+else
+    fail "layer-metadata" "short file: exit $SHORT_RC ($(cat err.txt)), without layer: exit $NO_LAYER_RC"
+fi
+
+cd /
+rm -rf "$WORKDIR"
+
+# --------------------------------------------------
+# Test 55: dedup-lowers
+# --------------------------------------------------
+echo ""
+echo "Test 55: dedup-lowers stack directories and tars above the parent"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/rootfs" "$WORKDIR/refdir" "$WORKDIR/reftar"
+echo "a" > "$WORKDIR/rootfs/a.txt"
+echo "b" > "$WORKDIR/rootfs/b.txt"
+echo "c" > "$WORKDIR/rootfs/c.txt"
+cp -a "$WORKDIR/rootfs/a.txt" "$WORKDIR/rootfs/b.txt" "$WORKDIR/refdir/"
+cp -a "$WORKDIR/rootfs/b.txt" "$WORKDIR/reftar/"
+cd "$WORKDIR"
+printf 'output: ref\nimages: [{architecture: amd64, os: linux, layer: reftar}]\n' | build-oci
+REF_MANIFEST=$(jq -r '.manifests[0].digest' ref/index.json | cut -d: -f2)
+REF_LAYER="ref/blobs/sha256/$(jq -r '.layers[0].digest' "ref/blobs/sha256/$REF_MANIFEST" | cut -d: -f2)"
+layer_files() {
+    local manifest layer
+    manifest=$(jq -r '.manifests[0].digest' "$1/index.json" | cut -d: -f2)
+    layer=$(jq -r '.layers[-1].digest' "$1/blobs/sha256/$manifest" | cut -d: -f2)
+    zstd -dc "$1/blobs/sha256/$layer" | tar -t | grep -v '/$' | sort | tr '\n' ' '
+}
+printf 'output: dir-only\nimages: [{architecture: amd64, os: linux, layer: rootfs, dedup-lowers: [{dir: refdir}]}]\n' | build-oci
+if [ "$(layer_files dir-only)" = "c.txt " ]; then
+    pass "Files identical in a lower directory are left out"
+else
+    fail "dedup-lowers" "layer with a directory lower: $(layer_files dir-only)"
+fi
+echo "changed" > reftar/b.txt
+printf 'output: ref2\nimages: [{architecture: amd64, os: linux, layer: reftar}]\n' | build-oci
+REF2_MANIFEST=$(jq -r '.manifests[0].digest' ref2/index.json | cut -d: -f2)
+REF2_LAYER="ref2/blobs/sha256/$(jq -r '.layers[0].digest' "ref2/blobs/sha256/$REF2_MANIFEST" | cut -d: -f2)"
+printf 'output: mixed\nimages: [{architecture: amd64, os: linux, layer: rootfs, dedup-lowers: [{dir: refdir}, {tar: %s}]}]\n' \
+    "$REF2_LAYER" | build-oci
+printf 'output: tar-only\nimages: [{architecture: amd64, os: linux, layer: rootfs, dedup-lowers: [{tar: %s}]}]\n' \
+    "$REF_LAYER" | build-oci
+if [ "$(layer_files mixed)" = "b.txt c.txt " ] && [ "$(layer_files tar-only)" = "a.txt c.txt " ]; then
+    pass "Lower tars are merged above directories in declared order"
+else
+    fail "dedup-lowers" "mixed: $(layer_files mixed), tar only: $(layer_files tar-only)"
+fi
+set +e
+printf 'images: [{architecture: amd64, os: linux, dedup-lowers: [{dir: refdir, tar: x}]}]\n' | build-oci --dry-run >/dev/null 2>&1
+RC=$?
+set -e
+if [ "$RC" -eq 2 ]; then
+    pass "A lower with both dir and tar is rejected"
+else
+    fail "dedup-lowers" "dir and tar: exit $RC"
+fi
+
+cd /
+rm -rf "$WORKDIR"
+
+# --------------------------------------------------
+# Test 56: push
+# --------------------------------------------------
+echo ""
+echo "Test 56: push uploads blobs and manifests to a registry"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/rootfs"
+head -c 3000000 /dev/urandom > "$WORKDIR/rootfs/random.bin"
+echo "hello" > "$WORKDIR/rootfs/hello.txt"
+cat > "$WORKDIR/registry.py" <<'PYEOF'
+import base64, hashlib, http.server, json, os, sys, urllib.parse, uuid
+store, log_path = sys.argv[1], sys.argv[2]
+blobs, uploads, manifests = {}, {}, {}
+TOKEN = "secret-token"
+class Handler(http.server.BaseHTTPRequestHandler):
+    def log_message(self, *args): pass
+    def reply(self, code, body=b"", headers=None):
+        self.send_response(code)
+        for k, v in (headers or {}).items(): self.send_header(k, v)
+        self.send_header("Content-Length", str(len(body)))
+        self.end_headers()
+        if self.command != "HEAD": self.wfile.write(body)
+    def body(self):
+        return self.rfile.read(int(self.headers.get("Content-Length") or 0))
+    def handle_any(self):
+        url = urllib.parse.urlparse(self.path)
+        with open(log_path, "a") as f: f.write("%s %s\n" % (self.command, url.path))
+        if url.path == "/token":
+            if self.headers.get("Authorization") != "Basic " + base64.b64encode(b"alice:wonderland").decode():
+                return self.reply(401)
+            return self.reply(200, json.dumps({"token": TOKEN}).encode())
+        if self.headers.get("Authorization") != "Bearer " + TOKEN:
+            realm = "http://%s/token" % self.headers["Host"]
+            return self.reply(401, headers={"WWW-Authenticate": 'Bearer realm="%s",service="mock"' % realm})
+        parts = url.path.split("/")
+        if url.path == "/v2/": return self.reply(200)
+        if "blobs" in parts and "uploads" not in parts:
+            digest = parts[-1]
+            return self.reply(200 if digest in blobs else 404)
+        if url.path.endswith("/blobs/uploads/") and self.command == "POST":
+            uid = str(uuid.uuid4()); uploads[uid] = b""
+            return self.reply(202, headers={"Location": url.path + uid})
+        if "uploads" in parts:
+            uid = parts[-1]; uploads[uid] += self.body()
+            if self.command == "PATCH":
+                return self.reply(202, headers={"Location": url.path, "Range": "0-%d" % (len(uploads[uid]) - 1)})
+            digest = urllib.parse.parse_qs(url.query)["digest"][0]
+            data = uploads.pop(uid)
+            if "sha256:" + hashlib.sha256(data).hexdigest() != digest:
+                return self.reply(400, b"digest mismatch")
+            blobs[digest] = data
+            return self.reply(201, headers={"Location": url.path})
+        if "manifests" in parts and self.command == "PUT":
+            data = self.body()
+            manifest = json.loads(data)
+            for desc in [manifest.get("config")] + manifest.get("layers", []):
+                if desc and desc["digest"] not in blobs: return self.reply(400, b"blob unknown")
+            for desc in manifest.get("manifests", []):
+                if desc["digest"] not in manifests: return self.reply(400, b"manifest unknown")
+            digest = "sha256:" + hashlib.sha256(data).hexdigest()
+            manifests[digest] = manifests[parts[-1]] = data
+            with open(os.path.join(store, parts[-1].replace(":", "_")), "wb") as f:
+                f.write(self.headers["Content-Type"].encode() + b"\n" + data)
+            return self.reply(201, headers={"Docker-Content-Digest": digest})
+        self.reply(404)
+    do_GET = do_HEAD = do_POST = do_PATCH = do_PUT = handle_any
+server = http.server.ThreadingHTTPServer(("127.0.0.1", 0), Handler)
+with open(os.path.join(store, "port"), "w") as f: f.write(str(server.server_port))
+server.serve_forever()
+PYEOF
+mkdir -p "$WORKDIR/store"
+python3 "$WORKDIR/registry.py" "$WORKDIR/store" "$WORKDIR/requests.log" &
+REGISTRY_PID=$!
+for _ in $(seq 50); do [ -s "$WORKDIR/store/port" ] && break; sleep 0.1; done
+PORT=$(cat "$WORKDIR/store/port")
+printf '{"auths":{"127.0.0.1:%s":{"auth":"%s"}}}\n' "$PORT" "$(printf 'alice:wonderland' | base64)" > "$WORKDIR/auth.json"
+cd "$WORKDIR"
+printf 'output: out\nimages: [{architecture: amd64, os: linux, layer: rootfs, name: app, tag: v1}]\n' | build-oci
+
+set +e
+PUSH_OUT=$(REGISTRY_AUTH_FILE="$WORKDIR/auth.json" build-oci push out:app:v1 "127.0.0.1:$PORT/test/app:v1" --chunk-size-mb 1 2>&1)
+RC=$?
+set -e
+MANIFEST_DIGEST=$(jq -r '.manifests[0].digest' out/index.json)
+if [ "$RC" -eq 0 ] && echo "$PUSH_OUT" | grep -q "test/app:v1@$MANIFEST_DIGEST (2 blob(s) uploaded" \
+    && [ "$(tail -n +2 store/v1)" = "$(cat "out/blobs/sha256/${MANIFEST_DIGEST#sha256:}")" ] \
+    && head -1 store/v1 | grep -q "application/vnd.oci.image.index.v1+json" \
+    && [ "$(ls store | grep -c '^sha256_')" -eq 1 ]; then
+    pass "Image index and its manifest pushed under the tag with a bearer token"
+else
+    fail "push" "exit $RC: $PUSH_OUT"
+fi
+if [ "$(grep -c '^PATCH ' requests.log)" -ge 3 ]; then
+    pass "Blobs above --chunk-size-mb are uploaded in chunks"
+else
+    fail "push" "expected chunked uploads: $(grep -c '^PATCH ' requests.log) PATCH requests"
+fi
+: > requests.log
+set +e
+PUSH_OUT=$(REGISTRY_AUTH_FILE="$WORKDIR/auth.json" build-oci push out "127.0.0.1:$PORT/test/app" 2>&1)
+RC=$?
+set -e
+if [ "$RC" -eq 0 ] && echo "$PUSH_OUT" | grep -q "(0 blob(s) uploaded, 2 already present)" \
+    && ! grep -q '^POST ' requests.log && [ -f store/latest ]; then
+    pass "Blobs the registry has are not uploaded again"
+else
+    fail "push" "second push, exit $RC: $PUSH_OUT"
+fi
+set +e
+PUSH_OUT=$(REGISTRY_AUTH_FILE=/dev/null DOCKER_CONFIG="$WORKDIR/none" build-oci push out "127.0.0.1:$PORT/test/app" 2>&1)
+RC=$?
+set -e
+if [ "$RC" -ne 0 ] && echo "$PUSH_OUT" | grep -q "401"; then
+    pass "Push without credentials fails"
+else
+    fail "push" "without credentials, exit $RC: $PUSH_OUT"
+fi
+
+mkdir -p limits
+for arch in amd64 arm64 riscv64 ppc64le; do
+    mkdir -p "limits/$arch"
+    head -c 300000 /dev/urandom > "limits/$arch/data.bin"
+done
+cat > limits.py <<'PYEOF'
+import http.server, os, sys, threading, time, urllib.parse
+store = sys.argv[1]
+lock = threading.Lock()
+state = {"now": 0, "max": 0}
+class Handler(http.server.BaseHTTPRequestHandler):
+    def log_message(self, *args): pass
+    def reply(self, code, headers=None):
+        with lock: state["now"] -= 1
+        self.send_response(code)
+        for k, v in (headers or {}).items(): self.send_header(k, v)
+        self.send_header("Content-Length", "0")
+        self.end_headers()
+    def handle_any(self):
+        with lock:
+            state["now"] += 1
+            state["max"] = max(state["max"], state["now"])
+            with open(os.path.join(store, "max"), "w") as f: f.write(str(state["max"]))
+        self.rfile.read(int(self.headers.get("Content-Length") or 0))
+        time.sleep(0.02)
+        path = urllib.parse.urlparse(self.path).path
+        if path.endswith("/blobs/uploads/"):
+            return self.reply(202, {"Location": path + "1"})
+        if "/blobs/" in path and "/uploads/" not in path: return self.reply(404)
+        self.reply(200 if path == "/v2/" else 201)
+    do_GET = do_HEAD = do_POST = do_PATCH = do_PUT = handle_any
+server = http.server.ThreadingHTTPServer(("127.0.0.1", 0), Handler)
+with open(os.path.join(store, "port"), "w") as f: f.write(str(server.server_port))
+server.serve_forever()
+PYEOF
+mkdir -p limits/store
+python3 limits.py limits/store &
+LIMITS_PID=$!
+for _ in $(seq 50); do [ -s limits/store/port ] && break; sleep 0.1; done
+LIMITS_PORT=$(cat limits/store/port)
+printf 'output: limits/out\ncompression: disabled\nimages:\n' > limits/build.yaml
+for arch in amd64 arm64 riscv64 ppc64le; do
+    printf '  - {architecture: %s, os: linux, layer: limits/%s}\n' "$arch" "$arch" >> limits/build.yaml
+done
+build-oci < limits/build.yaml
+set +e
+RAYON_NUM_THREADS=4 build-oci push limits/out "127.0.0.1:$LIMITS_PORT/test/app" --max-connections 1 >/dev/null 2>&1
+RC=$?
+set -e
+if [ "$RC" -eq 0 ] && [ "$(cat limits/store/max)" = "1" ]; then
+    pass "--max-connections 1 keeps one request in flight at a time"
+else
+    fail "push" "--max-connections 1, exit $RC, most requests in flight: $(cat limits/store/max)"
+fi
+START=$(date +%s%N)
+set +e
+RAYON_NUM_THREADS=4 build-oci push limits/out "127.0.0.1:$LIMITS_PORT/test/app" --limit-rate 300K >/dev/null 2>&1
+RC=$?
+set -e
+ELAPSED_MS=$(( ($(date +%s%N) - START) / 1000000 ))
+# 1.2MB of blobs at 300KB/s take about four seconds
+if [ "$RC" -eq 0 ] && [ "$ELAPSED_MS" -ge 3000 ]; then
+    pass "--limit-rate paces uploads to the registry host"
+else
+    fail "push" "--limit-rate 300K, exit $RC after ${ELAPSED_MS}ms"
+fi
+set +e
+build-oci push limits/out "127.0.0.1:$LIMITS_PORT/test/app" --limit-rate 5X >/dev/null 2>&1
+RC=$?
+set -e
+if [ "$RC" -ne 0 ]; then
+    pass "Invalid --limit-rate is rejected"
+else
+    fail "push" "--limit-rate 5X accepted"
+fi
+kill "$LIMITS_PID" 2>/dev/null || true
+wait "$LIMITS_PID" 2>/dev/null || true
+
+kill "$REGISTRY_PID" 2>/dev/null || true
+wait "$REGISTRY_PID" 2>/dev/null || true
+cd /
+rm -rf "$WORKDIR"
+
+# --------------------------------------------------
+# Test 57: overlay
+# --------------------------------------------------
+echo ""
+echo "Test 57: overlay exports the delta of an overlayfs upper directory"
+
+WORKDIR=$(mktemp -d)
+cd "$WORKDIR"
+mkdir -p lower1 lower2/dir upper/dir work merged
+echo "keep" > lower2/keep.txt
+echo "gone" > lower2/gone.txt
+echo "old" > lower2/dir/old.txt
+echo "same" > lower2/same.txt
+echo "top" > lower1/top.txt
+overlay_files() {
+    local manifest layer
+    manifest=$(jq -r '.manifests[0].digest' "$1/index.json" | cut -d: -f2)
+    layer=$(jq -r '.layers[-1].digest' "$1/blobs/sha256/$manifest" | cut -d: -f2)
+    zstd -dc "$1/blobs/sha256/$layer" | tar -t 2>/dev/null | grep -v '/$' | LC_ALL=C sort | tr '\n' ' '
+}
+EXPECTED="./.wh.gone.txt ./added.txt ./dir/.wh..wh..opq ./dir/new.txt ./top.txt "
+
+# An upper directory as overlayfs leaves it: a whiteout device, an opaque
+# directory and a copy up whose contents did not change
+if mknod upper/gone.txt c 0 0 2>/dev/null \
+    && python3 -c "import os; os.setxattr('upper/dir', 'trusted.overlay.opaque', b'y')" 2>/dev/null; then
+    echo "new" > upper/dir/new.txt
+    echo "changed" > upper/top.txt
+    echo "add" > upper/added.txt
+    cp -a lower2/same.txt upper/same.txt
+    printf 'output: out\nimages: [{architecture: amd64, os: linux, overlay: {upper: upper, lowers: [lower1, lower2]}}]\n' | build-oci
+    FILES=$(overlay_files out)
+    if [ "${FILES//\.\//}" = "${EXPECTED//\.\//}" ]; then
+        pass "Whiteouts, opaque directories and changed files make up the layer"
+    else
+        fail "overlay" "upper layer: $FILES"
+    fi
+    printf 'images: [{architecture: amd64, os: linux, overlay: {upper: upper, lowers: [lower1, lower2]}}]\n' \
+        | build-oci --dry-run > plan.json
+    if jq -e '.images[0].layer.skipped == ["./same.txt"] and .images[0].layer.whiteouts == ["./gone.txt"]' plan.json >/dev/null; then
+        pass "Unchanged copy ups are skipped and whiteouts reported"
+    else
+        fail "overlay" "dry run: $(cat plan.json)"
+    fi
+else
+    warn "overlay" "cannot create whiteout devices or trusted xattrs (not root?)"
+fi
+
+rm -rf upper work && mkdir -p upper work
+if mount -t overlay overlay -o lowerdir="$WORKDIR/lower1:$WORKDIR/lower2,upperdir=$WORKDIR/upper,workdir=$WORKDIR/work" \
+    "$WORKDIR/merged" 2>/dev/null; then
+    rm merged/gone.txt
+    rm -rf merged/dir && mkdir merged/dir && echo "new" > merged/dir/new.txt
+    echo "changed" > merged/top.txt
+    echo "add" > merged/added.txt
+    python3 -c "open('merged/same.txt', 'r+').close()"
+    printf 'output: mounted\nimages: [{architecture: amd64, os: linux, overlay: {merged: merged}}]\n' | build-oci
+    FILES=$(overlay_files mounted)
+    umount "$WORKDIR/merged"
+    if [ "${FILES//\.\//}" = "${EXPECTED//\.\//}" ]; then
+        pass "upperdir and lowerdir are read from the mount of merged"
+    else
+        fail "overlay" "mounted layer: $FILES"
+    fi
+else
+    warn "overlay" "cannot mount overlayfs here"
+fi
+set +e
+printf 'images: [{architecture: amd64, os: linux, overlay: {merged: lower1}}]\n' | build-oci --dry-run >/dev/null 2>&1
+RC=$?
+set -e
+if [ "$RC" -eq 2 ]; then
+    pass "A merged directory that is not an overlay mount is rejected"
+else
+    fail "overlay" "not a mount point: exit $RC"
+fi
+
+cd /
+rm -rf "$WORKDIR"
+
+# --------------------------------------------------
+# Test 58: docker-archive output
+# --------------------------------------------------
+echo ""
+echo "Test 58: output-format docker-archive writes a docker load tarball"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/base" "$WORKDIR/app"
+echo "base" > "$WORKDIR/base/base.txt"
+echo "app" > "$WORKDIR/app/app.txt"
+cd "$WORKDIR"
+printf 'output: parent\nimages: [{architecture: amd64, os: linux, layer: base}]\n' | build-oci
+cat <<'YAML' | build-oci
+output: out
+compression: gzip
+images:
+  - architecture: amd64
+    os: linux
+    name: example/app
+    tag: "1.0"
+    layer: app
+    parent: { image: parent }
+    output-format: docker-archive
+  - architecture: arm64
+    os: linux
+    layer: app
+    output-format: oci+docker-archive
+    archive-path: arm64.tar
+YAML
+mkdir extracted
+tar -xf out/linux-amd64.tar -C extracted
+CONFIG=$(jq -r '.[0].Config' extracted/manifest.json)
+LAYERS_OK=true
+i=0
+for layer in $(jq -r '.[0].Layers[]' extracted/manifest.json); do
+    DIFF_ID=$(jq -r ".rootfs.diff_ids[$i]" "extracted/$CONFIG")
+    [ "sha256:$(sha256sum "extracted/$layer" | cut -d' ' -f1)" = "$DIFF_ID" ] || LAYERS_OK=false
+    i=$((i + 1))
+done
+if [ "$i" -eq 2 ] && $LAYERS_OK && [ "$(jq -r '.[0].RepoTags[0]' extracted/manifest.json)" = "example/app:1.0" ] \
+    && [ "$(jq -r '."example/app"."1.0"' extracted/repositories)" = "$(jq -r '.rootfs.diff_ids[1]' "extracted/$CONFIG" | cut -d: -f2)" ] \
+    && tar -xOf "extracted/$(jq -r '.[0].Layers[1]' extracted/manifest.json)" app.txt 2>/dev/null | grep -q app; then
+    pass "Archive holds the config, uncompressed layers matching the diff_ids and the repo tag"
+else
+    fail "docker-archive" "manifest.json: $(cat extracted/manifest.json)"
+fi
+if [ "$(jq '.manifests | length' out/index.json)" -eq 1 ] \
+    && [ "$(jq -r '.manifests[0].platform.architecture' out/index.json)" = "arm64" ] \
+    && [ "$(tar -xOf arm64.tar manifest.json | jq '.[0].RepoTags | length')" -eq 0 ]; then
+    pass "docker-archive images stay out of index.json, oci+docker-archive ones are in both"
+else
+    fail "docker-archive" "index.json: $(cat out/index.json)"
+fi
+set +e
+printf 'images: [{architecture: amd64, os: linux, output-format: docker-archive}, {architecture: amd64, os: linux, output-format: docker-archive}]\n' \
+    | build-oci --dry-run >/dev/null 2>&1
+RC=$?
+set -e
+if [ "$RC" -eq 2 ]; then
+    pass "Two images writing the same archive are rejected"
+else
+    fail "docker-archive" "duplicate archive: exit $RC"
+fi
+
+cd /
+rm -rf "$WORKDIR"
+
+# --------------------------------------------------
+# Test 59: raw symlink targets
+# --------------------------------------------------
+echo ""
+echo "Test 59: symlink targets keep their raw bytes"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/rootfs"
+cd "$WORKDIR"
+ln -s "$(printf 'caf\351/latin1')" rootfs/latin1
+LONG_TARGET="$(printf 'd%.0s' $(seq 150))/target"
+ln -s "$LONG_TARGET" rootfs/long
+printf 'output: out\ncompression: disabled\nimages: [{architecture: amd64, os: linux, layer: rootfs}]\n' | build-oci
+MANIFEST=$(jq -r '.manifests[0].digest' out/index.json | cut -d: -f2)
+LAYER=$(jq -r '.layers[0].digest' "out/blobs/sha256/$MANIFEST" | cut -d: -f2)
+mkdir extracted
+tar -xf "out/blobs/sha256/$LAYER" -C extracted 2>/dev/null
+if [ "$(readlink extracted/latin1 | od -An -tx1 | tr -d ' \n')" = "$(readlink rootfs/latin1 | od -An -tx1 | tr -d ' \n')" ] \
+    && [ "$(readlink extracted/long)" = "$LONG_TARGET" ]; then
+    pass "Non-UTF-8 and long symlink targets are written byte for byte"
+else
+    fail "symlink targets" "latin1 -> $(readlink extracted/latin1 | od -c | head -1), long -> $(readlink extracted/long)"
+fi
+printf 'images: [{architecture: amd64, os: linux, layer: rootfs, parent: {image: out}}]\n' | build-oci --dry-run > plan.json
+if jq -e '.images[0].layer.skipped | sort == ["./latin1", "./long"]' plan.json >/dev/null; then
+    pass "Symlinks with non-UTF-8 targets deduplicate against the parent"
+else
+    fail "symlink targets" "dry run: $(jq -c '.images[0].layer' plan.json)"
+fi
+
+cd /
+rm -rf "$WORKDIR"
+
+# --------------------------------------------------
+# Test 60: oci-archive output
+# --------------------------------------------------
+echo ""
+echo "Test 60: output-format oci-archive writes a reproducible layout tarball"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/app"
+echo "app" > "$WORKDIR/app/app.txt"
+cd "$WORKDIR"
+for run in 1 2; do
+    touch -d "@$((1700000000 + run))" app/app.txt
+    cat <<'YAML' | SOURCE_DATE_EPOCH=1700000000 build-oci
+output: out
+compression: gzip
+images:
+  - architecture: amd64
+    os: linux
+    name: example/app
+    layer: app
+    output-format: oci-archive
+YAML
+    cp out/linux-amd64.oci.tar "run$run.tar"
+    rm -rf out
+done
+ENTRIES=$(tar -tf run1.tar)
+if cmp -s run1.tar run2.tar && [ "$ENTRIES" = "$(echo "$ENTRIES" | LC_ALL=C sort)" ] \
+    && [ "$(TZ=UTC tar -tvf run1.tar | awk '{print $4 $5}' | sort -u)" = "2023-11-1422:13" ]; then
+    pass "Two builds give byte-identical archives with sorted entries at SOURCE_DATE_EPOCH"
+else
+    fail "oci-archive" "entries: $ENTRIES"
+fi
+mkdir extracted
+tar -xf run1.tar -C extracted
+if [ "$(build-oci ls --json extracted | jq -r '.[0].ref')" = "example/app:latest" ] \
+    && [ "$(ls extracted/blobs/sha256 | wc -l)" -eq "$(tar -tf run1.tar | grep -c '^blobs/sha256/.')" ]; then
+    pass "The archive extracts to a layout holding the named image"
+else
+    fail "oci-archive" "ls: $(build-oci ls --json extracted 2>&1)"
+fi
+
+cd /
+rm -rf "$WORKDIR"
+
+# --------------------------------------------------
+# Test 61: build IDs
+# --------------------------------------------------
+echo ""
+echo "Test 61: build IDs ignore the clock and compression"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/app"
+echo "app" > "$WORKDIR/app/app.txt"
+cd "$WORKDIR"
+printf 'output: gz\ncompression: gzip\nbuild-id-annotation: true\nimages: [{architecture: amd64, os: linux, layer: app}]\n' \
+    | build-oci --iidfile gz.json
+sleep 1
+printf 'output: zst\ncompression: zstd\nimages: [{architecture: amd64, os: linux, layer: app}]\n' \
+    | build-oci --iidfile zst.json
+GZ_ID=$(jq -r '.[0].images[0].buildId' gz.json)
+GZ_MANIFEST=$(jq -r '.[0].images[0].manifest' gz.json | cut -d: -f2)
+if [ "$GZ_ID" = "$(jq -r '.[0].images[0].buildId' zst.json)" ] \
+    && [ "$(jq -r '.[0].images[0].manifest' gz.json)" = "$(jq -r '.[0].manifests[0]' gz.json)" ] \
+    && [ "$(jq -r '.annotations."org.freedesktopsdk.build.id"' "gz/blobs/sha256/$GZ_MANIFEST")" = "$GZ_ID" ]; then
+    pass "Builds at different times and compressions share the build ID recorded in the manifest"
+else
+    fail "build ID" "gzip: $(cat gz.json), zstd: $(cat zst.json)"
+fi
+echo "changed" > app/app.txt
+printf 'output: changed\nimages: [{architecture: amd64, os: linux, layer: app}]\n' | build-oci --iidfile changed.json
+if [ "$(jq -r '.[0].images[0].buildId' changed.json)" != "$GZ_ID" ] \
+    && [ "$(jq -r '.[0].images[0].manifest' zst.json | cut -d: -f2 | xargs -I{} jq '.annotations' zst/blobs/sha256/{})" = "null" ]; then
+    pass "Changed inputs change the build ID; the annotation is opt-in"
+else
+    fail "build ID" "changed: $(cat changed.json)"
+fi
+
+cd /
+rm -rf "$WORKDIR"
+
+# --------------------------------------------------
+# Test 62: merge-index
+# --------------------------------------------------
+echo ""
+echo "Test 62: merge-index adds to an existing index.json"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/app"
+echo "app" > "$WORKDIR/app/app.txt"
+cd "$WORKDIR"
+printf 'output: out\nimages: [{architecture: amd64, os: linux, name: example/app}, {architecture: arm64, os: linux}, {architecture: amd64, os: linux}]\n' | build-oci
+OLD_ARM64=$(jq -r '.manifests[1].digest' out/index.json)
+cat <<'YAML' | build-oci
+output: out
+merge-index: true
+annotations: { org.example.merged: "yes" }
+images:
+  - architecture: amd64
+    os: linux
+    layer: app
+  - architecture: riscv64
+    os: linux
+YAML
+if [ "$(jq -c '[.manifests[] | .annotations."org.opencontainers.image.ref.name" // .platform.architecture]' out/index.json)" = '["example/app:latest","arm64","amd64","riscv64"]' ] \
+    && [ "$(jq -r '.manifests[1].digest' out/index.json)" = "$OLD_ARM64" ] \
+    && [ "$(jq '.manifests[2].size' out/index.json)" -gt 0 ] \
+    && [ "$(jq -r '.annotations."org.example.merged"' out/index.json)" = "yes" ] \
+    && build-oci ls out >/dev/null \
+    && [ -z "$(find out -maxdepth 1 -name '.tmp*' -type f)" ]; then
+    pass "Matching entries are replaced in place, others kept and new ones appended"
+else
+    fail "merge-index" "index.json: $(jq -c . out/index.json)"
+fi
+MANIFEST=$(jq -r '.manifests[2].digest' out/index.json | cut -d: -f2)
+if [ "$(jq '.layers | length' "out/blobs/sha256/$MANIFEST")" -eq 1 ]; then
+    pass "The replaced amd64 entry is the new build"
+else
+    fail "merge-index" "amd64 manifest: $(cat "out/blobs/sha256/$MANIFEST")"
+fi
+printf 'output: out\nimages: [{architecture: s390x, os: linux}]\n' | build-oci
+if [ "$(jq '.manifests | length' out/index.json)" -eq 1 ]; then
+    pass "Without merge-index the index is replaced"
+else
+    fail "merge-index" "index.json: $(jq -c . out/index.json)"
+fi
+
+cd /
+rm -rf "$WORKDIR"
+
+# --------------------------------------------------
+# Test 63: retries of transient file system errors
+# --------------------------------------------------
+echo ""
+echo "Test 63: transient read errors are retried"
+
+if command -v cc >/dev/null 2>&1; then
+    WORKDIR=$(mktemp -d)
+    mkdir -p "$WORKDIR/rootfs"
+    cd "$WORKDIR"
+    echo "flaky contents" > rootfs/flaky.txt
+    # Fails the first $FLAKY_FAILURES reads of flaky.txt with ESTALE, as a
+    # stale NFS file handle would
+    cat > flaky.c <<'C'
+#define _GNU_SOURCE
+#include <dlfcn.h>
+#include <errno.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <unistd.h>
+static int failures = -1;
+ssize_t read(int fd, void *buf, size_t count) {
+    static ssize_t (*real_read)(int, void *, size_t);
+    if (!real_read) real_read = dlsym(RTLD_NEXT, "read");
+    char link[64], target[4096];
+    snprintf(link, sizeof link, "/proc/self/fd/%d", fd);
+    ssize_t n = readlink(link, target, sizeof target - 1);
+    if (n > 0) {
+        target[n] = 0;
+        if (strstr(target, "flaky.txt")) {
+            if (failures < 0) failures = atoi(getenv("FLAKY_FAILURES") ? getenv("FLAKY_FAILURES") : "0");
+            if (failures > 0) { failures--; errno = ESTALE; return -1; }
+        }
+    }
+    return real_read(fd, buf, count);
+}
+C
+    cc -shared -fPIC -o flaky.so flaky.c -ldl
+    printf 'output: out\ncompression: disabled\nio-retry-delay-ms: 1\nimages: [{architecture: amd64, os: linux, layer: rootfs}]\n' \
+        | FLAKY_FAILURES=2 LD_PRELOAD="$WORKDIR/flaky.so" build-oci --log-format json 2> err.txt
+    MANIFEST=$(jq -r '.manifests[0].digest' out/index.json | cut -d: -f2)
+    LAYER=$(jq -r '.layers[0].digest' "out/blobs/sha256/$MANIFEST" | cut -d: -f2)
+    if [ "$(tar -xOf "out/blobs/sha256/$LAYER" flaky.txt 2>/dev/null)" = "flaky contents" ] \
+        && [ "$(grep -c "retrying after" err.txt)" -eq 2 ] \
+        && [ "$(jq -r 'select(.fields.message == "io retries") | .fields.recovered' err.txt)" = "1" ]; then
+        pass "A file whose reads fail twice with ESTALE is read on the third attempt"
+    else
+        fail "io retries" "stderr: $(cat err.txt)"
+    fi
+    cd /
+    rm -rf "$WORKDIR"
+else
+    warn "io retries" "no C compiler to build the failing read() shim"
+fi
+set +e
+printf 'io-retries: -1\nimages: [{architecture: amd64, os: linux}]\n' | build-oci --dry-run >/dev/null 2>&1
+RC=$?
+set -e
+if [ "$RC" -eq 2 ]; then
+    pass "A negative io-retries is rejected"
+else
+    fail "io retries" "io-retries: -1 exit $RC"
+fi
+
+# --------------------------------------------------
+# Test 64: gc
+# --------------------------------------------------
+echo ""
+echo "Test 64: gc deletes the blobs index.json no longer reaches"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/app"
+cd "$WORKDIR"
+echo "v1" > app/app.txt
+printf 'output: out\nlayer-listing: json\nimages: [{architecture: amd64, os: linux, layer: app}]\n' | build-oci
+echo "v2" > app/app.txt
+cat <<'YAML' | build-oci
+output: out
+layer-listing: json
+layer-index: true
+images:
+  - architecture: amd64
+    os: linux
+    name: example/app
+    layer: app
+  - architecture: arm64
+    os: linux
+    name: example/app
+    layer: app
+YAML
+BEFORE=$(ls out/blobs/sha256 | wc -l)
+build-oci gc out --dry-run > dry.txt
+if [ "$(ls out/blobs/sha256 | wc -l)" -eq "$BEFORE" ] && [ "$(grep -c '^blobs/sha256/' dry.txt)" -eq 4 ] \
+    && grep -q "would remove 4 unreachable blob(s)" dry.txt; then
+    pass "--dry-run lists the old manifest, config, layer and listing without deleting them"
+else
+    fail "gc" "dry run: $(cat dry.txt)"
+fi
+build-oci gc out > gc.txt
+REACHABLE_OK=true
+NESTED=$(jq -r '.manifests[0].digest' out/index.json | cut -d: -f2)
+for digest in "sha256:$NESTED" $(jq -r '.manifests[].digest' "out/blobs/sha256/$NESTED"); do
+    [ -f "out/blobs/sha256/${digest#sha256:}" ] || REACHABLE_OK=false
+done
+MANIFEST=$(jq -r '.manifests[1].digest' "out/blobs/sha256/$NESTED" | cut -d: -f2)
+for digest in $(jq -r '.config.digest, .layers[].digest, .layers[].annotations[]' "out/blobs/sha256/$MANIFEST"); do
+    [ -f "out/blobs/sha256/${digest#sha256:}" ] || REACHABLE_OK=false
+done
+if [ "$(ls out/blobs/sha256 | wc -l)" -eq $((BEFORE - 4)) ] && $REACHABLE_OK \
+    && [ "$(build-oci gc out --dry-run | tail -1)" = "out: would remove 0 unreachable blob(s), 0 B" ] \
+    && build-oci du "out:sha256:$MANIFEST" >/dev/null; then
+    pass "Unreachable blobs are deleted; nested indexes, configs, layers, listings and indexes stay"
+else
+    fail "gc" "$(cat gc.txt); reachable: $REACHABLE_OK"
+fi
+
+cd /
+rm -rf "$WORKDIR"
+
+# --------------------------------------------------
+# Test 65: read-only layer sources
+# --------------------------------------------------
+echo ""
+echo "Test 65: layer sources are never written and noatime keeps access times"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/rootfs/dir" "$WORKDIR/ro"
+cd "$WORKDIR"
+echo "small" > rootfs/dir/small.txt
+head -c 2000000 /dev/urandom > rootfs/big.bin
+ln -s dir/small.txt rootfs/link
+ln rootfs/dir/small.txt rootfs/hardlink
+touch -a -d "2020-01-01 00:00:00" rootfs/dir/small.txt rootfs/big.bin
+# Writing contents or xattrs would change the ctime; listing directories
+# and reading symlinks update their access time whatever the open flags
+snapshot() { (cd rootfs && find . \( -type f -printf '%p %y %m %s %T@ %C@ %A@\n' \) -o -printf '%p %y %m %s %T@ %C@\n' | sort); }
+BEFORE=$(snapshot)
+printf 'output: out\nnoatime: true\nwrite-checksum-xattrs: false\nimages: [{architecture: amd64, os: linux, layer: rootfs}]\n' | build-oci
+printf 'output: out2\nnoatime: true\nimages: [{architecture: amd64, os: linux, layer: rootfs, parent: {image: out}}]\n' | build-oci
+if [ "$(snapshot)" = "$BEFORE" ]; then
+    pass "Files, links, change and access times of the source are untouched"
+else
+    fail "read-only sources" "$(diff <(echo "$BEFORE") <(snapshot) | head -5)"
+fi
+if mount --bind rootfs ro 2>/dev/null && mount -o remount,bind,ro ro 2>/dev/null; then
+    printf 'output: out3\nimages: [{architecture: amd64, os: linux, layer: ro}]\n' | build-oci
+    if [ "$(build-oci du out3 --json | jq -r '.layers[0].digest')" = "$(build-oci du out --json | jq -r '.layers[0].digest')" ]; then
+        pass "A read-only mount builds like the writable tree"
+    else
+        fail "read-only sources" "out3: $(build-oci du out3 2>&1)"
+    fi
+    umount ro
+else
+    warn "read-only sources" "cannot create a read-only bind mount"
+fi
+
+cd /
+rm -rf "$WORKDIR"
+
+# --------------------------------------------------
+# Test 66: blob writer queue
+# --------------------------------------------------
+echo ""
+echo "Test 66: layer blobs written through the writer queue match their digests"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/rootfs"
+cd "$WORKDIR"
+head -c 3000000 /dev/urandom > rootfs/random.bin
+seq 200000 > rootfs/numbers.txt
+QUEUE_OK=true
+for compression in gzip zstd disabled; do
+    for mb in 1 16; do
+        printf 'output: %s-%s\ncompression: %s\npersist-queue-mb: %s\nimages: [{architecture: amd64, os: linux, layer: rootfs}]\n' \
+            "$compression" "$mb" "$compression" "$mb" | SOURCE_DATE_EPOCH=1700000000 build-oci
+        MANIFEST=$(jq -r '.manifests[0].digest' "$compression-$mb/index.json" | cut -d: -f2)
+        LAYER=$(jq -r '.layers[0].digest' "$compression-$mb/blobs/sha256/$MANIFEST" | cut -d: -f2)
+        SIZE=$(jq -r '.layers[0].size' "$compression-$mb/blobs/sha256/$MANIFEST")
+        [ "$(sha256sum "$compression-$mb/blobs/sha256/$LAYER" | cut -d' ' -f1)" = "$LAYER" ] || QUEUE_OK=false
+        [ "$(stat -c %s "$compression-$mb/blobs/sha256/$LAYER")" = "$SIZE" ] || QUEUE_OK=false
+    done
+    [ "$(jq -r '.manifests[0].digest' "$compression-1/index.json")" = "$(jq -r '.manifests[0].digest' "$compression-16/index.json")" ] \
+        || QUEUE_OK=false
+done
+if $QUEUE_OK; then
+    pass "Blob digests and sizes match the files, whatever the queue size"
+else
+    fail "persist-queue-mb" "a layer blob does not match its descriptor"
+fi
+set +e
+printf 'persist-queue-mb: 0\nimages: [{architecture: amd64, os: linux}]\n' | build-oci --dry-run >/dev/null 2>&1
+RC=$?
+set -e
+if [ "$RC" -eq 2 ]; then
+    pass "persist-queue-mb: 0 is rejected"
+else
+    fail "persist-queue-mb" "persist-queue-mb: 0 exit $RC"
+fi
+
+cd /
+rm -rf "$WORKDIR"
+
+# --------------------------------------------------
+# Test 67: manifest-only rebuild
+# --------------------------------------------------
+echo ""
+echo "Test 67: --manifest-only keeps the layers and rewrites the metadata"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/rootfs"
+cd "$WORKDIR"
+echo hello > rootfs/hello.txt
+write_manifest() {
+    printf 'output: out\nimages:\n  - {architecture: amd64, os: linux, layer: rootfs, config: {Labels: {version: "%s"}}}\n  - {architecture: arm64, os: linux, name: app, layer: rootfs, config: {Labels: {version: "%s"}}}\n' "$1" "$1"
+}
+write_manifest 1 | SOURCE_DATE_EPOCH=1700000000 build-oci
+layers_of() {
+    for m in $(jq -r '.manifests[0].digest' out/index.json) \
+        $(jq -r '.manifests[1].digest' out/index.json | cut -d: -f2 | xargs -I{} jq -r '.manifests[0].digest' out/blobs/sha256/{}); do
+        jq -c '.layers | map(.digest)' "out/blobs/sha256/${m#sha256:}"
+    done
+}
+BEFORE=$(layers_of)
+# The layer directory is not read: a change in it does not show up
+echo changed > rootfs/hello.txt
+write_manifest 2 | SOURCE_DATE_EPOCH=1700000000 build-oci --manifest-only
+AFTER=$(layers_of)
+CONFIG=$(jq -r '.config.digest' "out/blobs/sha256/$(jq -r '.manifests[0].digest' out/index.json | cut -d: -f2)" | cut -d: -f2)
+if [ "$BEFORE" = "$AFTER" ] && [ "$(jq -r '.config.Labels.version' "out/blobs/sha256/$CONFIG")" = 2 ] \
+    && [ "$(jq '.history | length' "out/blobs/sha256/$CONFIG")" = 1 ] \
+    && [ "$(jq -r '.manifests[1].annotations["org.opencontainers.image.ref.name"]' out/index.json)" = "app:latest" ]; then
+    pass "Layers reused and configs rewritten"
+else
+    fail "--manifest-only" "layers $BEFORE -> $AFTER"
+fi
+
+set +e
+write_manifest 2 | sed 's/arm64/riscv64/' | build-oci --manifest-only >/dev/null 2>&1
+RC=$?
+set -e
+if [ "$RC" -eq 3 ]; then
+    pass "An image without a previous build is reported"
+else
+    fail "--manifest-only" "missing previous build exit $RC"
+fi
+
+cd /
+rm -rf "$WORKDIR"
+
+# --------------------------------------------------
+# Test 68: standard annotations
+# --------------------------------------------------
+echo ""
+echo "Test 68: standard-annotations fills in org.opencontainers.image.*"
+
+WORKDIR=$(mktemp -d)
+cd "$WORKDIR"
+printf 'output: out\nstandard-annotations: true\ndefaults: {licenses: MIT}\nimages:\n  - {architecture: amd64, os: linux, version: "1.2", annotations: {org.opencontainers.image.licenses: Apache-2.0}}\n' \
+    | SOURCE_DATE_EPOCH=1700000000 CI_COMMIT_SHA=abc123 CI_PROJECT_URL=https://example.com/p CI_COMMIT_TAG= build-oci
+MANIFEST=$(jq -r '.manifests[0].digest' out/index.json | cut -d: -f2)
+EXPECTED='{"org.opencontainers.image.created":"2023-11-14T22:13:20Z","org.opencontainers.image.licenses":"Apache-2.0","org.opencontainers.image.revision":"abc123","org.opencontainers.image.source":"https://example.com/p","org.opencontainers.image.version":"1.2"}'
+if [ "$(jq -cS '.annotations' "out/blobs/sha256/$MANIFEST")" = "$EXPECTED" ] \
+    && [ "$(jq -cS '.manifests[0].annotations' out/index.json)" = "$EXPECTED" ]; then
+    pass "Manifest and index entry carry the standard annotations"
+else
+    fail "standard-annotations" "$(jq -cS '.annotations' "out/blobs/sha256/$MANIFEST")"
+fi
+printf 'output: out\nimages: [{architecture: amd64, os: linux, revision: abc}]\n' | CI_COMMIT_SHA=abc123 build-oci
+MANIFEST=$(jq -r '.manifests[0].digest' out/index.json | cut -d: -f2)
+if [ "$(jq '.annotations' "out/blobs/sha256/$MANIFEST")" = "null" ]; then
+    pass "No standard annotations unless enabled"
+else
+    fail "standard-annotations" "annotations written without standard-annotations: true"
+fi
+
+cd /
+rm -rf "$WORKDIR"
+
+# --------------------------------------------------
+# Test 69: gc retention rules
+# --------------------------------------------------
+echo ""
+echo "Test 69: gc retention rules drop old index entries"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/v1" "$WORKDIR/v2" "$WORKDIR/v3" "$WORKDIR/v4"
+cd "$WORKDIR"
+for v in 1 2 3 4; do echo "$v" > "v$v/version.txt"; done
+{
+    echo "output: out"
+    echo "images:"
+    for v in 1 2 3; do
+        echo "  - {architecture: amd64, os: linux, layer: v$v, index-annotations: {org.opencontainers.image.ref.name: cache}}"
+    done
+    echo "  - {architecture: amd64, os: linux, layer: v4}"
+} | build-oci
+entry() { jq -r ".manifests[$1].digest" out/index.json; }
+E1=$(entry 0); E2=$(entry 1); E3=$(entry 2); E4=$(entry 3)
+echo "$E1  # pinned" > pinned.txt
+# Only the newest entry counts as recent
+for e in "$E1" "$E2" "$E4"; do touch -d '2 days ago' "out/blobs/sha256/${e#sha256:}"; done
+build-oci gc out --keep-last 1 --dry-run > dry.txt
+if [ "$(jq '.manifests | length' out/index.json)" -eq 4 ] && grep -q "^index.json: $E2 cache" dry.txt \
+    && grep -q "would drop 2 index entry(s)" dry.txt; then
+    pass "--dry-run lists the entries it would drop"
+else
+    fail "gc retention" "dry run: $(cat dry.txt)"
+fi
+build-oci gc out --keep-digests pinned.txt --min-age 1d > gc.txt
+if [ "$(jq -r '[.manifests[].digest] | join(" ")' out/index.json)" = "$E1 $E3 $E4" ] \
+    && [ ! -e "out/blobs/sha256/${E2#sha256:}" ] && grep -q "out: dropped 1 index entry(s)" gc.txt \
+    && [ "$(build-oci gc out --dry-run | tail -1)" = "out: would remove 0 unreachable blob(s), 0 B" ]; then
+    pass "Pinned, recent and unnamed entries stay; the dropped entry's blobs are deleted"
+else
+    fail "gc retention" "$(cat gc.txt); index: $(jq -c '[.manifests[].digest]' out/index.json)"
+fi
+set +e
+build-oci gc out --min-age soon >/dev/null 2>&1
+RC=$?
+set -e
+if [ "$RC" -ne 0 ]; then
+    pass "An invalid --min-age is rejected"
+else
+    fail "gc retention" "--min-age soon accepted"
+fi
+
+cd /
+rm -rf "$WORKDIR"
+
+# --------------------------------------------------
+# Test 70: history provenance
+# --------------------------------------------------
+echo ""
+echo "Test 70: history entries record created and created_by"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/rootfs"
+cd "$WORKDIR"
+echo hi > rootfs/hi.txt
+printf 'output: base\nimages: [{architecture: amd64, os: linux, layer: rootfs, comment: base, created-by: bst build base.bst}]\n' \
+    | SOURCE_DATE_EPOCH=1700000000 build-oci
+printf 'output: out\nimages: [{architecture: amd64, os: linux, parent: {image: base}, created-by: bst build app.bst}]\n' \
+    | SOURCE_DATE_EPOCH=1700000100 build-oci
+MANIFEST=$(jq -r '.manifests[0].digest' out/index.json | cut -d: -f2)
+CONFIG=$(jq -r '.config.digest' "out/blobs/sha256/$MANIFEST" | cut -d: -f2)
+EXPECTED='[{"comment":"base","created":"2023-11-14T22:13:20Z","created_by":"bst build base.bst"},{"created":"2023-11-14T22:15:00Z","created_by":"bst build app.bst","empty_layer":true}]'
+if [ "$(jq -cS '.history' "out/blobs/sha256/$CONFIG")" = "$EXPECTED" ]; then
+    pass "Each history entry carries its image's created time and created_by"
+else
+    fail "created-by" "$(jq -cS '.history' "out/blobs/sha256/$CONFIG")"
+fi
+
+cd /
+rm -rf "$WORKDIR"
+
+# --------------------------------------------------
+# Test 71: test corpus
+# --------------------------------------------------
+echo ""
+echo "Test 71: images built from the test corpus keep its tricky entries"
+
+WORKDIR=$(mktemp -d)
+cd "$WORKDIR"
+build-oci test-corpus corpus > corpus.txt
+if [ "$(grep -c ': written$' corpus.txt)" -ge 5 ] && [ -f corpus/hardlinks/c/d/link3 ]; then
+    pass "test-corpus writes its cases"
+else
+    fail "test-corpus" "$(cat corpus.txt)"
+fi
+printf 'output: out\ncompression: disabled\nimages: [{architecture: amd64, os: linux, layer: corpus}]\n' | build-oci
+MANIFEST=$(jq -r '.manifests[0].digest' out/index.json | cut -d: -f2)
+LAYER=$(jq -r '.layers[0].digest' "out/blobs/sha256/$MANIFEST" | cut -d: -f2)
+mkdir extracted
+tar --xattrs --xattrs-include='user.*' -xpf "out/blobs/sha256/$LAYER" -C extracted 2>/dev/null
+CORPUS_OK=true
+diff -r --no-dereference corpus extracted >/dev/null || CORPUS_OK=false
+[ "$(stat -c %i extracted/hardlinks/a/original)" = "$(stat -c %i extracted/hardlinks/c/d/link3)" ] || CORPUS_OK=false
+[ "$(stat -c %a extracted/modes/setuid extracted/modes/sticky | tr '\n' ' ')" = "4755 1777 " ] || CORPUS_OK=false
+[ "$(readlink extracted/long-paths/long-target)" = "$(readlink corpus/long-paths/long-target)" ] || CORPUS_OK=false
+if command -v getfattr >/dev/null 2>&1; then
+    [ "$(getfattr --absolute-names -d extracted/xattrs/many | grep -c '^user.key')" -eq 100 ] || CORPUS_OK=false
+fi
+if $CORPUS_OK; then
+    pass "Long paths, hard links, modes, symlinks and sparse files survive the round trip"
+else
+    fail "test-corpus" "extracted layer differs from the corpus"
+fi
+mkdir latin1
+python3 -c "open(b'latin1/caf\xe9.txt', 'w').close()"
+set +e
+printf 'output: latin1-out\nimages: [{architecture: amd64, os: linux, layer: latin1}]\n' | build-oci > latin1.txt 2>&1
+RC=$?
+set -e
+if [ "$RC" -ne 0 ] && grep -q "file name is not valid UTF-8" latin1.txt; then
+    pass "Non-UTF-8 names are rejected by path"
+else
+    fail "test-corpus" "non-UTF-8 names: exit $RC"
+fi
+
+cd /
+rm -rf "$WORKDIR"
+
+# --------------------------------------------------
+# Test 72: several layers per image
+# --------------------------------------------------
+echo ""
+echo "Test 72: layers: builds one layer per directory, deduplicated in order"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/base" "$WORKDIR/extra" "$WORKDIR/app"
+cd "$WORKDIR"
+echo base > base/base.txt
+echo shared > base/shared.txt
+cp base/* extra/
+echo extra > extra/extra.txt
+cp extra/shared.txt extra/extra.txt app/
+echo app > app/app.txt
+printf 'output: out\ncompression: disabled\nimages: [{architecture: amd64, os: linux, layers: [base, extra, app]}]\n' \
+    | SOURCE_DATE_EPOCH=1700000000 build-oci
+MANIFEST=$(jq -r '.manifests[0].digest' out/index.json | cut -d: -f2)
+CONFIG=$(jq -r '.config.digest' "out/blobs/sha256/$MANIFEST" | cut -d: -f2)
+SECOND=$(jq -r '.layers[1].digest' "out/blobs/sha256/$MANIFEST" | cut -d: -f2)
+THIRD=$(jq -r '.layers[2].digest' "out/blobs/sha256/$MANIFEST" | cut -d: -f2)
+if [ "$(jq '.layers | length' "out/blobs/sha256/$MANIFEST")" -eq 3 ] \
+    && [ "$(jq '.rootfs.diff_ids | length' "out/blobs/sha256/$CONFIG")" -eq 3 ] \
+    && [ "$(jq '[.history[] | select(.empty_layer != true)] | length' "out/blobs/sha256/$CONFIG")" -eq 3 ] \
+    && [ "$(tar -tf "out/blobs/sha256/$SECOND" 2>/dev/null | grep 'txt$' | sort | tr '\n' ' ')" = "extra.txt " ] \
+    && [ "$(tar -tf "out/blobs/sha256/$THIRD" 2>/dev/null | grep 'txt$' | sort | tr '\n' ' ')" = ".wh.base.txt app.txt " ]; then
+    pass "Three layers, diff_ids and history entries, each deduplicated against those below"
+else
+    fail "layers" "$(tar -tf "out/blobs/sha256/$SECOND" 2>/dev/null | tr '\n' ' ') / $(tar -tf "out/blobs/sha256/$THIRD" 2>/dev/null | tr '\n' ' ')"
+fi
+PLAN=$(printf 'output: out\nimages: [{architecture: amd64, os: linux, layers: [base, extra, app]}]\n' | build-oci --dry-run)
+if [ "$(echo "$PLAN" | jq '.images[0].layer | length')" -eq 3 ] \
+    && [ "$(echo "$PLAN" | jq -c '.images[0].layer[2] | [.added, .skipped, .whiteouts]')" \
+        = '[["./app.txt"],["./extra.txt","./shared.txt"],["./base.txt"]]' ]; then
+    pass "--dry-run plans every layer against those below it"
+else
+    fail "layers" "dry run: $PLAN"
+fi
+set +e
+printf 'images: [{architecture: amd64, os: linux, layer: base, layers: [extra]}]\n' | build-oci --dry-run >/dev/null 2>&1
+RC=$?
+set -e
+if [ "$RC" -eq 2 ]; then
+    pass "layers: and layer: together are rejected"
+else
+    fail "layers" "layer + layers exit $RC"
+fi
+
+cd /
+rm -rf "$WORKDIR"
+
+# Test 73: pre-built tar layers
+# --------------------------------------------------
+echo ""
+echo "Test 73: layer-tar: adds a tar file, plain or compressed, as the layer"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/root/etc"
+cd "$WORKDIR"
+echo hello > root/etc/hello.txt
+tar -C root -cf layer.tar etc
+gzip -kn layer.tar
+if command -v zstd >/dev/null 2>&1; then
+    zstd -q -k layer.tar
+else
+    cp layer.tar.gz layer.tar.zst
+fi
+EXPECTED="sha256:$(sha256sum layer.tar | cut -d' ' -f1)"
+OK=1
+for TAR in layer.tar layer.tar.gz layer.tar.zst; do
+    rm -rf out
+    printf 'output: out\ncompression: gzip\nimages: [{architecture: amd64, os: linux, layer-tar: %s}]\n' "$TAR" | build-oci
+    MANIFEST=$(jq -r '.manifests[0].digest' out/index.json | cut -d: -f2)
+    CONFIG=$(jq -r '.config.digest' "out/blobs/sha256/$MANIFEST" | cut -d: -f2)
+    LAYER=$(jq -r '.layers[0].digest' "out/blobs/sha256/$MANIFEST" | cut -d: -f2)
+    if [ "$(jq -r '.rootfs.diff_ids[0]' "out/blobs/sha256/$CONFIG")" != "$EXPECTED" ] \
+        || [ "$(jq -r '.layers[0].mediaType' "out/blobs/sha256/$MANIFEST")" != "application/vnd.oci.image.layer.v1.tar+gzip" ] \
+        || ! tar -tzf "out/blobs/sha256/$LAYER" | grep -q '^etc/hello.txt$'; then
+        OK=0
+        fail "layer-tar" "$TAR: $(jq -c '.rootfs' "out/blobs/sha256/$CONFIG")"
+    fi
+done
+if [ "$OK" -eq 1 ]; then
+    pass "Plain, gzip and zstd tars give the diff_id of the uncompressed tar, recompressed to gzip"
+fi
+echo "$EXPECTED" > layer.tar.gz.diffid
+rm -rf out
+printf 'output: out\nimages: [{architecture: amd64, os: linux, layer-tar: layer.tar.gz}]\n' | build-oci
+echo "sha256:0000000000000000000000000000000000000000000000000000000000000000" > layer.tar.gz.diffid
+set +e
+printf 'output: out2\nimages: [{architecture: amd64, os: linux, layer-tar: layer.tar.gz}]\n' | build-oci 2>/dev/null
+RC=$?
+set -e
+if [ -f out/index.json ] && [ "$RC" -eq 5 ]; then
+    pass "A matching .diffid sidecar is accepted, a wrong one fails with exit code 5"
+else
+    fail "layer-tar" "diffid sidecar: exit code $RC"
+fi
+PLAN=$(printf 'output: out\nimages: [{architecture: amd64, os: linux, layer-tar: layer.tar}]\n' | build-oci --dry-run)
+if [ "$(echo "$PLAN" | jq '.images[0].layer.uncompressedSize')" -eq "$(stat -c %s layer.tar)" ]; then
+    pass "--dry-run reports the uncompressed size of the tar"
+else
+    fail "layer-tar" "dry run: $PLAN"
+fi
+set +e
+printf 'images: [{architecture: amd64, os: linux, layer: root, layer-tar: layer.tar}]\n' | build-oci --dry-run >/dev/null 2>&1
+RC=$?
+set -e
+if [ "$RC" -eq 2 ]; then
+    pass "layer-tar: and layer: together are rejected"
+else
+    fail "layer-tar" "layer-tar with layer: exit code $RC"
+fi
+cd /
+rm -rf "$WORKDIR"
+
+# Test 74: filesystems without xattr support
+# --------------------------------------------------
+echo ""
+echo "Test 74: xattr calls stop after the filesystem reports ENOTSUP"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/root"
+cd "$WORKDIR"
+if mount -t ramfs none root 2>/dev/null; then
+    mkdir root/dir
+    for i in 1 2 3 4; do
+        echo "$i" > "root/file$i"
+        echo "$i" > "root/dir/file$i"
+    done
+    printf 'output: out\nwrite-checksum-xattrs: true\nimages: [{architecture: amd64, os: linux, layer: root}]\n' \
+        | build-oci -v --log-format json 2> log.json
+    NOTICES=$(jq -r 'select(.fields.message == "filesystem does not support extended attributes, skipping them") | .fields.path' log.json | wc -l)
+    LAYER=$(jq -r '.manifests[0].digest' out/index.json | cut -d: -f2)
+    LAYER=$(jq -r '.layers[0].digest' "out/blobs/sha256/$LAYER" | cut -d: -f2)
+    if [ "$NOTICES" -eq 1 ] && [ "$(zstd -dc "out/blobs/sha256/$LAYER" | tar -t 2>/dev/null | grep -c 'file')" -eq 8 ]; then
+        pass "A single notice for the ramfs layer directory, and every file is still packed"
+    else
+        fail "xattr support" "$NOTICES notices"
+    fi
+    umount root
+else
+    warn "xattr support" "cannot mount ramfs, skipped"
+fi
+cd /
+rm -rf "$WORKDIR"
+
+# Test 75: following symlinks
+# --------------------------------------------------
+echo ""
+echo "Test 75: follow-symlinks: packs link targets, rejecting escaping links and loops"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/store/pkg/bin" "$WORKDIR/root/bin"
+cd "$WORKDIR"
+echo tool > store/pkg/bin/tool
+echo config > store/pkg/config
+ln -s "$WORKDIR/store/pkg/bin/tool" root/bin/tool
+ln -s "$WORKDIR/store/pkg" root/pkg
+ln -s bin/tool root/tool
+printf 'output: out\ncompression: disabled\nimages: [{architecture: amd64, os: linux, layer: root, follow-symlinks: true, symlink-roots: [store]}]\n' \
+    | build-oci
+MANIFEST=$(jq -r '.manifests[0].digest' out/index.json | cut -d: -f2)
+LAYER=$(jq -r '.layers[0].digest' "out/blobs/sha256/$MANIFEST" | cut -d: -f2)
+LISTING=$(tar -tvf "out/blobs/sha256/$LAYER" 2>/dev/null)
+if echo "$LISTING" | grep -q '^d.* pkg/bin/$' && echo "$LISTING" | grep -q '^-.* 7 .* pkg/config$' \
+    && ! echo "$LISTING" | grep -q '^l' \
+    && [ "$(tar -xOf "out/blobs/sha256/$LAYER" tool 2>/dev/null)" = "tool" ]; then
+    pass "Links to files and directories are replaced by their targets"
+else
+    fail "follow-symlinks" "$LISTING"
+fi
+set +e
+printf 'output: out2\nimages: [{architecture: amd64, os: linux, layer: root, follow-symlinks: true}]\n' | build-oci 2>/dev/null
+RC_ESCAPE=$?
+ln -s .. root/bin/loop
+printf 'output: out2\nimages: [{architecture: amd64, os: linux, layer: root, follow-symlinks: true, symlink-roots: [store]}]\n' | build-oci 2>/dev/null
+RC_LOOP=$?
+rm root/bin/loop
+printf 'images: [{architecture: amd64, os: linux, layer: root, symlink-roots: [store]}]\n' | build-oci --dry-run >/dev/null 2>&1
+RC_ROOTS=$?
+set -e
+if [ "$RC_ESCAPE" -ne 0 ] && [ "$RC_LOOP" -ne 0 ] && [ ! -f out2/index.json ] && [ "$RC_ROOTS" -eq 2 ]; then
+    pass "Links outside symlink-roots and loops fail; symlink-roots needs follow-symlinks"
+else
+    fail "follow-symlinks" "escape $RC_ESCAPE, loop $RC_LOOP, roots $RC_ROOTS"
+fi
+cd /
+rm -rf "$WORKDIR"
+
+# Test 76: config-only derived images
+# --------------------------------------------------
+echo ""
+echo "Test 76: an image without layers of its own links its parent's layer blobs as they are"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/root"
+cd "$WORKDIR"
+echo base > root/base.txt
+printf 'output: parent\ncompression: gzip\nimages: [{architecture: amd64, os: linux, layer: root}]\n' | build-oci
+printf 'output: out\ncompression: zstd\nimages: [{architecture: amd64, os: linux, parent: {image: parent}, config: {Env: [A=1]}}]\n' \
+    | build-oci
+PARENT_MANIFEST=$(jq -r '.manifests[0].digest' parent/index.json | cut -d: -f2)
+MANIFEST=$(jq -r '.manifests[0].digest' out/index.json | cut -d: -f2)
+CONFIG=$(jq -r '.config.digest' "out/blobs/sha256/$MANIFEST" | cut -d: -f2)
+LAYER=$(jq -r '.layers[0].digest' "out/blobs/sha256/$MANIFEST" | cut -d: -f2)
+if [ "$(jq -c '.layers' "out/blobs/sha256/$MANIFEST")" = "$(jq -c '.layers' "parent/blobs/sha256/$PARENT_MANIFEST")" ] \
+    && [ "$(stat -c %i "out/blobs/sha256/$LAYER")" = "$(stat -c %i "parent/blobs/sha256/$LAYER")" ] \
+    && [ "$(jq -r '.config.Env[0]' "out/blobs/sha256/$CONFIG")" = "A=1" ]; then
+    pass "Layer descriptors copied verbatim and the blob hard linked, despite another output compression"
+else
+    fail "config-only" "$(jq -c '.layers' "out/blobs/sha256/$MANIFEST")"
+fi
+rm -rf out
+printf 'output: out\ncompression: zstd\nimages: [{architecture: amd64, os: linux, parent: {image: parent, recompress: true}}]\n' \
+    | build-oci
+MANIFEST=$(jq -r '.manifests[0].digest' out/index.json | cut -d: -f2)
+if [ "$(jq -r '.layers[0].mediaType' "out/blobs/sha256/$MANIFEST")" = "application/vnd.oci.image.layer.v1.tar+zstd" ]; then
+    pass "parent recompress: true still re-encodes the parent's layers"
+else
+    fail "config-only" "recompress: $(jq -c '.layers' "out/blobs/sha256/$MANIFEST")"
+fi
+cd /
+rm -rf "$WORKDIR"
+
+# Test 77: verifying parent blobs
+# --------------------------------------------------
+echo ""
+echo "Test 77: verify-parents: always hashes parent blobs once and rejects corrupt ones"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/root" "$WORKDIR/child"
+cd "$WORKDIR"
+export XDG_CACHE_HOME="$WORKDIR/cache"
+echo base > root/base.txt
+echo child > child/child.txt
+printf 'output: parent\ncompression: gzip\nimages: [{architecture: amd64, os: linux, layer: root}]\n' | build-oci
+verify_stat() {
+    jq -s -r --arg field "$2" \
+        '.[] | select(.fields.message == "cache statistics" and .fields.cache == "parentVerify") | .fields[$field]' "$1"
+}
+MANIFEST_YAML='verify-parents: always\ncompression: gzip\nimages: [{architecture: amd64, os: linux, parent: {image: parent}, layer: child}]\n'
+printf "output: out\n$MANIFEST_YAML" | build-oci -v --log-format json 2> first.log
+printf "output: out2\n$MANIFEST_YAML" | build-oci -v --log-format json 2> second.log
+if [ "$(verify_stat first.log misses)" -eq 3 ] && [ "$(verify_stat second.log misses)" -eq 0 ] \
+    && [ "$(verify_stat second.log hits)" -eq 3 ] && [ -s cache/build-oci/verified ]; then
+    pass "Manifest, config and layer hashed on the first build, remembered on the second"
+else
+    fail "verify-parents" "misses $(verify_stat first.log misses)/$(verify_stat second.log misses)"
+fi
+LAYER=$(jq -r '.manifests[0].digest' parent/index.json | cut -d: -f2)
+LAYER=$(jq -r '.layers[0].digest' "parent/blobs/sha256/$LAYER" | cut -d: -f2)
+printf 'x' | dd of="parent/blobs/sha256/$LAYER" bs=1 seek=20 conv=notrunc 2>/dev/null
+set +e
+printf "output: out3\n$MANIFEST_YAML" | build-oci 2>/dev/null
+RC=$?
+printf 'output: out4\nimages: [{architecture: amd64, os: linux, parent: {image: parent}}]\n' | build-oci >/dev/null 2>&1
+RC_UNVERIFIED=$?
+set -e
+if [ "$RC" -eq 5 ] && [ "$RC_UNVERIFIED" -eq 0 ]; then
+    pass "A modified parent layer fails with exit code 5, and is only checked when asked"
+else
+    fail "verify-parents" "corrupt parent: exit code $RC, unverified $RC_UNVERIFIED"
+fi
+unset XDG_CACHE_HOME
+cd /
+rm -rf "$WORKDIR"
+
+# Test 78: publishing to several destinations
+# --------------------------------------------------
+echo ""
+echo "Test 78: publish: copies the layout to every destination, each reporting its own status"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/root"
+cd "$WORKDIR"
+echo hello > root/hello.txt
+printf 'output: other\nimages: [{architecture: arm64, os: linux, layer: root, name: other}]\n' | build-oci
+cp -r other mirror2
+set +e
+OUTPUT=$(printf 'output: out\nimages: [{architecture: amd64, os: linux, layer: root, name: app}]
+publish: [{layout: mirror1}, {layout: mirror2}, {registry: "127.0.0.1:1/test/app", plain-http: true}]\n' \
+    | build-oci 2>/dev/null)
+RC=$?
+set -e
+if [ "$RC" -ne 0 ] && echo "$OUTPUT" | grep -q '/mirror1: published (4 blob(s) copied, 0 already present)' \
+    && echo "$OUTPUT" | grep -q '/mirror2: published (3 blob(s) copied, 1 already present)' \
+    && echo "$OUTPUT" | grep -q '^127.0.0.1:1/test/app: failed$' \
+    && [ "$(jq -c '.manifests' mirror1/index.json)" = "$(jq -c '.manifests' out/index.json)" ]; then
+    pass "Both layouts published while the unreachable registry fails the build"
+else
+    fail "publish" "exit $RC: $OUTPUT"
+fi
+if [ "$(jq -r '[.manifests[].annotations["org.opencontainers.image.ref.name"]] | sort | join(" ")' mirror2/index.json)" = "app:latest other:latest" ] \
+    && build-oci ls mirror2 >/dev/null; then
+    pass "Entries of a destination layout that the build does not replace are kept"
+else
+    fail "publish" "merged index: $(jq -c '.manifests' mirror2/index.json)"
+fi
+set +e
+printf 'images: [{architecture: amd64, os: linux, layer: root}]\npublish: [{layout: a, registry: b}]\n' | build-oci --dry-run >/dev/null 2>&1
+RC=$?
+set -e
+if [ "$RC" -eq 2 ]; then
+    pass "A destination with both layout and registry is rejected"
+else
+    fail "publish" "layout and registry: exit code $RC"
+fi
+set +e
+printf 'images: [{architecture: amd64, os: linux, layer: root}]\npublish: [{registry: b, limit-rate: 5X}]\n' | build-oci --dry-run >/dev/null 2>&1
+RC_RATE=$?
+printf 'images: [{architecture: amd64, os: linux, layer: root}]\npublish: [{registry: b, max-connections: 0}]\n' | build-oci --dry-run >/dev/null 2>&1
+RC_CONNECTIONS=$?
+set -e
+if [ "$RC_RATE" -eq 2 ] && [ "$RC_CONNECTIONS" -eq 2 ]; then
+    pass "Invalid limit-rate and max-connections are rejected"
+else
+    fail "publish" "limit-rate: 5X exit $RC_RATE, max-connections: 0 exit $RC_CONNECTIONS"
+fi
+cd /
+rm -rf "$WORKDIR"
+
+# Test 79: per-layer compression
+# --------------------------------------------------
+echo ""
+echo "Test 79: layers can choose their own compression"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/rootfs" "$WORKDIR/config" "$WORKDIR/app"
+cd "$WORKDIR"
+echo rootfs > rootfs/rootfs.txt
+echo config > config/config.txt
+echo app > app/app.txt
+printf 'output: out\ncompression: zstd\nimages: [{architecture: amd64, os: linux, compression: gzip,
+  layers: [{dir: rootfs, compression: zstd}, {dir: config, compression: disabled}, app]}]\n' | build-oci
+MANIFEST=$(jq -r '.manifests[0].digest' out/index.json | cut -d: -f2)
+TYPES=$(jq -r '[.layers[].mediaType | sub("application/vnd.oci.image.layer.v1."; "")] | join(" ")' "out/blobs/sha256/$MANIFEST")
+CONFIG_LAYER=$(jq -r '.layers[1].digest' "out/blobs/sha256/$MANIFEST" | cut -d: -f2)
+if [ "$TYPES" = "tar+zstd tar tar+gzip" ] && tar -tf "out/blobs/sha256/$CONFIG_LAYER" 2>/dev/null | grep -q 'config.txt$'; then
+    pass "One manifest mixes tar+zstd, tar and tar+gzip layers"
+else
+    fail "per-layer compression" "$TYPES"
+fi
+set +e
+printf 'images: [{architecture: amd64, os: linux, layers: [{dir: rootfs, compression: lz4}]}]\n' | build-oci --dry-run >/dev/null 2>&1
+RC=$?
+set -e
+if [ "$RC" -eq 2 ]; then
+    pass "An unknown layer compression is rejected"
+else
+    fail "per-layer compression" "lz4: exit code $RC"
+fi
+cd /
+rm -rf "$WORKDIR"
+
+# Test 80: eStargz output
+# --------------------------------------------------
+echo ""
+echo "Test 80: compression: estargz writes lazily pullable layers"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/rootfs/etc" "$WORKDIR/base"
+cd "$WORKDIR"
+head -c 5000000 /dev/urandom > rootfs/big.bin
+echo hello > rootfs/etc/hello.txt
+echo base > base/base.txt
+printf 'output: out\ncompression: estargz\nimages:\n  - {architecture: amd64, os: linux, layer: rootfs}\n' | build-oci
+MANIFEST=$(jq -r '.manifests[0].digest' out/index.json | cut -d: -f2)
+LAYER=$(jq -r '.layers[0].digest' "out/blobs/sha256/$MANIFEST" | cut -d: -f2)
+CONFIG=$(jq -r '.config.digest' "out/blobs/sha256/$MANIFEST" | cut -d: -f2)
+DIFF_ID=$(jq -r '.rootfs.diff_ids[0]' "out/blobs/sha256/$CONFIG")
+TOC=$(zcat "out/blobs/sha256/$LAYER" | tar -xOf - stargz.index.json 2>/dev/null)
+if [ "sha256:$(zcat "out/blobs/sha256/$LAYER" | sha256sum | cut -d' ' -f1)" = "$DIFF_ID" ] \
+    && [ "$(jq -r '.layers[0].annotations["containerd.io/snapshot/stargz/toc.digest"]' "out/blobs/sha256/$MANIFEST")" = "sha256:$(printf '%s' "$TOC" | sha256sum | cut -d' ' -f1)" ] \
+    && [ "$(echo "$TOC" | jq '[.entries[] | select(.name == "big.bin")] | length')" = "2" ] \
+    && build-oci verify --lazy-pull out >/dev/null; then
+    pass "eStargz layer has a TOC, chunked large files and passes verify --lazy-pull"
+else
+    fail "estargz" "diff_id, TOC or annotations wrong"
+fi
+printf 'output: parent\ncompression: zstd\nimages:\n  - {architecture: amd64, os: linux, layer: base}\n' | build-oci
+printf 'output: child\ncompression: estargz\nimages:\n  - {architecture: amd64, os: linux, layer: rootfs, parent: {image: parent}}\n' | build-oci
+PARENT_CONFIG=$(jq -r '.config.digest' "parent/blobs/sha256/$(jq -r '.manifests[0].digest' parent/index.json | cut -d: -f2)" | cut -d: -f2)
+CHILD_MANIFEST=$(jq -r '.manifests[0].digest' child/index.json | cut -d: -f2)
+CHILD_CONFIG=$(jq -r '.config.digest' "child/blobs/sha256/$CHILD_MANIFEST" | cut -d: -f2)
+if [ "$(jq -r '.layers[0].mediaType' "child/blobs/sha256/$CHILD_MANIFEST")" = "application/vnd.oci.image.layer.v1.tar+gzip" ] \
+    && [ "$(jq -r '.rootfs.diff_ids[0]' "child/blobs/sha256/$CHILD_CONFIG")" = "$(jq -r '.rootfs.diff_ids[0]' "parent/blobs/sha256/$PARENT_CONFIG")" ] \
+    && build-oci verify --lazy-pull child >/dev/null; then
+    pass "zstd parent layers are re-encoded as plain gzip, keeping their diff_id"
+else
+    fail "estargz" "parent layer re-encoding"
+fi
+cd /
+rm -rf "$WORKDIR"
+
+# Test 81: layer tar format golden file
+# --------------------------------------------------
+echo ""
+echo "Test 81: layer tar format version 1 headers match the golden listing"
+
+WORKDIR=$(mktemp -d)
+cd "$WORKDIR"
+mkdir -p base/etc rootfs/etc rootfs/bin
+echo old > base/etc/old.conf
+echo hello > rootfs/etc/hello.txt
+printf '#!/bin/sh\n' > rootfs/bin/run
+ln rootfs/bin/run rootfs/bin/run2
+ln -s ../etc/hello.txt rootfs/bin/hello
+ln -s "/$(printf 't%.0s' $(seq 1 110))" rootfs/bin/far
+echo long > "rootfs/etc/$(printf 'l%.0s' $(seq 1 110))"
+find base rootfs -type d -exec chmod 755 {} +
+find base rootfs -type f -exec chmod 644 {} +
+chmod 755 rootfs/bin/run
+printf 'output: parent\nsource-date-epoch: 1700000000\nskip-xattrs: true\ncompression: disabled\nimages:\n  - {architecture: amd64, os: linux, layer: base}\n' | build-oci
+printf 'output: out\nsource-date-epoch: 1700000000\nskip-xattrs: true\ncompression: disabled\nformat-version: 1\nimages:\n  - {architecture: amd64, os: linux, layer: rootfs, parent: {image: parent}}\n' | build-oci
+MANIFEST=$(jq -r '.manifests[0].digest' out/index.json | cut -d: -f2)
+LAYER=$(jq -r '.layers[1].digest' "out/blobs/sha256/$MANIFEST" | cut -d: -f2)
+python3 - "out/blobs/sha256/$LAYER" > headers.txt <<'PYEOF'
+import os, sys
+raw = open(sys.argv[1], 'rb').read()
+field = lambda h, a, b: h[a:b].rstrip(b'\0').decode()
+number = lambda h, a, b: int(h[a:b].strip(b'\0 ') or b'0', 8)
+owner = lambda n: 'self' if n == os.getuid() else str(n)
+offset = 0
+while offset < len(raw):
+    h = raw[offset:offset + 512]
+    if h == bytes(512):
+        print('end', len(raw) - offset)
+        break
+    size = number(h, 124, 136)
+    link = field(h, 157, 257)
+    print(chr(h[156]), repr(h[257:265])[2:-1], oct(number(h, 100, 108)), owner(number(h, 108, 116)),
+          owner(number(h, 116, 124)), repr(field(h, 265, 297) + ':' + field(h, 297, 329)),
+          number(h, 136, 148), size, field(h, 0, 100) + (' -> ' + link if link else ''))
+    data = raw[offset + 512:offset + 512 + size]
+    if chr(h[156]) in 'xL':
+        print("  " + data.rstrip(b"\0").decode().rstrip().replace("\n", "\n  "))
+    offset += 512 + (size + 511) // 512 * 512
+PYEOF
+cat > golden.txt <<'GOLDEN'
+5 ustar  \x00 0o40755 self self ':' 1700000000 0 ./
+5 ustar  \x00 0o40755 self self ':' 1700000000 0 bin/
+x ustar\x0000 0o0 self self ':' 0 125 bin/far
+  125 linkpath=/tttttttttttttttttttttttttttttttttttttttttttttttttttttttttttttttttttttttttttttttttttttttttttttttttttttttttttttt
+2 ustar  \x00 0o120777 self self ':' 1700000000 0 bin/far
+2 ustar  \x00 0o120777 self self ':' 1700000000 0 bin/hello -> ../etc/hello.txt
+x ustar\x0000 0o0 self self ':' 0 99 bin/run
+  99 freedesktopsdk.checksum.sha256=a8076d3d28d21e02012b20eaf7dbf75409a6277134439025f282e368e3305abf
+0 ustar  \x00 0o100755 self self ':' 1700000000 10 bin/run
+1 ustar  \x00 0o100755 self self ':' 1700000000 0 bin/run2 -> ./bin/run
+5 ustar  \x00 0o40755 self self ':' 1700000000 0 etc/
+0 ustar  \x00 0o100644 self self ':' 1700000000 0 etc/.wh.old.conf
+x ustar\x0000 0o0 self self ':' 0 99 etc/hello.txt
+  99 freedesktopsdk.checksum.sha256=5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03
+0 ustar  \x00 0o100644 self self ':' 1700000000 6 etc/hello.txt
+L ustar  \x00 0o644 self self ':' 0 117 ././@LongLink
+  ./etc/llllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllll
+x ustar\x0000 0o0 self self ':' 0 99 etc/llllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllll
+  99 freedesktopsdk.checksum.sha256=bbdbb75b415ee9a40f0b3796a8b41a0b7723afe5726b870474ad220a4886d06d
+L ustar  \x00 0o644 self self ':' 0 117 ././@LongLink
+  ./etc/llllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllll
+0 ustar  \x00 0o100644 self self ':' 1700000000 5 etc/llllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllll
+end 1024
+GOLDEN
+if [ "$(id -u)" -ne 0 ]; then
+    warn "layer format" "golden listing assumes root-owned files, skipped"
+elif diff -u golden.txt headers.txt; then
+    pass "Layer headers, PAX records, order and whiteouts match format version 1"
+else
+    fail "layer format" "headers differ from the golden listing"
+fi
+if [ "$(jq -r '.layers[1].annotations["org.freedesktopsdk.layer.format-version"]' "out/blobs/sha256/$MANIFEST")" = "1" ] \
+    && [ "$(jq -r '.layers[0].annotations' "out/blobs/sha256/$MANIFEST")" = "null" ]; then
+    pass "format-version: 1 is recorded on new layers only"
+else
+    fail "layer format" "format-version annotation"
+fi
+set +e
+printf 'format-version: 2\nimages:\n  - {architecture: amd64, os: linux, layer: rootfs}\n' | build-oci --dry-run >/dev/null 2>&1
+RC=$?
+set -e
+if [ "$RC" -eq 2 ]; then
+    pass "An unknown format-version is rejected"
+else
+    fail "layer format" "format-version 2: exit code $RC"
+fi
+cd /
+rm -rf "$WORKDIR"
+
+# Test 82: zstd:chunked output
+# --------------------------------------------------
+echo ""
+echo "Test 82: compression: zstd-chunked writes partially pullable layers"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/rootfs/etc"
+cd "$WORKDIR"
+head -c 2000000 /dev/urandom > rootfs/big.bin
+echo hello > rootfs/etc/hello.txt
+printf 'output: out\ncompression: zstd-chunked\nimages:\n  - {architecture: amd64, os: linux, layer: rootfs}\n' | build-oci
+MANIFEST=$(jq -r '.manifests[0].digest' out/index.json | cut -d: -f2)
+LAYER=$(jq -r '.layers[0].digest' "out/blobs/sha256/$MANIFEST" | cut -d: -f2)
+CONFIG=$(jq -r '.config.digest' "out/blobs/sha256/$MANIFEST" | cut -d: -f2)
+IFS=: read -r TOC_OFFSET TOC_LEN _ _ <<< "$(jq -r '.layers[0].annotations["io.github.containers.zstd-chunked.manifest-position"]' "out/blobs/sha256/$MANIFEST")"
+tail -c +$((TOC_OFFSET + 1)) "out/blobs/sha256/$LAYER" | head -c "$TOC_LEN" > toc.zst
+BIG_OFFSET=$(zstd -dc toc.zst | jq '.entries[] | select(.name == "big.bin") | .offset')
+BIG_END=$(zstd -dc toc.zst | jq '.entries[] | select(.name == "big.bin") | .endOffset')
+if [ "sha256:$(zstd -dc "out/blobs/sha256/$LAYER" | sha256sum | cut -d' ' -f1)" = "$(jq -r '.rootfs.diff_ids[0]' "out/blobs/sha256/$CONFIG")" ] \
+    && [ "$(jq -r '.layers[0].annotations["io.github.containers.zstd-chunked.manifest-checksum"]' "out/blobs/sha256/$MANIFEST")" = "sha256:$(sha256sum < toc.zst | cut -d' ' -f1)" ] \
+    && tail -c +$((BIG_OFFSET + 1)) "out/blobs/sha256/$LAYER" | head -c $((BIG_END - BIG_OFFSET)) | zstd -dc | cmp -s - rootfs/big.bin \
+    && build-oci verify --lazy-pull out >/dev/null; then
+    pass "zstd:chunked layer keeps its diff_id, indexes each file's frame and passes verify --lazy-pull"
+else
+    fail "zstd-chunked" "diff_id, TOC or file frame wrong"
+fi
+cd /
+rm -rf "$WORKDIR"
+
+# Test 83: artifact manifests with a subject
+# --------------------------------------------------
+echo ""
+echo "Test 83: artifact-type and subject attach an SBOM and its signature to an image"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/rootfs"
+cd "$WORKDIR"
+echo hello > rootfs/hello.txt
+echo '{"spdxVersion":"SPDX-2.3"}' > sbom.spdx.json
+cat > manifest.yaml <<'YAML'
+output: out
+defaults: {architecture: amd64, os: linux}
+images:
+  - artifact-type: application/vnd.example.signature
+    subject: 2
+  - layer: rootfs
+  - artifact-type: application/spdx+json
+    subject: 1
+    files:
+      - {path: sbom.spdx.json, media-type: application/spdx+json}
+YAML
+build-oci < manifest.yaml
+IMAGE=$(jq -r '.manifests[1].digest' out/index.json)
+SBOM=$(jq -r '.manifests[2].digest' out/index.json)
+SIG=$(jq -r '.manifests[0].digest' out/index.json)
+SBOM_LAYER=$(jq -r '.layers[0].digest' "out/blobs/sha256/${SBOM#sha256:}")
+if [ "$(jq -r '.subject.digest' "out/blobs/sha256/${SBOM#sha256:}")" = "$IMAGE" ] \
+    && [ "$(jq -r '.subject.digest' "out/blobs/sha256/${SIG#sha256:}")" = "$SBOM" ] \
+    && [ "$(jq -r '.artifactType + " " + .config.mediaType' "out/blobs/sha256/${SBOM#sha256:}")" = "application/spdx+json application/vnd.oci.empty.v1+json" ] \
+    && [ "$(jq -r '.layers[0].annotations["org.opencontainers.image.title"]' "out/blobs/sha256/${SBOM#sha256:}")" = "sbom.spdx.json" ] \
+    && cmp -s "out/blobs/sha256/${SBOM_LAYER#sha256:}" sbom.spdx.json \
+    && [ "$(jq -r '.manifests[2] | .artifactType + " " + (has("platform") | tostring)' out/index.json)" = "application/spdx+json false" ]; then
+    pass "Artifacts refer to their subject, hold their files and are listed without a platform"
+else
+    fail "artifacts" "subject, files or index entry wrong"
+fi
+
+set +e
+printf 'output: loop\ndefaults: {architecture: amd64, os: linux}\nimages:\n  - {artifact-type: a/b, subject: 1}\n  - {artifact-type: a/b, subject: 0}\n' | build-oci 2>/dev/null
+RC=$?
+set -e
+if [ "$RC" -eq 2 ]; then
+    pass "A subject loop is rejected as a config error"
+else
+    fail "artifacts" "subject loop: exit code $RC"
+fi
+cd /
+rm -rf "$WORKDIR"
+
+# Test 84: SBOM generation
+# --------------------------------------------------
+echo ""
+echo "Test 84: sbom: describes dpkg packages as a layer and as a referrer"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/rootfs/var/lib/dpkg" "$WORKDIR/rootfs/usr/lib"
+cd "$WORKDIR"
+printf 'Package: bash\nStatus: install ok installed\nArchitecture: amd64\nVersion: 5.2.15-2+b2\n\nPackage: old\nStatus: deinstall ok config-files\nVersion: 1\n' > rootfs/var/lib/dpkg/status
+printf 'NAME="Debian GNU/Linux"\nID=debian\nVERSION_ID="12"\n' > rootfs/usr/lib/os-release
+cat > manifest.yaml <<'YAML'
+output: out
+source-date-epoch: 0
+compression: gzip
+defaults: {architecture: amd64, os: linux}
+images:
+  - layer: rootfs
+    sbom: {files: true}
+  - subject: 0
+    sbom: {format: cyclonedx}
+YAML
+build-oci < manifest.yaml
+IMAGE=$(jq -r '.manifests[0].digest' out/index.json)
+SBOM_LAYER=$(jq -r '.layers[1].digest' "out/blobs/sha256/${IMAGE#sha256:}")
+tar -xzOf "out/blobs/sha256/${SBOM_LAYER#sha256:}" usr/share/sbom/sbom.spdx.json > spdx.json
+ARTIFACT=$(jq -r '.manifests[1].digest' out/index.json)
+CDX=$(jq -r '.layers[0].digest' "out/blobs/sha256/${ARTIFACT#sha256:}")
+if [ "$(jq -r '.layers[1].annotations["org.freedesktopsdk.layer.sbom"]' "out/blobs/sha256/${IMAGE#sha256:}")" = "application/spdx+json" ] \
+    && [ "$(jq -r '[.packages[].externalRefs[]?.referenceLocator] | join(" ")' spdx.json)" = "pkg:deb/debian/bash@5.2.15-2%2Bb2?arch=amd64&distro=debian-12" ] \
+    && [ "$(jq -r '.files[] | select(.fileName == "./var/lib/dpkg/status") | .checksums[0].checksumValue' spdx.json)" = "$(sha256sum < rootfs/var/lib/dpkg/status | cut -d' ' -f1)" ] \
+    && [ "$(jq -r '.artifactType + " " + .subject.digest' "out/blobs/sha256/${ARTIFACT#sha256:}")" = "application/vnd.cyclonedx+json $IMAGE" ] \
+    && [ "$(jq -r '[.components[] | .type + ":" + .name] | join(" ")' "out/blobs/sha256/${CDX#sha256:}")" = "operating-system:debian library:bash" ]; then
+    pass "The SBOM layer lists installed packages and file hashes, and the referrer its subject's packages"
+else
+    fail "sbom" "SBOM layer or referrer wrong"
+fi
+cd /
+rm -rf "$WORKDIR"
+
+# Test 85: verify re-hashes blobs and checks diff_ids
+# --------------------------------------------------
+echo ""
+echo "Test 85: verify checks blob digests, sizes, diff_ids and history"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/rootfs"
+cd "$WORKDIR"
+echo hello > rootfs/hello.txt
+printf 'output: out\ncompression: gzip\nimages:\n  - {architecture: amd64, os: linux, layer: rootfs}\n' | build-oci
+if build-oci verify out | grep -q "blobs OK"; then
+    pass "verify accepts a freshly built layout"
+else
+    fail "verify" "fresh layout rejected"
+fi
+
+# A config whose diff_id does not match the layer, rehashed into the layout
+cp -a out tampered
+MANIFEST=$(jq -r '.manifests[0].digest' tampered/index.json)
+CONFIG=$(jq -r '.config.digest' "tampered/blobs/sha256/${MANIFEST#sha256:}")
+jq -c '.rootfs.diff_ids[0] = "sha256:0000000000000000000000000000000000000000000000000000000000000000"' \
+    "tampered/blobs/sha256/${CONFIG#sha256:}" > config.json
+NEW_CONFIG=$(sha256sum < config.json | cut -d' ' -f1)
+cp config.json "tampered/blobs/sha256/$NEW_CONFIG"
+jq -c --arg d "sha256:$NEW_CONFIG" --argjson s "$(stat -c %s config.json)" '.config.digest = $d | .config.size = $s' \
+    "tampered/blobs/sha256/${MANIFEST#sha256:}" > manifest.json
+NEW_MANIFEST=$(sha256sum < manifest.json | cut -d' ' -f1)
+cp manifest.json "tampered/blobs/sha256/$NEW_MANIFEST"
+jq -c --arg d "sha256:$NEW_MANIFEST" --argjson s "$(stat -c %s manifest.json)" '.manifests[0].digest = $d | .manifests[0].size = $s' \
+    out/index.json > tampered/index.json
+set +e
+build-oci verify tampered 2> diff_id.err
+RC_DIFF_ID=$?
+LAYER=$(jq -r '.layers[0].digest' "out/blobs/sha256/${MANIFEST#sha256:}")
+echo garbage >> "out/blobs/sha256/${LAYER#sha256:}"
+build-oci verify out 2> corrupt.err
+RC_CORRUPT=$?
+set -e
+if [ "$RC_DIFF_ID" -eq 5 ] && grep -q "has diff_id" diff_id.err \
+    && [ "$RC_CORRUPT" -eq 5 ] && grep -q "content hashes to" corrupt.err && grep -q "gives size" corrupt.err; then
+    pass "verify reports a wrong diff_id and a corrupted layer with exit code 5"
+else
+    fail "verify" "diff_id exit $RC_DIFF_ID, corrupt layer exit $RC_CORRUPT"
+fi
+cd /
+rm -rf "$WORKDIR"
+
+# Test 86: inspect prints one image's manifest and config
+# --------------------------------------------------------
+echo ""
+echo "Test 86: inspect prints the config, layers and JSON documents of an image"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/rootfs"
+cd "$WORKDIR"
+echo hello > rootfs/hello.txt
+cat > build.yaml <<'YAML'
+output: out
+compression: gzip
+images:
+  - architecture: amd64
+    os: linux
+    name: first
+    layer: rootfs
+  - architecture: arm64
+    os: linux
+    name: second
+    layer: rootfs
+    config:
+      Entrypoint: [/bin/hello]
+      Env: [GREETING=hi]
+      Labels: {org.example.team: tools}
+YAML
+build-oci < build.yaml
+build-oci inspect out --ref second:latest > second.txt
+build-oci inspect out --index 0 --json > first.json
+set +e
+build-oci inspect out --index 5 2> missing.err
+RC_MISSING=$?
+set -e
+if grep -q '^Ref: *second:latest' second.txt && grep -q '^Platform: *linux/arm64' second.txt && grep -q '"/bin/hello"' second.txt \
+    && grep -q '^  GREETING=hi' second.txt && grep -q '^  org.example.team=tools' second.txt \
+    && grep -q 'application/vnd.oci.image.layer.v1.tar+gzip' second.txt && grep -q 'diff_id sha256:' second.txt; then
+    pass "inspect --ref prints platform, Entrypoint, Env, Labels and layers"
+else
+    fail "inspect" "unexpected output: $(cat second.txt)"
+fi
+if [ "$(jq -r '.config.architecture' first.json)" = "amd64" ] \
+    && [ "$(jq -r '.ref' first.json)" = "first:latest" ] \
+    && [ "$(jq -r '.manifest.layers | length' first.json)" = "1" ] \
+    && [ "$RC_MISSING" -ne 0 ] && grep -q "no --index 5" missing.err; then
+    pass "inspect --index --json selects by position and rejects a missing one"
+else
+    fail "inspect" "JSON output or --index handling wrong (exit $RC_MISSING)"
+fi
+cd /
+rm -rf "$WORKDIR"
+
+# Test 87: unpack applies layers with whiteouts
+# ---------------------------------------------
+echo ""
+echo "Test 87: unpack extracts the root file system an image's layers describe"
+
+WORKDIR=$(mktemp -d)
+cd "$WORKDIR"
+mkdir -p base/etc base/data base/bin base/ro
+echo keep > base/etc/keep
+echo gone > base/etc/gone
+echo old > base/data/old
+echo tool > base/bin/a
+ln base/bin/a base/bin/b
+echo locked > base/ro/file
+chmod 0750 base/bin/a
+chmod 0555 base/ro
+python3 -c "import os; os.setxattr('base/etc/keep', 'user.test', b'value')" 2>/dev/null || true
+printf 'output: base\ncompression: gzip\nimages: [{architecture: amd64, os: linux, layer: base}]\n' | build-oci
+cp -a base child
+rm child/etc/gone
+echo new > child/etc/new
+printf 'output: out\ncompression: gzip\nimages: [{architecture: amd64, os: linux, layer: child, parent: {image: base}}]\n' | build-oci
+build-oci unpack out unpacked > unpack.out
+if diff -r child unpacked >/dev/null && [ ! -e unpacked/etc/gone ] \
+    && [ "$(stat -c %i unpacked/bin/a)" = "$(stat -c %i unpacked/bin/b)" ] \
+    && [ "$(stat -c %a unpacked/bin/a)" = "750" ] && [ "$(stat -c %a unpacked/ro)" = "555" ] \
+    && [ "$(stat -c %Y unpacked/etc/keep)" = "$(stat -c %Y child/etc/keep)" ] \
+    && grep -q "2 layers unpacked" unpack.out; then
+    pass "unpack applies whiteouts and restores hard links, modes and mtimes"
+else
+    fail "unpack" "unpacked tree differs from the layer directory"
+fi
+if python3 -c "import os; os.getxattr('base/etc/keep', 'user.test')" 2>/dev/null; then
+    if python3 -c "import os,sys; sys.exit(os.getxattr('unpacked/etc/keep', 'user.test') != b'value')" 2>/dev/null; then
+        pass "unpack restores xattrs"
+    else
+        fail "unpack" "user.test xattr not restored"
+    fi
+else
+    warn "unpack" "user xattrs unsupported here, xattr restore not checked"
+fi
+
+# An opaque whiteout hides everything lower layers put in its directory
+python3 - <<'PY'
+import io, tarfile
+with tarfile.open("opaque.tar", "w", format=tarfile.PAX_FORMAT) as tar:
+    for name, data in [("data/", None), ("data/.wh..wh..opq", b""), ("data/fresh", b"fresh\n")]:
+        info = tarfile.TarInfo(name.rstrip("/"))
+        if data is None:
+            info.type, info.mode = tarfile.DIRTYPE, 0o755
+            tar.addfile(info)
+        else:
+            info.size = len(data)
+            tar.addfile(info, io.BytesIO(data))
+PY
+printf 'output: opaque\ncompression: gzip\nimages: [{architecture: amd64, os: linux, layer-tar: opaque.tar, parent: {image: out}}]\n' | build-oci
+set +e
+build-oci unpack opaque unpacked 2> notempty.err
+RC_NOTEMPTY=$?
+set -e
+build-oci unpack opaque opaque-root > /dev/null
+if [ "$(ls opaque-root/data)" = "fresh" ] && [ -f opaque-root/etc/new ] \
+    && [ "$RC_NOTEMPTY" -ne 0 ] && grep -q "is not empty" notempty.err; then
+    pass "unpack honours opaque whiteouts and refuses a non-empty destination"
+else
+    fail "unpack" "opaque data/: $(ls opaque-root/data 2>&1 | tr '\n' ' '), non-empty dest exit $RC_NOTEMPTY"
+fi
+
+# Whiteouts and replaced entries under a symlink a lower layer made must not
+# reach what it points to
+mkdir -p outside
+echo victim > outside/victim
+echo other > outside/x
+python3 - "$WORKDIR/outside" <<'PY'
+import io, sys, tarfile
+def layer(path, entries):
+    with tarfile.open(path, "w", format=tarfile.PAX_FORMAT) as tar:
+        for name, data in entries:
+            info = tarfile.TarInfo(name)
+            if isinstance(data, str):
+                info.type, info.linkname = tarfile.SYMTYPE, data
+                tar.addfile(info)
+            else:
+                info.size = len(data)
+                tar.addfile(info, io.BytesIO(data))
+layer("link.tar", [("a", sys.argv[1])])
+layer("whiteout.tar", [("a/.wh.victim", b"")])
+layer("replace.tar", [("a/x", b"replaced\n")])
+PY
+printf 'output: link\ncompression: gzip\nimages: [{architecture: amd64, os: linux, layer-tar: link.tar}]\n' | build-oci
+for kind in whiteout replace; do
+    printf 'output: %s\ncompression: gzip\nimages: [{architecture: amd64, os: linux, layer-tar: %s.tar, parent: {image: link}}]\n' "$kind" "$kind" \
+        | build-oci
+done
+set +e
+build-oci unpack whiteout whiteout-root 2> whiteout.err
+RC_WHITEOUT=$?
+build-oci unpack replace replace-root 2> replace.err
+RC_REPLACE=$?
+set -e
+if [ "$RC_WHITEOUT" -ne 0 ] && [ "$RC_REPLACE" -ne 0 ] && grep -q "is a symlink" whiteout.err \
+    && [ "$(cat outside/victim)" = "victim" ] && [ "$(cat outside/x)" = "other" ]; then
+    pass "unpack refuses entries under a symlinked directory"
+else
+    fail "unpack" "symlinked parent: exit $RC_WHITEOUT/$RC_REPLACE, outside: $(ls outside | tr '\n' ' ')"
+fi
+
+# Layer blobs are checked against their digests before anything is written
+cp -a out corrupt
+LAYER=$(jq -r '.layers[-1].digest' "corrupt/blobs/sha256/$(jq -r '.manifests[0].digest' corrupt/index.json | cut -d: -f2)")
+BLOB="corrupt/blobs/sha256/${LAYER#sha256:}"
+chmod u+w "$BLOB"
+printf 'X' | dd of="$BLOB" bs=1 seek=100 conv=notrunc 2>/dev/null
+cp -a out escape
+jq '.manifests[0].digest = "sha256:../../../outside/victim"' out/index.json > escape/index.json
+set +e
+build-oci unpack corrupt corrupt-root 2> corrupt.err
+RC_CORRUPT=$?
+build-oci unpack escape escape-root 2> escape.err
+RC_ESCAPE=$?
+set -e
+if [ "$RC_CORRUPT" -ne 0 ] && grep -q "does not match its digest" corrupt.err && [ ! -e corrupt-root ] \
+    && [ "$RC_ESCAPE" -ne 0 ] && grep -q "Invalid digest" escape.err; then
+    pass "unpack refuses corrupt layers and digests that are not digests"
+else
+    fail "unpack" "corrupt layer exit $RC_CORRUPT, escaping digest exit $RC_ESCAPE: $(cat corrupt.err escape.err)"
+fi
+chmod -R u+w "$WORKDIR"
+cd /
+rm -rf "$WORKDIR"
+
+# Test 88: non-distributable layers with urls
+# -------------------------------------------
+echo ""
+echo "Test 88: layers entries with nondistributable: and urls: get foreign descriptors"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/base" "$WORKDIR/vendor"
+cd "$WORKDIR"
+echo base > base/base.txt
+cp -a base/. vendor/
+echo blob > vendor/driver.bin
+cat > build.yaml <<'YAML'
+output: out
+compression: gzip
+images:
+  - architecture: amd64
+    os: linux
+    layers:
+      - base
+      - dir: vendor
+        nondistributable: true
+        urls: [https://vendor.example.com/driver.tar.gz]
+YAML
+build-oci < build.yaml
+MANIFEST=$(jq -r '.manifests[0].digest' out/index.json)
+LAYERS="out/blobs/sha256/${MANIFEST#sha256:}"
+if [ "$(jq -r '.layers[0].mediaType' "$LAYERS")" = "application/vnd.oci.image.layer.v1.tar+gzip" ] \
+    && jq -e '.layers[0] | has("urls") | not' "$LAYERS" >/dev/null \
+    && [ "$(jq -r '.layers[1].mediaType' "$LAYERS")" = "application/vnd.oci.image.layer.nondistributable.v1.tar+gzip" ] \
+    && [ "$(jq -c '.layers[1].urls' "$LAYERS")" = '["https://vendor.example.com/driver.tar.gz"]' ] \
+    && build-oci verify out >/dev/null; then
+    pass "The vendor layer has the non-distributable media type and urls"
+else
+    fail "nondistributable" "layers: $(jq -c '.layers' "$LAYERS")"
+fi
+sed -i 's|https://vendor.example.com|ftp://vendor.example.com|' build.yaml
+set +e
+build-oci --dry-run < build.yaml >/dev/null 2> bad.err
+RC=$?
+set -e
+if [ "$RC" -eq 2 ] && grep -q "layers\[1\].urls: must be http or https" bad.err; then
+    pass "urls other than http or https are rejected"
+else
+    fail "nondistributable" "ftp url: exit $RC"
+fi
+cd /
+rm -rf "$WORKDIR"
+
+# Test 89: index.json order and image names in errors
+# ---------------------------------------------------
+echo ""
+echo "Test 89: index.json follows images: order and errors name the image"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/big" "$WORKDIR/small"
+cd "$WORKDIR"
+head -c 20000000 /dev/urandom > big/random.bin
+echo small > small/file.txt
+cat > build.yaml <<'YAML'
+output: out
+compression: gzip
+images:
+  - {architecture: amd64, os: linux, layer: big}
+  - {architecture: arm64, os: linux, layer: small}
+  - {architecture: ppc64le, os: linux, layer: small}
+  - {architecture: s390x, os: linux, layer: small}
+YAML
+build-oci --image-parallelism 4 < build.yaml
+ORDER=$(jq -r '.manifests[].platform.architecture' out/index.json | tr '\n' ' ')
+if [ "$ORDER" = "amd64 arm64 ppc64le s390x " ]; then
+    pass "index.json lists images in manifest order whichever finished first"
+else
+    fail "index order" "got $ORDER"
+fi
+
+set +e
+build-oci --keep-going --error-json report.json > err.txt 2>&1 <<'YAML'
+output: failing
+compression: gzip
+images:
+  - {architecture: amd64, os: linux, layer: small}
+  - {architecture: arm64, os: linux, name: broken, tag: v1, parent: {image: missing}}
+YAML
+RC=$?
+set -e
+if [ "$RC" -ne 0 ] && grep -q "images\[1\] (broken:v1, linux/arm64)" err.txt \
+    && [ "$(jq -r '.images[0].name' report.json)" = "broken:v1" ]; then
+    pass "A failed image is named by position, name:tag and platform"
+else
+    fail "image names" "exit $RC: $(head -5 err.txt)"
+fi
+cd /
+rm -rf "$WORKDIR"
+
+# Test 90: labels: convenience key
+# --------------------------------
+echo ""
+echo "Test 90: labels: merges into config.Labels and rejects conflicts"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/rootfs"
+cd "$WORKDIR"
+echo hi > rootfs/hi.txt
+cat > build.yaml <<'YAML'
+output: out
+compression: gzip
+defaults:
+  labels: {org.example.team: tools}
+images:
+  - {architecture: amd64, os: linux, layer: rootfs, labels: {version: "1.0"}}
+  - architecture: arm64
+    os: linux
+    layer: rootfs
+    labels: {version: "1.0"}
+    config: {Cmd: [/bin/sh], Labels: {version: "1.0", keep: "yes"}}
+YAML
+build-oci < build.yaml
+labels_of() {
+    local manifest config
+    manifest=$(jq -r ".manifests[$1].digest" out/index.json)
+    config=$(jq -r '.config.digest' "out/blobs/sha256/${manifest#sha256:}")
+    jq -c '.config.Labels' "out/blobs/sha256/${config#sha256:}"
+}
+if [ "$(labels_of 0)" = '{"org.example.team":"tools","version":"1.0"}' ] \
+    && [ "$(labels_of 1)" = '{"keep":"yes","org.example.team":"tools","version":"1.0"}' ]; then
+    pass "labels: creates or extends config.Labels"
+else
+    fail "labels" "got $(labels_of 0) and $(labels_of 1)"
+fi
+set +e
+printf 'images: [{architecture: amd64, os: linux, layer: rootfs, labels: {version: "2"}, config: {Labels: {version: "1"}}}]\n' \
+    | build-oci --dry-run > /dev/null 2> conflict.err
+RC=$?
+set -e
+if [ "$RC" -eq 2 ] && grep -q "images\[0\].labels.version: '2' conflicts with config.Labels" conflict.err; then
+    pass "A label conflicting with config.Labels is rejected"
+else
+    fail "labels" "conflict: exit $RC"
+fi
+cd /
+rm -rf "$WORKDIR"
+
+# Test 91: created: pins the config timestamp
+# -------------------------------------------
+echo ""
+echo "Test 91: created: sets the config and history time, not layer mtimes"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/rootfs"
+cd "$WORKDIR"
+echo hi > rootfs/hi.txt
+printf 'output: out\ncompression: gzip\nsource-date-epoch: 1700000000\nimages:\n  - {architecture: amd64, os: linux, layer: rootfs, created: "2024-05-01T14:00:00+02:00"}\n' | build-oci
+MANIFEST=$(jq -r '.manifests[0].digest' out/index.json)
+CONFIG=$(jq -r '.config.digest' "out/blobs/sha256/${MANIFEST#sha256:}")
+LAYER=$(jq -r '.layers[0].digest' "out/blobs/sha256/${MANIFEST#sha256:}")
+CREATED=$(jq -r '[.created, .history[0].created] | join(" ")' "out/blobs/sha256/${CONFIG#sha256:}")
+MTIME=$(TZ=UTC tar -tvzf "out/blobs/sha256/${LAYER#sha256:}" --full-time 2>/dev/null | grep 'hi.txt' | awk '{print $4 "T" $5}')
+if [ "$CREATED" = "2024-05-01T12:00:00Z 2024-05-01T12:00:00Z" ] && [ "$MTIME" = "2023-11-14T22:13:20" ]; then
+    pass "created: is used for config and history while mtimes keep the epoch"
+else
+    fail "created" "created $CREATED, layer mtime $MTIME"
+fi
+set +e
+printf 'images: [{architecture: amd64, os: linux, layer: rootfs, created: "May 1st"}]\n' | build-oci --dry-run >/dev/null 2> bad.err
+RC=$?
+set -e
+if [ "$RC" -eq 2 ] && grep -q "images\[0\].created: expected an RFC 3339 time" bad.err; then
+    pass "A created: time that is not RFC 3339 is rejected"
+else
+    fail "created" "invalid time: exit $RC"
+fi
+cd /
+rm -rf "$WORKDIR"
+
+# Test 92: build-report: true writes build-report.json
+# -----------------------------------------------------
+echo ""
+echo "Test 92: build-report.json lists digests, layer sizes, dedup counts and timings"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/base" "$WORKDIR/rootfs"
+cd "$WORKDIR"
+echo keep > base/keep.txt
+echo gone > base/gone.txt
+echo keep > rootfs/keep.txt
+echo new > rootfs/new.txt
+printf 'output: parent\ncompression: gzip\nimages: [{architecture: amd64, os: linux, layer: base}]\n' | SOURCE_DATE_EPOCH=1700000000 build-oci
+printf 'output: out\ncompression: gzip\nbuild-report: true\nimages:\n  - {architecture: amd64, os: linux, name: app, tag: "1", layer: rootfs, parent: {image: parent}}\n' | SOURCE_DATE_EPOCH=1700000000 build-oci
+REPORT=out/build-report.json
+if [ -f "$REPORT" ] && [ ! -f parent/build-report.json ]; then
+    pass "build-report.json is written only when build-report: true"
+else
+    fail "build-report" "report missing or written without the key"
+fi
+NESTED=$(jq -r '.manifests[0].digest' out/index.json)
+MANIFEST=$(jq -r '.manifests[0].digest' "out/blobs/sha256/${NESTED#sha256:}")
+CHILD=$(jq -r '.layers[-1].digest' "out/blobs/sha256/${MANIFEST#sha256:}")
+R_MANIFEST=$(jq -r '.images[0].manifest' "$REPORT")
+R_NAME=$(jq -r '.images[0].name' "$REPORT")
+LAYERS=$(jq -r '.images[0].layers | map(.built) | map(tostring) | join(",")' "$REPORT")
+DEDUP=$(jq -r --arg d "$CHILD" '.images[0].layers[] | select(.digest == $d) | "\(.dedup.added) \(.dedup.skipped) \(.dedup.whiteouts) \(.uncompressedSize > 0)"' "$REPORT")
+STAGES=$(jq -r '.images[0].stages | has("parent") and has("layers") and has("manifest")' "$REPORT")
+if [ "$R_MANIFEST" = "$MANIFEST" ] && [ "$R_NAME" = "app:1" ] && [ "$LAYERS" = "false,true" ] \
+    && [ "$DEDUP" = "1 1 1 true" ] && [ "$STAGES" = "true" ]; then
+    pass "The report matches index.json and counts added, skipped and whited-out files"
+else
+    fail "build-report" "manifest $R_MANIFEST vs $MANIFEST, name $R_NAME, built $LAYERS, dedup '$DEDUP', stages $STAGES"
+fi
+cd /
+rm -rf "$WORKDIR"
+
+# Test 93: platform fields
+# ------------------------
+echo ""
+echo "Test 93: os.version is checked for windows and platform-fields pass through"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/rootfs"
+cd "$WORKDIR"
+echo hi > rootfs/hi.txt
+cat > win.yaml <<'YAML'
+output: out
+compression: gzip
+images:
+  - architecture: amd64
+    os: windows
+    os.version: "10.0.20348.2113"
+    os.features: [win32k]
+    features: [sse4]
+    platform-fields: {org.example.gpu: nvidia}
+    layer: rootfs
+YAML
+build-oci < win.yaml 2> build.err
+PLATFORM=$(jq -c '.manifests[0].platform' out/index.json)
+if [ "$PLATFORM" = '{"architecture":"amd64","features":["sse4"],"org.example.gpu":"nvidia","os":"windows","os.features":["win32k"],"os.version":"10.0.20348.2113"}' ]; then
+    pass "The index entry carries os.version, os.features and passed-through fields"
+else
+    fail "platform fields" "platform $PLATFORM"
+fi
+if grep -q "images\[0\].features: reserved by the OCI image spec" build.err; then
+    pass "The deprecated features field is warned about"
+else
+    fail "platform fields" "no warning for features: $(cat build.err)"
+fi
+set +e
+sed 's/10.0.20348.2113/ltsc2022/' win.yaml | build-oci --dry-run >/dev/null 2> bad.err
+RC_VERSION=$?
+sed 's/org.example.gpu: nvidia/variant: v2/' win.yaml | build-oci --dry-run >/dev/null 2> known.err
+RC_KNOWN=$?
+set -e
+if [ "$RC_VERSION" -eq 2 ] && grep -q "images\[0\].os.version: expected a Windows version" bad.err \
+    && [ "$RC_KNOWN" -eq 2 ] && grep -q "images\[0\].platform-fields.variant: set images\[0\].variant instead" known.err; then
+    pass "Malformed Windows versions and known fields in platform-fields are rejected"
+else
+    fail "platform fields" "os.version exit $RC_VERSION, platform-fields exit $RC_KNOWN"
+fi
+cd /
+rm -rf "$WORKDIR"
+
+# Test 94: parent.build chains images of one manifest
+# ----------------------------------------------------
+echo ""
+echo "Test 94: parent.build builds on an earlier image of the same manifest"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/base" "$WORKDIR/sdk" "$WORKDIR/app"
+cd "$WORKDIR"
+echo base > base/base.txt
+echo sdk > sdk/sdk.txt
+echo base > sdk/base.txt
+echo app > app/app.txt
+cat > chain.yaml <<'YAML'
+output: out
+compression: gzip
+images:
+  - {architecture: amd64, os: linux, name: base, layer: base}
+  - {architecture: amd64, os: linux, name: sdk, tag: "1", layer: sdk, parent: {build: base}}
+  - {architecture: amd64, os: linux, layer: app, parent: {build: "sdk:1"}}
+YAML
+SOURCE_DATE_EPOCH=1700000000 build-oci < chain.yaml
+APP=$(jq -r '.manifests[2].digest' out/index.json)
+APP_MANIFEST="out/blobs/sha256/${APP#sha256:}"
+LAYERS=$(jq '.layers | length' "$APP_MANIFEST")
+CONFIG=$(jq -r '.config.digest' "$APP_MANIFEST")
+HISTORY=$(jq '.history | length' "out/blobs/sha256/${CONFIG#sha256:}")
+SDK_LAYER=$(jq -r '.layers[1].digest' "$APP_MANIFEST")
+SDK_FILES=$(tar -tzf "out/blobs/sha256/${SDK_LAYER#sha256:}" 2>/dev/null | grep -v '/$' | tr '\n' ' ')
+if [ "$LAYERS" = "3" ] && [ "$HISTORY" = "3" ] && [ "$SDK_FILES" = "sdk.txt " ]; then
+    pass "app is built on sdk, built on base, with the sdk layer deduplicated against base"
+else
+    fail "parent.build" "layers $LAYERS, history $HISTORY, sdk layer files '$SDK_FILES'"
+fi
+set +e
+sed 's/build: base}/build: missing}/' chain.yaml | build-oci --dry-run >/dev/null 2> missing.err
+RC_MISSING=$?
+printf 'images:\n  - {architecture: amd64, os: linux, layer: app, parent: {build: base, image: out}}\n' | build-oci --dry-run >/dev/null 2> both.err
+RC_BOTH=$?
+set -e
+if [ "$RC_MISSING" -eq 2 ] && grep -q "images\[1\].parent.build" missing.err && grep -q "no earlier image is named 'missing' for linux/amd64" missing.err \
+    && [ "$RC_BOTH" -eq 2 ] && grep -q "images\[0\].parent: needs exactly one of image and build" both.err; then
+    pass "Unknown names and parents with both image and build are rejected"
+else
+    fail "parent.build" "missing: exit $RC_MISSING, both: exit $RC_BOTH"
+fi
+cd /
+rm -rf "$WORKDIR"
+
+# Test 95: compression-options for zstd
+# --------------------------------------
+echo ""
+echo "Test 95: compression-options sets zstd long mode, window and strategy"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/rootfs"
+cd "$WORKDIR"
+head -c 2000000 /dev/urandom > rootfs/a.bin
+cp rootfs/a.bin rootfs/b.bin
+printf 'output: plain\ncompression: zstd\nimages: [{architecture: amd64, os: linux, layer: rootfs}]\n' | SOURCE_DATE_EPOCH=1700000000 build-oci
+printf 'output: long\ncompression: zstd\ncompression-options: {long: true, window-log: 24, strategy: btopt}\nimages: [{architecture: amd64, os: linux, layer: rootfs}]\n' | SOURCE_DATE_EPOCH=1700000000 build-oci
+layer_of() {
+    local manifest
+    manifest=$(jq -r '.manifests[0].digest' "$1/index.json")
+    jq -r '.layers[0] | "\(.digest) \(.size)"' "$1/blobs/sha256/${manifest#sha256:}"
+}
+read -r PLAIN_DIGEST PLAIN_SIZE <<< "$(layer_of plain)"
+read -r LONG_DIGEST LONG_SIZE <<< "$(layer_of long)"
+if [ "$PLAIN_DIGEST" != "$LONG_DIGEST" ] && [ "$LONG_SIZE" -lt $((PLAIN_SIZE * 3 / 4)) ] \
+    && zstd -dc "long/blobs/sha256/${LONG_DIGEST#sha256:}" 2>/dev/null | tar -tf - 2>/dev/null | grep -q b.bin; then
+    pass "Long-distance matching shrinks a layer with distant repeats ($PLAIN_SIZE -> $LONG_SIZE bytes)"
+else
+    fail "compression-options" "plain $PLAIN_SIZE bytes, long $LONG_SIZE bytes"
+fi
+set +e
+printf 'compression-options: {window-log: 40}\nimages: [{architecture: amd64, os: linux, layer: rootfs}]\n' | build-oci --dry-run >/dev/null 2> window.err
+RC_WINDOW=$?
+printf 'compression-options: {strategy: slow}\nimages: [{architecture: amd64, os: linux, layer: rootfs}]\n' | build-oci --dry-run >/dev/null 2> strategy.err
+RC_STRATEGY=$?
+set -e
+if [ "$RC_WINDOW" -eq 2 ] && grep -q "window-log must be between 10 and 31, got: 40" window.err \
+    && [ "$RC_STRATEGY" -eq 2 ] && grep -q "strategy must be one of fast, dfast" strategy.err; then
+    pass "Out of range window logs and unknown strategies are rejected"
+else
+    fail "compression-options" "window-log: exit $RC_WINDOW, strategy: exit $RC_STRATEGY"
+fi
+cd /
+rm -rf "$WORKDIR"
+
+# Test 96: gzip-rsyncable
+# -----------------------
+echo ""
+echo "Test 96: gzip-rsyncable layers resynchronise after an insertion"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/rootfs"
+cd "$WORKDIR"
+echo small > rootfs/a.txt
+seq 1 400000 | sed 's/$/ some text to compress/' > rootfs/data.txt
+build_layer() {
+    printf 'output: %s\ncompression: gzip\n%bimages: [{architecture: amd64, os: linux, layer: rootfs}]\n' "$1" "$2" \
+        | SOURCE_DATE_EPOCH=1700000000 build-oci >/dev/null
+    local manifest
+    manifest=$(jq -r '.manifests[0].digest' "$1/index.json")
+    local layer
+    layer=$(jq -r '.layers[0].digest' "$1/blobs/sha256/${manifest#sha256:}")
+    echo "$1/blobs/sha256/${layer#sha256:}"
+}
+OLD_RSYNC=$(build_layer rsync-old 'gzip-rsyncable: true\n')
+OLD_PLAIN=$(build_layer plain-old '')
+head -c 2000 /dev/zero | tr '\0' 'x' >> rootfs/a.txt
+NEW_RSYNC=$(build_layer rsync-new 'gzip-rsyncable: true\n')
+NEW_PLAIN=$(build_layer plain-new '')
+common_suffix() {
+    python3 - "$1" "$2" <<'PYEOF'
+import sys
+# Without the CRC and size trailer, which always differ
+a, b = (open(p, 'rb').read()[:-8] for p in sys.argv[1:3])
+n = 0
+while n < min(len(a), len(b)) and a[-1 - n] == b[-1 - n]:
+    n += 1
+print(n * 100 // len(b))
+PYEOF
+}
+RSYNC_SHARED=$(common_suffix "$OLD_RSYNC" "$NEW_RSYNC")
+PLAIN_SHARED=$(common_suffix "$OLD_PLAIN" "$NEW_PLAIN")
+CONFIG=$(jq -r '.config.digest' "rsync-new/blobs/sha256/$(jq -r '.manifests[0].digest' rsync-new/index.json | cut -d: -f2)")
+DIFF_ID=$(jq -r '.rootfs.diff_ids[0]' "rsync-new/blobs/sha256/${CONFIG#sha256:}")
+if gzip -t "$NEW_RSYNC" && [ "sha256:$(gzip -dc "$NEW_RSYNC" | sha256sum | cut -d' ' -f1)" = "$DIFF_ID" ]; then
+    pass "An rsyncable layer is one valid gzip stream of the layer tar"
+else
+    fail "gzip-rsyncable" "layer does not decompress to its diff_id"
+fi
+if [ "$RSYNC_SHARED" -ge 90 ] && [ "$PLAIN_SHARED" -lt 50 ]; then
+    pass "After an insertion, ${RSYNC_SHARED}% of the rsyncable layer is unchanged (${PLAIN_SHARED}% without)"
+else
+    fail "gzip-rsyncable" "unchanged suffix: rsyncable ${RSYNC_SHARED}%, plain ${PLAIN_SHARED}%"
+fi
+cd /
+rm -rf "$WORKDIR"
+
+# Test 97: verifying parent diff_ids
+# --------------------------------------------------
+echo ""
+echo "Test 97: verify-parents: diff-ids rejects a parent whose config lies about its diff_ids"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/root" "$WORKDIR/child"
+cd "$WORKDIR"
+export XDG_CACHE_HOME="$WORKDIR/cache"
+echo base > root/base.txt
+echo child > child/child.txt
+printf 'output: parent\ncompression: gzip\nimages: [{architecture: amd64, os: linux, layer: root}]\n' | build-oci >/dev/null
+MANIFEST_YAML='compression: gzip\nimages: [{architecture: amd64, os: linux, parent: {image: parent}, layer: child}]\n'
+set +e
+printf "output: out\nverify-parents: diff-ids\n$MANIFEST_YAML" | build-oci >/dev/null 2>&1
+RC_GOOD=$?
+set -e
+# Rewrite the config with a wrong diff_id, keeping every blob digest consistent
+BLOBS=parent/blobs/sha256
+MANIFEST=$(jq -r '.manifests[0].digest' parent/index.json | cut -d: -f2)
+CONFIG=$(jq -r '.config.digest' "$BLOBS/$MANIFEST" | cut -d: -f2)
+jq -c ".rootfs.diff_ids[0] = \"sha256:$(printf 'x' | sha256sum | cut -d' ' -f1)\"" "$BLOBS/$CONFIG" > config.json
+NEW_CONFIG=$(sha256sum config.json | cut -d' ' -f1)
+mv config.json "$BLOBS/$NEW_CONFIG"
+jq -c ".config.digest = \"sha256:$NEW_CONFIG\" | .config.size = $(stat -c %s "$BLOBS/$NEW_CONFIG")" \
+    "$BLOBS/$MANIFEST" > manifest.json
+NEW_MANIFEST=$(sha256sum manifest.json | cut -d' ' -f1)
+mv manifest.json "$BLOBS/$NEW_MANIFEST"
+jq ".manifests[0].digest = \"sha256:$NEW_MANIFEST\" | .manifests[0].size = $(stat -c %s "$BLOBS/$NEW_MANIFEST")" \
+    parent/index.json > index.json
+mv index.json parent/index.json
+set +e
+printf "output: out2\nverify-parents: always\n$MANIFEST_YAML" | build-oci >/dev/null 2>&1
+RC_DIGESTS=$?
+printf "output: out3\nverify-parents: diff-ids\n$MANIFEST_YAML" | build-oci > diff-ids.log 2>&1
+RC=$?
+set -e
+if [ "$RC_GOOD" -eq 0 ] && [ "$RC_DIGESTS" -eq 0 ] && [ "$RC" -eq 5 ] \
+    && grep -q "does not match its diff_id" diff-ids.log; then
+    pass "A wrong diff_id passes digest checks but fails diff-ids with exit code 5"
+else
+    fail "verify-parents diff-ids" "exit codes good $RC_GOOD, digests $RC_DIGESTS, diff-ids $RC"
+fi
+set +e
+printf "output: out4\nverify-parents: sometimes\n$MANIFEST_YAML" | build-oci >/dev/null 2>&1
+RC_BAD=$?
+set -e
+if [ "$RC_BAD" -eq 2 ]; then
+    pass "An unknown verify-parents value is a config error"
+else
+    fail "verify-parents diff-ids" "unknown value exit code $RC_BAD"
+fi
+unset XDG_CACHE_HOME
+cd /
+rm -rf "$WORKDIR"
+
+# Test 98: building layers while the parent is recompressed
+# --------------------------------------------------
+echo ""
+echo "Test 98: layers built alongside parent recompression dedup against the parent"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/root" "$WORKDIR/child"
+cd "$WORKDIR"
+export XDG_CACHE_HOME="$WORKDIR/cache"
+echo kept > root/keep.txt
+echo removed > root/gone.txt
+head -c 2000000 /dev/urandom > root/big.bin
+cp -a root/keep.txt root/big.bin child/
+echo added > child/new.txt
+printf 'output: parent\ncompression: zstd\nimages: [{architecture: amd64, os: linux, layer: root}]\n' | SOURCE_DATE_EPOCH=1700000000 build-oci >/dev/null
+MANIFEST_YAML='compression: gzip\nimages: [{architecture: amd64, os: linux, parent: {image: parent}, layer: child}]\n'
+printf "output: out\n$MANIFEST_YAML" | SOURCE_DATE_EPOCH=1700000000 build-oci >/dev/null
+printf "output: out2\n$MANIFEST_YAML" | SOURCE_DATE_EPOCH=1700000000 build-oci >/dev/null
+MANIFEST=$(jq -r '.manifests[0].digest' out/index.json | cut -d: -f2)
+PARENT_TYPE=$(jq -r '.layers[0].mediaType' "out/blobs/sha256/$MANIFEST")
+LAST=$(jq -r '.layers[-1].digest' "out/blobs/sha256/$MANIFEST" | cut -d: -f2)
+LISTING=$(tar tzf "out/blobs/sha256/$LAST" 2>/dev/null | sort | tr '\n' ' ')
+if [ "$PARENT_TYPE" = "application/vnd.oci.image.layer.v1.tar+gzip" ] \
+    && [ "$LISTING" = "./ .wh.gone.txt new.txt " ] \
+    && [ "$(jq -r '.manifests[0].digest' out/index.json)" = "$(jq -r '.manifests[0].digest' out2/index.json)" ]; then
+    pass "The parent is recompressed, the new layer only adds and whites out, and rebuilds match"
+else
+    fail "concurrent parent" "parent $PARENT_TYPE, layer: $LISTING"
+fi
+LAYER=$(jq -r '.manifests[0].digest' parent/index.json | cut -d: -f2)
+LAYER=$(jq -r '.layers[0].digest' "parent/blobs/sha256/$LAYER" | cut -d: -f2)
+printf 'x' | dd of="parent/blobs/sha256/$LAYER" bs=1 seek=200 conv=notrunc 2>/dev/null
+set +e
+printf "output: out3\nverify-parents: always\n$MANIFEST_YAML" | build-oci >/dev/null 2>&1
+RC=$?
+set -e
+if [ "$RC" -eq 5 ]; then
+    pass "A corrupt parent still fails with exit code 5 while layers are built"
+else
+    fail "concurrent parent" "corrupt parent exit code $RC"
+fi
+unset XDG_CACHE_HOME
+cd /
+rm -rf "$WORKDIR"
+
+# Test 99: persistent layer cache
+# --------------------------------------------------
+echo ""
+echo "Test 99: cache-dir reuses layers built from the same inputs across runs"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/root/etc" "$WORKDIR/app"
+cd "$WORKDIR"
+echo base > root/etc/base.txt
+head -c 300000 /dev/urandom > app/data.bin
+echo one > app/file.txt
+ln app/file.txt app/link.txt
+layer_cache_stat() {
+    jq -s -r --arg field "$2" \
+        '.[] | select(.fields.message == "cache statistics" and .fields.cache == "layerCache") | .fields[$field]' "$1"
+}
+MANIFEST_YAML='cache-dir: cache\ncompression: zstd\nimages: [{architecture: amd64, os: linux, layers: [root, app]}]\n'
+printf "output: out1\n$MANIFEST_YAML" | SOURCE_DATE_EPOCH=1700000000 build-oci -v --log-format json 2> first.log
+printf "output: out2\n$MANIFEST_YAML" | SOURCE_DATE_EPOCH=1700000000 build-oci -v --log-format json 2> second.log
+if [ "$(layer_cache_stat first.log misses)" -eq 2 ] && [ "$(layer_cache_stat second.log hits)" -eq 2 ] \
+    && [ "$(jq -r '.manifests[0].digest' out1/index.json)" = "$(jq -r '.manifests[0].digest' out2/index.json)" ] \
+    && [ "$(ls cache/layers | wc -l)" -eq 2 ]; then
+    pass "A second build into a fresh output reuses both layers and writes the same image"
+else
+    fail "cache-dir" "misses $(layer_cache_stat first.log misses), hits $(layer_cache_stat second.log hits)"
+fi
+# Same size and mtime, other contents: only the upper layer is rebuilt
+touch -r app/file.txt stamp
+echo two > app/file.txt
+touch -r stamp app/file.txt
+printf "output: out3\n$MANIFEST_YAML" | SOURCE_DATE_EPOCH=1700000000 build-oci -v --log-format json 2> third.log
+LAYER=$(jq -r '.manifests[0].digest' out3/index.json | cut -d: -f2)
+LAYER=$(jq -r '.layers[1].digest' "out3/blobs/sha256/$LAYER" | cut -d: -f2)
+if [ "$(layer_cache_stat third.log hits)" -eq 1 ] && [ "$(layer_cache_stat third.log misses)" -eq 1 ] \
+    && [ "$(tar --zstd -xOf "out3/blobs/sha256/$LAYER" file.txt 2>/dev/null)" = "two" ]; then
+    pass "Changed file contents miss the cache even with the same size and mtime"
+else
+    fail "cache-dir" "edited file: hits $(layer_cache_stat third.log hits), misses $(layer_cache_stat third.log misses)"
+fi
+# Another compression level shapes the blob, so nothing is reused
+printf "output: out4\ncompression-level: 9\n$MANIFEST_YAML" | SOURCE_DATE_EPOCH=1700000000 build-oci -v --log-format json 2> fourth.log
+if [ "$(layer_cache_stat fourth.log misses)" -eq 2 ] && [ "$(layer_cache_stat fourth.log hits)" -eq 0 ]; then
+    pass "Other compression settings miss the cache"
+else
+    fail "cache-dir" "compression-level: hits $(layer_cache_stat fourth.log hits)"
+fi
+# A fresh checkout only changes mtimes, which the epoch replaces
+find root app -exec touch -h -d @1800000000 {} +
+printf "output: out5\n$MANIFEST_YAML" | SOURCE_DATE_EPOCH=1700000000 build-oci -v --log-format json 2> fifth.log
+if [ "$(layer_cache_stat fifth.log hits)" -eq 2 ] && [ "$(layer_cache_stat fifth.log misses)" -eq 0 ]; then
+    pass "New mtimes still hit the cache when source-date-epoch is set"
+else
+    fail "cache-dir" "touched tree: hits $(layer_cache_stat fifth.log hits), misses $(layer_cache_stat fifth.log misses)"
+fi
+cd /
+rm -rf "$WORKDIR"
+
+# Test 100: remote layer cache
+# --------------------------------------------------
+echo ""
+echo "Test 100: remote-cache shares layers between cache directories over HTTP"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/root" "$WORKDIR/app" "$WORKDIR/store"
+cd "$WORKDIR"
+echo base > root/base.txt
+head -c 200000 /dev/urandom > app/data.bin
+cat > cache_server.py <<'PYEOF'
+import http.server, os, sys
+store, log_path = sys.argv[1], sys.argv[2]
+class Handler(http.server.BaseHTTPRequestHandler):
+    def log_message(self, *args): pass
+    def reply(self, code, body=b""):
+        self.send_response(code)
+        self.send_header("Content-Length", str(len(body)))
+        self.end_headers()
+        if self.command != "HEAD": self.wfile.write(body)
+    def handle_any(self):
+        with open(log_path, "a") as f: f.write("%s %s\n" % (self.command, self.path))
+        if self.headers.get("Authorization") != "Bearer cache-token":
+            return self.reply(401)
+        path = os.path.join(store, self.path.lstrip("/").replace("..", ""))
+        if self.command == "PUT":
+            os.makedirs(os.path.dirname(path), exist_ok=True)
+            with open(path, "wb") as f: f.write(self.rfile.read(int(self.headers["Content-Length"])))
+            return self.reply(201)
+        if not os.path.isfile(path): return self.reply(404)
+        with open(path, "rb") as f: self.reply(200, f.read())
+    do_GET = do_HEAD = do_PUT = handle_any
+server = http.server.ThreadingHTTPServer(("127.0.0.1", 0), Handler)
+with open(os.path.join(store, "port"), "w") as f: f.write(str(server.server_port))
+server.serve_forever()
+PYEOF
+python3 cache_server.py store requests.log &
+SERVER_PID=$!
+for _ in $(seq 50); do [ -s store/port ] && break; sleep 0.1; done
+PORT=$(cat store/port)
+remote_stat() {
+    jq -s -r --arg cache "$2" --arg field "$3" \
+        '.[] | select(.fields.message == "cache statistics" and .fields.cache == $cache) | .fields[$field]' "$1"
+}
+IMAGES='compression: gzip\nimages: [{architecture: amd64, os: linux, layers: [root, app]}]\n'
+REMOTE="remote-cache: {url: \"http://127.0.0.1:$PORT/ci\", token-env: CACHE_TOKEN}\n"
+printf "output: out1\ncache-dir: cache1\n$REMOTE$IMAGES" | CACHE_TOKEN=cache-token SOURCE_DATE_EPOCH=1700000000 build-oci -v --log-format json 2> first.log
+printf "output: out2\ncache-dir: cache2\n$REMOTE$IMAGES" | CACHE_TOKEN=cache-token SOURCE_DATE_EPOCH=1700000000 build-oci -v --log-format json 2> second.log
+if [ "$(ls store/ci/layers | wc -l)" -eq 2 ] && [ "$(remote_stat second.log remoteCache hits)" -eq 2 ] \
+    && [ "$(remote_stat second.log layerCache hits)" -eq 2 ] && [ "$(ls cache2/layers | wc -l)" -eq 2 ] \
+    && [ "$(jq -r '.manifests[0].digest' out1/index.json)" = "$(jq -r '.manifests[0].digest' out2/index.json)" ]; then
+    pass "Layers uploaded by one cache directory are downloaded into another and reused"
+else
+    fail "remote-cache" "stored $(ls store/ci/layers 2>/dev/null | wc -l), remote hits $(remote_stat second.log remoteCache hits)"
+fi
+# Without the token reads are refused; the build goes on with the local cache
+printf "output: out3\ncache-dir: cache3\n$REMOTE$IMAGES" | SOURCE_DATE_EPOCH=1700000000 build-oci 2> third.log
+kill "$SERVER_PID"
+wait "$SERVER_PID" 2>/dev/null || true
+printf "output: out4\ncache-dir: cache4\n$REMOTE$IMAGES" | CACHE_TOKEN=cache-token SOURCE_DATE_EPOCH=1700000000 build-oci 2> fourth.log
+if [ "$(jq -r '.manifests[0].digest' out3/index.json)" = "$(jq -r '.manifests[0].digest' out1/index.json)" ] \
+    && grep -q "server answered 401" third.log \
+    && [ "$(jq -r '.manifests[0].digest' out4/index.json)" = "$(jq -r '.manifests[0].digest' out1/index.json)" ] \
+    && [ "$(grep -c "remote cache unreachable" fourth.log)" -eq 1 ] && [ "$(ls cache4/layers | wc -l)" -eq 2 ]; then
+    pass "A refusing or unreachable remote falls back to building with the local cache"
+else
+    fail "remote-cache" "fallback: $(cat third.log fourth.log | head -5)"
+fi
+set +e
+printf "output: out5\n$REMOTE$IMAGES" | build-oci >/dev/null 2>&1
+RC=$?
+set -e
+if [ "$RC" -eq 2 ]; then
+    pass "remote-cache without cache-dir is a config error"
+else
+    fail "remote-cache" "without cache-dir: exit code $RC"
+fi
+cd /
+rm -rf "$WORKDIR"
+
+# Test 101: exclude and include-only patterns
+# --------------------------------------------------
+echo ""
+echo "Test 101: exclude and include-only patterns leave paths out of layer directories"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/app/src" "$WORKDIR/app/.git" "$WORKDIR/app/var/cache/x" "$WORKDIR/app/var/lib" \
+    "$WORKDIR/tools/usr/bin" "$WORKDIR/tools/usr/share" "$WORKDIR/tools/etc"
+cd "$WORKDIR"
+echo obj > app/main.o
+echo code > app/src/main.c
+echo obj > app/src/main.o
+echo ref > app/.git/HEAD
+echo cached > app/var/cache/x/y
+echo kept > app/var/lib/keep
+echo tool > tools/usr/bin/tool
+echo doc > tools/usr/share/doc
+echo conf > tools/etc/conf
+cat <<'YAML' | build-oci
+output: out
+compression: gzip
+images:
+  - architecture: amd64
+    os: linux
+    exclude: ["*.o", ".git/", "var/cache/**"]
+    layers:
+      - app
+      - {dir: tools, include-only: ["usr/bin/**"]}
+YAML
+MANIFEST=$(jq -r '.manifests[0].digest' out/index.json | cut -d: -f2)
+layer_listing() {
+    tar tzf "out/blobs/sha256/$(jq -r ".layers[$1].digest" "out/blobs/sha256/$MANIFEST" | cut -d: -f2)" 2>/dev/null \
+        | sed 's#/$##' | grep -v '\.wh\.' | sort | tr '\n' ' '
+}
+APP=$(layer_listing 0)
+TOOLS=$(layer_listing 1)
+if [ "$APP" = ". src src/main.c var var/cache var/lib var/lib/keep " ]; then
+    pass "Excluded files, directories and directory contents are left out"
+else
+    fail "exclude" "app layer: $APP"
+fi
+if [ "$TOOLS" = ". usr usr/bin usr/bin/tool " ]; then
+    pass "Only included paths and the directories leading to them are kept"
+else
+    fail "include" "tools layer: $TOOLS"
+fi
+set +e
+printf 'output: out2\nimages: [{architecture: amd64, os: linux, layer-tar: t.tar, exclude: ["*.o"]}]\n' | build-oci 2>/dev/null
+RC_TAR=$?
+printf 'output: out3\nimages: [{architecture: amd64, os: linux, layer: app, include-only: ["src/{a"]}]\n' | build-oci 2> bad.log
+RC_BAD=$?
+set -e
+if [ "$RC_TAR" -eq 2 ] && [ "$RC_BAD" -eq 2 ] && grep -q "images\[0\]" bad.log; then
+    pass "Patterns without a layer directory, or that do not parse, are config errors"
+else
+    fail "exclude" "exit codes: layer-tar $RC_TAR, bad pattern $RC_BAD"
+fi
+cd /
+rm -rf "$WORKDIR"
+
+# Test 102: id-map rewrites entry owners
+# --------------------------------------------------
+echo ""
+echo "Test 102: id-map rewrites the owners of layer entries"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/rootfs/etc"
+cd "$WORKDIR"
+export XDG_CACHE_HOME="$WORKDIR/cache"
+echo conf > rootfs/etc/conf
+echo data > rootfs/data
+if [ "$(id -u)" = "$(id -g)" ]; then
+    ID_MAP="[\"$(id -u):1000\"]"
+else
+    ID_MAP="[\"$(id -u):1000\", \"$(id -g):1000\"]"
+fi
+build() {
+    printf "output: $1\nimages: [{architecture: amd64, os: linux, layer: rootfs$2}]\n" | SOURCE_DATE_EPOCH=1700000000 build-oci >/dev/null
+}
+layer_owners() {
+    local manifest
+    manifest=$(jq -r '.manifests[0].digest' "$1/index.json" | cut -d: -f2)
+    tar tvf "$1/blobs/sha256/$(jq -r '.layers[-1].digest' "$1/blobs/sha256/$manifest" | cut -d: -f2)" --numeric-owner 2>/dev/null \
+        | awk '{print substr($1, 1, 1), $2}' | sort -u | tr '\n' ' '
+}
+build parent ", id-map: $ID_MAP"
+build same ", id-map: $ID_MAP, parent: {image: parent}"
+build unmapped ", parent: {image: parent}"
+OWNERS=$(layer_owners parent)
+if [ "$OWNERS" = "- 1000/1000 d 1000/1000 " ]; then
+    pass "Every entry, the layer root included, is written with the mapped ids"
+else
+    fail "id-map" "owners: $OWNERS"
+fi
+SAME=$(layer_owners same)
+UNMAPPED=$(layer_owners unmapped)
+if [ "$SAME" = "d 1000/1000 " ] && echo "$UNMAPPED" | grep -q '^- '; then
+    pass "Deduplication compares the mapped ids against the parent's"
+else
+    fail "id-map" "same map: $SAME, unmapped: $UNMAPPED"
+fi
+set +e
+build bad1 ', id-map: ["0:1000:0"]' 2>/dev/null
+RC_COUNT=$?
+build bad2 ', id-map: ["0:1000:10", "5:2000"]' 2>/dev/null
+RC_OVERLAP=$?
+printf 'output: bad3\nimages: [{architecture: amd64, os: linux, layer-tar: t.tar, id-map: ["0:1000"]}]\n' | build-oci >/dev/null 2>&1
+RC_TAR=$?
+set -e
+if [ "$RC_COUNT" -eq 2 ] && [ "$RC_OVERLAP" -eq 2 ] && [ "$RC_TAR" -eq 2 ]; then
+    pass "Empty or overlapping ranges, and id-map on a layer-tar, are config errors"
+else
+    fail "id-map" "exit codes: count $RC_COUNT, overlap $RC_OVERLAP, layer-tar $RC_TAR"
+fi
+unset XDG_CACHE_HOME
+cd /
+rm -rf "$WORKDIR"
+
+# Test 103: ownership rules
+# --------------------------------------------------
+echo ""
+echo "Test 103: ownership rules set owners and modes by path"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/rootfs/etc" "$WORKDIR/rootfs/app/bin"
+cd "$WORKDIR"
+echo 'root:*:19000::::::' > rootfs/etc/shadow
+chmod 0644 rootfs/etc/shadow
+echo run > rootfs/app/bin/run
+chmod 0755 rootfs/app/bin/run
+ME="$(id -u)/$(id -g)"
+cat <<'YAML' | build-oci
+output: out
+lint:
+  - {rule: mode, path: /etc/shadow, mode: "0600"}
+images:
+  - architecture: amd64
+    os: linux
+    layer: rootfs
+    ownership:
+      - {path: /etc/shadow, mode: "0600"}
+      - {path: /app, uid: 1001, gid: 1001}
+      - {path: "/app/**", uid: 1001}
+      - {path: "**/run", mode: "4755"}
+YAML
+MANIFEST=$(jq -r '.manifests[0].digest' out/index.json | cut -d: -f2)
+LAYER=$(jq -r '.layers[0].digest' "out/blobs/sha256/$MANIFEST" | cut -d: -f2)
+ENTRIES=$(tar tvf "out/blobs/sha256/$LAYER" --numeric-owner 2>/dev/null | awk '{print $6, $1, $2}')
+entry() { echo "$ENTRIES" | grep "^$1 " | cut -d' ' -f2-; }
+if [ "$(entry etc/shadow)" = "-rw------- $ME" ] && [ "$(entry etc/)" = "drwxr-xr-x $ME" ]; then
+    pass "A rule's mode applies to the matching file only, and satisfies a lint rule"
+else
+    fail "ownership" "etc/shadow: $(entry etc/shadow), etc: $(entry etc/)"
+fi
+if [ "$(entry app/)" = "drwxr-xr-x 1001/1001" ] && [ "$(entry app/bin/)" = "drwxr-xr-x 1001/$(id -g)" ] \
+    && [ "$(entry app/bin/run)" = "-rwsr-xr-x 1001/$(id -g)" ]; then
+    pass "Later rules add to earlier ones, setting only the fields they give"
+else
+    fail "ownership" "app: $(entry app/), app/bin: $(entry app/bin/), run: $(entry app/bin/run)"
+fi
+set +e
+printf 'output: bad1\nimages: [{architecture: amd64, os: linux, layer: rootfs, ownership: [{path: /etc, mode: "999"}]}]\n' | build-oci 2>/dev/null
+RC_MODE=$?
+printf 'output: bad2\nimages: [{architecture: amd64, os: linux, layer: rootfs, ownership: [{uid: 0}]}]\n' | build-oci 2>/dev/null
+RC_PATH=$?
+set -e
+if [ "$RC_MODE" -eq 2 ] && [ "$RC_PATH" -eq 2 ]; then
+    pass "Invalid modes and rules without a path are config errors"
+else
+    fail "ownership" "exit codes: mode $RC_MODE, path $RC_PATH"
+fi
+cd /
+rm -rf "$WORKDIR"
+
+# Test 104: write-checksum-xattrs leaves dry runs and reference directories alone
+# --------------------------------------------------
+echo ""
+echo "Test 104: write-checksum-xattrs writes nothing in a dry run or to dedup-lowers directories"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/rootfs" "$WORKDIR/refdir"
+echo "hello" > "$WORKDIR/rootfs/hello.txt"
+echo "reference" > "$WORKDIR/refdir/ref.txt"
+cd "$WORKDIR"
+getxattr() {
+    python3 -c 'import os, sys; print(os.getxattr(sys.argv[1], "user.checksum.sha256").decode())' "$1" 2>/dev/null
+}
+if python3 -c 'import os, sys; os.setxattr(sys.argv[1], "user.probe", b"1")' rootfs/hello.txt 2>/dev/null; then
+    printf 'write-checksum-xattrs: true\nimages: [{architecture: amd64, os: linux, layer: rootfs}]\n' \
+        | build-oci --dry-run >/dev/null
+    if [ -z "$(getxattr rootfs/hello.txt)" ] && [ ! -e index.json ]; then
+        pass "--dry-run writes no checksum xattrs into the layer directory"
+    else
+        fail "write-checksum-xattrs" "dry run wrote $(getxattr rootfs/hello.txt)"
+    fi
+    printf 'write-checksum-xattrs: true\nimages: [{architecture: amd64, os: linux, layer: rootfs, dedup-lowers: [{dir: refdir}]}]\n' \
+        | build-oci
+    if [ -n "$(getxattr rootfs/hello.txt)" ] && [ -z "$(getxattr refdir/ref.txt)" ]; then
+        pass "A real build writes them to the layer directory only, not to dedup-lowers"
+    else
+        fail "write-checksum-xattrs" "layer: $(getxattr rootfs/hello.txt), reference: $(getxattr refdir/ref.txt)"
+    fi
+else
+    warn "write-checksum-xattrs" "filesystem lacks user xattrs, test skipped"
+fi
+
+cd /
+rm -rf "$WORKDIR"
+
+# Test 105: push credentials stay with the registry
+# --------------------------------------------------
+echo ""
+echo "Test 105: push sends registry credentials only to the registry's own origin"
+
+WORKDIR=$(mktemp -d)
+mkdir -p "$WORKDIR/rootfs" "$WORKDIR/store"
+echo "hello" > "$WORKDIR/rootfs/hello.txt"
+cat > "$WORKDIR/registry.py" <<'PYEOF'
+import base64, http.server, os, sys, threading, urllib.parse
+store, log_path = sys.argv[1], sys.argv[2]
+BASIC = "Basic " + base64.b64encode(b"alice:wonderland").decode()
+class Handler(http.server.BaseHTTPRequestHandler):
+    def log_message(self, *args): pass
+    def reply(self, code, headers=None):
+        self.send_response(code)
+        for k, v in (headers or {}).items(): self.send_header(k, v)
+        self.send_header("Content-Length", "0")
+        self.end_headers()
+    def handle_any(self):
+        url = urllib.parse.urlparse(self.path)
+        self.rfile.read(int(self.headers.get("Content-Length") or 0))
+        auth = "auth" if self.headers.get("Authorization") else "noauth"
+        with open(log_path, "a") as f: f.write("%s %s %s %s\n" % (self.server.name, self.command, url.path, auth))
+        if self.server.name == "storage":
+            return self.reply(201)
+        if self.headers.get("Authorization") != BASIC:
+            return self.reply(401, {"WWW-Authenticate": 'Basic realm="mock"'})
+        if url.path == "/v2/": return self.reply(200)
+        if "uploads" in url.path:
+            return self.reply(202, {"Location": "http://127.0.0.1:%d/upload/1" % storage.server_port})
+        if "/blobs/" in url.path: return self.reply(404)
+        self.reply(201)
+    do_GET = do_HEAD = do_POST = do_PATCH = do_PUT = handle_any
+def serve(name):
+    server = http.server.ThreadingHTTPServer(("127.0.0.1", 0), Handler)
+    server.name = name
+    threading.Thread(target=server.serve_forever, daemon=True).start()
+    return server
+storage = serve("storage")
+registry = serve("registry")
+with open(os.path.join(store, "port"), "w") as f: f.write(str(registry.server_port))
+threading.Event().wait()
+PYEOF
+python3 "$WORKDIR/registry.py" "$WORKDIR/store" "$WORKDIR/requests.log" &
+REGISTRY_PID=$!
+for _ in $(seq 50); do [ -s "$WORKDIR/store/port" ] && break; sleep 0.1; done
+PORT=$(cat "$WORKDIR/store/port")
+printf '{"auths":{"127.0.0.1:%s":{"auth":"%s"}}}\n' "$PORT" "$(printf 'alice:wonderland' | base64)" > "$WORKDIR/auth.json"
+cd "$WORKDIR"
+printf 'output: out\nimages: [{architecture: amd64, os: linux, layer: rootfs}]\n' | build-oci
+set +e
+PUSH_OUT=$(REGISTRY_AUTH_FILE="$WORKDIR/auth.json" build-oci push out "127.0.0.1:$PORT/test/app" 2>&1)
+RC=$?
+set -e
+if [ "$RC" -eq 0 ] && [ "$(grep -c '^storage PUT /upload/1 noauth$' requests.log)" -eq 2 ] \
+    && ! grep -q '^storage .* auth$' requests.log && grep -q '^registry PUT /v2/test/app/manifests/latest auth$' requests.log; then
+    pass "Uploads to another origin get no Authorization header, the registry still does"
+else
+    fail "push" "exit $RC: $PUSH_OUT $(cat requests.log)"
+fi
+kill "$REGISTRY_PID" 2>/dev/null || true
+wait "$REGISTRY_PID" 2>/dev/null || true
+cd /
+rm -rf "$WORKDIR"
+
+# Test 106: command line parsing
+# --------------------------------------------------
+echo ""
+echo "Test 106: The command line is parsed by the description completions come from"
+WORKDIR=$(mktemp -d)
+cd "$WORKDIR"
+build-oci completions fish > completions.fish
+set +e
+build-oci --no-such-flag --error-json bad-flag.json < /dev/null 2> bad-flag.err
+RC_FLAG=$?
+build-oci ls --layer-threads 2 . < /dev/null 2> misplaced.err
+RC_MISPLACED=$?
+set -e
+if [ "$RC_FLAG" -eq 2 ] && grep -q -- "--no-such-flag" bad-flag.err && [ "$(jq -r '.category' bad-flag.json)" = "config" ] \
+    && [ "$RC_MISPLACED" -eq 2 ] && ! grep -F '"__fish_build_oci_using_subcommand ls"' completions.fish | grep -q -- '-l layer-threads'; then
+    pass "flags completions do not offer are rejected with a config error"
+else
+    fail "cli" "unknown flag: exit $RC_FLAG $(cat bad-flag.err); ls --layer-threads: exit $RC_MISPLACED"
+fi
+build-oci --help > help.txt
+build-oci watch --help > watch-help.txt
+if grep -q -- '--layer-threads' help.txt && grep -q -- '--debounce-ms' watch-help.txt \
+    && [ "$(build-oci --version < /dev/null)" = "build-oci $(build-oci man | sed -n 's/^\.TH build-oci 1  "build-oci \([^"]*\)".*/\1/p')" ] \
+    && [ ! -e index.json ]; then
+    pass "--help and --version print without building"
+else
+    fail "cli" "--help/--version: $(build-oci --version < /dev/null 2>&1)"
+fi
+cd /
+rm -rf "$WORKDIR"
+
 
 # ======================================================================
 echo ""